@@ -0,0 +1,96 @@
+//! Aggregation of simulated paths: the goodput of a transfer against the sum of their bandwidths, and the delivery latency
+//!
+//! Run with `cargo bench --features sim --bench aggregation`.
+//! A bulk transfer over two equal paths gets above 90% of their sum and one over paths of 10:1 about 85%, the slow path holding up the reassembly.
+//! When a path fails a quarter of the way through, two thirds of the sum is the best the remaining path allows.
+//! Messages of 2 KiB are paced by the application, so only their latency tells: striped in 512 byte segments they wait for the slow path, while `SendMode::Sticky` gets most of them through at about the latency of the fast one.
+
+use std::{num::NonZeroUsize, time::Duration};
+
+use mptcp::{
+    sender::{SendMode, SenderBuilder},
+    sim::{self, SimConfig, TransferReport, Workload},
+};
+use tokio::runtime::Runtime;
+
+const MIB: u64 = 1 << 20;
+const TRANSFER_SIZE: usize = 64 << 20;
+const CHUNK_SIZE: usize = 1 << 20;
+const MESSAGE_SIZE: usize = 2 << 10;
+const STRIPED_MESSAGE_SEGMENT_SIZE: usize = 512;
+const STICKY_THRESHOLD: usize = 8 << 10;
+
+fn path(latency_ms: u64, bandwidth: u64) -> SimConfig {
+    SimConfig::new()
+        .latency(Duration::from_millis(latency_ms))
+        .bandwidth(bandwidth)
+}
+
+fn report(scenario: &str, workload: Workload, report: &TransferReport) {
+    let efficiency = match (workload, report.efficiency()) {
+        (Workload::Bulk { .. }, Some(efficiency)) => format!("{:.1}%", efficiency * 100.0),
+        _ => "-".to_owned(),
+    };
+    println!(
+        "{scenario:<16} {:>8.2} MiB/s {efficiency:>7} of the sum  p50 {:>9.2?}  p99 {:>9.2?}",
+        report.goodput() / MIB as f64,
+        report.latency(0.5),
+        report.latency(0.99),
+    );
+}
+
+fn main() {
+    let runtime = Runtime::new().unwrap();
+    let bulk = Workload::Bulk {
+        bytes: TRANSFER_SIZE,
+        chunk: CHUNK_SIZE,
+    };
+    let messages = Workload::Messages {
+        count: 2000,
+        size: MESSAGE_SIZE,
+        interval: Duration::from_millis(1),
+    };
+    let fast_and_slow = vec![path(10, 8 * MIB), path(50, 8 * MIB)];
+    let sticky = SendMode::Sticky {
+        threshold: STICKY_THRESHOLD,
+    };
+    let scenarios = [
+        (
+            "equal",
+            vec![path(20, 8 * MIB), path(20, 8 * MIB)],
+            SendMode::Stripe,
+            bulk,
+        ),
+        (
+            "asymmetric",
+            vec![path(20, 10 * MIB), path(20, MIB)],
+            SendMode::Stripe,
+            bulk,
+        ),
+        (
+            "path failure",
+            vec![
+                path(20, 8 * MIB),
+                path(20, 8 * MIB).fail_after(TRANSFER_SIZE as u64 / 4),
+            ],
+            SendMode::Stripe,
+            bulk,
+        ),
+        (
+            "messages",
+            fast_and_slow.clone(),
+            SendMode::Stripe,
+            messages,
+        ),
+        ("sticky messages", fast_and_slow, sticky, messages),
+    ];
+    for (scenario, paths, mode, workload) in scenarios {
+        let mut sender = SenderBuilder::new().send_mode(mode);
+        if let Workload::Messages { .. } = workload {
+            let size = NonZeroUsize::new(STRIPED_MESSAGE_SEGMENT_SIZE).unwrap();
+            sender = sender.min_segment_size(size);
+        }
+        let result = runtime.block_on(sim::transfer(&paths, sender, workload));
+        report(scenario, workload, &result.unwrap());
+    }
+}
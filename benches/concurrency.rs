@@ -0,0 +1,110 @@
+//! Compare spawning a task per segment write with polling the writes in place
+//!
+//! Run with `cargo bench --bench concurrency`.
+
+use std::num::NonZeroUsize;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use mptcp::{
+    message::{DataSegment, Message, Sequence},
+    sender::Sender,
+};
+use tokio::{io::Sink, runtime::Runtime, task::JoinSet};
+
+const STREAMS: usize = 4;
+const SEGMENT_SIZES: [usize; 3] = [1 << 10, 1 << 14, 1 << 18];
+
+fn sinks() -> Vec<Sink> {
+    (0..STREAMS).map(|_| tokio::io::sink()).collect()
+}
+
+fn segment(size: usize) -> DataSegment {
+    let payload = Bytes::from(vec![0; size]);
+    DataSegment::new(Sequence::new(0), payload).unwrap()
+}
+
+/// One round of the old path: a spawned task per stream
+async fn spawned(sinks: Vec<Sink>, segment: &DataSegment) -> Vec<Sink> {
+    let mut tasks = JoinSet::new();
+    for mut sink in sinks {
+        let message = Message::DataSegment(segment.clone());
+        tasks.spawn(async move {
+            message.encode(&mut sink).await.unwrap();
+            sink
+        });
+    }
+    let mut sinks = vec![];
+    while let Some(task) = tasks.join_next().await {
+        sinks.push(task.unwrap());
+    }
+    sinks
+}
+
+/// One round of the new path: the writes are polled by the caller
+async fn in_place(sinks: Vec<Sink>, segment: &DataSegment) -> Vec<Sink> {
+    let mut writes = FuturesUnordered::new();
+    for mut sink in sinks {
+        let message = Message::DataSegment(segment.clone());
+        writes.push(Box::pin(async move {
+            message.encode(&mut sink).await.unwrap();
+            sink
+        }));
+    }
+    let mut sinks = vec![];
+    while let Some(sink) = writes.next().await {
+        sinks.push(sink);
+    }
+    sinks
+}
+
+fn concurrency(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("concurrency");
+    for size in SEGMENT_SIZES {
+        let segment = segment(size);
+        group.throughput(Throughput::Bytes((size * STREAMS) as u64));
+        group.bench_with_input(BenchmarkId::new("spawned", size), &segment, |b, segment| {
+            b.to_async(&runtime).iter_batched(
+                sinks,
+                |sinks| spawned(sinks, segment),
+                BatchSize::SmallInput,
+            );
+        });
+        group.bench_with_input(
+            BenchmarkId::new("in_place", size),
+            &segment,
+            |b, segment| {
+                b.to_async(&runtime).iter_batched(
+                    sinks,
+                    |sinks| in_place(sinks, segment),
+                    BatchSize::SmallInput,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+fn batch_send_all(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("batch_send_all");
+    for size in SEGMENT_SIZES {
+        let data = Bytes::from(vec![0; size * STREAMS]);
+        let mut sender = Sender::new(sinks());
+        sender.set_max_segment_size(NonZeroUsize::new(size));
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                runtime
+                    .block_on(sender.batch_send_all(data.clone()))
+                    .unwrap()
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrency, batch_send_all);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+//! Throughput of sends of 64-byte payloads, where the per-segment overhead dominates
+//!
+//! Run with `cargo bench --bench small_segments`.
+
+use std::num::NonZeroUsize;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mptcp::sender::Sender;
+use tokio::runtime::Runtime;
+
+const STREAMS: usize = 4;
+const PAYLOAD_SIZE: usize = 64;
+const SEGMENTS: usize = 1 << 10;
+
+fn small_segments(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("small_segments");
+    let data = Bytes::from(vec![0; PAYLOAD_SIZE * SEGMENTS]);
+    group.throughput(Throughput::Elements(SEGMENTS as u64));
+    for compact in [false, true] {
+        let sinks = (0..STREAMS).map(|_| tokio::io::sink()).collect();
+        let mut sender = Sender::new(sinks);
+        sender.set_max_segment_size(NonZeroUsize::new(PAYLOAD_SIZE));
+        sender.set_compact_headers(compact);
+        let name = if compact { "compact" } else { "full" };
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                runtime
+                    .block_on(sender.batch_send_all(data.clone()))
+                    .unwrap()
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, small_segments);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+//! Delivery of a 1 GiB transfer by copying it out of the reassembly buffer or handing out its pieces
+//!
+//! Run with `cargo bench --bench zero_copy`.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::StreamExt;
+use mptcp::{receiver::Receiver, sender::Sender};
+use tokio::{io::AsyncReadExt, runtime::Runtime};
+
+const STREAMS: usize = 4;
+const CHUNK_SIZE: usize = 1 << 20;
+const TRANSFER_SIZE: usize = 1 << 30;
+const READ_SIZE: usize = 1 << 16;
+
+/// Send the transfer over fresh streams and hand the receiving end to `deliver`, which returns the bytes delivered
+async fn transfer<F, D>(deliver: D) -> usize
+where
+    D: FnOnce(Receiver) -> F,
+    F: std::future::Future<Output = usize>,
+{
+    let mut send_streams = vec![];
+    let mut recv_streams = vec![];
+    for _ in 0..STREAMS {
+        let (tx, rx) = tokio::io::duplex(1 << 20);
+        send_streams.push(tx);
+        recv_streams.push(rx);
+    }
+    let mut sender = Sender::new(send_streams);
+    let send = tokio::spawn(async move {
+        let chunk = Bytes::from(vec![0; CHUNK_SIZE]);
+        for _ in 0..TRANSFER_SIZE / CHUNK_SIZE {
+            sender.batch_send_all(chunk.clone()).await.unwrap();
+        }
+        sender.shutdown().await.unwrap();
+    });
+    let delivered = deliver(Receiver::new(recv_streams)).await;
+    send.await.unwrap();
+    delivered
+}
+
+async fn copied(receiver: Receiver) -> usize {
+    let mut reader = receiver.into_async_read();
+    let mut buf = vec![0; READ_SIZE];
+    let mut delivered = 0;
+    loop {
+        let read = reader.read(&mut buf).await.unwrap();
+        if read == 0 {
+            return delivered;
+        }
+        delivered += read;
+    }
+}
+
+async fn zero_copy(receiver: Receiver) -> usize {
+    let mut pieces = receiver.into_bytes_stream();
+    let mut delivered = 0;
+    while let Some(piece) = pieces.next().await {
+        delivered += piece.unwrap().len();
+    }
+    delivered
+}
+
+fn delivery(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("delivery");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(TRANSFER_SIZE as u64));
+    group.bench_function(BenchmarkId::from_parameter("copy"), |b| {
+        b.iter(|| assert_eq!(runtime.block_on(transfer(copied)), TRANSFER_SIZE));
+    });
+    group.bench_function(BenchmarkId::from_parameter("zero_copy"), |b| {
+        b.iter(|| assert_eq!(runtime.block_on(transfer(zero_copy)), TRANSFER_SIZE));
+    });
+    group.finish();
+}
+
+criterion_group!(benches, delivery);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+use std::io::{Read, Write};
+
+use mptcp::wire::inspect;
+
+/// Print the frames of one direction of a subflow, read from the file given or from stdin
+///
+/// Feed it the payload of a TCP stream out of a capture, from the hello of the subflow on.
+fn main() -> anyhow::Result<()> {
+    let mut bytes = vec![];
+    match std::env::args().nth(1) {
+        Some(path) => bytes = std::fs::read(path)?,
+        None => {
+            std::io::stdin().read_to_end(&mut bytes)?;
+        }
+    }
+    write!(std::io::stdout(), "{}", inspect(&bytes))?;
+    Ok(())
+}
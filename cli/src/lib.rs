@@ -6,7 +6,7 @@ use std::{
 use clap::{Args, Subcommand};
 use tokio::{
     fs::File,
-    io::{AsyncRead, AsyncWrite, BufReader},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
 };
 
 #[derive(Debug, Clone, Subcommand)]
@@ -47,6 +47,7 @@ pub async fn push_file(
     let mut file = BufReader::new(file);
 
     let read = tokio::io::copy(&mut file, &mut write).await.unwrap();
+    write.shutdown().await?;
 
     Ok(usize::try_from(read).unwrap())
 }
@@ -70,6 +71,7 @@ pub async fn pull_file(
     let mut file = File::options()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(output_file)
         .await
         .unwrap();
@@ -0,0 +1,58 @@
+//! A transfer over `futures_io` streams without tokio or any other runtime
+//!
+//! The sender writes its subflows into buffers and the receiver reads them back, all polled by the small executor below.
+//! On async-std or smol, any of their `TcpStream`s takes the place of the buffers and the runtime's `block_on` that of `block_on`.
+//!
+//! Run with `cargo run --example futures_io --no-default-features --features futures-io`, which builds no tokio in.
+
+use std::{
+    future::Future,
+    io,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake},
+    thread::{self, Thread},
+};
+
+use futures_util::io::{AsyncReadExt, AsyncWriteExt, Cursor};
+use mptcp::futures_io::{Receiver, Sender};
+
+const STREAMS: usize = 3;
+
+/// Wakes the thread blocked on a future
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    let message = b"hello over several subflows".repeat(1000);
+    block_on(async {
+        let mut sender = Sender::new(vec![Vec::new(); STREAMS]);
+        sender.write_all(&message).await?;
+        sender.close().await?;
+
+        let subflows = sender.into_inner().into_iter().map(Cursor::new).collect();
+        let mut receiver = Receiver::new(subflows);
+        let mut received = vec![];
+        receiver.read_to_end(&mut received).await?;
+        assert_eq!(received, message);
+        println!("received {} bytes", received.len());
+        Ok(())
+    })
+}
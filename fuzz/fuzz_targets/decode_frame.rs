@@ -0,0 +1,34 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use mptcp::wire::{Frame, HeaderContext, DEFAULT_MAX_PAYLOAD_SIZE};
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever decodes must encode back to the bytes it came from
+    let mut src = data;
+    let mut decoding = HeaderContext::new();
+    let mut encoding = HeaderContext::new();
+    let mut encoded = BytesMut::new();
+    while let Ok(Some(frame)) = Frame::decode_in(&mut src, &mut decoding, DEFAULT_MAX_PAYLOAD_SIZE) {
+        frame.encode_in(&mut encoded, &mut encoding).unwrap();
+    }
+    assert_eq!(encoded[..], data[..encoded.len()]);
+
+    // Feeding the bytes one by one decodes the same frames
+    let mut src = BytesMut::new();
+    let mut decoding = HeaderContext::new();
+    let mut encoding = HeaderContext::new();
+    let mut stepped = BytesMut::new();
+    'feed: for &byte in data {
+        src.extend_from_slice(&[byte]);
+        loop {
+            match Frame::decode_in(&mut src, &mut decoding, DEFAULT_MAX_PAYLOAD_SIZE) {
+                Ok(Some(frame)) => frame.encode_in(&mut stepped, &mut encoding).unwrap(),
+                Ok(None) => break,
+                Err(_) => break 'feed,
+            }
+        }
+    }
+    assert_eq!(stepped, encoded);
+});
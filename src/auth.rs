@@ -0,0 +1,162 @@
+//! Authentication of the subflows joining a session
+//!
+//! A listener with a `SessionSecret` answers the `Init` of every subflow with a fresh nonce.
+//! The client proves it knows the secret with an HMAC-SHA256 of the session ID and that nonce, and the listener replies with a verdict.
+//! A subflow whose proof is wrong never reaches its session, so guessing a session ID is not enough to inject frames into it.
+
+use std::{fmt, io, sync::Arc};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::message::Session;
+
+/// Bytes of the nonce a listener challenges a subflow with
+pub const NONCE_SIZE: usize = 16;
+/// Bytes of the proof a client answers a nonce with
+pub const PROOF_SIZE: usize = 32;
+
+const ACCEPTED: u8 = 0;
+const REJECTED: u8 = 1;
+
+/// A secret shared by a client and a listener, to authenticate the subflows of their sessions
+#[derive(Clone)]
+pub struct SessionSecret(Arc<[u8]>);
+
+impl SessionSecret {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self(secret.into().into())
+    }
+
+    /// The proof of a subflow of `session` challenged with `nonce`
+    pub fn proof(&self, session: Session, nonce: &[u8; NONCE_SIZE]) -> [u8; PROOF_SIZE] {
+        self.mac(session, nonce).finalize().into_bytes().into()
+    }
+
+    /// Whether `proof` is that of a subflow of `session` challenged with `nonce`, in constant time
+    pub fn verify(&self, session: Session, nonce: &[u8; NONCE_SIZE], proof: &[u8]) -> bool {
+        self.mac(session, nonce).verify_slice(proof).is_ok()
+    }
+
+    fn mac(&self, session: Session, nonce: &[u8; NONCE_SIZE]) -> Hmac<Sha256> {
+        // HMAC takes keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        mac.update(&session.inner().to_be_bytes());
+        mac.update(nonce);
+        mac
+    }
+}
+
+impl fmt::Debug for SessionSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionSecret(..)")
+    }
+}
+
+/// What a listener does with a subflow whose proof is wrong
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RejectPolicy {
+    /// Close the connection without a word, telling a prober nothing
+    #[default]
+    Close,
+    /// Send the rejection verdict before closing, so that a misconfigured client fails with `io::ErrorKind::PermissionDenied`
+    ErrorFrame,
+}
+
+/// Challenge the subflow of `session` that sent its `Init` on `stream`
+///
+/// Returns whether the subflow proved it knows `secret`; a rejected subflow is to be closed.
+pub(crate) async fn challenge<S>(
+    stream: &mut S,
+    session: Session,
+    secret: &SessionSecret,
+    reject: RejectPolicy,
+) -> io::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let nonce: [u8; NONCE_SIZE] = rand::random();
+    stream.write_all(&nonce).await?;
+    stream.flush().await?;
+    let mut proof = [0; PROOF_SIZE];
+    stream.read_exact(&mut proof).await?;
+    let accepted = secret.verify(session, &nonce, &proof);
+    let verdict = match (accepted, reject) {
+        (true, _) => ACCEPTED,
+        (false, RejectPolicy::ErrorFrame) => REJECTED,
+        (false, RejectPolicy::Close) => return Ok(false),
+    };
+    stream.write_u8(verdict).await?;
+    stream.flush().await?;
+    Ok(accepted)
+}
+
+/// Answer the challenge of the listener after sending the `Init` of a subflow of `session` on `stream`
+pub(crate) async fn prove<S>(
+    stream: &mut S,
+    session: Session,
+    secret: &SessionSecret,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut nonce = [0; NONCE_SIZE];
+    stream.read_exact(&mut nonce).await?;
+    stream.write_all(&secret.proof(session, &nonce)).await?;
+    stream.flush().await?;
+    match stream.read_u8().await? {
+        ACCEPTED => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "session proof rejected",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn challenge_and_prove() {
+        let secret = SessionSecret::new("secret");
+        let session = Session::new(7);
+        for (client_secret, reject, accepted) in [
+            ("secret", RejectPolicy::Close, true),
+            ("wrong", RejectPolicy::Close, false),
+            ("wrong", RejectPolicy::ErrorFrame, false),
+        ] {
+            let (mut client, mut server) = tokio::io::duplex(1 << 10);
+            let client_secret = SessionSecret::new(client_secret);
+            let client = tokio::spawn(async move {
+                let res = prove(&mut client, session, &client_secret).await;
+                drop(client);
+                res
+            });
+            let res = challenge(&mut server, session, &secret, reject).await;
+            assert_eq!(res.unwrap(), accepted);
+            drop(server);
+            let err = match client.await.unwrap() {
+                Ok(()) => {
+                    assert!(accepted);
+                    continue;
+                }
+                Err(err) => err,
+            };
+            let kind = match reject {
+                RejectPolicy::Close => io::ErrorKind::UnexpectedEof,
+                RejectPolicy::ErrorFrame => io::ErrorKind::PermissionDenied,
+            };
+            assert_eq!(err.kind(), kind);
+        }
+
+        // Bound to the session and the nonce
+        let nonce = [1; NONCE_SIZE];
+        let proof = secret.proof(session, &nonce);
+        assert!(secret.verify(session, &nonce, &proof));
+        assert!(!secret.verify(Session::new(8), &nonce, &proof));
+        assert!(!secret.verify(session, &[2; NONCE_SIZE], &proof));
+        assert!(!secret.verify(session, &nonce, &proof[..PROOF_SIZE - 1]));
+    }
+}
@@ -0,0 +1,108 @@
+//! Framing of data segments for `tokio_util::codec`
+//!
+//! A frame is made of exactly the bytes `DataSegment::encode` writes.
+
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::message::{DataSegment, Sequence};
+
+/// Length of the start sequence and the payload length fields
+const HEADER_SIZE: usize = 8 + 4;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DataSegmentCodec;
+
+impl Encoder<DataSegment> for DataSegmentCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: DataSegment, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(HEADER_SIZE + item.size());
+        dst.put_u64(item.start_sequence().inner());
+        dst.put_u32(item.size() as u32);
+        dst.put_slice(item.payload());
+        Ok(())
+    }
+}
+
+impl Decoder for DataSegmentCodec {
+    type Item = DataSegment;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_SIZE {
+            src.reserve(HEADER_SIZE - src.len());
+            return Ok(None);
+        }
+        let start_sequence = u64::from_be_bytes(src[..8].try_into().unwrap());
+        let length = u32::from_be_bytes(src[8..HEADER_SIZE].try_into().unwrap());
+        let length =
+            usize::try_from(length).map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e))?;
+        let frame_size = HEADER_SIZE + length;
+        if src.len() < frame_size {
+            src.reserve(frame_size - src.len());
+            return Ok(None);
+        }
+
+        src.advance(HEADER_SIZE);
+        let payload = src.split_to(length).freeze();
+        let data_segment = DataSegment::new(Sequence::new(start_sequence), payload)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid data segment"))?;
+        Ok(Some(data_segment))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    async fn encode(data_segment: &DataSegment) -> Vec<u8> {
+        let mut buf = vec![];
+        data_segment.encode(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let mut sizes = vec![1, 2, 4096, 1 << 24];
+        sizes.extend((0..16).map(|_| rand::random::<u16>() as usize + 1));
+        for size in sizes {
+            let payload: Vec<u8> = (0..size).map(|i| (i * 7) as u8).collect();
+            let start = rand::random::<u32>() as u64;
+            let data_segment =
+                DataSegment::new(Sequence::new(start), Bytes::from(payload)).unwrap();
+
+            let mut frame = BytesMut::new();
+            DataSegmentCodec
+                .encode(data_segment.clone(), &mut frame)
+                .unwrap();
+            assert_eq!(frame[..], encode(&data_segment).await[..]);
+
+            // Feed the frame in two pieces
+            let mut src = frame.split_to(size / 2 + 1);
+            assert!(DataSegmentCodec.decode(&mut src).unwrap().is_none());
+            src.unsplit(frame);
+            let decoded = DataSegmentCodec.decode(&mut src).unwrap().unwrap();
+            assert!(src.is_empty());
+            assert_eq!(decoded.start_sequence(), data_segment.start_sequence());
+            assert_eq!(decoded.payload(), data_segment.payload());
+
+            let mut reader = io::Cursor::new(encode(&data_segment).await);
+            let decoded = DataSegment::decode(&mut reader).await.unwrap();
+            assert_eq!(decoded.payload(), data_segment.payload());
+        }
+    }
+
+    #[test]
+    fn reject_empty_payload() {
+        let mut src = BytesMut::new();
+        src.put_u64(0);
+        src.put_u32(0);
+        let err = DataSegmentCodec.decode(&mut src).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
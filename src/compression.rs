@@ -0,0 +1,89 @@
+//! Compression of the payloads of data segments
+//!
+//! A sender only compresses a payload of at least `MIN_COMPRESSED_SIZE` bytes, and sends it as is if it would not shrink, so compressed and plain data segments mix on a subflow.
+//! The algorithms are behind cargo features, e.g., `lz4`, and a receiver built without one rejects the hello of a sender that uses it.
+
+use bytes::Bytes;
+
+/// Payloads smaller than this are sent as they are
+pub const MIN_COMPRESSED_SIZE: usize = 256;
+
+/// How the payloads of data segments are compressed on the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    #[default]
+    None,
+    /// The LZ4 block format, advertised as `CAPABILITY_LZ4`
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    /// The `CAPABILITY_*` bit of the hellos of a sender compressing this way
+    pub fn capability(self) -> u32 {
+        match self {
+            Self::None => 0,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => crate::message::CAPABILITY_LZ4,
+        }
+    }
+
+    /// `payload` compressed, or `None` if it is too small or would not take fewer bytes on the wire
+    pub fn compress(self, payload: &[u8]) -> Option<Bytes> {
+        if payload.len() < MIN_COMPRESSED_SIZE {
+            return None;
+        }
+        // The header of a compressed data segment is 4 bytes longer
+        self.encode(payload)
+            .filter(|compressed| compressed.len() + 4 <= payload.len())
+    }
+
+    /// `payload` compressed however large it turns out, or `None` without compression
+    #[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+    pub(crate) fn encode(self, payload: &[u8]) -> Option<Bytes> {
+        match self {
+            Self::None => None,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => Some(lz4_flex::block::compress(payload).into()),
+        }
+    }
+
+    /// The `size` bytes `compressed` came from, or `None` if it does not decompress to exactly that many
+    #[cfg(feature = "lz4")]
+    pub(crate) fn decode(self, compressed: &[u8], size: usize) -> Option<Bytes> {
+        match self {
+            Self::None => None,
+            Self::Lz4 => lz4_flex::block::decompress(compressed, size)
+                .ok()
+                .filter(|payload| payload.len() == size)
+                .map(Bytes::from),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "lz4"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_only_when_worth_it() {
+        let json = br#"{"id":1,"name":"sensor","values":[1,2,3]}"#.repeat(64);
+        let compressed = Compression::Lz4.compress(&json).unwrap();
+        assert!(compressed.len() < json.len() / 4);
+        assert_eq!(
+            Compression::Lz4.decode(&compressed, json.len()).unwrap(),
+            json
+        );
+        assert!(Compression::Lz4
+            .decode(&compressed, json.len() - 1)
+            .is_none());
+
+        let random: Vec<u8> = (0..1 << 12).map(|_| rand::random()).collect();
+        assert!(Compression::Lz4.compress(&random).is_none());
+        assert!(Compression::Lz4
+            .compress(&json[..MIN_COMPRESSED_SIZE - 1])
+            .is_none());
+        assert!(Compression::None.compress(&json).is_none());
+    }
+}
@@ -0,0 +1,460 @@
+use std::{io, net::SocketAddr, num::NonZeroUsize};
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use thiserror::Error;
+use tokio::{
+    io::{self as tokio_io, AsyncRead, AsyncWrite},
+    net::tcp,
+};
+
+use crate::{
+    auth::{self, SessionSecret},
+    factory::{PathInfo, StreamFactory, TcpFactory},
+    message::{Init, Session},
+    stream::{MptcpStream, SingleAddress},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Fewest subflows to settle for; every address must be reachable if `None`
+    pub min_subflows: Option<NonZeroUsize>,
+    /// Local address to bind the subflow to, indexed like the remote addresses
+    pub bind: Vec<Option<SocketAddr>>,
+}
+
+/// Sets up sessions, authenticating their subflows to listeners of `crate::MptcpListener::with_secret` if it has a secret
+#[derive(Debug, Default)]
+pub struct MptcpConnector {
+    secret: Option<SessionSecret>,
+}
+
+impl MptcpConnector {
+    /// Dial every address concurrently and set up one session over the subflows that connect
+    ///
+    /// The peer is told the number of subflows only after dialing, so a partial session is complete on both ends.
+    pub async fn connect(
+        addrs: &[SocketAddr],
+        options: ConnectOptions,
+    ) -> Result<Connected, ConnectError> {
+        Self::default().dial(addrs, options).await
+    }
+
+    /// `Self::connect` that dials every subflow through `factory`, e.g., to wrap each of them in TLS
+    pub async fn connect_with<F>(
+        factory: &F,
+        addrs: &[SocketAddr],
+        options: ConnectOptions,
+    ) -> Result<Connected<tokio_io::WriteHalf<F::Stream>>, ConnectError>
+    where
+        F: StreamFactory,
+    {
+        Self::default().dial_with(factory, addrs, options).await
+    }
+
+    /// A connector proving every subflow knows `secret`
+    ///
+    /// The listener has to share the secret: one without any leaves the handshake of a subflow hanging.
+    pub fn with_secret(secret: SessionSecret) -> Self {
+        Self {
+            secret: Some(secret),
+        }
+    }
+
+    /// `Self::connect` with the secret of this connector
+    pub async fn dial(
+        &self,
+        addrs: &[SocketAddr],
+        options: ConnectOptions,
+    ) -> Result<Connected, ConnectError> {
+        let Dialed { streams, failed } = dial_all(&TcpFactory, addrs, &options).await?;
+        let init = new_init(streams.len());
+        let mut read_streams = vec![];
+        let mut write_streams = vec![];
+        let mut peer_addr = None;
+        for (_, mut stream) in streams {
+            let addr = stream.peer_addr().map_err(ConnectError::Handshake)?;
+            self.handshake(&init, &mut stream)
+                .await
+                .map_err(ConnectError::Handshake)?;
+            peer_addr = Some(addr);
+            let (read, write) = stream.into_split();
+            read_streams.push(read);
+            write_streams.push(write);
+        }
+
+        let addr = SingleAddress::Peer(peer_addr.unwrap());
+        let stream = MptcpStream::from_split(read_streams, write_streams, addr, Some(&init));
+        Ok(Connected { stream, failed })
+    }
+
+    /// `Self::connect_with` with the secret of this connector
+    pub async fn dial_with<F>(
+        &self,
+        factory: &F,
+        addrs: &[SocketAddr],
+        options: ConnectOptions,
+    ) -> Result<Connected<tokio_io::WriteHalf<F::Stream>>, ConnectError>
+    where
+        F: StreamFactory,
+    {
+        let Dialed { streams, failed } = dial_all(factory, addrs, &options).await?;
+        let init = new_init(streams.len());
+        let mut read_streams = vec![];
+        let mut write_streams = vec![];
+        let mut peer_addr = None;
+        for (index, mut stream) in streams {
+            self.handshake(&init, &mut stream)
+                .await
+                .map_err(ConnectError::Handshake)?;
+            peer_addr = Some(addrs[index]);
+            let (read, write) = tokio_io::split(stream);
+            read_streams.push(read);
+            write_streams.push(write);
+        }
+
+        let addr = SingleAddress::Peer(peer_addr.unwrap());
+        let stream = MptcpStream::from_split(read_streams, write_streams, addr, Some(&init));
+        Ok(Connected { stream, failed })
+    }
+
+    /// Fails with `io::ErrorKind::PermissionDenied` if the listener rejects the proof and tells so
+    async fn handshake<S>(&self, init: &Init, stream: &mut S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        init.encode(stream).await?;
+        if let Some(secret) = &self.secret {
+            auth::prove(stream, init.session(), secret).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The subflows that connected, by the index of their address, and the addresses that could not be dialed
+struct Dialed<S> {
+    streams: Vec<(usize, S)>,
+    failed: Vec<DialError>,
+}
+
+async fn dial_all<F>(
+    factory: &F,
+    addrs: &[SocketAddr],
+    options: &ConnectOptions,
+) -> Result<Dialed<F::Stream>, ConnectError>
+where
+    F: StreamFactory,
+{
+    let mut dials: FuturesUnordered<_> = addrs
+        .iter()
+        .enumerate()
+        .map(|(index, &addr)| {
+            let local = options.bind.get(index).copied().flatten();
+            let path = PathInfo::new(index, addr, local);
+            async move { (index, factory.connect(&path).await) }
+        })
+        .collect();
+
+    let mut streams = vec![];
+    let mut failed = vec![];
+    while let Some((index, res)) = dials.next().await {
+        match res {
+            Ok(stream) => streams.push((index, stream)),
+            Err(error) => failed.push((index, DialError::new(addrs[index], error))),
+        }
+    }
+    streams.sort_by_key(|(index, _)| *index);
+    failed.sort_by_key(|(index, _)| *index);
+    let failed: Vec<DialError> = failed.into_iter().map(|(_, e)| e).collect();
+
+    let min_subflows = options.min_subflows.map_or(addrs.len(), |n| n.get());
+    if streams.is_empty() || streams.len() < min_subflows {
+        return Err(ConnectError::TooFewSubflows {
+            connected: streams.len(),
+            failed,
+        });
+    }
+    Ok(Dialed { streams, failed })
+}
+
+fn new_init(subflows: usize) -> Init {
+    let session = Session::new(rand::random());
+    Init::new(session, NonZeroUsize::new(subflows).unwrap())
+}
+
+#[derive(Debug)]
+pub struct Connected<W = tcp::OwnedWriteHalf> {
+    stream: MptcpStream<W>,
+    failed: Vec<DialError>,
+}
+
+impl<W> Connected<W> {
+    pub fn stream(&self) -> &MptcpStream<W> {
+        &self.stream
+    }
+
+    /// Addresses that could not be dialed
+    pub fn failed(&self) -> &[DialError] {
+        &self.failed
+    }
+
+    pub fn into_stream(self) -> MptcpStream<W> {
+        self.stream
+    }
+}
+
+#[derive(Debug)]
+pub struct DialError {
+    addr: SocketAddr,
+    error: io::Error,
+}
+
+impl DialError {
+    fn new(addr: SocketAddr, error: io::Error) -> Self {
+        Self { addr, error }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    pub fn into_error(self) -> io::Error {
+        self.error
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    #[error("Only {connected} subflows connected")]
+    TooFewSubflows {
+        connected: usize,
+        failed: Vec<DialError>,
+    },
+    #[error("Session handshake failed: {0}")]
+    Handshake(#[source] io::Error),
+}
+
+impl From<ConnectError> for io::Error {
+    fn from(e: ConnectError) -> Self {
+        let kind = match &e {
+            ConnectError::TooFewSubflows { failed, .. } => failed
+                .first()
+                .map(|e| e.error.kind())
+                .unwrap_or(io::ErrorKind::Other),
+            ConnectError::Handshake(e) => e.kind(),
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::TcpListener,
+    };
+
+    use crate::{auth::RejectPolicy, listen::MptcpListener};
+
+    use super::*;
+
+    /// Dials in memory and keeps the far ends of the subflows
+    #[derive(Debug, Default)]
+    struct MemoryFactory {
+        dials: AtomicUsize,
+        refused: Vec<usize>,
+        peers: Mutex<Vec<(usize, DuplexStream)>>,
+    }
+
+    impl StreamFactory for MemoryFactory {
+        type Stream = DuplexStream;
+
+        async fn connect(&self, path: &PathInfo) -> io::Result<DuplexStream> {
+            self.dials.fetch_add(1, Ordering::Relaxed);
+            if self.refused.contains(&path.index()) {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            let (stream, peer) = tokio::io::duplex(1 << 16);
+            self.peers.lock().unwrap().push((path.index(), peer));
+            Ok(stream)
+        }
+    }
+
+    async fn closed_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    async fn echo_once(listener: &mut MptcpListener, client: MptcpStream) {
+        let mut server = listener.accept().await.unwrap();
+        let (mut read, mut write) = client.into_split();
+        write.write_all(b"hello").await.unwrap();
+        write.flush().await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(&buf).await.unwrap();
+        server.flush().await.unwrap();
+        read.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn connect_all() {
+        let mut listener = MptcpListener::bind("127.0.0.1:0", NonZeroUsize::new(4).unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let local = "127.0.0.1:0".parse().unwrap();
+        let options = ConnectOptions {
+            bind: vec![Some(local), None, Some(local)],
+            ..Default::default()
+        };
+
+        let connected = MptcpConnector::connect(&[addr; 3], options).await.unwrap();
+        assert!(connected.failed().is_empty());
+        assert_eq!(connected.stream().peer_addr(), Some(addr));
+        echo_once(&mut listener, connected.into_stream()).await;
+    }
+
+    #[tokio::test]
+    async fn connect_partially() {
+        let mut listener = MptcpListener::bind("127.0.0.1:0", NonZeroUsize::new(4).unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let closed = closed_addr().await;
+        let addrs = [addr, closed, addr, closed];
+
+        let res = MptcpConnector::connect(&addrs, ConnectOptions::default()).await;
+        let Err(ConnectError::TooFewSubflows { connected, failed }) = res else {
+            panic!("expected too few subflows");
+        };
+        assert_eq!(connected, 2);
+        assert_eq!(failed.len(), 2);
+
+        let options = ConnectOptions {
+            min_subflows: NonZeroUsize::new(2),
+            ..Default::default()
+        };
+        let connected = MptcpConnector::connect(&addrs, options).await.unwrap();
+        let failed: Vec<SocketAddr> = connected.failed().iter().map(|e| e.addr()).collect();
+        assert_eq!(failed, [closed, closed]);
+        assert!(connected
+            .failed()
+            .iter()
+            .all(|e| e.error().kind() == io::ErrorKind::ConnectionRefused));
+        echo_once(&mut listener, connected.into_stream()).await;
+    }
+
+    #[tokio::test]
+    async fn connect_with_factory() {
+        let factory = MemoryFactory {
+            refused: vec![1],
+            ..Default::default()
+        };
+        let addrs: Vec<SocketAddr> = (1..=3)
+            .map(|i| format!("10.0.0.{i}:443").parse().unwrap())
+            .collect();
+        let options = ConnectOptions {
+            min_subflows: NonZeroUsize::new(2),
+            ..Default::default()
+        };
+
+        let connected = MptcpConnector::connect_with(&factory, &addrs, options.clone())
+            .await
+            .unwrap();
+        assert_eq!(factory.dials.load(Ordering::Relaxed), 3);
+        let failed: Vec<SocketAddr> = connected.failed().iter().map(|e| e.addr()).collect();
+        assert_eq!(failed, [addrs[1]]);
+        assert_eq!(connected.stream().peer_addr(), Some(addrs[2]));
+
+        let mut peers = std::mem::take(&mut *factory.peers.lock().unwrap());
+        peers.sort_by_key(|(index, _)| *index);
+        let mut server_streams = vec![];
+        for (_, mut peer) in peers {
+            let init = Init::decode(&mut peer).await.unwrap();
+            assert_eq!(init.streams().get(), 2);
+            server_streams.push(peer);
+        }
+        let mut server = MptcpStream::new(server_streams);
+        let mut client = connected.into_stream();
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Every dial goes through the factory
+        MptcpConnector::connect_with(&factory, &addrs, options)
+            .await
+            .unwrap();
+        assert_eq!(factory.dials.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test]
+    async fn connect_with_secret() {
+        let secret = SessionSecret::new("secret");
+        let mut listener = MptcpListener::with_secret(
+            "127.0.0.1:0",
+            NonZeroUsize::new(4).unwrap(),
+            secret.clone(),
+            RejectPolicy::ErrorFrame,
+        )
+        .await
+        .unwrap();
+        let addrs = [listener.local_addr().unwrap(); 2];
+
+        let connected = MptcpConnector::with_secret(secret)
+            .dial(&addrs, ConnectOptions::default())
+            .await
+            .unwrap();
+        echo_once(&mut listener, connected.into_stream()).await;
+
+        let res = MptcpConnector::with_secret(SessionSecret::new("wrong"))
+            .dial(&addrs, ConnectOptions::default())
+            .await;
+        let Err(ConnectError::Handshake(err)) = res else {
+            panic!("expected a handshake error");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn session_info() {
+        let mut listener = MptcpListener::bind("127.0.0.1:0", NonZeroUsize::new(4).unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connected = MptcpConnector::connect(&[addr; 2], ConnectOptions::default())
+            .await
+            .unwrap();
+        let mut client = connected.into_stream();
+        let mut server = listener.accept().await.unwrap();
+
+        let (client_info, server_info) = tokio::join!(client.session_info(), server.session_info());
+        let (client_info, server_info) = (client_info.unwrap(), server_info.unwrap());
+        assert!(client_info.session().is_some());
+        for info in [&client_info, &server_info] {
+            assert_eq!(info.session(), client_info.session());
+            assert_eq!(info.version(), crate::message::VERSION);
+            assert_eq!(info.peer_subflows(), NonZeroUsize::new(2));
+            assert_eq!(info.subflows().len(), 2);
+            assert!(!info.checksums());
+        }
+        for (client_subflow, server_subflow) in
+            client_info.subflows().iter().zip(server_info.subflows())
+        {
+            assert_eq!(client_subflow.peer_addr(), Some(addr));
+            assert!(server_subflow.peer_addr().is_some());
+            assert!(client_subflow.peer_hello().is_some());
+        }
+    }
+}
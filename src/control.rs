@@ -0,0 +1,118 @@
+//! A control channel beside the subflows
+//!
+//! A `Sender` and a `Receiver` given the two ends of one more stream, by `SenderBuilder::control_stream` and `ReceiverBuilder::control_stream`, exchange their control frames over it in both directions while the data goes over the subflows.
+//! The receiver acknowledges the byte stream on it, which feeds `Sender::control_acks`, and `Sender::send_control` and `Receiver::send_control` carry control frames on it.
+//! Without a control stream the acknowledgements have no way back to the sender, which then has nothing to drive `Sender::enable_retransmission` with unless the application carries them itself.
+
+use std::{
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinSet,
+};
+
+use crate::message::{Hello, Message};
+
+/// A stream able to carry a control channel
+pub trait ControlStream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T> ControlStream for T where T: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+/// A control stream waiting for a builder to start it, which the first build takes
+#[derive(Clone, Default)]
+pub(crate) struct PendingControl(Arc<Mutex<Option<Box<dyn ControlStream>>>>);
+
+impl PendingControl {
+    pub fn new(stream: impl ControlStream) -> Self {
+        Self(Arc::new(Mutex::new(Some(Box::new(stream)))))
+    }
+
+    pub fn take(&self) -> Option<Box<dyn ControlStream>> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl fmt::Debug for PendingControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PendingControl(..)")
+    }
+}
+
+/// Exchange messages over `stream` with the tasks spawned on `tasks`
+///
+/// Each side starts with a `Hello`. The messages of `outgoing` are written until it closes, and `incoming` is called with every message read until the stream ends.
+/// The channel goes down with its first error, after which the receiver of `outgoing` is dropped.
+pub(crate) fn spawn<S, F>(
+    tasks: &mut JoinSet<()>,
+    stream: S,
+    mut outgoing: mpsc::UnboundedReceiver<Message>,
+    mut incoming: F,
+) where
+    S: ControlStream,
+    F: FnMut(Message) + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    tasks.spawn(async move {
+        let _: io::Result<()> = async {
+            Hello::new(0).encode(&mut writer).await?;
+            writer.flush().await?;
+            while let Some(message) = outgoing.recv().await {
+                message.encode(&mut writer).await?;
+                // Whatever queued up meanwhile goes out with the same flush
+                while let Ok(message) = outgoing.try_recv() {
+                    message.encode(&mut writer).await?;
+                }
+                writer.flush().await?;
+            }
+            writer.shutdown().await
+        }
+        .await;
+    });
+    tasks.spawn(async move {
+        let _: io::Result<()> = async {
+            Hello::decode(&mut reader).await?;
+            while let Some(message) = Message::decode_next(&mut reader).await? {
+                incoming(message);
+            }
+            Ok(())
+        }
+        .await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn exchange() {
+        let (a, b) = tokio::io::duplex(1 << 10);
+        let mut tasks = JoinSet::new();
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        let (a_seen_tx, mut a_seen) = mpsc::unbounded_channel();
+        let (b_seen_tx, mut b_seen) = mpsc::unbounded_channel();
+        spawn(&mut tasks, a, a_rx, move |message| {
+            let _ = a_seen_tx.send(message);
+        });
+        spawn(&mut tasks, b, b_rx, move |message| {
+            let _ = b_seen_tx.send(message);
+        });
+
+        let control = Message::Control(Bytes::from_static(b"hi"));
+        a_tx.send(control.clone()).unwrap();
+        b_tx.send(Message::Ping).unwrap();
+        assert_eq!(b_seen.recv().await.unwrap(), control);
+        assert_eq!(a_seen.recv().await.unwrap(), Message::Ping);
+
+        // Closing one direction ends the reads of the other side
+        drop(a_tx);
+        assert!(b_seen.recv().await.is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    io,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+};
+
+/// A subflow over a connected UDP socket
+///
+/// Every flush sends what was written since the previous one as a single datagram, so each frame written by the `Sender` travels alone.
+/// Datagrams might be lost, duplicated or reordered; add it via `Sender::add_datagram_stream` so that lost segments are retransmitted.
+#[derive(Debug)]
+pub struct DatagramSubflow {
+    socket: Arc<UdpSocket>,
+    mtu: usize,
+    outgoing: BytesMut,
+    incoming: BytesMut,
+}
+
+impl DatagramSubflow {
+    /// `socket` must be connected to the peer
+    pub fn new(socket: Arc<UdpSocket>, mtu: NonZeroUsize) -> Self {
+        Self {
+            socket,
+            mtu: mtu.get(),
+            outgoing: BytesMut::new(),
+            incoming: BytesMut::new(),
+        }
+    }
+
+    /// The largest datagram sent or received
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+impl AsyncWrite for DatagramSubflow {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.mtu < this.outgoing.len() + buf.len() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram exceeds the MTU",
+            )));
+        }
+        this.outgoing.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.outgoing.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(this.socket.poll_send(cx, &this.outgoing))?;
+        this.outgoing.clear();
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncRead for DatagramSubflow {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.incoming.is_empty() {
+            let mut datagram = vec![0; this.mtu];
+            let mut datagram_buf = ReadBuf::new(&mut datagram);
+            ready!(this.socket.poll_recv(cx, &mut datagram_buf))?;
+            let len = datagram_buf.filled().len();
+            this.incoming.extend_from_slice(&datagram[..len]);
+        }
+        let n = this.incoming.len().min(buf.remaining());
+        buf.put_slice(&this.incoming[..n]);
+        this.incoming.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::io::AsyncReadExt;
+
+    use crate::{receiver::Receiver, sender::Sender};
+
+    use super::*;
+
+    type BoxWriter = Box<dyn AsyncWrite + Unpin + Send>;
+    type BoxReader = Box<dyn AsyncRead + Unpin + Send>;
+
+    /// Forward the datagrams received on `socket` to `to`, dropping every third data segment after the handshake
+    async fn lossy_relay(socket: UdpSocket, to: std::net::SocketAddr) {
+        let mut buf = vec![0; 1 << 16];
+        let mut datagrams = 0;
+        let mut data_segments = 0;
+        loop {
+            let Ok(n) = socket.recv(&mut buf).await else {
+                return;
+            };
+            datagrams += 1;
+            if datagrams > 1 && matches!(buf[0], 0 | 3) {
+                data_segments += 1;
+                if data_segments % 3 == 0 {
+                    continue;
+                }
+            }
+            let _ = socket.send_to(&buf[..n], to).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn retransmit_lost_datagrams() {
+        let mtu = NonZeroUsize::new(1200).unwrap();
+        let receive = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let send = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        send.connect(relay.local_addr().unwrap()).await.unwrap();
+        receive.connect(send.local_addr().unwrap()).await.unwrap();
+        tokio::spawn(lossy_relay(relay, receive.local_addr().unwrap()));
+
+        let (reliable_tx, reliable_rx) = tokio::io::duplex(1 << 16);
+        let datagram_tx = DatagramSubflow::new(Arc::new(send), mtu);
+        let datagram_rx = DatagramSubflow::new(Arc::new(receive), mtu);
+
+        let mut sender: Sender<BoxWriter> = Sender::new(vec![Box::new(reliable_tx)]);
+        sender.add_datagram_stream(Box::new(datagram_tx), mtu);
+        let recv_streams: Vec<BoxReader> = vec![Box::new(reliable_rx), Box::new(datagram_rx)];
+        let receiver = Receiver::new(recv_streams);
+        sender.enable_retransmission(receiver.acks(), NonZeroUsize::new(1 << 20).unwrap());
+        sender.set_loss_timeout(Duration::from_millis(50));
+
+        let mut async_read = receiver.into_async_read();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            async_read.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+
+        let msg: Vec<u8> = (0..1 << 18).map(|_| rand::random()).collect();
+        for chunk in msg.chunks(1 << 14) {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.flush().await.unwrap();
+        sender.shutdown().await.unwrap();
+
+        let buf = tokio::time::timeout(Duration::from_secs(10), recv_task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, msg);
+    }
+}
@@ -0,0 +1,72 @@
+use std::{future::Future, io, net::SocketAddr};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpSocket, TcpStream},
+};
+
+/// Where a subflow goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathInfo {
+    index: usize,
+    remote: SocketAddr,
+    local: Option<SocketAddr>,
+}
+
+impl PathInfo {
+    pub fn new(index: usize, remote: SocketAddr, local: Option<SocketAddr>) -> Self {
+        Self {
+            index,
+            remote,
+            local,
+        }
+    }
+
+    /// The position of the path among the addresses of the session
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Local address to bind the subflow to
+    pub fn local(&self) -> Option<SocketAddr> {
+        self.local
+    }
+}
+
+/// Establishes the subflows of a session
+///
+/// Every subflow is dialed through the factory, so wrapping each connection in TLS, SOCKS, etc. is just an implementation of it.
+pub trait StreamFactory: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn connect(&self, path: &PathInfo) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+}
+
+/// Dial plain TCP with `TCP_NODELAY`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpFactory;
+
+impl StreamFactory for TcpFactory {
+    type Stream = TcpStream;
+
+    async fn connect(&self, path: &PathInfo) -> io::Result<TcpStream> {
+        let addr = path.remote();
+        let stream = match path.local() {
+            Some(local) => {
+                let socket = match addr {
+                    SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                    SocketAddr::V6(_) => TcpSocket::new_v6()?,
+                };
+                socket.bind(local)?;
+                socket.connect(addr).await?
+            }
+            None => TcpStream::connect(addr).await?,
+        };
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
@@ -0,0 +1,105 @@
+//! Named points where a test makes the crate fail on purpose, behind the `failpoints` feature
+//!
+//! Without the feature nothing of it is compiled in. A point does nothing until armed with `arm`:
+//!
+//! | Name                 | Where                                              | `FailAction::Error` | `FailAction::Delay` |
+//! |----------------------|----------------------------------------------------|---------------------|---------------------|
+//! | `send.segment_write` | every data segment a sender writes on a subflow    | fails the write like the stream would, which evicts the stream | holds the write up |
+//! | `reconnect.dial`     | every dial attempt of `Sender::set_reconnect`      | fails the attempt, which backs off as the policy says | holds the attempt up |
+//! | `recv.handshake`     | a receiver reading the handshake of a subflow      | ends the subflow with the error in its `SubflowError` | holds the read up |
+//! | `recv.gap_timer`     | `Receiver::recv` waiting for data                  | fails it at once with `RecvError::MissingSegment` in an error of that kind | makes the wait time out like a gap timeout of that long |
+//!
+//! The points are armed for the whole process, so a test arming them should not run beside tests that do not expect them, e.g., in a test binary of their own.
+//!
+//! ```
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! use std::io::ErrorKind;
+//!
+//! use bytes::Bytes;
+//! use mptcp::{
+//!     failpoints::{self, FailAction},
+//!     sender::{SendError, Sender},
+//! };
+//!
+//! failpoints::arm("send.segment_write", FailAction::Error(ErrorKind::ConnectionReset));
+//! let (tx, _rx) = tokio::io::duplex(1 << 10);
+//! let mut sender = Sender::new(vec![tx]);
+//! // The write fails, which evicts the only stream
+//! let res = sender.batch_send_all(Bytes::from_static(b"hello")).await;
+//! assert!(matches!(res, Err(SendError::Incomplete { sent: 0, .. })));
+//! let res = sender.batch_send_all(Bytes::from_static(b"hello")).await;
+//! assert!(matches!(res, Err(SendError::NoStreamLeft { .. })));
+//! failpoints::disarm("send.segment_write");
+//! # }
+//! ```
+
+use std::{io, sync::Mutex, time::Duration};
+
+pub const SEND_SEGMENT_WRITE: &str = "send.segment_write";
+pub const RECONNECT_DIAL: &str = "reconnect.dial";
+pub const RECV_HANDSHAKE: &str = "recv.handshake";
+pub const RECV_GAP_TIMER: &str = "recv.gap_timer";
+
+const POINTS: [&str; 4] = [
+    SEND_SEGMENT_WRITE,
+    RECONNECT_DIAL,
+    RECV_HANDSHAKE,
+    RECV_GAP_TIMER,
+];
+
+static ARMED: Mutex<Vec<(&str, FailAction)>> = Mutex::new(Vec::new());
+
+/// What an armed point does every time it is passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailAction {
+    /// Fail with an error of this kind
+    Error(io::ErrorKind),
+    /// Wait this long and carry on
+    Delay(Duration),
+}
+
+/// Arm the point `name` with `action` until `disarm`, in place of its action so far
+///
+/// # Panics
+///
+/// Panics if `name` is not one of the points above.
+pub fn arm(name: &str, action: FailAction) {
+    let Some(name) = POINTS.into_iter().find(|&point| point == name) else {
+        panic!("no failpoint named {name:?}");
+    };
+    let mut armed = ARMED.lock().unwrap();
+    armed.retain(|&(point, _)| point != name);
+    armed.push((name, action));
+}
+
+/// Disarm the point `name`
+pub fn disarm(name: &str) {
+    ARMED.lock().unwrap().retain(|&(point, _)| point != name);
+}
+
+/// Disarm every point
+pub fn disarm_all() {
+    ARMED.lock().unwrap().clear();
+}
+
+/// The action of the point `name` if it is armed
+pub(crate) fn armed(name: &str) -> Option<FailAction> {
+    let armed = ARMED.lock().unwrap();
+    armed
+        .iter()
+        .find(|&&(point, _)| point == name)
+        .map(|&(_, action)| action)
+}
+
+/// Pass the point `name`, failing or waiting if it is armed
+pub(crate) async fn hit(name: &str) -> io::Result<()> {
+    match armed(name) {
+        None => Ok(()),
+        Some(FailAction::Error(kind)) => Err(io::Error::new(kind, format!("failpoint {name}"))),
+        Some(FailAction::Delay(delay)) => {
+            tokio::time::sleep(delay).await;
+            Ok(())
+        }
+    }
+}
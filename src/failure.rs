@@ -0,0 +1,244 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use tokio::io::{AsyncWrite, BufWriter};
+
+use crate::message::{EncodeOptions, Message, Sequence};
+
+/// Write attempts retried in place by default before the stream is evicted
+pub const DEFAULT_MAX_WRITE_RETRIES: u32 = 3;
+
+/// Decides whether a failed write on a stream is worth another attempt on that stream
+pub trait FailurePolicy: std::fmt::Debug + Send + Sync {
+    fn classify(&self, error: &io::Error) -> FailureAction;
+}
+
+/// What a `Sender` does with a stream whose write failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureAction {
+    /// Write the same bytes on the same stream again
+    Retry,
+    /// Give the stream up and move its data to the others
+    Evict,
+}
+
+/// Retries `io::ErrorKind::Interrupted` and evicts on any other error, e.g., `BrokenPipe` or `ConnectionReset`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultFailurePolicy;
+
+impl FailurePolicy for DefaultFailurePolicy {
+    fn classify(&self, error: &io::Error) -> FailureAction {
+        match error.kind() {
+            io::ErrorKind::Interrupted => FailureAction::Retry,
+            _ => FailureAction::Evict,
+        }
+    }
+}
+
+/// A stream whose failed polls are retried right away as long as the policy allows
+///
+/// A failed write has written nothing, so the bytes of a retried write are not duplicated.
+#[derive(Debug)]
+pub(crate) struct Retrying<W> {
+    inner: W,
+    policy: Arc<dyn FailurePolicy>,
+    max_retries: u32,
+}
+
+impl<W> Retrying<W> {
+    pub fn new(inner: W, policy: Arc<dyn FailurePolicy>, max_retries: u32) -> Self {
+        Self {
+            inner,
+            policy,
+            max_retries,
+        }
+    }
+
+    fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    #[cfg(test)]
+    fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn poll_retrying<T>(
+        &mut self,
+        mut poll: impl FnMut(Pin<&mut W>) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>>
+    where
+        W: Unpin,
+    {
+        let mut retries = 0;
+        loop {
+            match ready!(poll(Pin::new(&mut self.inner))) {
+                Err(e)
+                    if retries < self.max_retries
+                        && self.policy.classify(&e) == FailureAction::Retry =>
+                {
+                    retries += 1;
+                }
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+impl<W> AsyncWrite for Retrying<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_retrying(|inner| inner.poll_write(cx, buf))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_retrying(|inner| inner.poll_write_vectored(cx, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_retrying(|inner| inner.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_retrying(|inner| inner.poll_shutdown(cx))
+    }
+}
+
+/// A frame of a subflow, to tell whether a retried write is the one torn
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Frame {
+    Hello,
+    Message(Message, EncodeOptions),
+    /// Passthrough bytes, escaped, of the payload from a sequence
+    Passthrough(Sequence, Bytes),
+    /// The switch from passthrough bytes to frames
+    Switch,
+}
+
+/// The buffered stream of a subflow, keeping count of the bytes of the current frame it accepted
+///
+/// A write failing halfway through a frame leaves the frame torn.
+/// Writing the same frame again resumes it right after the bytes accepted before, so that the peer sees it whole and once.
+/// The stream cannot take any other frame after a torn one: that frame goes to another stream from scratch and this one is to be evicted.
+#[derive(Debug)]
+pub(crate) struct FrameWriter<W> {
+    inner: BufWriter<Retrying<W>>,
+    torn: Option<Frame>,
+    /// Bytes of the current frame accepted by an earlier attempt, not to be written again
+    skip: usize,
+    /// Bytes of the current frame accepted so far
+    accepted: usize,
+}
+
+impl<W> FrameWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Unbuffered with a `capacity` of zero
+    pub fn new(
+        inner: W,
+        capacity: usize,
+        policy: Arc<dyn FailurePolicy>,
+        max_retries: u32,
+    ) -> Self {
+        let inner = Retrying::new(inner, policy, max_retries);
+        Self {
+            inner: BufWriter::with_capacity(capacity, inner),
+            torn: None,
+            skip: 0,
+            accepted: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref().get_ref()
+    }
+
+    #[cfg(test)]
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.get_mut().get_mut()
+    }
+
+    /// The stream, dropping whatever is buffered
+    pub fn into_inner(self) -> W {
+        self.inner.into_inner().into_inner()
+    }
+}
+
+impl<W> FrameWriter<W> {
+    /// Get ready to write `frame`, resuming it if it is the one torn
+    ///
+    /// Fails if another frame is torn.
+    pub fn begin(&mut self, frame: Frame) -> io::Result<()> {
+        match &self.torn {
+            Some(torn) if *torn == frame => self.skip = self.accepted,
+            Some(_) => return Err(io::Error::other("stream left with a torn frame")),
+            None => self.skip = 0,
+        }
+        self.accepted = 0;
+        self.torn = Some(frame);
+        Ok(())
+    }
+
+    /// The frame begun last has been written whole
+    pub fn end(&mut self) {
+        self.torn = None;
+    }
+}
+
+impl<W> AsyncWrite for FrameWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.skip > 0 {
+            // Accepted by the attempt that tore the frame
+            let n = buf.len().min(this.skip);
+            this.skip -= n;
+            this.accepted += n;
+            return Poll::Ready(Ok(n));
+        }
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.accepted += n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
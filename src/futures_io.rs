@@ -0,0 +1,495 @@
+//! A sender and a receiver over `futures_io`, behind the `futures-io` feature
+//!
+//! `Sender` and `Receiver` speak the wire format of `crate::sender::Sender` and `crate::receiver::Receiver` over streams implementing `futures_io::AsyncWrite` and `futures_io::AsyncRead`, which async-std, smol and most executors other than tokio provide.
+//! They spawn no task and set no timer: the subflows only move while the sender or the receiver is polled, from whichever task does it.
+//! The segments come out of a `SendStreamBuf`, go on the wire as `Frame`s and are put back in order by a `RecvStreamBuf`, like on the tokio path.
+//!
+//! What needs a background task or a clock is left out: acknowledgements, retransmission, keepalives, gap timeouts and pacing.
+//! A subflow that fails fails the whole transfer since its segments have nowhere else to come from.
+//!
+//! Nothing here needs tokio: with `--no-default-features --features futures-io` the crate leaves out the tokio driver behind the default `tokio` feature and keeps this module, `crate::message` without its async codec, `crate::wire` and the stream buffers.
+
+use std::{
+    io,
+    num::NonZeroUsize,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    message::{DataSegment, EncodeOptions, Hello, Message, Sequence},
+    recv_buf::RecvStreamBuf,
+    send_buf::SendStreamBuf,
+    wire::{Frame, HeaderContext, SeqWidth, DEFAULT_MAX_PAYLOAD_SIZE},
+};
+
+/// The default of `Sender::set_max_segment_size`
+const DEFAULT_MAX_SEGMENT_SIZE: usize = 1 << 16;
+
+/// Bytes a `Sender` takes from writes before it waits for its subflows to drain them
+const SEND_BUFFER_SIZE: usize = 1 << 20;
+
+/// Bytes a `Receiver` reads from a subflow at a time
+const READ_SIZE: usize = 1 << 16;
+
+/// Writes a byte stream over several `futures_io::AsyncWrite` subflows
+///
+/// Every write goes to the subflow that is first ready to take the next segment.
+/// Closing the sender writes a FIN and a shutdown on every subflow before closing them, so that the receiver sees the end of the byte stream.
+#[derive(Debug)]
+pub struct Sender<W> {
+    streams: Vec<SendSubflow<W>>,
+    send_buf: SendStreamBuf,
+    max_segment_size: usize,
+    closing: bool,
+}
+
+#[derive(Debug)]
+struct SendSubflow<W> {
+    stream: W,
+    context: HeaderContext,
+    /// Encoded frames not written yet
+    pending: BytesMut,
+    closed: bool,
+}
+
+impl<W> Sender<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(streams: Vec<W>) -> Self {
+        let streams = streams
+            .into_iter()
+            .map(|stream| {
+                let mut pending = BytesMut::new();
+                Hello::new(0).put(&mut pending);
+                SendSubflow {
+                    stream,
+                    context: HeaderContext::new(),
+                    pending,
+                    closed: false,
+                }
+            })
+            .collect();
+        Self {
+            streams,
+            send_buf: SendStreamBuf::with_capacity_limit(Sequence::new(0), SEND_BUFFER_SIZE),
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            closing: false,
+        }
+    }
+
+    /// Split the data into segments of at most `size` bytes from the next write on
+    pub fn set_max_segment_size(&mut self, size: NonZeroUsize) {
+        self.max_segment_size = size.get();
+    }
+
+    /// Where the next byte written goes in the byte stream
+    pub fn next_sequence(&self) -> Sequence {
+        self.send_buf.end_sequence()
+    }
+
+    pub fn into_inner(self) -> Vec<W> {
+        self.streams
+            .into_iter()
+            .map(|subflow| subflow.stream)
+            .collect()
+    }
+
+    /// Write as much of the buffered data as the subflows take
+    ///
+    /// Ready once every segment is written to some subflow.
+    fn poll_drive(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut idle = true;
+        for subflow in &mut self.streams {
+            loop {
+                if subflow.pending.is_empty() {
+                    let Some(data_segment) = self.send_buf.peek_next_unsent() else {
+                        break;
+                    };
+                    let sequence = data_segment.start_sequence();
+                    let end = data_segment.end_sequence();
+                    Frame::new(Message::DataSegment(data_segment), EncodeOptions::default())
+                        .encode_in(&mut subflow.pending, &mut subflow.context)?;
+                    self.send_buf.mark_as_sent(sequence);
+                    // Nothing is ever written twice, so the data is released as soon as it is encoded
+                    self.send_buf.mark_as_acked(end);
+                }
+                match subflow.poll_write_pending(cx)? {
+                    Poll::Ready(()) => (),
+                    Poll::Pending => {
+                        idle = false;
+                        break;
+                    }
+                }
+            }
+        }
+        if idle {
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    }
+}
+
+impl<W> SendSubflow<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Ready once every pending byte is written
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = match Pin::new(&mut self.stream).poll_write(cx, &self.pending) {
+                Poll::Ready(res) => res?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> AsyncWrite for Sender<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.closing {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "sender closed",
+            )));
+        }
+        if this.streams.is_empty() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no streams",
+            )));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        // Make room by handing what is buffered to the subflows
+        let drained = this.poll_drive(cx)?.is_ready();
+        let room = SEND_BUFFER_SIZE.saturating_sub(this.send_buf.resident_bytes());
+        if room == 0 {
+            debug_assert!(!drained);
+            return Poll::Pending;
+        }
+        let n = buf.len().min(room);
+        this.send_buf
+            .push(buf[..n].to_vec().into())
+            .expect("within the capacity limit");
+        this.send_buf.limit_segment_size(this.max_segment_size);
+        // Get the new data going without waiting for a flush
+        let _ = this.poll_drive(cx)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.poll_drive(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        let mut flushed = true;
+        for subflow in this.streams.iter_mut().filter(|subflow| !subflow.closed) {
+            flushed &= Pin::new(&mut subflow.stream).poll_flush(cx)?.is_ready();
+        }
+        if flushed {
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.poll_drive(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        if !this.closing {
+            this.closing = true;
+            let fin = this.send_buf.end_sequence();
+            for subflow in &mut this.streams {
+                for message in [Message::Fin(fin), Message::Shutdown] {
+                    Frame::new(message, EncodeOptions::default())
+                        .encode_in(&mut subflow.pending, &mut subflow.context)?;
+                }
+            }
+        }
+        let mut closed = true;
+        for subflow in this.streams.iter_mut().filter(|subflow| !subflow.closed) {
+            if subflow.poll_write_pending(cx)?.is_pending() {
+                closed = false;
+                continue;
+            }
+            match Pin::new(&mut subflow.stream).poll_close(cx)? {
+                Poll::Ready(()) => subflow.closed = true,
+                Poll::Pending => closed = false,
+            }
+        }
+        if closed {
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    }
+}
+
+/// Reads the byte stream of a `Sender`, or of a `crate::sender::Sender`, off several `futures_io::AsyncRead` subflows
+///
+/// Reads end once every byte up to the FIN is read, and fail with `io::ErrorKind::UnexpectedEof` if the subflows all end before it.
+/// Only subflows of `SeqWidth::U64` are accepted.
+#[derive(Debug)]
+pub struct Receiver<R> {
+    streams: Vec<RecvSubflow<R>>,
+    recv_buf: RecvStreamBuf,
+    /// The rest of a segment popped by a read too short for it
+    leftover: Option<DataSegment>,
+    subflow_buffer_limit: usize,
+}
+
+#[derive(Debug)]
+struct RecvSubflow<R> {
+    stream: R,
+    /// Read and not decoded yet
+    buf: BytesMut,
+    /// Set once the hello is decoded
+    context: Option<HeaderContext>,
+    ended: bool,
+}
+
+impl<R> Receiver<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(streams: Vec<R>) -> Self {
+        Self::with_subflow_buffer_limit(streams, NonZeroUsize::MAX)
+    }
+
+    /// Stop reading a subflow while `limit` bytes it carried wait in the reassembly buffer for a gap before them
+    ///
+    /// The subflow carrying the gap holds nothing in the buffer, so it is still read and the transfer goes on.
+    pub fn with_subflow_buffer_limit(streams: Vec<R>, limit: NonZeroUsize) -> Self {
+        let streams = streams
+            .into_iter()
+            .map(|stream| RecvSubflow {
+                stream,
+                buf: BytesMut::new(),
+                context: None,
+                ended: false,
+            })
+            .collect();
+        Self {
+            streams,
+            recv_buf: RecvStreamBuf::new(),
+            leftover: None,
+            subflow_buffer_limit: limit.get(),
+        }
+    }
+
+    /// The sequence of the next byte to read
+    pub fn next_sequence(&self) -> Sequence {
+        match &self.leftover {
+            Some(leftover) => leftover.start_sequence(),
+            None => self.recv_buf.next(),
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<R> {
+        self.streams
+            .into_iter()
+            .map(|subflow| subflow.stream)
+            .collect()
+    }
+
+    /// Read from every subflow that has data until one of them made progress
+    ///
+    /// Ready with `false` once every subflow has ended.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        let mut live = false;
+        for (index, subflow) in self.streams.iter_mut().enumerate() {
+            if subflow.ended
+                || self.subflow_buffer_limit <= self.recv_buf.subflow_buffered_bytes(index)
+            {
+                live |= !subflow.ended;
+                continue;
+            }
+            live = true;
+            let filled = subflow.buf.len();
+            subflow.buf.resize(filled + READ_SIZE, 0);
+            let res = Pin::new(&mut subflow.stream).poll_read(cx, &mut subflow.buf[filled..]);
+            let read = match res {
+                Poll::Ready(Ok(read)) => read,
+                Poll::Ready(Err(e)) => {
+                    subflow.buf.truncate(filled);
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => {
+                    subflow.buf.truncate(filled);
+                    continue;
+                }
+            };
+            subflow.buf.truncate(filled + read);
+            if read == 0 {
+                subflow.ended = true;
+                if !subflow.buf.is_empty() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ended within a frame",
+                    )));
+                }
+            } else {
+                subflow.decode(index, &mut self.recv_buf)?;
+            }
+            return Poll::Ready(Ok(true));
+        }
+        if live {
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl<R> RecvSubflow<R> {
+    /// Take every whole frame off the read bytes
+    fn decode(&mut self, index: usize, recv_buf: &mut RecvStreamBuf) -> io::Result<()> {
+        let context = match &mut self.context {
+            Some(context) => context,
+            None => {
+                let Some(hello) = Hello::decode_buf(&mut self.buf)? else {
+                    return Ok(());
+                };
+                hello.expect_sequence_width(SeqWidth::U64)?;
+                self.context.insert(HeaderContext::new())
+            }
+        };
+        while !self.ended {
+            context.observe(recv_buf.next());
+            let Some(frame) = Frame::decode_in(&mut self.buf, context, DEFAULT_MAX_PAYLOAD_SIZE)?
+            else {
+                break;
+            };
+            match Message::from(frame) {
+                Message::DataSegment(data_segment) => recv_buf.insert_from(index, data_segment),
+                Message::Fin(fin) => recv_buf.set_fin(fin),
+                Message::Shutdown => self.ended = true,
+                // Nothing to answer them with
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R> AsyncRead for Receiver<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        mut buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            let mut filled = 0;
+            while !buf.is_empty() {
+                let Some(data_segment) = this.leftover.take().or_else(|| this.recv_buf.pop_first())
+                else {
+                    break;
+                };
+                let n = data_segment.size().min(buf.len());
+                buf.put_slice(&data_segment.payload()[..n]);
+                filled += n;
+                this.leftover = data_segment.advance(n);
+            }
+            if filled > 0 || this.recv_buf.finished() {
+                return Poll::Ready(Ok(filled));
+            }
+            match this.poll_fill(cx)? {
+                Poll::Ready(true) => continue,
+                Poll::Ready(false) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "all streams ended before FIN",
+                    )))
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for _ in 0..3 {
+            let (tx, rx) = tokio::io::duplex(1 << 10);
+            send_streams.push(tx.compat());
+            recv_streams.push(rx.compat());
+        }
+        let mut sender = Sender::new(send_streams);
+        sender.set_max_segment_size(NonZeroUsize::new(100).unwrap());
+        let mut receiver =
+            Receiver::with_subflow_buffer_limit(recv_streams, NonZeroUsize::new(1000).unwrap());
+
+        let data: Vec<u8> = (0..1 << 16).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let send = tokio::spawn(async move {
+            sender.write_all(&data).await.unwrap();
+            sender.close().await.unwrap();
+            sender
+        });
+        let mut received = vec![];
+        // Short reads leave the rest of a segment for the next one
+        let mut buf = [0; 33];
+        loop {
+            let n = receiver.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(received, expected);
+        assert_eq!(receiver.next_sequence(), Sequence::new(1 << 16));
+        let sender = send.await.unwrap();
+        assert_eq!(sender.next_sequence(), Sequence::new(1 << 16));
+
+        let mut buf = [0; 1];
+        assert_eq!(receiver.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn streams_ending_before_fin() {
+        let (tx, rx) = tokio::io::duplex(1 << 10);
+        let mut sender = Sender::new(vec![tx.compat()]);
+        let mut receiver = Receiver::new(vec![rx.compat()]);
+        sender.write_all(b"hello").await.unwrap();
+        sender.flush().await.unwrap();
+        drop(sender);
+        let mut buf = [0; 5];
+        receiver.read_exact(&mut buf).await.unwrap();
+        let err = receiver.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
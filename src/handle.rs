@@ -0,0 +1,622 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    num::NonZeroUsize,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::AsyncWrite,
+    select,
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    message::Sequence,
+    receiver::sleep_until,
+    sender::{SendError, Sender},
+};
+
+/// Commands queued to the task by default before `SenderHandle::send` waits
+pub const DEFAULT_HANDLE_CAPACITY: usize = 32;
+
+enum Command {
+    Send(Bytes),
+    SendTracked(Bytes, oneshot::Sender<Completion>),
+    Flush(oneshot::Sender<Result<(), SendError>>),
+    Close(oneshot::Sender<Result<(), SendError>>),
+}
+
+/// The lane a message queued by `SenderHandle::send_with_priority` waits in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessagePriority {
+    /// Goes out ahead of every normal message not started yet
+    High,
+    #[default]
+    Normal,
+}
+
+/// How far a message queued by `SenderHandle::send_tracked` went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// Written to the streams, without retransmission to learn about its delivery
+    Written { at: Instant },
+    /// Acknowledged by the receiver, `rtt` after it was written
+    Acked { rtt: Duration },
+}
+
+/// The queueing of a message by `SenderHandle::send_tracked`, which fails like `SenderHandle::send`
+#[must_use = "the message is only queued once awaited"]
+pub struct SendFuture(Pin<Box<dyn Future<Output = Result<(), SendError>> + Send>>);
+
+impl Future for SendFuture {
+    type Output = Result<(), SendError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+impl std::fmt::Debug for SendFuture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SendFuture(..)")
+    }
+}
+
+/// Resolves once the message of `SenderHandle::send_tracked` is complete
+///
+/// Fails with `SendError::Stopped` if the task stops first, or if the message never got queued.
+/// Dropping it does not hold anything up.
+#[derive(Debug)]
+pub struct CompletionHandle(oneshot::Receiver<Completion>);
+
+impl Future for CompletionHandle {
+    type Output = Result<Completion, SendError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map_err(|_| SendError::Stopped)
+    }
+}
+
+/// A clonable front end of a `Sender` running on a task of its own
+///
+/// Every handle queues its commands to the same task, so the data sent through all of them in one lane is in the order the sends returned.
+/// Once every handle is dropped, the task sends what is queued, shuts the streams down and returns.
+/// With `Sender::set_keepalive`, the task also sends the heartbeats in between, on a single timer for all the streams.
+#[derive(Debug, Clone)]
+pub struct SenderHandle {
+    commands: mpsc::Sender<Command>,
+    /// The lane of `MessagePriority::High`, which the task takes from first
+    urgent: mpsc::Sender<Bytes>,
+}
+
+impl SenderHandle {
+    /// Queue `data` to be sent after the data queued before
+    ///
+    /// Waits while the queue is full. A failed send stops the task, which returns its error, and every handle then fails with `SendError::Stopped`.
+    pub async fn send(&self, data: Bytes) -> Result<(), SendError> {
+        self.send_with_priority(data, MessagePriority::Normal).await
+    }
+
+    /// `Self::send` in the lane of `priority`
+    ///
+    /// A message of `MessagePriority::High` is sent as soon as the message being sent is done, ahead of the normal ones queued before it, and waits only for a full queue of high ones.
+    /// Messages are never split, so the priorities only reorder whole messages on the byte stream, whose sequences stay in order: a receiver must tell the messages apart by its own framing, since one of them no longer follows the message queued right before it.
+    /// Flushes and the close wait for the normal lane only.
+    pub async fn send_with_priority(
+        &self,
+        data: Bytes,
+        priority: MessagePriority,
+    ) -> Result<(), SendError> {
+        let res = match priority {
+            MessagePriority::High => self.urgent.send(data).await.map_err(drop),
+            MessagePriority::Normal => self.commands.send(Command::Send(data)).await.map_err(drop),
+        };
+        res.map_err(|()| SendError::Stopped)
+    }
+
+    /// `Self::send` of a message whose completion the returned handle reports
+    ///
+    /// The message is complete once written to the streams or, if the sender has `Sender::enable_retransmission`, once acknowledged.
+    /// Completions come in the order of the messages on the byte stream.
+    pub fn send_tracked(&self, data: Bytes) -> (SendFuture, CompletionHandle) {
+        let (completion_tx, completion_rx) = oneshot::channel();
+        let commands = self.commands.clone();
+        let send = async move {
+            let command = Command::SendTracked(data, completion_tx);
+            commands.send(command).await.map_err(|_| SendError::Stopped)
+        };
+        (SendFuture(Box::pin(send)), CompletionHandle(completion_rx))
+    }
+
+    /// Wait until the data queued before is sent and flushed
+    ///
+    /// See `Sender::flush`.
+    pub async fn flush(&self) -> Result<(), SendError> {
+        self.request(Command::Flush).await
+    }
+
+    /// Send the data queued before, shut the streams down and stop the task
+    ///
+    /// Fails with `SendError::Stopped` if the shutdown fails, whose error the task returns.
+    /// Every handle fails with `SendError::Stopped` from then on.
+    pub async fn close(&self) -> Result<(), SendError> {
+        self.request(Command::Close).await
+    }
+
+    async fn request<F>(&self, command: F) -> Result<(), SendError>
+    where
+        F: FnOnce(oneshot::Sender<Result<(), SendError>>) -> Command,
+    {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.commands
+            .send(command(reply_tx))
+            .await
+            .map_err(|_| SendError::Stopped)?;
+        reply_rx.await.map_err(|_| SendError::Stopped)?
+    }
+}
+
+impl<W> Sender<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    /// `Self::spawn_with_capacity` with `DEFAULT_HANDLE_CAPACITY`
+    pub fn spawn(self) -> (SenderHandle, JoinHandle<Result<(), SendError>>) {
+        self.spawn_with_capacity(NonZeroUsize::new(DEFAULT_HANDLE_CAPACITY).unwrap())
+    }
+
+    /// Move the sender to a task fed by the returned handle
+    ///
+    /// At most `capacity` commands wait in the queue of each lane. The task returns the first error of a send or of the final shutdown.
+    pub fn spawn_with_capacity(
+        self,
+        capacity: NonZeroUsize,
+    ) -> (SenderHandle, JoinHandle<Result<(), SendError>>) {
+        let (commands_tx, commands_rx) = mpsc::channel(capacity.get());
+        let (urgent_tx, urgent_rx) = mpsc::channel(capacity.get());
+        let task = tokio::spawn(serve(self, commands_rx, urgent_rx));
+        let handle = SenderHandle {
+            commands: commands_tx,
+            urgent: urgent_tx,
+        };
+        (handle, task)
+    }
+}
+
+async fn serve<W>(
+    mut sender: Sender<W>,
+    mut commands: mpsc::Receiver<Command>,
+    mut urgent: mpsc::Receiver<Bytes>,
+) -> Result<(), SendError>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut closed = None;
+    let mut acks = sender.retransmission_acks();
+    let mut tracked = Tracked::default();
+    loop {
+        let command = select! {
+            biased;
+            Some(data) = urgent.recv() => Command::Send(data),
+            res = ack_changed(&mut acks), if !tracked.unacked.is_empty() => {
+                if res.is_err() {
+                    acks = None;
+                }
+                tracked.complete(sender.acked());
+                continue;
+            }
+            command = commands.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+            () = sleep_until(sender.next_heartbeat()) => {
+                // Streams that fail the heartbeat are evicted, and the next send fails once none is left
+                let _ = sender.heartbeat().await;
+                continue;
+            }
+        };
+        match command {
+            Command::Send(data) => sender.send(data).await?,
+            Command::SendTracked(data, completion) => {
+                sender.send(data).await?;
+                tracked.written(sender.next_sequence(), sender.acked(), completion);
+            }
+            Command::Flush(reply) => {
+                let _ = reply.send(sender.flush().await);
+            }
+            Command::Close(reply) => {
+                // Whatever was queued before still goes out
+                commands.close();
+                urgent.close();
+                closed.get_or_insert(vec![]).push(reply);
+            }
+        }
+    }
+    while let Ok(data) = urgent.try_recv() {
+        sender.send(data).await?;
+    }
+
+    let res = sender.shutdown().await;
+    // The acknowledgements that came with the shutdown, the rest never complete
+    tracked.complete(sender.acked());
+    for reply in closed.into_iter().flatten() {
+        let _ = reply.send(match &res {
+            Ok(()) => Ok(()),
+            Err(_) => Err(SendError::Stopped),
+        });
+    }
+    res
+}
+
+/// The tracked messages written and not acknowledged yet, in sequence order
+#[derive(Default)]
+struct Tracked {
+    unacked: VecDeque<(Sequence, Instant, oneshot::Sender<Completion>)>,
+}
+
+impl Tracked {
+    /// A message ending at `end` was just written, with `ack` the cumulative acknowledgement under retransmission
+    fn written(
+        &mut self,
+        end: Sequence,
+        ack: Option<Sequence>,
+        completion: oneshot::Sender<Completion>,
+    ) {
+        let at = Instant::now();
+        match ack {
+            Some(_) => {
+                self.unacked.push_back((end, at, completion));
+                self.complete(ack);
+            }
+            None => {
+                let _ = completion.send(Completion::Written { at });
+            }
+        }
+    }
+
+    /// Complete the messages acknowledged whole
+    fn complete(&mut self, ack: Option<Sequence>) {
+        let Some(ack) = ack else {
+            return;
+        };
+        while let Some((end, at, _)) = self.unacked.front() {
+            if ack < *end {
+                break;
+            }
+            let rtt = at.elapsed();
+            let (_, _, completion) = self.unacked.pop_front().unwrap();
+            let _ = completion.send(Completion::Acked { rtt });
+        }
+    }
+}
+
+/// Wait for the next acknowledgement, forever without retransmission
+async fn ack_changed(
+    acks: &mut Option<watch::Receiver<Sequence>>,
+) -> Result<(), watch::error::RecvError> {
+    match acks {
+        Some(acks) => acks.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+    use tokio::io::AsyncReadExt;
+
+    use crate::{
+        message::{Hello, Message},
+        receiver::Receiver,
+        sender::{Priority, SenderBuilder},
+    };
+
+    use super::*;
+
+    const RECORD_SIZE: usize = 8;
+
+    /// A record of the `index`th send of `writer`
+    fn record(writer: u8, index: u32) -> Bytes {
+        let mut record = vec![writer; RECORD_SIZE - 4];
+        record.extend_from_slice(&index.to_be_bytes());
+        record.into()
+    }
+
+    #[tokio::test]
+    async fn interleaved_handles() {
+        const SENDS: u32 = 1000;
+        let (send_streams, recv_streams): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| tokio::io::duplex(1 << 10)).unzip();
+        let mut receiver = Receiver::new(recv_streams).into_async_read();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+
+        let (handle, task) = Sender::new(send_streams).spawn_with_capacity(NonZeroUsize::MIN);
+        let writers: Vec<_> = b"ab"
+            .iter()
+            .map(|&writer| {
+                let handle = handle.clone();
+                tokio::spawn(async move {
+                    for index in 0..SENDS {
+                        handle.send(record(writer, index)).await.unwrap();
+                        if index % 100 == 0 {
+                            tokio::task::yield_now().await;
+                        }
+                    }
+                    handle.flush().await.unwrap();
+                })
+            })
+            .collect();
+        drop(handle);
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        task.await.unwrap().unwrap();
+
+        // Whole records of each writer in order
+        let buf = recv_task.await.unwrap();
+        assert_eq!(buf.len(), 2 * SENDS as usize * RECORD_SIZE);
+        let mut next = [0, 0];
+        let mut switches = 0;
+        let mut previous = None;
+        for record in buf.chunks(RECORD_SIZE) {
+            let writer = usize::from(record[0] - b'a');
+            assert!(record[..RECORD_SIZE - 4].iter().all(|&b| b == record[0]));
+            let index = u32::from_be_bytes(record[RECORD_SIZE - 4..].try_into().unwrap());
+            assert_eq!(index, next[writer]);
+            next[writer] += 1;
+            if previous.is_some_and(|previous| previous != writer) {
+                switches += 1;
+            }
+            previous = Some(writer);
+        }
+        assert_eq!(next, [SENDS, SENDS]);
+        assert!(switches > 1);
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let (tx, rx) = tokio::io::duplex(1 << 16);
+        let mut receiver = Receiver::new(vec![rx]).into_async_read();
+        let (handle, task) = Sender::new(vec![tx]).spawn();
+        let other = handle.clone();
+
+        handle.send(Bytes::from_static(b"hello ")).await.unwrap();
+        other.send(Bytes::from_static(b"world")).await.unwrap();
+        handle.close().await.unwrap();
+        assert!(matches!(
+            other.send(Bytes::from_static(b"!")).await,
+            Err(SendError::Stopped)
+        ));
+        assert!(matches!(other.flush().await, Err(SendError::Stopped)));
+        task.await.unwrap().unwrap();
+
+        let mut buf = vec![];
+        receiver.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn urgent_message_overtakes_bulk() {
+        const BULK: usize = 100 << 20;
+        const CHUNK: usize = 1 << 16;
+        const URGENT: usize = 1 << 10;
+        let (send_streams, recv_streams): (Vec<_>, Vec<_>) =
+            (0..2).map(|_| tokio::io::duplex(1 << 16)).unzip();
+        let mut receiver = Receiver::new(recv_streams).into_async_read();
+        // When the urgent message arrived and how many bulk bytes came before it
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![0; 1 << 16];
+            let mut received = 0;
+            let mut urgent = 0;
+            let mut arrival = None;
+            loop {
+                let n = receiver.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                for &byte in &buf[..n] {
+                    if byte == 0xff {
+                        urgent += 1;
+                        if urgent == URGENT {
+                            arrival = Some((Instant::now(), received + 1 - URGENT));
+                        }
+                    }
+                    received += 1;
+                }
+            }
+            assert_eq!(urgent, URGENT);
+            assert_eq!(received, BULK + URGENT);
+            arrival.unwrap()
+        });
+
+        let (handle, task) = Sender::new(send_streams).spawn();
+        let start = Instant::now();
+        let bulk = tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                let chunk = Bytes::from(vec![0; CHUNK]);
+                for _ in 0..BULK / CHUNK {
+                    handle.send(chunk.clone()).await.unwrap();
+                }
+                handle.close().await.unwrap();
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let sent = Instant::now();
+        handle
+            .send_with_priority(Bytes::from(vec![0xff; URGENT]), MessagePriority::High)
+            .await
+            .unwrap();
+        drop(handle);
+        bulk.await.unwrap();
+        task.await.unwrap().unwrap();
+        let finished = start.elapsed();
+
+        let (arrival, bulk_before) = recv_task.await.unwrap();
+        let latency = arrival - sent;
+        // Behind at most the queue and the message being sent rather than the rest of the bulk
+        assert!(0 < bulk_before && bulk_before < BULK / 2, "{bulk_before}");
+        assert!(latency < finished / 4, "{latency:?} {finished:?}");
+    }
+
+    #[tokio::test]
+    async fn keepalive_on_many_senders() {
+        const SENDERS: usize = 100;
+        const SENDS: usize = 25;
+        const INTERVAL: Duration = Duration::from_secs(1);
+        let mut recv_streams = vec![];
+        let mut tasks = vec![];
+        for _ in 0..SENDERS {
+            let (send_streams, rx): (Vec<_>, Vec<_>) =
+                (0..3).map(|_| tokio::io::duplex(1 << 16)).unzip();
+            recv_streams.push(rx);
+            let (handle, task) = SenderBuilder::new()
+                .priorities(vec![Priority::PRIMARY, Priority::BACKUP, Priority::BACKUP])
+                .keepalive(INTERVAL)
+                .build(send_streams)
+                .spawn();
+            tasks.push(tokio::spawn(async move {
+                // Keep the primary stream busy for 2.5 intervals
+                for _ in 0..SENDS {
+                    handle.send(Bytes::from_static(b"data")).await.unwrap();
+                    handle.flush().await.unwrap();
+                    tokio::time::sleep(INTERVAL / 10).await;
+                }
+                handle.close().await.unwrap();
+                task.await.unwrap().unwrap();
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        for streams in recv_streams {
+            let mut heartbeats = vec![];
+            for mut rx in streams {
+                Hello::decode(&mut rx).await.unwrap();
+                let mut pings = 0;
+                while let Some(message) = Message::decode_next(&mut rx).await.unwrap() {
+                    pings += usize::from(message == Message::Ping);
+                }
+                heartbeats.push(pings);
+            }
+            // The backups share the wakeups of their sender, one per interval
+            assert_eq!(heartbeats[0], 0);
+            assert_eq!(heartbeats[1], heartbeats[2]);
+            assert!((1..=3).contains(&heartbeats[1]), "{heartbeats:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_task() {
+        let (tx, rx) = tokio::io::duplex(1 << 16);
+        drop(rx);
+        let (handle, task) = Sender::new(vec![tx]).spawn();
+
+        handle.send(Bytes::from_static(b"hello")).await.unwrap();
+        assert!(matches!(handle.flush().await, Err(SendError::Stopped)));
+        let res = task.await.unwrap();
+        assert!(matches!(res, Err(SendError::Incomplete { sent: 0, .. })));
+    }
+
+    #[tokio::test]
+    async fn tracked_completions_in_order() {
+        const SENDS: u32 = 100;
+        let (tx, rx) = tokio::io::duplex(1 << 16);
+        let mut receiver = Receiver::new(vec![rx]).into_async_read();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        let (handle, task) = Sender::new(vec![tx]).spawn();
+        let mut completions = vec![];
+        for index in 0..SENDS {
+            let (send, completion) = handle.send_tracked(record(b'a', index));
+            send.await.unwrap();
+            completions.push(completion);
+        }
+        let mut previous = None;
+        for completion in completions {
+            let Completion::Written { at } = completion.await.unwrap() else {
+                panic!("acknowledged without retransmission");
+            };
+            assert!(previous <= Some(at));
+            previous = Some(at);
+        }
+        handle.close().await.unwrap();
+        task.await.unwrap().unwrap();
+        assert_eq!(recv_task.await.unwrap().len(), SENDS as usize * RECORD_SIZE);
+
+        // Acknowledged in the order of the acknowledgements
+        let (tx, _rx) = tokio::io::duplex(1 << 16);
+        let (ack_tx, ack_rx) = watch::channel(Sequence::new(0));
+        let (handle, task) = SenderBuilder::new()
+            .retransmission(ack_rx, NonZeroUsize::new(1 << 16).unwrap())
+            .build(vec![tx])
+            .spawn();
+        let mut completions = vec![];
+        for index in 0..SENDS {
+            let (send, completion) = handle.send_tracked(record(b'a', index));
+            send.await.unwrap();
+            completions.push(completion);
+        }
+        handle.flush().await.unwrap();
+        ack_tx.send(Sequence::new(RECORD_SIZE as u64 + 1)).unwrap();
+        assert!(matches!(
+            (&mut completions[0]).await.unwrap(),
+            Completion::Acked { .. }
+        ));
+        let pending = tokio::time::timeout(Duration::from_millis(50), &mut completions[1]);
+        assert!(pending.await.is_err());
+        ack_tx
+            .send(Sequence::new(u64::from(SENDS) * RECORD_SIZE as u64))
+            .unwrap();
+        // Once the last one is acknowledged, so is every one before it
+        let last = completions.pop().unwrap();
+        assert!(matches!(last.await.unwrap(), Completion::Acked { .. }));
+        for completion in completions.drain(1..) {
+            let completion = completion.now_or_never().unwrap();
+            assert!(matches!(completion.unwrap(), Completion::Acked { .. }));
+        }
+        drop(handle);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tracked_sends_fail_with_the_streams() {
+        let (send_streams, recv_streams): (Vec<_>, Vec<_>) =
+            (0..2).map(|_| tokio::io::duplex(1 << 16)).unzip();
+        let (_ack_tx, ack_rx) = watch::channel(Sequence::new(0));
+        let (handle, task) = SenderBuilder::new()
+            .retransmission(ack_rx, NonZeroUsize::new(1 << 16).unwrap())
+            .build(send_streams)
+            .spawn();
+        let (send, unacked) = handle.send_tracked(record(b'a', 0));
+        send.await.unwrap();
+        handle.flush().await.unwrap();
+
+        drop(recv_streams);
+        let (send, failed) = handle.send_tracked(record(b'a', 1));
+        send.await.unwrap();
+        // Dropped without waiting
+        drop(handle.send_tracked(record(b'a', 2)).1);
+        assert!(matches!(unacked.await, Err(SendError::Stopped)));
+        assert!(matches!(failed.await, Err(SendError::Stopped)));
+        let (send, completion) = handle.send_tracked(record(b'a', 3));
+        assert!(matches!(send.await, Err(SendError::Stopped)));
+        assert!(matches!(completion.await, Err(SendError::Stopped)));
+        assert!(task.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Feeding a `Sender` from other sources of data
+
+use std::{error::Error as StdError, future::poll_fn, io, pin::Pin};
+
+use bytes::Bytes;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use thiserror::Error;
+use tokio::io::AsyncWrite;
+
+use crate::sender::Sender;
+
+/// `copy_stream_to_sender` did not copy the whole stream
+#[derive(Debug, Error)]
+pub enum CopyError {
+    /// The stream failed after `copied` bytes, and the byte stream was aborted there
+    ///
+    /// The abort is attempted whatever happened to the sender, so the peer may see the streams end without it.
+    #[error("The source stream failed after {copied} bytes")]
+    Source {
+        copied: u64,
+        #[source]
+        error: Box<dyn StdError + Send + Sync>,
+    },
+    /// The sender failed after `copied` bytes of the stream were handed to it
+    #[error("The sender failed after {copied} bytes")]
+    Send {
+        copied: u64,
+        #[source]
+        error: io::Error,
+    },
+}
+
+/// Send every chunk of `stream` and close `sender`, returning the bytes copied
+///
+/// A chunk is only pulled out of `stream` once `sender` has room for it, as `Sender::poll_ready` tells, so a stream producing faster than the subflows drain takes no more memory than the send window and a chunk.
+/// The peer reads to a FIN once the stream ends.
+/// If it fails instead, the byte stream is cut short with `Sender::abort` and the error as its reason, so that the peer fails with `crate::receiver::RecvError::PeerAborted` past the data copied rather than taking it for all there is.
+pub async fn copy_stream_to_sender<S, T, E, W>(
+    mut stream: S,
+    sender: &mut Sender<W>,
+) -> Result<u64, CopyError>
+where
+    S: Stream<Item = Result<T, E>> + Unpin,
+    T: Into<Bytes>,
+    E: Into<Box<dyn StdError + Send + Sync>>,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut copied = 0;
+    let send_failed = |copied| move |error| CopyError::Send { copied, error };
+    loop {
+        poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .map_err(send_failed(copied))?;
+        match stream.next().await {
+            Some(Ok(chunk)) => {
+                let chunk = chunk.into();
+                if chunk.is_empty() {
+                    continue;
+                }
+                let len = chunk.len() as u64;
+                Pin::new(&mut *sender)
+                    .start_send(chunk)
+                    .map_err(send_failed(copied))?;
+                copied += len;
+            }
+            Some(Err(error)) => {
+                let error = error.into();
+                // A failed submission leaves its data out of the byte stream, which the abort cuts short where the sent data ends anyway
+                let _ = poll_fn(|cx| sender.poll_submitted(cx)).await;
+                let _ = sender.abort(&error.to_string()).await;
+                return Err(CopyError::Source { copied, error });
+            }
+            None => {
+                SinkExt::close(sender).await.map_err(send_failed(copied))?;
+                return Ok(copied);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroUsize,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    };
+
+    use futures_util::stream;
+
+    use super::*;
+    use crate::receiver::{Receiver, RecvError};
+
+    const WINDOW: usize = 10 << 20;
+    const CHUNK_SIZE: usize = 1 << 20;
+
+    #[tokio::test]
+    async fn copy_with_bounded_memory() {
+        const TRANSFER_SIZE: u64 = 1 << 30;
+        let (streams, peers): (Vec<_>, Vec<_>) = (0..2).map(|_| tokio::io::duplex(1 << 20)).unzip();
+        let mut sender = Sender::new(streams);
+        sender.set_send_window(NonZeroUsize::new(WINDOW));
+        let mut receiver = Receiver::new(peers);
+
+        let received = Arc::new(AtomicU64::new(0));
+        let recv = tokio::spawn({
+            let received = received.clone();
+            async move {
+                while let Some(bytes) = receiver.recv_bytes().await.unwrap() {
+                    received.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                }
+            }
+        });
+        let chunk = Bytes::from(vec![7; CHUNK_SIZE]);
+        let mut pulled = 0;
+        let mut max_outstanding = 0;
+        let chunks = (0..TRANSFER_SIZE / CHUNK_SIZE as u64).map(|_| {
+            max_outstanding = max_outstanding.max(pulled - received.load(Ordering::Relaxed));
+            pulled += CHUNK_SIZE as u64;
+            Ok::<_, io::Error>(chunk.clone())
+        });
+        let copied = copy_stream_to_sender(stream::iter(chunks), &mut sender)
+            .await
+            .unwrap();
+        recv.await.unwrap();
+        assert_eq!(copied, TRANSFER_SIZE);
+        assert_eq!(received.load(Ordering::Relaxed), TRANSFER_SIZE);
+        assert!(max_outstanding <= WINDOW as u64, "{max_outstanding}");
+    }
+
+    #[tokio::test]
+    async fn failed_stream_aborts() {
+        let (streams, peers): (Vec<_>, Vec<_>) = (0..2).map(|_| tokio::io::duplex(1 << 16)).unzip();
+        let mut sender = Sender::new(streams);
+        let mut receiver = Receiver::new(peers);
+
+        let chunks = [
+            Ok(Bytes::from_static(b"hello")),
+            Ok(Bytes::from_static(b" world")),
+            Err(io::Error::other("disk on fire")),
+            Ok(Bytes::from_static(b"never sent")),
+        ];
+        let res = copy_stream_to_sender(stream::iter(chunks), &mut sender).await;
+        let Err(CopyError::Source { copied, error }) = res else {
+            panic!("{res:?}");
+        };
+        assert_eq!(copied, 11);
+        assert_eq!(error.to_string(), "disk on fire");
+
+        let mut received = vec![];
+        let e = loop {
+            match receiver.recv_bytes().await {
+                Ok(Some(bytes)) => received.extend_from_slice(&bytes),
+                Ok(None) => panic!("read to a FIN"),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(received, b"hello world");
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+        let e = e.into_inner().unwrap().downcast::<RecvError>().unwrap();
+        let RecvError::PeerAborted { sequence, reason } = *e else {
+            panic!("{e:?}");
+        };
+        assert_eq!(sequence.inner(), 11);
+        assert_eq!(reason, "disk on fire");
+        assert!(sender
+            .batch_send_all(Bytes::from_static(b"!"))
+            .await
+            .is_err());
+    }
+}
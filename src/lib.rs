@@ -1,20 +1,69 @@
+#[cfg(feature = "tokio")]
+pub mod auth;
+#[cfg(feature = "codec")]
+pub mod codec;
+pub mod compression;
+#[cfg(feature = "tokio")]
+pub mod connect;
+#[cfg(feature = "tokio")]
+pub mod control;
+#[cfg(feature = "tokio")]
+pub mod datagram;
+#[cfg(feature = "tokio")]
+pub mod factory;
+#[cfg(feature = "failpoints")]
+pub mod failpoints;
+#[cfg(feature = "tokio")]
+pub mod failure;
+#[cfg(feature = "futures-io")]
+pub mod futures_io;
+#[cfg(feature = "tokio")]
+pub mod handle;
+#[cfg(feature = "tokio")]
+pub mod io;
+#[cfg(feature = "tokio")]
 pub mod listen;
 pub mod message;
+#[cfg(feature = "tokio")]
 pub mod receiver;
 pub mod recv_buf;
+#[cfg(feature = "tokio")]
+pub mod scheduler;
 pub mod send_buf;
+#[cfg(feature = "tokio")]
 pub mod sender;
+#[cfg(feature = "tokio")]
+pub mod session;
+#[cfg(all(feature = "tokio", any(test, feature = "sim")))]
+pub mod sim;
+#[cfg(feature = "tokio")]
 pub mod stream;
+#[cfg(feature = "tokio")]
+pub mod trace;
+pub mod wire;
 
+#[cfg(feature = "tokio")]
+pub use connect::MptcpConnector;
+#[cfg(feature = "tokio")]
+pub use factory::StreamFactory;
+#[cfg(feature = "tokio")]
 pub use listen::MptcpListener;
+#[cfg(feature = "tokio")]
 pub use stream::{MptcpStream, OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf};
 
-#[cfg(test)]
+#[cfg(all(test, feature = "tokio"))]
 mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
 
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
 
-    use crate::{receiver::Receiver, sender::Sender};
+    use crate::{
+        message::{HandshakeError, Hello, SeqWidth, Sequence},
+        receiver::{Receiver, ReceiverBuilder},
+        sender::{RemoveError, Sender, SenderBuilder, StreamId},
+        sim::{SimConfig, SimStream},
+    };
 
     #[tokio::test]
     async fn test_sender_receiver_1() {
@@ -39,6 +88,7 @@ mod tests {
 
         assert_eq!(&msg[..], &buf);
 
+        async_write.shutdown().await.unwrap();
         drop(async_write);
         let n = async_read.read(&mut buf).await.unwrap();
         assert_eq!(n, 0);
@@ -67,8 +117,379 @@ mod tests {
 
         assert_eq!(&msg[..], &buf);
 
+        async_write.shutdown().await.unwrap();
         drop(async_write);
         let n = async_read.read(&mut buf).await.unwrap();
         assert_eq!(n, 0);
     }
+
+    #[tokio::test]
+    async fn test_sender_receiver_copy() {
+        let streams = 4;
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for _ in 0..streams {
+            let (tx, rx) = tokio::io::duplex(64);
+            send_streams.push(tx);
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+        sender.set_max_segment_size(NonZeroUsize::new(4096));
+        let receiver = Receiver::new(recv_streams);
+
+        let mut async_write = sender.into_async_write();
+        let mut async_read = receiver.into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 18).map(|_| rand::random()).collect();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            tokio::io::copy(&mut async_read, &mut buf).await.unwrap();
+            buf
+        });
+        tokio::io::copy(&mut &msg[..], &mut async_write)
+            .await
+            .unwrap();
+        async_write.shutdown().await.unwrap();
+
+        let buf = recv_task.await.unwrap();
+        assert_eq!(buf, msg);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_control() {
+        let streams = 3;
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for _ in 0..streams {
+            let (tx, rx) = tokio::io::duplex(64);
+            send_streams.push(tx);
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+        sender.set_max_segment_size(NonZeroUsize::new(4096));
+        let mut receiver = Receiver::new(recv_streams);
+        let mut control_frames = receiver.control_frames();
+
+        let mut async_read = receiver.into_async_read();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            tokio::io::copy(&mut async_read, &mut buf).await.unwrap();
+            buf
+        });
+
+        let msg: Vec<u8> = (0..1 << 18).map(|_| rand::random()).collect();
+        for (i, chunk) in msg.chunks(1 << 14).enumerate() {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+            if i % 8 == 0 {
+                let payload = format!("control {i}");
+                sender.broadcast_control(payload.into()).await.unwrap();
+            }
+        }
+        sender.shutdown().await.unwrap();
+
+        let buf = recv_task.await.unwrap();
+        assert_eq!(buf, msg);
+        let mut seen = vec![vec![]; streams];
+        for _ in 0..streams * 2 {
+            let frame = control_frames.recv().await.unwrap();
+            seen[frame.index()].push(frame.into_payload());
+        }
+        for payloads in seen {
+            assert_eq!(payloads, [&b"control 0"[..], b"control 8"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_u32_sequences_across_wrap() {
+        // Crosses the wrap of the wire sequences a few kilobytes in
+        let start = Sequence::new(u64::from(u32::MAX) - 5000);
+        for (compact, checksum) in [(false, false), (true, true)] {
+            let mut send_streams = vec![];
+            let mut recv_streams = vec![];
+            for _ in 0..3 {
+                let (tx, rx) = tokio::io::duplex(1 << 12);
+                send_streams.push(tx);
+                recv_streams.push(rx);
+            }
+            let mut sender = SenderBuilder::new()
+                .initial_sequence(start)
+                .max_segment_size(NonZeroUsize::new(1000).unwrap())
+                .compact_headers(compact)
+                .checksum(checksum)
+                .sequence_width(SeqWidth::U32)
+                .build(send_streams);
+            let receiver = ReceiverBuilder::new()
+                .expected_sequence(start)
+                .sequence_width(SeqWidth::U32)
+                .build(recv_streams);
+
+            let mut async_read = receiver.into_async_read();
+            let recv_task = tokio::spawn(async move {
+                let mut buf = vec![];
+                async_read.read_to_end(&mut buf).await.unwrap();
+                buf
+            });
+            let msg: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+            for chunk in msg.chunks(1 << 12) {
+                sender
+                    .batch_send_all(Bytes::copy_from_slice(chunk))
+                    .await
+                    .unwrap();
+            }
+            sender.shutdown().await.unwrap();
+            assert_eq!(recv_task.await.unwrap(), msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sequence_width_mismatch() {
+        for (sender_width, receiver_width) in [
+            (SeqWidth::U32, SeqWidth::U64),
+            (SeqWidth::U64, SeqWidth::U32),
+        ] {
+            let (tx, rx) = tokio::io::duplex(1 << 12);
+            let mut sender = SenderBuilder::new()
+                .sequence_width(sender_width)
+                .build(vec![tx]);
+            let mut receiver = ReceiverBuilder::new()
+                .sequence_width(receiver_width)
+                .build(vec![rx]);
+            sender
+                .batch_send_all(Bytes::from_static(b"hello"))
+                .await
+                .unwrap();
+
+            let mut buf = [0; 5];
+            let err = receiver.recv(&mut buf).await.unwrap_err();
+            let err = err
+                .into_inner()
+                .unwrap()
+                .downcast::<HandshakeError>()
+                .unwrap();
+            assert!(matches!(
+                *err,
+                HandshakeError::SequenceWidthMismatch { local, peer }
+                    if local == receiver_width && peer == sender_width
+            ));
+            assert_eq!(receiver.buffered_bytes(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retransmission_over_control_stream() {
+        let (tx, rx) = tokio::io::duplex(1 << 20);
+        // Takes every write of the sender and delivers none of them
+        let (black_hole, lost_rx) =
+            SimStream::pair(SimConfig::new().drop_after(0), SimConfig::new());
+        let (sender_control, receiver_control) = tokio::io::duplex(1 << 12);
+        let send_streams: Vec<Box<dyn AsyncWrite + Send + Unpin>> =
+            vec![Box::new(tx), Box::new(black_hole)];
+        let mut sender = SenderBuilder::new()
+            .max_segment_size(NonZeroUsize::new(1 << 14).unwrap())
+            .retransmission_timeout(Duration::from_millis(50))
+            .control_stream(sender_control)
+            .build(send_streams);
+        let acks = sender.control_acks().unwrap();
+        sender.enable_retransmission(acks, NonZeroUsize::new(1 << 20).unwrap());
+        let mut sender_frames = sender.control_frames().unwrap();
+        let mut receiver = ReceiverBuilder::new()
+            .control_stream(receiver_control)
+            .build(vec![rx]);
+        let mut receiver_frames = receiver.control_frames();
+
+        // Control frames go both ways on the control stream
+        sender
+            .send_control(Bytes::from_static(b"ping"))
+            .await
+            .unwrap();
+        let frame = receiver_frames.recv().await.unwrap();
+        assert_eq!(frame.index(), 1);
+        assert_eq!(frame.payload(), &Bytes::from_static(b"ping"));
+        receiver.send_control(Bytes::from_static(b"pong")).unwrap();
+        assert_eq!(sender_frames.recv().await.unwrap(), "pong");
+
+        let msg: Vec<u8> = (0..1 << 17).map(|_| rand::random()).collect();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver
+                .into_async_read()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            buf
+        });
+        sender
+            .batch_send_all(Bytes::from(msg.clone()))
+            .await
+            .unwrap();
+        // Only the acknowledgements of the control stream tell what the black hole lost
+        sender.close().await.unwrap();
+        assert_eq!(sender.retained_bytes(), 0);
+        assert_eq!(recv_task.await.unwrap(), msg);
+        drop(lost_rx);
+
+        // Without a control stream nothing acknowledges the data and control frames take the subflows
+        let (tx, rx) = tokio::io::duplex(1 << 12);
+        let mut sender = Sender::new(vec![tx]);
+        let mut receiver = Receiver::new(vec![rx]);
+        assert!(sender.control_acks().is_none());
+        assert!(sender.control_frames().is_none());
+        let mut receiver_frames = receiver.control_frames();
+        sender
+            .send_control(Bytes::from_static(b"ping"))
+            .await
+            .unwrap();
+        assert_eq!(receiver_frames.recv().await.unwrap().index(), 0);
+        let err = receiver.send_control(Bytes::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+    }
+
+    #[tokio::test]
+    async fn remove_a_stream_mid_transfer() {
+        const HALF: usize = 1 << 16;
+        let (send_streams, recv_streams): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| tokio::io::duplex(1 << 12)).unzip();
+        let mut sender = SenderBuilder::new()
+            .max_segment_size(NonZeroUsize::new(1 << 10).unwrap())
+            .build(send_streams);
+        let mut receiver = Receiver::new(recv_streams);
+        let msg: Vec<u8> = (0..2 * HALF).map(|_| rand::random()).collect();
+
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![0; HALF];
+            let mut filled = 0;
+            while filled < HALF {
+                filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+            }
+            // Once the sender has shut it down, with every segment it carried received
+            let removed = receiver.remove_stream(1, false).await.unwrap();
+            let mut rest = vec![];
+            receiver
+                .into_async_read()
+                .read_to_end(&mut rest)
+                .await
+                .unwrap();
+            buf.extend(rest);
+            (buf, removed)
+        });
+
+        sender
+            .batch_send_all(Bytes::copy_from_slice(&msg[..HALF]))
+            .await
+            .unwrap();
+        let mut removed = sender.remove_stream(StreamId::new(1), false).await.unwrap();
+        assert!(matches!(
+            sender.remove_stream(StreamId::new(1), false).await,
+            Err(RemoveError::UnknownStream)
+        ));
+        assert_eq!(sender.live_streams(), 2);
+        assert!(!sender.stats()[1].live());
+        sender
+            .batch_send_all(Bytes::copy_from_slice(&msg[HALF..]))
+            .await
+            .unwrap();
+        sender.shutdown().await.unwrap();
+
+        let (buf, peer) = recv_task.await.unwrap();
+        assert!(buf == msg);
+        // Both ends of the same stream, still open
+        let mut peer = peer.downcast::<DuplexStream>().unwrap();
+        removed.write_all(b"bye").await.unwrap();
+        let mut bye = [0; 3];
+        peer.read_exact(&mut bye).await.unwrap();
+        assert_eq!(&bye, b"bye");
+    }
+
+    #[tokio::test]
+    async fn passthrough_is_a_plain_copy() {
+        let (tx, mut wire) = tokio::io::duplex(1 << 16);
+        let mut sender = SenderBuilder::new().passthrough(true).build(vec![tx]);
+        let msg: Vec<u8> = (0..1 << 18).map(|i| b'a' + (i % 26) as u8).collect();
+        let capture = tokio::spawn(async move {
+            let mut buf = vec![];
+            wire.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        for chunk in msg.chunks(1 << 12) {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.shutdown().await.unwrap();
+        drop(sender);
+        let captured = capture.await.unwrap();
+
+        let mut hello = vec![];
+        Hello::new(0)
+            .with_passthrough(Sequence::new(0))
+            .put(&mut hello);
+        assert_eq!(captured[..hello.len()], hello);
+        let rest = &captured[hello.len()..];
+        assert_eq!(rest[..msg.len()], msg);
+        // The switch to frames, a fin and a shutdown
+        assert_eq!(rest.len() - msg.len(), 2 + 9 + 1);
+
+        let (mut tx, rx) = tokio::io::duplex(1 << 16);
+        let mut async_read = Receiver::new(vec![rx]).into_async_read();
+        tx.write_all(&captured).await.unwrap();
+        drop(tx);
+        let mut buf = vec![];
+        async_read.read_to_end(&mut buf).await.unwrap();
+        assert!(buf == msg);
+    }
+
+    #[tokio::test]
+    async fn passthrough_upgrade_to_two_streams() {
+        const HALF: usize = 1 << 16;
+        let (tx0, rx0) = tokio::io::duplex(1 << 12);
+        let (tx1, rx1) = tokio::io::duplex(1 << 12);
+        let mut sender = SenderBuilder::new()
+            .passthrough(true)
+            .max_segment_size(NonZeroUsize::new(1 << 10).unwrap())
+            .build(vec![tx0]);
+        let mut receiver = Receiver::new(vec![rx0]);
+        // Escapes throughout
+        let msg: Vec<u8> = (0..2 * HALF).map(|_| rand::random()).collect();
+
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![0; 2 * HALF];
+            let mut filled = 0;
+            while filled < HALF {
+                filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+            }
+            let hello = receiver.peer_hello(0).unwrap();
+            assert_eq!(hello.passthrough(), Some(Sequence::new(0)));
+            assert_eq!(receiver.add_stream(rx1), 1);
+            while filled < 2 * HALF {
+                filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+            }
+            assert_eq!(receiver.recv(&mut [0; 1]).await.unwrap(), 0);
+            (buf, receiver.peer_hello(1))
+        });
+
+        for chunk in msg[..HALF].chunks(1 << 12) {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.add_stream(tx1);
+        for chunk in msg[HALF..].chunks(1 << 12) {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.shutdown().await.unwrap();
+        let stats = sender.stats();
+        assert!(stats.iter().all(|stats| stats.bytes_written() > 0));
+
+        let (buf, hello) = recv_task.await.unwrap();
+        assert!(buf == msg);
+        assert_eq!(hello.unwrap().passthrough(), None);
+    }
 }
@@ -13,6 +13,7 @@ use tokio::{
 };
 
 use crate::{
+    auth::{self, RejectPolicy, SessionSecret},
     message::{Init, Session},
     stream::{MptcpStream, SingleAddress},
 };
@@ -32,6 +33,26 @@ impl MptcpListener {
     pub async fn bind(
         addr: impl ToSocketAddrs,
         max_session_streams: NonZeroUsize,
+    ) -> io::Result<Self> {
+        Self::bind_with(addr, max_session_streams, None).await
+    }
+
+    /// `Self::bind` admitting only the subflows that prove they know `secret`, as those of `crate::MptcpConnector::with_secret`
+    ///
+    /// A subflow with a wrong proof is closed as `reject` says before it joins any session.
+    pub async fn with_secret(
+        addr: impl ToSocketAddrs,
+        max_session_streams: NonZeroUsize,
+        secret: SessionSecret,
+        reject: RejectPolicy,
+    ) -> io::Result<Self> {
+        Self::bind_with(addr, max_session_streams, Some((secret, reject))).await
+    }
+
+    async fn bind_with(
+        addr: impl ToSocketAddrs,
+        max_session_streams: NonZeroUsize,
+        auth: Option<(SessionSecret, RejectPolicy)>,
     ) -> io::Result<Self> {
         let listener = Arc::new(TcpListener::bind(addr).await?);
         let (tx, rx) = tokio::sync::mpsc::channel(BACKLOG_MAX);
@@ -39,6 +60,7 @@ impl MptcpListener {
         let backlog = Arc::new(Backlog::new(
             NonZeroUsize::new(BACKLOG_MAX).unwrap(),
             max_session_streams,
+            auth,
         ));
 
         let mut tasks = JoinSet::new();
@@ -98,14 +120,20 @@ struct Backlog {
     queued: RwLock<HashMap<Session, QueuedConnection>>,
     queue_max: NonZeroUsize,
     max_session_streams: NonZeroUsize,
+    auth: Option<(SessionSecret, RejectPolicy)>,
 }
 
 impl Backlog {
-    pub fn new(queue_max: NonZeroUsize, max_session_streams: NonZeroUsize) -> Self {
+    pub fn new(
+        queue_max: NonZeroUsize,
+        max_session_streams: NonZeroUsize,
+        auth: Option<(SessionSecret, RejectPolicy)>,
+    ) -> Self {
         Self {
             queued: RwLock::new(HashMap::new()),
             queue_max,
             max_session_streams,
+            auth,
         }
     }
 
@@ -130,6 +158,12 @@ impl Backlog {
             if init.streams() > this.max_session_streams {
                 return Ok(());
             }
+            if let Some((secret, reject)) = &this.auth {
+                let challenge = auth::challenge(&mut stream, init.session(), secret, *reject);
+                if !tokio::time::timeout(INIT_TIMEOUT, challenge).await?? {
+                    return Ok(());
+                }
+            }
 
             let stream = loop {
                 let mut queued = this.queued.write().unwrap();
@@ -141,14 +175,15 @@ impl Backlog {
                                 queued.insert(init.session(), queued_connection);
                                 break None;
                             }
-                            QueuedConnectionPushResult::Stream(stream) => break Some(stream),
+                            QueuedConnectionPushResult::Stream(stream) => break Some(*stream),
                         }
                     }
                     None => {
                         if queued.len() >= this.queue_max.get() {
                             break None;
                         }
-                        let queued_connection = QueuedConnection::new(init.streams());
+                        let queued_connection =
+                            QueuedConnection::new(init.session(), init.streams());
                         queued.insert(init.session(), queued_connection);
                     }
                 }
@@ -166,15 +201,17 @@ impl Backlog {
 struct QueuedConnection {
     read_streams: Vec<tcp::OwnedReadHalf>,
     write_streams: Vec<tcp::OwnedWriteHalf>,
+    session: Session,
     max: NonZeroUsize,
     last_update: Instant,
 }
 
 impl QueuedConnection {
-    pub fn new(number: NonZeroUsize) -> Self {
+    pub fn new(session: Session, number: NonZeroUsize) -> Self {
         Self {
             read_streams: Vec::new(),
             write_streams: Vec::new(),
+            session,
             max: number,
             last_update: Instant::now(),
         }
@@ -186,8 +223,10 @@ impl QueuedConnection {
         self.read_streams.push(read);
         self.write_streams.push(write);
         if self.read_streams.len() == self.max.get() {
-            let stream = MptcpStream::new(self.read_streams, self.write_streams, addr);
-            return QueuedConnectionPushResult::Stream(stream);
+            let init = Init::new(self.session, self.max);
+            let stream =
+                MptcpStream::from_split(self.read_streams, self.write_streams, addr, Some(&init));
+            return QueuedConnectionPushResult::Stream(Box::new(stream));
         }
         self.last_update = Instant::now();
         QueuedConnectionPushResult::QueuedConnection(self)
@@ -201,5 +240,105 @@ impl QueuedConnection {
 #[derive(Debug)]
 enum QueuedConnectionPushResult {
     QueuedConnection(QueuedConnection),
-    Stream(MptcpStream),
+    Stream(Box<MptcpStream>),
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::io::AsyncReadExt;
+
+    use crate::message::{DataSegment, Hello, Message, Sequence};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn group_session_streams() {
+        const STREAMS: usize = 3;
+        let mut listener = MptcpListener::bind("127.0.0.1:0", NonZeroUsize::new(4).unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Never completes
+        let mut stray = TcpStream::connect(addr).await.unwrap();
+        let stray_init = Init::new(Session::new(1), NonZeroUsize::new(2).unwrap());
+        stray_init.encode(&mut stray).await.unwrap();
+
+        let init = Init::new(Session::new(2), NonZeroUsize::new(STREAMS).unwrap());
+        let mut streams = vec![];
+        for i in 0..STREAMS {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            init.encode(&mut stream).await.unwrap();
+            Hello::new(0).encode(&mut stream).await.unwrap();
+            let payload = Bytes::from(vec![i as u8; 4]);
+            let data_segment = DataSegment::new(Sequence::new(i as u64 * 4), payload).unwrap();
+            Message::DataSegment(data_segment)
+                .encode(&mut stream)
+                .await
+                .unwrap();
+            streams.push(stream);
+        }
+
+        let mut stream = listener.accept().await.unwrap();
+        assert_eq!(stream.local_addr(), Some(addr));
+        let mut buf = [0; STREAMS * 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2]);
+
+        let res = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_wrong_proofs() {
+        let secret = SessionSecret::new("secret");
+        for reject in [RejectPolicy::Close, RejectPolicy::ErrorFrame] {
+            let mut listener = MptcpListener::with_secret(
+                "127.0.0.1:0",
+                NonZeroUsize::new(4).unwrap(),
+                secret.clone(),
+                reject,
+            )
+            .await
+            .unwrap();
+            let addr = listener.local_addr().unwrap();
+            let session = Session::new(3);
+            let init = Init::new(session, NonZeroUsize::new(2).unwrap());
+            let join = |secret: SessionSecret| {
+                let init = init.clone();
+                async move {
+                    let mut stream = TcpStream::connect(addr).await.unwrap();
+                    init.encode(&mut stream).await.unwrap();
+                    auth::prove(&mut stream, session, &secret)
+                        .await
+                        .map(|()| stream)
+                }
+            };
+
+            // Claiming the session of the legitimate subflows in between them
+            let mut streams = vec![join(secret.clone()).await.unwrap()];
+            let err = join(SessionSecret::new("guess")).await.unwrap_err();
+            let kind = match reject {
+                RejectPolicy::Close => io::ErrorKind::UnexpectedEof,
+                RejectPolicy::ErrorFrame => io::ErrorKind::PermissionDenied,
+            };
+            assert_eq!(err.kind(), kind);
+            streams.push(join(secret.clone()).await.unwrap());
+
+            for (i, stream) in streams.iter_mut().enumerate() {
+                Hello::new(0).encode(stream).await.unwrap();
+                let payload = Bytes::from(vec![i as u8; 4]);
+                let data_segment = DataSegment::new(Sequence::new(i as u64 * 4), payload).unwrap();
+                Message::DataSegment(data_segment)
+                    .encode(stream)
+                    .await
+                    .unwrap();
+            }
+            let mut stream = listener.accept().await.unwrap();
+            let mut buf = [0; 8];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [0, 0, 0, 0, 1, 1, 1, 1]);
+        }
+    }
 }
@@ -1,70 +1,515 @@
-use std::{io, num::NonZeroUsize};
+use std::{io, num::NonZeroUsize, time::Duration};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(feature = "tokio")]
+use bytes::BytesMut;
+use bytes::{Buf, BufMut, Bytes};
+use thiserror::Error;
+#[cfg(feature = "tokio")]
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-const DATA_SEGMENT_TYPE_CODE: u8 = 0;
-const PING_TYPE_CODE: u8 = 1;
-const SHUTDOWN_TYPE_CODE: u8 = 2;
+use crate::compression::Compression;
+use crate::wire::peek;
+#[cfg(feature = "tokio")]
+use crate::wire::{
+    compression_of, data_segment_type_code, decode_varint, decompress, is_data_segment,
+    put_sequence, put_varint, ABORT_TYPE_CODE, ACK_TYPE_CODE, CHECKSUMMED_DATA_SEGMENT_TYPE_CODE,
+    COMPACT_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE, COMPACT_DATA_SEGMENT_TYPE_CODE, CONTROL_TYPE_CODE,
+    DEFAULT_MAX_PAYLOAD_SIZE, EXTENSION_TYPE_CODES, FEEDBACK_TYPE_CODE, FIN_TYPE_CODE,
+    LZ4_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE, MAX_VARINT_SIZE, OOB_TYPE_CODE, PING_TYPE_CODE,
+    PONG_TYPE_CODE, PROBE_TYPE_CODE, SHUTDOWN_TYPE_CODE,
+};
+pub use crate::wire::{DecodeError, HeaderContext, SeqWidth};
 
-#[derive(Debug)]
+/// The largest payload the length field of a data segment can describe
+pub const MAX_PAYLOAD_SIZE: usize = u32::MAX as usize;
+
+/// The largest payload the length field of a control frame can describe
+pub const MAX_CONTROL_PAYLOAD_SIZE: usize = u16::MAX as usize;
+
+/// The largest payload the length field of an out-of-band message can describe
+pub const MAX_OOB_PAYLOAD_SIZE: usize = u16::MAX as usize;
+
+/// Bytes a data segment frame takes on top of its payload, checksum and `SeqWidth::U64` start sequence included
+pub const DATA_SEGMENT_OVERHEAD: usize = 1 + 8 + 4 + 4;
+
+/// The most scores the count field of a feedback frame can describe
+pub const MAX_FEEDBACK_SCORES: usize = u16::MAX as usize;
+
+/// The longest reason the length field of an abort frame can describe
+pub const MAX_ABORT_REASON_SIZE: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
     DataSegment(DataSegment),
     Ping,
+    /// No more data on this subflow
     Shutdown,
+    /// The byte stream ends at this sequence
+    Fin(Sequence),
+    /// Every byte before this sequence of the opposite byte stream has been received
+    Ack(Sequence),
+    /// Application signaling outside of the byte stream, of `MAX_CONTROL_PAYLOAD_SIZE` bytes at most
+    Control(Bytes),
+    /// A timestamp of the sender to be reflected in a `Self::Pong` to measure the round-trip time
+    Probe(u64),
+    /// The timestamp of a `Self::Probe` from the peer
+    Pong(u64),
+    /// An application message delivered apart from the byte stream, of `MAX_OOB_PAYLOAD_SIZE` bytes at most
+    ///
+    /// `sequence` tells the copies of a message on different subflows apart from other messages.
+    Oob {
+        sequence: u32,
+        payload: Bytes,
+    },
+    /// How the receiver of the opposite direction saw its subflows, of `MAX_FEEDBACK_SCORES` at most
+    Feedback(Vec<SubflowScore>),
+    /// The byte stream is cut short at `sequence` for `reason`, of `MAX_ABORT_REASON_SIZE` bytes at most, instead of ending at a FIN
+    Abort {
+        sequence: Sequence,
+        reason: Bytes,
+    },
+    /// An extension frame of a later version, which receivers skip
+    ///
+    /// `type_code` is one of `crate::wire::EXTENSION_TYPE_CODES`.
+    Unknown {
+        type_code: u8,
+        payload: Bytes,
+    },
+}
+
+/// How messages are put on the wire
+///
+/// See `crate::wire` for the format. Decoding accepts messages encoded with any options.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncodeOptions {
+    /// Append a CRC32 of the payload to every data segment
+    pub checksum: bool,
+    /// Encode the headers of data segments as varints relative to the previous data segment on the subflow
+    pub compact: bool,
+    /// Compress the payloads of data segments worth it, which then keep full headers
+    pub compression: Compression,
 }
 
 impl Message {
+    #[cfg(feature = "tokio")]
     pub async fn encode<W>(&self, writer: &mut W) -> io::Result<()>
     where
         W: AsyncWrite + Unpin,
     {
+        self.encode_with(writer, EncodeOptions::default()).await
+    }
+
+    /// `Self::encode_in` with a new `HeaderContext`
+    #[cfg(feature = "tokio")]
+    pub async fn encode_with<W>(&self, writer: &mut W, options: EncodeOptions) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.encode_in(writer, options, &mut HeaderContext::new())
+            .await
+    }
+
+    /// Encode the next message of a subflow whose frames so far went through `context`
+    ///
+    /// `context` only moves on once the message is written and flushed, so a failed message encodes to the same bytes again.
+    #[cfg(feature = "tokio")]
+    pub async fn encode_in<W>(
+        &self,
+        writer: &mut W,
+        options: EncodeOptions,
+        context: &mut HeaderContext,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.encode_counted(writer, options, context).await?;
+        Ok(())
+    }
+
+    /// `Self::encode_in` returning the payload bytes of a data segment as they went on the wire, compressed or not
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn encode_counted<W>(
+        &self,
+        writer: &mut W,
+        options: EncodeOptions,
+        context: &mut HeaderContext,
+    ) -> io::Result<usize>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut written = 0;
         match self {
             Message::DataSegment(data_segment) => {
-                writer.write_u8(DATA_SEGMENT_TYPE_CODE).await?;
-                data_segment.encode(writer).await?;
+                let compressed = options.compression.compress(data_segment.payload());
+                let compression = match compressed {
+                    Some(_) => options.compression,
+                    None => Compression::None,
+                };
+                let compact = options.compact && compressed.is_none();
+                let type_code = data_segment_type_code(options.checksum, compact, compression);
+                let width = context.sequence_width();
+                if let Some(compressed) = &compressed {
+                    // Smaller than the payload, so it fits in the length field too
+                    let mut header = [0; 1 + 8 + 4 + 4];
+                    let mut rest = &mut header[..];
+                    rest.put_u8(type_code);
+                    put_sequence(&mut rest, data_segment.start_sequence(), width);
+                    rest.put_u32(data_segment.size() as u32);
+                    rest.put_u32(compressed.len() as u32);
+                    let size = 1 + 8 + 4 + 4 - rest.len();
+                    writer.write_all(&header[..size]).await?;
+                    writer.write_all(compressed).await?;
+                    written = compressed.len();
+                } else if compact {
+                    // On the stack, as a heap buffer per segment shows at high message rates
+                    let mut header = [0; 1 + MAX_VARINT_SIZE * 2];
+                    let mut rest = &mut header[..];
+                    rest.put_u8(type_code);
+                    put_varint(&mut rest, context.delta_to(data_segment.start_sequence()));
+                    put_varint(&mut rest, data_segment.size() as u64);
+                    let size = 1 + MAX_VARINT_SIZE * 2 - rest.len();
+                    writer.write_all(&header[..size]).await?;
+                    writer.write_all(data_segment.payload()).await?;
+                    written = data_segment.size();
+                } else {
+                    let mut header = [0; 1 + 8 + 4];
+                    let mut rest = &mut header[..];
+                    rest.put_u8(type_code);
+                    put_sequence(&mut rest, data_segment.start_sequence(), width);
+                    rest.put_u32(data_segment.size() as u32);
+                    let size = 1 + 8 + 4 - rest.len();
+                    writer.write_all(&header[..size]).await?;
+                    writer.write_all(data_segment.payload()).await?;
+                    written = data_segment.size();
+                }
+                if options.checksum {
+                    writer.write_u32(data_segment.checksum()).await?;
+                }
             }
             Message::Ping => writer.write_u8(PING_TYPE_CODE).await?,
             Message::Shutdown => writer.write_u8(SHUTDOWN_TYPE_CODE).await?,
+            Message::Fin(sequence) | Message::Ack(sequence) => {
+                let type_code = match self {
+                    Message::Fin(_) => FIN_TYPE_CODE,
+                    _ => ACK_TYPE_CODE,
+                };
+                let mut frame = [0; 1 + 8];
+                let mut rest = &mut frame[..];
+                rest.put_u8(type_code);
+                put_sequence(&mut rest, *sequence, context.sequence_width());
+                let size = 1 + 8 - rest.len();
+                writer.write_all(&frame[..size]).await?;
+            }
+            Message::Probe(timestamp) => {
+                writer.write_u8(PROBE_TYPE_CODE).await?;
+                writer.write_u64(*timestamp).await?;
+            }
+            Message::Pong(timestamp) => {
+                writer.write_u8(PONG_TYPE_CODE).await?;
+                writer.write_u64(*timestamp).await?;
+            }
+            Message::Control(payload) => {
+                let length = u16::try_from(payload.len()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "control payload too large")
+                })?;
+                writer.write_u8(CONTROL_TYPE_CODE).await?;
+                writer.write_u16(length).await?;
+                writer.write_all(payload).await?;
+            }
+            Message::Oob { sequence, payload } => {
+                let length = u16::try_from(payload.len()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "out-of-band payload too large")
+                })?;
+                let mut header = [0; 1 + 4 + 2];
+                let mut rest = &mut header[..];
+                rest.put_u8(OOB_TYPE_CODE);
+                rest.put_u32(*sequence);
+                rest.put_u16(length);
+                writer.write_all(&header).await?;
+                writer.write_all(payload).await?;
+            }
+            Message::Feedback(scores) => {
+                let count = u16::try_from(scores.len()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "too many feedback scores")
+                })?;
+                let mut frame = BytesMut::with_capacity(1 + 2 + scores.len() * SUBFLOW_SCORE_SIZE);
+                frame.put_u8(FEEDBACK_TYPE_CODE);
+                frame.put_u16(count);
+                for score in scores {
+                    score.put(&mut frame);
+                }
+                writer.write_all(&frame).await?;
+            }
+            Message::Abort { sequence, reason } => {
+                let length = u16::try_from(reason.len()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "abort reason too large")
+                })?;
+                let mut header = [0; 1 + 8 + 2];
+                let mut rest = &mut header[..];
+                rest.put_u8(ABORT_TYPE_CODE);
+                put_sequence(&mut rest, *sequence, context.sequence_width());
+                rest.put_u16(length);
+                let size = 1 + 8 + 2 - rest.len();
+                writer.write_all(&header[..size]).await?;
+                writer.write_all(reason).await?;
+            }
+            Message::Unknown { type_code, payload } => {
+                if !EXTENSION_TYPE_CODES.contains(type_code) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "not an extension type code",
+                    ));
+                }
+                let length = u16::try_from(payload.len()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "extension payload too large")
+                })?;
+                writer.write_all(&[*type_code]).await?;
+                writer.write_u16(length).await?;
+                writer.write_all(payload).await?;
+            }
         }
         writer.flush().await?;
-        Ok(())
+        // Only once the frame is out so that encoding it again after a failure gives the same bytes
+        if let Message::DataSegment(data_segment) = self {
+            context.record(data_segment.end_sequence());
+        }
+        Ok(written)
     }
 
+    /// Decode a message encoded with a new `HeaderContext`, of a payload of at most `DEFAULT_MAX_PAYLOAD_SIZE`
+    #[cfg(feature = "tokio")]
     pub async fn decode<R>(reader: &mut R) -> io::Result<Self>
     where
         R: AsyncRead + Unpin,
     {
         let type_code = reader.read_u8().await?;
+        let context = &mut HeaderContext::new();
+        Self::decode_body(type_code, reader, context, DEFAULT_MAX_PAYLOAD_SIZE).await
+    }
+
+    /// Like `Self::decode` but returns `Ok(None)` if the stream ends cleanly between two messages
+    ///
+    /// An end of the stream in the middle of a message is still an `io::ErrorKind::UnexpectedEof`.
+    #[cfg(feature = "tokio")]
+    pub async fn decode_next<R>(reader: &mut R) -> io::Result<Option<Self>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        Self::decode_next_in(reader, &mut HeaderContext::new()).await
+    }
+
+    /// `Self::decode_next` of a subflow whose frames so far went through `context`
+    ///
+    /// Payloads are of at most `DEFAULT_MAX_PAYLOAD_SIZE`, see `Self::decode_next_limited`.
+    #[cfg(feature = "tokio")]
+    pub async fn decode_next_in<R>(
+        reader: &mut R,
+        context: &mut HeaderContext,
+    ) -> io::Result<Option<Self>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        Self::decode_next_limited(reader, context, DEFAULT_MAX_PAYLOAD_SIZE).await
+    }
+
+    /// `Self::decode_next_in` rejecting a payload larger than `max_payload_size` with `DecodeError::FrameTooLarge`
+    ///
+    /// The length is checked as soon as it is read, so nothing is allocated for a frame that a corrupt length field makes huge.
+    /// A type code of no frame fails with `DecodeError::UnknownType`.
+    #[cfg(feature = "tokio")]
+    pub async fn decode_next_limited<R>(
+        reader: &mut R,
+        context: &mut HeaderContext,
+        max_payload_size: usize,
+    ) -> io::Result<Option<Self>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut type_code = [0_u8; 1];
+        if reader.read(&mut type_code).await? == 0 {
+            return Ok(None);
+        }
+        Self::decode_body(type_code[0], reader, context, max_payload_size)
+            .await
+            .map(Some)
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn decode_body<R>(
+        type_code: u8,
+        reader: &mut R,
+        context: &mut HeaderContext,
+        max_payload_size: usize,
+    ) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let check = |length: usize| {
+            if max_payload_size < length {
+                return Err(DecodeError::FrameTooLarge {
+                    length,
+                    limit: max_payload_size,
+                });
+            }
+            Ok(length)
+        };
         let this = match type_code {
-            DATA_SEGMENT_TYPE_CODE => {
-                let data_segment = DataSegment::decode(reader).await?;
+            type_code if is_data_segment(type_code) => {
+                let compression = compression_of(type_code);
+                let data_segment = if compression != Compression::None {
+                    let start_sequence = context.expand(read_sequence(reader, context).await?)?;
+                    let length = check(reader.read_u32().await? as usize)?;
+                    let compressed_length = check(reader.read_u32().await? as usize)?;
+                    let mut compressed = vec![0; compressed_length];
+                    reader.read_exact(&mut compressed).await?;
+                    let invalid = || DecodeError::InvalidCompressedPayload {
+                        sequence: start_sequence,
+                    };
+                    let payload =
+                        decompress(compression, &compressed, length).ok_or_else(invalid)?;
+                    DataSegment::new(start_sequence, payload).ok_or_else(invalid)?
+                } else if matches!(
+                    type_code,
+                    COMPACT_DATA_SEGMENT_TYPE_CODE | COMPACT_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+                ) {
+                    let start_sequence = context.start_sequence(read_varint(reader).await?);
+                    let length = usize::try_from(read_varint(reader).await?)
+                        .ok()
+                        .filter(|&length| length <= MAX_PAYLOAD_SIZE)
+                        .ok_or(DecodeError::InvalidVarint)?;
+                    DataSegment::decode_payload(start_sequence, check(length)?, reader).await?
+                } else {
+                    let start_sequence = context.expand(read_sequence(reader, context).await?)?;
+                    let length = reader.read_u32().await?;
+                    let length = usize::try_from(length)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e))?;
+                    DataSegment::decode_payload(start_sequence, check(length)?, reader).await?
+                };
+                let checksummed = matches!(
+                    type_code,
+                    CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+                        | COMPACT_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+                        | LZ4_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+                );
+                if checksummed && reader.read_u32().await? != data_segment.checksum() {
+                    return Err(DecodeError::ChecksumMismatch {
+                        sequence: data_segment.start_sequence(),
+                    }
+                    .into());
+                }
+                context.record(data_segment.end_sequence());
                 Self::DataSegment(data_segment)
             }
             PING_TYPE_CODE => Self::Ping,
             SHUTDOWN_TYPE_CODE => Self::Shutdown,
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("unknown type code: {type_code}"),
-                ))
+            FIN_TYPE_CODE => {
+                let sequence = read_sequence(reader, context).await?;
+                Self::Fin(context.expand(sequence)?)
+            }
+            ACK_TYPE_CODE => {
+                let sequence = read_sequence(reader, context).await?;
+                Self::Ack(context.expand_ack(sequence)?)
+            }
+            PROBE_TYPE_CODE => Self::Probe(reader.read_u64().await?),
+            PONG_TYPE_CODE => Self::Pong(reader.read_u64().await?),
+            CONTROL_TYPE_CODE => {
+                let length = check(reader.read_u16().await?.into())?;
+                let mut payload = vec![0; length];
+                reader.read_exact(&mut payload).await?;
+                Self::Control(payload.into())
+            }
+            OOB_TYPE_CODE => {
+                let sequence = reader.read_u32().await?;
+                let length = check(reader.read_u16().await?.into())?;
+                let mut payload = vec![0; length];
+                reader.read_exact(&mut payload).await?;
+                Self::Oob {
+                    sequence,
+                    payload: payload.into(),
+                }
             }
+            FEEDBACK_TYPE_CODE => {
+                let count = reader.read_u16().await?.into();
+                let mut payload = vec![0; check(count * SUBFLOW_SCORE_SIZE)?];
+                reader.read_exact(&mut payload).await?;
+                let mut payload = &payload[..];
+                Self::Feedback(
+                    (0..count)
+                        .map(|_| SubflowScore::get(&mut payload))
+                        .collect(),
+                )
+            }
+            ABORT_TYPE_CODE => {
+                let sequence = read_sequence(reader, context).await?;
+                let sequence = context.expand(sequence)?;
+                let length = check(reader.read_u16().await?.into())?;
+                let mut reason = vec![0; length];
+                reader.read_exact(&mut reason).await?;
+                Self::Abort {
+                    sequence,
+                    reason: reason.into(),
+                }
+            }
+            type_code if EXTENSION_TYPE_CODES.contains(&type_code) => {
+                let length = check(reader.read_u16().await?.into())?;
+                let mut payload = vec![0; length];
+                reader.read_exact(&mut payload).await?;
+                Self::Unknown {
+                    type_code,
+                    payload: payload.into(),
+                }
+            }
+            _ => return Err(DecodeError::UnknownType(type_code).into()),
         };
         Ok(this)
     }
 }
 
-#[derive(Debug)]
+/// The sequence field of a frame on a subflow whose frames so far went through `context`, still truncated
+#[cfg(feature = "tokio")]
+async fn read_sequence<R>(reader: &mut R, context: &HeaderContext) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    match context.sequence_width() {
+        SeqWidth::U32 => Ok(reader.read_u32().await?.into()),
+        SeqWidth::U64 => reader.read_u64().await,
+    }
+}
+
+#[cfg(feature = "tokio")]
+async fn read_varint<R>(reader: &mut R) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = [0; MAX_VARINT_SIZE];
+    for len in 1..=MAX_VARINT_SIZE {
+        buf[len - 1] = reader.read_u8().await?;
+        if let Some((value, _)) = decode_varint(&buf[..len])? {
+            return Ok(value);
+        }
+    }
+    Err(DecodeError::InvalidVarint.into())
+}
+
+/// A piece of the byte stream
+///
+/// On the wire, the start sequence is a big-endian `u64`, followed by the payload length as a big-endian `u32` and the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DataSegment {
+    /// The sequence of the first payload byte
     start_sequence: Sequence,
+    /// Never empty
     payload: Bytes,
 }
 
 impl DataSegment {
+    /// Returns `None` if `payload` is empty, larger than `MAX_PAYLOAD_SIZE` or runs past the end of the sequence space
     pub fn new(start_sequence: Sequence, payload: Bytes) -> Option<Self> {
-        if payload.is_empty() {
+        if payload.is_empty() || payload.len() > MAX_PAYLOAD_SIZE {
             return None;
         }
+        start_sequence.checked_add(payload.len() as u64)?;
 
         Some(Self {
             start_sequence,
@@ -84,12 +529,18 @@ impl DataSegment {
         &self.payload
     }
 
+    /// CRC32 of the payload
+    pub fn checksum(&self) -> u32 {
+        crc32fast::hash(&self.payload)
+    }
+
+    #[cfg(feature = "tokio")]
     pub async fn encode<W>(&self, writer: &mut W) -> io::Result<()>
     where
         W: AsyncWrite + Unpin,
     {
         writer.write_u64(self.start_sequence.inner()).await?;
-        writer.write_u16(self.payload.len() as u16).await?;
+        writer.write_u32(self.payload.len() as u32).await?;
         writer.write_all(&self.payload).await?;
         Ok(())
     }
@@ -119,24 +570,49 @@ impl DataSegment {
         self.payload.len()
     }
 
+    #[cfg(feature = "tokio")]
     pub async fn decode<R>(reader: &mut R) -> io::Result<Self>
     where
         R: AsyncRead + Unpin,
     {
         let start_sequence = reader.read_u64().await?;
-        let length = reader.read_u16().await?;
+        let length = reader.read_u32().await?;
         let length =
             usize::try_from(length).map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e))?;
+        if DEFAULT_MAX_PAYLOAD_SIZE < length {
+            let limit = DEFAULT_MAX_PAYLOAD_SIZE;
+            return Err(DecodeError::FrameTooLarge { length, limit }.into());
+        }
+        Self::decode_payload(Sequence::new(start_sequence), length, reader).await
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn decode_payload<R>(
+        start_sequence: Sequence,
+        length: usize,
+        reader: &mut R,
+    ) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
         let mut payload = BytesMut::with_capacity(length);
         payload.put_bytes(0, length);
         reader.read_exact(&mut payload[..]).await?;
-        let this = Self::new(Sequence::new(start_sequence), payload.into())
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid data segment"))?;
+        let this =
+            Self::new(start_sequence, payload.into()).ok_or(DecodeError::InvalidDataSegment {
+                sequence: start_sequence,
+            })?;
         Ok(this)
     }
 }
 
+/// The position of a byte in the byte stream
+///
+/// The sequence space is a plain `u64` that never wraps around, so sequences compare as plain `u64`s.
+/// The reassembly of the receiver and the bookkeeping of the sender both rely on this order.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct Sequence(u64);
 
 impl Sequence {
@@ -147,6 +623,22 @@ impl Sequence {
     pub fn inner(&self) -> u64 {
         self.0
     }
+
+    /// Sequences do not wrap around, so this returns `None` past `u64::MAX`
+    pub fn checked_add(&self, bytes: u64) -> Option<Self> {
+        self.0.checked_add(bytes).map(Self)
+    }
+
+    /// The number of bytes from `self` up to `other` or `None` if `other` comes before `self`
+    pub fn distance_to(&self, other: Sequence) -> Option<u64> {
+        other.0.checked_sub(self.0)
+    }
+}
+
+impl std::fmt::Display for Sequence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -168,6 +660,7 @@ impl Init {
         self.streams
     }
 
+    #[cfg(feature = "tokio")]
     pub async fn encode<W>(&self, writer: &mut W) -> io::Result<()>
     where
         W: AsyncWrite + Unpin,
@@ -186,6 +679,7 @@ impl Init {
         Ok(())
     }
 
+    #[cfg(feature = "tokio")]
     pub async fn decode<R>(reader: &mut R) -> io::Result<Self>
     where
         R: AsyncRead + Unpin,
@@ -205,6 +699,392 @@ impl Init {
     }
 }
 
+/// Bytes a `SubflowScore` takes in a feedback frame
+pub const SUBFLOW_SCORE_SIZE: usize = 4 + 8 + 8 + 4;
+
+/// How a receiver saw one of its subflows over the interval of a feedback frame
+///
+/// On the wire, the index is a big-endian `u32`, followed by the frames and the gaps as big-endian `u64`s and the jitter in microseconds as a big-endian `u32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubflowScore {
+    index: u32,
+    frames: u64,
+    gaps: u64,
+    /// Microseconds
+    jitter: u32,
+}
+
+impl SubflowScore {
+    /// The jitter is carried in whole microseconds, up to `u32::MAX` of them
+    pub fn new(index: u32, frames: u64, gaps: u64, jitter: Duration) -> Self {
+        let jitter = u32::try_from(jitter.as_micros()).unwrap_or(u32::MAX);
+        Self {
+            index,
+            frames,
+            gaps,
+            jitter,
+        }
+    }
+
+    /// The index of the subflow among the streams of the receiver
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Frames of any kind received on the subflow
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Data segments of the subflow that the byte stream had been waiting for while later data was buffered
+    pub fn gaps(&self) -> u64 {
+        self.gaps
+    }
+
+    /// Smoothed variation of the time between two frames of the subflow
+    pub fn jitter(&self) -> Duration {
+        Duration::from_micros(self.jitter.into())
+    }
+
+    pub(crate) fn put(&self, dst: &mut impl BufMut) {
+        dst.put_u32(self.index);
+        dst.put_u64(self.frames);
+        dst.put_u64(self.gaps);
+        dst.put_u32(self.jitter);
+    }
+
+    /// Take a score off `src`, which holds `SUBFLOW_SCORE_SIZE` bytes at least
+    pub(crate) fn get(src: &mut impl Buf) -> Self {
+        Self {
+            index: src.get_u32(),
+            frames: src.get_u64(),
+            gaps: src.get_u64(),
+            jitter: src.get_u32(),
+        }
+    }
+}
+
+/// Identifies the start of an MPTCP subflow
+pub const MAGIC: [u8; 4] = *b"MPTC";
+pub const VERSION: u8 = 1;
+
+/// Data segments might carry checksums
+pub const CAPABILITY_CHECKSUM: u32 = 1 << 0;
+/// Data segments might have compact headers
+pub const CAPABILITY_COMPACT_HEADERS: u32 = 1 << 1;
+/// Probes might be sent to measure the round-trip time
+pub const CAPABILITY_RTT_PROBES: u32 = 1 << 2;
+/// Data segments might carry payloads compressed with `Compression::Lz4`, only understood with the `lz4` feature
+pub const CAPABILITY_LZ4: u32 = 1 << 3;
+/// Sequences are carried as their low 32 bits, see `SeqWidth::U32`
+pub const CAPABILITY_SEQUENCE_U32: u32 = 1 << 4;
+/// The handshake carries the largest frame accepted on the opposite direction of the subflow, see `Hello::with_max_frame_size`
+pub const CAPABILITY_MAX_FRAME_SIZE: u32 = 1 << 5;
+/// The handshake carries the session of the subflow and its ID in the session, see `Hello::with_subflow`
+pub const CAPABILITY_SUBFLOW_ID: u32 = 1 << 6;
+/// The handshake carries how often the receiver is to send feedback frames back, see `Hello::with_feedback_interval`
+pub const CAPABILITY_FEEDBACK: u32 = 1 << 7;
+/// The handshake carries the sequence of the raw payload bytes that follow it in place of frames, see `Hello::with_passthrough`
+pub const CAPABILITY_PASSTHROUGH: u32 = 1 << 8;
+/// The capabilities this build understands
+pub const SUPPORTED_CAPABILITIES: u32 = CAPABILITY_CHECKSUM
+    | CAPABILITY_COMPACT_HEADERS
+    | CAPABILITY_RTT_PROBES
+    | CAPABILITY_SEQUENCE_U32
+    | CAPABILITY_MAX_FRAME_SIZE
+    | CAPABILITY_SUBFLOW_ID
+    | CAPABILITY_FEEDBACK
+    | CAPABILITY_PASSTHROUGH
+    | if cfg!(feature = "lz4") {
+        CAPABILITY_LZ4
+    } else {
+        0
+    };
+
+/// The first frame on every subflow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    version: u8,
+    capabilities: u32,
+    max_frame_size: Option<u32>,
+    subflow: Option<(Session, u32)>,
+    /// Milliseconds
+    feedback_interval: Option<u32>,
+    passthrough: Option<Sequence>,
+}
+
+/// The capabilities that come with a field of the hello, which only its `with_*` method sets
+const FIELD_CAPABILITIES: u32 = CAPABILITY_MAX_FRAME_SIZE
+    | CAPABILITY_SUBFLOW_ID
+    | CAPABILITY_FEEDBACK
+    | CAPABILITY_PASSTHROUGH;
+
+impl Hello {
+    pub fn new(capabilities: u32) -> Self {
+        Self {
+            version: VERSION,
+            capabilities: capabilities & !FIELD_CAPABILITIES,
+            max_frame_size: None,
+            subflow: None,
+            feedback_interval: None,
+            passthrough: None,
+        }
+    }
+
+    /// Ask the receiver for a feedback frame on the opposite direction about once per `interval`, carried in whole milliseconds of at least 1
+    pub fn with_feedback_interval(mut self, interval: Duration) -> Self {
+        let millis = u32::try_from(interval.as_millis()).unwrap_or(u32::MAX);
+        self.capabilities |= CAPABILITY_FEEDBACK;
+        self.feedback_interval = Some(millis.max(1));
+        self
+    }
+
+    /// How often the peer asks for feedback frames, if it does
+    pub fn feedback_interval(&self) -> Option<Duration> {
+        self.feedback_interval
+            .map(|millis| Duration::from_millis(millis.into()))
+    }
+
+    /// Announce that the payload from `start` follows as raw bytes instead of frames, until they switch to frames with `crate::wire::PASSTHROUGH_SWITCH`
+    pub fn with_passthrough(mut self, start: Sequence) -> Self {
+        self.capabilities |= CAPABILITY_PASSTHROUGH;
+        self.passthrough = Some(start);
+        self
+    }
+
+    /// The sequence of the first raw payload byte after the hello, if they follow
+    pub fn passthrough(&self) -> Option<Sequence> {
+        self.passthrough
+    }
+
+    /// Announce that the subflow belongs to `session` under the ID `id`, which no other subflow of the session has
+    pub fn with_subflow(mut self, session: Session, id: u32) -> Self {
+        self.capabilities |= CAPABILITY_SUBFLOW_ID;
+        self.subflow = Some((session, id));
+        self
+    }
+
+    /// The session the subflow belongs to, if announced
+    pub fn session(&self) -> Option<Session> {
+        self.subflow.map(|(session, _)| session)
+    }
+
+    /// The ID of the subflow in its session, if announced
+    pub fn subflow_id(&self) -> Option<u32> {
+        self.subflow.map(|(_, id)| id)
+    }
+
+    /// Advertise that frames of at most `size` bytes are accepted on the opposite direction of the subflow
+    pub fn with_max_frame_size(mut self, size: u32) -> Self {
+        self.capabilities |= CAPABILITY_MAX_FRAME_SIZE;
+        self.max_frame_size = Some(size);
+        self
+    }
+
+    /// The largest frame the peer accepts on the opposite direction of the subflow, if it advertised one
+    ///
+    /// The frames a `crate::sender::Sender` writes on the subflow are to fit, see `crate::sender::Sender::set_max_frame_size`.
+    pub fn max_frame_size(&self) -> Option<u32> {
+        self.max_frame_size
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// The width of the sequences of the frames that follow
+    pub fn sequence_width(&self) -> SeqWidth {
+        if self.capabilities & CAPABILITY_SEQUENCE_U32 != 0 {
+            SeqWidth::U32
+        } else {
+            SeqWidth::U64
+        }
+    }
+
+    /// Fails with `HandshakeError::SequenceWidthMismatch` wrapped in `io::ErrorKind::InvalidData` unless the frames that follow have sequences of `width`
+    pub fn expect_sequence_width(&self, width: SeqWidth) -> io::Result<()> {
+        let peer = self.sequence_width();
+        if peer != width {
+            let e = HandshakeError::SequenceWidthMismatch { local: width, peer };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn encode<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(MAX_HELLO_SIZE);
+        self.put(&mut buf);
+        writer.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Put the hello on `dst` as `Self::encode` writes it
+    pub fn put(&self, dst: &mut impl BufMut) {
+        dst.put_slice(&MAGIC);
+        dst.put_u8(self.version);
+        dst.put_u32(self.capabilities);
+        if let Some(size) = self.max_frame_size {
+            dst.put_u32(size);
+        }
+        if let Some((session, id)) = self.subflow {
+            dst.put_u64(session.inner());
+            dst.put_u32(id);
+        }
+        if let Some(interval) = self.feedback_interval {
+            dst.put_u32(interval);
+        }
+        if let Some(start) = self.passthrough {
+            dst.put_u64(start.inner());
+        }
+    }
+
+    /// Take the hello off `src` or return `None` without consuming anything if `src` does not hold all of it yet
+    ///
+    /// Fails like `Self::decode` as soon as `src` holds the bytes in error.
+    pub fn decode_buf(src: &mut impl Buf) -> io::Result<Option<Self>> {
+        let mut buf = [0; MAX_HELLO_SIZE];
+        let head = peek(src, &mut buf);
+        if head.len() < 4 + 1 + 4 {
+            if head.len() >= 4 && head[..4] != MAGIC {
+                return Err(invalid(HandshakeError::BadMagic));
+            }
+            return Ok(None);
+        }
+        let mut fields = head;
+        if fields[..4] != MAGIC {
+            return Err(invalid(HandshakeError::BadMagic));
+        }
+        fields.advance(4);
+        let version = fields.get_u8();
+        check_version(version)?;
+        let capabilities = fields.get_u32();
+        check_capabilities(capabilities)?;
+        let mut size = 4 + 1 + 4;
+        if capabilities & CAPABILITY_MAX_FRAME_SIZE != 0 {
+            size += 4;
+        }
+        if capabilities & CAPABILITY_SUBFLOW_ID != 0 {
+            size += 8 + 4;
+        }
+        if capabilities & CAPABILITY_FEEDBACK != 0 {
+            size += 4;
+        }
+        if capabilities & CAPABILITY_PASSTHROUGH != 0 {
+            size += 8;
+        }
+        if head.len() < size {
+            return Ok(None);
+        }
+        let max_frame_size =
+            (capabilities & CAPABILITY_MAX_FRAME_SIZE != 0).then(|| fields.get_u32());
+        let subflow = (capabilities & CAPABILITY_SUBFLOW_ID != 0)
+            .then(|| (Session::new(fields.get_u64()), fields.get_u32()));
+        let feedback_interval = (capabilities & CAPABILITY_FEEDBACK != 0).then(|| fields.get_u32());
+        let passthrough =
+            (capabilities & CAPABILITY_PASSTHROUGH != 0).then(|| Sequence::new(fields.get_u64()));
+        src.advance(size);
+        Ok(Some(Self {
+            version,
+            capabilities,
+            max_frame_size,
+            subflow,
+            feedback_interval,
+            passthrough,
+        }))
+    }
+
+    /// Fails with `HandshakeError` wrapped in `io::ErrorKind::InvalidData` if the peer is incompatible
+    #[cfg(feature = "tokio")]
+    pub async fn decode<R>(reader: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut magic = [0_u8; 4];
+        reader.read_exact(&mut magic).await?;
+        if magic != MAGIC {
+            return Err(invalid(HandshakeError::BadMagic));
+        }
+        let version = reader.read_u8().await?;
+        check_version(version)?;
+        let capabilities = reader.read_u32().await?;
+        check_capabilities(capabilities)?;
+        let max_frame_size = match capabilities & CAPABILITY_MAX_FRAME_SIZE {
+            0 => None,
+            _ => Some(reader.read_u32().await?),
+        };
+        let subflow = match capabilities & CAPABILITY_SUBFLOW_ID {
+            0 => None,
+            _ => {
+                let session = Session::new(reader.read_u64().await?);
+                Some((session, reader.read_u32().await?))
+            }
+        };
+        let feedback_interval = match capabilities & CAPABILITY_FEEDBACK {
+            0 => None,
+            _ => Some(reader.read_u32().await?),
+        };
+        let passthrough = match capabilities & CAPABILITY_PASSTHROUGH {
+            0 => None,
+            _ => Some(Sequence::new(reader.read_u64().await?)),
+        };
+        Ok(Self {
+            version,
+            capabilities,
+            max_frame_size,
+            subflow,
+            feedback_interval,
+            passthrough,
+        })
+    }
+}
+
+/// The largest hello on the wire, with every optional field
+const MAX_HELLO_SIZE: usize = 4 + 1 + 4 + 4 + 8 + 4 + 4 + 8;
+
+fn invalid(e: HandshakeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn check_version(version: u8) -> io::Result<()> {
+    if version != VERSION {
+        return Err(invalid(HandshakeError::VersionMismatch {
+            local: VERSION,
+            peer: version,
+        }));
+    }
+    Ok(())
+}
+
+fn check_capabilities(capabilities: u32) -> io::Result<()> {
+    let unsupported = capabilities & !SUPPORTED_CAPABILITIES;
+    if unsupported != 0 {
+        return Err(invalid(HandshakeError::UnsupportedCapabilities(
+            unsupported,
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("Not an MPTCP subflow")]
+    BadMagic,
+    #[error("Protocol version mismatch: local {local}, peer {peer}")]
+    VersionMismatch { local: u8, peer: u8 },
+    #[error("Unsupported capabilities: {0:#x}")]
+    UnsupportedCapabilities(u32),
+    #[error("Sequence width mismatch: local {local:?}, peer {peer:?}")]
+    SequenceWidthMismatch { local: SeqWidth, peer: SeqWidth },
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, std::hash::Hash)]
 pub struct Session(u64);
 
@@ -218,7 +1098,7 @@ impl Session {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "tokio"))]
 mod tests {
     use super::*;
 
@@ -233,4 +1113,290 @@ mod tests {
         assert_eq!(src.start_sequence(), dst.start_sequence());
         assert_eq!(src.payload(), dst.payload());
     }
+
+    #[tokio::test]
+    async fn test_compact_data_segment_codec() {
+        let starts = [0, 4, 4 + 127, 4 + 127 + 128, u64::MAX - 1, 2];
+        let mut context = HeaderContext::new();
+        let mut buf = vec![];
+        for (i, &start) in starts.iter().enumerate() {
+            let options = EncodeOptions {
+                checksum: i % 2 == 0,
+                // Either encoding continues the deltas
+                compact: i != 1,
+                ..Default::default()
+            };
+            let payload = Bytes::from_static(b"x");
+            let message = Message::DataSegment(DataSegment::new(Sequence(start), payload).unwrap());
+            message
+                .encode_in(&mut buf, options, &mut context)
+                .await
+                .unwrap();
+        }
+
+        let mut context = HeaderContext::new();
+        let mut reader = io::Cursor::new(&buf[..]);
+        for &start in &starts {
+            let message = Message::decode_next_in(&mut reader, &mut context)
+                .await
+                .unwrap();
+            let Some(Message::DataSegment(data_segment)) = message else {
+                panic!("expected a data segment");
+            };
+            assert_eq!(data_segment.start_sequence(), Sequence(start));
+            assert_eq!(context.previous_end(), data_segment.end_sequence());
+        }
+        assert!(Message::decode_next_in(&mut reader, &mut context)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_checksummed_data_segment_codec() {
+        let options = EncodeOptions {
+            checksum: true,
+            ..Default::default()
+        };
+        let src =
+            DataSegment::new(Sequence(42), Bytes::from_static(&[0xde, 0xad, 0xbe, 0xef])).unwrap();
+        let mut buf = vec![];
+        Message::DataSegment(src)
+            .encode_with(&mut buf, options)
+            .await
+            .unwrap();
+        let mut reader = io::Cursor::new(&buf[..]);
+        let Message::DataSegment(dst) = Message::decode(&mut reader).await.unwrap() else {
+            panic!("expected a data segment");
+        };
+        assert_eq!(dst.payload(), &[0xde, 0xad, 0xbe, 0xef][..]);
+
+        // Flip a bit of the payload
+        let payload_start = 1 + 8 + 4;
+        buf[payload_start + 2] ^= 0x10;
+        let mut reader = io::Cursor::new(&buf[..]);
+        let err = Message::decode(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = err.into_inner().unwrap().downcast::<DecodeError>().unwrap();
+        assert!(matches!(
+            *err,
+            DecodeError::ChecksumMismatch {
+                sequence: Sequence(42)
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_decode_next() {
+        let mut buf = vec![];
+        Message::Ping.encode(&mut buf).await.unwrap();
+        Message::Fin(Sequence(3)).encode(&mut buf).await.unwrap();
+
+        let mut reader = io::Cursor::new(&buf[..]);
+        let message = Message::decode_next(&mut reader).await.unwrap();
+        assert!(matches!(message, Some(Message::Ping)));
+        let message = Message::decode_next(&mut reader).await.unwrap();
+        assert!(matches!(message, Some(Message::Fin(Sequence(3)))));
+        assert!(Message::decode_next(&mut reader).await.unwrap().is_none());
+
+        let mut reader = io::Cursor::new(&buf[1..5]);
+        let err = Message::decode_next(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_control_codec() {
+        let mut buf = vec![];
+        for payload in [&b""[..], b"priority"] {
+            let message = Message::Control(Bytes::copy_from_slice(payload));
+            message.encode(&mut buf).await.unwrap();
+            let mut reader = io::Cursor::new(&buf[..]);
+            let Message::Control(decoded) = Message::decode(&mut reader).await.unwrap() else {
+                panic!("expected a control frame");
+            };
+            assert_eq!(&decoded[..], payload);
+            buf.clear();
+        }
+
+        let message = Message::Control(vec![0; MAX_CONTROL_PAYLOAD_SIZE + 1].into());
+        let err = message.encode(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hello_codec() {
+        let src = Hello::new(CAPABILITY_CHECKSUM);
+        let mut buf = vec![];
+        src.encode(&mut buf).await.unwrap();
+        let dst = Hello::decode(&mut io::Cursor::new(&buf[..])).await.unwrap();
+        assert_eq!(src, dst);
+
+        let mut bad_version = buf.clone();
+        bad_version[4] = VERSION + 1;
+        let err = Hello::decode(&mut io::Cursor::new(&bad_version[..]))
+            .await
+            .unwrap_err();
+        let err = err
+            .into_inner()
+            .unwrap()
+            .downcast::<HandshakeError>()
+            .unwrap();
+        assert!(matches!(*err, HandshakeError::VersionMismatch { .. }));
+
+        let mut bad_magic = buf.clone();
+        bad_magic[0] = b'H';
+        let err = Hello::decode(&mut io::Cursor::new(&bad_magic[..]))
+            .await
+            .unwrap_err();
+        let err = err
+            .into_inner()
+            .unwrap()
+            .downcast::<HandshakeError>()
+            .unwrap();
+        assert!(matches!(*err, HandshakeError::BadMagic));
+
+        let mut bad_capabilities = buf.clone();
+        bad_capabilities[5] = 0x80;
+        let err = Hello::decode(&mut io::Cursor::new(&bad_capabilities[..]))
+            .await
+            .unwrap_err();
+        let err = err
+            .into_inner()
+            .unwrap()
+            .downcast::<HandshakeError>()
+            .unwrap();
+        assert!(matches!(*err, HandshakeError::UnsupportedCapabilities(_)));
+
+        let src = Hello::new(CAPABILITY_CHECKSUM).with_max_frame_size(1 << 12);
+        let mut buf = vec![];
+        src.encode(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 4 + 1 + 4 + 4);
+        let dst = Hello::decode(&mut io::Cursor::new(&buf[..])).await.unwrap();
+        assert_eq!(dst.max_frame_size(), Some(1 << 12));
+        assert_eq!(
+            dst.capabilities(),
+            CAPABILITY_CHECKSUM | CAPABILITY_MAX_FRAME_SIZE
+        );
+        // The size comes with the capability only
+        let src = Hello::new(CAPABILITY_MAX_FRAME_SIZE);
+        assert_eq!((src.capabilities(), src.max_frame_size()), (0, None));
+
+        let src = Hello::new(0)
+            .with_max_frame_size(1 << 12)
+            .with_subflow(Session::new(7), 3);
+        let mut buf = vec![];
+        src.encode(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 4 + 1 + 4 + 4 + 8 + 4);
+        let dst = Hello::decode(&mut io::Cursor::new(&buf[..])).await.unwrap();
+        assert_eq!(dst, src);
+        assert_eq!(dst.session(), Some(Session::new(7)));
+        assert_eq!(dst.subflow_id(), Some(3));
+        let src = Hello::new(CAPABILITY_SUBFLOW_ID);
+        assert_eq!((src.capabilities(), src.session()), (0, None));
+
+        let src = Hello::new(0)
+            .with_subflow(Session::new(7), 3)
+            .with_feedback_interval(Duration::from_millis(50));
+        let mut buf = vec![];
+        src.encode(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 4 + 1 + 4 + 8 + 4 + 4);
+        let dst = Hello::decode(&mut io::Cursor::new(&buf[..])).await.unwrap();
+        assert_eq!(dst, src);
+        assert_eq!(dst.feedback_interval(), Some(Duration::from_millis(50)));
+        assert_eq!(Hello::decode_buf(&mut &buf[..]).unwrap(), Some(src));
+        let src = Hello::new(CAPABILITY_FEEDBACK);
+        assert_eq!((src.capabilities(), src.feedback_interval()), (0, None));
+
+        let src = Hello::new(0).with_passthrough(Sequence::new(1 << 40));
+        let mut buf = vec![];
+        src.encode(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 4 + 1 + 4 + 8);
+        let dst = Hello::decode(&mut io::Cursor::new(&buf[..])).await.unwrap();
+        assert_eq!(dst, src);
+        assert_eq!(dst.passthrough(), Some(Sequence::new(1 << 40)));
+        assert_eq!(Hello::decode_buf(&mut &buf[..]).unwrap(), Some(src));
+        let src = Hello::new(CAPABILITY_PASSTHROUGH);
+        assert_eq!((src.capabilities(), src.passthrough()), (0, None));
+
+        // Byte by byte, without an executor
+        let src = Hello::new(0).with_subflow(Session::new(7), 3);
+        let mut buf = BytesMut::new();
+        src.put(&mut buf);
+        let encoded = buf.split().freeze();
+        for &byte in &encoded[..encoded.len() - 1] {
+            buf.put_u8(byte);
+            assert_eq!(Hello::decode_buf(&mut buf).unwrap(), None);
+        }
+        buf.put_u8(encoded[encoded.len() - 1]);
+        assert_eq!(Hello::decode_buf(&mut buf).unwrap(), Some(src));
+        assert!(buf.is_empty());
+        let err = Hello::decode_buf(&mut &bad_magic[..4]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn lz4_capability_needs_the_feature() {
+        let mut buf = vec![];
+        Hello::new(CAPABILITY_LZ4).encode(&mut buf).await.unwrap();
+        let res = Hello::decode(&mut io::Cursor::new(&buf[..])).await;
+        if cfg!(feature = "lz4") {
+            assert_eq!(res.unwrap().capabilities(), CAPABILITY_LZ4);
+        } else {
+            let err = res.unwrap_err().into_inner().unwrap();
+            let err = err.downcast::<HandshakeError>().unwrap();
+            assert!(matches!(
+                *err,
+                HandshakeError::UnsupportedCapabilities(CAPABILITY_LZ4)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_data_segment_codec() {
+        let src = DataSegment::new(Sequence(0), Bytes::from(vec![0xab; 1 << 17])).unwrap();
+        let mut buf = vec![];
+        src.encode(&mut buf).await.unwrap();
+        let mut reader = io::Cursor::new(&buf[..]);
+        let dst = DataSegment::decode(&mut reader).await.unwrap();
+        assert_eq!(src.payload(), dst.payload());
+    }
+
+    #[test]
+    fn test_sequence_order() {
+        let last = Sequence(u64::MAX);
+        let before_last = Sequence(u64::MAX - 1);
+        assert!(Sequence(0) < before_last);
+        assert!(before_last < last);
+        assert_eq!(before_last.checked_add(1), Some(last));
+        assert_eq!(last.checked_add(1), None);
+        assert_eq!(Sequence(0).distance_to(last), Some(u64::MAX));
+        assert_eq!(last.distance_to(before_last), None);
+        assert_eq!(last.distance_to(last), Some(0));
+        assert_eq!(last.to_string(), u64::MAX.to_string());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_sequence_serde() {
+        let sequence = Sequence(u64::MAX);
+        let json = serde_json::to_string(&sequence).unwrap();
+        assert_eq!(json, u64::MAX.to_string());
+        assert_eq!(serde_json::from_str::<Sequence>(&json).unwrap(), sequence);
+    }
+
+    #[tokio::test]
+    async fn test_data_segment_past_sequence_space() {
+        let payload = Bytes::from_static(&[0xde, 0xad]);
+        assert!(DataSegment::new(Sequence(u64::MAX - 2), payload.clone()).is_some());
+        assert!(DataSegment::new(Sequence(u64::MAX - 1), payload.clone()).is_none());
+
+        let mut buf = vec![];
+        buf.write_u64(u64::MAX - 1).await.unwrap();
+        buf.write_u32(payload.len() as u32).await.unwrap();
+        buf.write_all(&payload).await.unwrap();
+        let mut reader = io::Cursor::new(&buf[..]);
+        let err = DataSegment::decode(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
 }
@@ -1,32 +1,106 @@
 use std::{
+    any::Any,
+    collections::BTreeSet,
+    future::Future,
     io,
-    sync::{Arc, Mutex, RwLock},
-    time::Duration,
+    num::NonZeroUsize,
+    ops::Range,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
 };
 
-use async_async_io::read::{AsyncAsyncRead, PollRead};
+use async_async_io::read::AsyncAsyncRead;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::Stream;
 use thiserror::Error;
 use tokio::{
-    io::{AsyncRead, AsyncReadExt},
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
     select,
-    sync::{mpsc, Notify},
+    sync::{mpsc, watch, Notify},
     task::JoinSet,
 };
 
 use crate::{
-    message::{DataSegment, Message},
+    control::{self, ControlStream, PendingControl},
+    message::{
+        DataSegment, DecodeError, HeaderContext, Hello, Message, SeqWidth, Sequence, Session,
+        SubflowScore, DATA_SEGMENT_OVERHEAD, MAX_CONTROL_PAYLOAD_SIZE, MAX_FEEDBACK_SCORES,
+    },
     recv_buf::RecvStreamBuf,
+    sender::RemoveError,
+    session::SetupCheck,
+    trace,
+    wire::{Unescape, DEFAULT_MAX_PAYLOAD_SIZE},
 };
 
 const LINGER: Duration = Duration::from_secs(10);
+/// Payload bytes of the largest extension frame skipped rather than rejected
+const DEFAULT_MAX_UNKNOWN_FRAME_SIZE: usize = 1 << 12;
+/// The defaults of `ReceiverBuilder::ack_every` and `ReceiverBuilder::ack_delay`
+const DEFAULT_ACK_EVERY: usize = 16;
+const DEFAULT_ACK_DELAY: Duration = Duration::from_millis(25);
+/// The most passthrough bytes read at once
+const PASSTHROUGH_READ_SIZE: usize = 1 << 16;
+/// Weight of a new sample in the jitter of a subflow, that of RTP
+const JITTER_SMOOTHING: f64 = 1.0 / 16.0;
+
+/// Where frames outside of the byte stream go, if anyone listens
+type Tap<T> = Arc<Mutex<Option<mpsc::UnboundedSender<T>>>>;
+
+/// The streams shut down by the peer by index, kept for `Receiver::remove_stream`
+type Parked = Arc<Mutex<Vec<Option<Box<dyn Any + Send>>>>>;
+
+fn tap<T>(tap: &Tap<T>, frame: T) {
+    if let Some(tx) = &*tap.lock().unwrap() {
+        let _ = tx.send(frame);
+    }
+}
 
 #[derive(Debug)]
 pub struct Receiver {
     recv_buf: Arc<RwLock<RecvStreamBuf>>,
     recv_buf_inserted: Arc<Notify>,
+    recv_buf_popped: Arc<Notify>,
     leftover_data_segment: Option<DataSegment>,
-    last_io_error: Arc<Mutex<Option<io::Error>>>,
+    /// Errors of the streams that ended abnormally, in the order they ended
+    subflow_errors: Arc<Mutex<Vec<SubflowError>>>,
     recv_tasks: JoinSet<()>,
+    /// Spawns the tasks of `Self::recv_tasks`
+    subflows: Subflows,
+    /// The last time each stream carried a message or `None` if it has ended
+    last_message: Arc<Mutex<Vec<Option<Instant>>>>,
+    /// Notified whenever a stream ends or has its handshake read
+    stream_ended: Arc<Notify>,
+    parked: Parked,
+    keepalive_tasks: JoinSet<()>,
+    /// Emit the delayed acknowledgements and run the control channel until dropped
+    _background_tasks: JoinSet<()>,
+    ack_frames: Tap<AckFrame>,
+    /// What goes out on the control stream, if there is one
+    control: Option<mpsc::UnboundedSender<Message>>,
+    /// The end of the contiguous data received
+    acks: Arc<watch::Sender<Sequence>>,
+    /// Acknowledgements from the peer for the opposite byte stream
+    peer_acks: Arc<watch::Sender<Sequence>>,
+    /// The handshake of each stream, once read
+    peer_hellos: Arc<Mutex<Vec<Option<Hello>>>>,
+    control_frames: Tap<ControlFrame>,
+    unknown_frames: Tap<UnknownFrame>,
+    probes: Tap<ProbeFrame>,
+    pongs: Tap<ProbeFrame>,
+    oob_messages: Tap<OobMessage>,
+    /// The scores of the streams, once the sender of the streams asks for them
+    feedback: Tap<Vec<SubflowScore>>,
+    peer_feedback: Tap<FeedbackFrame>,
+    gap_timeout: Option<Duration>,
+    /// The missing head-of-line sequence and since when it has been waited for
+    gap: Option<(Sequence, Instant)>,
+    counters: Arc<Counters>,
     _closed: mpsc::Receiver<()>,
 }
 
@@ -35,73 +109,194 @@ impl Receiver {
     where
         R: AsyncRead + Unpin + Send + 'static,
     {
-        let recv_buf = Arc::new(RwLock::new(RecvStreamBuf::new()));
+        Self::with_buffer_limit(streams, NonZeroUsize::MAX)
+    }
+
+    /// Hold at most `limit` bytes of out-of-order data
+    ///
+    /// A stream whose next segment does not fit is not read any further until the buffer drains, which lets the backpressure propagate to the sender of that stream only.
+    /// Segments that are next in order are always accepted, so `limit` should be at least as large as the data the sender puts in flight at once.
+    pub fn with_buffer_limit<R>(streams: Vec<R>, limit: NonZeroUsize) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        ReceiverBuilder::new().buffer_limit(limit).build(streams)
+    }
+
+    /// Resume a byte stream whose bytes before `expected` have already been received
+    ///
+    /// Pair it with `Sender::with_initial_sequence`.
+    pub fn with_expected_sequence<R>(streams: Vec<R>, expected: Sequence) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        ReceiverBuilder::new()
+            .expected_sequence(expected)
+            .build(streams)
+    }
+
+    /// Build on `streams`, the control stream numbered after `subflows` of them
+    fn build<R>(streams: Vec<R>, subflows: usize, options: ReceiverBuilder) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let ReceiverBuilder {
+            buffer_limit: limit,
+            subflow_buffer_limit: subflow_limit,
+            expected_sequence: expected,
+            gap_timeout,
+            sequence_width,
+            max_unknown_frame_size: unknown_frame_limit,
+            max_payload_size,
+            max_frame_sizes,
+            ack_every,
+            ack_delay,
+            ack_on_gap_fill,
+            control_stream,
+            session,
+        } = options;
+        let recv_buf = Arc::new(RwLock::new(RecvStreamBuf::with_next(expected)));
         let recv_buf_inserted = Arc::new(Notify::new());
-        let last_io_error = Arc::new(Mutex::new(None));
+        let recv_buf_popped = Arc::new(Notify::new());
+        let subflow_errors = Arc::new(Mutex::new(Vec::new()));
+        let last_message = Arc::new(Mutex::new(vec![Some(Instant::now()); streams.len()]));
+        let stream_ended = Arc::new(Notify::new());
+        let parked: Parked = Arc::new(Mutex::new((0..streams.len()).map(|_| None).collect()));
         let (closed_tx, closed_rx) = mpsc::channel(1);
+        let acks = Arc::new(watch::channel(expected).0);
+        let peer_acks = Arc::new(watch::channel(Sequence::new(0)).0);
+        let peer_hellos = Arc::new(Mutex::new(vec![None; streams.len()]));
+        let control_frames: Tap<ControlFrame> = Arc::new(Mutex::new(None));
+        let unknown_frames: Tap<UnknownFrame> = Arc::new(Mutex::new(None));
+        let probes: Tap<ProbeFrame> = Arc::new(Mutex::new(None));
+        let pongs: Tap<ProbeFrame> = Arc::new(Mutex::new(None));
+        let oob_messages: Tap<OobMessage> = Arc::new(Mutex::new(None));
+        let feedback: Tap<Vec<SubflowScore>> = Arc::new(Mutex::new(None));
+        let peer_feedback: Tap<FeedbackFrame> = Arc::new(Mutex::new(None));
+        let ack_frames: Tap<AckFrame> = Arc::new(Mutex::new(None));
+        let ack_schedule = Arc::new(Mutex::new(AckSchedule::new(
+            ack_every.get(),
+            ack_delay,
+            ack_on_gap_fill,
+        )));
+        let oob_window = Arc::new(Mutex::new(OobWindow::new()));
+        let setup = Arc::new(Mutex::new(SetupCheck::new(session)));
+        let counters = Arc::new(Counters::new(streams.len()));
 
+        let mut background_tasks = JoinSet::new();
+        let control = control_stream
+            .and_then(|control| control.take())
+            .map(|stream| {
+                let (control, outgoing) = mpsc::unbounded_channel();
+                // The frames of the control stream come after those of the subflows
+                let index = subflows;
+                let control_frames = control_frames.clone();
+                control::spawn(&mut background_tasks, stream, outgoing, move |message| {
+                    if let Message::Control(payload) = message {
+                        tap(&control_frames, ControlFrame { index, payload });
+                    }
+                });
+                control
+            });
+
+        let subflows = Subflows {
+            recv_buf: recv_buf.clone(),
+            recv_buf_inserted: recv_buf_inserted.clone(),
+            recv_buf_popped: recv_buf_popped.clone(),
+            subflow_errors: subflow_errors.clone(),
+            last_message: last_message.clone(),
+            stream_ended: stream_ended.clone(),
+            parked: parked.clone(),
+            closed_tx,
+            acks: acks.clone(),
+            peer_acks: peer_acks.clone(),
+            peer_hellos: peer_hellos.clone(),
+            control_frames: control_frames.clone(),
+            unknown_frames: unknown_frames.clone(),
+            probes: probes.clone(),
+            pongs: pongs.clone(),
+            oob_messages: oob_messages.clone(),
+            peer_feedback: peer_feedback.clone(),
+            ack_frames: ack_frames.clone(),
+            control: control.clone(),
+            ack_schedule: ack_schedule.clone(),
+            oob_window,
+            setup,
+            counters: counters.clone(),
+            limit,
+            subflow_limit,
+            expected,
+            sequence_width,
+            unknown_frame_limit,
+            max_payload_size,
+            max_frame_sizes,
+        };
         let mut recv_tasks = JoinSet::new();
-        for mut stream in streams {
-            let recv_buf_inserted = recv_buf_inserted.clone();
+        for (index, stream) in streams.into_iter().enumerate() {
+            subflows.spawn(&mut recv_tasks, index, stream);
+        }
+
+        {
             let recv_buf = recv_buf.clone();
-            let last_io_error = last_io_error.clone();
-            let closed_tx = closed_tx.clone();
-            recv_tasks.spawn(async move {
+            let recv_buf_inserted = recv_buf_inserted.clone();
+            let ack_frames = ack_frames.clone();
+            let control = control.clone();
+            background_tasks.spawn(async move {
                 loop {
-                    let res = select! {
-                        () = closed_tx.closed() => {
-                            // Prevent triggering TCP RST from our side
-                            let mut drain_task = JoinSet::new();
-                            drain_task.spawn(async move {
-                                let mut buf = [0; 1];
-                                loop {
-                                    let n = stream.read(&mut buf).await?;
-                                    if n == 0 {
-                                        break;
-                                    }
-                                }
-                                Ok::<_, io::Error>(())
-                            });
-
-                            tokio::select! {
-                                res = drain_task.join_next() => {
-                                    if let Some(task) = res {
-                                        // In case the task panicked
-                                        let _ = task.unwrap();
-                                    }
-                                }
-                                () = tokio::time::sleep(LINGER) => (),
-                            }
-                            break;
-                        }
-                        // `Message::decode` is NOT cancel safe but it's OK if it will not be called again
-                        res = Message::decode(&mut stream) => res,
-                    };
-
-                    let message = match res {
-                        Ok(message) => message,
-                        Err(e) => {
-                            let mut last_io_error = last_io_error.lock().unwrap();
-                            *last_io_error = Some(e);
-                            break;
-                        }
-                    };
-                    let data_segment = match message {
-                        Message::DataSegment(data_segment) => data_segment,
-                        Message::Ping => continue,
-                        Message::Shutdown => {
-                            let mut last_io_error = last_io_error.lock().unwrap();
-                            *last_io_error = None;
-                            break;
-                        }
+                    let inserted = recv_buf_inserted.notified();
+                    let deadline = ack_schedule.lock().unwrap().deadline();
+                    let Some(deadline) = deadline else {
+                        inserted.await;
+                        continue;
                     };
-
+                    tokio::time::sleep_until(deadline.into()).await;
+                    let recv_buf = recv_buf.read().unwrap();
+                    let mut ack_schedule = ack_schedule.lock().unwrap();
+                    // Unless the segments were acknowledged in the meantime
+                    if ack_schedule
+                        .deadline()
+                        .is_some_and(|deadline| deadline <= Instant::now())
                     {
-                        let mut recv_buf = recv_buf.write().unwrap();
-                        recv_buf.insert(data_segment);
+                        ack_schedule.reset();
+                        emit_ack(&recv_buf, &ack_frames, control.as_ref());
                     }
-
-                    recv_buf_inserted.notify_waiters();
+                }
+            });
+        }
+        {
+            let counters = counters.clone();
+            let last_message = last_message.clone();
+            let peer_hellos = peer_hellos.clone();
+            let stream_ended = stream_ended.clone();
+            let feedback = feedback.clone();
+            background_tasks.spawn(async move {
+                // Until a hello asks for feedback
+                let interval = loop {
+                    let read = stream_ended.notified();
+                    let interval = peer_hellos
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .flatten()
+                        .find_map(|hello| hello.feedback_interval());
+                    if let Some(interval) = interval {
+                        break interval;
+                    }
+                    read.await;
+                };
+                let mut reported = vec![];
+                let mut ticks = tokio::time::interval(interval);
+                ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                ticks.tick().await;
+                loop {
+                    ticks.tick().await;
+                    let live: Vec<bool> = last_message
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(Option::is_some)
+                        .collect();
+                    tap(&feedback, counters.scores(&live, &mut reported));
                 }
             });
         }
@@ -109,77 +304,2969 @@ impl Receiver {
         Self {
             recv_buf,
             recv_buf_inserted,
+            recv_buf_popped,
             leftover_data_segment: None,
-            last_io_error,
+            subflow_errors,
             recv_tasks,
+            subflows,
+            last_message,
+            stream_ended,
+            parked,
+            keepalive_tasks: JoinSet::new(),
+            _background_tasks: background_tasks,
+            ack_frames,
+            control,
+            acks,
+            peer_acks,
+            peer_hellos,
+            control_frames,
+            unknown_frames,
+            probes,
+            pongs,
+            oob_messages,
+            feedback,
+            peer_feedback,
+            gap_timeout,
+            gap: None,
+            counters,
             _closed: closed_rx,
         }
     }
 
+    /// Fail `Self::recv` with `RecvError::MissingSegment` once the next segment has been missing for `timeout` while later data is buffered
+    ///
+    /// The wait starts over whenever the byte stream moves forward.
+    pub fn set_gap_timeout(&mut self, timeout: Option<Duration>) {
+        self.gap_timeout = timeout;
+    }
+
+    /// Returns `Ok(0)` once every byte up to the FIN has been read
+    ///
+    /// Returns as soon as any contiguous data is available, filling `buf` with as much of it as fits without waiting for more.
+    /// A stream that ends is dropped and the rest keep being read.
+    /// Fails with the error of the last stream that ended abnormally, or `io::ErrorKind::UnexpectedEof`, if all streams end before the FIN.
     pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        loop {
-            let leftover_data_segment = self.leftover_data_segment.take();
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let data_segments = match self.leftover_data_segment.take() {
+            Some(leftover) => {
+                let room = buf.len().saturating_sub(leftover.size());
+                let mut data_segments = vec![leftover];
+                data_segments.extend(self.pop_available(room));
+                data_segments
+            }
+            None => self.pop_contiguous(buf.len()).await?,
+        };
+        let (filled, leftover) = copy_out(data_segments, buf);
+        self.leftover_data_segment = leftover;
+        Ok(filled)
+    }
 
-            let mut handle_data_segment = |data_segment: DataSegment| {
-                let readable = buf.len().min(data_segment.size());
-                buf[..readable].copy_from_slice(&data_segment.payload()[..readable]);
-                self.leftover_data_segment = data_segment.advance(readable);
-                Ok(readable)
-            };
+    /// Returns `Ok(None)` once every byte up to the FIN has been read
+    ///
+    /// Hands out the next contiguous piece of the reassembly buffer without copying it.
+    /// Pieces are whatever is buffered contiguously, so they need not line up with the segments of the sender.
+    /// Fails like `Self::recv`.
+    pub async fn recv_bytes(&mut self) -> io::Result<Option<Bytes>> {
+        let data_segment = match self.leftover_data_segment.take() {
+            Some(leftover) => Some(leftover),
+            None => self.pop_contiguous(1).await?.pop(),
+        };
+        Ok(data_segment.map(|data_segment| data_segment.payload().clone()))
+    }
 
-            if let Some(data_segment) = leftover_data_segment {
-                return handle_data_segment(data_segment);
-            }
+    /// Pop contiguous segments until at least `room` bytes are popped, without waiting
+    fn pop_available(&self, mut room: usize) -> Vec<DataSegment> {
+        let mut data_segments = vec![];
+        let mut recv_buf = self.recv_buf.write().unwrap();
+        while room > 0 {
+            let Some(data_segment) = recv_buf.pop_first() else {
+                break;
+            };
+            room = room.saturating_sub(data_segment.size());
+            data_segments.push(data_segment);
+        }
+        self.counters.observe_head(&recv_buf);
+        drop(recv_buf);
+        if !data_segments.is_empty() {
+            self.recv_buf_popped.notify_waiters();
+        }
+        data_segments
+    }
 
+    /// Wait for contiguous data and pop segments of it until at least `room` bytes are popped
+    ///
+    /// Returns no segment once every byte up to the FIN has been popped.
+    async fn pop_contiguous(&mut self, room: usize) -> io::Result<Vec<DataSegment>> {
+        loop {
             // Checkout receive buffer
             let recv_buf_inserted = self.recv_buf_inserted.notified();
-            {
-                let mut recv_buf = self.recv_buf.write().unwrap();
-                if let Some(data_segment) = recv_buf.pop_first() {
-                    drop(recv_buf);
-                    return handle_data_segment(data_segment);
-                }
+            let data_segments = self.pop_available(room);
+            if !data_segments.is_empty() {
+                return Ok(data_segments);
             }
+            let gap = {
+                let recv_buf = self.recv_buf.read().unwrap();
+                if let Some(e) = peer_aborted(&recv_buf) {
+                    return Err(e);
+                }
+                if recv_buf.finished() {
+                    return Ok(vec![]);
+                }
+                gap_deadline(&recv_buf, self.gap_timeout, &mut self.gap)
+            };
+            #[cfg(feature = "failpoints")]
+            let gap = self.injected_gap(gap)?;
 
             tokio::select! {
                 () = recv_buf_inserted => (),
+                () = sleep_until(gap.map(|(_, _, deadline)| deadline)) => {
+                    let (expected, since, _) = gap.unwrap();
+                    let e = RecvError::MissingSegment {
+                        expected,
+                        waited: since.elapsed(),
+                    };
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, e));
+                }
                 res = self.recv_tasks.join_next() => {
                     if let Some(task) = res {
                         task.unwrap();
                         continue;
                     }
 
-                    let mut last_io_error = self.last_io_error.lock().unwrap();
-                    match last_io_error.take() {
-                        Some(e) => return Err(e),
-                        None => return Ok(0),
+                    let recv_buf = self.recv_buf.read().unwrap();
+                    if let Some(e) = peer_aborted(&recv_buf) {
+                        return Err(e);
+                    }
+                    if recv_buf.finished() {
+                        return Ok(vec![]);
+                    }
+                    drop(recv_buf);
+                    let mut subflow_errors = self.subflow_errors.lock().unwrap();
+                    let e = subflow_errors.pop().map(|e| e.error).unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "all streams ended before FIN")
+                    });
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// `gap` as the `recv.gap_timer` failpoint has it
+    #[cfg(feature = "failpoints")]
+    fn injected_gap(
+        &self,
+        gap: Option<(Sequence, Instant, Instant)>,
+    ) -> io::Result<Option<(Sequence, Instant, Instant)>> {
+        use crate::failpoints::{armed, FailAction, RECV_GAP_TIMER};
+
+        let expected = self.recv_buf.read().unwrap().next();
+        match armed(RECV_GAP_TIMER) {
+            None => Ok(gap),
+            Some(FailAction::Error(kind)) => {
+                let e = RecvError::MissingSegment {
+                    expected,
+                    waited: Duration::ZERO,
+                };
+                Err(io::Error::new(kind, e))
+            }
+            Some(FailAction::Delay(delay)) => {
+                let now = Instant::now();
+                Ok(gap.or(Some((expected, now, now + delay))))
+            }
+        }
+    }
+
+    /// Report a stream as dead once it has carried no message for `misses` consecutive heartbeat `interval`s
+    ///
+    /// Each stream is reported at most once, and streams that have ended are never reported.
+    pub fn watch_keepalive(
+        &mut self,
+        interval: Duration,
+        misses: NonZeroUsize,
+    ) -> mpsc::UnboundedReceiver<SubflowDead> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let last_message = self.last_message.clone();
+        let deadline = interval * u32::try_from(misses.get()).unwrap_or(u32::MAX);
+        self.keepalive_tasks.spawn(async move {
+            let mut reported = vec![false; last_message.lock().unwrap().len()];
+            loop {
+                tokio::time::sleep(interval).await;
+                let last_message = last_message.lock().unwrap().clone();
+                for (index, last_message) in last_message.into_iter().enumerate() {
+                    let Some(last_message) = last_message else {
+                        continue;
+                    };
+                    if reported[index] || last_message.elapsed() < deadline {
+                        continue;
+                    }
+                    reported[index] = true;
+                    if tx.send(SubflowDead { index }).is_err() {
+                        return;
                     }
                 }
             }
+        });
+        rx
+    }
+
+    /// Cumulative acknowledgements of the received data
+    ///
+    /// The value is the sequence right after the contiguous data received so far. Feed it back to the sender, e.g., in `Message::Ack` frames.
+    pub fn acks(&self) -> watch::Receiver<Sequence> {
+        self.acks.subscribe()
+    }
+
+    /// Coalesced acknowledgements of the received data, to be fed to `Sender::handle_ack` of the sender of the byte stream
+    ///
+    /// One is due once `ReceiverBuilder::ack_every` data segments have arrived since the last one, `ReceiverBuilder::ack_delay` after the oldest of them, or right away when a segment fills a gap under `ReceiverBuilder::ack_on_gap_fill`.
+    /// Each carries the selective acknowledgements of the data buffered past the gaps, so that the sender does not retransmit it.
+    /// Like `Self::control_frames`, those due while nobody listens are dropped.
+    pub fn ack_frames(&mut self) -> mpsc::UnboundedReceiver<AckFrame> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.ack_frames.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Write a control frame carrying `payload` on the control stream, for `Sender::control_frames` on the other end
+    ///
+    /// Fails with `io::ErrorKind::NotConnected` without a control stream or once it has gone down.
+    pub fn send_control(&self, payload: Bytes) -> io::Result<()> {
+        if payload.len() > MAX_CONTROL_PAYLOAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "control frame too large",
+            ));
+        }
+        let sent = self
+            .control
+            .as_ref()
+            .is_some_and(|control| control.send(Message::Control(payload)).is_ok());
+        if !sent {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no control stream",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Cumulative acknowledgements carried in `Message::Ack` frames from the peer
+    ///
+    /// These acknowledge the opposite byte stream on bidirectional subflows.
+    pub fn peer_acks(&self) -> watch::Receiver<Sequence> {
+        self.peer_acks.subscribe()
+    }
+
+    /// The largest frame the peer accepts on the opposite direction of the stream at `index`, as advertised in its handshake
+    ///
+    /// Feed it to `Sender::set_max_frame_size` of the opposite direction. `None` until the handshake is read or if the peer advertised no limit.
+    pub fn peer_max_frame_size(&self, index: usize) -> Option<u32> {
+        self.peer_hello(index)?.max_frame_size()
+    }
+
+    /// The handshake the peer started the stream at `index` with, `None` until it is read
+    pub fn peer_hello(&self, index: usize) -> Option<Hello> {
+        self.peer_hellos
+            .lock()
+            .unwrap()
+            .get(index)
+            .copied()
+            .flatten()
+    }
+
+    /// Wait until the handshake of every stream has been read or the stream has ended, and return them by index
+    ///
+    /// A stream that ended before its handshake was read, e.g., for failing `ReceiverBuilder::session`, has `None`.
+    pub async fn peer_hellos(&self) -> Vec<Option<Hello>> {
+        loop {
+            let stream_ended = self.stream_ended.notified();
+            {
+                let hellos = self.peer_hellos.lock().unwrap();
+                let last_message = self.last_message.lock().unwrap();
+                let pending = (hellos.iter().zip(last_message.iter()))
+                    .any(|(hello, last)| hello.is_none() && last.is_some());
+                if !pending {
+                    return hellos.clone();
+                }
+            }
+            stream_ended.await;
+        }
+    }
+
+    /// Control frames from every stream, in the order each stream carried them
+    ///
+    /// Frames that arrive while nobody listens are dropped, and a new call takes the frames away from the previous receiver.
+    pub fn control_frames(&mut self) -> mpsc::UnboundedReceiver<ControlFrame> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.control_frames.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Extension frames that this version does not know, from every stream in the order each stream carried them
+    ///
+    /// They are skipped without ending their stream and counted in `ReceiverStats::unknown_frames`.
+    /// Like `Self::control_frames`, frames that arrive while nobody listens are dropped.
+    pub fn unknown_frames(&mut self) -> mpsc::UnboundedReceiver<UnknownFrame> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.unknown_frames.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Probes from every stream, to be reflected by `Sender::reflect_probe` on the opposite direction
+    ///
+    /// Like `Self::control_frames`, probes that arrive while nobody listens are dropped.
+    pub fn probes(&mut self) -> mpsc::UnboundedReceiver<ProbeFrame> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.probes.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Pongs answering the probes of the `Sender` of the opposite direction, to be fed to its `Sender::enable_rtt_probes`
+    pub fn pongs(&mut self) -> mpsc::UnboundedReceiver<ProbeFrame> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.pongs.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// The scores of every stream that has not ended, once per the interval the sender of the streams asks for in its hellos, see `Sender::enable_feedback`
+    ///
+    /// Each report covers the frames and gaps since the previous one, and is to be written back by `Sender::send_feedback` on the opposite direction.
+    /// Like `Self::control_frames`, reports made while nobody listens are dropped.
+    pub fn feedback(&mut self) -> mpsc::UnboundedReceiver<Vec<SubflowScore>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.feedback.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Feedback frames about the streams of the `Sender` of the opposite direction, to be fed to its `Sender::enable_feedback`
+    pub fn peer_feedback(&mut self) -> mpsc::UnboundedReceiver<FeedbackFrame> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.peer_feedback.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Out-of-band messages from every stream in the order they arrived, each once however many streams carried it
+    ///
+    /// They arrive as soon as their stream carries them, whatever the byte stream waits for.
+    /// Like `Self::control_frames`, messages that arrive while nobody listens are dropped.
+    pub fn oob_messages(&mut self) -> mpsc::UnboundedReceiver<OobMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.oob_messages.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// The number of streams that have not ended
+    pub fn live_streams(&self) -> usize {
+        self.liveness().live_streams()
+    }
+
+    /// Wait until the peer shuts the stream of `index` down, as `Sender::remove_stream` does, and take it out
+    ///
+    /// Every segment the stream carried is in the reassembly buffer by then, so none of its data is lost.
+    /// The stream comes back boxed as it was given to `Self::new` or `ReceiverBuilder::build`; the streams shut down by the peer are kept for this until the receiver is dropped.
+    /// Fails with `RemoveError::LastStream` if no other stream that has not ended would be left, unless `force`, and with `RemoveError::Ended` if the stream ends otherwise or has been removed already.
+    pub async fn remove_stream(
+        &mut self,
+        index: usize,
+        force: bool,
+    ) -> Result<Box<dyn Any + Send>, RemoveError> {
+        loop {
+            let stream_ended = self.stream_ended.notified();
+            let ended = {
+                let last_message = self.last_message.lock().unwrap();
+                let Some(this) = last_message.get(index) else {
+                    return Err(RemoveError::UnknownStream);
+                };
+                let others = last_message.iter().flatten().count() - usize::from(this.is_some());
+                if others == 0 && !force {
+                    return Err(RemoveError::LastStream);
+                }
+                this.is_none()
+            };
+            // Parked before it is marked as ended
+            if let Some(stream) = self.parked.lock().unwrap()[index].take() {
+                return Ok(stream);
+            }
+            if ended {
+                return Err(RemoveError::Ended);
+            }
+            stream_ended.await;
+        }
+    }
+
+    /// Read `stream` as well, the counterpart of `Sender::add_stream`, returning its index
+    ///
+    /// A stream added by the sender to one carrying passthrough bytes works along with it: that one switches to frames in band, see `Sender::set_passthrough`.
+    pub fn add_stream<R>(&mut self, stream: R) -> usize
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        self.spawn_streams(vec![stream]).start
+    }
+
+    /// Read the byte stream on from `streams` in place of every stream so far, the counterpart of `Sender::migrate`
+    ///
+    /// The old streams are dropped right away rather than drained, and the data they carried into the reassembly buffer stays there for the new streams to carry on from.
+    /// The new streams take the indices after those of the old ones, which are returned.
+    /// Their handshakes are checked against those of the old streams: one announcing another session, or parameters of its own, ends with `crate::session::SessionSetupError` in its `SubflowError` before carrying anything.
+    pub async fn migrate<R>(&mut self, streams: Vec<R>) -> Range<usize>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        self.recv_tasks.shutdown().await;
+        self.parked
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .for_each(|stream| *stream = None);
+        self.spawn_streams(streams)
+    }
+
+    /// Start reading `streams` with the indices after those of the streams so far, which are returned
+    fn spawn_streams<R>(&mut self, streams: Vec<R>) -> Range<usize>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let start = {
+            let mut last_message = self.last_message.lock().unwrap();
+            let start = last_message.len();
+            last_message.resize(start + streams.len(), Some(Instant::now()));
+            start
+        };
+        let end = start + streams.len();
+        self.parked.lock().unwrap().resize_with(end, || None);
+        self.peer_hellos.lock().unwrap().resize(end, None);
+        for (index, stream) in (start..).zip(streams) {
+            self.subflows.spawn(&mut self.recv_tasks, index, stream);
+        }
+        start..end
+    }
+
+    /// Tells how many streams have not ended even after `self` is dropped
+    pub(crate) fn liveness(&self) -> Liveness {
+        Liveness(self.last_message.clone())
+    }
+
+    /// Take the errors of the streams that ended abnormally since the last call, e.g., in the middle of a message
+    pub fn take_subflow_errors(&mut self) -> Vec<SubflowError> {
+        std::mem::take(&mut self.subflow_errors.lock().unwrap())
+    }
+
+    /// Bytes of out-of-order data waiting in the reassembly buffer
+    pub fn buffered_bytes(&self) -> usize {
+        self.recv_buf.read().unwrap().buffered_bytes()
+    }
+
+    pub fn stats(&self) -> ReceiverStats {
+        let recv_buf = self.recv_buf.read().unwrap();
+        let counters = &self.counters;
+        let subflows: Vec<RecvStats> = counters
+            .subflows
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(index, subflow)| RecvStats {
+                index,
+                bytes_received: subflow.bytes.load(Ordering::Relaxed),
+                frames_received: subflow.frames.load(Ordering::Relaxed),
+                checksum_failures: subflow.checksum_failures.load(Ordering::Relaxed),
+                unknown_frames: subflow.unknown_frames.load(Ordering::Relaxed),
+                gaps: subflow.gaps.load(Ordering::Relaxed),
+                jitter: subflow.jitter(),
+            })
+            .collect();
+        ReceiverStats {
+            buffered_bytes: recv_buf.buffered_bytes(),
+            duplicate_segments: recv_buf.duplicate_segments(),
+            duplicate_bytes: recv_buf.duplicate_bytes(),
+            subflow_buffered_bytes: recv_buf.all_subflow_buffered_bytes().to_vec(),
+            head_of_line_wait: counters.head_of_line_wait(),
+            max_gap: counters.max_gap.load(Ordering::Relaxed),
+            checksum_failures: subflows.iter().map(|s| s.checksum_failures).sum(),
+            unknown_frames: subflows.iter().map(|s| s.unknown_frames).sum(),
+            subflows,
+        }
+    }
+
+    /// Zero the counters of `Self::stats`, e.g., after each scrape of an interval
+    ///
+    /// The buffered bytes are a state rather than counters and are left alone, and an ongoing head-of-line wait counts from now on.
+    pub fn reset_stats(&self) {
+        let mut recv_buf = self.recv_buf.write().unwrap();
+        recv_buf.reset_duplicates();
+        self.counters.reset();
+    }
+
+    /// An `AsyncRead` copying the reassembly buffer straight into the buffers it reads into
+    pub fn into_async_read(self) -> RecvReader {
+        RecvReader {
+            receiver: Some(self),
+            pending: None,
         }
     }
 
-    pub fn into_async_read(self) -> PollRead<Self> {
-        PollRead::new(self)
+    /// A `Stream` of the pieces handed out by `Self::recv_bytes`, e.g., for an HTTP body
+    ///
+    /// It ends after the FIN or the first error.
+    pub fn into_bytes_stream(self) -> BytesStream {
+        let pieces = futures_util::stream::unfold(Some(self), |receiver| async move {
+            let mut receiver = receiver?;
+            match receiver.recv_bytes().await {
+                Ok(Some(piece)) => Some((Ok(piece), Some(receiver))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        BytesStream {
+            pieces: Box::pin(pieces),
+        }
     }
 }
 
-impl Drop for Receiver {
-    fn drop(&mut self) {
-        // The drop of `self.closed` will signal receive tasks to end later
-        self.recv_tasks.detach_all();
+/// A `Receiver` whose streams join one by one, e.g., as the subflows of a session connect
+///
+/// Each stream is read from the moment it joins, so the data the first ones carry is buffered rather than held up, and the upgrade to the `Receiver` keeps the reassembly buffer as it is.
+/// The data is only handed out by the `Receiver`, and its control stream, if any, is numbered after the expected streams.
+#[derive(Debug)]
+pub struct PartialReceiver {
+    receiver: Receiver,
+    expected: NonZeroUsize,
+}
+
+impl PartialReceiver {
+    /// Start reading `stream`, returning its index
+    pub fn add_stream<R>(&mut self, stream: R) -> usize
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        self.receiver.spawn_streams(vec![stream]).start
+    }
+
+    /// The number of streams joined so far
+    pub fn streams(&self) -> usize {
+        self.receiver.last_message.lock().unwrap().len()
+    }
+
+    /// Whether every expected stream has joined
+    pub fn is_complete(&self) -> bool {
+        self.expected.get() <= self.streams()
+    }
+
+    /// Cumulative acknowledgements of the data received so far, see `Receiver::acks`
+    pub fn acks(&self) -> watch::Receiver<Sequence> {
+        self.receiver.acks()
+    }
+
+    /// Bytes of data buffered so far, in order or not
+    pub fn buffered_bytes(&self) -> usize {
+        self.receiver.recv_buf.read().unwrap().buffered_bytes()
+    }
+
+    /// Add the streams of `streams` as they come until every expected stream has joined, `streams` ends or `timeout` passes, and upgrade
+    pub async fn complete<S, R>(mut self, mut streams: S, timeout: Duration) -> Receiver
+    where
+        S: Stream<Item = R> + Unpin,
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        while !self.is_complete() {
+            select! {
+                stream = std::future::poll_fn(|cx| Pin::new(&mut streams).poll_next(cx)) => {
+                    let Some(stream) = stream else {
+                        break;
+                    };
+                    self.add_stream(stream);
+                }
+                () = &mut deadline => break,
+            }
+        }
+        self.into_receiver()
+    }
+
+    /// Upgrade to the `Receiver` with the streams joined so far
+    pub fn into_receiver(self) -> Receiver {
+        self.receiver
     }
 }
 
-impl AsyncAsyncRead for Receiver {
-    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.recv(buf).await {
-            Ok(n) => Ok(n),
-            Err(_) => Ok(0),
+/// Waits for contiguous data with the `Receiver` inside until it pops some
+type PopFuture = Pin<Box<dyn Future<Output = (Receiver, io::Result<Vec<DataSegment>>)> + Send>>;
+
+/// The byte stream of a `Receiver` as an `AsyncRead`
+///
+/// A read copies the pieces of the reassembly buffer into its `ReadBuf` and never initializes more of it than it fills, so it may be handed uninitialized memory, e.g., by `AsyncReadExt::read_buf`.
+/// `Self::poll_read_buf` appends to a `BytesMut` instead, and `Receiver::into_bytes_stream` hands out the pieces themselves without copying them.
+pub struct RecvReader {
+    /// `None` while `Self::pending` holds it
+    receiver: Option<Receiver>,
+    pending: Option<PopFuture>,
+}
+
+impl RecvReader {
+    /// # Panics
+    ///
+    /// Panics if a read was left pending.
+    pub fn into_inner(mut self) -> Receiver {
+        self.receiver.take().expect("a read was left pending")
+    }
+
+    /// Append the next contiguous data to `buf`, at least as much as fits in its spare capacity if that much is buffered
+    ///
+    /// The pieces popped are appended whole, growing `buf` as needed, so no data is held back for the next read.
+    /// Returns `Poll::Ready(Ok(0))` once every byte up to the FIN has been read and fails like `Receiver::recv`.
+    pub fn poll_read_buf(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut BytesMut,
+    ) -> Poll<io::Result<usize>> {
+        let room = (buf.capacity() - buf.len()).max(1);
+        let data_segments = ready!(self.poll_pop(cx, room))?;
+        let before = buf.len();
+        for data_segment in data_segments {
+            buf.extend_from_slice(data_segment.payload());
+        }
+        Poll::Ready(Ok(buf.len() - before))
+    }
+
+    /// Pop contiguous segments until at least `room` bytes are popped, waiting for the first one if none is buffered
+    ///
+    /// Only the last segment may go past `room`.
+    fn poll_pop(
+        &mut self,
+        cx: &mut Context<'_>,
+        room: usize,
+    ) -> Poll<io::Result<Vec<DataSegment>>> {
+        if self.pending.is_none() {
+            let receiver = self.receiver.as_mut().expect("a read was left pending");
+            let mut data_segments: Vec<DataSegment> =
+                receiver.leftover_data_segment.take().into_iter().collect();
+            let popped: usize = data_segments.iter().map(DataSegment::size).sum();
+            data_segments.extend(receiver.pop_available(room.saturating_sub(popped)));
+            if !data_segments.is_empty() {
+                return Poll::Ready(Ok(data_segments));
+            }
+            let mut receiver = self.receiver.take().unwrap();
+            self.pending = Some(Box::pin(async move {
+                // A single segment, so that a smaller buffer of the next poll is no trouble
+                let res = receiver.pop_contiguous(1).await;
+                (receiver, res)
+            }));
         }
+        let (receiver, res) = ready!(self.pending.as_mut().unwrap().as_mut().poll(cx));
+        self.pending = None;
+        let receiver = self.receiver.insert(receiver);
+        let mut data_segments = res?;
+        if let Some(first) = data_segments.first() {
+            let room = room.saturating_sub(first.size());
+            data_segments.extend(receiver.pop_available(room));
+        }
+        Poll::Ready(Ok(data_segments))
     }
 }
 
-#[derive(Debug, Error)]
-#[error("No stream left")]
-pub struct NoStreamLeft;
+impl AsyncRead for RecvReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let data_segments = ready!(this.poll_pop(cx, buf.remaining()))?;
+        let mut leftover = None;
+        for data_segment in data_segments {
+            let readable = buf.remaining().min(data_segment.size());
+            buf.put_slice(&data_segment.payload()[..readable]);
+            leftover = data_segment.advance(readable);
+        }
+        this.receiver.as_mut().unwrap().leftover_data_segment = leftover;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl std::fmt::Debug for RecvReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RecvReader")
+            .field("receiver", &self.receiver)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The byte stream of a `Receiver` as contiguous pieces of the reassembly buffer
+pub struct BytesStream {
+    pieces: Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>,
+}
+
+impl Stream for BytesStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.pieces.as_mut().poll_next(cx)
+    }
+}
+
+impl std::fmt::Debug for BytesStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BytesStream").finish_non_exhaustive()
+    }
+}
+
+/// Configures a `Receiver` before the streams are read
+///
+/// The defaults are those of `Receiver::new`.
+#[derive(Debug, Clone)]
+pub struct ReceiverBuilder {
+    buffer_limit: NonZeroUsize,
+    subflow_buffer_limit: NonZeroUsize,
+    expected_sequence: Sequence,
+    gap_timeout: Option<Duration>,
+    sequence_width: SeqWidth,
+    max_unknown_frame_size: usize,
+    max_payload_size: usize,
+    max_frame_sizes: Vec<Option<usize>>,
+    ack_every: NonZeroUsize,
+    ack_delay: Duration,
+    ack_on_gap_fill: bool,
+    control_stream: Option<PendingControl>,
+    session: Option<Session>,
+}
+
+impl ReceiverBuilder {
+    pub fn new() -> Self {
+        Self {
+            buffer_limit: NonZeroUsize::MAX,
+            subflow_buffer_limit: NonZeroUsize::MAX,
+            expected_sequence: Sequence::new(0),
+            gap_timeout: None,
+            sequence_width: SeqWidth::default(),
+            max_unknown_frame_size: DEFAULT_MAX_UNKNOWN_FRAME_SIZE,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            max_frame_sizes: Vec::new(),
+            ack_every: NonZeroUsize::new(DEFAULT_ACK_EVERY).unwrap(),
+            ack_delay: DEFAULT_ACK_DELAY,
+            ack_on_gap_fill: true,
+            control_stream: None,
+            session: None,
+        }
+    }
+
+    /// See `Receiver::with_buffer_limit`
+    pub fn buffer_limit(mut self, limit: NonZeroUsize) -> Self {
+        self.buffer_limit = limit;
+        self
+    }
+
+    /// Hold at most `limit` bytes of out-of-order data from any one stream
+    ///
+    /// A stream that races ahead of the others is not read any further once it has put `limit` bytes into the buffer, while the streams filling the gap before them keep being read.
+    /// Each stream's share shows in `ReceiverStats::subflow_buffered_bytes`.
+    pub fn subflow_buffer_limit(mut self, limit: NonZeroUsize) -> Self {
+        self.subflow_buffer_limit = limit;
+        self
+    }
+
+    /// See `Receiver::with_expected_sequence`
+    pub fn expected_sequence(mut self, sequence: Sequence) -> Self {
+        self.expected_sequence = sequence;
+        self
+    }
+
+    /// See `Receiver::set_gap_timeout`
+    pub fn gap_timeout(mut self, timeout: Duration) -> Self {
+        self.gap_timeout = Some(timeout);
+        self
+    }
+
+    /// Expect the sequences of the frames to be `width` wide, as set by `SenderBuilder::sequence_width` on the peer
+    ///
+    /// A stream whose handshake announces another width ends with `crate::message::HandshakeError::SequenceWidthMismatch` before carrying anything.
+    pub fn sequence_width(mut self, width: SeqWidth) -> Self {
+        self.sequence_width = width;
+        self
+    }
+
+    /// Skip unknown extension frames of at most `limit` payload bytes, 4 KiB by default
+    ///
+    /// A stream carrying a larger one ends with `RecvError::ProtocolViolation`.
+    pub fn max_unknown_frame_size(mut self, limit: usize) -> Self {
+        self.max_unknown_frame_size = limit;
+        self
+    }
+
+    /// Reject the frames of a payload larger than `size` on every stream, `DEFAULT_MAX_PAYLOAD_SIZE` by default
+    ///
+    /// The length is checked before anything is allocated for the payload, so that a corrupt length field costs nothing.
+    /// The stream ends with `RecvError::ProtocolViolation` in its `SubflowError`. See `Sender::set_max_segment_size` for the segments of the sender.
+    pub fn max_payload_size(mut self, size: NonZeroUsize) -> Self {
+        self.max_payload_size = size.get();
+        self
+    }
+
+    /// Reject the data segments of the stream at `index` whose frame takes more than `size` bytes, counting `DATA_SEGMENT_OVERHEAD` for the header
+    ///
+    /// The stream ends with `RecvError::ProtocolViolation` in its `SubflowError`.
+    /// Advertise the limit to the peer with `Sender::advertise_max_frame_size` on the opposite direction.
+    pub fn max_frame_size(mut self, index: usize, size: NonZeroUsize) -> Self {
+        if self.max_frame_sizes.len() <= index {
+            self.max_frame_sizes.resize(index + 1, None);
+        }
+        self.max_frame_sizes[index] = Some(size.get());
+        self
+    }
+
+    /// Let `Receiver::ack_frames` acknowledge once `frames` data segments have arrived since the last acknowledgement, 16 by default
+    pub fn ack_every(mut self, frames: NonZeroUsize) -> Self {
+        self.ack_every = frames;
+        self
+    }
+
+    /// Let `Receiver::ack_frames` acknowledge at most `delay` after the oldest data segment not acknowledged yet arrived, 25 ms by default
+    pub fn ack_delay(mut self, delay: Duration) -> Self {
+        self.ack_delay = delay;
+        self
+    }
+
+    /// Whether `Receiver::ack_frames` acknowledges right away a segment that fills a gap, on by default
+    ///
+    /// The sender learns early that the data past the gap arrived, e.g., before it decides to retransmit it.
+    pub fn ack_on_gap_fill(mut self, enabled: bool) -> Self {
+        self.ack_on_gap_fill = enabled;
+        self
+    }
+
+    /// Reject the streams whose handshake announces another session than `session`, see `SenderBuilder::session`
+    ///
+    /// Whether or not a session is given, a stream disagreeing with the handshakes of the streams read before ends with `crate::session::SessionSetupError` before carrying anything.
+    /// It disagrees if it repeats the subflow ID of one of them, announces another session, announces one unlike them or the other way around, or negotiated other parameters.
+    pub fn session(mut self, session: Session) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Exchange control frames with the `Sender` of the peer over `stream`, whose other end goes to its `SenderBuilder::control_stream`
+    ///
+    /// The acknowledgements of `Receiver::ack_frames` go out on it too, for `Sender::control_acks` on the other end.
+    /// Its control frames show in `Receiver::control_frames` with the index after those of the streams, and `Receiver::send_control` writes on it.
+    /// The first build takes the stream, and builds of clones of this builder go without it.
+    pub fn control_stream(mut self, stream: impl ControlStream) -> Self {
+        self.control_stream = Some(PendingControl::new(stream));
+        self
+    }
+
+    pub fn build<R>(self, streams: Vec<R>) -> Receiver
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let subflows = streams.len();
+        Receiver::build(streams, subflows, self)
+    }
+
+    /// Start a `PartialReceiver` expecting `streams` streams, reading each as soon as it joins
+    pub fn build_partial(self, streams: NonZeroUsize) -> PartialReceiver {
+        PartialReceiver {
+            receiver: Receiver::build(Vec::<tokio::io::Empty>::new(), streams.get(), self),
+            expected: streams,
+        }
+    }
+}
+
+impl Default for ReceiverBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Move a cumulative acknowledgement forward only
+/// Hand a due acknowledgement to `Receiver::ack_frames` and to the control stream
+fn emit_ack(
+    recv_buf: &RecvStreamBuf,
+    ack_frames: &Tap<AckFrame>,
+    control: Option<&mpsc::UnboundedSender<Message>>,
+) {
+    if let Some(control) = control {
+        let _ = control.send(Message::Ack(recv_buf.received()));
+    }
+    tap(ack_frames, AckFrame::of(recv_buf));
+}
+
+/// What the tasks reading the subflows share with each other and with the `Receiver`
+#[derive(Debug, Clone)]
+struct Subflows {
+    recv_buf: Arc<RwLock<RecvStreamBuf>>,
+    recv_buf_inserted: Arc<Notify>,
+    recv_buf_popped: Arc<Notify>,
+    subflow_errors: Arc<Mutex<Vec<SubflowError>>>,
+    last_message: Arc<Mutex<Vec<Option<Instant>>>>,
+    stream_ended: Arc<Notify>,
+    parked: Parked,
+    /// Closed once the `Receiver` is dropped
+    closed_tx: mpsc::Sender<()>,
+    acks: Arc<watch::Sender<Sequence>>,
+    peer_acks: Arc<watch::Sender<Sequence>>,
+    peer_hellos: Arc<Mutex<Vec<Option<Hello>>>>,
+    control_frames: Tap<ControlFrame>,
+    unknown_frames: Tap<UnknownFrame>,
+    probes: Tap<ProbeFrame>,
+    pongs: Tap<ProbeFrame>,
+    oob_messages: Tap<OobMessage>,
+    peer_feedback: Tap<FeedbackFrame>,
+    ack_frames: Tap<AckFrame>,
+    control: Option<mpsc::UnboundedSender<Message>>,
+    ack_schedule: Arc<Mutex<AckSchedule>>,
+    oob_window: Arc<Mutex<OobWindow>>,
+    setup: Arc<Mutex<SetupCheck>>,
+    counters: Arc<Counters>,
+    limit: NonZeroUsize,
+    subflow_limit: NonZeroUsize,
+    expected: Sequence,
+    sequence_width: SeqWidth,
+    unknown_frame_limit: usize,
+    max_payload_size: usize,
+    max_frame_sizes: Vec<Option<usize>>,
+}
+
+impl Subflows {
+    /// Read `stream` as the subflow at `index` on a task of `tasks`
+    fn spawn<R>(&self, tasks: &mut JoinSet<()>, index: usize, mut stream: R)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let Self {
+            recv_buf,
+            recv_buf_inserted,
+            recv_buf_popped,
+            subflow_errors,
+            last_message,
+            stream_ended,
+            parked,
+            closed_tx,
+            acks,
+            peer_acks,
+            peer_hellos,
+            control_frames,
+            unknown_frames,
+            probes,
+            pongs,
+            oob_messages,
+            peer_feedback,
+            ack_frames,
+            control,
+            ack_schedule,
+            oob_window,
+            setup,
+            counters,
+            limit,
+            subflow_limit,
+            expected,
+            sequence_width,
+            unknown_frame_limit,
+            max_payload_size,
+            max_frame_sizes,
+        } = self.clone();
+        let max_frame_size = max_frame_sizes.get(index).copied().flatten();
+        let subflow = counters.subflow(index);
+        tasks.spawn(async move {
+            let _ended = scopeguard::guard((), |()| {
+                last_message.lock().unwrap()[index] = None;
+                stream_ended.notify_waiters();
+            });
+
+            // Drop an incompatible stream before it can put anything into the buffer
+            let res = select! {
+                () = closed_tx.closed() => {
+                    linger(stream).await;
+                    return;
+                }
+                res = read_hello(&mut stream) => res,
+            };
+            let res = res.and_then(|hello| {
+                setup
+                    .lock()
+                    .unwrap()
+                    .admit(index, &hello)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                hello.expect_sequence_width(sequence_width)?;
+                Ok(hello)
+            });
+            let report = |error| {
+                let mut subflow_errors = subflow_errors.lock().unwrap();
+                subflow_errors.push(SubflowError { index, error });
+            };
+            let hello = match res {
+                Ok(hello) => hello,
+                Err(e) => {
+                    report(e);
+                    return;
+                }
+            };
+            peer_hellos.lock().unwrap()[index] = Some(hello);
+            last_message.lock().unwrap()[index] = Some(Instant::now());
+            stream_ended.notify_waiters();
+
+            // Each read of passthrough bytes makes a data segment, held to the limits of frames
+            let read_size = max_frame_size
+                .map_or(PASSTHROUGH_READ_SIZE, |limit| {
+                    limit.saturating_sub(DATA_SEGMENT_OVERHEAD)
+                })
+                .min(max_payload_size)
+                .clamp(1, PASSTHROUGH_READ_SIZE);
+            let mut passthrough = Passthrough::new(hello.passthrough(), read_size);
+            let mut header = HeaderContext::with_sequence_width(sequence_width);
+            header.observe(expected);
+            loop {
+                if sequence_width == SeqWidth::U32 {
+                    header.observe(recv_buf.read().unwrap().next());
+                    header.observe_ack(*peer_acks.borrow());
+                }
+                let res = select! {
+                    () = closed_tx.closed() => {
+                        linger(stream).await;
+                        break;
+                    }
+                    // `Message::decode_next_in` is NOT cancel safe but it's OK if it will not be called again
+                    res = passthrough.next_message(&mut stream, &mut header, max_payload_size) => res,
+                };
+
+                let subflow = &*subflow;
+                let message = match res {
+                    Ok(Some(message)) => {
+                        last_message.lock().unwrap()[index] = Some(Instant::now());
+                        subflow.frames.fetch_add(1, Ordering::Relaxed);
+                        subflow.observe_arrival(counters.now());
+                        message
+                    }
+                    // The stream ended between two messages, so the others carry on without it
+                    Ok(None) => break,
+                    Err(e) => {
+                        if is_checksum_mismatch(&e) {
+                            subflow.checksum_failures.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // Nothing after a corrupt frame can be trusted, so the stream is not read any further
+                        report(protocol_violation(index, e));
+                        break;
+                    }
+                };
+                let data_segment = match message {
+                    Message::DataSegment(data_segment) => {
+                        let length = DATA_SEGMENT_OVERHEAD + data_segment.size();
+                        if let Some(limit) = max_frame_size.filter(|&limit| limit < length) {
+                            let e = DecodeError::FrameTooLarge { length, limit };
+                            report(protocol_violation(index, e.into()));
+                            break;
+                        }
+                        let size = data_segment.size() as u64;
+                        subflow.bytes.fetch_add(size, Ordering::Relaxed);
+                        data_segment
+                    }
+                    Message::Ping => continue,
+                    Message::Fin(fin) => {
+                        recv_buf.write().unwrap().set_fin(fin);
+                        recv_buf_inserted.notify_waiters();
+                        continue;
+                    }
+                    Message::Abort { sequence, reason } => {
+                        recv_buf.write().unwrap().set_abort(sequence, reason);
+                        recv_buf_inserted.notify_waiters();
+                        continue;
+                    }
+                    Message::Ack(ack) => {
+                        peer_acks.send_if_modified(|peer_ack| advance(peer_ack, ack));
+                        continue;
+                    }
+                    Message::Control(payload) => {
+                        tap(&control_frames, ControlFrame { index, payload });
+                        continue;
+                    }
+                    // Skip the extension frames of newer peers, only so large that a stream cannot make us buffer much
+                    Message::Unknown { type_code, payload } => {
+                        if payload.len() > unknown_frame_limit {
+                            let e = DecodeError::FrameTooLarge {
+                                length: payload.len(),
+                                limit: unknown_frame_limit,
+                            };
+                            report(protocol_violation(index, e.into()));
+                            break;
+                        }
+                        subflow.unknown_frames.fetch_add(1, Ordering::Relaxed);
+                        let frame = UnknownFrame {
+                            index,
+                            type_code,
+                            payload,
+                        };
+                        tap(&unknown_frames, frame);
+                        continue;
+                    }
+                    Message::Probe(timestamp) => {
+                        tap(&probes, ProbeFrame { index, timestamp });
+                        continue;
+                    }
+                    Message::Pong(timestamp) => {
+                        tap(&pongs, ProbeFrame { index, timestamp });
+                        continue;
+                    }
+                    Message::Feedback(scores) => {
+                        tap(&peer_feedback, FeedbackFrame { index, scores });
+                        continue;
+                    }
+                    Message::Oob { sequence, payload } => {
+                        if oob_window.lock().unwrap().insert(sequence) {
+                            let message = OobMessage {
+                                index,
+                                sequence,
+                                payload,
+                            };
+                            tap(&oob_messages, message);
+                        }
+                        continue;
+                    }
+                    Message::Shutdown => {
+                        parked.lock().unwrap()[index] = Some(Box::new(stream));
+                        break;
+                    }
+                };
+
+                // Pause reading this stream until the segment fits in the buffer and in its share of it
+                let received = loop {
+                    let recv_buf_popped = recv_buf_popped.notified();
+                    {
+                        let mut recv_buf = recv_buf.write().unwrap();
+                        if recv_buf.admits_from(
+                            index,
+                            &data_segment,
+                            limit.get(),
+                            subflow_limit.get(),
+                        ) {
+                            let before = recv_buf.received();
+                            let end = data_segment.end_sequence();
+                            recv_buf.insert_from(index, data_segment);
+                            counters.observe_head(&recv_buf);
+                            let received = recv_buf.received();
+                            // The segment made the data buffered past it contiguous
+                            let filled_gap = before.max(end) < received;
+                            if filled_gap {
+                                subflow.gaps.fetch_add(1, Ordering::Relaxed);
+                            }
+                            if ack_schedule.lock().unwrap().on_segment(filled_gap) {
+                                emit_ack(&recv_buf, &ack_frames, control.as_ref());
+                            }
+                            break Some(received);
+                        }
+                    }
+                    select! {
+                        () = recv_buf_popped => (),
+                        () = closed_tx.closed() => break None,
+                    }
+                };
+                let Some(received) = received else {
+                    linger(stream).await;
+                    break;
+                };
+
+                recv_buf_inserted.notify_waiters();
+                acks.send_if_modified(|ack| advance(ack, received));
+            }
+        });
+    }
+}
+
+/// The passthrough bytes a subflow may start with, see `crate::sender::Sender::set_passthrough`
+#[derive(Debug)]
+struct Passthrough {
+    /// The sequence of the next passthrough byte, until the subflow switches to frames
+    next: Option<Sequence>,
+    read_size: usize,
+    unescape: Unescape,
+    /// Bytes read past the switch, the start of the first frame
+    leftover: io::Cursor<Bytes>,
+}
+
+impl Passthrough {
+    fn new(start: Option<Sequence>, read_size: usize) -> Self {
+        Self {
+            next: start,
+            read_size,
+            unescape: Unescape::default(),
+            leftover: io::Cursor::new(Bytes::new()),
+        }
+    }
+
+    /// The next message of `stream`, a data segment of what passthrough bytes came next until they switch to frames
+    async fn next_message<R>(
+        &mut self,
+        stream: &mut R,
+        header: &mut HeaderContext,
+        max_payload_size: usize,
+    ) -> io::Result<Option<Message>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        while let Some(next) = self.next {
+            let mut raw = BytesMut::with_capacity(self.read_size);
+            if stream
+                .read_buf(&mut (&mut raw).limit(self.read_size))
+                .await?
+                == 0
+            {
+                return Ok(None);
+            }
+            let (len, switch) = self.unescape.unescape(&mut raw)?;
+            if let Some(switch) = switch {
+                self.leftover = io::Cursor::new(raw.split_off(switch).freeze());
+                self.next = None;
+            }
+            raw.truncate(len);
+            if raw.is_empty() {
+                continue;
+            }
+            let data_segment = DataSegment::new(next, raw.freeze())
+                .ok_or(DecodeError::InvalidDataSegment { sequence: next })?;
+            if self.next.is_some() {
+                self.next = Some(data_segment.end_sequence());
+            }
+            return Ok(Some(Message::DataSegment(data_segment)));
+        }
+        let mut stream = (&mut self.leftover).chain(stream);
+        Message::decode_next_limited(&mut stream, header, max_payload_size).await
+    }
+}
+
+pub(crate) fn advance(ack: &mut Sequence, to: Sequence) -> bool {
+    if to <= *ack {
+        return false;
+    }
+    *ack = to;
+    true
+}
+
+/// Copy `data_segments` in order into `buf` and return how much was copied and what did not fit
+///
+/// Only the last segment may not fit.
+fn copy_out(data_segments: Vec<DataSegment>, buf: &mut [u8]) -> (usize, Option<DataSegment>) {
+    let mut filled = 0;
+    let mut leftover = None;
+    for data_segment in data_segments {
+        let readable = (buf.len() - filled).min(data_segment.size());
+        buf[filled..filled + readable].copy_from_slice(&data_segment.payload()[..readable]);
+        filled += readable;
+        leftover = data_segment.advance(readable);
+    }
+    (filled, leftover)
+}
+
+/// The missing sequence, since when it has been missing, and when to give up on it
+fn gap_deadline(
+    recv_buf: &RecvStreamBuf,
+    timeout: Option<Duration>,
+    gap: &mut Option<(Sequence, Instant)>,
+) -> Option<(Sequence, Instant, Instant)> {
+    let Some(timeout) = timeout.filter(|_| recv_buf.buffered_bytes() != 0) else {
+        *gap = None;
+        return None;
+    };
+    let expected = recv_buf.next();
+    let since = match *gap {
+        Some((sequence, since)) if sequence == expected => since,
+        _ => Instant::now(),
+    };
+    *gap = Some((expected, since));
+    Some((expected, since, since + timeout))
+}
+
+/// When the acknowledgements of `Receiver::ack_frames` are due
+#[derive(Debug)]
+struct AckSchedule {
+    every: usize,
+    delay: Duration,
+    on_gap_fill: bool,
+    /// Data segments arrived since the last acknowledgement
+    unacked: usize,
+    /// When the oldest of them arrived
+    unacked_since: Option<Instant>,
+}
+
+impl AckSchedule {
+    fn new(every: usize, delay: Duration, on_gap_fill: bool) -> Self {
+        Self {
+            every,
+            delay,
+            on_gap_fill,
+            unacked: 0,
+            unacked_since: None,
+        }
+    }
+
+    /// Count a data segment in and return whether it is to be acknowledged right away
+    fn on_segment(&mut self, filled_gap: bool) -> bool {
+        self.unacked += 1;
+        self.unacked_since.get_or_insert_with(Instant::now);
+        let due = self.every <= self.unacked || (self.on_gap_fill && filled_gap);
+        if due {
+            self.reset();
+        }
+        due
+    }
+
+    /// When the delayed acknowledgement is due, if any segment waits for one
+    fn deadline(&self) -> Option<Instant> {
+        self.unacked_since.map(|since| since + self.delay)
+    }
+
+    fn reset(&mut self) {
+        self.unacked = 0;
+        self.unacked_since = None;
+    }
+}
+
+/// Counters bumped by the receive tasks and the reassembly without locking
+#[derive(Debug)]
+struct Counters {
+    /// What the instants below count from
+    epoch: Instant,
+    /// `Self::now` when the head of line went missing, or 0 while it is not
+    blocked_since: AtomicU64,
+    /// Nanoseconds of the past head-of-line waits
+    head_of_line_wait: AtomicU64,
+    max_gap: AtomicU64,
+    subflows: RwLock<Vec<Arc<SubflowCounters>>>,
+}
+
+#[derive(Debug, Default)]
+struct SubflowCounters {
+    bytes: AtomicU64,
+    frames: AtomicU64,
+    checksum_failures: AtomicU64,
+    unknown_frames: AtomicU64,
+    /// Data segments that filled the gap the byte stream waited on
+    gaps: AtomicU64,
+    /// Smoothed nanoseconds between the intervals of two frames, only updated by the task reading the subflow
+    jitter: AtomicU64,
+    /// `Counters::now` of the last frame, or 0 before the first
+    last_arrival: AtomicU64,
+    /// Nanoseconds between the last two frames, or 0 before the second
+    last_interval: AtomicU64,
+}
+
+impl SubflowCounters {
+    /// Count a frame arriving at `now` in the jitter
+    fn observe_arrival(&self, now: u64) {
+        let last = self.last_arrival.swap(now, Ordering::Relaxed);
+        if last == 0 {
+            return;
+        }
+        let interval = now.saturating_sub(last);
+        let previous = self.last_interval.swap(interval, Ordering::Relaxed);
+        if previous == 0 {
+            return;
+        }
+        let sample = interval.abs_diff(previous) as f64;
+        let jitter = self.jitter.load(Ordering::Relaxed) as f64;
+        let jitter = jitter + JITTER_SMOOTHING * (sample - jitter);
+        self.jitter.store(jitter as u64, Ordering::Relaxed);
+    }
+
+    fn jitter(&self) -> Duration {
+        Duration::from_nanos(self.jitter.load(Ordering::Relaxed))
+    }
+}
+
+impl Counters {
+    fn new(streams: usize) -> Self {
+        Self {
+            epoch: Instant::now(),
+            blocked_since: AtomicU64::new(0),
+            head_of_line_wait: AtomicU64::new(0),
+            max_gap: AtomicU64::new(0),
+            subflows: RwLock::new((0..streams).map(|_| Arc::default()).collect()),
+        }
+    }
+
+    /// The counters of the subflow at `index`, added along with those before it if need be
+    fn subflow(&self, index: usize) -> Arc<SubflowCounters> {
+        let mut subflows = self.subflows.write().unwrap();
+        if subflows.len() <= index {
+            subflows.resize_with(index + 1, Arc::default);
+        }
+        subflows[index].clone()
+    }
+
+    /// Nanoseconds since `Self::epoch`, never 0
+    fn now(&self) -> u64 {
+        u64::try_from(self.epoch.elapsed().as_nanos()).unwrap_or(u64::MAX - 1) + 1
+    }
+
+    /// Start or end a head-of-line wait as the head of `recv_buf` goes missing or arrives
+    ///
+    /// Called with `recv_buf` locked for writing, which orders the calls.
+    fn observe_head(&self, recv_buf: &RecvStreamBuf) {
+        match recv_buf.head_gap() {
+            Some(gap) => {
+                self.max_gap.fetch_max(gap, Ordering::Relaxed);
+                if self.blocked_since.load(Ordering::Relaxed) == 0 {
+                    self.blocked_since.store(self.now(), Ordering::Relaxed);
+                    trace::debug_event!(
+                        expected = recv_buf.next().inner(),
+                        missing_bytes = gap,
+                        "gap_opened"
+                    );
+                }
+            }
+            None => {
+                let since = self.blocked_since.swap(0, Ordering::Relaxed);
+                if since != 0 {
+                    let waited = self.now().saturating_sub(since);
+                    self.head_of_line_wait.fetch_add(waited, Ordering::Relaxed);
+                    trace::debug_event!(
+                        next = recv_buf.next().inner(),
+                        waited_us = waited / 1000,
+                        "gap_closed"
+                    );
+                }
+            }
+        }
+    }
+
+    /// The scores of the `live` subflows since `reported`, the frames and gaps of each subflow as of the previous call
+    fn scores(&self, live: &[bool], reported: &mut Vec<(u64, u64)>) -> Vec<SubflowScore> {
+        let subflows = self.subflows.read().unwrap();
+        reported.resize(subflows.len(), (0, 0));
+        subflows
+            .iter()
+            .zip(reported)
+            .enumerate()
+            .filter_map(|(index, (subflow, reported))| {
+                let frames = subflow.frames.load(Ordering::Relaxed);
+                let gaps = subflow.gaps.load(Ordering::Relaxed);
+                let (frames_before, gaps_before) = std::mem::replace(reported, (frames, gaps));
+                if !live.get(index).copied().unwrap_or(false) {
+                    return None;
+                }
+                let index = u32::try_from(index).ok()?;
+                Some(SubflowScore::new(
+                    index,
+                    frames.saturating_sub(frames_before),
+                    gaps.saturating_sub(gaps_before),
+                    subflow.jitter(),
+                ))
+            })
+            .take(MAX_FEEDBACK_SCORES)
+            .collect()
+    }
+
+    /// The past head-of-line waits plus the ongoing one
+    fn head_of_line_wait(&self) -> Duration {
+        let mut wait = self.head_of_line_wait.load(Ordering::Relaxed);
+        let since = self.blocked_since.load(Ordering::Relaxed);
+        if since != 0 {
+            wait += self.now().saturating_sub(since);
+        }
+        Duration::from_nanos(wait)
+    }
+
+    fn reset(&self) {
+        if self.blocked_since.load(Ordering::Relaxed) != 0 {
+            self.blocked_since.store(self.now(), Ordering::Relaxed);
+        }
+        self.head_of_line_wait.store(0, Ordering::Relaxed);
+        self.max_gap.store(0, Ordering::Relaxed);
+        for subflow in self.subflows.read().unwrap().iter() {
+            subflow.bytes.store(0, Ordering::Relaxed);
+            subflow.frames.store(0, Ordering::Relaxed);
+            subflow.checksum_failures.store(0, Ordering::Relaxed);
+            subflow.unknown_frames.store(0, Ordering::Relaxed);
+            subflow.gaps.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+fn is_checksum_mismatch(e: &io::Error) -> bool {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<DecodeError>())
+        .is_some_and(|e| matches!(e, DecodeError::ChecksumMismatch { .. }))
+}
+
+/// `e` as a `RecvError::ProtocolViolation` of the stream at `index` if the frames of the stream broke the wire format
+fn protocol_violation(index: usize, e: io::Error) -> io::Error {
+    if !e.get_ref().is_some_and(|e| e.is::<DecodeError>()) {
+        return e;
+    }
+    let detail = *e.into_inner().unwrap().downcast::<DecodeError>().unwrap();
+    let e = RecvError::ProtocolViolation {
+        stream_id: index,
+        detail,
+    };
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Read the handshake of a stream
+async fn read_hello<R>(stream: &mut R) -> io::Result<Hello>
+where
+    R: AsyncRead + Unpin,
+{
+    #[cfg(feature = "failpoints")]
+    crate::failpoints::hit(crate::failpoints::RECV_HANDSHAKE).await?;
+    Hello::decode(stream).await
+}
+
+/// Pend forever without a deadline
+pub(crate) async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Drain the stream for a while to prevent triggering TCP RST from our side
+async fn linger<R>(mut stream: R)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let mut drain_task = JoinSet::new();
+    drain_task.spawn(async move {
+        let mut buf = [0; 1];
+        loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(())
+    });
+
+    tokio::select! {
+        res = drain_task.join_next() => {
+            if let Some(task) = res {
+                // In case the task panicked
+                let _ = task.unwrap();
+            }
+        }
+        () = tokio::time::sleep(LINGER) => (),
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        // The drop of `self.closed` will signal receive tasks to end later
+        self.recv_tasks.detach_all();
+    }
+}
+
+impl AsyncAsyncRead for Receiver {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv(buf).await
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("No stream left")]
+pub struct NoStreamLeft;
+
+#[derive(Debug, Error)]
+pub enum RecvError {
+    #[error("The segment at {expected:?} has been missing for {waited:?}")]
+    MissingSegment {
+        expected: Sequence,
+        waited: Duration,
+    },
+    /// The stream at `stream_id` in `Receiver::new` carried a frame breaking the wire format, and was not read any further
+    ///
+    /// The data it still owed is missing from the byte stream until sent again on the others.
+    #[error("Protocol violation on stream {stream_id}: {detail}")]
+    ProtocolViolation {
+        stream_id: usize,
+        #[source]
+        detail: DecodeError,
+    },
+    /// The sender cut the byte stream short at `sequence` with `Sender::abort`, so what was read is all there is but not all there was to be
+    #[error("The peer aborted the byte stream at {sequence}: {reason}")]
+    PeerAborted { sequence: Sequence, reason: String },
+}
+
+/// `RecvError::PeerAborted` in an error of `io::ErrorKind::ConnectionAborted` once every byte up to the abort of the peer has been read
+fn peer_aborted(recv_buf: &RecvStreamBuf) -> Option<io::Error> {
+    let (sequence, reason) = recv_buf.aborted()?;
+    let e = RecvError::PeerAborted {
+        sequence,
+        reason: String::from_utf8_lossy(reason).into_owned(),
+    };
+    Some(io::Error::new(io::ErrorKind::ConnectionAborted, e))
+}
+
+/// A stream missed too many heartbeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubflowDead {
+    index: usize,
+}
+
+impl SubflowDead {
+    /// The index of the stream in `Receiver::new`
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+/// A view of the streams of a `Receiver` that have not ended
+#[derive(Debug, Clone)]
+pub(crate) struct Liveness(Arc<Mutex<Vec<Option<Instant>>>>);
+
+impl Liveness {
+    pub(crate) fn live_streams(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.is_some())
+            .count()
+    }
+}
+
+/// A control frame written by `Sender::broadcast_control` or `Sender::send_control`
+#[derive(Debug, Clone)]
+pub struct ControlFrame {
+    index: usize,
+    payload: Bytes,
+}
+
+impl ControlFrame {
+    /// The index of the stream in `Receiver::new` that carried the frame, or the number of streams for the control stream
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        self.payload
+    }
+}
+
+/// An extension frame skipped for having a type code unknown to this version
+#[derive(Debug, Clone)]
+pub struct UnknownFrame {
+    index: usize,
+    type_code: u8,
+    payload: Bytes,
+}
+
+impl UnknownFrame {
+    /// The index of the stream in `Receiver::new` that carried the frame
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// One of `crate::wire::EXTENSION_TYPE_CODES`
+    pub fn type_code(&self) -> u8 {
+        self.type_code
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        self.payload
+    }
+}
+
+/// A cumulative acknowledgement and the selective ones of the data past the gaps, see `Receiver::ack_frames`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AckFrame {
+    cumulative: Sequence,
+    sacks: Vec<Range<Sequence>>,
+}
+
+impl AckFrame {
+    fn of(recv_buf: &RecvStreamBuf) -> Self {
+        Self {
+            cumulative: recv_buf.received(),
+            sacks: recv_buf.sack_ranges(),
+        }
+    }
+
+    /// The sequence right after the contiguous data received, like `Receiver::acks`
+    pub fn cumulative(&self) -> Sequence {
+        self.cumulative
+    }
+
+    /// The ranges of the data received past the gaps, in order
+    pub fn sacks(&self) -> &[Range<Sequence>] {
+        &self.sacks
+    }
+}
+
+/// An out-of-band message written by `Sender::send_oob`
+#[derive(Debug, Clone)]
+pub struct OobMessage {
+    index: usize,
+    sequence: u32,
+    payload: Bytes,
+}
+
+impl OobMessage {
+    /// The index of the stream in `Receiver::new` that carried the message first
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The sequence of the message among those of its sender, which wraps around
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        self.payload
+    }
+}
+
+/// Which out-of-band sequences have been seen, to drop the copies of a message
+#[derive(Debug)]
+struct OobWindow {
+    /// Every sequence before this one has been seen, counted without wrapping around
+    next: u64,
+    /// The sequences seen after `Self::next`
+    seen: BTreeSet<u64>,
+}
+
+impl OobWindow {
+    fn new() -> Self {
+        Self {
+            next: 0,
+            seen: BTreeSet::new(),
+        }
+    }
+
+    /// Whether `sequence` is seen for the first time
+    ///
+    /// A sequence up to half the sequence space behind `Self::next` counts as seen.
+    fn insert(&mut self, sequence: u32) -> bool {
+        let distance = sequence.wrapping_sub(self.next as u32);
+        if 1 << 31 <= distance {
+            return false;
+        }
+        if !self.seen.insert(self.next + u64::from(distance)) {
+            return false;
+        }
+        while self.seen.remove(&self.next) {
+            self.next += 1;
+        }
+        true
+    }
+}
+
+/// A probe or a pong carrying the timestamp of a probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeFrame {
+    index: usize,
+    timestamp: u64,
+}
+
+impl ProbeFrame {
+    pub fn new(index: usize, timestamp: u64) -> Self {
+        Self { index, timestamp }
+    }
+
+    /// The index of the stream in `Receiver::new` that carried the frame
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+/// The scores of a feedback frame and the stream that carried it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedbackFrame {
+    index: usize,
+    scores: Vec<SubflowScore>,
+}
+
+impl FeedbackFrame {
+    pub fn new(index: usize, scores: Vec<SubflowScore>) -> Self {
+        Self { index, scores }
+    }
+
+    /// The index of the stream in `Receiver::new` that carried the frame
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Indexed by the streams of the `Sender` the feedback is about
+    pub fn scores(&self) -> &[SubflowScore] {
+        &self.scores
+    }
+}
+
+/// A snapshot of the reassembly of a `Receiver`
+#[derive(Debug, Clone, Default)]
+pub struct ReceiverStats {
+    buffered_bytes: usize,
+    duplicate_segments: u64,
+    duplicate_bytes: u64,
+    subflow_buffered_bytes: Vec<usize>,
+    head_of_line_wait: Duration,
+    max_gap: u64,
+    checksum_failures: u64,
+    unknown_frames: u64,
+    subflows: Vec<RecvStats>,
+}
+
+impl ReceiverStats {
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Segments that carried bytes received before, e.g., over redundant streams or as retransmissions
+    pub fn duplicate_segments(&self) -> u64 {
+        self.duplicate_segments
+    }
+
+    /// Bytes discarded for having been received before
+    pub fn duplicate_bytes(&self) -> u64 {
+        self.duplicate_bytes
+    }
+
+    /// Bytes waiting in the reassembly buffer by the index of the stream they came from
+    ///
+    /// Streams that have not delivered any data segment yet may be missing from the end.
+    pub fn subflow_buffered_bytes(&self) -> &[usize] {
+        &self.subflow_buffered_bytes
+    }
+
+    /// Time spent with data buffered while the byte stream waited for the segment before it
+    pub fn head_of_line_wait(&self) -> Duration {
+        self.head_of_line_wait
+    }
+
+    /// The most bytes missing at once between the byte stream and the data buffered after it
+    pub fn max_gap(&self) -> u64 {
+        self.max_gap
+    }
+
+    /// Data segments whose checksum did not match, each of which ended its stream
+    pub fn checksum_failures(&self) -> u64 {
+        self.checksum_failures
+    }
+
+    /// Extension frames skipped for having a type code unknown to this version
+    pub fn unknown_frames(&self) -> u64 {
+        self.unknown_frames
+    }
+
+    /// Statistics of every stream, ordered by their index in `Receiver::new`
+    pub fn subflows(&self) -> &[RecvStats] {
+        &self.subflows
+    }
+}
+
+/// What one stream of a `Receiver` carried, like `StreamStats` on the sending side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvStats {
+    index: usize,
+    bytes_received: u64,
+    frames_received: u64,
+    checksum_failures: u64,
+    unknown_frames: u64,
+    gaps: u64,
+    jitter: Duration,
+}
+
+impl RecvStats {
+    /// The index of the stream in `Receiver::new`
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Payload bytes of the data segments received
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Frames of any kind received after the hello
+    pub fn frames_received(&self) -> u64 {
+        self.frames_received
+    }
+
+    pub fn checksum_failures(&self) -> u64 {
+        self.checksum_failures
+    }
+
+    pub fn unknown_frames(&self) -> u64 {
+        self.unknown_frames
+    }
+
+    /// Data segments the byte stream had been waiting for while later data was buffered, see `SubflowScore::gaps`
+    pub fn gaps(&self) -> u64 {
+        self.gaps
+    }
+
+    /// Smoothed variation of the time between two frames, see `SubflowScore::jitter`
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+}
+
+/// A stream ended with an error
+#[derive(Debug)]
+pub struct SubflowError {
+    index: usize,
+    error: io::Error,
+}
+
+impl SubflowError {
+    /// The index of the stream in `Receiver::new`
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    pub fn into_error(self) -> io::Error {
+        self.error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{
+        message::{HandshakeError, Sequence},
+        sender::SenderBuilder,
+        session::SessionSetupError,
+    };
+
+    use super::*;
+
+    async fn write_hello<W>(stream: &mut W)
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        Hello::new(0).encode(stream).await.unwrap();
+    }
+
+    async fn write_segment<W>(stream: &mut W, start: u64, payload: Vec<u8>)
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        let data_segment = DataSegment::new(Sequence::new(start), Bytes::from(payload)).unwrap();
+        Message::DataSegment(data_segment)
+            .encode(stream)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn eager_delivery() {
+        let (mut first_tx, first_rx) = tokio::io::duplex(64);
+        let (mut second_tx, second_rx) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![first_rx, second_rx]);
+        write_hello(&mut first_tx).await;
+        write_hello(&mut second_tx).await;
+
+        // The second segment is still on its way
+        write_segment(&mut first_tx, 0, b"hello".to_vec()).await;
+        let mut buf = [0; 64];
+        let n = tokio::time::timeout(Duration::from_millis(100), receiver.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], b"hello");
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        write_segment(&mut second_tx, 5, b" world".to_vec()).await;
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b" world");
+    }
+
+    #[tokio::test]
+    async fn drain_contiguous_segments() {
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for _ in 0..3 {
+            let (tx, rx) = tokio::io::duplex(64);
+            send_streams.push(tx);
+            recv_streams.push(rx);
+        }
+        let mut receiver = Receiver::new(recv_streams);
+        for (i, tx) in send_streams.iter_mut().enumerate().rev() {
+            write_hello(tx).await;
+            write_segment(tx, i as u64 * 5, vec![b'a' + i as u8; 5]).await;
+        }
+        while receiver.buffered_bytes() < 15 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // One read takes every contiguous segment that fits, the last one partially
+        let mut buf = [0; 12];
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"aaaaabbbbbcc");
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ccc");
+    }
+
+    #[tokio::test]
+    async fn bounded_buffer() {
+        const SEGMENT: usize = 1024;
+        const SEGMENTS: usize = 16;
+        const LIMIT: usize = SEGMENT * 4;
+        let (mut slow_tx, slow_rx) = tokio::io::duplex(64);
+        let (mut fast_tx, fast_rx) = tokio::io::duplex(64);
+        let receiver =
+            Receiver::with_buffer_limit(vec![slow_rx, fast_rx], NonZeroUsize::new(LIMIT).unwrap());
+
+        let msg: Vec<u8> = (0..SEGMENT * (SEGMENTS + 1)).map(|i| i as u8).collect();
+        let fast_msg = msg.clone();
+        write_hello(&mut slow_tx).await;
+        let fast_task = tokio::spawn(async move {
+            write_hello(&mut fast_tx).await;
+            for i in 1..=SEGMENTS {
+                let payload = fast_msg[i * SEGMENT..(i + 1) * SEGMENT].to_vec();
+                write_segment(&mut fast_tx, (i * SEGMENT) as u64, payload).await;
+            }
+            fast_tx
+        });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(receiver.buffered_bytes() <= LIMIT);
+        assert!(!fast_task.is_finished());
+
+        // The slow stream is still read while the fast one is paused
+        write_segment(&mut slow_tx, 0, msg[..SEGMENT].to_vec()).await;
+        let mut async_read = receiver.into_async_read();
+        let mut buf = vec![0; msg.len()];
+        async_read.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+        fast_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn report_dead_subflow() {
+        const INTERVAL: Duration = Duration::from_millis(20);
+        let (mut alive_tx, alive_rx) = tokio::io::duplex(64);
+        let (_dead_tx, dead_rx) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![alive_rx, dead_rx]);
+        let mut dead = receiver.watch_keepalive(INTERVAL, NonZeroUsize::new(3).unwrap());
+
+        let heartbeat_task = tokio::spawn(async move {
+            write_hello(&mut alive_tx).await;
+            loop {
+                Message::Ping.encode(&mut alive_tx).await.unwrap();
+                tokio::time::sleep(INTERVAL).await;
+            }
+        });
+
+        let subflow_dead = dead.recv().await.unwrap();
+        assert_eq!(subflow_dead.index(), 1);
+        let res = tokio::time::timeout(INTERVAL * 6, dead.recv()).await;
+        assert!(res.is_err());
+        heartbeat_task.abort();
+    }
+
+    #[tokio::test]
+    async fn reject_incompatible_stream() {
+        let (mut good_tx, good_rx) = tokio::io::duplex(64);
+        let (mut bad_tx, bad_rx) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![good_rx, bad_rx]);
+
+        bad_tx.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        write_hello(&mut good_tx).await;
+        write_segment(&mut good_tx, 0, b"hello".to_vec()).await;
+        let mut buf = [0; 5];
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+
+        let (mut bad_tx, bad_rx) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![bad_rx]);
+        let mut hello = vec![];
+        Hello::new(0).encode(&mut hello).await.unwrap();
+        hello[4] += 1;
+        bad_tx.write_all(&hello).await.unwrap();
+        write_segment(&mut bad_tx, 0, b"hello".to_vec()).await;
+        let err = receiver.recv(&mut buf).await.unwrap_err();
+        let err = err
+            .into_inner()
+            .unwrap()
+            .downcast::<HandshakeError>()
+            .unwrap();
+        assert!(matches!(*err, HandshakeError::VersionMismatch { .. }));
+    }
+
+    #[tokio::test]
+    async fn fin_after_data() {
+        let (mut tx_1, rx_1) = tokio::io::duplex(64);
+        let (mut tx_2, rx_2) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![rx_1, rx_2]);
+
+        write_hello(&mut tx_1).await;
+        write_hello(&mut tx_2).await;
+        write_segment(&mut tx_1, 0, b"hello".to_vec()).await;
+        Message::Fin(Sequence::new(5))
+            .encode(&mut tx_1)
+            .await
+            .unwrap();
+
+        let mut buf = [0; 5];
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        // The other stream is still open
+        assert_eq!(receiver.recv(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn fin_before_data() {
+        let (mut tx_1, rx_1) = tokio::io::duplex(64);
+        let (mut tx_2, rx_2) = tokio::io::duplex(64);
+        let receiver = Receiver::new(vec![rx_1, rx_2]);
+
+        write_hello(&mut tx_1).await;
+        write_hello(&mut tx_2).await;
+        write_segment(&mut tx_1, 0, b"hello".to_vec()).await;
+        Message::Fin(Sequence::new(11))
+            .encode(&mut tx_1)
+            .await
+            .unwrap();
+        Message::Shutdown.encode(&mut tx_1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        write_segment(&mut tx_2, 5, b" world".to_vec()).await;
+        Message::Shutdown.encode(&mut tx_2).await.unwrap();
+
+        let mut buf = vec![];
+        receiver
+            .into_async_read()
+            .read_to_end(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn subflow_eof() {
+        let (mut tx_1, rx_1) = tokio::io::duplex(64);
+        let (mut tx_2, rx_2) = tokio::io::duplex(64);
+        let (mut tx_3, rx_3) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![rx_1, rx_2, rx_3]);
+        assert_eq!(receiver.live_streams(), 3);
+
+        for tx in [&mut tx_1, &mut tx_2, &mut tx_3] {
+            write_hello(tx).await;
+        }
+        write_segment(&mut tx_1, 0, b"hello".to_vec()).await;
+        write_segment(&mut tx_2, 5, b" ".to_vec()).await;
+        drop(tx_2);
+        // End in the middle of a data segment
+        tx_3.write_all(&[0; 5]).await.unwrap();
+        drop(tx_3);
+        write_segment(&mut tx_1, 6, b"world".to_vec()).await;
+        Message::Fin(Sequence::new(11))
+            .encode(&mut tx_1)
+            .await
+            .unwrap();
+
+        let mut buf = [0; 11];
+        let mut read = 0;
+        while read < buf.len() {
+            read += receiver.recv(&mut buf[read..]).await.unwrap();
+        }
+        assert_eq!(&buf, b"hello world");
+        assert_eq!(receiver.recv(&mut buf).await.unwrap(), 0);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(receiver.live_streams(), 1);
+        let errors = receiver.take_subflow_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].index(), 2);
+        assert_eq!(errors[0].error().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn gap_timeout() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![rx]);
+        receiver.set_gap_timeout(Some(TIMEOUT));
+        write_hello(&mut tx).await;
+        write_segment(&mut tx, 0, b"hello".to_vec()).await;
+        write_segment(&mut tx, 6, b"world".to_vec()).await;
+
+        let mut buf = [0; 11];
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        let start = Instant::now();
+        let err = receiver.recv(&mut buf).await.unwrap_err();
+        assert!(start.elapsed() >= TIMEOUT);
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err = err.into_inner().unwrap().downcast::<RecvError>().unwrap();
+        let RecvError::MissingSegment { expected, waited } = *err else {
+            panic!("{err:?}");
+        };
+        assert_eq!(expected, Sequence::new(5));
+        assert!(waited >= TIMEOUT);
+
+        // The wait starts over after the gap is filled
+        write_segment(&mut tx, 5, b" ".to_vec()).await;
+        write_segment(&mut tx, 12, b"!".to_vec()).await;
+        tokio::time::sleep(TIMEOUT / 5).await;
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b" world");
+        let start = Instant::now();
+        let err = receiver.recv(&mut buf).await.unwrap_err();
+        assert!(start.elapsed() >= TIMEOUT);
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn expected_sequence() {
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut receiver = Receiver::with_expected_sequence(vec![rx], Sequence::new(100));
+        let acks = receiver.acks();
+        assert_eq!(*acks.borrow(), Sequence::new(100));
+        write_hello(&mut tx).await;
+        write_segment(&mut tx, 95, b"stale".to_vec()).await;
+        write_segment(&mut tx, 100, b"hello".to_vec()).await;
+
+        let mut buf = [0; 5];
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+    }
+
+    #[tokio::test]
+    async fn builder() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut receiver = ReceiverBuilder::new()
+            .buffer_limit(NonZeroUsize::new(4).unwrap())
+            .expected_sequence(Sequence::new(100))
+            .gap_timeout(TIMEOUT)
+            .build(vec![rx]);
+        write_hello(&mut tx).await;
+        write_segment(&mut tx, 100, b"hello".to_vec()).await;
+        write_segment(&mut tx, 106, b"wo".to_vec()).await;
+        write_segment(&mut tx, 108, b"rld".to_vec()).await;
+        let mut buf = [0; 5];
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+
+        // The segment past the buffer limit is held back on the stream
+        tokio::time::sleep(TIMEOUT / 5).await;
+        assert_eq!(receiver.buffered_bytes(), 2);
+        let err = receiver.recv(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let (mut tx_1, rx_1) = tokio::io::duplex(64);
+        let (tx_2, rx_2) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![rx_1, rx_2]);
+
+        write_hello(&mut tx_1).await;
+        write_segment(&mut tx_1, 0, b"hello".to_vec()).await;
+        Message::Fin(Sequence::new(11))
+            .encode(&mut tx_1)
+            .await
+            .unwrap();
+        Message::Shutdown.encode(&mut tx_1).await.unwrap();
+        drop(tx_2);
+
+        let mut buf = [0; 11];
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        let err = receiver.recv(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Streams that shut down without a FIN truncate the byte stream too
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![rx]);
+        write_hello(&mut tx).await;
+        write_segment(&mut tx, 0, b"hello".to_vec()).await;
+        Message::Shutdown.encode(&mut tx).await.unwrap();
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        let err = receiver.recv(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn acks() {
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let receiver = Receiver::new(vec![rx]);
+        let mut acks = receiver.acks();
+        let mut peer_acks = receiver.peer_acks();
+
+        write_hello(&mut tx).await;
+        write_segment(&mut tx, 5, b"world".to_vec()).await;
+        write_segment(&mut tx, 0, b"hello".to_vec()).await;
+        acks.wait_for(|ack| *ack == Sequence::new(10))
+            .await
+            .unwrap();
+
+        Message::Ack(Sequence::new(3))
+            .encode(&mut tx)
+            .await
+            .unwrap();
+        peer_acks.changed().await.unwrap();
+        assert_eq!(*peer_acks.borrow(), Sequence::new(3));
+    }
+
+    #[tokio::test]
+    async fn duplicate_segments() {
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![rx]);
+        write_hello(&mut tx).await;
+
+        // Resent segments split differently
+        write_segment(&mut tx, 0, b"hello ".to_vec()).await;
+        write_segment(&mut tx, 3, b"lo world".to_vec()).await;
+        write_segment(&mut tx, 0, b"hel".to_vec()).await;
+        Message::Fin(Sequence::new(11))
+            .encode(&mut tx)
+            .await
+            .unwrap();
+
+        let mut buf = vec![];
+        let mut piece = [0; 64];
+        loop {
+            let n = receiver.recv(&mut piece).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&piece[..n]);
+        }
+        assert_eq!(buf, b"hello world");
+        let stats = receiver.stats();
+        assert_eq!(stats.duplicate_segments(), 2);
+        assert_eq!(stats.duplicate_bytes(), 6);
+        assert_eq!(stats.buffered_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn recv_counters() {
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let (mut corrupt_tx, corrupt_rx) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![rx, corrupt_rx]);
+        write_hello(&mut tx).await;
+        write_hello(&mut corrupt_tx).await;
+
+        write_segment(&mut tx, 3, b"lo".to_vec()).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!receiver.stats().head_of_line_wait().is_zero());
+        write_segment(&mut tx, 0, b"hel".to_vec()).await;
+        Message::Ping.encode(&mut tx).await.unwrap();
+        let mut buf = [0; 5];
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+        }
+        assert_eq!(&buf, b"hello");
+
+        // Flip a bit of the payload
+        let options = crate::message::EncodeOptions {
+            checksum: true,
+            ..Default::default()
+        };
+        let data_segment = DataSegment::new(Sequence::new(5), Bytes::from_static(b"!")).unwrap();
+        let mut frame = vec![];
+        Message::DataSegment(data_segment)
+            .encode_with(&mut frame, options)
+            .await
+            .unwrap();
+        frame[1 + 8 + 4] ^= 0x10;
+        corrupt_tx.write_all(&frame).await.unwrap();
+        while receiver.live_streams() == 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let stats = receiver.stats();
+        let waited = stats.head_of_line_wait();
+        assert!(waited >= Duration::from_millis(20), "{waited:?}");
+        assert_eq!(stats.max_gap(), 3);
+        assert_eq!(stats.checksum_failures(), 1);
+        let subflows = stats.subflows();
+        assert_eq!(subflows[0].bytes_received(), 5);
+        assert_eq!(subflows[0].frames_received(), 3);
+        assert_eq!(subflows[0].checksum_failures(), 0);
+        assert_eq!(subflows[1].frames_received(), 0);
+        assert_eq!(subflows[1].checksum_failures(), 1);
+
+        // The wait ended, so a reset leaves nothing counting
+        receiver.reset_stats();
+        write_segment(&mut tx, 5, b"!".to_vec()).await;
+        receiver.recv(&mut buf).await.unwrap();
+        let stats = receiver.stats();
+        assert!(stats.head_of_line_wait().is_zero());
+        assert_eq!(stats.max_gap(), 0);
+        assert_eq!(stats.checksum_failures(), 0);
+        assert_eq!(stats.subflows()[0].bytes_received(), 1);
+        assert_eq!(stats.subflows()[0].frames_received(), 1);
+    }
+
+    #[tokio::test]
+    async fn oob_messages_bypass_head_of_line() {
+        async fn write_oob<W>(stream: &mut W, sequence: u32, payload: &'static [u8])
+        where
+            W: tokio::io::AsyncWrite + Unpin,
+        {
+            let payload = Bytes::from_static(payload);
+            Message::Oob { sequence, payload }
+                .encode(stream)
+                .await
+                .unwrap();
+        }
+
+        let (mut tx0, rx0) = tokio::io::duplex(64);
+        let (mut tx1, rx1) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![rx0, rx1]);
+        let mut oob_messages = receiver.oob_messages();
+        write_hello(&mut tx0).await;
+        write_hello(&mut tx1).await;
+
+        // The byte stream waits for the segment at 0
+        write_segment(&mut tx1, 3, b"lo".to_vec()).await;
+        write_oob(&mut tx1, 0, b"now").await;
+        let message = tokio::time::timeout(Duration::from_secs(1), oob_messages.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((message.index(), message.sequence()), (1, 0));
+        assert_eq!(message.payload(), "now");
+        let mut buf = [0; 5];
+        let recv = tokio::time::timeout(Duration::from_millis(50), receiver.recv(&mut buf));
+        assert!(recv.await.is_err());
+
+        // Copies are dropped, in any order and across the wrap-around
+        write_oob(&mut tx0, 0, b"now").await;
+        write_oob(&mut tx0, 2, b"two").await;
+        assert_eq!(oob_messages.recv().await.unwrap().sequence(), 2);
+        write_oob(&mut tx1, 1, b"one").await;
+        write_oob(&mut tx1, 2, b"two").await;
+        write_oob(&mut tx1, 0, b"now").await;
+        assert_eq!(oob_messages.recv().await.unwrap().sequence(), 1);
+        let mut window = OobWindow {
+            next: u64::from(u32::MAX - 1),
+            seen: BTreeSet::new(),
+        };
+        assert!(window.insert(u32::MAX));
+        assert!(window.insert(u32::MAX - 1));
+        assert!(window.insert(0));
+        assert!(!window.insert(u32::MAX));
+        assert!(window.insert(2));
+        assert!(!window.insert(0));
+        assert_eq!(window.seen.len(), 1);
+
+        write_segment(&mut tx0, 0, b"hel".to_vec()).await;
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+        }
+        assert_eq!(&buf, b"hello");
+        assert!(oob_messages.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn skip_unknown_frames() {
+        async fn write_unknown<W>(stream: &mut W, payload: &'static [u8])
+        where
+            W: tokio::io::AsyncWrite + Unpin,
+        {
+            let payload = Bytes::from_static(payload);
+            Message::Unknown {
+                type_code: 200,
+                payload,
+            }
+            .encode(stream)
+            .await
+            .unwrap();
+        }
+
+        // A frame of a newer peer in the middle of the byte stream
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut receiver = Receiver::new(vec![rx]);
+        let mut unknown_frames = receiver.unknown_frames();
+        write_hello(&mut tx).await;
+        write_segment(&mut tx, 0, b"hello".to_vec()).await;
+        write_unknown(&mut tx, b"abc").await;
+        write_segment(&mut tx, 5, b"world".to_vec()).await;
+        let mut buf = [0; 10];
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+        }
+        assert_eq!(&buf, b"helloworld");
+        let frame = unknown_frames.recv().await.unwrap();
+        assert_eq!((frame.index(), frame.type_code()), (0, 200));
+        assert_eq!(frame.payload(), "abc");
+        assert_eq!(receiver.stats().unknown_frames(), 1);
+        assert_eq!(receiver.stats().subflows()[0].unknown_frames(), 1);
+        assert!(receiver.take_subflow_errors().is_empty());
+
+        // Too large to be skipped
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut receiver = ReceiverBuilder::new()
+            .max_unknown_frame_size(2)
+            .build(vec![rx]);
+        write_hello(&mut tx).await;
+        write_unknown(&mut tx, b"abc").await;
+        let err = receiver.recv(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = err.get_ref().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<RecvError>(),
+            Some(RecvError::ProtocolViolation {
+                stream_id: 0,
+                detail: DecodeError::FrameTooLarge {
+                    length: 3,
+                    limit: 2
+                },
+            })
+        ));
+        assert_eq!(receiver.stats().unknown_frames(), 0);
+    }
+
+    #[tokio::test]
+    async fn max_frame_size() {
+        let (mut tx0, rx0) = tokio::io::duplex(1 << 14);
+        let (mut tx1, rx1) = tokio::io::duplex(1 << 14);
+        let mut receiver = ReceiverBuilder::new()
+            .max_frame_size(1, NonZeroUsize::new(1 << 12).unwrap())
+            .build(vec![rx0, rx1]);
+        write_hello(&mut tx0).await;
+        Hello::new(0)
+            .with_max_frame_size(1 << 10)
+            .encode(&mut tx1)
+            .await
+            .unwrap();
+
+        // The limit is of the stream at 1 only
+        let payload = 1 << 12;
+        write_segment(&mut tx0, 0, vec![1; payload]).await;
+        let mut buf = vec![0; payload];
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+        }
+        assert_eq!(receiver.peer_max_frame_size(0), None);
+        assert_eq!(receiver.peer_max_frame_size(1), Some(1 << 10));
+
+        write_segment(&mut tx1, payload as u64, vec![1; payload]).await;
+        // The stream at 0 carries on
+        let recv = tokio::time::timeout(Duration::from_millis(50), receiver.recv(&mut buf));
+        assert!(recv.await.is_err());
+        assert_eq!(receiver.live_streams(), 1);
+        let errors = receiver.take_subflow_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].index(), 1);
+        let err = errors[0].error().get_ref().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<RecvError>(),
+            Some(&RecvError::ProtocolViolation {
+                stream_id: 1,
+                detail: DecodeError::FrameTooLarge { length, limit: 4096 },
+            }) if length == DATA_SEGMENT_OVERHEAD + payload
+        ));
+    }
+
+    #[tokio::test]
+    async fn corrupt_subflow() {
+        let (mut tx0, rx0) = tokio::io::duplex(1 << 10);
+        let (mut tx1, rx1) = tokio::io::duplex(1 << 10);
+        let mut receiver = ReceiverBuilder::new()
+            .max_payload_size(NonZeroUsize::new(1 << 10).unwrap())
+            .gap_timeout(Duration::from_millis(100))
+            .build(vec![rx0, rx1]);
+        write_hello(&mut tx0).await;
+        write_hello(&mut tx1).await;
+        write_segment(&mut tx0, 0, b"hello".to_vec()).await;
+        // A flipped bit in the length of the segment the stream at 1 owes
+        let mut frame = vec![];
+        let data_segment = DataSegment::new(Sequence::new(5), Bytes::from_static(b" world"));
+        Message::DataSegment(data_segment.unwrap())
+            .encode(&mut frame)
+            .await
+            .unwrap();
+        frame[1 + 8] ^= 0x80;
+        tx1.write_all(&frame).await.unwrap();
+
+        let mut buf = [0; 11];
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        while receiver.live_streams() > 1 {
+            tokio::task::yield_now().await;
+        }
+        let errors = receiver.take_subflow_errors();
+        assert_eq!(errors.len(), 1);
+        let err = errors[0].error().get_ref().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<RecvError>(),
+            Some(RecvError::ProtocolViolation {
+                stream_id: 1,
+                detail: DecodeError::FrameTooLarge { limit: 1024, .. },
+            })
+        ));
+
+        // The clean stream carries on with what the corrupt one owed
+        write_segment(&mut tx0, 5, b" world".to_vec()).await;
+        let mut filled = n;
+        while filled < buf.len() {
+            filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+        }
+        assert_eq!(&buf, b"hello world");
+
+        // Or leaves a gap that times out
+        write_segment(&mut tx0, 12, b"!".to_vec()).await;
+        let err = receiver.recv(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // A type code of no frame
+        let (mut tx, rx) = tokio::io::duplex(1 << 10);
+        let mut receiver = Receiver::new(vec![rx]);
+        write_hello(&mut tx).await;
+        tx.write_all(&[127, 0, 0]).await.unwrap();
+        let err = receiver.recv(&mut buf).await.unwrap_err();
+        assert!(matches!(
+            err.get_ref().unwrap().downcast_ref::<RecvError>(),
+            Some(RecvError::ProtocolViolation {
+                stream_id: 0,
+                detail: DecodeError::UnknownType(127),
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn delayed_acks() {
+        const FRAMES: u64 = 10_000;
+        let (mut tx, rx) = tokio::io::duplex(1 << 16);
+        let mut receiver = Receiver::new(vec![rx]);
+        let mut ack_frames = receiver.ack_frames();
+        let writer = tokio::spawn(async move {
+            write_hello(&mut tx).await;
+            for i in 0..FRAMES {
+                write_segment(&mut tx, i * 4, vec![1; 4]).await;
+            }
+            tx
+        });
+        let mut buf = vec![0; FRAMES as usize * 4];
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+        }
+        let _tx = writer.await.unwrap();
+        // The last ones are acknowledged after the delay
+        tokio::time::sleep(DEFAULT_ACK_DELAY * 2).await;
+        let mut acks = 0;
+        let mut last = None;
+        while let Ok(ack_frame) = ack_frames.try_recv() {
+            assert!(ack_frame.sacks().is_empty());
+            acks += 1;
+            last = Some(ack_frame.cumulative());
+        }
+        assert!(acks <= FRAMES / 10, "{acks} acknowledgements");
+        assert_eq!(last, Some(Sequence::new(FRAMES * 4)));
+
+        // Filling a gap is acknowledged right away, with the data past the gaps left
+        let (mut tx, rx) = tokio::io::duplex(1 << 10);
+        let mut receiver = ReceiverBuilder::new()
+            .ack_every(NonZeroUsize::new(1000).unwrap())
+            .ack_delay(Duration::from_secs(3600))
+            .build(vec![rx]);
+        let mut ack_frames = receiver.ack_frames();
+        write_hello(&mut tx).await;
+        write_segment(&mut tx, 3, b"lo".to_vec()).await;
+        write_segment(&mut tx, 8, b"rld".to_vec()).await;
+        let mut buf = [0; 11];
+        let recv = tokio::time::timeout(Duration::from_millis(50), receiver.recv(&mut buf));
+        assert!(recv.await.is_err());
+        assert!(ack_frames.try_recv().is_err());
+        write_segment(&mut tx, 0, b"hel".to_vec()).await;
+        let ack_frame = ack_frames.recv().await.unwrap();
+        assert_eq!(ack_frame.cumulative(), Sequence::new(5));
+        assert_eq!(ack_frame.sacks(), [Sequence::new(8)..Sequence::new(11)]);
+        write_segment(&mut tx, 5, b" wo".to_vec()).await;
+        let ack_frame = ack_frames.recv().await.unwrap();
+        assert_eq!(ack_frame.cumulative(), Sequence::new(11));
+        assert!(ack_frame.sacks().is_empty());
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+        }
+        assert_eq!(&buf, b"hello world");
+
+        // The delay bounds the wait of a lone segment
+        let (mut tx, rx) = tokio::io::duplex(1 << 10);
+        let mut receiver = ReceiverBuilder::new()
+            .ack_every(NonZeroUsize::new(1000).unwrap())
+            .ack_delay(Duration::from_millis(20))
+            .ack_on_gap_fill(false)
+            .build(vec![rx]);
+        let mut ack_frames = receiver.ack_frames();
+        write_hello(&mut tx).await;
+        let start = Instant::now();
+        write_segment(&mut tx, 2, b"y".to_vec()).await;
+        write_segment(&mut tx, 0, b"xx".to_vec()).await;
+        let ack_frame = ack_frames.recv().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(ack_frame.cumulative(), Sequence::new(3));
+    }
+
+    #[tokio::test]
+    async fn bytes_stream_matches_async_read() {
+        // Record one session on the wire
+        let streams = 3;
+        let (send_streams, wire): (Vec<_>, Vec<_>) =
+            (0..streams).map(|_| tokio::io::duplex(1 << 16)).unzip();
+        let mut sender = crate::sender::Sender::new(send_streams);
+        sender.set_max_segment_size(NonZeroUsize::new(1000));
+        let msg: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        let recordings =
+            futures_util::future::join_all(wire.into_iter().map(|mut rx| async move {
+                let mut recording = vec![];
+                rx.read_to_end(&mut recording).await.unwrap();
+                recording
+            }));
+        let send = async {
+            for chunk in msg.chunks(1 << 12) {
+                sender
+                    .batch_send_all(Bytes::copy_from_slice(chunk))
+                    .await
+                    .unwrap();
+            }
+            sender.shutdown().await.unwrap();
+            drop(sender);
+        };
+        let (recordings, ()) = tokio::join!(recordings, send);
+
+        let replay = || {
+            let recv_streams = recordings.iter().map(|r| std::io::Cursor::new(r.clone()));
+            Receiver::new(recv_streams.collect())
+        };
+
+        let mut via_read = vec![];
+        replay()
+            .into_async_read()
+            .read_to_end(&mut via_read)
+            .await
+            .unwrap();
+
+        let mut pieces = replay().into_bytes_stream();
+        let mut via_stream = vec![];
+        while let Some(piece) = futures_util::StreamExt::next(&mut pieces).await {
+            let piece = piece.unwrap();
+            assert!(!piece.is_empty());
+            via_stream.extend_from_slice(&piece);
+        }
+
+        assert_eq!(via_read, msg);
+        assert_eq!(via_stream, msg);
+    }
+
+    #[tokio::test]
+    async fn reject_mismatched_subflows() {
+        let session = Session::new(7);
+        for (second, expected) in [
+            (
+                Hello::new(0).with_subflow(session, 0),
+                SessionSetupError::DuplicateSubflow {
+                    index: 1,
+                    first: 0,
+                    id: 0,
+                },
+            ),
+            (
+                Hello::new(0).with_subflow(Session::new(8), 1),
+                SessionSetupError::SessionMismatch {
+                    index: 1,
+                    expected: session,
+                    peer: Session::new(8),
+                },
+            ),
+            (
+                Hello::new(0),
+                SessionSetupError::IdentityMismatch { index: 1, first: 0 },
+            ),
+        ] {
+            let (mut tx0, rx0) = tokio::io::duplex(1 << 10);
+            let (mut tx1, rx1) = tokio::io::duplex(1 << 10);
+            let mut receiver = ReceiverBuilder::new().build(vec![rx0, rx1]);
+            Hello::new(0)
+                .with_subflow(session, 0)
+                .encode(&mut tx0)
+                .await
+                .unwrap();
+            write_segment(&mut tx0, 0, b"hello".to_vec()).await;
+            let mut buf = [0; 10];
+            assert_eq!(receiver.recv(&mut buf).await.unwrap(), 5);
+
+            second.encode(&mut tx1).await.unwrap();
+            write_segment(&mut tx1, 5, b"world".to_vec()).await;
+            // Nothing of the rejected stream gets in
+            let recv = tokio::time::timeout(Duration::from_millis(50), receiver.recv(&mut buf));
+            assert!(recv.await.is_err());
+            assert_eq!(receiver.buffered_bytes(), 0);
+            assert_eq!(receiver.live_streams(), 1);
+            let errors = receiver.take_subflow_errors();
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].index(), 1);
+            let err = errors[0].error().get_ref().unwrap();
+            assert_eq!(err.downcast_ref::<SessionSetupError>(), Some(&expected));
+            assert_eq!(expected.index(), 1);
+        }
+
+        // A session known in advance holds for the first stream as well
+        let (mut tx, rx) = tokio::io::duplex(1 << 10);
+        let mut receiver = ReceiverBuilder::new().session(session).build(vec![rx]);
+        Hello::new(0)
+            .with_subflow(Session::new(8), 0)
+            .encode(&mut tx)
+            .await
+            .unwrap();
+        write_segment(&mut tx, 0, b"hello".to_vec()).await;
+        let err = receiver.recv(&mut [0; 5]).await.unwrap_err();
+        assert!(matches!(
+            err.get_ref().unwrap().downcast_ref::<SessionSetupError>(),
+            Some(SessionSetupError::SessionMismatch { index: 0, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn migrate_within_session() {
+        let session = Session::new(7);
+        let (mut old_tx, old_rx) = tokio::io::duplex(1 << 10);
+        let mut receiver = ReceiverBuilder::new().build(vec![old_rx]);
+        Hello::new(0)
+            .with_subflow(session, 0)
+            .encode(&mut old_tx)
+            .await
+            .unwrap();
+        write_segment(&mut old_tx, 0, b"hello".to_vec()).await;
+        let mut buf = [0; 10];
+        assert_eq!(receiver.recv(&mut buf).await.unwrap(), 5);
+
+        let (mut tx1, rx1) = tokio::io::duplex(1 << 10);
+        let (mut tx2, rx2) = tokio::io::duplex(1 << 10);
+        assert_eq!(receiver.migrate(vec![rx1, rx2]).await, 1..3);
+        // The old stream is gone without ending
+        assert!(old_tx.write_all(&[0; 1 << 11]).await.is_err());
+        Hello::new(0)
+            .with_subflow(Session::new(8), 1)
+            .encode(&mut tx1)
+            .await
+            .unwrap();
+        write_segment(&mut tx1, 5, b"stray".to_vec()).await;
+        Hello::new(0)
+            .with_subflow(session, 2)
+            .encode(&mut tx2)
+            .await
+            .unwrap();
+        write_segment(&mut tx2, 5, b"world".to_vec()).await;
+        assert_eq!(receiver.recv(&mut buf).await.unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+
+        assert_eq!(receiver.live_streams(), 1);
+        let errors = receiver.take_subflow_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].index(), 1);
+        let err = errors[0].error().get_ref().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<SessionSetupError>(),
+            Some(SessionSetupError::SessionMismatch { index: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn partial_receiver() {
+        let (tx0, rx0) = tokio::io::duplex(1 << 16);
+        let (tx1, rx1) = tokio::io::duplex(1 << 16);
+        let mut partial = ReceiverBuilder::new().build_partial(NonZeroUsize::new(2).unwrap());
+        assert_eq!(partial.add_stream(rx0), 0);
+        assert!(!partial.is_complete());
+
+        let msg: Vec<u8> = (0..1 << 18).map(|_| rand::random()).collect();
+        let msg = Bytes::from(msg);
+        let (joined_tx, mut joined) = mpsc::unbounded_channel();
+        let send_task = tokio::spawn({
+            let msg = msg.clone();
+            async move {
+                let mut sender = SenderBuilder::new()
+                    .max_segment_size(NonZeroUsize::new(1 << 12).unwrap())
+                    .build(vec![tx0]);
+                let half = msg.len() / 2;
+                sender.batch_send_all(msg.slice(..half)).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                sender.add_stream(tx1);
+                joined_tx.send(rx1).unwrap();
+                sender.batch_send_all(msg.slice(half..)).await.unwrap();
+                sender.shutdown().await.unwrap();
+                sender.stats()
+            }
+        });
+
+        // The first stream is read while the second has yet to join
+        let mut acks = partial.acks();
+        acks.wait_for(|ack| ack.inner() > 0).await.unwrap();
+        assert!(partial.buffered_bytes() > 0);
+        assert_eq!(partial.streams(), 1);
+        let joined = futures_util::stream::poll_fn(move |cx| joined.poll_recv(cx));
+        let receiver = partial.complete(joined, Duration::from_secs(10)).await;
+        assert_eq!(receiver.live_streams(), 2);
+
+        let mut buf = vec![];
+        receiver
+            .into_async_read()
+            .read_to_end(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, msg);
+        let stats = send_task.await.unwrap();
+        assert!(stats[1].bytes_written() > 0);
+    }
+
+    #[tokio::test]
+    async fn read_into_uninitialized_memory() {
+        let (mut tx, rx) = tokio::io::duplex(1 << 10);
+        let mut reader = Receiver::new(vec![rx]).into_async_read();
+        write_hello(&mut tx).await;
+        write_segment(&mut tx, 0, b"hello".to_vec()).await;
+        write_segment(&mut tx, 5, b" world".to_vec()).await;
+        Message::Fin(Sequence::new(11))
+            .encode(&mut tx)
+            .await
+            .unwrap();
+
+        // Small enough for Miri
+        let mut storage = [std::mem::MaybeUninit::<u8>::uninit(); 8];
+        let mut buf = ReadBuf::uninit(&mut storage);
+        while buf.remaining() > 0 {
+            std::future::poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut buf))
+                .await
+                .unwrap();
+            // Nothing is initialized but what is filled
+            assert_eq!(buf.initialized().len(), buf.filled().len());
+        }
+        assert_eq!(buf.filled(), b"hello wo");
+
+        // The rest of the piece is appended whole
+        let mut bytes = BytesMut::with_capacity(2);
+        let read = std::future::poll_fn(|cx| reader.poll_read_buf(cx, &mut bytes));
+        assert_eq!(read.await.unwrap(), 3);
+        assert_eq!(&bytes[..], b"rld");
+        let read = std::future::poll_fn(|cx| reader.poll_read_buf(cx, &mut bytes));
+        assert_eq!(read.await.unwrap(), 0);
+        assert_eq!(reader.into_inner().buffered_bytes(), 0);
+    }
+}
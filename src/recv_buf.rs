@@ -1,67 +1,254 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, ops::Range};
+
+use bytes::Bytes;
 
 use crate::message::{DataSegment, Sequence};
 
+/// Reassembles the byte stream out of segments that might overlap
+///
+/// Buffered segments never overlap: each one inserted is trimmed down to the bytes neither popped nor buffered yet.
 #[derive(Debug)]
 pub struct RecvStreamBuf {
     next: Sequence,
     data_segments: BTreeMap<Sequence, DataSegment>,
+    /// The subflow each buffered segment inserted by `Self::insert_from` came from
+    sources: BTreeMap<Sequence, usize>,
+    buffered_bytes: usize,
+    /// Buffered bytes by the subflow they came from
+    subflow_bytes: Vec<usize>,
+    fin: Option<Sequence>,
+    /// Where the sender cut the byte stream short, and why
+    abort: Option<(Sequence, Bytes)>,
+    duplicate_segments: u64,
+    duplicate_bytes: u64,
 }
 
 impl RecvStreamBuf {
     pub fn new() -> Self {
+        Self::with_next(Sequence::new(0))
+    }
+
+    /// Expect the byte stream to pick up at `next`
+    pub fn with_next(next: Sequence) -> Self {
         Self {
-            next: Sequence::new(0),
+            next,
             data_segments: BTreeMap::new(),
+            sources: BTreeMap::new(),
+            buffered_bytes: 0,
+            subflow_bytes: Vec::new(),
+            fin: None,
+            abort: None,
+            duplicate_segments: 0,
+            duplicate_bytes: 0,
         }
     }
 
-    pub fn insert(&mut self, data_segment: DataSegment) {
-        // Remove stale data
-        let Some(data_segment) = data_segment.advance_to(self.next) else {
-            return;
-        };
+    /// The sequence of the next byte to pop
+    pub fn next(&self) -> Sequence {
+        self.next
+    }
 
-        // Resolve key conflict
-        if let Some(old_data_segment) = self.data_segments.get(&data_segment.start_sequence()) {
-            if data_segment.size() <= old_data_segment.size() {
-                return;
+    /// The end of the contiguous data received so far, including buffered data
+    pub fn received(&self) -> Sequence {
+        let mut received = self.next;
+        for (start_sequence, data_segment) in &self.data_segments {
+            if received < *start_sequence {
+                break;
             }
+            received = received.max(data_segment.end_sequence());
         }
+        received
+    }
 
-        self.data_segments
-            .insert(data_segment.start_sequence(), data_segment);
+    /// Record where the byte stream ends
+    ///
+    /// Only the first FIN counts.
+    pub fn set_fin(&mut self, fin: Sequence) {
+        self.fin.get_or_insert(fin);
     }
 
-    pub fn pop_first(&mut self) -> Option<DataSegment> {
-        let (first_key, _) = self.data_segments.first_key_value()?;
-        if *first_key != self.next {
-            return None;
+    /// Whether every byte up to the FIN has been popped
+    pub fn finished(&self) -> bool {
+        self.fin.is_some_and(|fin| fin <= self.next)
+    }
+
+    /// Record where the sender cut the byte stream short and why
+    ///
+    /// Only the first abort counts.
+    pub fn set_abort(&mut self, sequence: Sequence, reason: Bytes) {
+        self.abort.get_or_insert((sequence, reason));
+    }
+
+    /// The sequence and the reason of the abort once every byte up to it has been popped
+    pub fn aborted(&self) -> Option<(Sequence, &Bytes)> {
+        let (sequence, reason) = self.abort.as_ref()?;
+        (*sequence <= self.next).then_some((*sequence, reason))
+    }
+
+    /// Payload bytes held in the buffer
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Bytes held in the buffer that came from `subflow` through `Self::insert_from`
+    pub fn subflow_buffered_bytes(&self, subflow: usize) -> usize {
+        self.subflow_bytes.get(subflow).copied().unwrap_or(0)
+    }
+
+    /// `Self::subflow_buffered_bytes` of every subflow that has inserted data so far
+    pub fn all_subflow_buffered_bytes(&self) -> &[usize] {
+        &self.subflow_bytes
+    }
+
+    /// Inserted segments that carried bytes already popped or buffered
+    pub fn duplicate_segments(&self) -> u64 {
+        self.duplicate_segments
+    }
+
+    /// Bytes discarded for having been popped or buffered already
+    pub fn duplicate_bytes(&self) -> u64 {
+        self.duplicate_bytes
+    }
+
+    /// Forget the duplicates counted so far
+    pub fn reset_duplicates(&mut self) {
+        self.duplicate_segments = 0;
+        self.duplicate_bytes = 0;
+    }
+
+    /// Bytes missing before the first buffered segment, if the buffer holds data but not the next byte
+    pub fn head_gap(&self) -> Option<u64> {
+        let (start_sequence, _) = self.data_segments.first_key_value()?;
+        (self.next < *start_sequence).then(|| start_sequence.inner() - self.next.inner())
+    }
+
+    /// The ranges of the data buffered past the end of the contiguous data, coalesced and in order
+    ///
+    /// They are the selective acknowledgements to go with the cumulative one of `Self::received`.
+    pub fn sack_ranges(&self) -> Vec<Range<Sequence>> {
+        let mut ranges: Vec<Range<Sequence>> = vec![];
+        for data_segment in self.data_segments.range(self.received()..).map(|(_, s)| s) {
+            match ranges.last_mut() {
+                Some(range) if range.end == data_segment.start_sequence() => {
+                    range.end = data_segment.end_sequence();
+                }
+                _ => ranges.push(data_segment.start_sequence()..data_segment.end_sequence()),
+            }
         }
+        ranges
+    }
+
+    /// Whether inserting `data_segment` keeps the buffer within `limit` bytes
+    ///
+    /// Data at or before the next expected sequence is always admitted so that the buffer can drain.
+    /// So is a copy of a buffered segment since inserting it is a no-op.
+    pub fn admits(&self, data_segment: &DataSegment, limit: usize) -> bool {
+        data_segment.start_sequence() <= self.next
+            || self.buffered_bytes + data_segment.size() <= limit
+            || self
+                .data_segments
+                .get(&data_segment.start_sequence())
+                .is_some_and(|old| data_segment.size() <= old.size())
+    }
+
+    /// `Self::admits` that also keeps what `subflow` holds in the buffer within `subflow_limit` bytes
+    ///
+    /// Data at or before the next expected sequence is still always admitted, so a subflow filling the head-of-line gap is never held back.
+    pub fn admits_from(
+        &self,
+        subflow: usize,
+        data_segment: &DataSegment,
+        limit: usize,
+        subflow_limit: usize,
+    ) -> bool {
+        data_segment.start_sequence() <= self.next
+            || (self.admits(data_segment, limit)
+                && self.subflow_buffered_bytes(subflow) + data_segment.size() <= subflow_limit)
+            || self
+                .data_segments
+                .get(&data_segment.start_sequence())
+                .is_some_and(|old| data_segment.size() <= old.size())
+    }
+
+    /// Buffer the bytes of `data_segment` that are neither popped nor buffered yet
+    pub fn insert(&mut self, data_segment: DataSegment) {
+        self.insert_in(None, data_segment);
+    }
 
-        let (_, first_segment) = self.data_segments.pop_first().unwrap();
+    /// `Self::insert` counting the bytes kept against `subflow`
+    pub fn insert_from(&mut self, subflow: usize, data_segment: DataSegment) {
+        self.insert_in(Some(subflow), data_segment);
+    }
 
-        self.next = first_segment.end_sequence();
+    fn insert_in(&mut self, subflow: Option<usize>, data_segment: DataSegment) {
+        let size = data_segment.size();
+        let mut kept = 0;
 
-        // Deduplicate data
-        loop {
-            if let Some((start_sequence, _)) = self.data_segments.first_key_value() {
-                if self.next <= *start_sequence {
-                    break;
+        // Remove stale data
+        let mut rest = data_segment.advance_to(self.next);
+        while let Some(mut data_segment) = rest.take() {
+            // Skip what the buffered segment at or before the start covers
+            if let Some((_, prev)) = self
+                .data_segments
+                .range(..=data_segment.start_sequence())
+                .next_back()
+            {
+                if data_segment.start_sequence() < prev.end_sequence() {
+                    rest = data_segment.advance_to(prev.end_sequence());
+                    continue;
                 }
             }
-            let Some((_, data_segment)) = self.data_segments.pop_first() else {
-                break;
-            };
 
-            let data_segment = data_segment.advance_to(self.next);
-            if let Some(data_segment) = data_segment {
-                self.data_segments
-                    .insert(data_segment.start_sequence(), data_segment);
+            // Keep what comes before the next buffered segment
+            let next_start = self
+                .data_segments
+                .range(data_segment.start_sequence()..)
+                .next()
+                .map(|(start_sequence, _)| *start_sequence);
+            if let Some(next_start) = next_start {
+                if next_start < data_segment.end_sequence() {
+                    let start_sequence = data_segment.start_sequence();
+                    let len = (next_start.inner() - start_sequence.inner()) as usize;
+                    let head = data_segment.payload().slice(..len);
+                    rest = data_segment.advance(len);
+                    data_segment = DataSegment::new(start_sequence, head).unwrap();
+                }
             }
+            kept += data_segment.size();
+            self.put(subflow, data_segment);
+        }
+
+        if kept < size {
+            self.duplicate_segments += 1;
+            self.duplicate_bytes += (size - kept) as u64;
         }
+    }
 
-        Some(first_segment)
+    fn put(&mut self, subflow: Option<usize>, data_segment: DataSegment) {
+        self.buffered_bytes += data_segment.size();
+        if let Some(subflow) = subflow {
+            if self.subflow_bytes.len() <= subflow {
+                self.subflow_bytes.resize(subflow + 1, 0);
+            }
+            self.subflow_bytes[subflow] += data_segment.size();
+            self.sources.insert(data_segment.start_sequence(), subflow);
+        }
+        self.data_segments
+            .insert(data_segment.start_sequence(), data_segment);
+    }
+
+    pub fn pop_first(&mut self) -> Option<DataSegment> {
+        let entry = self.data_segments.first_entry()?;
+        if *entry.key() != self.next {
+            return None;
+        }
+        let data_segment = entry.remove();
+        self.buffered_bytes -= data_segment.size();
+        if let Some(subflow) = self.sources.remove(&data_segment.start_sequence()) {
+            self.subflow_bytes[subflow] -= data_segment.size();
+        }
+        self.next = data_segment.end_sequence();
+        Some(data_segment)
     }
 }
 
@@ -86,6 +273,38 @@ mod tests {
         assert!(buf.pop_first().is_none());
     }
 
+    #[test]
+    fn head_gap() {
+        let mut buf = RecvStreamBuf::new();
+        assert_eq!(buf.head_gap(), None);
+        buf.insert(DataSegment::new(Sequence::new(3), Bytes::from_iter(vec![3])).unwrap());
+        assert_eq!(buf.head_gap(), Some(3));
+        buf.insert(DataSegment::new(Sequence::new(1), Bytes::from_iter(vec![1])).unwrap());
+        assert_eq!(buf.head_gap(), Some(1));
+        buf.insert(DataSegment::new(Sequence::new(0), Bytes::from_iter(vec![0])).unwrap());
+        assert_eq!(buf.head_gap(), None);
+        let _ = buf.pop_first().unwrap();
+        let _ = buf.pop_first().unwrap();
+        assert_eq!(buf.head_gap(), Some(1));
+    }
+
+    #[test]
+    fn sack_ranges() {
+        let piece = |start: u64, len: usize| {
+            DataSegment::new(Sequence::new(start), Bytes::from(vec![0; len])).unwrap()
+        };
+        let mut buf = RecvStreamBuf::new();
+        buf.insert(piece(0, 2));
+        assert!(buf.sack_ranges().is_empty());
+        buf.insert(piece(4, 2));
+        buf.insert(piece(6, 1));
+        buf.insert(piece(9, 1));
+        let range = |start, end| Sequence::new(start)..Sequence::new(end);
+        assert_eq!(buf.sack_ranges(), [range(4, 7), range(9, 10)]);
+        buf.insert(piece(2, 2));
+        assert_eq!(buf.sack_ranges(), [range(9, 10)]);
+    }
+
     #[test]
     fn unordered() {
         let mut buf = RecvStreamBuf::new();
@@ -121,6 +340,95 @@ mod tests {
         assert!(buf.pop_first().is_none());
     }
 
+    #[test]
+    fn buffered_bytes() {
+        let mut buf = RecvStreamBuf::new();
+        let segment =
+            |start, len| DataSegment::new(Sequence::new(start), vec![0; len].into()).unwrap();
+        buf.insert(segment(3, 3));
+        buf.insert(segment(6, 2));
+        assert_eq!(buf.buffered_bytes(), 5);
+        assert!(!buf.admits(&segment(8, 2), 6));
+        assert!(buf.admits(&segment(0, 2), 6));
+        // The overlapping byte is counted once
+        buf.insert(segment(0, 4));
+        assert_eq!(buf.buffered_bytes(), 8);
+        let _ = buf.pop_first().unwrap();
+        assert_eq!(buf.buffered_bytes(), 5);
+        assert!(buf.admits(&segment(8, 2), 7));
+        assert!(!buf.admits(&segment(10, 3), 7));
+        buf.insert(segment(10, 3));
+        assert!(buf.admits(&segment(10, 3), 7));
+        buf.insert(segment(10, 3));
+        assert_eq!(buf.buffered_bytes(), 8);
+    }
+
+    #[test]
+    fn subflow_buffered_bytes() {
+        let mut buf = RecvStreamBuf::new();
+        let segment =
+            |start, len| DataSegment::new(Sequence::new(start), vec![0; len].into()).unwrap();
+        buf.insert_from(1, segment(4, 4));
+        assert_eq!(buf.subflow_buffered_bytes(1), 4);
+        assert!(!buf.admits_from(1, &segment(8, 2), 100, 5));
+        assert!(buf.admits_from(0, &segment(8, 2), 100, 5));
+        // The head of line is admitted past the limit of its subflow
+        assert!(buf.admits_from(1, &segment(0, 6), 100, 5));
+        // Overlapping bytes count against the subflow that buffered them first
+        buf.insert_from(0, segment(0, 6));
+        assert_eq!(buf.all_subflow_buffered_bytes(), [4, 4]);
+        buf.insert(segment(8, 1));
+        assert_eq!(buf.buffered_bytes(), 9);
+        assert_eq!(buf.all_subflow_buffered_bytes(), [4, 4]);
+
+        while buf.pop_first().is_some() {}
+        assert_eq!(buf.all_subflow_buffered_bytes(), [0, 0]);
+    }
+
+    #[test]
+    fn received() {
+        let mut buf = RecvStreamBuf::new();
+        let segment =
+            |start, len| DataSegment::new(Sequence::new(start), vec![0; len].into()).unwrap();
+        buf.insert(segment(3, 3));
+        assert_eq!(buf.received(), Sequence::new(0));
+        buf.insert(segment(0, 4));
+        buf.insert(segment(8, 2));
+        assert_eq!(buf.received(), Sequence::new(6));
+        let _ = buf.pop_first().unwrap();
+        assert_eq!(buf.received(), Sequence::new(6));
+    }
+
+    #[test]
+    fn fin() {
+        let mut buf = RecvStreamBuf::new();
+        buf.set_fin(Sequence::new(3));
+        buf.insert(DataSegment::new(Sequence::new(0), Bytes::from_iter(vec![0, 1])).unwrap());
+        let _ = buf.pop_first().unwrap();
+        assert!(!buf.finished());
+        buf.set_fin(Sequence::new(2));
+        buf.insert(DataSegment::new(Sequence::new(2), Bytes::from_iter(vec![2])).unwrap());
+        let _ = buf.pop_first().unwrap();
+        assert!(buf.finished());
+    }
+
+    #[test]
+    fn abort() {
+        let mut buf = RecvStreamBuf::new();
+        buf.set_abort(Sequence::new(2), Bytes::from_static(b"gone"));
+        buf.set_abort(Sequence::new(0), Bytes::new());
+        buf.insert(DataSegment::new(Sequence::new(0), Bytes::from_iter(vec![0])).unwrap());
+        let _ = buf.pop_first().unwrap();
+        assert!(buf.aborted().is_none());
+        buf.insert(DataSegment::new(Sequence::new(1), Bytes::from_iter(vec![1])).unwrap());
+        let _ = buf.pop_first().unwrap();
+        assert_eq!(
+            buf.aborted(),
+            Some((Sequence::new(2), &Bytes::from("gone")))
+        );
+        assert!(!buf.finished());
+    }
+
     #[test]
     fn deduplicate_1() {
         let mut buf = RecvStreamBuf::new();
@@ -144,4 +452,79 @@ mod tests {
         assert_eq!(buf.next, Sequence::new(4));
         assert!(buf.pop_first().is_none());
     }
+
+    #[test]
+    fn overlap_resolution() {
+        let mut buf = RecvStreamBuf::new();
+        let segment =
+            |start, len| DataSegment::new(Sequence::new(start), vec![0; len].into()).unwrap();
+        buf.insert(segment(2, 2));
+        buf.insert(segment(6, 2));
+        // Only the gaps of a segment spanning both are buffered
+        buf.insert(segment(0, 10));
+        assert_eq!(buf.buffered_bytes(), 10);
+        assert_eq!(buf.duplicate_segments(), 1);
+        assert_eq!(buf.duplicate_bytes(), 4);
+        let starts: Vec<u64> = buf.data_segments.keys().map(|s| s.inner()).collect();
+        assert_eq!(starts, [0, 2, 4, 6, 8]);
+
+        // Nothing new
+        buf.insert(segment(3, 4));
+        assert_eq!(buf.buffered_bytes(), 10);
+        assert_eq!(buf.duplicate_segments(), 2);
+        assert_eq!(buf.duplicate_bytes(), 8);
+
+        while buf.pop_first().is_some() {}
+        buf.insert(segment(8, 3));
+        assert_eq!(buf.duplicate_bytes(), 10);
+        assert_eq!(buf.next(), Sequence::new(10));
+        assert_eq!(buf.pop_first().unwrap().size(), 1);
+    }
+
+    #[test]
+    fn random_overlaps_reassemble() {
+        use rand::{seq::SliceRandom, Rng};
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..200 {
+            let len = rng.gen_range(2..512);
+            let payload: Bytes = (0..len).map(|_| rng.gen::<u8>()).collect();
+            let piece = |start: usize, end: usize| {
+                DataSegment::new(Sequence::new(start as u64), payload.slice(start..end)).unwrap()
+            };
+
+            // A partition of the payload plus arbitrary overlapping copies
+            let mut cuts: Vec<usize> = (0..rng.gen_range(0..8))
+                .map(|_| rng.gen_range(1..len))
+                .collect();
+            cuts.extend([0, len]);
+            cuts.sort_unstable();
+            cuts.dedup();
+            let mut segments: Vec<DataSegment> =
+                cuts.windows(2).map(|w| piece(w[0], w[1])).collect();
+            for _ in 0..rng.gen_range(0..16) {
+                let start = rng.gen_range(0..len);
+                segments.push(piece(start, rng.gen_range(start + 1..=len)));
+            }
+            segments.shuffle(&mut rng);
+            let inserted: usize = segments.iter().map(|s| s.size()).sum();
+
+            let mut buf = RecvStreamBuf::new();
+            let mut output = vec![];
+            for data_segment in segments {
+                buf.insert(data_segment);
+                if rng.gen_bool(0.5) {
+                    while let Some(data_segment) = buf.pop_first() {
+                        output.extend_from_slice(data_segment.payload());
+                    }
+                }
+            }
+            while let Some(data_segment) = buf.pop_first() {
+                output.extend_from_slice(data_segment.payload());
+            }
+            assert_eq!(output, payload);
+            assert_eq!(buf.buffered_bytes(), 0);
+            assert_eq!(buf.duplicate_bytes(), (inserted - len) as u64);
+        }
+    }
 }
@@ -0,0 +1,499 @@
+use std::{
+    collections::{vec_deque, VecDeque},
+    io,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    message::{Sequence, SubflowScore},
+    sender::{StreamId, StreamStats},
+};
+
+/// Decides which stream carries which segment in a round of `Sender::batch_send`
+pub trait Scheduler: std::fmt::Debug + Send {
+    /// Pair the unsent `segments` with the live `streams`
+    ///
+    /// Each stream carries at most one segment per round, so later assignments to the same stream are ignored.
+    /// A segment assigned to several streams is written on each of them.
+    /// Unassigned segments wait for the next round and unassigned streams are pinged.
+    fn assign(&mut self, segments: &[SegmentMeta], streams: &[StreamMeta]) -> Vec<Assignment>;
+}
+
+/// An unsent segment offered to a `Scheduler`
+#[derive(Debug, Clone)]
+pub struct SegmentMeta {
+    sequence: Range<Sequence>,
+}
+
+impl SegmentMeta {
+    pub(crate) fn new(sequence: Range<Sequence>) -> Self {
+        Self { sequence }
+    }
+
+    pub fn start_sequence(&self) -> Sequence {
+        self.sequence.start
+    }
+
+    pub fn end_sequence(&self) -> Sequence {
+        self.sequence.end
+    }
+
+    pub fn size(&self) -> usize {
+        (self.sequence.end.inner() - self.sequence.start.inner()) as usize
+    }
+}
+
+/// A live stream offered to a `Scheduler`
+#[derive(Debug, Clone)]
+pub struct StreamMeta {
+    stats: StreamStats,
+}
+
+impl StreamMeta {
+    pub(crate) fn new(stats: StreamStats) -> Self {
+        Self { stats }
+    }
+
+    pub fn id(&self) -> StreamId {
+        self.stats.id()
+    }
+
+    pub fn stats(&self) -> &StreamStats {
+        &self.stats
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.stats.bytes_written()
+    }
+
+    pub fn last_write_latency(&self) -> Option<Duration> {
+        self.stats.last_write_latency()
+    }
+
+    /// See `StreamStats::rtt`
+    pub fn rtt(&self) -> Option<Duration> {
+        self.stats.rtt()
+    }
+
+    /// See `StreamStats::budget`
+    pub fn budget(&self) -> Option<usize> {
+        self.stats.budget()
+    }
+
+    /// See `StreamStats::feedback`
+    pub fn feedback(&self) -> Option<SubflowScore> {
+        self.stats.feedback()
+    }
+
+    /// See `StreamStats::feedback_gaps`
+    pub fn feedback_gaps(&self) -> Option<f64> {
+        self.stats.feedback_gaps()
+    }
+}
+
+/// The segment at index `segment` goes to the stream at index `stream`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assignment {
+    pub segment: usize,
+    pub stream: usize,
+}
+
+/// Hand the segments to the streams in the order they are offered
+///
+/// The `Sender` offers the streams from the highest goodput.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoundRobin;
+
+impl Scheduler for RoundRobin {
+    fn assign(&mut self, segments: &[SegmentMeta], streams: &[StreamMeta]) -> Vec<Assignment> {
+        (0..segments.len().min(streams.len()))
+            .map(|i| Assignment {
+                segment: i,
+                stream: i,
+            })
+            .collect()
+    }
+}
+
+/// Hand the segments to the streams from the lowest last write latency
+///
+/// Streams without a measurement yet go first so that they get one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LowestLatency;
+
+impl Scheduler for LowestLatency {
+    fn assign(&mut self, segments: &[SegmentMeta], streams: &[StreamMeta]) -> Vec<Assignment> {
+        let mut order: Vec<usize> = (0..streams.len()).collect();
+        order.sort_by_key(|&i| streams[i].last_write_latency());
+        order
+            .into_iter()
+            .zip(0..segments.len())
+            .map(|(stream, segment)| Assignment { segment, stream })
+            .collect()
+    }
+}
+
+/// How many times the lowest round-trip time a stream may take and still be handed segments by `LowestRtt`
+const RTT_TOLERANCE: u32 = 2;
+
+/// Hand the segments to the streams from the lowest smoothed round-trip time, leaving out those over `RTT_TOLERANCE` times the lowest
+///
+/// Streams without an estimate yet go first, so that data flows before the first pong, see `Sender::enable_rtt_probes`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LowestRtt;
+
+impl Scheduler for LowestRtt {
+    fn assign(&mut self, segments: &[SegmentMeta], streams: &[StreamMeta]) -> Vec<Assignment> {
+        let lowest = streams.iter().filter_map(|stream| stream.rtt()).min();
+        let mut order: Vec<usize> = (0..streams.len())
+            .filter(|&i| match (streams[i].rtt(), lowest) {
+                (Some(rtt), Some(lowest)) => rtt <= lowest * RTT_TOLERANCE,
+                _ => true,
+            })
+            .collect();
+        order.sort_by_key(|&i| streams[i].rtt());
+        order
+            .into_iter()
+            .zip(0..segments.len())
+            .map(|(stream, segment)| Assignment { segment, stream })
+            .collect()
+    }
+}
+
+/// How many more smoothed gaps than the fewest a stream may cause and still be handed segments by `FewestGaps`
+const GAP_TOLERANCE: f64 = 1.0;
+
+/// Hand the segments to the streams from the fewest smoothed gaps in the feedback of the receiver, leaving out those over `GAP_TOLERANCE` more than the fewest
+///
+/// Ties go to the lowest jitter, and streams without feedback yet go first, see `Sender::enable_feedback`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FewestGaps;
+
+impl Scheduler for FewestGaps {
+    fn assign(&mut self, segments: &[SegmentMeta], streams: &[StreamMeta]) -> Vec<Assignment> {
+        let fewest = streams
+            .iter()
+            .filter_map(|stream| stream.feedback_gaps())
+            .min_by(f64::total_cmp);
+        let mut order: Vec<usize> = (0..streams.len())
+            .filter(|&i| match (streams[i].feedback_gaps(), fewest) {
+                (Some(gaps), Some(fewest)) => gaps <= fewest + GAP_TOLERANCE,
+                _ => true,
+            })
+            .collect();
+        let key = |i: usize| {
+            let gaps = streams[i].feedback_gaps().unwrap_or(f64::NEG_INFINITY);
+            let jitter = streams[i].feedback().map(|score| score.jitter());
+            (gaps, jitter)
+        };
+        order.sort_by(|&a, &b| {
+            let (a, b) = (key(a), key(b));
+            a.0.total_cmp(&b.0).then(a.1.cmp(&b.1))
+        });
+        order
+            .into_iter()
+            .zip(0..segments.len())
+            .map(|(stream, segment)| Assignment { segment, stream })
+            .collect()
+    }
+}
+
+/// Hand the first segment to every stream
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Duplicate;
+
+impl Scheduler for Duplicate {
+    fn assign(&mut self, segments: &[SegmentMeta], streams: &[StreamMeta]) -> Vec<Assignment> {
+        if segments.is_empty() {
+            return vec![];
+        }
+        (0..streams.len())
+            .map(|stream| Assignment { segment: 0, stream })
+            .collect()
+    }
+}
+
+/// A segment handed to a stream in a round of `Sender::batch_send`, as kept by a `DecisionLog`
+#[derive(Debug, Clone)]
+pub struct Decision {
+    at: Duration,
+    round: u64,
+    sequence: Range<Sequence>,
+    stream: StreamId,
+    streams: Arc<[StreamStats]>,
+}
+
+impl Decision {
+    /// When the segment was handed out, since the log was created
+    pub fn at(&self) -> Duration {
+        self.at
+    }
+
+    /// The round the segment was handed out in, counted from 0 by the log
+    ///
+    /// The decisions of a round share it and its `Self::streams`.
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    pub fn start_sequence(&self) -> Sequence {
+        self.sequence.start
+    }
+
+    pub fn end_sequence(&self) -> Sequence {
+        self.sequence.end
+    }
+
+    pub fn size(&self) -> usize {
+        (self.sequence.end.inner() - self.sequence.start.inner()) as usize
+    }
+
+    /// The stream chosen to carry the segment
+    pub fn stream(&self) -> StreamId {
+        self.stream
+    }
+
+    /// Statistics of the streams offered in the round, in the order they were offered
+    pub fn streams(&self) -> &[StreamStats] {
+        &self.streams
+    }
+}
+
+/// The latest decisions of the scheduler of a `Sender`, see `Sender::set_decision_log`
+///
+/// A bounded ring buffer: once full, each new decision drops the oldest.
+/// The segments stolen from a stream that fell behind are not decisions of the scheduler and are left out,
+/// and a segment is logged whole though the stream may write less of it, so the sizes logged add up to `StreamStats::bytes_written` only while nothing is stolen or cut short by a budget.
+#[derive(Debug, Clone)]
+pub struct DecisionLog {
+    decisions: VecDeque<Decision>,
+    capacity: usize,
+    dropped: u64,
+    rounds: u64,
+    epoch: Instant,
+}
+
+impl DecisionLog {
+    /// A log of the latest `capacity` decisions, which keeps none and costs nothing if `capacity` is 0
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            decisions: VecDeque::new(),
+            capacity,
+            dropped: 0,
+            rounds: 0,
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.decisions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decisions.is_empty()
+    }
+
+    /// The decisions kept, oldest first
+    pub fn iter(&self) -> vec_deque::Iter<'_, Decision> {
+        self.decisions.iter()
+    }
+
+    /// How many decisions were dropped to make room for newer ones
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Write one line per decision kept, oldest first
+    ///
+    /// A line reads `<at µs> <round> <start sequence>+<size> -> <stream ID> |`, then `<ID>:<bytes written>:<last write latency µs>:<RTT µs>` for each stream offered, with `-` for a measurement missing, e.g.:
+    ///
+    /// ```text
+    /// 1523 4 1048576+65536 -> 1 | 0:1048576:812:- 1:983040:790:-
+    /// ```
+    pub fn write_to(&self, mut writer: impl io::Write) -> io::Result<()> {
+        let micros = |duration: Option<Duration>| match duration {
+            Some(duration) => duration.as_micros().to_string(),
+            None => "-".to_string(),
+        };
+        for decision in &self.decisions {
+            write!(
+                writer,
+                "{} {} {}+{} -> {} |",
+                decision.at.as_micros(),
+                decision.round,
+                decision.sequence.start.inner(),
+                decision.size(),
+                decision.stream.inner(),
+            )?;
+            for stats in decision.streams.iter() {
+                write!(
+                    writer,
+                    " {}:{}:{}:{}",
+                    stats.id().inner(),
+                    stats.bytes_written(),
+                    micros(stats.last_write_latency()),
+                    micros(stats.rtt()),
+                )?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Start a round offering `streams`, returning the snapshot its decisions share unless the log keeps none
+    pub(crate) fn start_round(&mut self, streams: &[StreamMeta]) -> Option<Arc<[StreamStats]>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        self.rounds += 1;
+        Some(streams.iter().map(|stream| stream.stats).collect())
+    }
+
+    /// Log that the segment `sequence` went to `stream` in the round started last
+    pub(crate) fn record(
+        &mut self,
+        sequence: Range<Sequence>,
+        stream: StreamId,
+        streams: &Arc<[StreamStats]>,
+    ) {
+        if self.decisions.len() == self.capacity {
+            self.decisions.pop_front();
+            self.dropped += 1;
+        }
+        self.decisions.push_back(Decision {
+            at: self.epoch.elapsed(),
+            round: self.rounds - 1,
+            sequence,
+            stream,
+            streams: Arc::clone(streams),
+        });
+    }
+}
+
+impl Default for DecisionLog {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn streams(latencies: &[u64]) -> Vec<StreamMeta> {
+        latencies
+            .iter()
+            .enumerate()
+            .map(|(i, &latency)| {
+                let mut stats = StreamStats::new(StreamId::new(i));
+                stats.record_write(1, Duration::from_millis(latency));
+                StreamMeta::new(stats)
+            })
+            .collect()
+    }
+
+    fn segments(count: u64) -> Vec<SegmentMeta> {
+        (0..count)
+            .map(|i| SegmentMeta::new(Sequence::new(i)..Sequence::new(i + 1)))
+            .collect()
+    }
+
+    #[test]
+    fn policies_differ() {
+        let segments = segments(2);
+        let streams = streams(&[30, 10, 20]);
+
+        let assignments = RoundRobin.assign(&segments, &streams);
+        let targets: Vec<usize> = assignments.iter().map(|a| a.stream).collect();
+        assert_eq!(targets, [0, 1]);
+
+        let assignments = LowestLatency.assign(&segments, &streams);
+        let targets: Vec<usize> = assignments.iter().map(|a| a.stream).collect();
+        assert_eq!(targets, [1, 2]);
+    }
+
+    #[test]
+    fn unmeasured_streams_first() {
+        let mut streams = streams(&[10, 20]);
+        streams.push(StreamMeta::new(StreamStats::new(StreamId::new(2))));
+
+        let assignments = LowestLatency.assign(&segments(3), &streams);
+        let targets: Vec<usize> = assignments.iter().map(|a| a.stream).collect();
+        assert_eq!(targets, [2, 0, 1]);
+    }
+
+    #[test]
+    fn slow_paths_left_out() {
+        let streams: Vec<StreamMeta> = [Some(50), Some(5), None, Some(9)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, rtt)| {
+                let mut stats = StreamStats::new(StreamId::new(i));
+                if let Some(rtt) = rtt {
+                    stats.record_rtt(Duration::from_millis(rtt));
+                }
+                StreamMeta::new(stats)
+            })
+            .collect();
+
+        let assignments = LowestRtt.assign(&segments(4), &streams);
+        let targets: Vec<usize> = assignments.iter().map(|a| a.stream).collect();
+        assert_eq!(targets, [2, 1, 3]);
+    }
+
+    #[test]
+    fn gapped_paths_left_out() {
+        let streams: Vec<StreamMeta> = [Some((3, 1)), Some((0, 9)), None, Some((0, 2))]
+            .into_iter()
+            .enumerate()
+            .map(|(i, feedback)| {
+                let mut stats = StreamStats::new(StreamId::new(i));
+                if let Some((gaps, jitter)) = feedback {
+                    let jitter = Duration::from_millis(jitter);
+                    stats.record_feedback(SubflowScore::new(i as u32, 10, gaps, jitter));
+                }
+                StreamMeta::new(stats)
+            })
+            .collect();
+
+        let assignments = FewestGaps.assign(&segments(4), &streams);
+        let targets: Vec<usize> = assignments.iter().map(|a| a.stream).collect();
+        assert_eq!(targets, [2, 3, 1]);
+    }
+
+    #[test]
+    fn decision_log() {
+        let streams = streams(&[3, 5]);
+        let mut log = DecisionLog::new(0);
+        assert!(log.start_round(&streams).is_none());
+        assert!(log.is_empty());
+
+        let mut log = DecisionLog::new(3);
+        for round in 0..2 {
+            let snapshot = log.start_round(&streams).unwrap();
+            for i in 0..2 {
+                let start = round * 2 + i;
+                let sequence = Sequence::new(start)..Sequence::new(start + 1);
+                log.record(sequence, StreamId::new(i as usize), &snapshot);
+            }
+        }
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.dropped(), 1);
+        let rounds: Vec<u64> = log.iter().map(|d| d.round()).collect();
+        assert_eq!(rounds, [0, 1, 1]);
+
+        let mut dump = vec![];
+        log.write_to(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let (_, line) = lines[2].split_once(' ').unwrap();
+        assert_eq!(line, "1 3+1 -> 1 | 0:1:3000:- 1:1:5000:-");
+    }
+}
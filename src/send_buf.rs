@@ -1,48 +1,546 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{btree_map, BTreeMap},
+    iter::Peekable,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use bytes::Bytes;
+use bytes::{Buf, Bytes, BytesMut};
+use thiserror::Error;
 
 use crate::message::{DataSegment, Sequence};
 
+/// The default of `SendStreamBuf::set_min_segment_size`
 const MINIMUM_PAYLOAD_SIZE: usize = 8192;
 
-#[derive(Debug)]
+/// The data of a send and which of its segments are unsent or waiting for an acknowledgement
+///
+/// Segments are only ever ranges of sequences, so every segment handed out and every split of one, be it for a round, a steal or a retransmission, is a `Bytes::slice` of the pushed data and never a copy.
+/// One `Bytes` pushed holds one payload allocation however many segments it is split into.
+#[derive(Debug, Clone)]
 pub struct SendStreamBuf {
-    data: Bytes,
+    /// The pushed data by its start sequence, released once every byte of it is acknowledged
+    chunks: BTreeMap<Sequence, Bytes>,
     unsent_segments: BTreeMap<Sequence, usize>,
+    /// Written but not acknowledged yet
+    sent_segments: BTreeMap<Sequence, usize>,
+    /// Where the next pushed data starts
+    end_sequence: Sequence,
+    total_bytes: usize,
+    capacity_limit: Option<usize>,
+    min_segment_size: usize,
+    progress: Option<ProgressHandle>,
+    /// Bytes to be pushed that `progress` counts in its total already
+    progress_ahead: usize,
+}
+
+/// `SendStreamBuf::push` would hold more than its capacity limit
+#[derive(Debug, Error)]
+#[error("Send buffer full with {} bytes left over", .remainder.len())]
+pub struct BufferFull {
+    remainder: Bytes,
+}
+
+impl BufferFull {
+    /// The data that did not fit
+    pub fn remainder(&self) -> &Bytes {
+        &self.remainder
+    }
+
+    pub fn into_remainder(self) -> Bytes {
+        self.remainder
+    }
+}
+
+/// `SendStreamBuf::from_segments` was given segments sharing sequences
+#[derive(Debug, Error)]
+#[error("Segment at {sequence} overlaps another")]
+pub struct OverlappingSegment {
+    sequence: Sequence,
+}
+
+impl OverlappingSegment {
+    /// The start of the later of the two segments
+    pub fn sequence(&self) -> Sequence {
+        self.sequence
+    }
+}
+
+/// Whether a segment listed by `SendStreamBuf::segments` still has to be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SegmentState {
+    Unsent,
+    /// Written but not acknowledged yet
+    Sent,
+}
+
+/// A segment of a `SendStreamBuf`, as a range of sequences without its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SegmentInfo {
     start_sequence: Sequence,
+    len: usize,
+    state: SegmentState,
+}
+
+impl SegmentInfo {
+    pub fn start_sequence(&self) -> Sequence {
+        self.start_sequence
+    }
+
+    /// One past the last sequence of the segment
+    pub fn end_sequence(&self) -> Sequence {
+        Sequence::new(self.start_sequence.inner() + self.len as u64)
+    }
+
+    /// Payload bytes of the segment, never zero
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn state(&self) -> SegmentState {
+        self.state
+    }
+
+    pub fn is_sent(&self) -> bool {
+        self.state == SegmentState::Sent
+    }
+}
+
+/// The segments of a `SendStreamBuf` in ascending sequence, see `SendStreamBuf::segments`
+#[derive(Debug, Clone)]
+pub struct Segments<'a> {
+    unsent: Peekable<btree_map::Iter<'a, Sequence, usize>>,
+    sent: Peekable<btree_map::Iter<'a, Sequence, usize>>,
+}
+
+impl Iterator for Segments<'_> {
+    type Item = SegmentInfo;
+
+    fn next(&mut self) -> Option<SegmentInfo> {
+        // Both maps are disjoint ranges of sequences, so merging their keys keeps them ascending
+        let unsent_first = match (self.unsent.peek(), self.sent.peek()) {
+            (Some((unsent, _)), Some((sent, _))) => unsent < sent,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return None,
+        };
+        let (state, (&start_sequence, &len)) = if unsent_first {
+            (SegmentState::Unsent, self.unsent.next()?)
+        } else {
+            (SegmentState::Sent, self.sent.next()?)
+        };
+        Some(SegmentInfo {
+            start_sequence,
+            len,
+            state,
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.unsent.len() + self.sent.len();
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for Segments<'_> {}
+
+/// A snapshot of how far a `SendStreamBuf` has been written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Progress {
+    pub total_bytes: usize,
+    /// Bytes written at least once, less those found lost since
+    pub sent_bytes: usize,
+    pub unsent_segments: usize,
+}
+
+/// Follows the `Progress` of a `SendStreamBuf` from another task without locking the buffer
+#[derive(Debug, Clone, Default)]
+pub struct ProgressHandle {
+    counters: Arc<ProgressCounters>,
+}
+
+#[derive(Debug, Default)]
+struct ProgressCounters {
+    total_bytes: AtomicUsize,
+    sent_bytes: AtomicUsize,
+    unsent_segments: AtomicUsize,
+}
+
+impl ProgressHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            total_bytes: self.counters.total_bytes.load(Ordering::Relaxed),
+            sent_bytes: self.counters.sent_bytes.load(Ordering::Relaxed),
+            unsent_segments: self.counters.unsent_segments.load(Ordering::Relaxed),
+        }
+    }
+
+    fn store(&self, progress: Progress) {
+        let counters = &self.counters;
+        counters
+            .total_bytes
+            .store(progress.total_bytes, Ordering::Relaxed);
+        counters
+            .sent_bytes
+            .store(progress.sent_bytes, Ordering::Relaxed);
+        counters
+            .unsent_segments
+            .store(progress.unsent_segments, Ordering::Relaxed);
+    }
 }
 
 impl SendStreamBuf {
     pub fn new(data: Bytes, start_sequence: Sequence) -> Self {
-        let mut unsent = BTreeMap::new();
-        if !data.is_empty() {
-            unsent.insert(start_sequence, data.len());
+        let mut this = Self::empty(start_sequence, None);
+        this.push(data).unwrap();
+        this
+    }
+
+    /// A buffer of exactly `segments`, which may leave gaps between them but not overlap
+    ///
+    /// Nothing can be pushed after the last segment.
+    pub fn from_segments(mut segments: Vec<DataSegment>) -> Result<Self, OverlappingSegment> {
+        segments.sort_by_key(|segment| segment.start_sequence());
+        let start_sequence = segments
+            .first()
+            .map_or(Sequence::new(0), |segment| segment.start_sequence());
+        let mut this = Self::empty(start_sequence, Some(0));
+        for segment in segments {
+            let sequence = segment.start_sequence();
+            if sequence < this.end_sequence {
+                return Err(OverlappingSegment { sequence });
+            }
+            this.end_sequence = segment.end_sequence();
+            this.total_bytes += segment.size();
+            this.unsent_segments.insert(sequence, segment.size());
+            this.chunks.insert(sequence, segment.payload().clone());
         }
+        Ok(this)
+    }
+
+    /// An empty buffer that holds at most `limit` bytes of data pushed from `start_sequence` on
+    pub fn with_capacity_limit(start_sequence: Sequence, limit: usize) -> Self {
+        Self::empty(start_sequence, Some(limit))
+    }
+
+    fn empty(start_sequence: Sequence, capacity_limit: Option<usize>) -> Self {
         Self {
-            data,
-            unsent_segments: unsent,
-            start_sequence,
+            chunks: BTreeMap::new(),
+            unsent_segments: BTreeMap::new(),
+            sent_segments: BTreeMap::new(),
+            end_sequence: start_sequence,
+            total_bytes: 0,
+            capacity_limit,
+            min_segment_size: MINIMUM_PAYLOAD_SIZE,
+            progress: None,
+            progress_ahead: 0,
+        }
+    }
+
+    /// Append `data` as a new unsent segment
+    ///
+    /// Takes as much of `data` as the capacity limit allows and returns the rest in `BufferFull`.
+    pub fn push(&mut self, mut data: Bytes) -> Result<(), BufferFull> {
+        let room = self.capacity_limit.map_or(usize::MAX, |limit| {
+            limit.saturating_sub(self.resident_bytes())
+        });
+        let remainder = data.split_off(data.len().min(room));
+        if !data.is_empty() {
+            let start_sequence = self.end_sequence;
+            self.end_sequence = Sequence::new(start_sequence.inner() + data.len() as u64);
+            self.total_bytes += data.len();
+            self.unsent_segments.insert(start_sequence, data.len());
+            if let Some(handle) = &self.progress {
+                let counted = data.len().min(self.progress_ahead);
+                self.progress_ahead -= counted;
+                handle
+                    .counters
+                    .total_bytes
+                    .fetch_add(data.len() - counted, Ordering::Relaxed);
+            }
+            self.chunks.insert(start_sequence, data);
+            self.publish_unsent_segments();
+        }
+        if remainder.is_empty() {
+            return Ok(());
+        }
+        Err(BufferFull { remainder })
+    }
+
+    /// Append the chunks of `buf` as new unsent segments, contiguous like the data of a single `Self::push`
+    ///
+    /// The chunks of a `Bytes` or a chain of them are sliced out with `Buf::copy_to_bytes`, which shares their allocations, while other `Buf`s copy them.
+    /// The rest in `BufferFull` is copied into one piece.
+    pub fn push_buf(&mut self, mut buf: impl Buf) -> Result<(), BufferFull> {
+        while buf.has_remaining() {
+            let chunk = buf.chunk().len();
+            if let Err(full) = self.push(buf.copy_to_bytes(chunk)) {
+                let rest = buf.copy_to_bytes(buf.remaining());
+                let remainder = [full.remainder, rest].concat().into();
+                return Err(BufferFull { remainder });
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop splitting segments into pieces smaller than `size` bytes
+    ///
+    /// Only the last piece of a segment may fall below it.
+    pub fn set_min_segment_size(&mut self, size: usize) {
+        self.min_segment_size = size;
+    }
+
+    /// The most bytes the buffer holds, if limited
+    pub fn capacity_limit(&self) -> Option<usize> {
+        self.capacity_limit
+    }
+
+    /// Payload bytes held in memory
+    ///
+    /// Pushed data is only released as a whole, so this is at least `Self::retained_bytes`.
+    pub fn resident_bytes(&self) -> usize {
+        self.chunks.values().map(Bytes::len).sum()
+    }
+
+    pub fn progress(&self) -> Progress {
+        let total_bytes = self.total_bytes;
+        Progress {
+            total_bytes,
+            sent_bytes: total_bytes - self.unsent_bytes(),
+            unsent_segments: self.unsent_segments.len(),
         }
     }
 
+    /// Keep `handle` up to date with the progress of the buffer from now on
+    pub fn track_progress(&mut self, handle: ProgressHandle) {
+        handle.store(self.progress());
+        self.progress = Some(handle);
+    }
+
+    /// Keep `handle` up to date with the progress of the buffer from now on, counting the `ahead` bytes to be pushed later in its total from the start
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(crate) fn track_progress_ahead(&mut self, handle: ProgressHandle, ahead: usize) {
+        let mut progress = self.progress();
+        progress.total_bytes += ahead;
+        handle.store(progress);
+        self.progress = Some(handle);
+        self.progress_ahead = ahead;
+    }
+
+    /// An empty buffer that picks up where this one ends, for the rest of the same data
+    ///
+    /// It takes over the progress handle along with the bytes it counts ahead, see `Self::track_progress_ahead`.
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(crate) fn continuation(&mut self) -> Self {
+        let mut next = Self::empty(self.end_sequence, self.capacity_limit);
+        next.min_segment_size = self.min_segment_size;
+        next.progress = self.progress.clone();
+        next.progress_ahead = std::mem::take(&mut self.progress_ahead);
+        next
+    }
+
+    fn publish_unsent_segments(&self) {
+        if let Some(handle) = &self.progress {
+            let segments = self.unsent_segments.len();
+            handle
+                .counters
+                .unsent_segments
+                .store(segments, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether every segment has been written
     pub fn done(&self) -> bool {
         self.unsent_segments.is_empty()
     }
 
+    /// Whether every segment has been written and acknowledged
+    pub fn acked(&self) -> bool {
+        self.unsent_segments.is_empty() && self.sent_segments.is_empty()
+    }
+
+    /// Where the next pushed data starts
+    pub fn end_sequence(&self) -> Sequence {
+        self.end_sequence
+    }
+
+    pub fn unsent_bytes(&self) -> usize {
+        self.unsent_segments.values().sum()
+    }
+
+    /// The start of the earliest unsent segment
+    pub fn first_unsent_sequence(&self) -> Option<Sequence> {
+        self.unsent_segments.keys().next().copied()
+    }
+
+    /// The data pushed from `sequence` on, which is copied only if it spans several pushes
+    ///
+    /// Empty if `sequence` is at or past the end and missing what has been released by acknowledgements.
+    pub fn data_from(&self, sequence: Sequence) -> Bytes {
+        let mut parts = self
+            .chunks
+            .iter()
+            .filter_map(|(start, chunk)| {
+                let end = start.inner() + chunk.len() as u64;
+                let skip = sequence.inner().saturating_sub(start.inner());
+                (sequence.inner() < end).then(|| chunk.slice(skip as usize..))
+            })
+            .peekable();
+        let Some(first) = parts.next() else {
+            return Bytes::new();
+        };
+        if parts.peek().is_none() {
+            return first;
+        }
+        let mut data = BytesMut::from(&first[..]);
+        for part in parts {
+            data.extend_from_slice(&part);
+        }
+        data.freeze()
+    }
+
+    /// Bytes that are either unsent or unacknowledged
+    pub fn retained_bytes(&self) -> usize {
+        self.unsent_bytes() + self.sent_bytes()
+    }
+
+    /// Bytes of the segments listed by `Self::segments`, the same as `Self::retained_bytes`
+    pub fn len(&self) -> usize {
+        self.retained_bytes()
+    }
+
+    /// Whether no segment is left, be it unsent or unacknowledged, the same as `Self::acked`
+    pub fn is_empty(&self) -> bool {
+        self.acked()
+    }
+
+    /// Bytes written but not acknowledged yet
+    ///
+    /// Unlike `Progress::sent_bytes`, acknowledged bytes are not counted.
+    pub fn sent_bytes(&self) -> usize {
+        self.sent_segments.values().sum()
+    }
+
+    /// Every unsent and unacknowledged segment in ascending sequence
+    ///
+    /// Segments split afterwards, e.g., by `Self::split_unsent_at`, or marked with `Self::mark_as_sent`, are listed as they are then by the next call.
+    pub fn segments(&self) -> Segments<'_> {
+        Segments {
+            unsent: self.unsent_segments.iter().peekable(),
+            sent: self.sent_segments.iter().peekable(),
+        }
+    }
+
     /// Best-effect
     pub fn split_first_unsent_segment(&mut self, segments: usize) {
         if self.unsent_segments.len() >= segments {
             return;
         }
-        let Some((sequence, length)) = self.unsent_segments.pop_first() else {
+        let Some((&sequence, _)) = self.unsent_segments.first_key_value() else {
             return;
         };
+        self.split_unsent_segment(sequence, segments);
+    }
 
-        let segment_bytes = length.div_ceil(segments).max(MINIMUM_PAYLOAD_SIZE);
-        if segment_bytes == 0 {
+    /// Best-effect
+    ///
+    /// Split the first unsent segment into pieces proportional to `weights` in order.
+    ///
+    /// Trailing weights are dropped while their pieces would fall below the minimum payload size.
+    pub fn split_first_unsent_segment_weighted(&mut self, weights: &[f64]) {
+        if self.unsent_segments.len() >= weights.len() {
             return;
         }
+        let Some((&sequence, &length)) = self.unsent_segments.first_key_value() else {
+            return;
+        };
+
+        let mut weights = weights;
+        let total = loop {
+            let total: f64 = weights.iter().sum();
+            let Some(last) = weights.last() else {
+                return;
+            };
+            if weights.len() == 1 || length as f64 * last / total >= self.min_segment_size as f64 {
+                break total;
+            }
+            weights = &weights[..weights.len() - 1];
+        };
+        if !total.is_normal() {
+            return;
+        }
+
+        self.unsent_segments.remove(&sequence);
+        let mut next_sequence = sequence;
+        let mut remaining_bytes = length;
+        for (i, weight) in weights.iter().enumerate() {
+            let length = if i + 1 == weights.len() {
+                remaining_bytes
+            } else {
+                ((length as f64 * weight / total).round() as usize).min(remaining_bytes)
+            };
+            if length == 0 {
+                continue;
+            }
+            self.unsent_segments.insert(next_sequence, length);
+            remaining_bytes -= length;
+            next_sequence = Sequence::new(next_sequence.inner() + length as u64);
+        }
+        self.publish_unsent_segments();
+    }
+
+    /// Split every unsent segment evenly into pieces of at most `max` bytes
+    ///
+    /// If even pieces would fall below the minimum segment size, the pieces are of `max` bytes but the last.
+    pub fn limit_segment_size(&mut self, max: usize) {
+        if max == 0 {
+            return;
+        }
+        let oversized: Vec<(Sequence, usize)> = self
+            .unsent_segments
+            .iter()
+            .filter(|(_, length)| **length > max)
+            .map(|(sequence, length)| (*sequence, *length))
+            .collect();
+        for (sequence, length) in oversized {
+            self.unsent_segments.remove(&sequence);
+
+            // Even pieces so that no tiny tail is left behind
+            let pieces = length.div_ceil(max);
+            let mut segment_bytes = length.div_ceil(pieces);
+            if segment_bytes < self.min_segment_size {
+                segment_bytes = max;
+            }
+            let mut next_sequence = sequence;
+            let mut remaining_bytes = length;
+            while remaining_bytes > 0 {
+                let length = segment_bytes.min(remaining_bytes);
+                self.unsent_segments.insert(next_sequence, length);
+                remaining_bytes -= length;
+                next_sequence = Sequence::new(next_sequence.inner() + length as u64);
+            }
+        }
+        self.publish_unsent_segments();
+    }
+
+    /// Best-effect
+    fn split_unsent_segment(&mut self, sequence: Sequence, segments: usize) {
+        if segments == 0 {
+            return;
+        }
+        let Some(length) = self.unsent_segments.remove(&sequence) else {
+            return;
+        };
+
+        let segment_bytes = length.div_ceil(segments).max(self.min_segment_size);
 
         // e.g.,
         // `|      17       |`
@@ -55,19 +553,551 @@ impl SendStreamBuf {
             remaining_bytes -= length;
             next_sequence = Sequence::new(next_sequence.inner() + length as u64);
         }
+        self.publish_unsent_segments();
+    }
+
+    /// Split the unsent segment that contains `sequence` so that a new segment starts there
+    ///
+    /// Returns `false` if no unsent segment contains `sequence` past its start.
+    pub fn split_unsent_at(&mut self, sequence: Sequence) -> bool {
+        let Some((&start, &length)) = self.unsent_segments.range(..sequence).next_back() else {
+            return false;
+        };
+        let head = sequence.inner() - start.inner();
+        let Some(tail) = (length as u64).checked_sub(head).filter(|tail| *tail > 0) else {
+            return false;
+        };
+        self.unsent_segments.insert(start, head as usize);
+        self.unsent_segments.insert(sequence, tail as usize);
+        self.publish_unsent_segments();
+        true
+    }
+
+    /// The unsent segment starting at `sequence`
+    pub fn unsent_segment(&self, sequence: Sequence) -> Option<DataSegment> {
+        let length = self.unsent_segments.get(&sequence)?;
+        Some(self.segment(sequence, *length))
+    }
+
+    /// The earliest unsent segment, still unsent
+    pub fn peek_next_unsent(&self) -> Option<DataSegment> {
+        let (&sequence, &length) = self.unsent_segments.first_key_value()?;
+        Some(self.segment(sequence, length))
+    }
+
+    /// Split the unsent segment that contains `sequence` so that the rest of it from there on stays unsent as a segment of its own, and return that
+    ///
+    /// Returns `None` if no unsent segment contains `sequence`.
+    pub fn retain_unsent(&mut self, sequence: Sequence) -> Option<DataSegment> {
+        self.split_unsent_at(sequence);
+        self.unsent_segment(sequence)
     }
 
+    /// Every unsent segment with its data in ascending sequence
     pub fn iter_unsent_segments(&self) -> impl Iterator<Item = DataSegment> + '_ {
-        self.unsent_segments.iter().map(|(start_sequence, length)| {
-            let start = start_sequence.inner() - self.start_sequence.inner();
-            let start = usize::try_from(start).unwrap();
-            let range = start..(start + *length);
-            let payload = self.data.slice(range);
-            DataSegment::new(*start_sequence, payload).unwrap()
-        })
+        self.unsent_segments
+            .iter()
+            .map(|(start_sequence, length)| self.segment(*start_sequence, *length))
+    }
+
+    /// The addresses of the pushed data that `sequence` falls in, for tests to check that segments point into it
+    #[cfg(test)]
+    fn payload_ptr_range(&self, sequence: Sequence) -> Option<Range<*const u8>> {
+        let (start, chunk) = self.chunks.range(..=sequence).next_back()?;
+        (sequence.inner() < start.inner() + chunk.len() as u64).then(|| chunk.as_ptr_range())
     }
 
+    fn segment(&self, start_sequence: Sequence, length: usize) -> DataSegment {
+        // Segments never span chunks since each chunk is pushed as a segment of its own
+        let (chunk_start, chunk) = self.chunks.range(..=start_sequence).next_back().unwrap();
+        let start = start_sequence.inner() - chunk_start.inner();
+        let start = usize::try_from(start).unwrap();
+        let range = start..(start + length);
+        let payload = chunk.slice(range);
+        DataSegment::new(start_sequence, payload).unwrap()
+    }
+
+    /// The segment starting at `sequence` now waits for an acknowledgement
     pub fn mark_as_sent(&mut self, sequence: Sequence) {
-        self.unsent_segments.remove(&sequence);
+        if let Some(length) = self.unsent_segments.remove(&sequence) {
+            self.sent_segments.insert(sequence, length);
+            if let Some(handle) = &self.progress {
+                handle
+                    .counters
+                    .sent_bytes
+                    .fetch_add(length, Ordering::Relaxed);
+            }
+            self.publish_unsent_segments();
+        }
+    }
+
+    /// Release the sent segments that end at or before the cumulative acknowledgement `ack`
+    pub fn mark_as_acked(&mut self, ack: Sequence) {
+        self.sent_segments
+            .retain(|sequence, length| sequence.inner() + *length as u64 > ack.inner());
+
+        let retained = [
+            self.unsent_segments.first_key_value(),
+            self.sent_segments.first_key_value(),
+        ];
+        let first_retained = retained
+            .into_iter()
+            .flatten()
+            .map(|(sequence, _)| *sequence)
+            .min()
+            .unwrap_or(self.end_sequence);
+        while let Some(chunk) = self.chunks.first_entry() {
+            if chunk.key().inner() + chunk.get().len() as u64 > first_retained.inner() {
+                break;
+            }
+            chunk.remove();
+        }
+    }
+
+    /// Release the sent segments that lie whole within the selective acknowledgement `range`
+    ///
+    /// Their data stays in memory until the cumulative acknowledgement passes it.
+    pub fn mark_as_selectively_acked(&mut self, range: Range<Sequence>) {
+        self.sent_segments.retain(|sequence, length| {
+            let end = sequence.inner() + *length as u64;
+            !(range.start <= *sequence && end <= range.end.inner())
+        });
+    }
+
+    /// Send the segment starting at `sequence` again since it might never have reached the receiver
+    ///
+    /// The segment is re-split into at most `segments` pieces like in `Self::mark_as_failed`.
+    pub fn mark_as_lost(&mut self, sequence: Sequence, segments: usize) {
+        let Some(length) = self.sent_segments.remove(&sequence) else {
+            return;
+        };
+        if let Some(handle) = &self.progress {
+            handle
+                .counters
+                .sent_bytes
+                .fetch_sub(length, Ordering::Relaxed);
+        }
+        self.unsent_segments.insert(sequence, length);
+        self.split_unsent_segment(sequence, segments);
+    }
+
+    /// Keep the segment starting at `sequence` unsent so that it is the next to be retransmitted
+    ///
+    /// The segment is re-split into at most `segments` pieces so that the remaining streams each carry a fair share of it.
+    pub fn mark_as_failed(&mut self, sequence: Sequence, segments: usize) {
+        self.split_unsent_segment(sequence, segments);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment_sizes(buf: &SendStreamBuf) -> Vec<usize> {
+        buf.iter_unsent_segments().map(|s| s.size()).collect()
+    }
+
+    #[test]
+    fn progress() {
+        let mut buf = SendStreamBuf::new(Bytes::from(vec![0; 100]), Sequence::new(0));
+        let handle = ProgressHandle::new();
+        buf.track_progress(handle.clone());
+        assert!(buf.split_unsent_at(Sequence::new(40)));
+        assert_eq!(handle.progress(), buf.progress());
+        buf.mark_as_sent(Sequence::new(0));
+        let progress = handle.progress();
+        assert_eq!(progress, buf.progress());
+        assert_eq!(progress.sent_bytes, 40);
+        assert_eq!(progress.unsent_segments, 1);
+        buf.mark_as_lost(Sequence::new(0), 2);
+        assert_eq!(handle.progress(), buf.progress());
+        assert_eq!(handle.progress().sent_bytes, 0);
+    }
+
+    #[test]
+    fn progress_of_parts() {
+        let handle = ProgressHandle::new();
+        let mut first = SendStreamBuf::new(Bytes::from(vec![0; 60]), Sequence::new(0));
+        first.track_progress_ahead(handle.clone(), 40);
+        assert_eq!(handle.progress().total_bytes, 100);
+        first.mark_as_sent(Sequence::new(0));
+        let mut second = first.continuation();
+        second.push(Bytes::from(vec![0; 40])).unwrap();
+        assert_eq!(second.end_sequence(), Sequence::new(100));
+        let progress = handle.progress();
+        assert_eq!(progress.total_bytes, 100);
+        assert_eq!(progress.sent_bytes, 60);
+        assert_eq!(progress.unsent_segments, 1);
+        second.mark_as_sent(Sequence::new(60));
+        assert_eq!(handle.progress().sent_bytes, 100);
+        first.mark_as_lost(Sequence::new(0), 1);
+        assert_eq!(handle.progress().sent_bytes, 40);
+    }
+
+    #[test]
+    fn data_from() {
+        let mut buf = SendStreamBuf::new(Bytes::from_static(b"hello"), Sequence::new(10));
+        buf.push(Bytes::from_static(b" world")).unwrap();
+        assert_eq!(&buf.data_from(Sequence::new(12))[..], b"llo world");
+        assert_eq!(&buf.data_from(Sequence::new(16))[..], b"world");
+        assert!(buf.data_from(Sequence::new(21)).is_empty());
+
+        buf.split_unsent_at(Sequence::new(13));
+        buf.mark_as_sent(Sequence::new(10));
+        assert_eq!(buf.first_unsent_sequence(), Some(Sequence::new(13)));
+    }
+
+    #[test]
+    fn push_chained_bytes() {
+        let chunks = [
+            Bytes::from_static(b"hello"),
+            Bytes::from(b" multipath".to_vec()),
+            Bytes::from(b" world".to_vec()),
+        ];
+        let rope = chunks[0]
+            .clone()
+            .chain(chunks[1].clone())
+            .chain(chunks[2].clone());
+        let mut buf = SendStreamBuf::with_capacity_limit(Sequence::new(7), usize::MAX);
+        buf.push_buf(rope).unwrap();
+        assert_eq!(buf.unsent_bytes(), 21);
+        assert_eq!(
+            &buf.data_from(Sequence::new(7))[..],
+            b"hello multipath world"
+        );
+
+        // One contiguous range made of the chunks themselves
+        let segments: Vec<DataSegment> = buf.iter_unsent_segments().collect();
+        let mut sequence = Sequence::new(7);
+        for (segment, chunk) in segments.iter().zip(&chunks) {
+            assert_eq!(segment.start_sequence(), sequence);
+            assert_eq!(segment.payload().as_ptr_range(), chunk.as_ptr_range());
+            sequence = segment.end_sequence();
+        }
+        assert_eq!(segments.len(), 3);
+        assert_eq!(sequence, Sequence::new(28));
+
+        // A foreign `Buf` is copied, and what does not fit is handed back
+        let mut buf = SendStreamBuf::with_capacity_limit(Sequence::new(0), 8);
+        let full = buf
+            .push_buf((&b"hello"[..]).chain(&b" world"[..]))
+            .unwrap_err();
+        assert_eq!(&buf.data_from(Sequence::new(0))[..], b"hello wo");
+        assert_eq!(full.remainder(), "rld");
+    }
+
+    /// Every unsent segment points into the pushed data at its own offset
+    fn assert_sliced(buf: &SendStreamBuf, data: &Bytes, start_sequence: Sequence) {
+        let range = buf.payload_ptr_range(start_sequence).unwrap();
+        assert_eq!(range, data.as_ptr_range());
+        for segment in buf.iter_unsent_segments() {
+            let offset = (segment.start_sequence().inner() - start_sequence.inner()) as usize;
+            let payload = segment.payload().as_ptr_range();
+            assert_eq!(payload.start, data[offset..].as_ptr());
+            assert!(range.start <= payload.start && payload.end <= range.end);
+        }
+    }
+
+    #[test]
+    fn segments_share_the_data() {
+        let data = Bytes::from(vec![0; 100]);
+        let mut buf = SendStreamBuf::new(data.clone(), Sequence::new(0));
+        buf.limit_segment_size(30);
+        let mut offset = 0;
+        for segment in buf.iter_unsent_segments() {
+            assert_eq!(segment.payload().as_ptr(), data[offset..].as_ptr());
+            offset += segment.size();
+        }
+        assert_eq!(offset, data.len());
+    }
+
+    #[test]
+    fn payload_ptr_range() {
+        let first = Bytes::from(vec![0; 10]);
+        let second = Bytes::from(vec![0; 20]);
+        let mut buf = SendStreamBuf::new(first.clone(), Sequence::new(7));
+        buf.push(second.clone()).unwrap();
+        assert_eq!(
+            buf.payload_ptr_range(Sequence::new(7)),
+            Some(first.as_ptr_range())
+        );
+        assert_eq!(
+            buf.payload_ptr_range(Sequence::new(16)),
+            Some(first.as_ptr_range())
+        );
+        assert_eq!(
+            buf.payload_ptr_range(Sequence::new(17)),
+            Some(second.as_ptr_range())
+        );
+        assert_eq!(
+            buf.payload_ptr_range(Sequence::new(36)),
+            Some(second.as_ptr_range())
+        );
+        assert!(buf.payload_ptr_range(Sequence::new(6)).is_none());
+        assert!(buf.payload_ptr_range(Sequence::new(37)).is_none());
+    }
+
+    #[test]
+    fn resplit_segments_share_the_data() {
+        let start_sequence = Sequence::new(7);
+        let data = Bytes::from(vec![0; 1 << 20]);
+        let mut buf = SendStreamBuf::new(data.clone(), start_sequence);
+        buf.set_min_segment_size(1 << 10);
+        buf.split_first_unsent_segment(3);
+        assert_sliced(&buf, &data, start_sequence);
+        buf.split_first_unsent_segment_weighted(&[1., 2., 3.]);
+        buf.limit_segment_size(1 << 16);
+        buf.split_unsent_at(Sequence::new(7 + 12345));
+        assert_sliced(&buf, &data, start_sequence);
+
+        // A segment whose stream failed is split again for the others
+        let first = buf.first_unsent_sequence().unwrap();
+        let segments = buf.iter_unsent_segments().count();
+        buf.mark_as_failed(first, 3);
+        assert_eq!(buf.iter_unsent_segments().count(), segments + 2);
+        assert_sliced(&buf, &data, start_sequence);
+    }
+
+    #[test]
+    fn retransmitted_segments_share_the_data() {
+        let start_sequence = Sequence::new(7);
+        let data = Bytes::from(vec![0; 1 << 20]);
+        let mut buf = SendStreamBuf::new(data.clone(), start_sequence);
+        buf.limit_segment_size(1 << 16);
+        let sent: Vec<Sequence> = buf
+            .iter_unsent_segments()
+            .map(|segment| segment.start_sequence())
+            .collect();
+        for &sequence in &sent {
+            buf.mark_as_sent(sequence);
+        }
+        assert_eq!(buf.unsent_bytes(), 0);
+
+        buf.mark_as_lost(sent[1], 5);
+        buf.mark_as_lost(sent[3], 2);
+        assert_eq!(buf.unsent_bytes(), 2 << 16);
+        assert_eq!(buf.iter_unsent_segments().count(), 7);
+        assert_sliced(&buf, &data, start_sequence);
+    }
+
+    #[test]
+    fn from_segments() {
+        let segment = |start, payload| {
+            DataSegment::new(Sequence::new(start), Bytes::from_static(payload)).unwrap()
+        };
+        let mut buf =
+            SendStreamBuf::from_segments(vec![segment(20, b"world"), segment(10, b"hello")])
+                .unwrap();
+        assert_eq!(segment_sizes(&buf), [5, 5]);
+        assert_eq!(
+            buf.unsent_segment(Sequence::new(20)).unwrap().payload(),
+            "world"
+        );
+        assert!(buf.push(Bytes::from_static(b"!")).is_err());
+
+        buf.mark_as_sent(Sequence::new(10));
+        buf.mark_as_acked(Sequence::new(15));
+        assert_eq!(buf.retained_bytes(), 5);
+
+        let err = SendStreamBuf::from_segments(vec![segment(10, b"hello"), segment(14, b"world")])
+            .unwrap_err();
+        assert_eq!(err.sequence(), Sequence::new(14));
+    }
+
+    #[test]
+    fn selective_acks() {
+        let mut buf = SendStreamBuf::new(Bytes::from(vec![0; 30]), Sequence::new(0));
+        buf.split_unsent_at(Sequence::new(10));
+        buf.split_unsent_at(Sequence::new(20));
+        for sequence in [0, 10, 20] {
+            buf.mark_as_sent(Sequence::new(sequence));
+        }
+        buf.mark_as_selectively_acked(Sequence::new(5)..Sequence::new(20));
+        assert_eq!(buf.retained_bytes(), 20);
+        buf.mark_as_selectively_acked(Sequence::new(20)..Sequence::new(30));
+        buf.mark_as_acked(Sequence::new(10));
+        assert!(buf.acked());
+    }
+
+    #[test]
+    fn capacity_limit() {
+        let mut buf = SendStreamBuf::with_capacity_limit(Sequence::new(10), 100);
+        buf.push(Bytes::from(vec![0; 60])).unwrap();
+        let full = buf.push(Bytes::from(vec![1; 60])).unwrap_err();
+        assert_eq!(full.remainder().len(), 20);
+        assert_eq!(buf.resident_bytes(), 100);
+        assert_eq!(segment_sizes(&buf), [60, 40]);
+        let segment = buf.unsent_segment(Sequence::new(70)).unwrap();
+        assert_eq!(segment.payload()[..], [1; 40]);
+        assert!(buf.push(full.into_remainder()).is_err());
+
+        // Only whole chunks are released
+        assert!(buf.split_unsent_at(Sequence::new(40)));
+        buf.mark_as_sent(Sequence::new(10));
+        buf.mark_as_sent(Sequence::new(40));
+        buf.mark_as_sent(Sequence::new(70));
+        buf.mark_as_acked(Sequence::new(40));
+        assert_eq!(buf.retained_bytes(), 70);
+        assert_eq!(buf.resident_bytes(), 100);
+        buf.mark_as_acked(Sequence::new(70));
+        assert_eq!(buf.resident_bytes(), 40);
+        buf.push(Bytes::from(vec![2; 60])).unwrap();
+        assert_eq!(buf.resident_bytes(), 100);
+        assert_eq!(buf.progress().total_bytes, 160);
+    }
+
+    #[test]
+    fn split_at() {
+        let mut buf = SendStreamBuf::new(Bytes::from(vec![0; 100]), Sequence::new(10));
+        assert!(!buf.split_unsent_at(Sequence::new(10)));
+        assert!(!buf.split_unsent_at(Sequence::new(110)));
+        assert!(buf.split_unsent_at(Sequence::new(70)));
+        assert_eq!(segment_sizes(&buf), [60, 40]);
+        buf.mark_as_sent(Sequence::new(10));
+        assert!(!buf.split_unsent_at(Sequence::new(30)));
+        let segment = buf.unsent_segment(Sequence::new(70)).unwrap();
+        assert_eq!(segment.end_sequence(), Sequence::new(110));
+        assert_eq!(buf.unsent_bytes(), 40);
+    }
+
+    #[test]
+    fn split_weighted() {
+        let mut buf = SendStreamBuf::new(Bytes::from(vec![0; 1 << 20]), Sequence::new(0));
+        buf.split_first_unsent_segment_weighted(&[3., 1.]);
+        assert_eq!(segment_sizes(&buf), [786432, 262144]);
+    }
+
+    #[test]
+    fn split_weighted_drops_tiny_pieces() {
+        let mut buf = SendStreamBuf::new(Bytes::from(vec![0; 1 << 16]), Sequence::new(0));
+        buf.split_first_unsent_segment_weighted(&[8., 2., 1.]);
+        assert_eq!(segment_sizes(&buf), [52429, 13107]);
+    }
+
+    #[test]
+    fn limit_segment_size() {
+        let mut buf = SendStreamBuf::new(Bytes::from(vec![0; 100_000]), Sequence::new(0));
+        buf.split_first_unsent_segment(2);
+        buf.limit_segment_size(1 << 14);
+        assert_eq!(segment_sizes(&buf), [12500; 8]);
+    }
+
+    #[test]
+    fn min_segment_size() {
+        let mut buf = SendStreamBuf::new(Bytes::from(vec![0; 40_000]), Sequence::new(0));
+        buf.set_min_segment_size(1 << 14);
+        buf.split_first_unsent_segment(4);
+        assert_eq!(segment_sizes(&buf), [16384, 16384, 7232]);
+
+        let mut buf = SendStreamBuf::new(Bytes::from(vec![0; 40_000]), Sequence::new(0));
+        buf.set_min_segment_size(1 << 14);
+        buf.limit_segment_size(30_000);
+        assert_eq!(segment_sizes(&buf), [20000, 20000]);
+        buf.set_min_segment_size(25_000);
+        buf.limit_segment_size(15_000);
+        assert_eq!(segment_sizes(&buf), [15000, 5000, 15000, 5000]);
+    }
+
+    #[test]
+    fn segments_share_allocation() {
+        let data = Bytes::from(vec![0; 1 << 20]);
+        let range = data.as_ptr_range();
+        let mut buf = SendStreamBuf::new(data, Sequence::new(0));
+        buf.split_first_unsent_segment(4);
+        buf.limit_segment_size(1 << 16);
+        for segment in buf.iter_unsent_segments() {
+            assert!(range.contains(&segment.payload().as_ptr()));
+        }
+    }
+
+    fn listed(buf: &SendStreamBuf) -> Vec<(u64, usize, bool)> {
+        buf.segments()
+            .map(|s| (s.start_sequence().inner(), s.len(), s.is_sent()))
+            .collect()
+    }
+
+    #[test]
+    fn segments() {
+        let mut buf = SendStreamBuf::new(Bytes::from(vec![0; 300]), Sequence::new(100));
+        assert_eq!(listed(&buf), [(100, 300, false)]);
+        buf.limit_segment_size(100);
+        buf.mark_as_sent(Sequence::new(200));
+        assert_eq!(
+            listed(&buf),
+            [(100, 100, false), (200, 100, true), (300, 100, false)]
+        );
+        assert_eq!(buf.segments().len(), 3);
+        assert_eq!((buf.len(), buf.sent_bytes()), (300, 100));
+
+        // Re-splitting a segment lists both pieces in place of it, still ascending
+        assert!(buf.split_unsent_at(Sequence::new(150)));
+        buf.mark_as_sent(Sequence::new(300));
+        let segments: Vec<SegmentInfo> = buf.segments().collect();
+        assert!(segments
+            .windows(2)
+            .all(|w| w[0].end_sequence() == w[1].start_sequence()));
+        assert_eq!(
+            listed(&buf),
+            [
+                (100, 50, false),
+                (150, 50, false),
+                (200, 100, true),
+                (300, 100, true)
+            ]
+        );
+        assert_eq!(
+            buf.peek_next_unsent().unwrap().start_sequence(),
+            Sequence::new(100)
+        );
+
+        buf.mark_as_acked(Sequence::new(100 + 300));
+        assert_eq!(buf.sent_bytes(), 0);
+        assert_eq!(buf.len(), 100);
+        buf.mark_as_sent(Sequence::new(100));
+        buf.mark_as_sent(Sequence::new(150));
+        buf.mark_as_acked(Sequence::new(400));
+        assert!(buf.is_empty());
+        assert!(buf.peek_next_unsent().is_none());
+    }
+
+    #[test]
+    fn retain_unsent() {
+        let mut buf = SendStreamBuf::new(Bytes::from_static(b"hello world"), Sequence::new(10));
+        let rest = buf.retain_unsent(Sequence::new(15)).unwrap();
+        assert_eq!(rest.payload(), " world");
+        assert_eq!(segment_sizes(&buf), [5, 6]);
+        // A segment already starting there is returned as it is
+        assert_eq!(buf.retain_unsent(Sequence::new(15)), Some(rest));
+        assert_eq!(
+            buf.retain_unsent(Sequence::new(10)).unwrap().payload(),
+            "hello"
+        );
+
+        buf.mark_as_sent(Sequence::new(10));
+        assert!(buf.retain_unsent(Sequence::new(12)).is_none());
+        assert!(buf.retain_unsent(Sequence::new(21)).is_none());
+        assert_eq!(listed(&buf), [(10, 5, true), (15, 6, false)]);
+    }
+
+    #[test]
+    fn sent_and_acked() {
+        let mut buf = SendStreamBuf::new(Bytes::from(vec![0; 300]), Sequence::new(100));
+        buf.limit_segment_size(100);
+        buf.mark_as_sent(Sequence::new(100));
+        buf.mark_as_sent(Sequence::new(200));
+        assert_eq!(segment_sizes(&buf), [100]);
+        assert_eq!(buf.retained_bytes(), 300);
+
+        buf.mark_as_acked(Sequence::new(250));
+        assert_eq!(buf.retained_bytes(), 200);
+        buf.mark_as_lost(Sequence::new(200), 1);
+        assert_eq!(segment_sizes(&buf), [100, 100]);
+        buf.mark_as_sent(Sequence::new(200));
+        buf.mark_as_sent(Sequence::new(300));
+        assert!(buf.done());
+        assert!(!buf.acked());
+
+        buf.mark_as_acked(Sequence::new(400));
+        assert!(buf.acked());
     }
 }
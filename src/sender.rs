@@ -1,172 +1,8509 @@
-use std::{collections::VecDeque, io};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io::{self, IoSlice},
+    net::SocketAddr,
+    num::NonZeroUsize,
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
 
 use async_async_io::write::{AsyncAsyncWrite, PollWrite};
-use bytes::Bytes;
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{stream::FuturesUnordered, Sink, StreamExt};
 use thiserror::Error;
 use tokio::{
     io::{AsyncWrite, AsyncWriteExt},
+    sync::{broadcast, mpsc, watch},
     task::JoinSet,
 };
 
 use crate::{
-    message::{Message, Sequence},
-    send_buf::SendStreamBuf,
+    compression::Compression,
+    control::{self, ControlStream, PendingControl},
+    failure::{DefaultFailurePolicy, FailurePolicy, Frame, FrameWriter, DEFAULT_MAX_WRITE_RETRIES},
+    message::{
+        DataSegment, EncodeOptions, HeaderContext, Hello, Message, SeqWidth, Sequence, Session,
+        SubflowScore, CAPABILITY_CHECKSUM, CAPABILITY_COMPACT_HEADERS, CAPABILITY_RTT_PROBES,
+        CAPABILITY_SEQUENCE_U32, DATA_SEGMENT_OVERHEAD, MAX_ABORT_REASON_SIZE,
+        MAX_CONTROL_PAYLOAD_SIZE, MAX_FEEDBACK_SCORES, MAX_OOB_PAYLOAD_SIZE, MAX_PAYLOAD_SIZE,
+    },
+    receiver::{advance, FeedbackFrame, ProbeFrame},
+    scheduler::{
+        Assignment, DecisionLog, Duplicate, RoundRobin, Scheduler, SegmentMeta, StreamMeta,
+    },
+    send_buf::{Progress, ProgressHandle, SendStreamBuf},
+    session::PeerAddr,
+    trace,
+    wire::{escape_passthrough, DEFAULT_MAX_PAYLOAD_SIZE, PASSTHROUGH_ESCAPE, PASSTHROUGH_SWITCH},
 };
 
+/// Weight of the newest sample in the smoothed goodput of a stream
+const GOODPUT_SMOOTHING: f64 = 0.5;
+
+/// Weight of the newest sample in the smoothed round-trip time of a stream, as in RFC 6298
+const RTT_SMOOTHING: f64 = 0.125;
+
+/// Weight of the newest feedback in the smoothed gaps of a stream
+const FEEDBACK_SMOOTHING: f64 = 0.25;
+
+/// The longest time `SendMode::Sticky` expects the unacknowledged bytes of a stream to take
+const MAX_QUEUE_ESTIMATE: Duration = Duration::from_secs(3600);
+
+/// Probes of a stream awaiting their pongs, beyond which the oldest is given up on
+const MAX_OUTSTANDING_PROBES: usize = 8;
+
+/// Consecutive rounds of `Sender::batch_send` that may fail without sending any segment
+const MAX_FAILED_ROUNDS: usize = 4;
+
+/// Payload bytes written per frame before checking whether the rest of the segment was stolen by an idle stream
+const CHUNK_SIZE: usize = 1 << 18;
+
+/// The budget of a stream under `Sender::set_pacing` before its first write, and the step it grows by
+const BUDGET_STEP: usize = 1 << 14;
+const INITIAL_BUDGET: usize = 4 * BUDGET_STEP;
+
+/// Streams that would be due within this fraction of the keepalive interval get their heartbeats in the same wakeup as the first one due
+const KEEPALIVE_COALESCING: u32 = 4;
+
+/// The most a heartbeat is brought forward, as a fraction of the keepalive interval, so that sessions started together do not stay in step
+const KEEPALIVE_JITTER: u32 = 8;
+
+/// Events kept for a subscriber that falls behind
+const EVENT_CAPACITY: usize = 64;
+
+/// The default of `Sender::set_loss_timeout`
+const LOSS_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The default of `Sender::set_send_chunk_size`
+const DEFAULT_SEND_CHUNK_SIZE: usize = 16 << 20;
+
+/// Pieces a chunk of `Sender::set_send_chunk_size` is pushed in, each released apart once the streams are done with it
+const CHUNK_PIECES: usize = 16;
+
+/// The default of `Sender::set_stall_multiplier`
+const DEFAULT_STALL_MULTIPLIER: f64 = 4.0;
+
+/// The shortest a segment may go unacknowledged before `Sender::set_stall_reassignment` writes it again
+const MIN_STALL_TIMEOUT: Duration = Duration::from_millis(10);
+
 /// You will have to explicitly call `Self::shutdown` before the drop
+///
+/// `P` picks whether the writes in flight, and so the sender, are `Send`: `Threaded` for `Send` streams and `Local` for the others.
 #[derive(Debug)]
-pub struct Sender<W> {
-    streams: VecDeque<W>,
+pub struct Sender<W, P: sealed::Threading = Threaded> {
+    streams: VecDeque<Subflow<W>>,
     next: Sequence,
+    evicted: Vec<StreamError>,
+    /// Statistics of the evicted streams
+    retired: Vec<StreamStats>,
+    next_stream_id: usize,
+    write_timeout: Option<Duration>,
+    /// How long each write through `AsyncWrite` or `AsyncAsyncWrite` may take
+    send_deadline: Option<Duration>,
+    /// Capacity of the write buffer of the streams added from now on
+    write_buffer: Option<NonZeroUsize>,
+    cork: Option<Cork>,
+    /// Data of the `AsyncWrite` path held back by the cork and since when
+    staged: BytesMut,
+    staged_since: Instant,
+    max_segment_size: Option<NonZeroUsize>,
+    min_segment_size: Option<NonZeroUsize>,
+    /// The most bytes of a single send held in its `SendStreamBuf` at once
+    send_chunk_size: NonZeroUsize,
+    keepalive: Option<Duration>,
+    keepalive_stats: KeepaliveStats,
+    /// How much earlier than due the next heartbeats go out
+    keepalive_jitter: Duration,
+    encode_options: EncodeOptions,
+    sequence_width: SeqWidth,
+    /// Announced with the ID of every stream in its handshake
+    session: Option<Session>,
+    retransmission: Option<Retransmission>,
+    send_window: Option<NonZeroUsize>,
+    /// Segments carried by evicted streams and not acknowledged yet
+    lost: Vec<Range<Sequence>>,
+    scheduler: Box<dyn Scheduler>,
+    decision_log: DecisionLog,
+    send_mode: SendMode,
+    /// The priority of the streams the segments go to
+    active_tier: Option<Priority>,
+    tier_changes: Vec<TierChange>,
+    /// Writes that own their streams until joined, kept across cancellations so that no stream is lost
+    ///
+    /// They are polled in place by the call that awaits them rather than spawned.
+    writes: Writes<W, P>,
+    events: broadcast::Sender<SubflowEvent>,
+    /// The sender itself while a poll of its `AsyncWrite` methods is pending, which leaves this one empty
+    lent: Option<Lent<W, P>>,
+    reconnect: Option<Reconnect<W>>,
+    /// Streams from `PoolHandle`s, kept open by `Self::added_tx`
+    added: mpsc::UnboundedReceiver<W>,
+    added_tx: mpsc::UnboundedSender<W>,
+    /// How long to wait for a stream once none is left
+    zero_stream_grace: Option<Duration>,
+    /// How long a segment written on a datagram stream may go unacknowledged
+    loss_timeout: Duration,
+    /// The sequence of the next out-of-band message
+    next_oob: u32,
+    /// Data queued by `Self::submit`, oldest first
+    submissions: VecDeque<Submission>,
+    /// How long a segment written on a reliable stream may go unacknowledged, if at all
+    retransmission_timeout: Option<Duration>,
+    /// Set while retransmitting, which keeps the datagram streams and those that timed out out of the rounds where possible
+    retransmitting: bool,
+    stall_reassignment: bool,
+    stall_multiplier: f64,
+    /// The end of the data written and flushed by the last successful flush
+    flushed: Sequence,
+    /// Set by `Self::close`, after which no new data is accepted
+    closed: bool,
+    close_timeout: Option<Duration>,
+    probing: Option<Probing>,
+    feedback: Option<Feedback>,
+    control: Option<SenderControl>,
+    failure_policy: Arc<dyn FailurePolicy>,
+    max_write_retries: u32,
+    /// Let `Self::send_segments` send data before `Self::next` again
+    allow_retransmit: bool,
+    /// How long a write should take with the budget of its stream
+    pacing: Option<Duration>,
+    passthrough: bool,
+    scratch: Scratch<W>,
 }
 
-impl<W> Sender<W>
+/// How the segments of a send are spread over the streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SendMode {
+    /// Split the data across the streams as the scheduler decides
+    #[default]
+    Stripe,
+    /// Write every segment on every stream and let the receiver drop the copies
+    ///
+    /// Trades bandwidth for the latency of the fastest stream.
+    Duplicate,
+    /// Write each send of fewer than `threshold` bytes whole on the least loaded stream, and stripe the larger ones
+    ///
+    /// A small message then waits for one path instead of the slowest of them, and the messages complete out of order across the paths.
+    /// The least loaded stream is the one expected to deliver first: its round-trip time plus its bytes written and not acknowledged at its goodput.
+    /// The round-trip time is that of `Sender::enable_rtt_probes`, or else the time the acknowledgements of `Sender::enable_retransmission` take, and streams without one go first.
+    /// A send larger than the maximum segment size still takes several segments, each on the least loaded stream of its round.
+    Sticky { threshold: usize },
+}
+
+impl SendMode {
+    /// The mode of a send of `bytes`
+    fn for_send(self, bytes: usize) -> Self {
+        match self {
+            Self::Sticky { threshold } if threshold <= bytes => Self::Stripe,
+            mode => mode,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Submission {
+    send_buf: SendStreamBuf,
+    mode: SendMode,
+    /// Whether its segments have been split for the streams, which waits for its first round
+    prepared: bool,
+}
+
+#[derive(Debug)]
+struct Probing {
+    pongs: mpsc::UnboundedReceiver<ProbeFrame>,
+    interval: Duration,
+    /// What the timestamps of the probes count the microseconds from
+    epoch: Instant,
+}
+
+#[derive(Debug)]
+struct Feedback {
+    frames: mpsc::UnboundedReceiver<FeedbackFrame>,
+    interval: Duration,
+}
+
+/// The control channel of `Sender::set_control_stream`
+#[derive(Debug)]
+struct SenderControl {
+    messages: mpsc::UnboundedSender<Message>,
+    acks: watch::Receiver<Sequence>,
+    frames: Option<mpsc::UnboundedReceiver<Bytes>>,
+    _tasks: JoinSet<()>,
+}
+
+#[derive(Debug)]
+struct Retransmission {
+    acks: watch::Receiver<Sequence>,
+    /// The largest cumulative acknowledgement passed to `Sender::handle_ack`
+    handled_ack: Sequence,
+    limit: NonZeroUsize,
+    /// Sent data kept until acknowledged, ordered by sequence
+    in_flight: VecDeque<SendStreamBuf>,
+}
+
+impl Retransmission {
+    fn ack(&self) -> Sequence {
+        (*self.acks.borrow()).max(self.handled_ack)
+    }
+}
+
+impl<W, P> Sender<W, P>
 where
-    W: AsyncWrite + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    P: sealed::BoxWrite<W>,
 {
-    pub fn new(streams: Vec<W>) -> Self {
-        Self {
-            streams: streams.into(),
-            next: Sequence::new(0),
+    fn with_streams(streams: Vec<W>, sequence: Sequence) -> Self {
+        let (added_tx, added) = mpsc::unbounded_channel();
+        let mut this = Self {
+            streams: VecDeque::new(),
+            next: sequence,
+            evicted: Vec::new(),
+            retired: Vec::new(),
+            next_stream_id: 0,
+            write_timeout: None,
+            send_deadline: None,
+            write_buffer: None,
+            cork: None,
+            staged: BytesMut::new(),
+            staged_since: Instant::now(),
+            max_segment_size: None,
+            min_segment_size: None,
+            send_chunk_size: NonZeroUsize::new(DEFAULT_SEND_CHUNK_SIZE).unwrap(),
+            keepalive: None,
+            keepalive_stats: KeepaliveStats::new(),
+            keepalive_jitter: Duration::ZERO,
+            encode_options: EncodeOptions::default(),
+            sequence_width: SeqWidth::default(),
+            session: None,
+            retransmission: None,
+            send_window: None,
+            lost: Vec::new(),
+            scheduler: Box::new(RoundRobin),
+            decision_log: DecisionLog::default(),
+            send_mode: SendMode::default(),
+            active_tier: None,
+            tier_changes: Vec::new(),
+            writes: Writes::new(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            lent: None,
+            reconnect: None,
+            added,
+            added_tx,
+            zero_stream_grace: None,
+            loss_timeout: LOSS_TIMEOUT,
+            retransmission_timeout: None,
+            retransmitting: false,
+            stall_reassignment: false,
+            stall_multiplier: DEFAULT_STALL_MULTIPLIER,
+            flushed: sequence,
+            closed: false,
+            close_timeout: None,
+            probing: None,
+            feedback: None,
+            control: None,
+            failure_policy: Arc::new(DefaultFailurePolicy),
+            max_write_retries: DEFAULT_MAX_WRITE_RETRIES,
+            allow_retransmit: false,
+            pacing: None,
+            passthrough: false,
+            scratch: Scratch::default(),
+            next_oob: 0,
+            submissions: VecDeque::new(),
+        };
+        this.add_streams(streams);
+        this.tier_changes.clear();
+        this
+    }
+
+    /// Add a stream to the pool
+    ///
+    /// The stream takes its share of the data from the next `Self::batch_send_all` on.
+    pub fn add_stream(&mut self, stream: W) -> StreamId {
+        self.add_subflow(None, Priority::default(), stream)
+    }
+
+    pub fn add_stream_with_label(&mut self, label: String, stream: W) -> StreamId {
+        self.add_subflow(Some(label.into()), Priority::default(), stream)
+    }
+
+    /// Add a stream that carries data only while no live stream has a higher priority
+    ///
+    /// A stream with a higher priority than the active tier takes over the data from the next round on.
+    pub fn add_stream_with_priority(&mut self, priority: Priority, stream: W) -> StreamId {
+        self.add_subflow(None, priority, stream)
+    }
+
+    fn add_subflow(&mut self, label: Option<Arc<str>>, priority: Priority, stream: W) -> StreamId {
+        let id = StreamId::new(self.next_stream_id);
+        self.next_stream_id += 1;
+        let mut stats = StreamStats::new(id);
+        stats.priority = priority;
+        let write_buffer = self.write_buffer.map(|capacity| capacity.get());
+        let stream = FrameWriter::new(
+            stream,
+            write_buffer.unwrap_or(0),
+            self.failure_policy.clone(),
+            self.max_write_retries,
+        );
+        self.streams.push_back(Subflow {
+            id,
+            label,
+            stream,
+            write_buffer,
+            stats,
+            last_write: Instant::now(),
+            greeted: false,
+            header: HeaderContext::with_sequence_width(self.sequence_width),
+            unacked: Vec::new(),
+            ack_rtt: None,
+            timed_out: false,
+            stalled: false,
+            mtu: None,
+            max_frame_size: None,
+            advertised_max_frame_size: None,
+            fin: None,
+            last_probe: None,
+            probes: VecDeque::new(),
+            passthrough: None,
+        });
+        self.update_tier();
+        self.emit(|| SubflowEvent::Added { id });
+        id
+    }
+
+    /// Add a stream that may lose frames, e.g., a `DatagramSubflow`
+    ///
+    /// Every frame written on it fits in `mtu` bytes.
+    /// With retransmission enabled, its segments left unacknowledged for `Self::set_loss_timeout` are written again on the reliable streams.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mtu` cannot fit a data segment with a byte of payload.
+    pub fn add_datagram_stream(&mut self, stream: W, mtu: NonZeroUsize) -> StreamId {
+        assert!(
+            mtu.get() > DATA_SEGMENT_OVERHEAD,
+            "MTU of {mtu} bytes cannot fit a data segment"
+        );
+        let id = self.add_subflow(None, Priority::default(), stream);
+        self.streams.back_mut().unwrap().mtu = Some(mtu.get());
+        id
+    }
+
+    /// Write frames of at most `size` bytes on the stream of `id`, e.g., as the peer advertised with `Receiver::peer_max_frame_size`
+    ///
+    /// The segments it is assigned are split into frames as they are written, so a stream with a small limit carries as much data in more frames.
+    /// Returns `false` without effect unless `Self::stream_state` of `id` is `StreamState::Idle`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` cannot fit a data segment with a byte of payload.
+    pub fn set_max_frame_size(&mut self, id: StreamId, size: Option<NonZeroUsize>) -> bool {
+        if let Some(size) = size {
+            assert!(
+                size.get() > DATA_SEGMENT_OVERHEAD,
+                "frames of {size} bytes cannot fit a data segment"
+            );
         }
+        let Some(subflow) = self.streams.iter_mut().find(|subflow| subflow.id == id) else {
+            return false;
+        };
+        subflow.max_frame_size = size.map(NonZeroUsize::get);
+        true
     }
 
-    pub async fn batch_send(&mut self, send_buf: &mut SendStreamBuf) -> Result<(), SendError> {
-        if self.streams.is_empty() {
-            return Err(SendError::NoStreamLeft);
+    /// Advertise in the handshake of the stream of `id` that frames of at most `size` bytes are accepted on its opposite direction
+    ///
+    /// Pair it with `ReceiverBuilder::max_frame_size` on the receiver of the opposite direction, which rejects the larger ones.
+    /// Returns `false` without effect unless `Self::stream_state` of `id` is `StreamState::Idle` and its handshake has not been written yet.
+    pub fn advertise_max_frame_size(&mut self, id: StreamId, size: u32) -> bool {
+        let subflow = self.streams.iter_mut().find(|subflow| subflow.id == id);
+        let Some(subflow) = subflow.filter(|subflow| !subflow.greeted) else {
+            return false;
+        };
+        subflow.advertised_max_frame_size = Some(size);
+        true
+    }
+
+    /// Give up on the segments written on datagram streams once they go unacknowledged for `timeout`, 200 ms by default
+    pub fn set_loss_timeout(&mut self, timeout: Duration) {
+        self.loss_timeout = timeout;
+    }
+
+    /// Retransmit the segment the acknowledgements wait for once it has been unacknowledged for `timeout` on a reliable stream, preferably on the other streams
+    ///
+    /// Off by default, when the segments of reliable streams are only retransmitted once their stream is evicted.
+    /// Lost segments are retransmitted by the sends and `Self::close`, and while idle by `Self::retransmit_expired`, which should be called around `Self::next_retransmission`.
+    pub fn set_retransmission_timeout(&mut self, timeout: Option<Duration>) {
+        self.retransmission_timeout = timeout;
+    }
+
+    /// Write the segment the acknowledgements wait for again on another stream once it has gone unacknowledged for `Self::set_stall_multiplier` round-trip times of its stream
+    ///
+    /// Off by default, as it trades bandwidth for latency, and only with retransmission enabled.
+    /// The copy goes to a stream of the active tier that neither stalled nor carries the segment, and the receiver drops whichever copy comes second.
+    /// The round-trip times are those measured by `Self::enable_rtt_probes`, a stream without an estimate taking the lowest of its tier.
+    /// A stalled stream gets no new segments while its tier has streams that did not stall, until one of its segments or probes is answered.
+    /// The stalls show in `StreamStats::stalls` and as `SubflowEvent::Stalled`.
+    pub fn set_stall_reassignment(&mut self, enabled: bool) {
+        self.stall_reassignment = enabled;
+    }
+
+    /// How many round-trip times a segment may go unacknowledged under `Self::set_stall_reassignment`, 4 by default
+    ///
+    /// The wait is at least 10 ms however short the round-trip time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `multiplier` is not positive.
+    pub fn set_stall_multiplier(&mut self, multiplier: f64) {
+        assert!(multiplier > 0.0, "stall multiplier must be positive");
+        self.stall_multiplier = multiplier;
+    }
+
+    /// When the oldest unacknowledged segment times out, see `Self::set_retransmission_timeout` and `Self::set_loss_timeout`
+    pub fn next_retransmission(&self) -> Option<Instant> {
+        self.next_loss()
+    }
+
+    /// Retransmit the segments that timed out or were carried by evicted streams
+    ///
+    /// Happens on every send too.
+    pub async fn retransmit_expired(&mut self) -> Result<(), SendError> {
+        self.retransmit_lost().await
+    }
+
+    /// Release the data acknowledged by the cumulative acknowledgement `cumulative` and the selective acknowledgements `sacks`
+    ///
+    /// Feeds acknowledgements on top of those of `Self::enable_retransmission`, e.g., from an acknowledgement scheme of its own.
+    /// Only the segments written whole within a range of `sacks` are released by it.
+    pub fn handle_ack(&mut self, cumulative: Sequence, sacks: &[Range<Sequence>]) {
+        let Some(retransmission) = &mut self.retransmission else {
+            return;
+        };
+        retransmission.handled_ack = retransmission.handled_ack.max(cumulative);
+        for send_buf in &mut retransmission.in_flight {
+            for sack in sacks {
+                send_buf.mark_as_selectively_acked(sack.clone());
+            }
+        }
+        let sacked = |sequence: &Range<Sequence>| {
+            sacks
+                .iter()
+                .any(|sack| sack.start <= sequence.start && sequence.end <= sack.end)
+        };
+        for subflow in &mut self.streams {
+            if subflow.release(sacked) {
+                subflow.timed_out = false;
+            }
         }
+        self.process_acks();
+    }
 
-        let mut write_tasks: JoinSet<io::Result<_>> = JoinSet::new();
-        let segments = send_buf.iter_unsent_segments();
+    /// Wait up to `grace` for a stream once none is left before failing with `SendError::NoStreamLeft`, e.g., while a roaming device reconnects
+    ///
+    /// A send that runs out of streams parks the rest of its data in the meantime and carries on as if nothing happened once a re-dial or a `PoolHandle` supplies a stream.
+    /// Only the send that waits holds the parked data, so it stays within `Self::set_send_window` like the data in flight.
+    pub fn set_zero_stream_grace(&mut self, grace: Option<Duration>) {
+        self.zero_stream_grace = grace;
+    }
 
-        for segment in segments {
-            let Some(mut stream) = self.streams.pop_front() else {
-                break;
-            };
+    /// A handle adding streams to the pool from another task, e.g., while a send waits out `Self::set_zero_stream_grace`
+    pub fn pool_handle(&self) -> PoolHandle<W> {
+        PoolHandle {
+            added: self.added_tx.clone(),
+        }
+    }
+
+    /// Give up on `Self::close` after `timeout`
+    pub fn set_close_timeout(&mut self, timeout: Option<Duration>) {
+        self.close_timeout = timeout;
+    }
 
-            write_tasks.spawn(async move {
-                let start_sequence = segment.start_sequence();
+    pub fn add_streams(&mut self, streams: impl IntoIterator<Item = W>) -> Vec<StreamId> {
+        streams
+            .into_iter()
+            .map(|stream| self.add_stream(stream))
+            .collect()
+    }
 
-                let message = Message::DataSegment(segment);
-                message.encode(&mut stream).await?;
+    /// Evict a stream if writing a single message to it takes longer than `timeout`
+    ///
+    /// The segment it was writing is reassigned to the other streams.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
 
-                Ok((Some(start_sequence), stream))
-            });
+    /// Buffer the writes to each stream added from now on in `capacity` bytes, e.g., to save syscalls on a socket
+    ///
+    /// The frames written to such a stream fit in the buffer, so each one reaches the stream in a single write when it is flushed at its end.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` cannot fit a data segment with a byte of payload.
+    pub fn set_write_buffer(&mut self, capacity: Option<NonZeroUsize>) {
+        if let Some(capacity) = capacity {
+            assert!(
+                capacity.get() > DATA_SEGMENT_OVERHEAD,
+                "write buffer of {capacity} bytes cannot fit a data segment"
+            );
         }
+        self.write_buffer = capacity;
+    }
 
-        // Send pings for the remaining streams
-        while let Some(mut stream) = self.streams.pop_front() {
-            write_tasks.spawn(async move {
-                Message::Ping.encode(&mut stream).await?;
+    /// Hold back the data written through `AsyncWrite` or `AsyncAsyncWrite` to send it with later writes as one send, or every write on its own if `None`, the default
+    ///
+    /// The data held back is sent once `Cork::size` bytes pile up, with the first write after `Cork::delay` passes, or on a flush or shutdown.
+    /// It also goes ahead of the data of `Self::batch_send_all`.
+    /// Dropping the sender before a flush loses it.
+    pub fn set_cork(&mut self, cork: Option<Cork>) {
+        self.cork = cork;
+    }
 
-                Ok((None, stream))
-            });
+    /// Fail each write through `AsyncWrite` or `AsyncAsyncWrite` with `SendError::DeadlineExceeded` if it takes longer than `deadline`
+    ///
+    /// See `Self::batch_send_all_with_deadline`.
+    pub fn set_send_deadline(&mut self, deadline: Option<Duration>) {
+        self.send_deadline = deadline;
+    }
+
+    /// Decide which stream carries which segment with `scheduler` instead of `RoundRobin`
+    pub fn set_scheduler(&mut self, scheduler: impl Scheduler + 'static) {
+        self.scheduler = Box::new(scheduler);
+    }
+
+    /// Keep the latest `capacity` decisions of the scheduler in `Self::decision_log`, in place of those kept so far
+    ///
+    /// Off with a `capacity` of 0, the default, which takes no snapshot of the streams and costs a branch per round.
+    pub fn set_decision_log(&mut self, capacity: usize) {
+        self.decision_log = DecisionLog::new(capacity);
+    }
+
+    /// The latest decisions of the scheduler, see `Self::set_decision_log`
+    pub fn decision_log(&self) -> &DecisionLog {
+        &self.decision_log
+    }
+
+    /// Tell the errors worth another write on the same stream from those that evict it, for the streams added from now on
+    ///
+    /// Defaults to `DefaultFailurePolicy`.
+    pub fn set_failure_policy(&mut self, policy: impl FailurePolicy + 'static) {
+        self.failure_policy = Arc::new(policy);
+    }
+
+    /// How many times in a row a write retried by the failure policy may fail before its stream is evicted, for the streams added from now on
+    pub fn set_max_write_retries(&mut self, retries: u32) {
+        self.max_write_retries = retries;
+    }
+
+    /// Cap the bytes each stream takes per write to a budget that lets the write take about `target`
+    ///
+    /// A budget grows by a step after a write that used it up within `target` and halves after a write that took longer or failed, so it follows how fast its stream drains.
+    /// A stream stops at its budget and leaves the rest of its segment to the streams with room left or to the next round, rather than filling up the buffers of a slow path.
+    /// The budgets show in `StreamStats::budget` and `StreamMeta::budget`.
+    pub fn set_pacing(&mut self, target: Option<Duration>) {
+        self.pacing = target;
+    }
+
+    /// Let `Self::send_segments` take segments of data sent before
+    pub fn set_allow_retransmit(&mut self, allow: bool) {
+        self.allow_retransmit = allow;
+    }
+
+    /// The mode of the sends that do not pick one
+    pub fn set_send_mode(&mut self, mode: SendMode) {
+        self.send_mode = mode;
+    }
+
+    /// Split the data of `Self::batch_send_all` into segments of at most `size` bytes, `DEFAULT_MAX_PAYLOAD_SIZE` if `None`
+    ///
+    /// Each stream then takes several smaller segments in turn, which bounds the head-of-line blocking on the receiver.
+    /// Segments larger than the default are rejected by a receiver unless raised by `ReceiverBuilder::max_payload_size`.
+    pub fn set_max_segment_size(&mut self, size: Option<NonZeroUsize>) {
+        self.max_segment_size = size;
+    }
+
+    /// Stripe the data of `Self::batch_send_all` into segments of at least `size` bytes, 8 KiB by default
+    ///
+    /// Small writes then go to fewer streams rather than each paying the framing of a tiny segment.
+    /// Only the last segment of a write may be smaller, and the maximum segment size wins over it.
+    pub fn set_min_segment_size(&mut self, size: Option<NonZeroUsize>) {
+        self.min_segment_size = size;
+    }
+
+    /// Hold at most `size` bytes of a send at once, 16 MiB by default
+    ///
+    /// The send still takes up one contiguous range of sequences and completes once, and its progress counts the whole of it from the start.
+    /// The held data is topped up as the streams write it, or as it is acknowledged with `Self::enable_retransmission` and within its limit, and a write through `AsyncWrite` or `AsyncAsyncWrite` takes in at most `size` bytes, so that no more than that is copied at once.
+    pub fn set_send_chunk_size(&mut self, size: NonZeroUsize) {
+        self.send_chunk_size = size;
+    }
+
+    /// Append a checksum of the payload to every data segment
+    ///
+    /// Receivers verify checksums whenever they are present, so this can be enabled on the sender alone.
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.encode_options.checksum = checksum;
+    }
+
+    /// Encode the headers of data segments as varints relative to the previous one on their stream
+    ///
+    /// This cuts the framing of a small segment by about half, and receivers of this crate decode both encodings.
+    /// Datagram streams keep the full headers, since a lost datagram would throw off the segments after it.
+    pub fn set_compact_headers(&mut self, compact: bool) {
+        self.encode_options.compact = compact;
+    }
+
+    /// Compress the payloads of data segments of `compression::MIN_COMPRESSED_SIZE` bytes or more that shrink
+    ///
+    /// The algorithm is advertised in the handshake, and a receiver built without it rejects the subflows.
+    /// Compressed data segments keep full headers. See `StreamStats::compression_ratio`.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.encode_options.compression = compression;
+    }
+
+    /// Write the data of a lone stream right after its handshake as the bytes themselves, with no frame around them
+    ///
+    /// Only `crate::wire::PASSTHROUGH_ESCAPE` bytes are escaped, by doubling them, so a payload without them goes on the wire as a plain copy would.
+    /// It applies to the handshake of the first stream as long as no other has been added and nothing is on that needs frames: checksums, compact headers, compression, 32-bit sequences, a session, keepalives, RTT probes or feedback.
+    /// The stream switches to frames for good, in band, before it carries anything but the next bytes of the passthrough data, e.g., once `Self::add_stream` brings a second stream, for a fin or for a retransmission.
+    pub fn set_passthrough(&mut self, passthrough: bool) {
+        self.passthrough = passthrough;
+    }
+
+    /// Put sequences on the wire `width` wide, which the receiver must expect too
+    ///
+    /// The width is announced in the handshake, so it only applies to the streams whose handshake has not been written yet.
+    pub fn set_sequence_width(&mut self, width: SeqWidth) {
+        self.sequence_width = width;
+        for subflow in self.streams.iter_mut().filter(|subflow| !subflow.greeted) {
+            subflow.header = HeaderContext::with_sequence_width(width);
         }
+    }
 
-        let mut io_errors = vec![];
-        while let Some(task) = write_tasks.join_next().await {
-            let res = task.unwrap();
-            match res {
-                Ok((Some(sequence), stream)) => {
-                    self.streams.push_back(stream);
-                    send_buf.mark_as_sent(sequence);
-                }
-                Ok((None, stream)) => {
-                    self.streams.push_back(stream);
-                }
-                Err(e) => {
-                    io_errors.push(e);
-                }
+    /// Announce `session` and the `StreamId` of every stream in its handshake, so that the receiver rejects the streams of another session and those repeating an ID
+    ///
+    /// The receiver checks them with `crate::session::SessionSetupError`, see `ReceiverBuilder::session`. It only applies to the streams whose handshake has not been written yet.
+    pub fn set_session(&mut self, session: Option<Session>) {
+        self.session = session;
+    }
+
+    /// Send a heartbeat on every stream that has been idle for `interval`
+    ///
+    /// The heartbeats are sent by `Self::heartbeat`, which should be called around `Self::next_heartbeat`; `SenderHandle` does it on its task.
+    /// Streams that carried anything within the interval get none, not even the pings a send writes on the streams it gives no segment, and those nearly due get theirs along with the first one due, so all the streams of a sender share one wakeup per interval at most.
+    /// Every wakeup comes up to an eighth of the interval early at random, which keeps many sessions from waking up in step.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.keepalive = interval;
+        self.keepalive_stats = KeepaliveStats::new();
+        self.reroll_keepalive_jitter();
+    }
+
+    fn reroll_keepalive_jitter(&mut self) {
+        let max = self.keepalive.unwrap_or_default() / KEEPALIVE_JITTER;
+        self.keepalive_jitter = max.mul_f64(rand::random());
+    }
+
+    /// Keep sent data until the receiver acknowledges it and retransmit the data of evicted streams
+    ///
+    /// `acks` carries the cumulative acknowledgements, e.g., from `Receiver::acks` or `Receiver::peer_acks`.
+    /// `Self::batch_send_all` waits for acknowledgements while more than `limit` bytes are kept.
+    pub fn enable_retransmission(&mut self, acks: watch::Receiver<Sequence>, limit: NonZeroUsize) {
+        self.retransmission = Some(Retransmission {
+            acks,
+            handled_ack: Sequence::new(0),
+            limit,
+            in_flight: VecDeque::new(),
+        });
+    }
+
+    /// Measure the round-trip time of every stream by probes written at most once per `interval` on each
+    ///
+    /// The peer reflects the probes, e.g., by passing `Receiver::probes` to `Self::reflect_probe` of the `Sender` of the opposite direction, and `pongs` carries the reflections back, e.g., from `Receiver::pongs`.
+    /// The probes take no sequence. Due ones are written before each send and by `Self::probe_rtt`.
+    /// The estimates show in `StreamStats::rtt` and `StreamMeta::rtt`.
+    pub fn enable_rtt_probes(
+        &mut self,
+        pongs: mpsc::UnboundedReceiver<ProbeFrame>,
+        interval: Duration,
+    ) {
+        self.probing = Some(Probing {
+            pongs,
+            interval,
+            epoch: Instant::now(),
+        });
+    }
+
+    /// Ask the peer for the scores of every stream once per `interval`, announced in the handshakes of the streams
+    ///
+    /// The peer writes them back, e.g., by passing `Receiver::feedback` to `Self::send_feedback` of the `Sender` of the opposite direction, and `frames` carries them here, e.g., from `Receiver::peer_feedback`.
+    /// The streams that have written their handshake already do not announce it. The scores are taken in before each send.
+    /// They show in `StreamStats::feedback` and `StreamMeta::feedback` for a scheduler such as `crate::scheduler::FewestGaps`.
+    pub fn enable_feedback(
+        &mut self,
+        frames: mpsc::UnboundedReceiver<FeedbackFrame>,
+        interval: Duration,
+    ) {
+        self.feedback = Some(Feedback { frames, interval });
+    }
+
+    /// Bound the bytes buffered by the `AsyncWrite` path
+    ///
+    /// Each write then accepts at most `window` bytes minus those held for retransmission and waits for acknowledgements while the window is full.
+    /// `Self::batch_send_all` still takes its data as a whole.
+    pub fn set_send_window(&mut self, window: Option<NonZeroUsize>) {
+        self.send_window = window;
+    }
+
+    /// Payload bytes held in memory for retransmission
+    ///
+    /// The send window and the retransmission limit bound these, which include the acknowledged parts of partly acknowledged writes.
+    pub fn resident_bytes(&self) -> usize {
+        let Some(retransmission) = &self.retransmission else {
+            return 0;
+        };
+        retransmission
+            .in_flight
+            .iter()
+            .map(|send_buf| send_buf.resident_bytes())
+            .sum()
+    }
+
+    /// Bytes kept for retransmission
+    pub fn retained_bytes(&self) -> usize {
+        let Some(retransmission) = &self.retransmission else {
+            return 0;
+        };
+        retransmission
+            .in_flight
+            .iter()
+            .map(|send_buf| send_buf.retained_bytes())
+            .sum()
+    }
+
+    /// Write a control frame carrying `payload` on every stream
+    ///
+    /// Control frames take no sequence and reach the peer through `Receiver::control_frames` rather than the byte stream.
+    /// Streams that fail to write it are evicted.
+    pub async fn broadcast_control(&mut self, payload: Bytes) -> Result<(), SendError> {
+        if payload.len() > MAX_CONTROL_PAYLOAD_SIZE {
+            return Err(SendError::ControlTooLarge(payload.len()));
+        }
+        self.for_each_stream(true, Job::Control(payload)).await
+    }
+
+    /// Exchange control frames with the `Receiver` of the peer over `stream`, whose other end goes to its `ReceiverBuilder::control_stream`
+    ///
+    /// The acknowledgements read from it show in `Self::control_acks` and the control frames in `Self::control_frames`, while `Self::send_control` writes on it.
+    /// The data keeps going over the other streams. It spawns the tasks of the channel, so it must be called within a Tokio runtime.
+    pub fn set_control_stream(&mut self, stream: impl ControlStream) {
+        let (messages, outgoing) = mpsc::unbounded_channel();
+        let (acks_tx, acks) = watch::channel(self.next);
+        let (frames_tx, frames) = mpsc::unbounded_channel();
+        let mut tasks = JoinSet::new();
+        control::spawn(&mut tasks, stream, outgoing, move |message| match message {
+            Message::Ack(ack) => {
+                acks_tx.send_if_modified(|acked| advance(acked, ack));
+            }
+            Message::Control(payload) => {
+                let _ = frames_tx.send(payload);
             }
+            _ => (),
+        });
+        self.control = Some(SenderControl {
+            messages,
+            acks,
+            frames: Some(frames),
+            _tasks: tasks,
+        });
+    }
+
+    /// The cumulative acknowledgements read from the control stream, to be passed to `Self::enable_retransmission`
+    ///
+    /// `None` without a control stream, as there is then no way for the receiver to acknowledge anything.
+    pub fn control_acks(&self) -> Option<watch::Receiver<Sequence>> {
+        self.control.as_ref().map(|control| control.acks.clone())
+    }
+
+    /// The acknowledgements retransmission is driven by, if enabled
+    pub(crate) fn retransmission_acks(&self) -> Option<watch::Receiver<Sequence>> {
+        Some(self.retransmission.as_ref()?.acks.clone())
+    }
+
+    /// The cumulative acknowledgement, if retransmission is enabled
+    pub(crate) fn acked(&self) -> Option<Sequence> {
+        Some(self.retransmission.as_ref()?.ack())
+    }
+
+    /// The payloads of the control frames read from the control stream, for the first call only
+    pub fn control_frames(&mut self) -> Option<mpsc::UnboundedReceiver<Bytes>> {
+        self.control.as_mut()?.frames.take()
+    }
+
+    /// Write a control frame carrying `payload` on the control stream, or on every stream like `Self::broadcast_control` if there is none or it has gone down
+    pub async fn send_control(&mut self, payload: Bytes) -> Result<(), SendError> {
+        if payload.len() > MAX_CONTROL_PAYLOAD_SIZE {
+            return Err(SendError::ControlTooLarge(payload.len()));
         }
-        if !io_errors.is_empty() {
-            return Err(SendError::Io(io_errors));
+        let payload = match &self.control {
+            Some(control) => match control.messages.send(Message::Control(payload)) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::error::SendError(message)) => {
+                    let Message::Control(payload) = message else {
+                        unreachable!()
+                    };
+                    payload
+                }
+            },
+            None => payload,
+        };
+        self.broadcast_control(payload).await
+    }
+
+    /// Write an out-of-band message carrying `payload` on the least loaded stream, or on every stream in `SendMode::Duplicate`
+    ///
+    /// Out-of-band messages take no sequence of the byte stream and reach the peer through `Receiver::oob_messages` as soon as a stream carries them, however long the byte stream is blocked.
+    /// The least loaded stream is the one with the fewest bytes written and not acknowledged.
+    /// A stream that fails to write the message is evicted and the message goes to the next one.
+    pub async fn send_oob(&mut self, payload: Bytes) -> Result<(), SendError> {
+        if payload.len() > MAX_OOB_PAYLOAD_SIZE {
+            return Err(SendError::OobTooLarge(payload.len()));
         }
-        Ok(())
+        let job = Job::Oob(self.next_oob, payload);
+        self.next_oob = self.next_oob.wrapping_add(1);
+        if self.send_mode == SendMode::Duplicate {
+            return self.for_each_stream(true, job).await;
+        }
+        self.write_least_loaded(job).await
     }
 
-    pub async fn batch_send_all(&mut self, data: Bytes) -> Result<(), NoStreamLeft> {
-        let data_len = data.len();
-        let mut send_buf = SendStreamBuf::new(data, self.next);
-        send_buf.split_first_unsent_segment(self.streams.len());
+    /// Write the scores of a `Receiver::feedback` report in a feedback frame on the least loaded stream, like `Self::send_oob`
+    ///
+    /// The scores are those of the streams of the peer, which are the opposite directions of the streams of this sender when both go over the same subflows.
+    /// Scores past `MAX_FEEDBACK_SCORES` are left out.
+    pub async fn send_feedback(&mut self, mut scores: Vec<SubflowScore>) -> Result<(), SendError> {
+        scores.truncate(MAX_FEEDBACK_SCORES);
+        self.write_least_loaded(Job::Feedback(scores)).await
+    }
 
+    /// Write `job` on the stream with the fewest bytes written and not acknowledged, evicting those that fail until one succeeds
+    async fn write_least_loaded(&mut self, job: Job) -> Result<(), SendError> {
+        self.reclaim().await;
+        let mut errors = vec![];
         loop {
-            let res = self.batch_send(&mut send_buf).await;
+            let least_loaded =
+                (0..self.streams.len()).min_by_key(|&i| self.streams[i].unacked_bytes());
+            let Some(index) = least_loaded else {
+                if errors.is_empty() {
+                    return Err(SendError::NoStreamLeft {
+                        streams: self.next_stream_id,
+                        errors: std::mem::take(&mut self.evicted),
+                    });
+                }
+                return Err(SendError::Io(errors));
+            };
+            let subflow = self.streams.remove(index).unwrap();
+            self.writes.push(subflow, job.clone(), self.write_options());
+            let write = self.writes.next().await.unwrap();
+            let res = self.settle(write, true);
+            self.update_tier();
             match res {
-                Ok(()) => (),
-                Err(SendError::NoStreamLeft) => return Err(NoStreamLeft),
-                _ => continue,
-            }
-            if send_buf.done() {
-                self.next = Sequence::new(self.next.inner() + data_len as u64);
-                return Ok(());
+                Ok(_) => {
+                    self.evicted.extend(errors);
+                    return Ok(());
+                }
+                Err(error) => errors.push(error),
             }
         }
     }
 
-    pub fn into_async_write(self) -> PollWrite<Self> {
-        PollWrite::new(self)
+    /// Acknowledge the opposite byte stream up to `ack` on every stream
+    pub async fn send_ack(&mut self, ack: Sequence) -> Result<(), SendError> {
+        self.for_each_stream(true, Job::Ack(ack)).await
+    }
+
+    /// Write a probe on every stream that is due
+    ///
+    /// Streams that fail to carry the probe are evicted.
+    pub async fn probe_rtt(&mut self) -> Result<(), SendError> {
+        self.process_pongs();
+        let Some(probing) = &self.probing else {
+            return Ok(());
+        };
+        let job = Job::Probe {
+            interval: probing.interval,
+            epoch: probing.epoch,
+        };
+        self.for_each_stream(true, job).await
+    }
+
+    /// When the next stream becomes due for a probe
+    pub fn next_probe(&self) -> Option<Instant> {
+        let probing = self.probing.as_ref()?;
+        let now = Instant::now();
+        self.streams
+            .iter()
+            .map(|s| s.last_probe.map_or(now, |probe| probe + probing.interval))
+            .min()
+    }
+
+    /// Answer a probe from the peer with a pong on the stream of the same index, or on every stream if there is no such one
+    ///
+    /// Streams that fail to carry the pong are evicted.
+    pub async fn reflect_probe(&mut self, probe: ProbeFrame) -> Result<(), SendError> {
+        let job = Job::Pong(probe.timestamp());
+        let id = StreamId::new(probe.index());
+        self.reclaim().await;
+        let Some(index) = self.streams.iter().position(|s| s.id == id) else {
+            return self.for_each_stream(true, job).await;
+        };
+        let subflow = self.streams.remove(index).unwrap();
+        self.writes.push(subflow, job, self.write_options());
+        let write = self.writes.next().await.unwrap();
+        let res = self.settle(write, true);
+        self.update_tier();
+        match res {
+            Ok(_) => Ok(()),
+            Err(error) => Err(SendError::Io(vec![error])),
+        }
     }
 
-    pub async fn shutdown(&mut self) -> io::Result<()> {
-        let mut last_io_error = None;
-        for stream in &mut self.streams {
-            if let Err(e) = shutdown_stream(stream).await {
-                last_io_error = Some(e);
+    /// Take the round-trip time samples of the pongs received
+    fn process_pongs(&mut self) {
+        let Some(probing) = &mut self.probing else {
+            return;
+        };
+        while let Ok(pong) = probing.pongs.try_recv() {
+            let Some(sample) = probing
+                .epoch
+                .elapsed()
+                .checked_sub(Duration::from_micros(pong.timestamp()))
+            else {
+                continue;
+            };
+            for subflow in &mut self.streams {
+                let Some(index) = subflow.probes.iter().position(|&p| p == pong.timestamp()) else {
+                    continue;
+                };
+                // The older probes are lost or their pongs overtaken
+                subflow.probes.drain(..=index);
+                subflow.stats.record_rtt(sample);
+                subflow.stalled = false;
             }
         }
-        match last_io_error {
-            Some(e) => Err(e),
-            None => Ok(()),
+    }
+
+    fn process_feedback(&mut self) {
+        let Some(feedback) = &mut self.feedback else {
+            return;
+        };
+        while let Ok(frame) = feedback.frames.try_recv() {
+            for score in frame.scores() {
+                let id = StreamId::new(score.index() as usize);
+                if let Some(subflow) = self.streams.iter_mut().find(|s| s.id == id) {
+                    subflow.stats.record_feedback(*score);
+                }
+            }
         }
     }
-}
 
-impl<W> AsyncAsyncWrite for Sender<W>
-where
-    W: AsyncWrite + Unpin + Send + 'static,
-{
-    async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        {
-            // SAFETY: `data` will be dropped outside of this scope
-            let data = Bytes::from_static(unsafe { std::mem::transmute(buf) });
-            self.batch_send_all(data)
-                .await
-                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+    /// When the next stream becomes due for a heartbeat, less the jitter of `Self::set_keepalive`
+    pub fn next_heartbeat(&self) -> Option<Instant> {
+        let interval = self.keepalive?;
+        let due = self.streams.iter().map(|s| s.last_write + interval).min()?;
+        Some(due - self.keepalive_jitter)
+    }
+
+    /// Send a heartbeat on every stream that is due or nearly so
+    ///
+    /// Streams that fail to carry the heartbeat are evicted.
+    pub async fn heartbeat(&mut self) -> Result<(), SendError> {
+        let Some(interval) = self.keepalive else {
+            return Ok(());
+        };
+        self.keepalive_stats.wakeups += 1;
+        self.reroll_keepalive_jitter();
+        self.for_each_stream(true, Job::Heartbeat(keepalive_idle(interval)))
+            .await
+    }
+
+    /// How often `Self::heartbeat` has run since `Self::set_keepalive`, if enabled
+    pub fn keepalive_stats(&self) -> Option<KeepaliveStats> {
+        self.keepalive?;
+        Some(self.keepalive_stats)
+    }
+
+    /// The sequence of the next byte to send
+    ///
+    /// Persist it to resume the byte stream with `Self::with_initial_sequence`.
+    pub fn next_sequence(&self) -> Sequence {
+        self.next
+    }
+
+    /// The number of streams that have not been evicted
+    ///
+    /// Streams still writing on behalf of a cancelled call count as live until the next call joins them.
+    pub fn live_streams(&self) -> usize {
+        self.streams.len() + self.writes.len()
+    }
+
+    /// Statistics of every stream ever added, ordered by ID
+    pub fn stats(&self) -> Vec<StreamStats> {
+        let mut stats: Vec<StreamStats> = self
+            .streams
+            .iter()
+            .map(|s| s.stats)
+            .chain(self.retired.iter().copied())
+            .collect();
+        stats.sort_by_key(|s| s.id);
+        stats
+    }
+
+    /// The priority of the streams that currently carry the data
+    pub fn active_tier(&self) -> Option<Priority> {
+        self.active_tier
+    }
+
+    /// Take the changes of the active tier since the last call
+    pub fn take_tier_changes(&mut self) -> Vec<TierChange> {
+        std::mem::take(&mut self.tier_changes)
+    }
+
+    /// The number of live streams in the active tier
+    fn active_streams(&self) -> usize {
+        self.streams
+            .iter()
+            .filter(|s| Some(s.stats.priority) == self.active_tier)
+            .count()
+    }
+
+    /// Follow the highest priority among the live streams
+    fn update_tier(&mut self) {
+        let tier = self.streams.iter().map(|s| s.stats.priority).max();
+        if tier == self.active_tier {
+            return;
         }
-        Ok(buf.len())
+        let reason = if tier > self.active_tier {
+            TierChangeReason::Failback
+        } else {
+            TierChangeReason::Failover
+        };
+        self.tier_changes.push(TierChange {
+            from: self.active_tier,
+            to: tier,
+            reason,
+        });
+        self.active_tier = tier;
     }
 
-    async fn flush(&mut self) -> io::Result<()> {
-        for stream in &mut self.streams {
-            stream.flush().await?;
+    fn evict(&mut self, subflow: Subflow<W>) {
+        let (id, priority) = (subflow.id, subflow.stats.priority);
+        let label = subflow.label.clone();
+        self.retire(subflow);
+        if let Some(reconnect) = &self.reconnect {
+            let dial = (reconnect.redial)(self.events.clone(), id, label, priority);
+            reconnect.pending.push(dial);
         }
-        Ok(())
     }
 
-    async fn shutdown(&mut self) -> io::Result<()> {
-        Self::shutdown(self).await
+    /// Take `subflow` out of the pool for good, its unacknowledged segments lost
+    fn retire(&mut self, mut subflow: Subflow<W>) {
+        if self.retransmission.is_some() {
+            let unacked = subflow.unacked.drain(..);
+            self.lost.extend(unacked.map(|(sequence, _)| sequence));
+        }
+        subflow.stats.live = false;
+        self.retired.push(subflow.stats);
+        self.emit(|| SubflowEvent::Removed { id: subflow.id });
     }
-}
 
-async fn shutdown_stream<W>(stream: &mut W) -> io::Result<()>
-where
-    W: AsyncWrite + Unpin,
-{
-    let message = Message::Shutdown;
-    message.encode(stream).await?;
-    stream.shutdown().await?;
-    Ok(())
-}
+    /// Move the byte stream onto `streams` in place of every stream it has, e.g., once a device has switched networks and its old paths are dead
+    ///
+    /// The old streams are dropped right away rather than shut down, along with the writes a cancelled call left on them and the re-dials of `Self::set_reconnect` still pending, so no TCP timeout is waited out.
+    /// With retransmission enabled, whatever they carried and was not acknowledged goes out again on `streams` before this returns, so the byte stream resumes from the last cumulative acknowledgement.
+    /// Without it the data in flight on the old streams is lost unless it has arrived already.
+    /// The handshakes of `streams` announce the session of `Self::set_session` for `Receiver::migrate` on the other end to check.
+    /// Returns the IDs of `streams`.
+    pub async fn migrate(&mut self, streams: Vec<W>) -> Result<Vec<StreamId>, SendError> {
+        let mut old: Vec<_> = self.streams.drain(..).collect();
+        for (sequence, subflow, _) in self.writes.abort().await {
+            if let (Some(sequence), Some(_)) = (sequence, &self.retransmission) {
+                self.lost.push(sequence);
+            }
+            old.push(subflow);
+        }
+        if let Some(reconnect) = &mut self.reconnect {
+            reconnect.pending.clear();
+        }
+        for subflow in old {
+            self.retire(subflow);
+        }
+        let ids = self.add_streams(streams);
+        self.retransmit_lost().await?;
+        Ok(ids)
+    }
 
-#[derive(Debug, Error)]
-pub enum SendError {
-    #[error("No stream left")]
-    NoStreamLeft,
-    #[error("Stream I/O errors")]
-    Io(Vec<io::Error>),
-}
+    /// Re-dial every evicted stream with `redial` and put the new stream in its place
+    ///
+    /// `redial` is given the ID of the evicted stream, e.g., to look up its path for a `StreamFactory`.
+    /// Failed dials are retried with exponential backoff as `policy` says.
+    /// The dials make progress while the sender is in use, so the data keeps flowing over the remaining streams in the meantime, and the sender waits for them rather than giving up once no stream is left.
+    pub fn set_reconnect<F, Fut>(&mut self, policy: ReconnectPolicy, redial: F)
+    where
+        W: Send,
+        F: Fn(StreamId) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<W>> + Send + 'static,
+    {
+        let redial = Arc::new(redial);
+        self.reconnect = Some(Reconnect {
+            redial: Box::new(move |events, id, label, priority| {
+                let redial = redial.clone();
+                let dial = redial_stream(policy, move |id| redial(id), events, id, label, priority);
+                Box::pin(dial)
+            }),
+            pending: FuturesUnordered::new(),
+        });
+    }
 
-#[derive(Debug, Error)]
-#[error("No stream left")]
-pub struct NoStreamLeft;
+    /// The number of evicted streams being re-dialed
+    pub fn reconnecting(&self) -> usize {
+        self.reconnect
+            .as_ref()
+            .map_or(0, |reconnect| reconnect.pending.len())
+    }
+
+    /// Wait until every evicted stream being re-dialed is back in the pool or given up on
+    pub async fn wait_for_reconnects(&mut self) {
+        loop {
+            let Some(reconnect) = &mut self.reconnect else {
+                return;
+            };
+            let Some(redialed) = reconnect.pending.next().await else {
+                return;
+            };
+            self.splice(redialed);
+        }
+    }
+
+    /// Put the streams re-dialed or added by a `PoolHandle` so far into the pool, waiting for them while no stream is left
+    ///
+    /// Without a re-dial pending, it waits for `Self::set_zero_stream_grace` at most.
+    async fn splice_redialed(&mut self) {
+        while let Ok(stream) = self.added.try_recv() {
+            self.add_stream(stream);
+        }
+        if let Some(reconnect) = &mut self.reconnect {
+            let redialed = std::future::poll_fn(|cx| {
+                let mut redialed = vec![];
+                while let Poll::Ready(Some(r)) = reconnect.pending.poll_next_unpin(cx) {
+                    redialed.push(r);
+                }
+                Poll::Ready(redialed)
+            })
+            .await;
+            for redialed in redialed {
+                self.splice(redialed);
+            }
+        }
+
+        let deadline = self.zero_stream_grace.map(|grace| Instant::now() + grace);
+        while self.streams.is_empty() {
+            let dialing = self
+                .reconnect
+                .as_ref()
+                .is_some_and(|reconnect| !reconnect.pending.is_empty());
+            if !dialing && deadline.is_none() {
+                return;
+            }
+            let redialed = async {
+                match &mut self.reconnect {
+                    Some(reconnect) if dialing => reconnect.pending.next().await,
+                    _ => std::future::pending().await,
+                }
+            };
+            let grace = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                Some(redialed) = redialed => self.splice(redialed),
+                Some(stream) = self.added.recv() => {
+                    self.add_stream(stream);
+                }
+                () = grace => return,
+            }
+        }
+    }
+
+    fn splice(&mut self, redialed: Redialed<W>) {
+        let Some(stream) = redialed.stream else {
+            return;
+        };
+        let id = redialed.id;
+        let replacement = self.add_subflow(redialed.label, redialed.priority, stream);
+        self.emit(|| SubflowEvent::Reconnected { id, replacement });
+    }
+
+    /// Subscribe to the events of the streams from now on
+    ///
+    /// A subscriber that falls behind by more than a few dozen events misses the oldest ones.
+    /// Nobody needs to listen, the send path never waits for a subscriber.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<SubflowEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: impl FnOnce() -> SubflowEvent) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event());
+        }
+    }
+
+    /// Put the stream of a finished write back into the pool, or evict it if the write failed and `evict` is set
+    fn settle(
+        &mut self,
+        (sequence, mut subflow, res): WriteResult<W>,
+        evict: bool,
+    ) -> Result<Option<Range<Sequence>>, StreamError> {
+        let error = match res {
+            Ok(()) => {
+                if let (Some(sequence), Some(_)) = (&sequence, &self.retransmission) {
+                    subflow.unacked.push((sequence.clone(), Instant::now()));
+                    if self.retransmitting {
+                        subflow.stats.retransmitted_segments += 1;
+                        subflow.stats.retransmitted_bytes +=
+                            sequence.end.inner() - sequence.start.inner();
+                    }
+                }
+                self.streams.push_back(subflow);
+                return Ok(sequence);
+            }
+            Err(error) => error,
+        };
+        subflow.stats.errors += 1;
+        let id = subflow.id;
+        trace::warn_event!(
+            stream_id = id.inner(),
+            sequence = sequence.as_ref().map(|sequence| sequence.start.inner()),
+            kind = ?error.kind(),
+            error = %error,
+            "subflow_failed"
+        );
+        self.emit(|| match error.kind() {
+            io::ErrorKind::TimedOut => SubflowEvent::WriteTimeout { id },
+            kind => SubflowEvent::Failed {
+                id,
+                error: Arc::new(io::Error::new(kind, error.to_string())),
+            },
+        });
+        let error = StreamError {
+            id: subflow.id,
+            label: subflow.label.clone(),
+            sequence: sequence.map(|sequence| sequence.start),
+            error,
+        };
+        if evict {
+            self.evict(subflow);
+        } else {
+            self.streams.push_back(subflow);
+        }
+        Err(error)
+    }
+
+    /// Wait for the writes left behind by a cancelled call and put their streams back into the pool
+    ///
+    /// Streams whose write failed are evicted and reported by `Self::take_evicted_streams`.
+    async fn reclaim(&mut self) {
+        while let Some(write) = self.writes.next().await {
+            if let Err(e) = self.settle(write, true) {
+                self.evicted.push(e);
+            }
+        }
+        self.splice_redialed().await;
+        self.update_tier();
+    }
+
+    /// Hand the stream freed last a share of the unwritten rest of the largest segment still being written
+    ///
+    /// The rest is shared in proportion to the goodput of both streams, or evenly until both are measured.
+    fn steal(
+        &mut self,
+        send_buf: &mut SendStreamBuf,
+        claims: &mut Vec<Arc<Claim>>,
+        options: WriteOptions,
+    ) {
+        let Some(thief) = self.streams.back().map(|subflow| subflow.stats) else {
+            return;
+        };
+        if Some(thief.priority) != self.active_tier {
+            return;
+        }
+        let Some(victim) = claims.iter().max_by_key(|claim| claim.remaining()) else {
+            return;
+        };
+        let share = match (thief.goodput, victim.goodput) {
+            (Some(thief), Some(victim)) => thief / (thief + victim),
+            _ => 0.5,
+        };
+        let min = self.min_segment_size.map_or(0, |size| size.get());
+        let Some(mid) = victim.split(share, CHUNK_SIZE.max(min)) else {
+            return;
+        };
+        // The rest stays unsent in `send_buf` for the next round if it cannot be carved out
+        let Some(data_segment) = send_buf.retain_unsent(mid) else {
+            return;
+        };
+        let subflow = self.streams.pop_back().unwrap();
+        let claim = Claim::new(&data_segment, &subflow);
+        claims.push(Arc::clone(&claim));
+        self.writes
+            .push(subflow, Job::Segment(data_segment, claim), options);
+    }
+
+    /// Take the streams evicted by `Self::batch_send_all` since the last call
+    ///
+    /// Streams evicted by `Self::batch_send` are reported in its `SendError::Io` instead.
+    pub fn take_evicted_streams(&mut self) -> Vec<StreamError> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Write each unsent segment of `send_buf` at most once
+    ///
+    /// Cancel safe: the streams of the writes still in flight are put back into the pool by the next call, and the segments they carried stay unsent in `send_buf`.
+    pub async fn batch_send(&mut self, send_buf: &mut SendStreamBuf) -> Result<(), SendError> {
+        if self.closed {
+            return Err(SendError::Closed);
+        }
+        let mode = self.send_mode.for_send(send_buf.unsent_bytes());
+        self.batch_send_with_mode(send_buf, mode).await
+    }
+
+    async fn batch_send_with_mode(
+        &mut self,
+        send_buf: &mut SendStreamBuf,
+        mode: SendMode,
+    ) -> Result<(), SendError> {
+        self.reclaim().await;
+        self.process_pongs();
+        self.process_feedback();
+        if self.streams.is_empty() {
+            return Err(SendError::NoStreamLeft {
+                streams: self.next_stream_id,
+                errors: std::mem::take(&mut self.evicted),
+            });
+        }
+
+        // Offer the scheduler the streams of the active tier and as many segments as they can carry
+        let preferred: &[fn(&Subflow<W>) -> bool] = match self.retransmitting {
+            true => &[|s| s.mtu.is_none() && !s.timed_out, |s| s.mtu.is_none()],
+            // Stalled streams get no new segments while their tier has others
+            false => &[|s| !s.stalled],
+        };
+        let tier = self.active_tier;
+        let preferred = preferred.iter().copied().find(|preferred| {
+            self.streams
+                .iter()
+                .any(|s| Some(s.stats.priority) == tier && preferred(s))
+        });
+        let mut scratch = std::mem::take(&mut self.scratch);
+        let Scratch {
+            offered,
+            segments,
+            segment_meta,
+            stream_meta,
+            subflows,
+            copies,
+            claims,
+        } = &mut scratch;
+        offered.extend(
+            (0..self.streams.len())
+                .filter(|&i| Some(self.streams[i].stats.priority) == self.active_tier)
+                .filter(|&i| preferred.is_none_or(|preferred| preferred(&self.streams[i]))),
+        );
+        segments.extend(send_buf.iter_unsent_segments().take(offered.len()));
+        segment_meta.extend(
+            segments
+                .iter()
+                .map(|s| SegmentMeta::new(s.start_sequence()..s.end_sequence())),
+        );
+        stream_meta.extend(
+            offered
+                .iter()
+                .map(|&i| StreamMeta::new(self.streams[i].stats)),
+        );
+        let assignments = match mode {
+            SendMode::Stripe => self.scheduler.assign(segment_meta, stream_meta),
+            SendMode::Duplicate => Duplicate.assign(segment_meta, stream_meta),
+            SendMode::Sticky { .. } if segments.is_empty() => vec![],
+            SendMode::Sticky { .. } => {
+                let least_loaded = (0..offered.len())
+                    .min_by_key(|&stream| self.streams[offered[stream]].expected_delivery());
+                least_loaded
+                    .map(|stream| Assignment { segment: 0, stream })
+                    .into_iter()
+                    .collect()
+            }
+        };
+        let snapshot = match assignments.is_empty() {
+            true => None,
+            false => self.decision_log.start_round(stream_meta),
+        };
+        subflows.extend(self.streams.drain(..).map(Some));
+
+        let options = self.write_options();
+        copies.resize(segments.len(), 0);
+        for assignment in &assignments {
+            if let Some(copies) = copies.get_mut(assignment.segment) {
+                *copies += 1;
+            }
+        }
+        for Assignment { segment, stream } in assignments {
+            let Some(data_segment) = segments.get(segment).cloned() else {
+                continue;
+            };
+            let Some(subflow) = offered
+                .get(stream)
+                .and_then(|&stream| subflows[stream].take())
+            else {
+                continue;
+            };
+
+            trace::debug_event!(
+                stream_id = subflow.id.inner(),
+                sequence = data_segment.start_sequence().inner(),
+                len = data_segment.size(),
+                "segment_dispatched"
+            );
+            if let Some(snapshot) = &snapshot {
+                let sequence = data_segment.start_sequence()..data_segment.end_sequence();
+                self.decision_log.record(sequence, subflow.id, snapshot);
+            }
+            let claim = Claim::new(&data_segment, &subflow);
+            // Only a segment carried by a single stream can have its rest stolen
+            if mode == SendMode::Stripe && copies[segment] == 1 {
+                claims.push(Arc::clone(&claim));
+            }
+            self.writes
+                .push(subflow, Job::Segment(data_segment, claim), options);
+        }
+
+        // Send pings for the remaining streams, only as they fall due under a keepalive
+        let ping = match self.keepalive {
+            Some(interval) => Job::Heartbeat(keepalive_idle(interval)),
+            None => Job::Ping,
+        };
+        for subflow in subflows.drain(..).flatten() {
+            self.writes.push(subflow, ping.clone(), options);
+        }
+
+        let mut evicted = vec![];
+        let mut failed_segments = vec![];
+        while let Some(write) = self.writes.next().await {
+            // The stream might have written a partial frame, so it is torn down instead of being reused
+            match self.settle(write, true) {
+                Ok(sequence) => {
+                    if let Some(sequence) = sequence {
+                        // A stream that ran out of budget leaves the rest of its segment unsent
+                        send_buf.retain_unsent(sequence.end);
+                        send_buf.mark_as_sent(sequence.start);
+                    }
+                    self.steal(send_buf, claims, options);
+                }
+                Err(error) => {
+                    failed_segments.extend(error.sequence);
+                    evicted.push(error);
+                }
+            }
+        }
+        scratch.clear();
+        self.scratch = scratch;
+        self.update_tier();
+        for sequence in failed_segments {
+            send_buf.mark_as_failed(sequence, self.active_streams());
+        }
+        if !evicted.is_empty() {
+            return Err(SendError::Io(evicted));
+        }
+        Ok(())
+    }
+
+    /// Send all of `data` as the next part of the byte stream
+    ///
+    /// Failed segments are retransmitted on the remaining streams and the evicted streams are reported by `Self::take_evicted_streams`.
+    /// Data larger than `Self::set_send_chunk_size` is fed to the streams as they take it, holding that much of it at most.
+    /// Returns `SendError::Incomplete` with the errors and the unsent rest of `data` instead once every stream is evicted or the streams keep failing without making progress.
+    /// Returns `SendError::NoStreamLeft` if there is no stream left to begin with and `SendError::SequenceExhausted` without sending anything if `data` would run past the end of the sequence space.
+    ///
+    /// Cancelling it loses no stream, but the part of the held data not written yet leaves a gap in the byte stream, and the data after it is left out.
+    pub async fn batch_send_all(&mut self, data: Bytes) -> Result<(), SendError> {
+        self.batch_send_all_with_mode(data, self.send_mode).await
+    }
+
+    /// Send all of `buf`, e.g., a chain of `Bytes`, as the next part of the byte stream
+    ///
+    /// `buf` takes up one contiguous range of sequences, and its chunks are sent as they are without flattening them, see `SendStreamBuf::push_buf`.
+    /// Fails like `Self::batch_send_all`.
+    pub async fn send_buf<B: Buf>(&mut self, buf: B) -> Result<(), SendError> {
+        self.send_data(buf, self.send_mode, None).await
+    }
+
+    /// Send exactly `segments`, each a start sequence and its payload, e.g., for a retransmission layer of its own
+    ///
+    /// The segments are striped across the streams as they are, split only to fit the maximum segment size, and may leave gaps between them.
+    /// The sequence of the other sends does not move on, so the caller keeps them from reusing the sequences.
+    /// Returns `SendError::InvalidSegment` without sending anything if a segment is empty, too large, overlaps another or, unless `Self::set_allow_retransmit`, starts before the next sequence of the other sends.
+    /// The segments are not kept for `Self::enable_retransmission`, and those of a failed call are to be sent again whole.
+    pub async fn send_segments(
+        &mut self,
+        segments: Vec<(Sequence, Bytes)>,
+    ) -> Result<(), SendError> {
+        if self.closed {
+            return Err(SendError::Closed);
+        }
+        let mut data_segments = Vec::with_capacity(segments.len());
+        for (sequence, payload) in segments {
+            if sequence < self.next && !self.allow_retransmit {
+                return Err(SendError::InvalidSegment { sequence });
+            }
+            let data_segment = DataSegment::new(sequence, payload)
+                .ok_or(SendError::InvalidSegment { sequence })?;
+            data_segments.push(data_segment);
+        }
+        let mut send_buf =
+            SendStreamBuf::from_segments(data_segments).map_err(|e| SendError::InvalidSegment {
+                sequence: e.sequence(),
+            })?;
+        if send_buf.done() {
+            return Ok(());
+        }
+        self.reclaim().await;
+        if self.streams.is_empty() {
+            return Err(SendError::NoStreamLeft {
+                streams: self.next_stream_id,
+                errors: std::mem::take(&mut self.evicted),
+            });
+        }
+        self.retransmit_lost().await?;
+        let max_segment_size = self
+            .max_segment_size
+            .map_or(DEFAULT_MAX_PAYLOAD_SIZE, |size| {
+                size.get().min(MAX_PAYLOAD_SIZE)
+            });
+        send_buf.limit_segment_size(max_segment_size);
+        self.send_all(&mut send_buf, self.send_mode).await
+    }
+
+    /// `Self::batch_send_all` in `mode` instead of the mode of the sender
+    pub async fn batch_send_all_with_mode(
+        &mut self,
+        data: Bytes,
+        mode: SendMode,
+    ) -> Result<(), SendError> {
+        self.send_data(data, mode, None).await
+    }
+
+    /// `Self::batch_send_all` that keeps `progress` up to date while it runs
+    ///
+    /// Poll `progress` from another task, e.g., to draw a progress bar.
+    pub async fn batch_send_all_with_progress(
+        &mut self,
+        data: Bytes,
+        progress: ProgressHandle,
+    ) -> Result<(), SendError> {
+        self.send_data(data, self.send_mode, Some(progress)).await
+    }
+
+    /// `Self::batch_send_all` that gives up at `deadline`
+    ///
+    /// At the deadline the writes in flight are aborted.
+    /// The streams whose write finished anyway are kept and the others are evicted, since they might be left in the middle of a frame.
+    /// Returns `SendError::DeadlineExceeded` then, and the part of `data` not written leaves a gap in the byte stream as if the call were cancelled.
+    pub async fn batch_send_all_with_deadline(
+        &mut self,
+        data: Bytes,
+        deadline: Instant,
+    ) -> Result<(), SendError> {
+        let progress = ProgressHandle::new();
+        let send = self.send_data(data, self.send_mode, Some(progress.clone()));
+        if let Ok(res) = tokio::time::timeout_at(deadline.into(), send).await {
+            return res;
+        }
+        self.abort_writes().await;
+        Err(SendError::DeadlineExceeded {
+            bytes_sent: progress.progress().sent_bytes,
+        })
+    }
+
+    /// Queue `data` as the next part of the byte stream, for `Self::drive_once` to send a round at a time
+    ///
+    /// `data` takes its sequences right away, so the data of later sends follows it in the byte stream whenever it goes out.
+    /// Only `Self::drive_once` sends the submissions, so drive them to the end before `Self::close`.
+    /// Returns `SendError::Closed` after `Self::close` and `SendError::SequenceExhausted` if `data` would run past the end of the sequence space.
+    pub fn submit(&mut self, data: Bytes) -> Result<SendTicket, SendError> {
+        if self.closed {
+            return Err(SendError::Closed);
+        }
+        let bytes = self.staged.len() + data.len();
+        let end = self
+            .next
+            .checked_add(bytes as u64)
+            .ok_or(SendError::SequenceExhausted)?;
+        let mut send_buf = SendStreamBuf::new(self.staged.split().freeze(), self.next);
+        send_buf.push(data).unwrap();
+        let progress = ProgressHandle::new();
+        send_buf.track_progress(progress.clone());
+        let ticket = SendTicket {
+            sequences: self.next..end,
+            progress,
+        };
+        self.next = end;
+        if !send_buf.done() {
+            self.submissions.push_back(Submission {
+                mode: self.send_mode.for_send(bytes),
+                send_buf,
+                prepared: false,
+            });
+        }
+        Ok(ticket)
+    }
+
+    /// Write each unsent segment of the oldest submission of `Self::submit` at most once
+    ///
+    /// The round is preceded by the due probes and the retransmissions that `Self::batch_send_all` makes, and the first round of a submission waits for room in the retransmission buffer like it.
+    /// Streams evicted by the round are reported by `Self::take_evicted_streams` and the segments they failed to write are left to the next rounds.
+    /// Returns `SendError::NoStreamLeft` if there is no stream left.
+    ///
+    /// Cancel safe: a cancelled round leaves the segments it was writing to be written again, so it can be a branch of `tokio::select!`.
+    pub async fn drive_once(&mut self) -> Result<DriveProgress, SendError> {
+        self.reclaim().await;
+        if self.submissions.is_empty() {
+            return Ok(DriveProgress {
+                sent_bytes: 0,
+                pending: 0,
+            });
+        }
+        if self
+            .next_probe()
+            .is_some_and(|probe| probe <= Instant::now())
+        {
+            if let Err(SendError::Io(errors)) = self.probe_rtt().await {
+                self.evicted.extend(errors);
+            }
+        }
+        if self.streams.is_empty() {
+            return Err(SendError::NoStreamLeft {
+                streams: self.next_stream_id,
+                errors: std::mem::take(&mut self.evicted),
+            });
+        }
+        self.retransmit_lost().await?;
+        if !self.submissions[0].prepared {
+            let bytes = self.submissions[0].send_buf.unsent_bytes();
+            self.wait_for_room(bytes).await?;
+            let mut send_buf = self.submissions[0].send_buf.clone();
+            let mode = self.submissions[0].mode;
+            self.prepare(&mut send_buf, mode);
+            self.submissions[0] = Submission {
+                send_buf,
+                mode,
+                prepared: true,
+            };
+        }
+
+        // The round works on a copy so that a cancelled one leaves the submission as it was
+        let mut send_buf = self.submissions[0].send_buf.clone();
+        let unsent = send_buf.unsent_bytes();
+        match self
+            .batch_send_with_mode(&mut send_buf, self.submissions[0].mode)
+            .await
+        {
+            Ok(()) => (),
+            Err(SendError::Io(errors)) => self.evicted.extend(errors),
+            Err(e) => return Err(e),
+        }
+        let sent_bytes = unsent.saturating_sub(send_buf.unsent_bytes());
+        if !send_buf.done() {
+            self.submissions[0].send_buf = send_buf;
+        } else {
+            self.submissions.pop_front();
+            if let Some(retransmission) = &mut self.retransmission {
+                // Later sends might have finished first
+                let end = send_buf.end_sequence();
+                let index = retransmission
+                    .in_flight
+                    .partition_point(|in_flight| in_flight.end_sequence() < end);
+                retransmission.in_flight.insert(index, send_buf);
+            }
+        }
+        let progress = DriveProgress {
+            sent_bytes,
+            pending: self.submissions.len(),
+        };
+        self.retransmit_lost().await?;
+        Ok(progress)
+    }
+
+    /// Abort the writes left in flight by a cancelled call
+    ///
+    /// Streams whose write did not finish are evicted.
+    async fn abort_writes(&mut self) {
+        for write in self.writes.abort().await {
+            if let Err(e) = self.settle(write, true) {
+                self.evicted.push(e);
+            }
+        }
+        self.update_tier();
+    }
+
+    /// Send the data of a write through `AsyncWrite` or `AsyncAsyncWrite`
+    async fn send_written(&mut self, data: Bytes) -> Result<(), SendError> {
+        match self.send_deadline {
+            Some(deadline) => {
+                self.batch_send_all_with_deadline(data, Instant::now() + deadline)
+                    .await
+            }
+            None => self.batch_send_all(data).await,
+        }
+    }
+
+    /// Send the data held back by the cork followed by `data`
+    async fn send_data(
+        &mut self,
+        data: impl Buf,
+        mode: SendMode,
+        progress: Option<ProgressHandle>,
+    ) -> Result<(), SendError> {
+        let bytes = self.staged.len() + data.remaining();
+        self.next
+            .checked_add(bytes as u64)
+            .ok_or(SendError::SequenceExhausted)?;
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "batch_send_all",
+            start_sequence = self.next.inner(),
+            end_sequence = self.next.inner() + bytes as u64,
+            bytes,
+        );
+        let send = self.send_chunked(data, mode.for_send(bytes), progress);
+        #[cfg(feature = "tracing")]
+        let send = tracing::Instrument::instrument(send, span);
+        send.await
+    }
+
+    /// Send the data held back by the cork followed by `data`, holding at most `Self::set_send_chunk_size` bytes of it at once
+    ///
+    /// The data goes into one `SendStreamBuf` that `Self::refill` tops up between the rounds, and the next sequence moves on with each refill.
+    async fn send_chunked(
+        &mut self,
+        mut data: impl Buf,
+        mode: SendMode,
+        progress: Option<ProgressHandle>,
+    ) -> Result<(), SendError> {
+        let start = self.next;
+        let mut send_buf = SendStreamBuf::new(self.staged.split().freeze(), start);
+        if let Some(progress) = progress {
+            send_buf.track_progress_ahead(progress, data.remaining());
+        }
+        let bytes = send_buf.unsent_bytes() + data.remaining();
+        self.ready_for_chunk(bytes.min(self.send_chunk_size.get()))
+            .await?;
+        let res = self
+            .send_chunk(send_buf, mode, |this, send_buf| {
+                this.refill(send_buf, &mut data, start)
+            })
+            .await;
+        match res {
+            Ok(()) => self.retransmit_lost().await,
+            Err(SendError::Incomplete {
+                sent,
+                remaining,
+                errors,
+            }) => {
+                // The rest of `data` was never pushed, so it goes back too
+                let rest = data.copy_to_bytes(data.remaining());
+                let remaining = match (remaining.is_empty(), rest.is_empty()) {
+                    (true, _) => rest,
+                    (_, true) => remaining,
+                    _ => [remaining, rest].concat().into(),
+                };
+                Err(SendError::Incomplete {
+                    sent,
+                    remaining,
+                    errors,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Release what the streams are done with in `send_buf` and top it up with the next of `data`, a send from `start` on
+    ///
+    /// The streams are done with a segment once it is written, or once it is acknowledged with `Self::enable_retransmission`.
+    /// The send holds at most `Self::set_send_chunk_size` bytes and stays within the retransmission limit, so a `send_buf` written in full without room left goes to the retransmission, which sends its losses again while the send waits for acknowledgements, and a fresh one picks up after it.
+    /// Returns whether `data` has more left.
+    fn refill(
+        &mut self,
+        send_buf: &mut SendStreamBuf,
+        data: &mut impl Buf,
+        start: Sequence,
+    ) -> bool {
+        let chunk_size = self.send_chunk_size.get();
+        self.process_acks();
+        let ack = self
+            .retransmission
+            .as_ref()
+            .map_or(send_buf.end_sequence(), Retransmission::ack);
+        send_buf.mark_as_acked(ack);
+        let mut room = chunk_size.saturating_sub(send_buf.resident_bytes());
+        if let Some(retransmission) = &mut self.retransmission {
+            let in_flight = &retransmission.in_flight;
+            let held = in_flight
+                .iter()
+                .map(SendStreamBuf::resident_bytes)
+                .sum::<usize>()
+                + send_buf.resident_bytes();
+            // The parts of the send handed to the retransmission count against the chunk too
+            let handed_over: usize = in_flight
+                .iter()
+                .rev()
+                .take_while(|in_flight| start < in_flight.end_sequence())
+                .map(SendStreamBuf::resident_bytes)
+                .sum();
+            room = room.saturating_sub(handed_over);
+            // Like `Self::wait_for_room`, a send over the limit only waits for the buffer to empty
+            if held > 0 {
+                room = room.min(retransmission.limit.get().saturating_sub(held));
+            }
+            if room == 0 && send_buf.done() && !send_buf.is_empty() {
+                let next = send_buf.continuation();
+                retransmission
+                    .in_flight
+                    .push_back(std::mem::replace(send_buf, next));
+            }
+        }
+        if data.remaining() <= room {
+            send_buf.push_buf(&mut *data).unwrap();
+        } else {
+            // Pieces of their own are released apart, so a piece written leaves room for the next
+            let piece = (chunk_size / CHUNK_PIECES).max(1);
+            while room > 0 {
+                let len = room.min(piece);
+                send_buf.push_buf((&mut *data).take(len)).unwrap();
+                room -= len;
+            }
+        }
+        self.next = send_buf.end_sequence();
+        data.has_remaining()
+    }
+
+    /// Send the data held back by the cork
+    async fn uncork(&mut self) -> Result<(), SendError> {
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+        self.send_written(Bytes::new()).await
+    }
+
+    /// Send the data of a write through `AsyncWrite` or `AsyncAsyncWrite` unless the cork holds it back
+    async fn submit_written(&mut self, data: &[u8]) -> Result<(), SendError> {
+        if self.closed {
+            return Err(SendError::Closed);
+        }
+        let Some(cork) = self.cork else {
+            return self.send_written(Bytes::copy_from_slice(data)).await;
+        };
+        if self.staged.is_empty() {
+            self.staged_since = Instant::now();
+        }
+        self.staged.extend_from_slice(data);
+        if cork.size <= self.staged.len() || cork.delay <= self.staged_since.elapsed() {
+            self.uncork().await?;
+        }
+        Ok(())
+    }
+
+    /// Send the data pushed into `send_buf`, which picks up at the next sequence
+    async fn send_buffer(
+        &mut self,
+        mut send_buf: SendStreamBuf,
+        mode: SendMode,
+        progress: Option<ProgressHandle>,
+    ) -> Result<(), SendError> {
+        self.next
+            .checked_add(send_buf.unsent_bytes() as u64)
+            .ok_or(SendError::SequenceExhausted)?;
+        if let Some(progress) = progress {
+            send_buf.track_progress(progress);
+        }
+        self.ready_for_chunk(send_buf.unsent_bytes()).await?;
+        self.send_chunk(send_buf, mode, |_, _| false).await?;
+        self.retransmit_lost().await
+    }
+
+    /// Get the streams ready to take a chunk of `bytes`, retransmitting the data lost before and waiting for room to hold the chunk for retransmission
+    async fn ready_for_chunk(&mut self, bytes: usize) -> Result<(), SendError> {
+        if self.closed {
+            return Err(SendError::Closed);
+        }
+        self.reclaim().await;
+        if self
+            .next_probe()
+            .is_some_and(|probe| probe <= Instant::now())
+        {
+            // A stream that fails to carry a probe would fail to carry the data too
+            if let Err(SendError::Io(errors)) = self.probe_rtt().await {
+                self.evicted.extend(errors);
+            }
+        }
+        if self.streams.is_empty() {
+            return Err(SendError::NoStreamLeft {
+                streams: self.next_stream_id,
+                errors: std::mem::take(&mut self.evicted),
+            });
+        }
+        self.retransmit_lost().await?;
+        self.wait_for_room(bytes).await
+    }
+
+    /// Send the data pushed into `send_buf`, which picks up at the next sequence, and what `refill` tops it up with, once `Self::ready_for_chunk` is done
+    ///
+    /// `refill` is called before the first round too, and again after acknowledgements whenever `Self::send_all_refilled` finds no room.
+    /// Returns `SendError::Incomplete` about the pushed data alone, with the next sequence back at its first unsent byte.
+    async fn send_chunk(
+        &mut self,
+        mut send_buf: SendStreamBuf,
+        mode: SendMode,
+        mut refill: impl FnMut(&mut Self, &mut SendStreamBuf) -> bool,
+    ) -> Result<(), SendError> {
+        let start = self.next;
+        refill(self, &mut send_buf);
+        let bytes = send_buf.unsent_bytes();
+        let mode = mode.for_send(bytes);
+        self.prepare(&mut send_buf, mode);
+
+        // Later data must not reuse the sequences even if this send is cancelled
+        self.next = send_buf.end_sequence();
+        let mut res = self
+            .send_all_refilled(&mut send_buf, mode, &mut refill)
+            .await;
+        // Written in full, with the rest to come once acknowledgements make room for it
+        while let Ok(true) = res {
+            if let Err(e) = self.wait_for_acks().await {
+                res = Err(e);
+                break;
+            }
+            let more = refill(self, &mut send_buf);
+            res = match send_buf.done() {
+                true => Ok(more),
+                false => {
+                    self.prepare(&mut send_buf, mode);
+                    self.send_all_refilled(&mut send_buf, mode, &mut refill)
+                        .await
+                }
+            };
+        }
+        if let Err(e) = res {
+            return Err(self.rewind(&send_buf, start, e));
+        }
+        if let Some(retransmission) = &mut self.retransmission {
+            retransmission.in_flight.push_back(send_buf);
+        }
+        Ok(())
+    }
+
+    /// Split the data of `send_buf` into the segments of its first round
+    fn prepare(&mut self, send_buf: &mut SendStreamBuf, mode: SendMode) {
+        if let Some(size) = self.min_segment_size {
+            send_buf.set_min_segment_size(size.get());
+        }
+        if mode == SendMode::Stripe {
+            match self.goodput_weights() {
+                Some(weights) => send_buf.split_first_unsent_segment_weighted(&weights),
+                None => send_buf.split_first_unsent_segment(self.active_streams()),
+            }
+        }
+        let max_segment_size = self
+            .max_segment_size
+            .map_or(DEFAULT_MAX_PAYLOAD_SIZE, |size| {
+                size.get().min(MAX_PAYLOAD_SIZE)
+            });
+        send_buf.limit_segment_size(max_segment_size);
+    }
+
+    /// Take back the sequences of `send_buf` from its first unsent byte on, so that the rest can be resubmitted
+    ///
+    /// `start` is where `send_buf` starts.
+    fn rewind(&mut self, send_buf: &SendStreamBuf, start: Sequence, e: SendError) -> SendError {
+        let errors = match e {
+            SendError::Incomplete { errors, .. } | SendError::NoStreamLeft { errors, .. } => errors,
+            e => return e,
+        };
+        let first_unsent = send_buf.first_unsent_sequence().unwrap_or(self.next);
+        self.next = first_unsent;
+        SendError::Incomplete {
+            sent: (first_unsent.inner() - start.inner()) as usize,
+            remaining: send_buf.data_from(first_unsent),
+            errors,
+        }
+    }
+
+    async fn send_all(
+        &mut self,
+        send_buf: &mut SendStreamBuf,
+        mode: SendMode,
+    ) -> Result<(), SendError> {
+        self.send_all_refilled(send_buf, mode, |_, _| false)
+            .await
+            .map(|_| ())
+    }
+
+    /// `Self::send_all` that tops up `send_buf` with `refill` after each round until it returns that nothing is left
+    ///
+    /// Returns whether `refill` has more left once `send_buf` is written in full, which is then out of room until acknowledgements make some.
+    async fn send_all_refilled(
+        &mut self,
+        send_buf: &mut SendStreamBuf,
+        mode: SendMode,
+        mut refill: impl FnMut(&mut Self, &mut SendStreamBuf) -> bool,
+    ) -> Result<bool, SendError> {
+        let mut total = send_buf.unsent_bytes();
+        let mut more = true;
+        let mut errors = vec![];
+        let mut failed_rounds = 0;
+        loop {
+            let unsent = send_buf.unsent_bytes();
+            let res = self.batch_send_with_mode(send_buf, mode).await;
+            match res {
+                Ok(()) => (),
+                Err(SendError::Io(evicted)) => errors.extend(evicted),
+                Err(e) => {
+                    self.evicted.extend(errors);
+                    return Err(e);
+                }
+            }
+            // A scheduler that assigns nothing makes no progress either
+            if send_buf.unsent_bytes() < unsent {
+                failed_rounds = 0;
+            } else {
+                failed_rounds += 1;
+            }
+            if !send_buf.done() && self.streams.is_empty() && self.zero_stream_grace.is_some() {
+                // Park the rest until a stream joins or the grace period is over
+                self.reclaim().await;
+            }
+            if !send_buf.done() && (self.streams.is_empty() || failed_rounds >= MAX_FAILED_ROUNDS) {
+                let sent = total - send_buf.unsent_bytes();
+                return Err(SendError::Incomplete {
+                    sent,
+                    remaining: Bytes::new(),
+                    errors,
+                });
+            }
+            if more {
+                let end = send_buf.end_sequence();
+                let unsent = send_buf.unsent_bytes();
+                more = refill(self, send_buf);
+                if send_buf.end_sequence() > end {
+                    total += send_buf.unsent_bytes() - unsent;
+                    self.prepare(send_buf, mode);
+                }
+            }
+            if send_buf.done() {
+                self.evicted.extend(errors);
+                return Ok(more);
+            }
+        }
+    }
+
+    /// Release the acknowledged data
+    fn process_acks(&mut self) {
+        let Some(retransmission) = &mut self.retransmission else {
+            return;
+        };
+        let ack = (*retransmission.acks.borrow_and_update()).max(retransmission.handled_ack);
+        for send_buf in &mut retransmission.in_flight {
+            send_buf.mark_as_acked(ack);
+        }
+        while retransmission
+            .in_flight
+            .front()
+            .is_some_and(|send_buf| send_buf.acked())
+        {
+            retransmission.in_flight.pop_front();
+        }
+        for subflow in &mut self.streams {
+            if subflow.release(|sequence| sequence.end <= ack) {
+                subflow.timed_out = false;
+                subflow.stalled = false;
+            }
+        }
+
+        // The frames of datagram streams might never arrive, and a reliable stream might hold up the acknowledgements
+        let stall_timeouts: Vec<_> = (0..self.streams.len())
+            .map(|i| self.stall_timeout(i))
+            .collect();
+        let mut stalls = vec![];
+        let lost = &mut self.lost;
+        for (subflow, stall_timeout) in self.streams.iter_mut().zip(stall_timeouts) {
+            let loss_timeout = subflow.loss_timeout(self.loss_timeout, self.retransmission_timeout);
+            let Some(timeout) = loss_timeout.into_iter().chain(stall_timeout).min() else {
+                continue;
+            };
+            let datagram = subflow.mtu.is_some();
+            let unacked = subflow.unacked.len();
+            let mut stalled = None;
+            subflow.unacked.retain(|(sequence, written)| {
+                // The later segments of a reliable stream wait for the missing one rather than being lost
+                let elapsed = written.elapsed();
+                let expired = elapsed >= timeout && (datagram || sequence.start <= ack);
+                if expired {
+                    if loss_timeout.is_none_or(|timeout| elapsed < timeout) {
+                        stalled = Some(sequence.start);
+                    }
+                    lost.push(sequence.clone());
+                }
+                !expired
+            });
+            subflow.timed_out |= subflow.unacked.len() < unacked;
+            if let Some(sequence) = stalled {
+                subflow.stalled = true;
+                subflow.stats.stalls += 1;
+                stalls.push((subflow.id, sequence));
+            }
+        }
+        for (id, sequence) in stalls {
+            self.emit(|| SubflowEvent::Stalled { id, sequence });
+        }
+    }
+
+    /// How long the segment the acknowledgements wait for may go unacknowledged on the stream at `index` before it is written again on another, if at all
+    fn stall_timeout(&self, index: usize) -> Option<Duration> {
+        if !self.stall_reassignment {
+            return None;
+        }
+        let ack = self.retransmission.as_ref()?.ack();
+        let subflow = &self.streams[index];
+        if subflow.mtu.is_some() {
+            return None;
+        }
+        let tier = |s: &&Subflow<W>| Some(s.stats.priority) == self.active_tier;
+        let idle = self.streams.iter().enumerate().any(|(i, s)| {
+            i != index && tier(&s) && s.mtu.is_none() && !s.stalled && s.written_at(ack).is_none()
+        });
+        if !idle {
+            return None;
+        }
+        let rtt = subflow.stats.rtt.or_else(|| {
+            self.streams
+                .iter()
+                .filter(tier)
+                .filter_map(|s| s.stats.rtt)
+                .min()
+        })?;
+        Some(rtt.mul_f64(self.stall_multiplier).max(MIN_STALL_TIMEOUT))
+    }
+
+    /// When the oldest unacknowledged segment is given up on
+    fn next_loss(&self) -> Option<Instant> {
+        let lost = self.streams.iter().filter_map(|subflow| {
+            let timeout = subflow.loss_timeout(self.loss_timeout, self.retransmission_timeout)?;
+            let written = subflow.unacked.iter().map(|(_, written)| *written).min()?;
+            Some(written + timeout)
+        });
+        let stalled = (0..self.streams.len()).filter_map(|i| {
+            let timeout = self.stall_timeout(i)?;
+            let ack = self.retransmission.as_ref()?.ack();
+            Some(self.streams[i].written_at(ack)? + timeout)
+        });
+        lost.chain(stalled).min()
+    }
+
+    /// Wait until the datagram streams have every segment acknowledged, retransmitting those found lost
+    async fn settle_datagrams(&mut self) -> Result<(), SendError> {
+        loop {
+            self.retransmit_lost().await?;
+            if self.next_loss().is_none() || self.retransmission.is_none() {
+                return Ok(());
+            }
+            self.wait_for_acks().await?;
+        }
+    }
+
+    /// Send `data` without copying it
+    ///
+    /// This is the preferred way to send owned data: every segment is a slice of `data` sharing its allocation.
+    /// With a send window, `data` is sent in pieces of at most the window and `Self::set_send_chunk_size` each.
+    pub async fn send(&mut self, mut data: Bytes) -> Result<(), SendError> {
+        if self.send_window.is_none() {
+            return self.batch_send_all(data).await;
+        }
+        let mut sent = 0;
+        loop {
+            self.next
+                .checked_add(data.len() as u64)
+                .ok_or(SendError::SequenceExhausted)?;
+            let room = self.write_room().await?;
+            let mut send_buf = SendStreamBuf::with_capacity_limit(self.next, room);
+            let full = send_buf.push(data).err();
+            let piece = send_buf.unsent_bytes();
+            match self.send_buffer(send_buf, self.send_mode, None).await {
+                Ok(()) => sent += piece,
+                Err(SendError::Incomplete {
+                    sent: piece_sent,
+                    remaining,
+                    errors,
+                }) => {
+                    let remaining = match full {
+                        Some(full) => [remaining, full.into_remainder()].concat().into(),
+                        None => remaining,
+                    };
+                    return Err(SendError::Incomplete {
+                        sent: sent + piece_sent,
+                        remaining,
+                        errors,
+                    });
+                }
+                Err(e) => return Err(e),
+            }
+            match full {
+                Some(full) => data = full.into_remainder(),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Coalesce `bufs` into a single write so that they share the segmentation and framing
+    ///
+    /// Returns the number of bytes taken like `AsyncWrite::poll_write_vectored`.
+    pub async fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        if bufs.iter().all(|buf| buf.is_empty()) {
+            return Ok(0);
+        }
+        let room = self.write_room().await?;
+        let len = bufs.iter().map(|buf| buf.len()).sum::<usize>().min(room);
+        let mut data = BytesMut::with_capacity(len);
+        for buf in bufs {
+            let n = buf.len().min(len - data.len());
+            data.extend_from_slice(&buf[..n]);
+        }
+        self.submit_written(&data).await?;
+        Ok(len)
+    }
+
+    /// How many bytes the next write may take, at most a chunk of `Self::set_send_chunk_size`
+    async fn write_room(&mut self) -> Result<usize, SendError> {
+        let chunk_size = self.send_chunk_size.get();
+        let Some(window) = self.send_window else {
+            return Ok(chunk_size);
+        };
+        // The data held back counts against the window too
+        if window.get() <= self.resident_bytes() + self.staged.len() {
+            self.uncork().await?;
+        }
+        let room = self.wait_for_send_window(window).await?;
+        Ok((room - self.staged.len()).min(chunk_size))
+    }
+
+    /// Wait for the acknowledgements to move on, retransmitting the segments that time out meanwhile
+    async fn wait_for_acks(&mut self) -> Result<(), SendError> {
+        // Segments found lost since the last retransmission hold up the acknowledgements
+        if !self.lost.is_empty() {
+            return self.retransmit_lost().await;
+        }
+        let next_loss = self.next_loss();
+        let Some(retransmission) = &mut self.retransmission else {
+            return Ok(());
+        };
+        let loss = async {
+            match next_loss {
+                Some(loss) => tokio::time::sleep_until(loss.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        let lost = tokio::select! {
+            changed = retransmission.acks.changed() => {
+                if changed.is_err() {
+                    return Err(SendError::AcksClosed);
+                }
+                false
+            }
+            () = loss => true,
+        };
+        if lost {
+            self.retransmit_lost().await?;
+        }
+        Ok(())
+    }
+
+    /// Wait until the send window has room and return how many bytes fit
+    async fn wait_for_send_window(&mut self, window: NonZeroUsize) -> Result<usize, SendError> {
+        loop {
+            self.process_acks();
+            let room = window.get().saturating_sub(self.resident_bytes());
+            if room > 0 {
+                return Ok(room);
+            }
+            if self.retransmission.is_none() {
+                return Ok(window.get());
+            }
+            self.wait_for_acks().await?;
+        }
+    }
+
+    /// Wait until `bytes` more fit in the retransmission buffer
+    ///
+    /// A single write larger than the limit only waits for the buffer to empty.
+    async fn wait_for_room(&mut self, bytes: usize) -> Result<(), SendError> {
+        loop {
+            self.process_acks();
+            let resident = self.resident_bytes();
+            let Some(retransmission) = &self.retransmission else {
+                return Ok(());
+            };
+            if resident == 0 || resident + bytes <= retransmission.limit.get() {
+                return Ok(());
+            }
+            self.wait_for_acks().await?;
+        }
+    }
+
+    /// Send the unacknowledged segments of evicted streams again on the others
+    async fn retransmit_lost(&mut self) -> Result<(), SendError> {
+        self.process_acks();
+        self.retransmitting = true;
+        let res = self.retransmit_lost_reliably().await;
+        self.retransmitting = false;
+        res
+    }
+
+    async fn retransmit_lost_reliably(&mut self) -> Result<(), SendError> {
+        while !self.lost.is_empty() {
+            let lost = std::mem::take(&mut self.lost);
+            let Some(retransmission) = &mut self.retransmission else {
+                return Ok(());
+            };
+            let mut in_flight = std::mem::take(&mut retransmission.in_flight);
+            for sequence in lost {
+                for send_buf in &mut in_flight {
+                    send_buf.mark_as_lost(sequence.start, self.active_streams());
+                }
+            }
+
+            let mut res = Ok(());
+            for send_buf in &mut in_flight {
+                if send_buf.done() {
+                    continue;
+                }
+                res = self.send_all(send_buf, self.send_mode).await;
+                if res.is_err() {
+                    break;
+                }
+            }
+            self.retransmission.as_mut().unwrap().in_flight = in_flight;
+            res?;
+        }
+        Ok(())
+    }
+
+    /// The capabilities the handshakes of the streams advertise
+    pub(crate) fn capabilities(&self) -> u32 {
+        self.write_options().capabilities
+    }
+
+    /// The session the handshakes announce, see `Self::set_session`
+    pub(crate) fn session(&self) -> Option<Session> {
+        self.session
+    }
+
+    /// The ID of every live stream with the frame size its handshake advertises and the address of its peer
+    pub(crate) fn subflow_handshakes(&self) -> Vec<(StreamId, Option<u32>, Option<SocketAddr>)>
+    where
+        W: PeerAddr,
+    {
+        let mut subflows: Vec<_> = self
+            .streams
+            .iter()
+            .map(|subflow| {
+                let addr = subflow.stream.get_ref().peer_addr();
+                (subflow.id, subflow.advertised_max_frame_size, addr)
+            })
+            .collect();
+        subflows.sort_unstable_by_key(|(id, ..)| *id);
+        subflows
+    }
+
+    fn write_options(&self) -> WriteOptions {
+        let mut capabilities = 0;
+        if self.encode_options.checksum {
+            capabilities |= CAPABILITY_CHECKSUM;
+        }
+        if self.encode_options.compact {
+            capabilities |= CAPABILITY_COMPACT_HEADERS;
+        }
+        capabilities |= self.encode_options.compression.capability();
+        if self.probing.is_some() {
+            capabilities |= CAPABILITY_RTT_PROBES;
+        }
+        if self.sequence_width == SeqWidth::U32 {
+            capabilities |= CAPABILITY_SEQUENCE_U32;
+        }
+        let passthrough = self.passthrough
+            && capabilities == 0
+            && self.session.is_none()
+            && self.keepalive.is_none()
+            && self.feedback.is_none()
+            && self.next_stream_id == 1;
+        WriteOptions {
+            encode: self.encode_options,
+            timeout: self.write_timeout,
+            capabilities,
+            session: self.session,
+            feedback_interval: self.feedback.as_ref().map(|feedback| feedback.interval),
+            pacing: self.pacing,
+            passthrough: passthrough.then_some(self.next),
+        }
+    }
+
+    /// Sort the streams by their goodput from the fastest and return the weights of the active tier in that order
+    ///
+    /// Streams without a measurement yet are assumed to be as fast as the average.
+    fn goodput_weights(&mut self) -> Option<Vec<f64>> {
+        let tier = self.active_tier;
+        let measured: Vec<f64> = self
+            .streams
+            .iter()
+            .filter(|s| Some(s.stats.priority) == tier)
+            .filter_map(|s| s.stats.goodput)
+            .collect();
+        if measured.is_empty() {
+            return None;
+        }
+        let average = measured.iter().sum::<f64>() / measured.len() as f64;
+
+        let weight = |subflow: &Subflow<W>| subflow.stats.goodput.unwrap_or(average);
+        self.streams
+            .make_contiguous()
+            .sort_by(|a, b| weight(b).total_cmp(&weight(a)));
+        Some(
+            self.streams
+                .iter()
+                .filter(|s| Some(s.stats.priority) == tier)
+                .map(weight)
+                .collect(),
+        )
+    }
+
+    /// Send as much of `buf` as the send window has room for
+    ///
+    /// An empty `buf` is accepted right away without touching the streams or the sequence.
+    async fn write_copy(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let room = self.write_room().await?;
+        let buf = &buf[..buf.len().min(room)];
+        self.submit_written(buf).await?;
+        Ok(buf.len())
+    }
+
+    /// `Self::write_copy` for data that has been copied already
+    async fn write_bytes(&mut self, mut data: Bytes) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let room = self.write_room().await?;
+        data.truncate(room);
+        let len = data.len();
+        if self.cork.is_some() {
+            self.submit_written(&data).await?;
+        } else {
+            self.send_written(data).await?;
+        }
+        Ok(len)
+    }
+
+    /// Write the handshake on every stream that has not carried anything yet
+    ///
+    /// Otherwise the handshake is written right before the first message on each stream.
+    pub async fn handshake(&mut self) -> Result<(), SendError> {
+        self.for_each_stream(true, Job::Greet).await
+    }
+
+    /// Flush all streams concurrently
+    ///
+    /// Writes left in flight by a cancelled call are awaited first, so every segment submitted before is written and flushed on the stream carrying it.
+    /// Streams that fail to flush are evicted and their unacknowledged data is retransmitted on the others.
+    /// With retransmission enabled, it also waits until the segments written on datagram streams are acknowledged or retransmitted as lost.
+    pub async fn flush(&mut self) -> Result<(), SendError> {
+        self.uncork().await?;
+        let end = self.next;
+        let res = self.for_each_stream(true, Job::Flush).await;
+        self.settle_datagrams().await?;
+        if res.is_ok() {
+            self.flushed = self.flushed.max(end);
+        }
+        res
+    }
+
+    /// Flush the stream of `id` alone, evicting it if that fails
+    ///
+    /// Unlike `Self::flush`, the writes left in flight by a cancelled call are not awaited: the stream has to be in the pool.
+    /// Fails with a `FlushStreamError` inside the `io::Error` if it is not, see `Self::stream_state`.
+    pub async fn flush_stream(&mut self, id: StreamId) -> io::Result<()> {
+        let state = self.stream_state(id);
+        if state != Some(StreamState::Idle) {
+            let error = match state {
+                Some(StreamState::InFlight) => FlushStreamError::InFlight(id),
+                _ => FlushStreamError::UnknownStream(id),
+            };
+            return Err(error.into());
+        }
+        let index = self.streams.iter().position(|s| s.id == id).unwrap();
+        let subflow = self.streams.remove(index).unwrap();
+        self.writes.push(subflow, Job::Flush, self.write_options());
+        // The only write in flight
+        let write = self.writes.next().await.unwrap();
+        let res = self.settle(write, true);
+        self.update_tier();
+        match res {
+            Ok(_) => Ok(()),
+            Err(error) => Err(io::Error::new(error.error.kind(), error)),
+        }
+    }
+
+    /// Take the stream of `id` out of the pool and hand it back, e.g., to retire a path before it fails
+    ///
+    /// The writes left in flight by a cancelled call are awaited first, so no segment is cut short.
+    /// The stream then carries a shutdown and is flushed, which tells the receiver that it carries nothing more, see `Receiver::remove_stream`; it is left open for the caller to close.
+    /// With retransmission enabled, the segments it carried and that are not acknowledged yet are written again on the other streams by the next send.
+    ///
+    /// Fails with `RemoveError::LastStream` if no other live stream would be left, unless `force`.
+    /// A stream that fails to carry the shutdown is evicted and its error returned in `RemoveError::Io`.
+    pub async fn remove_stream(&mut self, id: StreamId, force: bool) -> Result<W, RemoveError> {
+        self.reclaim().await;
+        let Some(index) = self.streams.iter().position(|s| s.id == id) else {
+            return Err(RemoveError::UnknownStream);
+        };
+        if self.streams.len() == 1 && !force {
+            return Err(RemoveError::LastStream);
+        }
+        let mut subflow = self.streams.remove(index).unwrap();
+        let options = self.write_options();
+        let res = async {
+            subflow.write(&Message::Shutdown, options).await?;
+            with_timeout(options.timeout, subflow.stream.flush()).await
+        }
+        .await;
+        if let Err(error) = res {
+            self.evict(subflow);
+            self.update_tier();
+            return Err(RemoveError::Io(error));
+        }
+        if self.retransmission.is_some() {
+            let unacked = subflow.unacked.drain(..);
+            self.lost.extend(unacked.map(|(sequence, _)| sequence));
+        }
+        subflow.stats.live = false;
+        self.retired.push(subflow.stats);
+        self.update_tier();
+        self.emit(|| SubflowEvent::Removed { id });
+        Ok(subflow.stream.into_inner())
+    }
+
+    /// Where the stream of `id` is, or `None` if no stream of this ID has been added
+    pub fn stream_state(&self, id: StreamId) -> Option<StreamState> {
+        if self.streams.iter().any(|s| s.id == id) {
+            Some(StreamState::Idle)
+        } else if self.writes.contains(id) {
+            Some(StreamState::InFlight)
+        } else if self.retired.iter().any(|s| s.id == id) {
+            Some(StreamState::Evicted)
+        } else {
+            None
+        }
+    }
+
+    /// Shut down all streams concurrently
+    ///
+    /// Every stream carries a FIN with the end of the sent data before it is shut down, so that the receiver can tell a finished byte stream from a truncated one.
+    /// Every stream is shut down even if some of them fail, or fail to carry the FIN, and `SendError::Shutdown` then lists the outcome of each.
+    pub async fn shutdown(&mut self) -> Result<(), SendError> {
+        self.uncork().await?;
+        self.settle_datagrams().await?;
+        let fin = self.next;
+        self.shutdown_streams(Job::Shutdown(fin)).await
+    }
+
+    /// Cut the byte stream short where the sent data ends and shut down all streams
+    ///
+    /// Every stream carries an abort with `reason`, cut to `MAX_ABORT_REASON_SIZE` bytes, in place of a FIN, so that the receiver reads the data sent and then fails with `RecvError::PeerAborted` instead of ending.
+    /// The data held back by the cork is sent first, and every stream is shut down like in `Self::shutdown`.
+    /// Sends fail with `SendError::Closed` from then on, even if the abort fails.
+    pub async fn abort(&mut self, reason: &str) -> Result<(), SendError> {
+        self.closed = true;
+        self.uncork().await?;
+        self.settle_datagrams().await?;
+        let mut len = reason.len().min(MAX_ABORT_REASON_SIZE);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        let reason = Bytes::copy_from_slice(&reason.as_bytes()[..len]);
+        self.shutdown_streams(Job::Abort(self.next, reason)).await
+    }
+
+    /// Stop accepting new data, deliver what has been sent and shut down all streams
+    ///
+    /// The data held back by the cork and the lost segments are sent, every stream is flushed and carries a FIN.
+    /// With retransmission enabled, it then waits until the receiver acknowledges every byte before the streams are shut down.
+    /// Sends fail with `SendError::Closed` from then on, even if the close fails.
+    ///
+    /// Once `Self::set_close_timeout` passes, the writes in flight are aborted and `CloseError::TimedOut` tells how many bytes might not have been delivered.
+    pub async fn close(&mut self) -> Result<(), CloseError> {
+        if !self.closed {
+            self.uncork().await?;
+            self.closed = true;
+        }
+        let Some(timeout) = self.close_timeout else {
+            return Ok(self.close_gracefully().await?);
+        };
+        match tokio::time::timeout(timeout, self.close_gracefully()).await {
+            Ok(res) => Ok(res?),
+            Err(_) => {
+                self.abort_writes().await;
+                Err(CloseError::TimedOut {
+                    undelivered: self.undelivered(),
+                })
+            }
+        }
+    }
+
+    async fn close_gracefully(&mut self) -> Result<(), SendError> {
+        self.flush().await?;
+        self.retransmit_lost().await?;
+        let fin = self.next;
+        self.for_each_stream(true, Job::Fin(fin)).await?;
+        self.wait_for_ack(fin).await?;
+        self.shutdown_streams(Job::Shutdown(fin)).await
+    }
+
+    /// Shut down every stream with `job`, a `Job::Shutdown` or a `Job::Abort`, whatever happens to the others
+    async fn shutdown_streams(&mut self, job: Job) -> Result<(), SendError> {
+        self.reclaim().await;
+        let options = self.write_options();
+        while let Some(subflow) = self.streams.pop_front() {
+            self.writes.push(subflow, job.clone(), options);
+        }
+
+        let mut outcomes = vec![];
+        while let Some(write) = self.writes.next().await {
+            let (id, label) = (write.1.id, write.1.label.clone());
+            let error = self.settle(write, false).err().map(StreamError::into_error);
+            outcomes.push(ShutdownOutcome { id, label, error });
+        }
+        self.update_tier();
+        if outcomes.iter().all(ShutdownOutcome::is_ok) {
+            return Ok(());
+        }
+        outcomes.sort_by_key(|outcome| outcome.id);
+        Err(SendError::Shutdown(outcomes))
+    }
+
+    /// Wait until the receiver acknowledges every byte before `end`, retransmitting the segments found lost
+    async fn wait_for_ack(&mut self, end: Sequence) -> Result<(), SendError> {
+        loop {
+            self.retransmit_lost().await?;
+            let Some(retransmission) = &self.retransmission else {
+                return Ok(());
+            };
+            if end <= retransmission.ack() {
+                return Ok(());
+            }
+            self.wait_for_acks().await?;
+        }
+    }
+
+    /// Bytes sent and not known to be delivered
+    fn undelivered(&self) -> u64 {
+        let delivered = match &self.retransmission {
+            Some(retransmission) => retransmission.ack(),
+            None => self.flushed,
+        };
+        self.next.inner().saturating_sub(delivered.inner())
+    }
+
+    async fn for_each_stream(&mut self, evict: bool, job: Job) -> Result<(), SendError> {
+        self.reclaim().await;
+        let options = self.write_options();
+        while let Some(subflow) = self.streams.pop_front() {
+            self.writes.push(subflow, job.clone(), options);
+        }
+
+        let mut errors = vec![];
+        while let Some(write) = self.writes.next().await {
+            if let Err(error) = self.settle(write, evict) {
+                errors.push(error);
+            }
+        }
+        self.update_tier();
+        if !errors.is_empty() {
+            return Err(SendError::Io(errors));
+        }
+        Ok(())
+    }
+}
+
+impl<W> Sender<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(streams: Vec<W>) -> Self {
+        Self::with_initial_sequence(streams, Sequence::new(0))
+    }
+
+    /// Resume a byte stream whose bytes before `sequence` have already been sent
+    ///
+    /// Pair it with `Receiver::with_expected_sequence`.
+    pub fn with_initial_sequence(streams: Vec<W>, sequence: Sequence) -> Self {
+        Self::with_streams(streams, sequence)
+    }
+
+    /// Name every stream so that its errors can be told apart, e.g., by the network path it takes
+    pub fn new_with_labels(streams: Vec<(String, W)>) -> Self {
+        let mut this = Self::new(vec![]);
+        for (label, stream) in streams {
+            this.add_stream_with_label(label, stream);
+        }
+        this.tier_changes.clear();
+        this
+    }
+
+    /// Only send data on the live streams of the highest priority
+    ///
+    /// The other streams are kept alive by pings and take over once every stream above them is evicted.
+    pub fn new_with_priorities(streams: Vec<(W, Priority)>) -> Self {
+        let mut this = Self::new(vec![]);
+        for (stream, priority) in streams {
+            this.add_stream_with_priority(priority, stream);
+        }
+        this.tier_changes.clear();
+        this
+    }
+
+    /// Wrap the sender in `PollWrite`
+    ///
+    /// `Sender` implements `AsyncWrite` itself, so this is only kept for the callers that name `PollWrite`.
+    pub fn into_async_write(self) -> PollWrite<Self> {
+        PollWrite::new(self)
+    }
+}
+
+/// A sender of streams that are not `Send`
+///
+/// Use `SenderBuilder::build_local` to configure one.
+impl<W> Sender<W, Local>
+where
+    W: AsyncWrite + Unpin + 'static,
+{
+    /// `Sender::new` for streams that are not `Send`
+    pub fn new_local(streams: Vec<W>) -> Self {
+        Self::with_streams(streams, Sequence::new(0))
+    }
+
+    /// `Sender::into_async_write` for streams that are not `Send`
+    pub fn into_local_async_write(self) -> LocalPollWrite<W> {
+        LocalPollWrite::new(self)
+    }
+}
+
+/// Sends on streams that the caller only lends for the duration of a call, e.g., `&mut` halves inside a larger connection object
+///
+/// These streams need neither be `'static` nor `Send`.
+/// Everything else requires a `Sender` owning its streams with `W: 'static`: it keeps them across calls in the writes it polls, the `AsyncWrite` and `AsyncAsyncWrite` forms lend the sender itself to their futures, `SenderHandle` spawns it on a task and `Self::set_reconnect` dials owned replacements.
+/// Borrowed streams get none of the retransmission, scheduling, pacing or probing of an owning `Sender`.
+impl<W> Sender<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Write the handshake on each of `streams` before their first `Self::batch_send_all_on`
+    pub async fn handshake_on(streams: &mut [W]) -> Result<(), SendError> {
+        let greet = streams
+            .iter_mut()
+            .enumerate()
+            .map(|(index, stream)| async move {
+                let res = async {
+                    Hello::new(0).encode(&mut *stream).await?;
+                    stream.flush().await
+                }
+                .await;
+                res.map_err(|error| StreamError {
+                    id: StreamId(index),
+                    label: None,
+                    sequence: None,
+                    error,
+                })
+            });
+        let errors: Vec<StreamError> = futures_util::future::join_all(greet)
+            .await
+            .into_iter()
+            .filter_map(Result::err)
+            .collect();
+        if !errors.is_empty() {
+            return Err(SendError::Io(errors));
+        }
+        Ok(())
+    }
+
+    /// Send all of `data` on `streams` borrowed for the call as the part of the byte stream starting at `next`, and move `next` past it
+    ///
+    /// The segments are striped across the streams, which write theirs concurrently.
+    /// A stream that fails is not written to for the rest of the call, since it might be left in the middle of a frame, and its segments go to the others.
+    /// Returns the errors of the streams that failed, which the caller is to drop, each with the index of the stream in `streams` as its `StreamId`.
+    /// Returns `SendError::Incomplete` like `Self::batch_send_all` instead once every stream has failed, with `next` back at the start of the remaining data.
+    pub async fn batch_send_all_on(
+        streams: &mut [W],
+        data: Bytes,
+        next: &mut Sequence,
+    ) -> Result<Vec<StreamError>, SendError> {
+        let start = *next;
+        let end = start
+            .checked_add(data.len() as u64)
+            .ok_or(SendError::SequenceExhausted)?;
+        if data.is_empty() {
+            return Ok(vec![]);
+        }
+        if streams.is_empty() {
+            return Err(SendError::NoStreamLeft {
+                streams: 0,
+                errors: vec![],
+            });
+        }
+        let mut send_buf = SendStreamBuf::new(data, start);
+        send_buf.split_first_unsent_segment(streams.len());
+        send_buf.limit_segment_size(DEFAULT_MAX_PAYLOAD_SIZE);
+
+        let mut live = vec![true; streams.len()];
+        let mut errors = vec![];
+        while !send_buf.done() {
+            let live_streams: Vec<usize> = (0..streams.len()).filter(|&i| live[i]).collect();
+            if live_streams.is_empty() {
+                let first_unsent = send_buf.first_unsent_sequence().unwrap();
+                *next = first_unsent;
+                return Err(SendError::Incomplete {
+                    sent: (first_unsent.inner() - start.inner()) as usize,
+                    remaining: send_buf.data_from(first_unsent),
+                    errors,
+                });
+            }
+            let mut assigned = vec![vec![]; streams.len()];
+            for (i, data_segment) in send_buf.iter_unsent_segments().enumerate() {
+                assigned[live_streams[i % live_streams.len()]].push(data_segment);
+            }
+            let writes = streams
+                .iter_mut()
+                .zip(assigned)
+                .enumerate()
+                .filter(|(_, (_, data_segments))| !data_segments.is_empty())
+                .map(|(index, (stream, data_segments))| async move {
+                    let mut written = vec![];
+                    for data_segment in data_segments {
+                        let sequence = data_segment.start_sequence();
+                        let message = Message::DataSegment(data_segment);
+                        if let Err(error) = message.encode(&mut *stream).await {
+                            return (index, Err((sequence, error)));
+                        }
+                        written.push(sequence);
+                    }
+                    match stream.flush().await {
+                        Ok(()) => (index, Ok(written)),
+                        Err(error) => (index, Err((written[0], error))),
+                    }
+                });
+            for (index, res) in futures_util::future::join_all(writes).await {
+                match res {
+                    Ok(written) => {
+                        for sequence in written {
+                            send_buf.mark_as_sent(sequence);
+                        }
+                    }
+                    // What the stream buffered is lost with it, so every segment it took goes to the others
+                    Err((sequence, error)) => {
+                        live[index] = false;
+                        errors.push(StreamError {
+                            id: StreamId(index),
+                            label: None,
+                            sequence: Some(sequence),
+                            error,
+                        });
+                    }
+                }
+            }
+        }
+        *next = end;
+        Ok(errors)
+    }
+}
+
+impl<W> AsyncAsyncWrite for Sender<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    /// Segments might outlive `buf` while being retransmitted, so `buf` is copied
+    ///
+    /// Use `Sender::batch_send_all` to send owned data without copying.
+    async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_copy(buf).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        Self::flush(self).await?;
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        Self::shutdown(self).await?;
+        Ok(())
+    }
+}
+
+/// An `AsyncWrite` operation that owns the `Sender` it runs on
+struct Lent<W, P: sealed::Threading>(Operation, LentOperation<W, P>);
+
+type LentOperation<W, P> = Pin<Box<dyn Future<Output = (Sender<W, P>, io::Result<usize>)> + Send>>;
+
+impl<W, P: sealed::Threading> std::fmt::Debug for Lent<W, P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Lent").field(&self.0).finish()
+    }
+}
+
+impl<W> Sender<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    /// Poll the lent `operation`, lending the sender to it first if none is pending
+    ///
+    /// # Panics
+    ///
+    /// Panics if another operation is pending.
+    fn poll_lent<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        operation: Operation,
+        start: F,
+    ) -> Poll<io::Result<usize>>
+    where
+        F: FnOnce(Sender<W>) -> LentOperation<W, Threaded>,
+    {
+        let Lent(pending, future) = match &mut self.lent {
+            Some(lent) => lent,
+            None => {
+                let sender = std::mem::replace(self, Self::new(vec![]));
+                self.lent.insert(Lent(operation, start(sender)))
+            }
+        };
+        assert_eq!(
+            *pending, operation,
+            "another `AsyncWrite` operation was left pending"
+        );
+        let Poll::Ready((sender, res)) = future.as_mut().poll(cx) else {
+            return Poll::Pending;
+        };
+        *self = sender;
+        Poll::Ready(res)
+    }
+
+    fn poll_write_bytes(&mut self, cx: &mut Context<'_>, data: Bytes) -> Poll<io::Result<usize>> {
+        self.poll_lent(cx, Operation::Write, |mut sender| {
+            Box::pin(async move {
+                let res = sender.write_bytes(data).await;
+                (sender, res)
+            })
+        })
+    }
+
+    /// Start sending `data` in the background unless the sender is busy or full
+    ///
+    /// `data` is handed back in `TrySendError::Full` if a previous submission is still being sent, or if it would overflow the send window or the retransmission limit while data is held for retransmission.
+    /// Otherwise the sender is lent to the submission, which `Self::poll_ready` drives, so its other methods see an empty sender until `Self::poll_ready` returns.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an `AsyncWrite` operation is pending.
+    pub fn try_send(&mut self, data: Bytes) -> Result<(), TrySendError> {
+        match &self.lent {
+            Some(Lent(Operation::Send | Operation::Ready, _)) => {
+                return Err(TrySendError::Full(data));
+            }
+            Some(_) => panic!("another `AsyncWrite` operation was left pending"),
+            None => (),
+        }
+        if self.is_full(data.len()) {
+            return Err(TrySendError::Full(data));
+        }
+        self.lend_send(data);
+        Ok(())
+    }
+
+    /// Lend the sender to sending `data` in the background, to be driven by `Self::poll_ready`
+    fn lend_send(&mut self, data: Bytes) {
+        let sender = std::mem::replace(self, Self::new(vec![]));
+        let submission: LentOperation<W, Threaded> = Box::pin(async move {
+            let mut sender = sender;
+            let len = data.len();
+            let res = sender.send_written(data).await.map(|()| len);
+            (sender, res.map_err(io::Error::from))
+        });
+        self.lent = Some(Lent(Operation::Send, submission));
+    }
+
+    /// Drive the submission of `Self::try_send` or the wait of `Self::poll_ready` left pending, if any
+    pub(crate) fn poll_submitted(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(Lent(operation @ (Operation::Send | Operation::Ready), _)) = &self.lent {
+            let operation = *operation;
+            ready!(self.poll_lent(cx, operation, |_| unreachable!()))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Drive the submission of `Self::try_send` and wait until the sender has room for more
+    ///
+    /// Fails with the error of the submission, which is then lost.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an `AsyncWrite` operation is pending.
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(Lent(Operation::Send, _)) = &self.lent {
+            ready!(self.poll_lent(cx, Operation::Send, |_| unreachable!()))?;
+        }
+        if self.lent.is_none() && !self.is_full(1) {
+            return Poll::Ready(Ok(()));
+        }
+        let res = self.poll_lent(cx, Operation::Ready, |mut sender| {
+            Box::pin(async move {
+                let res = sender.wait_until_not_full().await;
+                (sender, res.map(|()| 0).map_err(io::Error::from))
+            })
+        });
+        res.map_ok(|_| ())
+    }
+
+    /// Whether `bytes` more would overflow the send window or the retransmission limit
+    fn is_full(&mut self, bytes: usize) -> bool {
+        self.process_acks();
+        let resident = self.resident_bytes() + self.staged.len();
+        if resident == 0 {
+            return false;
+        }
+        let limits = [
+            self.send_window.map(|window| window.get()),
+            self.retransmission.as_ref().map(|r| r.limit.get()),
+        ];
+        limits
+            .into_iter()
+            .flatten()
+            .any(|limit| limit < resident + bytes)
+    }
+
+    async fn wait_until_not_full(&mut self) -> Result<(), SendError> {
+        while self.is_full(1) {
+            if self.retransmission.is_none() {
+                return Ok(());
+            }
+            self.wait_for_acks().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Each poll accepts only the bytes that made it into the send buffer, up to the room in the send window and at most a chunk of `Sender::set_send_chunk_size`
+///
+/// While a poll is pending, the sender is lent to the operation it started and its other methods see an empty sender.
+/// Poll the same operation to completion before using them again.
+impl<W> AsyncWrite for Sender<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    /// Segments might outlive `buf` while being retransmitted, so `buf` is copied
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() && this.lent.is_none() {
+            return Poll::Ready(Ok(0));
+        }
+        let len = buf.len().min(this.send_chunk_size.get());
+        this.poll_write_bytes(cx, Bytes::copy_from_slice(&buf[..len]))
+    }
+
+    /// Coalesce `bufs` like `Sender::write_vectored`
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if bufs.iter().all(|buf| buf.is_empty()) && this.lent.is_none() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut data = BytesMut::new();
+        if this.lent.is_none() {
+            let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+            let len = len.min(this.send_chunk_size.get());
+            data.reserve(len);
+            for buf in bufs {
+                let n = buf.len().min(len - data.len());
+                data.extend_from_slice(&buf[..n]);
+            }
+        }
+        this.poll_write_bytes(cx, data.freeze())
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = self
+            .get_mut()
+            .poll_lent(cx, Operation::Flush, |mut sender| {
+                Box::pin(async move {
+                    let res = Sender::flush(&mut sender).await.map(|()| 0);
+                    (sender, res.map_err(io::Error::from))
+                })
+            });
+        res.map_ok(|_| ())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = self
+            .get_mut()
+            .poll_lent(cx, Operation::Shutdown, |mut sender| {
+                Box::pin(async move {
+                    let res = Sender::shutdown(&mut sender).await.map(|()| 0);
+                    (sender, res.map_err(io::Error::from))
+                })
+            });
+        res.map_ok(|_| ())
+    }
+}
+
+/// `Sender::try_send` and `Sender::poll_ready` as a `Sink`, e.g., for `StreamExt::forward`
+///
+/// A chunk is sent in the background until the next `Sink::poll_ready`, which waits for it and then for room in the send window or the retransmission limit.
+/// `Sink::start_send` takes any chunk after a ready `Sink::poll_ready`, even one larger than the room left.
+/// `Sink::poll_flush` flushes every stream like `Sender::flush`, and `Sink::poll_close` closes the sender like `Sender::close`.
+impl<W> Sink<Bytes> for Sender<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Sender::poll_ready(self.get_mut(), cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> io::Result<()> {
+        let this = self.get_mut();
+        if this.lent.is_some() {
+            return Err(io::Error::other(
+                "`Sink::start_send` before a ready `Sink::poll_ready`",
+            ));
+        }
+        this.lend_send(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_submitted(cx))?;
+        AsyncWrite::poll_flush(Pin::new(this), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_submitted(cx))?;
+        let res = this.poll_lent(cx, Operation::Close, |mut sender| {
+            Box::pin(async move {
+                let res = sender.close().await.map(|()| 0);
+                (sender, res.map_err(io::Error::from))
+            })
+        });
+        res.map_ok(|_| ())
+    }
+}
+
+/// Drive a `Sender` whose streams are not `Send` through `AsyncWrite`
+///
+/// Unlike `PollWrite`, the operations in flight need not be `Send`, so it is not `Send` either.
+pub struct LocalPollWrite<W> {
+    sender: Option<Sender<W, Local>>,
+    pending: Option<(Operation, LocalOperation<W>)>,
+}
+
+type LocalOperation<W> = Pin<Box<dyn Future<Output = (Sender<W, Local>, io::Result<usize>)>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Write,
+    Flush,
+    Shutdown,
+    /// A submission of `Sender::try_send`
+    Send,
+    /// Waiting for room in `Sender::poll_ready`
+    Ready,
+    /// `Sink::poll_close`
+    Close,
+}
+
+impl<W> LocalPollWrite<W> {
+    pub fn new(sender: Sender<W, Local>) -> Self {
+        Self {
+            sender: Some(sender),
+            pending: None,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if an `AsyncWrite` operation was left pending.
+    pub fn into_inner(self) -> Sender<W, Local> {
+        self.sender
+            .expect("an `AsyncWrite` operation was left pending")
+    }
+
+    /// # Panics
+    ///
+    /// Panics if an `AsyncWrite` operation was left pending.
+    pub fn inner(&self) -> &Sender<W, Local> {
+        self.sender
+            .as_ref()
+            .expect("an `AsyncWrite` operation was left pending")
+    }
+
+    /// # Panics
+    ///
+    /// Panics if an `AsyncWrite` operation was left pending.
+    pub fn inner_mut(&mut self) -> &mut Sender<W, Local> {
+        self.sender
+            .as_mut()
+            .expect("an `AsyncWrite` operation was left pending")
+    }
+
+    /// Poll the pending `operation`, starting it first if none is pending
+    ///
+    /// # Panics
+    ///
+    /// Panics if another operation is pending.
+    fn poll_operation<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        operation: Operation,
+        start: F,
+    ) -> Poll<io::Result<usize>>
+    where
+        F: FnOnce(Sender<W, Local>) -> LocalOperation<W>,
+    {
+        let (pending, future) = match &mut self.pending {
+            Some(pending) => pending,
+            None => {
+                let sender = self.sender.take().unwrap();
+                self.pending.insert((operation, start(sender)))
+            }
+        };
+        assert_eq!(
+            *pending, operation,
+            "another `AsyncWrite` operation was left pending"
+        );
+        let Poll::Ready((sender, res)) = future.as_mut().poll(cx) else {
+            return Poll::Pending;
+        };
+        self.pending = None;
+        self.sender = Some(sender);
+        Poll::Ready(res)
+    }
+}
+
+impl<W> std::fmt::Debug for LocalPollWrite<W>
+where
+    W: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalPollWrite")
+            .field("sender", &self.sender)
+            .field("pending", &self.pending.as_ref().map(|(op, _)| op))
+            .finish()
+    }
+}
+
+impl<W> AsyncWrite for LocalPollWrite<W>
+where
+    W: AsyncWrite + Unpin + 'static,
+{
+    /// Segments might outlive `buf` while being retransmitted, so `buf` is copied
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_operation(cx, Operation::Write, |mut sender| {
+                let len = buf.len().min(sender.send_chunk_size.get());
+                let data = Bytes::copy_from_slice(&buf[..len]);
+                Box::pin(async move {
+                    let res = sender.write_bytes(data).await;
+                    (sender, res)
+                })
+            })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = self
+            .get_mut()
+            .poll_operation(cx, Operation::Flush, |mut sender| {
+                Box::pin(async move {
+                    let res = sender.flush().await.map(|()| 0);
+                    (sender, res.map_err(io::Error::from))
+                })
+            });
+        res.map_ok(|_| ())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = self
+            .get_mut()
+            .poll_operation(cx, Operation::Shutdown, |mut sender| {
+                Box::pin(async move {
+                    let res = sender.shutdown().await.map(|()| 0);
+                    (sender, res.map_err(io::Error::from))
+                })
+            });
+        res.map_ok(|_| ())
+    }
+}
+
+/// Configures a `Sender` before the streams are added
+///
+/// The defaults are those of `Sender::new`.
+#[derive(Debug)]
+pub struct SenderBuilder {
+    initial_sequence: Sequence,
+    labels: Vec<String>,
+    priorities: Vec<Priority>,
+    write_timeout: Option<Duration>,
+    send_deadline: Option<Duration>,
+    write_buffer: Option<NonZeroUsize>,
+    cork: Option<Cork>,
+    max_segment_size: Option<NonZeroUsize>,
+    min_segment_size: Option<NonZeroUsize>,
+    send_chunk_size: NonZeroUsize,
+    checksum: bool,
+    compact_headers: bool,
+    compression: Compression,
+    sequence_width: SeqWidth,
+    session: Option<Session>,
+    keepalive: Option<Duration>,
+    send_window: Option<NonZeroUsize>,
+    retransmission: Option<(watch::Receiver<Sequence>, NonZeroUsize)>,
+    scheduler: Box<dyn Scheduler>,
+    decision_log: usize,
+    send_mode: SendMode,
+    close_timeout: Option<Duration>,
+    zero_stream_grace: Option<Duration>,
+    retransmission_timeout: Option<Duration>,
+    stall_reassignment: bool,
+    stall_multiplier: f64,
+    rtt_probes: Option<(mpsc::UnboundedReceiver<ProbeFrame>, Duration)>,
+    feedback: Option<(mpsc::UnboundedReceiver<FeedbackFrame>, Duration)>,
+    control_stream: Option<PendingControl>,
+    failure_policy: Arc<dyn FailurePolicy>,
+    max_write_retries: u32,
+    allow_retransmit: bool,
+    pacing: Option<Duration>,
+    passthrough: bool,
+}
+
+impl SenderBuilder {
+    pub fn new() -> Self {
+        Self {
+            initial_sequence: Sequence::new(0),
+            labels: Vec::new(),
+            priorities: Vec::new(),
+            write_timeout: None,
+            send_deadline: None,
+            write_buffer: None,
+            cork: None,
+            max_segment_size: None,
+            min_segment_size: None,
+            send_chunk_size: NonZeroUsize::new(DEFAULT_SEND_CHUNK_SIZE).unwrap(),
+            checksum: false,
+            compact_headers: false,
+            compression: Compression::None,
+            sequence_width: SeqWidth::default(),
+            session: None,
+            keepalive: None,
+            send_window: None,
+            retransmission: None,
+            scheduler: Box::new(RoundRobin),
+            decision_log: 0,
+            send_mode: SendMode::default(),
+            close_timeout: None,
+            zero_stream_grace: None,
+            retransmission_timeout: None,
+            stall_reassignment: false,
+            stall_multiplier: DEFAULT_STALL_MULTIPLIER,
+            rtt_probes: None,
+            feedback: None,
+            control_stream: None,
+            failure_policy: Arc::new(DefaultFailurePolicy),
+            max_write_retries: DEFAULT_MAX_WRITE_RETRIES,
+            allow_retransmit: false,
+            pacing: None,
+            passthrough: false,
+        }
+    }
+
+    /// See `Sender::with_initial_sequence`
+    pub fn initial_sequence(mut self, sequence: Sequence) -> Self {
+        self.initial_sequence = sequence;
+        self
+    }
+
+    /// Labels of the streams, indexed like the streams passed to `Self::build`
+    pub fn labels(mut self, labels: Vec<String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Priorities of the streams, indexed like the streams passed to `Self::build`
+    ///
+    /// Streams without one get `Priority::default()`. See `Sender::new_with_priorities`.
+    pub fn priorities(mut self, priorities: Vec<Priority>) -> Self {
+        self.priorities = priorities;
+        self
+    }
+
+    /// See `Sender::set_write_timeout`
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// See `Sender::set_send_deadline`
+    pub fn send_deadline(mut self, deadline: Duration) -> Self {
+        self.send_deadline = Some(deadline);
+        self
+    }
+
+    /// See `Sender::set_write_buffer`
+    pub fn write_buffer(mut self, capacity: NonZeroUsize) -> Self {
+        self.write_buffer = Some(capacity);
+        self
+    }
+
+    /// See `Sender::set_cork`
+    pub fn cork(mut self, cork: Cork) -> Self {
+        self.cork = Some(cork);
+        self
+    }
+
+    /// See `Sender::set_max_segment_size`
+    pub fn max_segment_size(mut self, size: NonZeroUsize) -> Self {
+        self.max_segment_size = Some(size);
+        self
+    }
+
+    /// See `Sender::set_min_segment_size`
+    pub fn min_segment_size(mut self, size: NonZeroUsize) -> Self {
+        self.min_segment_size = Some(size);
+        self
+    }
+
+    /// See `Sender::set_send_chunk_size`
+    pub fn send_chunk_size(mut self, size: NonZeroUsize) -> Self {
+        self.send_chunk_size = size;
+        self
+    }
+
+    /// See `Sender::set_checksum`
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// See `Sender::set_compact_headers`
+    pub fn compact_headers(mut self, compact: bool) -> Self {
+        self.compact_headers = compact;
+        self
+    }
+
+    /// See `Sender::set_compression`
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// See `Sender::set_sequence_width`
+    pub fn sequence_width(mut self, width: SeqWidth) -> Self {
+        self.sequence_width = width;
+        self
+    }
+
+    /// See `Sender::set_session`
+    pub fn session(mut self, session: Session) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// See `Sender::set_keepalive`
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// See `Sender::set_send_window`
+    pub fn send_window(mut self, window: NonZeroUsize) -> Self {
+        self.send_window = Some(window);
+        self
+    }
+
+    /// See `Sender::enable_retransmission`
+    pub fn retransmission(mut self, acks: watch::Receiver<Sequence>, limit: NonZeroUsize) -> Self {
+        self.retransmission = Some((acks, limit));
+        self
+    }
+
+    /// See `Sender::set_scheduler`
+    pub fn scheduler(mut self, scheduler: impl Scheduler + 'static) -> Self {
+        self.scheduler = Box::new(scheduler);
+        self
+    }
+
+    /// See `Sender::set_decision_log`
+    pub fn decision_log(mut self, capacity: usize) -> Self {
+        self.decision_log = capacity;
+        self
+    }
+
+    /// See `Sender::set_send_mode`
+    pub fn send_mode(mut self, mode: SendMode) -> Self {
+        self.send_mode = mode;
+        self
+    }
+
+    /// See `Sender::set_close_timeout`
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = Some(timeout);
+        self
+    }
+
+    /// See `Sender::set_zero_stream_grace`
+    pub fn zero_stream_grace(mut self, grace: Duration) -> Self {
+        self.zero_stream_grace = Some(grace);
+        self
+    }
+
+    /// See `Sender::set_retransmission_timeout`
+    pub fn retransmission_timeout(mut self, timeout: Duration) -> Self {
+        self.retransmission_timeout = Some(timeout);
+        self
+    }
+
+    /// See `Sender::set_stall_reassignment`
+    pub fn stall_reassignment(mut self, enabled: bool) -> Self {
+        self.stall_reassignment = enabled;
+        self
+    }
+
+    /// See `Sender::set_stall_multiplier`
+    pub fn stall_multiplier(mut self, multiplier: f64) -> Self {
+        self.stall_multiplier = multiplier;
+        self
+    }
+
+    /// See `Sender::enable_rtt_probes`
+    pub fn rtt_probes(
+        mut self,
+        pongs: mpsc::UnboundedReceiver<ProbeFrame>,
+        interval: Duration,
+    ) -> Self {
+        self.rtt_probes = Some((pongs, interval));
+        self
+    }
+
+    /// See `Sender::enable_feedback`
+    pub fn feedback(
+        mut self,
+        frames: mpsc::UnboundedReceiver<FeedbackFrame>,
+        interval: Duration,
+    ) -> Self {
+        self.feedback = Some((frames, interval));
+        self
+    }
+
+    /// See `Sender::set_control_stream`
+    pub fn control_stream(mut self, stream: impl ControlStream) -> Self {
+        self.control_stream = Some(PendingControl::new(stream));
+        self
+    }
+
+    /// See `Sender::set_failure_policy`
+    pub fn failure_policy(mut self, policy: impl FailurePolicy + 'static) -> Self {
+        self.failure_policy = Arc::new(policy);
+        self
+    }
+
+    /// See `Sender::set_max_write_retries`
+    pub fn max_write_retries(mut self, retries: u32) -> Self {
+        self.max_write_retries = retries;
+        self
+    }
+
+    /// See `Sender::set_allow_retransmit`
+    pub fn allow_retransmit(mut self, allow: bool) -> Self {
+        self.allow_retransmit = allow;
+        self
+    }
+
+    /// See `Sender::set_pacing`
+    pub fn pacing(mut self, target: Duration) -> Self {
+        self.pacing = Some(target);
+        self
+    }
+
+    /// See `Sender::set_passthrough`
+    pub fn passthrough(mut self, passthrough: bool) -> Self {
+        self.passthrough = passthrough;
+        self
+    }
+
+    pub fn build<W>(self, streams: Vec<W>) -> Sender<W>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        self.build_with(streams)
+    }
+
+    /// `Self::build` for streams that are not `Send`
+    pub fn build_local<W>(self, streams: Vec<W>) -> Sender<W, Local>
+    where
+        W: AsyncWrite + Unpin + 'static,
+    {
+        self.build_with(streams)
+    }
+
+    fn build_with<W, P>(self, streams: Vec<W>) -> Sender<W, P>
+    where
+        W: AsyncWrite + Unpin + 'static,
+        P: sealed::BoxWrite<W>,
+    {
+        let mut sender = Sender::with_streams(vec![], self.initial_sequence);
+        sender.set_write_timeout(self.write_timeout);
+        sender.set_send_deadline(self.send_deadline);
+        sender.set_write_buffer(self.write_buffer);
+        sender.set_cork(self.cork);
+        sender.set_max_segment_size(self.max_segment_size);
+        sender.set_min_segment_size(self.min_segment_size);
+        sender.set_send_chunk_size(self.send_chunk_size);
+        sender.set_checksum(self.checksum);
+        sender.set_compact_headers(self.compact_headers);
+        sender.set_compression(self.compression);
+        sender.set_sequence_width(self.sequence_width);
+        sender.set_session(self.session);
+        sender.set_keepalive(self.keepalive);
+        sender.set_send_window(self.send_window);
+        if let Some((acks, limit)) = self.retransmission {
+            sender.enable_retransmission(acks, limit);
+        }
+        sender.scheduler = self.scheduler;
+        sender.set_decision_log(self.decision_log);
+        sender.set_send_mode(self.send_mode);
+        sender.set_close_timeout(self.close_timeout);
+        sender.set_zero_stream_grace(self.zero_stream_grace);
+        sender.set_retransmission_timeout(self.retransmission_timeout);
+        sender.set_stall_reassignment(self.stall_reassignment);
+        sender.set_stall_multiplier(self.stall_multiplier);
+        if let Some((pongs, interval)) = self.rtt_probes {
+            sender.enable_rtt_probes(pongs, interval);
+        }
+        if let Some((frames, interval)) = self.feedback {
+            sender.enable_feedback(frames, interval);
+        }
+        if let Some(stream) = self.control_stream.and_then(|control| control.take()) {
+            sender.set_control_stream(stream);
+        }
+        sender.failure_policy = self.failure_policy;
+        sender.set_max_write_retries(self.max_write_retries);
+        sender.set_allow_retransmit(self.allow_retransmit);
+        sender.set_pacing(self.pacing);
+        sender.set_passthrough(self.passthrough);
+
+        let mut labels = self.labels.into_iter();
+        let mut priorities = self.priorities.into_iter();
+        for stream in streams {
+            let label = labels.next().map(Arc::from);
+            let priority = priorities.next().unwrap_or_default();
+            sender.add_subflow(label, priority, stream);
+        }
+        sender.tier_changes.clear();
+        sender
+    }
+}
+
+impl Default for SenderBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The range of the segment written, if any, and the outcome
+type WriteResult<W> = (Option<Range<Sequence>>, Subflow<W>, io::Result<()>);
+
+/// How `Sender::set_reconnect` re-dials an evicted stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Dials to try before giving up on the stream
+    pub max_attempts: u32,
+    /// The wait after the first failed dial, doubled after every failed dial since
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Adds streams to the pool of a `Sender` from another task, see `Sender::pool_handle`
+#[derive(Debug)]
+pub struct PoolHandle<W> {
+    added: mpsc::UnboundedSender<W>,
+}
+
+impl<W> PoolHandle<W> {
+    /// Add `stream` to the pool by the next round of the sender, or right away if a send waits for a stream
+    ///
+    /// Returns the stream back if the sender is gone.
+    pub fn add_stream(&self, stream: W) -> Result<(), W> {
+        self.added.send(stream).map_err(|e| e.0)
+    }
+}
+
+impl<W> Clone for PoolHandle<W> {
+    fn clone(&self) -> Self {
+        Self {
+            added: self.added.clone(),
+        }
+    }
+}
+
+/// Follows the data queued by `Sender::submit`
+#[derive(Debug, Clone)]
+pub struct SendTicket {
+    sequences: Range<Sequence>,
+    progress: ProgressHandle,
+}
+
+impl SendTicket {
+    /// The sequences the data takes up in the byte stream
+    pub fn sequences(&self) -> Range<Sequence> {
+        self.sequences.clone()
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress.progress()
+    }
+
+    /// Whether every byte has been written, and none found lost since
+    pub fn is_sent(&self) -> bool {
+        let progress = self.progress();
+        progress.sent_bytes == progress.total_bytes
+    }
+}
+
+/// What a round of `Sender::drive_once` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DriveProgress {
+    /// Payload bytes of the submissions written by the round
+    pub sent_bytes: usize,
+    /// Submissions left with unsent bytes
+    pub pending: usize,
+}
+
+/// When `Sender::set_cork` sends the data held back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cork {
+    /// Bytes to hold back at most
+    pub size: usize,
+    /// How long to hold back the data before the next write sends it
+    pub delay: Duration,
+}
+
+impl Default for Cork {
+    fn default() -> Self {
+        Self {
+            size: 1 << 14,
+            delay: Duration::from_millis(1),
+        }
+    }
+}
+
+type PendingRedial<W> = Pin<Box<dyn Future<Output = Redialed<W>> + Send>>;
+
+struct Reconnect<W> {
+    /// Start re-dialing an evicted stream
+    #[allow(clippy::type_complexity)]
+    redial: Box<
+        dyn Fn(
+                broadcast::Sender<SubflowEvent>,
+                StreamId,
+                Option<Arc<str>>,
+                Priority,
+            ) -> PendingRedial<W>
+            + Send,
+    >,
+    pending: FuturesUnordered<PendingRedial<W>>,
+}
+
+/// The stream dialed to replace the evicted stream `id`, if any
+struct Redialed<W> {
+    id: StreamId,
+    label: Option<Arc<str>>,
+    priority: Priority,
+    stream: Option<W>,
+}
+
+/// Dial a replacement for the evicted stream `id` with backoff as `policy` says
+async fn redial_stream<W, F, Fut>(
+    policy: ReconnectPolicy,
+    redial: F,
+    events: broadcast::Sender<SubflowEvent>,
+    id: StreamId,
+    label: Option<Arc<str>>,
+    priority: Priority,
+) -> Redialed<W>
+where
+    F: Fn(StreamId) -> Fut,
+    Fut: Future<Output = io::Result<W>>,
+{
+    let emit = |event| {
+        if events.receiver_count() > 0 {
+            let _ = events.send(event);
+        }
+    };
+    let mut backoff = policy.initial_backoff;
+    let mut stream = None;
+    for attempt in 1..=policy.max_attempts {
+        emit(SubflowEvent::ReconnectAttempt { id, attempt });
+        let dial = async {
+            #[cfg(feature = "failpoints")]
+            crate::failpoints::hit(crate::failpoints::RECONNECT_DIAL).await?;
+            redial(id).await
+        };
+        match dial.await {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(error) => {
+                let error = Arc::new(error);
+                emit(SubflowEvent::ReconnectFailed { id, attempt, error });
+            }
+        }
+        if attempt < policy.max_attempts {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(policy.max_backoff);
+        }
+    }
+    Redialed {
+        id,
+        label,
+        priority,
+        stream,
+    }
+}
+
+impl<W> std::fmt::Debug for Reconnect<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reconnect")
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+/// The writes of a `Sender` of `Send` streams, which is `Send` too
+#[derive(Debug)]
+pub enum Threaded {}
+
+/// The writes of a `Sender` of streams that are not `Send`, which is not `Send` either
+#[derive(Debug)]
+pub enum Local {}
+
+mod sealed {
+    use super::*;
+
+    /// The arguments of `run`
+    pub struct Start<W> {
+        pub(super) subflow: Subflow<W>,
+        pub(super) job: Job,
+        pub(super) options: WriteOptions,
+        pub(super) abort: watch::Receiver<bool>,
+    }
+
+    pub struct Finished<W>(pub(super) WriteResult<W>);
+
+    /// How the writes in flight are boxed
+    pub trait Threading {
+        /// A write that owns its stream until it finishes
+        type Pending<W>: Future<Output = Finished<W>> + Unpin;
+    }
+
+    pub trait BoxWrite<W>: Threading {
+        fn boxed(start: Start<W>) -> Self::Pending<W>;
+    }
+}
+
+impl sealed::Threading for Threaded {
+    type Pending<W> = Pin<Box<dyn Future<Output = sealed::Finished<W>> + Send>>;
+}
+
+impl<W> sealed::BoxWrite<W> for Threaded
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    fn boxed(start: sealed::Start<W>) -> Self::Pending<W> {
+        Box::pin(async { sealed::Finished(start.run().await) })
+    }
+}
+
+impl sealed::Threading for Local {
+    type Pending<W> = Pin<Box<dyn Future<Output = sealed::Finished<W>>>>;
+}
+
+impl<W> sealed::BoxWrite<W> for Local
+where
+    W: AsyncWrite + Unpin + 'static,
+{
+    fn boxed(start: sealed::Start<W>) -> Self::Pending<W> {
+        Box::pin(async { sealed::Finished(start.run().await) })
+    }
+}
+
+impl<W> sealed::Start<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    async fn run(self) -> WriteResult<W> {
+        run(self.subflow, self.job, self.options, self.abort).await
+    }
+}
+
+/// The buffers of a round of `Sender::batch_send_with_mode`, kept empty across rounds so that their allocations are reused
+#[derive(Debug)]
+struct Scratch<W> {
+    offered: Vec<usize>,
+    segments: Vec<DataSegment>,
+    segment_meta: Vec<SegmentMeta>,
+    stream_meta: Vec<StreamMeta>,
+    subflows: Vec<Option<Subflow<W>>>,
+    copies: Vec<usize>,
+    claims: Vec<Arc<Claim>>,
+}
+
+impl<W> Default for Scratch<W> {
+    fn default() -> Self {
+        Self {
+            offered: Vec::new(),
+            segments: Vec::new(),
+            segment_meta: Vec::new(),
+            stream_meta: Vec::new(),
+            subflows: Vec::new(),
+            copies: Vec::new(),
+            claims: Vec::new(),
+        }
+    }
+}
+
+impl<W> Scratch<W> {
+    fn clear(&mut self) {
+        self.offered.clear();
+        self.segments.clear();
+        self.segment_meta.clear();
+        self.stream_meta.clear();
+        self.subflows.clear();
+        self.copies.clear();
+        self.claims.clear();
+    }
+}
+
+/// The writes in flight, each one a `run` future
+struct Writes<W, P: sealed::Threading> {
+    pending: FuturesUnordered<P::Pending<W>>,
+    /// The streams of `pending`
+    streams: Vec<StreamId>,
+    /// Tells the writes to give up
+    abort: watch::Sender<bool>,
+}
+
+impl<W, P: sealed::Threading> std::fmt::Debug for Writes<W, P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Writes")
+            .field("pending", &self.pending.len())
+            .field("streams", &self.streams)
+            .finish()
+    }
+}
+
+impl<W, P> Writes<W, P>
+where
+    W: AsyncWrite + Unpin + 'static,
+    P: sealed::BoxWrite<W>,
+{
+    fn new() -> Self {
+        Self {
+            pending: FuturesUnordered::new(),
+            streams: Vec::new(),
+            abort: watch::channel(false).0,
+        }
+    }
+
+    fn push(&mut self, subflow: Subflow<W>, job: Job, options: WriteOptions) {
+        self.streams.push(subflow.id);
+        let abort = self.abort.subscribe();
+        self.pending.push(P::boxed(sealed::Start {
+            subflow,
+            job,
+            options,
+            abort,
+        }));
+    }
+
+    /// Make every write in flight finish right away, failing those not done yet
+    ///
+    /// The writes pushed later are not affected.
+    async fn abort(&mut self) -> Vec<WriteResult<W>> {
+        self.abort.send_replace(true);
+        let mut results = vec![];
+        while let Some(result) = self.next().await {
+            results.push(result);
+        }
+        self.abort.send_replace(false);
+        results
+    }
+
+    /// The next write to finish, or `None` if there is none in flight
+    async fn next(&mut self) -> Option<WriteResult<W>> {
+        std::future::poll_fn(|cx| self.poll_next(cx)).await
+    }
+
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<WriteResult<W>>> {
+        let res = ready!(self.pending.poll_next_unpin(cx)).map(|sealed::Finished(res)| res);
+        if let Some((_, subflow, _)) = &res {
+            self.streams.retain(|&id| id != subflow.id);
+        }
+        Poll::Ready(res)
+    }
+
+    fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn contains(&self, id: StreamId) -> bool {
+        self.streams.contains(&id)
+    }
+}
+
+/// What a write does with its stream
+#[derive(Debug, Clone)]
+enum Job {
+    Segment(DataSegment, Arc<Claim>),
+    Ping,
+    /// Ping unless the stream has been written to within the interval
+    Heartbeat(Duration),
+    Ack(Sequence),
+    Control(Bytes),
+    Oob(u32, Bytes),
+    Feedback(Vec<SubflowScore>),
+    Greet,
+    Flush,
+    /// Probe unless the stream has been probed within the interval
+    Probe {
+        interval: Duration,
+        epoch: Instant,
+    },
+    Pong(u64),
+    /// Announce the end of the data and flush
+    Fin(Sequence),
+    Shutdown(Sequence),
+    /// Cut the byte stream short at the sequence and shut down
+    Abort(Sequence, Bytes),
+}
+
+/// Do `job` on the stream of `subflow` unless told to `abort` first
+async fn run<W>(
+    mut subflow: Subflow<W>,
+    job: Job,
+    options: WriteOptions,
+    mut abort: watch::Receiver<bool>,
+) -> WriteResult<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    let segment = match &job {
+        Job::Segment(data_segment, claim) => Some((data_segment.start_sequence(), claim.clone())),
+        _ => None,
+    };
+    let (sequence, res) = tokio::select! {
+        biased;
+        res = run_job(&mut subflow, job, options) => res,
+        _ = abort.wait_for(|&abort| abort) => {
+            // The frame being written might be torn
+            let sequence = segment.map(|(start, claim)| start..claim.close());
+            let error = io::Error::new(io::ErrorKind::TimedOut, "write aborted");
+            (sequence, Err(error))
+        }
+    };
+    (sequence, subflow, res)
+}
+
+async fn run_job<W>(
+    subflow: &mut Subflow<W>,
+    job: Job,
+    options: WriteOptions,
+) -> (Option<Range<Sequence>>, io::Result<()>)
+where
+    W: AsyncWrite + Unpin,
+{
+    let message = match job {
+        Job::Segment(data_segment, claim) => {
+            return write_segment(subflow, data_segment, claim, options).await;
+        }
+        Job::Ping => Message::Ping,
+        Job::Heartbeat(idle) => {
+            if subflow.last_write.elapsed() < idle {
+                return (None, Ok(()));
+            }
+            subflow.stats.heartbeats += 1;
+            Message::Ping
+        }
+        Job::Ack(ack) => Message::Ack(ack),
+        Job::Probe { interval, epoch } => {
+            if subflow
+                .last_probe
+                .is_some_and(|probe| probe.elapsed() < interval)
+            {
+                return (None, Ok(()));
+            }
+            let timestamp = epoch.elapsed().as_micros() as u64;
+            subflow.last_probe = Some(Instant::now());
+            if subflow.probes.len() == MAX_OUTSTANDING_PROBES {
+                subflow.probes.pop_front();
+            }
+            subflow.probes.push_back(timestamp);
+            Message::Probe(timestamp)
+        }
+        Job::Pong(timestamp) => Message::Pong(timestamp),
+        Job::Control(payload) => Message::Control(payload),
+        Job::Oob(sequence, payload) => Message::Oob { sequence, payload },
+        Job::Feedback(scores) => Message::Feedback(scores),
+        Job::Greet => return (None, subflow.greet(options, options.passthrough).await),
+        Job::Flush => return (None, subflow.stream.flush().await),
+        Job::Fin(fin) => return (None, subflow.fin(fin, options).await),
+        Job::Shutdown(fin) => return (None, subflow.shutdown(fin, options).await),
+        Job::Abort(sequence, reason) => {
+            return (None, subflow.abort(sequence, reason, options).await);
+        }
+    };
+    let res = subflow.write(&message, options).await;
+    if res.is_ok() {
+        subflow.last_write = Instant::now();
+    }
+    (None, res.map(drop))
+}
+
+/// The part of a segment that its write task has not taken yet
+#[derive(Debug)]
+struct Claim {
+    rest: Mutex<Range<Sequence>>,
+    /// Goodput of the stream writing the segment when the write began
+    goodput: Option<f64>,
+}
+
+impl Claim {
+    fn new<W>(data_segment: &DataSegment, subflow: &Subflow<W>) -> Arc<Self> {
+        let rest = data_segment.start_sequence()..data_segment.end_sequence();
+        Arc::new(Self {
+            rest: Mutex::new(rest),
+            goodput: subflow.stats.goodput,
+        })
+    }
+
+    fn remaining(&self) -> u64 {
+        let rest = self.rest.lock().unwrap();
+        rest.end.inner() - rest.start.inner()
+    }
+
+    /// Take the next `size` bytes at most
+    fn take(&self, size: usize) -> Option<Range<Sequence>> {
+        let mut rest = self.rest.lock().unwrap();
+        if rest.is_empty() {
+            return None;
+        }
+        let end = Sequence::new(
+            rest.end
+                .inner()
+                .min(rest.start.inner().saturating_add(size as u64)),
+        );
+        let chunk = rest.start..end;
+        rest.start = end;
+        Some(chunk)
+    }
+
+    /// Give up `share` of the rest if both parts are `min` bytes at least
+    fn split(&self, share: f64, min: usize) -> Option<Sequence> {
+        let mut rest = self.rest.lock().unwrap();
+        let remaining = rest.end.inner() - rest.start.inner();
+        let given = (remaining as f64 * share) as u64;
+        let kept = remaining - given.min(remaining);
+        if given < min as u64 || kept < min as u64 {
+            return None;
+        }
+        let mid = Sequence::new(rest.start.inner() + kept);
+        rest.end = mid;
+        Some(mid)
+    }
+
+    /// Give the bytes not taken yet back and return the end of the part taken
+    fn stop(&self) -> Sequence {
+        let mut rest = self.rest.lock().unwrap();
+        rest.end = rest.start;
+        rest.start
+    }
+
+    /// Stop taking bytes and return the end of the part taken
+    fn close(&self) -> Sequence {
+        let mut rest = self.rest.lock().unwrap();
+        rest.start = rest.end;
+        rest.end
+    }
+}
+
+/// Write `data_segment` up to where its claim ends, or the budget of the stream runs out, in frames of `CHUNK_SIZE` at most
+async fn write_segment<W>(
+    subflow: &mut Subflow<W>,
+    data_segment: DataSegment,
+    claim: Arc<Claim>,
+    options: WriteOptions,
+) -> (Option<Range<Sequence>>, io::Result<()>)
+where
+    W: AsyncWrite + Unpin,
+{
+    let start_sequence = data_segment.start_sequence();
+    let start = Instant::now();
+    let budget = match options.pacing {
+        Some(_) => *subflow.stats.budget.get_or_insert(INITIAL_BUDGET),
+        None => usize::MAX,
+    };
+    let mut written = 0;
+    let res = async {
+        #[cfg(feature = "failpoints")]
+        crate::failpoints::hit(crate::failpoints::SEND_SEGMENT_WRITE).await?;
+        while written < budget {
+            let Some(chunk) = claim.take(subflow.max_frame_payload().min(budget - written)) else {
+                break;
+            };
+            let offset = (chunk.start.inner() - start_sequence.inner()) as usize;
+            let size = (chunk.end.inner() - chunk.start.inner()) as usize;
+            let payload = data_segment.payload().slice(offset..offset + size);
+            if subflow
+                .write_passthrough(chunk.start, &payload, options)
+                .await?
+            {
+                written += size;
+                continue;
+            }
+            let message = Message::DataSegment(DataSegment::new(chunk.start, payload).unwrap());
+            let on_wire = subflow.write(&message, options).await?;
+            if options.encode.compression != Compression::None {
+                subflow.stats.record_compression(size, on_wire);
+            }
+            written += size;
+        }
+        Ok(())
+    }
+    .await;
+    // The whole segment counts as one sample so that the goodput does not suffer from the framing
+    if res.is_ok() {
+        subflow.stats.record_write(written, start.elapsed());
+        subflow.last_write = Instant::now();
+        trace::debug_event!(
+            stream_id = subflow.id.inner(),
+            sequence = start_sequence.inner(),
+            len = written,
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "segment_completed"
+        );
+    }
+    let end = match written < budget {
+        true => claim.close(),
+        false => claim.stop(),
+    };
+    if let (Some(target), Some(budget)) = (options.pacing, &mut subflow.stats.budget) {
+        if res.is_err() || start.elapsed() > target {
+            *budget = (*budget / 2).max(BUDGET_STEP);
+        } else if written >= *budget {
+            *budget += BUDGET_STEP;
+        }
+    }
+
+    (Some(start_sequence..end), res)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct WriteOptions {
+    encode: EncodeOptions,
+    timeout: Option<Duration>,
+    /// Advertised in the handshake
+    capabilities: u32,
+    session: Option<Session>,
+    feedback_interval: Option<Duration>,
+    pacing: Option<Duration>,
+    /// Where the data would start on a stream greeted for passthrough, if it may be
+    passthrough: Option<Sequence>,
+}
+
+async fn with_timeout<F, T>(timeout: Option<Duration>, write: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    let Some(timeout) = timeout else {
+        return write.await;
+    };
+    tokio::time::timeout(timeout, write)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "write timed out"))?
+}
+
+#[derive(Debug)]
+struct Subflow<W> {
+    id: StreamId,
+    label: Option<Arc<str>>,
+    /// Unbuffered without a write buffer
+    stream: FrameWriter<W>,
+    write_buffer: Option<usize>,
+    stats: StreamStats,
+    last_write: Instant,
+    /// Whether the handshake has been written
+    greeted: bool,
+    header: HeaderContext,
+    /// Ranges of the segments written and not known to be acknowledged, with when they were written
+    unacked: Vec<(Range<Sequence>, Instant)>,
+    /// Smoothed time from writing a segment to its acknowledgement
+    ack_rtt: Option<Duration>,
+    /// Whether a segment went unacknowledged past its timeout since the stream last had one acknowledged
+    timed_out: bool,
+    /// Whether a segment stalled under `Sender::set_stall_reassignment` since the stream last had one or a probe answered
+    stalled: bool,
+    /// The largest frame of a datagram stream
+    mtu: Option<usize>,
+    /// The largest frame the peer accepts, see `Sender::set_max_frame_size`
+    max_frame_size: Option<usize>,
+    /// Advertised in the handshake
+    advertised_max_frame_size: Option<u32>,
+    /// The FIN written, if any
+    fin: Option<Sequence>,
+    last_probe: Option<Instant>,
+    /// The timestamps of the probes not answered yet, from the oldest
+    probes: VecDeque<u64>,
+    /// The sequence of the next passthrough byte, until the stream switches to frames
+    passthrough: Option<Sequence>,
+}
+
+impl<W> Subflow<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Write the handshake unless written already, for passthrough bytes from `passthrough` if any
+    async fn greet(
+        &mut self,
+        options: WriteOptions,
+        passthrough: Option<Sequence>,
+    ) -> io::Result<()> {
+        if self.greeted {
+            return Ok(());
+        }
+        let mut hello = Hello::new(options.capabilities);
+        // A lost datagram would leave the bytes after it out of place
+        let passthrough = passthrough.filter(|_| self.mtu.is_none());
+        if let Some(start) = passthrough {
+            hello = hello.with_passthrough(start);
+        }
+        if let Some(size) = self.advertised_max_frame_size {
+            hello = hello.with_max_frame_size(size);
+        }
+        if let Some(session) = options.session {
+            hello = hello.with_subflow(session, self.id.inner() as u32);
+        }
+        if let Some(interval) = options.feedback_interval {
+            hello = hello.with_feedback_interval(interval);
+        }
+        self.stream.begin(Frame::Hello)?;
+        with_timeout(options.timeout, hello.encode(&mut self.stream)).await?;
+        self.stream.end();
+        if self.mtu.is_some() {
+            // A datagram of its own
+            with_timeout(options.timeout, self.stream.flush()).await?;
+        }
+        self.greeted = true;
+        self.passthrough = passthrough;
+        Ok(())
+    }
+
+    /// Write `payload` from `start` as passthrough bytes, if they carry on those written so far and `options` still let them
+    ///
+    /// Returns `false` without writing anything otherwise.
+    async fn write_passthrough(
+        &mut self,
+        start: Sequence,
+        payload: &Bytes,
+        options: WriteOptions,
+    ) -> io::Result<bool> {
+        self.greet(options, options.passthrough.map(|_| start))
+            .await?;
+        if options.passthrough.is_none() || self.passthrough != Some(start) {
+            return Ok(false);
+        }
+        let escaped = escape_passthrough(payload);
+        self.stream
+            .begin(Frame::Passthrough(start, escaped.clone()))?;
+        with_timeout(options.timeout, self.stream.write_all(&escaped)).await?;
+        self.stream.end();
+        self.passthrough = start.checked_add(payload.len() as u64);
+        Ok(true)
+    }
+
+    /// Switch from passthrough bytes to frames, if not done yet
+    async fn switch_to_frames(&mut self, options: WriteOptions) -> io::Result<()> {
+        if self.passthrough.is_none() {
+            return Ok(());
+        }
+        self.stream.begin(Frame::Switch)?;
+        let switch = [PASSTHROUGH_ESCAPE, PASSTHROUGH_SWITCH];
+        with_timeout(options.timeout, self.stream.write_all(&switch)).await?;
+        self.stream.end();
+        self.passthrough = None;
+        Ok(())
+    }
+
+    /// How long a segment written now would take to be acknowledged, zero without an estimate
+    fn expected_delivery(&self) -> Duration {
+        let rtt = self.stats.rtt.or(self.ack_rtt).unwrap_or(Duration::ZERO);
+        let queued = match self.stats.goodput {
+            Some(goodput) if goodput > 0.0 => self.unacked_bytes() as f64 / goodput,
+            _ => 0.0,
+        };
+        rtt + Duration::from_secs_f64(queued.min(MAX_QUEUE_ESTIMATE.as_secs_f64()))
+    }
+
+    /// Drop the unacknowledged segments that `acked` tells, and sample the time their acknowledgement took
+    ///
+    /// Returns whether any was dropped.
+    fn release(&mut self, acked: impl Fn(&Range<Sequence>) -> bool) -> bool {
+        let now = Instant::now();
+        let mut sample = None;
+        self.unacked.retain(|(sequence, written)| {
+            if !acked(sequence) {
+                return true;
+            }
+            // The latest segment acknowledged waited the least on those before it
+            sample = Some(now - *written);
+            false
+        });
+        let Some(sample) = sample else {
+            return false;
+        };
+        self.ack_rtt = Some(match self.ack_rtt {
+            Some(rtt) => rtt.mul_f64(1.0 - RTT_SMOOTHING) + sample.mul_f64(RTT_SMOOTHING),
+            None => sample,
+        });
+        true
+    }
+
+    /// Bytes of the segments written and not known to be acknowledged
+    fn unacked_bytes(&self) -> u64 {
+        self.unacked
+            .iter()
+            .map(|(range, _)| range.end.inner() - range.start.inner())
+            .sum()
+    }
+
+    /// When the unacknowledged segment carrying `sequence` was written, if the stream carries it
+    fn written_at(&self, sequence: Sequence) -> Option<Instant> {
+        self.unacked
+            .iter()
+            .find(|(range, _)| range.contains(&sequence))
+            .map(|(_, written)| *written)
+    }
+
+    /// How long its segments may go unacknowledged given those of datagram and reliable streams
+    fn loss_timeout(&self, datagram: Duration, reliable: Option<Duration>) -> Option<Duration> {
+        match self.mtu {
+            Some(_) => Some(datagram),
+            None => reliable,
+        }
+    }
+
+    fn max_frame_payload(&self) -> usize {
+        [self.mtu, self.max_frame_size, self.write_buffer]
+            .into_iter()
+            .flatten()
+            .map(|frame| frame - DATA_SEGMENT_OVERHEAD)
+            .fold(CHUNK_SIZE, usize::min)
+    }
+
+    /// Write `message`, preceded by the handshake if this is the first one
+    ///
+    /// Writing the message torn by a failed write again resumes it. See `FrameWriter`.
+    /// Returns the payload bytes of a data segment as they went on the wire.
+    async fn write(&mut self, message: &Message, options: WriteOptions) -> io::Result<usize> {
+        self.greet(options, None).await?;
+        self.switch_to_frames(options).await?;
+        let mut encode_options = options.encode;
+        encode_options.compact &= self.mtu.is_none();
+        self.stream
+            .begin(Frame::Message(message.clone(), encode_options))?;
+        let encode = message.encode_counted(&mut self.stream, encode_options, &mut self.header);
+        let written = with_timeout(options.timeout, encode).await?;
+        self.stream.end();
+        Ok(written)
+    }
+
+    async fn fin(&mut self, fin: Sequence, options: WriteOptions) -> io::Result<()> {
+        if self.fin != Some(fin) {
+            self.write(&Message::Fin(fin), options).await?;
+            self.fin = Some(fin);
+        }
+        with_timeout(options.timeout, self.stream.flush()).await
+    }
+
+    async fn shutdown(&mut self, fin: Sequence, options: WriteOptions) -> io::Result<()> {
+        let frames = async {
+            if self.fin != Some(fin) {
+                self.write(&Message::Fin(fin), options).await?;
+            }
+            self.write(&Message::Shutdown, options).await
+        }
+        .await;
+        // Shut down even a stream that could not carry the frames
+        let shutdown = self.stream.shutdown().await;
+        frames.and(shutdown)
+    }
+
+    async fn abort(
+        &mut self,
+        sequence: Sequence,
+        reason: Bytes,
+        options: WriteOptions,
+    ) -> io::Result<()> {
+        let frames = async {
+            self.write(&Message::Abort { sequence, reason }, options)
+                .await?;
+            self.write(&Message::Shutdown, options).await
+        }
+        .await;
+        let shutdown = self.stream.shutdown().await;
+        frames.and(shutdown)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StreamStats {
+    id: StreamId,
+    live: bool,
+    bytes_written: u64,
+    segments_written: u64,
+    last_write_latency: Option<Duration>,
+    errors: u64,
+    /// Smoothed bytes per second
+    goodput: Option<f64>,
+    priority: Priority,
+    rtt: Option<Duration>,
+    retransmitted_segments: u64,
+    retransmitted_bytes: u64,
+    stalls: u64,
+    budget: Option<usize>,
+    uncompressed_bytes: u64,
+    compressed_bytes: u64,
+    heartbeats: u64,
+    feedback: Option<SubflowScore>,
+    /// Smoothed gaps per feedback
+    feedback_gaps: Option<f64>,
+}
+
+impl StreamStats {
+    pub(crate) fn new(id: StreamId) -> Self {
+        Self {
+            id,
+            live: true,
+            bytes_written: 0,
+            segments_written: 0,
+            last_write_latency: None,
+            errors: 0,
+            goodput: None,
+            priority: Priority::default(),
+            rtt: None,
+            retransmitted_segments: 0,
+            retransmitted_bytes: 0,
+            stalls: 0,
+            budget: None,
+            uncompressed_bytes: 0,
+            compressed_bytes: 0,
+            heartbeats: 0,
+            feedback: None,
+            feedback_gaps: None,
+        }
+    }
+
+    pub(crate) fn record_feedback(&mut self, score: SubflowScore) {
+        let sample = score.gaps() as f64;
+        let gaps = match self.feedback_gaps {
+            Some(gaps) => gaps + FEEDBACK_SMOOTHING * (sample - gaps),
+            None => sample,
+        };
+        self.feedback = Some(score);
+        self.feedback_gaps = Some(gaps);
+    }
+
+    pub(crate) fn record_rtt(&mut self, sample: Duration) {
+        let rtt = match self.rtt {
+            Some(rtt) => rtt.mul_f64(1.0 - RTT_SMOOTHING) + sample.mul_f64(RTT_SMOOTHING),
+            None => sample,
+        };
+        self.rtt = Some(rtt);
+    }
+
+    pub(crate) fn record_compression(&mut self, payload: usize, on_wire: usize) {
+        self.uncompressed_bytes += payload as u64;
+        self.compressed_bytes += on_wire as u64;
+    }
+
+    pub(crate) fn record_write(&mut self, bytes: usize, elapsed: Duration) {
+        self.bytes_written += bytes as u64;
+        self.segments_written += 1;
+        self.last_write_latency = Some(elapsed);
+
+        if elapsed.is_zero() {
+            return;
+        }
+        let sample = bytes as f64 / elapsed.as_secs_f64();
+        let goodput = match self.goodput {
+            Some(goodput) => goodput + GOODPUT_SMOOTHING * (sample - goodput),
+            None => sample,
+        };
+        self.goodput = Some(goodput);
+    }
+
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Whether the stream is still in the pool
+    pub fn live(&self) -> bool {
+        self.live
+    }
+
+    /// Payload bytes of the data segments written
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn segments_written(&self) -> u64 {
+        self.segments_written
+    }
+
+    /// How long the last data segment took to write
+    pub fn last_write_latency(&self) -> Option<Duration> {
+        self.last_write_latency
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Smoothed payload bytes per second
+    pub fn goodput(&self) -> Option<f64> {
+        self.goodput
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Smoothed round-trip time measured by the probes of `Sender::enable_rtt_probes`
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Segments written again on this stream after they were lost on another or timed out
+    pub fn retransmitted_segments(&self) -> u64 {
+        self.retransmitted_segments
+    }
+
+    /// Payload bytes of `Self::retransmitted_segments`
+    pub fn retransmitted_bytes(&self) -> u64 {
+        self.retransmitted_bytes
+    }
+
+    /// Segments that stalled on this stream and were written again on another, see `Sender::set_stall_reassignment`
+    pub fn stalls(&self) -> u64 {
+        self.stalls
+    }
+
+    /// The bytes the stream may take per write under `Sender::set_pacing`, once it has written
+    pub fn budget(&self) -> Option<usize> {
+        self.budget
+    }
+
+    /// Payload bytes of the data segments written under `Sender::set_compression`
+    pub fn uncompressed_bytes(&self) -> u64 {
+        self.uncompressed_bytes
+    }
+
+    /// What `Self::uncompressed_bytes` took on the wire, counting the payloads sent as they are
+    pub fn compressed_bytes(&self) -> u64 {
+        self.compressed_bytes
+    }
+
+    /// `Self::uncompressed_bytes` over `Self::compressed_bytes`, 1 for incompressible data
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.compressed_bytes > 0)
+            .then(|| self.uncompressed_bytes as f64 / self.compressed_bytes as f64)
+    }
+
+    /// Heartbeats of `Sender::heartbeat` written on this stream
+    pub fn heartbeats(&self) -> u64 {
+        self.heartbeats
+    }
+
+    /// The latest score of the stream in the feedback of `Sender::enable_feedback`
+    pub fn feedback(&self) -> Option<SubflowScore> {
+        self.feedback
+    }
+
+    /// The gaps of `Self::feedback` smoothed over the feedback so far
+    pub fn feedback_gaps(&self) -> Option<f64> {
+        self.feedback_gaps
+    }
+}
+
+/// How long a stream goes without a write before a heartbeat is sent on it under a keepalive of `interval`
+fn keepalive_idle(interval: Duration) -> Duration {
+    interval - interval / KEEPALIVE_COALESCING
+}
+
+/// The wakeups of `Sender::heartbeat` since `Sender::set_keepalive`
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveStats {
+    since: Instant,
+    wakeups: u64,
+}
+
+impl KeepaliveStats {
+    fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            wakeups: 0,
+        }
+    }
+
+    /// Calls of `Sender::heartbeat`, each of which sends the heartbeats of every stream due
+    pub fn wakeups(&self) -> u64 {
+        self.wakeups
+    }
+
+    /// Wakeups per second since the keepalive was set
+    pub fn wakeup_rate(&self) -> f64 {
+        self.wakeups as f64 / self.since.elapsed().as_secs_f64()
+    }
+}
+
+/// Streams of a higher priority are preferred over the rest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
+pub struct Priority(u8);
+
+impl Priority {
+    pub const BACKUP: Self = Self(0);
+    pub const PRIMARY: Self = Self(1);
+
+    pub fn new(priority: u8) -> Self {
+        Self(priority)
+    }
+
+    pub fn inner(&self) -> u8 {
+        self.0
+    }
+}
+
+/// The data moved to the streams of another priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TierChange {
+    pub from: Option<Priority>,
+    pub to: Option<Priority>,
+    pub reason: TierChangeReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TierChangeReason {
+    /// Every stream of the active tier was evicted
+    Failover,
+    /// A stream of a higher priority was added
+    Failback,
+}
+
+/// Something that happened to a stream of a `Sender`
+#[derive(Debug, Clone)]
+pub enum SubflowEvent {
+    Added {
+        id: StreamId,
+    },
+    /// A write failed with `error`
+    Failed {
+        id: StreamId,
+        error: Arc<io::Error>,
+    },
+    /// A write took longer than `Sender::set_write_timeout`
+    WriteTimeout {
+        id: StreamId,
+    },
+    /// The stream was evicted after a failed write, or taken out by `Sender::remove_stream`
+    Removed {
+        id: StreamId,
+    },
+    /// The segment starting at `sequence` stalled on the stream and is written again on another, see `Sender::set_stall_reassignment`
+    Stalled {
+        id: StreamId,
+        sequence: Sequence,
+    },
+    /// Dialing a replacement for the evicted stream `id`, see `Sender::set_reconnect`
+    ReconnectAttempt {
+        id: StreamId,
+        attempt: u32,
+    },
+    /// A dial for the evicted stream `id` failed with `error`
+    ///
+    /// The stream is given up on after the last attempt of the policy.
+    ReconnectFailed {
+        id: StreamId,
+        attempt: u32,
+        error: Arc<io::Error>,
+    },
+    /// `replacement` took the place of the evicted stream `id`
+    Reconnected {
+        id: StreamId,
+        replacement: StreamId,
+    },
+}
+
+/// Identifies a stream by the order it was added to a `Sender`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
+pub struct StreamId(usize);
+
+impl StreamId {
+    pub fn new(number: usize) -> Self {
+        Self(number)
+    }
+
+    pub fn inner(&self) -> usize {
+        self.0
+    }
+}
+
+/// Where a stream of a `Sender` is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    /// In the pool, waiting for the next write
+    Idle,
+    /// Writing on behalf of a call that was cancelled, until the next call joins it
+    InFlight,
+    Evicted,
+}
+
+/// An I/O error on one of the streams
+#[derive(Debug)]
+pub struct StreamError {
+    id: StreamId,
+    label: Option<Arc<str>>,
+    sequence: Option<Sequence>,
+    error: io::Error,
+}
+
+impl StreamError {
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// The start of the segment being written when the error happened
+    pub fn sequence(&self) -> Option<Sequence> {
+        self.sequence
+    }
+
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    pub fn into_error(self) -> io::Error {
+        self.error
+    }
+}
+
+impl std::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stream {}", self.id.inner())?;
+        if let Some(label) = &self.label {
+            write!(f, " ({label})")?;
+        }
+        if let Some(sequence) = self.sequence {
+            write!(f, " at sequence {}", sequence.inner())?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// How the shutdown of one of the streams went, see `SendError::Shutdown`
+#[derive(Debug)]
+pub struct ShutdownOutcome {
+    id: StreamId,
+    label: Option<Arc<str>>,
+    error: Option<io::Error>,
+}
+
+impl ShutdownOutcome {
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Why the stream failed to shut down, or failed to carry the FIN before, if it did
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl std::fmt::Display for ShutdownOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stream {}", self.id.inner())?;
+        if let Some(label) = &self.label {
+            write!(f, " ({label})")?;
+        }
+        match &self.error {
+            Some(error) => write!(f, ": {error}"),
+            None => write!(f, ": shut down"),
+        }
+    }
+}
+
+fn display_outcomes(outcomes: &[ShutdownOutcome]) -> String {
+    let outcomes: Vec<String> = outcomes.iter().map(|o| o.to_string()).collect();
+    outcomes.join("; ")
+}
+
+fn display_errors(errors: &[StreamError]) -> String {
+    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    errors.join("; ")
+}
+
+/// `Sender::try_send` did not take the data
+#[derive(Debug, Error)]
+pub enum TrySendError {
+    #[error("Sender is full")]
+    Full(Bytes),
+}
+
+impl TrySendError {
+    /// The data handed back
+    pub fn into_inner(self) -> Bytes {
+        match self {
+            Self::Full(data) => data,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SendError {
+    /// Every one of the `streams` ever added has been evicted
+    ///
+    /// `errors` holds the evictions not taken by `Sender::take_evicted_streams` yet.
+    #[error("No stream left of {streams}: [{}]", display_errors(errors))]
+    NoStreamLeft {
+        streams: usize,
+        errors: Vec<StreamError>,
+    },
+    #[error("Stream I/O errors: [{}]", display_errors(.0))]
+    Io(Vec<StreamError>),
+    /// Some of the streams failed to shut down, with the outcome of every stream attempted by ID
+    #[error("Shutdown failed: [{}]", display_outcomes(.0))]
+    Shutdown(Vec<ShutdownOutcome>),
+    #[error("Sequence space exhausted")]
+    SequenceExhausted,
+    #[error("Acknowledgements stopped while waiting for room to retransmit")]
+    AcksClosed,
+    /// The payload of a control frame was longer than `MAX_CONTROL_PAYLOAD_SIZE`
+    #[error("Control payload of {0} bytes is too large")]
+    ControlTooLarge(usize),
+    /// The payload of an out-of-band message was longer than `MAX_OOB_PAYLOAD_SIZE`
+    #[error("Out-of-band payload of {0} bytes is too large")]
+    OobTooLarge(usize),
+    /// The streams kept failing after the first `sent` bytes of the data were written
+    ///
+    /// `remaining` is the rest of the data, held back by the cork included, and the next sequence is back at its start.
+    /// Resubmit it, e.g., once `Sender::add_stream` has replaced the evicted streams, to carry on the byte stream without a gap.
+    /// Parts of it might have been written already, which the receiver drops as duplicates.
+    #[error("Gave up after sending {sent} bytes: [{}]", display_errors(errors))]
+    Incomplete {
+        sent: usize,
+        remaining: Bytes,
+        errors: Vec<StreamError>,
+    },
+    /// The deadline passed after `bytes_sent` bytes of the data were handed to the streams
+    #[error("Deadline exceeded after sending {bytes_sent} bytes")]
+    DeadlineExceeded { bytes_sent: usize },
+    /// The task behind a `SenderHandle` has stopped, for the reason its `JoinHandle` returns
+    #[error("Sender task stopped")]
+    Stopped,
+    /// `Sender::close` has been called
+    #[error("Sender closed")]
+    Closed,
+    /// A segment passed to `Sender::send_segments` could not be sent as it is
+    #[error("Invalid segment at {sequence}")]
+    InvalidSegment { sequence: Sequence },
+}
+
+#[derive(Debug, Error)]
+pub enum CloseError {
+    /// The close timeout passed with `undelivered` bytes not acknowledged, or not flushed without retransmission
+    #[error("Timed out with {undelivered} bytes not known to be delivered")]
+    TimedOut { undelivered: u64 },
+    #[error(transparent)]
+    Send(#[from] SendError),
+}
+
+/// `Sender::remove_stream` or `crate::receiver::Receiver::remove_stream` did not hand the stream back
+#[derive(Debug, Error)]
+pub enum RemoveError {
+    /// Never added, evicted or removed already
+    #[error("No such live stream")]
+    UnknownStream,
+    /// No other live stream would be left to carry the data
+    #[error("Refusing to remove the last live stream")]
+    LastStream,
+    /// The stream ended without a shutdown from the peer, which may have left data on it
+    #[error("Stream ended without a shutdown")]
+    Ended,
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+/// `Sender::flush_stream` could not get hold of the stream
+#[derive(Debug, Error)]
+pub enum FlushStreamError {
+    /// Never added or evicted
+    #[error("No live stream of ID {}", .0.inner())]
+    UnknownStream(StreamId),
+    /// See `StreamState::InFlight`
+    #[error("Stream of ID {} is in flight", .0.inner())]
+    InFlight(StreamId),
+}
+
+impl From<FlushStreamError> for io::Error {
+    fn from(e: FlushStreamError) -> Self {
+        let kind = match e {
+            FlushStreamError::UnknownStream(_) => io::ErrorKind::NotFound,
+            FlushStreamError::InFlight(_) => io::ErrorKind::ResourceBusy,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+impl From<CloseError> for io::Error {
+    fn from(e: CloseError) -> Self {
+        match e {
+            CloseError::TimedOut { .. } => io::Error::new(io::ErrorKind::TimedOut, e),
+            CloseError::Send(e) => e.into(),
+        }
+    }
+}
+
+impl From<SendError> for io::Error {
+    fn from(e: SendError) -> Self {
+        let kind = match &e {
+            SendError::NoStreamLeft { .. }
+            | SendError::AcksClosed
+            | SendError::Stopped
+            | SendError::Closed => io::ErrorKind::BrokenPipe,
+            SendError::SequenceExhausted => io::ErrorKind::Other,
+            SendError::ControlTooLarge(_)
+            | SendError::OobTooLarge(_)
+            | SendError::InvalidSegment { .. } => io::ErrorKind::InvalidInput,
+            SendError::DeadlineExceeded { .. } => io::ErrorKind::TimedOut,
+            SendError::Io(errors) | SendError::Incomplete { errors, .. } => errors
+                .first()
+                .map(|e| e.error.kind())
+                .unwrap_or(io::ErrorKind::Other),
+            SendError::Shutdown(outcomes) => outcomes
+                .iter()
+                .find_map(|o| o.error.as_ref())
+                .map_or(io::ErrorKind::Other, io::Error::kind),
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{ready, Context, Poll},
+    };
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use crate::{
+        failure::FailureAction,
+        receiver::{Receiver, ReceiverBuilder},
+    };
+
+    use super::*;
+
+    /// Delays every write to `inner` by `delay`
+    #[derive(Debug)]
+    struct DelayedWriter {
+        inner: DuplexStream,
+        delay: Duration,
+        sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+    }
+
+    impl DelayedWriter {
+        fn new(inner: DuplexStream, delay: Duration) -> Self {
+            Self {
+                inner,
+                delay,
+                sleep: None,
+            }
+        }
+    }
+
+    impl AsyncWrite for DelayedWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let delay = self.delay;
+            let sleep = self
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(delay)));
+            ready!(sleep.as_mut().poll(cx));
+            let res = ready!(Pin::new(&mut self.inner).poll_write(cx, buf));
+            self.sleep = None;
+            Poll::Ready(res)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Fails every write after `budget` bytes have been written
+    #[derive(Debug)]
+    struct FlakyWriter {
+        inner: DuplexStream,
+        budget: usize,
+    }
+
+    impl FlakyWriter {
+        fn new(inner: DuplexStream, budget: usize) -> Self {
+            Self { inner, budget }
+        }
+    }
+
+    impl AsyncWrite for FlakyWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.budget == 0 {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "flaky")));
+            }
+            let n = buf.len().min(self.budget);
+            let res = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..n]));
+            if let Ok(n) = res {
+                self.budget -= n;
+            }
+            Poll::Ready(res)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Fails the first `failures` writes with `kind` before passing the others through
+    #[derive(Debug)]
+    struct FailingWriter {
+        inner: DuplexStream,
+        kind: io::ErrorKind,
+        failures: usize,
+    }
+
+    impl FailingWriter {
+        fn new(inner: DuplexStream, kind: io::ErrorKind, failures: usize) -> Self {
+            Self {
+                inner,
+                kind,
+                failures,
+            }
+        }
+    }
+
+    impl AsyncWrite for FailingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Poll::Ready(Err(io::Error::new(self.kind, "failing")));
+            }
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Accepts `budget` bytes, fails the next write and passes the others through
+    #[derive(Debug)]
+    struct TearingWriter {
+        inner: DuplexStream,
+        budget: Option<usize>,
+    }
+
+    impl TearingWriter {
+        fn new(inner: DuplexStream, budget: usize) -> Self {
+            Self {
+                inner,
+                budget: Some(budget),
+            }
+        }
+    }
+
+    impl AsyncWrite for TearingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let Some(budget) = self.budget else {
+                return Pin::new(&mut self.inner).poll_write(cx, buf);
+            };
+            if budget == 0 {
+                self.budget = None;
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "torn")));
+            }
+            let n = buf.len().min(budget);
+            let res = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..n]));
+            if let Ok(n) = res {
+                self.budget = Some(budget - n);
+            }
+            Poll::Ready(res)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Accepts every write whole and counts the calls, failing the flushes if told to
+    #[derive(Debug, Default)]
+    struct CountingWriter {
+        writes: Arc<AtomicUsize>,
+        bytes: Vec<u8>,
+        fail_flush: bool,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            self.bytes.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            if self.fail_flush {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    /// Takes `delay` to complete every flush
+    #[derive(Debug)]
+    struct SlowFlushWriter {
+        delay: Duration,
+        sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+        flushes: usize,
+    }
+
+    impl SlowFlushWriter {
+        fn new(delay: Duration) -> Self {
+            Self {
+                delay,
+                sleep: None,
+                flushes: 0,
+            }
+        }
+    }
+
+    impl AsyncWrite for SlowFlushWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let delay = self.delay;
+            let sleep = self
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(delay)));
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+            self.flushes += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    /// Writes at most `bytes_per_ms` bytes per millisecond
+    #[derive(Debug)]
+    struct ThrottledWriter {
+        bytes_per_ms: usize,
+        sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+        written: Arc<AtomicUsize>,
+    }
+
+    impl ThrottledWriter {
+        fn new(bytes_per_ms: usize) -> Self {
+            Self {
+                bytes_per_ms,
+                sleep: None,
+                written: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    impl AsyncWrite for ThrottledWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(self.bytes_per_ms);
+            let sleep = self
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(Duration::from_millis(1))));
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+            self.written.fetch_add(n, Ordering::Relaxed);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Shares its buffer through an `Rc`, so it is not `Send`
+    #[derive(Debug, Default, Clone)]
+    struct RcWriter {
+        written: std::rc::Rc<std::cell::RefCell<Vec<u8>>>,
+    }
+
+    impl AsyncWrite for RcWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.written.borrow_mut().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Panics on the first write
+    #[derive(Debug)]
+    struct PanickingWriter;
+
+    impl AsyncWrite for PanickingWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            panic!("broken writer");
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Never completes a write
+    #[derive(Debug)]
+    struct StalledWriter;
+
+    impl AsyncWrite for StalledWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    /// Loses every write and fails everything once `dead` is set
+    #[derive(Debug)]
+    struct BlackHoleWriter {
+        dead: Arc<AtomicBool>,
+    }
+
+    impl BlackHoleWriter {
+        fn check(&self) -> io::Result<()> {
+            if self.dead.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "dead"));
+            }
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for BlackHoleWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(self.check().map(|()| buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(self.check())
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(self.check())
+        }
+    }
+
+    type BoxWriter = Pin<Box<dyn AsyncWrite + Send>>;
+
+    #[tokio::test]
+    async fn retransmit_failed_segment() {
+        let budgets = [usize::MAX, 100, usize::MAX];
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for budget in budgets {
+            let (tx, rx) = tokio::io::duplex(1 << 16);
+            send_streams.push(FlakyWriter::new(tx, budget));
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+        let mut receiver = Receiver::new(recv_streams).into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 16).map(|i| i as u8).collect();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![0; 1 << 16];
+            receiver.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        sender
+            .batch_send_all(Bytes::from(msg.clone()))
+            .await
+            .unwrap();
+        assert_eq!(sender.live_streams(), 2);
+
+        let buf = recv_task.await.unwrap();
+        assert_eq!(buf, msg);
+    }
+
+    #[tokio::test]
+    async fn evict_dead_stream() {
+        let budgets = [usize::MAX, 0, usize::MAX];
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for budget in budgets {
+            let (tx, rx) = tokio::io::duplex(1 << 16);
+            send_streams.push(FlakyWriter::new(tx, budget));
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+        let mut receiver = Receiver::new(recv_streams).into_async_read();
+        assert_eq!(sender.live_streams(), 3);
+
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(sender.live_streams(), 2);
+        let evicted = sender.take_evicted_streams();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].id(), StreamId::new(1));
+        assert_eq!(evicted[0].error().kind(), io::ErrorKind::BrokenPipe);
+
+        sender
+            .batch_send_all(Bytes::from_static(b" world"))
+            .await
+            .unwrap();
+        assert_eq!(sender.live_streams(), 2);
+        assert!(sender.take_evicted_streams().is_empty());
+
+        let mut buf = [0; 11];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn add_stream_mid_transfer() {
+        let (tx, rx) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::new(vec![tx]);
+        let mut receiver = Receiver::new(vec![rx]).into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 15).map(|i| i as u8).collect();
+        sender
+            .batch_send_all(Bytes::from(msg.clone()))
+            .await
+            .unwrap();
+        let mut buf = vec![0; msg.len()];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+
+        let (tx, mut rx) = tokio::io::duplex(1 << 16);
+        let id = sender.add_stream(tx);
+        assert_eq!(id, StreamId::new(1));
+        assert_eq!(sender.live_streams(), 2);
+
+        sender
+            .batch_send_all(Bytes::from(msg.clone()))
+            .await
+            .unwrap();
+        Hello::decode(&mut rx).await.unwrap();
+        let Message::DataSegment(data_segment) = Message::decode(&mut rx).await.unwrap() else {
+            panic!("expected a data segment");
+        };
+        assert!(data_segment.size() > 0);
+        assert!(data_segment.size() < msg.len());
+    }
+
+    #[tokio::test]
+    async fn poll_write_owns_written_data() {
+        let (tx, rx) = tokio::io::duplex(1 << 16);
+        let mut async_write = Sender::new(vec![tx]).into_async_write();
+        let mut receiver = Receiver::new(vec![rx]).into_async_read();
+
+        let mut msg = b"hello world".to_vec();
+        async_write.write_all(&msg).await.unwrap();
+        msg.fill(0);
+        drop(msg);
+
+        let mut buf = [0; 11];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn flush_concurrently() {
+        const DELAY: Duration = Duration::from_millis(100);
+        let streams = (0..4).map(|_| SlowFlushWriter::new(DELAY)).collect();
+        let mut sender = Sender::new(streams);
+
+        let start = Instant::now();
+        sender.flush().await.unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= DELAY);
+        assert!(elapsed < DELAY * 2);
+        assert!(sender
+            .streams
+            .iter()
+            .all(|s| s.stream.get_ref().flushes == 1));
+    }
+
+    /// The flushes of every stream in the pool, ordered by ID
+    fn flushes(sender: &Sender<SlowFlushWriter>) -> Vec<usize> {
+        let mut flushes: Vec<(StreamId, usize)> = sender
+            .streams
+            .iter()
+            .map(|s| (s.id, s.stream.get_ref().flushes))
+            .collect();
+        flushes.sort_unstable();
+        flushes.into_iter().map(|(_, flushes)| flushes).collect()
+    }
+
+    #[tokio::test]
+    async fn flush_one_stream() {
+        let streams = (0..3)
+            .map(|_| SlowFlushWriter::new(Duration::ZERO))
+            .collect();
+        let mut sender = Sender::new(streams);
+        sender.flush_stream(StreamId::new(1)).await.unwrap();
+        assert_eq!(flushes(&sender), [0, 1, 0]);
+        assert_eq!(sender.live_streams(), 3);
+
+        let err = sender.flush_stream(StreamId::new(3)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = err.into_inner().unwrap().downcast::<FlushStreamError>();
+        assert!(matches!(*err.unwrap(), FlushStreamError::UnknownStream(id) if id.inner() == 3));
+        assert_eq!(sender.stream_state(StreamId::new(3)), None);
+    }
+
+    #[tokio::test]
+    async fn flush_stream_in_flight() {
+        let streams = (0..2)
+            .map(|_| SlowFlushWriter::new(Duration::from_millis(50)))
+            .collect();
+        let mut sender = Sender::new(streams);
+        let res = tokio::time::timeout(Duration::from_millis(10), sender.flush()).await;
+        assert!(res.is_err());
+        assert_eq!(
+            sender.stream_state(StreamId::new(0)),
+            Some(StreamState::InFlight)
+        );
+
+        let err = sender.flush_stream(StreamId::new(0)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        let err = err.into_inner().unwrap().downcast::<FlushStreamError>();
+        assert!(matches!(*err.unwrap(), FlushStreamError::InFlight(_)));
+
+        // Back in the pool once a call joins the cancelled flush
+        sender.flush().await.unwrap();
+        assert_eq!(
+            sender.stream_state(StreamId::new(0)),
+            Some(StreamState::Idle)
+        );
+        sender.flush_stream(StreamId::new(0)).await.unwrap();
+        assert_eq!(flushes(&sender), [3, 2]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_reports_every_stream() {
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for budget in [usize::MAX, 0, 0] {
+            let (tx, rx) = tokio::io::duplex(1 << 16);
+            send_streams.push(FlakyWriter::new(tx, budget));
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+
+        let Err(SendError::Shutdown(outcomes)) = sender.shutdown().await else {
+            panic!("expected shutdown outcomes");
+        };
+        let outcomes: Vec<(StreamId, bool)> =
+            outcomes.iter().map(|o| (o.id(), o.is_ok())).collect();
+        assert_eq!(
+            outcomes,
+            [
+                (StreamId::new(0), true),
+                (StreamId::new(1), false),
+                (StreamId::new(2), false)
+            ]
+        );
+        assert_eq!(sender.live_streams(), 3);
+    }
+
+    /// Takes every write and fails its shutdown if told to, recording whether it was shut down
+    #[derive(Debug)]
+    struct ShutdownWriter {
+        fail: bool,
+        shut_down: Arc<AtomicBool>,
+    }
+
+    impl AsyncWrite for ShutdownWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.shut_down.store(true, Ordering::SeqCst);
+            if self.fail {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "dead")));
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_past_a_failed_stream() {
+        let shut_down: Vec<Arc<AtomicBool>> = (0..3).map(|_| Arc::default()).collect();
+        let send_streams = shut_down
+            .iter()
+            .enumerate()
+            .map(|(index, shut_down)| ShutdownWriter {
+                fail: index == 1,
+                shut_down: shut_down.clone(),
+            })
+            .collect();
+        let mut sender = SenderBuilder::new()
+            .labels(vec!["a".into(), "b".into(), "c".into()])
+            .build(send_streams);
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+
+        // Through `AsyncWrite`, like the shutdown of an `MptcpStream`
+        let err = AsyncWriteExt::shutdown(&mut sender).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(shut_down
+            .iter()
+            .all(|shut_down| shut_down.load(Ordering::SeqCst)));
+        let err = err.into_inner().unwrap().downcast::<SendError>().unwrap();
+        let SendError::Shutdown(outcomes) = *err else {
+            panic!("expected shutdown outcomes");
+        };
+        let outcomes: Vec<(Option<&str>, bool)> =
+            outcomes.iter().map(|o| (o.label(), o.is_ok())).collect();
+        assert_eq!(
+            outcomes,
+            [(Some("a"), true), (Some("b"), false), (Some("c"), true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn weight_segments_by_goodput() {
+        let fast = ThrottledWriter::new(1 << 16);
+        let slow = ThrottledWriter::new(1 << 12);
+        let fast_written = fast.written.clone();
+        let slow_written = slow.written.clone();
+        let mut sender = Sender::new(vec![fast, slow]);
+
+        // Measure the goodput first
+        sender
+            .batch_send_all(Bytes::from(vec![0; 1 << 20]))
+            .await
+            .unwrap();
+        fast_written.store(0, Ordering::Relaxed);
+        slow_written.store(0, Ordering::Relaxed);
+
+        for _ in 0..4 {
+            sender
+                .batch_send_all(Bytes::from(vec![0; 1 << 20]))
+                .await
+                .unwrap();
+        }
+        let fast_written = fast_written.load(Ordering::Relaxed);
+        let slow_written = slow_written.load(Ordering::Relaxed);
+        assert!(
+            fast_written > slow_written * 5,
+            "{fast_written} {slow_written}"
+        );
+    }
+
+    #[tokio::test]
+    async fn steal_from_slow_stream() {
+        let fast = ThrottledWriter::new(1 << 16);
+        let slow = ThrottledWriter::new(1 << 12);
+        let mut sender = Sender::new(vec![fast, slow]);
+
+        // Without a goodput measurement the payload is split evenly at first
+        sender
+            .batch_send_all(Bytes::from(vec![0; 1 << 22]))
+            .await
+            .unwrap();
+        let written: Vec<u64> = sender.stats().iter().map(|s| s.bytes_written()).collect();
+        assert!(written[0] > 1 << 21, "{written:?}");
+        assert_eq!(written[0] + written[1], 1 << 22);
+    }
+
+    #[tokio::test]
+    async fn subflow_events() {
+        let (tx, _rx) = tokio::io::duplex(1 << 16);
+        let (flaky, _flaky_rx) = tokio::io::duplex(1 << 16);
+        let send_streams: Vec<BoxWriter> = vec![
+            Box::pin(tx),
+            Box::pin(FlakyWriter::new(flaky, 0)),
+            Box::pin(StalledWriter),
+        ];
+        let mut sender = Sender::new(vec![]);
+        let mut events = sender.subscribe_events();
+        sender.add_streams(send_streams);
+        sender.set_write_timeout(Some(Duration::from_millis(50)));
+
+        sender
+            .batch_send_all(Bytes::from(vec![0; 1 << 14]))
+            .await
+            .unwrap();
+        let mut added = vec![];
+        let mut failed = vec![];
+        let mut timed_out = vec![];
+        let mut removed = vec![];
+        while let Ok(event) = events.try_recv() {
+            match event {
+                SubflowEvent::Added { id } => added.push(id),
+                SubflowEvent::Failed { id, error } => {
+                    assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+                    failed.push(id);
+                }
+                SubflowEvent::WriteTimeout { id } => timed_out.push(id),
+                SubflowEvent::Removed { id } => removed.push(id),
+                SubflowEvent::Stalled { .. }
+                | SubflowEvent::ReconnectAttempt { .. }
+                | SubflowEvent::ReconnectFailed { .. }
+                | SubflowEvent::Reconnected { .. } => panic!("unexpected {event:?}"),
+            }
+        }
+        removed.sort();
+        assert_eq!(
+            added,
+            [StreamId::new(0), StreamId::new(1), StreamId::new(2)]
+        );
+        assert_eq!(failed, [StreamId::new(1)]);
+        assert_eq!(timed_out, [StreamId::new(2)]);
+        assert_eq!(removed, [StreamId::new(1), StreamId::new(2)]);
+
+        // Nobody listening does not get in the way
+        drop(events);
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn progress() {
+        let send_streams = vec![ThrottledWriter::new(1 << 12), ThrottledWriter::new(1 << 12)];
+        let mut sender = Sender::new(send_streams);
+        sender.set_max_segment_size(NonZeroUsize::new(1 << 14));
+
+        let handle = ProgressHandle::new();
+        let poller = tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                let mut samples = vec![];
+                loop {
+                    let progress = handle.progress();
+                    samples.push(progress.sent_bytes);
+                    if progress.total_bytes > 0 && progress.unsent_segments == 0 {
+                        return samples;
+                    }
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        });
+        sender
+            .batch_send_all_with_progress(Bytes::from(vec![0; 1 << 20]), handle.clone())
+            .await
+            .unwrap();
+
+        let samples = poller.await.unwrap();
+        assert!(samples.windows(2).all(|w| w[0] <= w[1]), "{samples:?}");
+        assert!(samples.iter().any(|&sent| 0 < sent && sent < 1 << 20));
+        assert_eq!(samples.last(), Some(&(1 << 20)));
+        let progress = handle.progress();
+        assert_eq!(progress.sent_bytes, progress.total_bytes);
+    }
+
+    #[tokio::test]
+    async fn chunked_send() {
+        const CHUNK: usize = 1 << 16;
+        let (send_streams, recv_streams) = duplex_streams(2);
+        let mut sender = SenderBuilder::new()
+            .send_chunk_size(NonZeroUsize::new(CHUNK).unwrap())
+            .decision_log(1 << 10)
+            .build(send_streams);
+        let reader = tokio::spawn(async move {
+            let mut buf = vec![];
+            let mut reader = Receiver::new(recv_streams).into_async_read();
+            reader.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+
+        let msg: Vec<u8> = (0..16 * CHUNK + 5).map(|i| (i % 251) as u8).collect();
+        let handle = ProgressHandle::new();
+        sender
+            .batch_send_all_with_progress(Bytes::from(msg.clone()), handle.clone())
+            .await
+            .unwrap();
+        assert_eq!(sender.next_sequence(), Sequence::new(msg.len() as u64));
+        let progress = handle.progress();
+        assert_eq!(progress.total_bytes, msg.len());
+        assert_eq!(progress.sent_bytes, msg.len());
+        // No round reaches past the chunk it draws from
+        let log = sender.decision_log();
+        assert!(log.iter().all(|d| {
+            let chunk = d.start_sequence().inner() as usize / CHUNK;
+            (d.end_sequence().inner() as usize - 1) / CHUNK == chunk
+        }));
+        let rounds = log.iter().map(|d| d.round()).max().unwrap();
+        assert!(rounds >= 16);
+
+        sender.shutdown().await.unwrap();
+        assert!(reader.await.unwrap() == msg);
+    }
+
+    #[tokio::test]
+    async fn chunked_send_incomplete() {
+        const CHUNK: usize = 1 << 12;
+        let (tx, _rx) = tokio::io::duplex(1 << 20);
+        let mut sender = Sender::new(vec![FlakyWriter::new(tx, 3 * CHUNK)]);
+        sender.set_send_chunk_size(NonZeroUsize::new(CHUNK).unwrap());
+
+        let msg: Vec<u8> = (0..8 * CHUNK).map(|i| i as u8).collect();
+        let res = sender.batch_send_all(Bytes::from(msg.clone())).await;
+        let Err(SendError::Incomplete {
+            sent, remaining, ..
+        }) = res
+        else {
+            panic!("{res:?}");
+        };
+        assert!(0 < sent && sent < 3 * CHUNK, "{sent}");
+        assert_eq!(&remaining[..], &msg[sent..]);
+        assert_eq!(sender.next_sequence(), Sequence::new(sent as u64));
+    }
+
+    #[tokio::test]
+    async fn chunked_send_resubmitted() {
+        const CHUNK: usize = 1 << 12;
+        let (send_streams, recv_streams) = duplex_streams(2);
+        let mut send_streams = send_streams.into_iter();
+        let receiver = Receiver::new(recv_streams);
+        // Dies in the middle of a frame a few refills in
+        let flaky = FlakyWriter::new(send_streams.next().unwrap(), 2 * CHUNK + 100);
+        let mut sender = Sender::new(vec![flaky]);
+        sender.set_send_chunk_size(NonZeroUsize::new(CHUNK).unwrap());
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver
+                .into_async_read()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            buf
+        });
+
+        let msg: Vec<u8> = (0..8 * CHUNK).map(|i| (i % 251) as u8).collect();
+        let res = sender.batch_send_all(Bytes::from(msg.clone())).await;
+        let Err(SendError::Incomplete {
+            sent,
+            remaining,
+            errors,
+        }) = res
+        else {
+            panic!("{res:?}");
+        };
+        assert!(CHUNK < sent && sent < 2 * CHUNK, "{sent}");
+        assert_eq!(&remaining[..], &msg[sent..]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(sender.next_sequence(), Sequence::new(sent as u64));
+
+        let spare = send_streams.next().unwrap();
+        sender.add_stream(FlakyWriter::new(spare, usize::MAX));
+        sender.send(remaining).await.unwrap();
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        sender.shutdown().await.unwrap();
+        drop(sender);
+        assert_eq!(recv_task.await.unwrap(), [&msg[..], b"hello"].concat());
+    }
+
+    #[tokio::test]
+    async fn chunked_send_acknowledged() {
+        const CHUNK: usize = 1 << 12;
+        let (send_streams, recv_streams) = duplex_streams(2);
+        let receiver = Receiver::new(recv_streams);
+        let mut sender = Sender::new(send_streams);
+        sender.set_send_chunk_size(NonZeroUsize::new(CHUNK).unwrap());
+        // No more than a chunk in flight, so the refills keep waiting for the acknowledgements
+        sender.enable_retransmission(receiver.acks(), NonZeroUsize::new(CHUNK).unwrap());
+        let read = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver
+                .into_async_read()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            buf
+        });
+
+        let msg: Vec<u8> = (0..64 * CHUNK + 5).map(|i| (i % 251) as u8).collect();
+        sender.send(Bytes::from(msg.clone())).await.unwrap();
+        assert_eq!(sender.next_sequence(), Sequence::new(msg.len() as u64));
+        assert!(sender.resident_bytes() <= CHUNK);
+        sender.shutdown().await.unwrap();
+        drop(sender);
+        assert!(read.await.unwrap() == msg);
+    }
+
+    #[tokio::test]
+    async fn chunked_send_refill() {
+        const CHUNK: usize = 1 << 12;
+        const PIECE: usize = CHUNK / CHUNK_PIECES;
+        let (tx, _rx) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::new(vec![tx]);
+        sender.set_send_chunk_size(NonZeroUsize::new(CHUNK).unwrap());
+        let start = sender.next_sequence();
+        let mut data = Bytes::from(vec![0; 4 * CHUNK]);
+        let mut send_buf = SendStreamBuf::new(Bytes::new(), start);
+        assert!(sender.refill(&mut send_buf, &mut data, start));
+        assert_eq!(send_buf.resident_bytes(), CHUNK);
+        assert_eq!(send_buf.unsent_bytes(), CHUNK);
+        assert_eq!(sender.next_sequence(), Sequence::new(CHUNK as u64));
+
+        // Without retransmission a written piece makes room right away
+        send_buf.mark_as_sent(Sequence::new(0));
+        sender.refill(&mut send_buf, &mut data, start);
+        assert_eq!(send_buf.resident_bytes(), CHUNK);
+        assert_eq!(
+            sender.next_sequence(),
+            Sequence::new((CHUNK + PIECE) as u64)
+        );
+
+        // With it, only once acknowledged
+        let (acks_tx, acks) = watch::channel(Sequence::new(0));
+        sender.enable_retransmission(acks, NonZeroUsize::new(1 << 20).unwrap());
+        send_buf.mark_as_sent(Sequence::new(PIECE as u64));
+        sender.refill(&mut send_buf, &mut data, start);
+        assert_eq!(
+            sender.next_sequence(),
+            Sequence::new((CHUNK + PIECE) as u64)
+        );
+        acks_tx.send(Sequence::new(2 * PIECE as u64)).unwrap();
+        sender.refill(&mut send_buf, &mut data, start);
+        assert_eq!(send_buf.resident_bytes(), CHUNK);
+        assert_eq!(
+            sender.next_sequence(),
+            Sequence::new((CHUNK + 2 * PIECE) as u64)
+        );
+
+        // Written in full and out of room, it goes to the retransmission, which still counts against the chunk
+        while let Some(sequence) = send_buf.first_unsent_sequence() {
+            send_buf.mark_as_sent(sequence);
+        }
+        assert!(sender.refill(&mut send_buf, &mut data, start));
+        assert!(send_buf.is_empty());
+        assert_eq!(sender.resident_bytes(), CHUNK);
+        sender.refill(&mut send_buf, &mut data, start);
+        assert!(send_buf.is_empty());
+        acks_tx.send(sender.next_sequence()).unwrap();
+        sender.refill(&mut send_buf, &mut data, start);
+        assert_eq!(sender.resident_bytes(), 0);
+        assert_eq!(send_buf.resident_bytes(), CHUNK);
+        assert_eq!(
+            sender.next_sequence(),
+            Sequence::new((2 * CHUNK + 2 * PIECE) as u64)
+        );
+    }
+
+    #[tokio::test]
+    async fn evict_stalled_stream() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+        let (tx, rx) = tokio::io::duplex(1 << 20);
+        let send_streams: Vec<BoxWriter> = vec![Box::pin(StalledWriter), Box::pin(tx)];
+        let mut sender = Sender::new(send_streams);
+        sender.set_write_timeout(Some(TIMEOUT));
+        let mut receiver = Receiver::new(vec![rx]).into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 16).map(|i| i as u8).collect();
+        let start = Instant::now();
+        sender
+            .batch_send_all(Bytes::from(msg.clone()))
+            .await
+            .unwrap();
+        assert!(start.elapsed() < TIMEOUT * 2);
+        assert_eq!(sender.live_streams(), 1);
+        let evicted = sender.take_evicted_streams();
+        assert_eq!(evicted[0].id(), StreamId::new(0));
+        assert_eq!(evicted[0].error().kind(), io::ErrorKind::TimedOut);
+
+        let mut buf = vec![0; msg.len()];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+    }
+
+    #[tokio::test]
+    async fn write_buffer_coalesces_frames() {
+        let send = |write_buffer: Option<NonZeroUsize>| async move {
+            let writes = Arc::new(AtomicUsize::new(0));
+            let writer = CountingWriter {
+                writes: writes.clone(),
+                ..Default::default()
+            };
+            let mut sender = Sender::new(vec![]);
+            sender.set_write_buffer(write_buffer);
+            sender.set_max_segment_size(NonZeroUsize::new(1 << 12));
+            sender.add_stream(writer);
+            sender
+                .batch_send_all(Bytes::from(vec![0; 1 << 15]))
+                .await
+                .unwrap();
+            let segments = sender.stats()[0].segments_written();
+            let bytes = std::mem::take(&mut sender.streams[0].stream.get_mut().bytes);
+            (writes.load(Ordering::Relaxed), segments, bytes)
+        };
+
+        let (unbuffered_writes, segments, unbuffered_bytes) = send(None).await;
+        assert_eq!(segments, 8);
+        assert!(unbuffered_writes > 2 * segments as usize);
+        let (buffered_writes, segments, buffered_bytes) = send(NonZeroUsize::new(1 << 13)).await;
+        assert_eq!(segments, 8);
+        // The handshake is coalesced with the first segment
+        assert_eq!(buffered_writes, segments as usize);
+        assert_eq!(buffered_bytes, unbuffered_bytes);
+    }
+
+    #[tokio::test]
+    async fn evict_buffered_stream_failing_flush() {
+        let (tx, rx) = tokio::io::duplex(1 << 20);
+        let failing: BoxWriter = Box::pin(CountingWriter {
+            fail_flush: true,
+            ..Default::default()
+        });
+        let mut sender = SenderBuilder::new()
+            .write_buffer(NonZeroUsize::new(1 << 16).unwrap())
+            .build(vec![failing, Box::pin(tx)]);
+        let mut receiver = Receiver::new(vec![rx]).into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 17).map(|_| rand::random()).collect();
+        sender
+            .batch_send_all(Bytes::from(msg.clone()))
+            .await
+            .unwrap();
+        let evicted = sender.take_evicted_streams();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].id(), StreamId::new(0));
+        assert_eq!(evicted[0].error().kind(), io::ErrorKind::BrokenPipe);
+        sender.shutdown().await.unwrap();
+
+        let mut buf = vec![];
+        receiver.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+    }
+
+    #[tokio::test]
+    async fn cork_small_writes() {
+        let msg: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+        let send = |cork: Option<Cork>| {
+            let msg = msg.clone();
+            async move {
+                let (send_streams, recv_streams) = duplex_streams(4);
+                let mut sender = Sender::new(send_streams);
+                sender.set_cork(cork);
+                let write = async move {
+                    for chunk in msg.chunks(100) {
+                        sender.write_all(chunk).await.unwrap();
+                    }
+                    sender.flush().await.unwrap();
+                    sender.shutdown().await.unwrap();
+                };
+                let (segments, ()) = tokio::join!(capture_segments(recv_streams), write);
+                segments
+            }
+        };
+        let reassemble = |segments: &[DataSegment]| -> Vec<u8> {
+            segments
+                .iter()
+                .flat_map(|segment| segment.payload().to_vec())
+                .collect()
+        };
+
+        let uncorked = send(None).await;
+        assert_eq!(uncorked.len(), 1000);
+        assert_eq!(reassemble(&uncorked), msg);
+        let cork = Cork {
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let corked = send(Some(cork)).await;
+        assert!(corked.len() < 50);
+        assert_eq!(reassemble(&corked), msg);
+    }
+
+    #[tokio::test]
+    async fn cork_goes_ahead_of_batch_send() {
+        let (send_streams, recv_streams) = duplex_streams(1);
+        let mut sender = Sender::new(send_streams);
+        sender.set_cork(Some(Cork::default()));
+        let mut receiver = Receiver::new(recv_streams).into_async_read();
+
+        sender.write_all(b"hello").await.unwrap();
+        assert_eq!(sender.next_sequence(), Sequence::new(0));
+        sender
+            .batch_send_all(Bytes::from_static(b" world"))
+            .await
+            .unwrap();
+        assert_eq!(sender.next_sequence(), Sequence::new(11));
+        sender.shutdown().await.unwrap();
+        let mut buf = vec![];
+        receiver.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn deadline_exceeded() {
+        const DEADLINE: Duration = Duration::from_millis(100);
+        let (tx, mut rx) = tokio::io::duplex(1 << 20);
+        let send_streams: Vec<BoxWriter> = vec![Box::pin(StalledWriter), Box::pin(tx)];
+        let mut sender = Sender::new(send_streams);
+
+        let start = Instant::now();
+        let res = sender
+            .batch_send_all_with_deadline(Bytes::from(vec![0; 1 << 16]), start + DEADLINE)
+            .await;
+        let Err(SendError::DeadlineExceeded { bytes_sent }) = res else {
+            panic!("expected the deadline to pass");
+        };
+        assert!(start.elapsed() < DEADLINE * 2);
+        assert!(0 < bytes_sent && bytes_sent <= 1 << 16);
+
+        // The stalled stream was left mid-write and the other one is kept
+        assert_eq!(sender.live_streams(), 1);
+        let evicted = sender.take_evicted_streams();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].id(), StreamId::new(0));
+        sender
+            .batch_send_all_with_deadline(Bytes::from_static(b"hello"), Instant::now() + DEADLINE)
+            .await
+            .unwrap();
+        drop(sender);
+        let mut wire = vec![];
+        rx.read_to_end(&mut wire).await.unwrap();
+        assert!(wire.ends_with(b"hello"));
+    }
+
+    #[tokio::test]
+    async fn default_send_deadline() {
+        const DEADLINE: Duration = Duration::from_millis(100);
+        let send_streams: Vec<BoxWriter> = vec![Box::pin(StalledWriter)];
+        let mut sender = SenderBuilder::new()
+            .send_deadline(DEADLINE)
+            .build(send_streams);
+
+        let start = Instant::now();
+        let e = sender.write_all(b"hello").await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < DEADLINE * 2);
+        assert_eq!(sender.live_streams(), 0);
+    }
+
+    #[tokio::test]
+    async fn try_send_stalled_stream() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+        let (tx, rx) = tokio::io::duplex(1 << 20);
+        let send_streams: Vec<BoxWriter> = vec![Box::pin(StalledWriter), Box::pin(tx)];
+        let mut sender = Sender::new(send_streams);
+        sender.set_write_timeout(Some(TIMEOUT));
+        let mut receiver = Receiver::new(vec![rx]).into_async_read();
+
+        sender.try_send(Bytes::from_static(b"hello ")).unwrap();
+        let res = sender.try_send(Bytes::from_static(b"world"));
+        let Err(TrySendError::Full(data)) = res else {
+            panic!("expected the sender to be busy");
+        };
+        let start = Instant::now();
+        std::future::poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .unwrap();
+        assert!(TIMEOUT <= start.elapsed());
+        assert_eq!(sender.live_streams(), 1);
+
+        // The data handed back is sent right after the first submission
+        sender.try_send(data).unwrap();
+        std::future::poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .unwrap();
+        assert_eq!(sender.next_sequence(), Sequence::new(11));
+        sender.shutdown().await.unwrap();
+        let mut buf = vec![];
+        receiver.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn try_send_full_window() {
+        let (send_streams, recv_streams) = duplex_streams(1);
+        let (ack_tx, ack_rx) = watch::channel(Sequence::new(0));
+        let mut sender = Sender::new(send_streams);
+        sender.enable_retransmission(ack_rx, NonZeroUsize::new(1 << 16).unwrap());
+        sender.set_send_window(NonZeroUsize::new(8));
+        let capture = tokio::spawn(capture_segments(recv_streams));
+
+        sender.try_send(Bytes::from_static(b"abcdefgh")).unwrap();
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..1000 {
+            if sender.poll_ready(&mut cx).is_pending() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(sender.poll_ready(&mut cx).is_pending());
+        let res = sender.try_send(Bytes::from_static(b"ij"));
+        let Err(TrySendError::Full(data)) = res else {
+            panic!("expected the window to be full");
+        };
+
+        ack_tx.send(Sequence::new(8)).unwrap();
+        std::future::poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .unwrap();
+        sender.try_send(data).unwrap();
+        std::future::poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .unwrap();
+        sender.shutdown().await.unwrap();
+
+        let wire: Vec<u8> = capture
+            .await
+            .unwrap()
+            .iter()
+            .flat_map(|segment| segment.payload().to_vec())
+            .collect();
+        assert_eq!(wire, b"abcdefghij");
+    }
+
+    #[tokio::test]
+    async fn sink_forward() {
+        use futures_util::SinkExt;
+
+        let (send_streams, recv_streams) = duplex_streams(3);
+        let receiver = Receiver::new(recv_streams);
+        let mut sender = Sender::new(send_streams);
+        // Far less than the chunks, which wait for acknowledgements in `Sink::poll_ready`
+        sender.enable_retransmission(receiver.acks(), NonZeroUsize::new(1 << 12).unwrap());
+        let read = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver
+                .into_async_read()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            buf
+        });
+
+        let chunks: Vec<Bytes> = (0..10_000)
+            .map(|i| Bytes::from(format!("{i},").repeat(i % 7 + 1)))
+            .collect();
+        let expected = chunks.concat();
+        futures_util::stream::iter(chunks.into_iter().map(Ok))
+            .forward(&mut sender)
+            .await
+            .unwrap();
+        assert_eq!(sender.next_sequence(), Sequence::new(expected.len() as u64));
+        assert_eq!(read.await.unwrap(), expected);
+        // Closed by `forward`
+        let res = sender.batch_send_all(Bytes::from_static(b"late")).await;
+        assert!(matches!(res, Err(SendError::Closed)));
+
+        // Chunks larger than the send window are taken whole
+        let (send_streams, recv_streams) = duplex_streams(2);
+        let mut sender = Sender::new(send_streams);
+        sender.set_send_window(NonZeroUsize::new(8));
+        let chunk = Bytes::from(vec![7; 100]);
+        let mut chunks = futures_util::stream::iter([Ok(chunk.clone()), Ok(chunk.clone())]);
+        SinkExt::send_all(&mut sender, &mut chunks).await.unwrap();
+        SinkExt::send(&mut sender, chunk).await.unwrap();
+        SinkExt::close(&mut sender).await.unwrap();
+        let mut buf = vec![];
+        Receiver::new(recv_streams)
+            .into_async_read()
+            .read_to_end(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, [7; 300]);
+    }
+
+    #[tokio::test]
+    async fn sequence_exhausted() {
+        let (tx, mut rx) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::new(vec![tx]);
+        sender.next = Sequence::new(u64::MAX - 5);
+
+        // Straddling `u64::MAX`
+        let res = sender
+            .batch_send_all(Bytes::from_static(b"hello world"))
+            .await;
+        assert!(matches!(res, Err(SendError::SequenceExhausted)));
+        assert_eq!(sender.next, Sequence::new(u64::MAX - 5));
+
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(sender.next, Sequence::new(u64::MAX));
+        Hello::decode(&mut rx).await.unwrap();
+        let Message::DataSegment(data_segment) = Message::decode(&mut rx).await.unwrap() else {
+            panic!("expected a data segment");
+        };
+        assert_eq!(data_segment.start_sequence(), Sequence::new(u64::MAX - 5));
+        assert_eq!(data_segment.end_sequence(), Sequence::new(u64::MAX));
+
+        let res = sender.batch_send_all(Bytes::from_static(b"!")).await;
+        assert!(matches!(res, Err(SendError::SequenceExhausted)));
+    }
+
+    #[tokio::test]
+    async fn stats_add_up() {
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for budget in [usize::MAX, usize::MAX, 0] {
+            let (tx, rx) = tokio::io::duplex(1 << 20);
+            send_streams.push(FlakyWriter::new(tx, budget));
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+
+        let sizes = [5, 1 << 16, 1 << 18];
+        for size in sizes {
+            sender
+                .batch_send_all(Bytes::from(vec![0; size]))
+                .await
+                .unwrap();
+        }
+
+        let stats = sender.stats();
+        assert_eq!(stats.len(), 3);
+        let total: u64 = stats.iter().map(|s| s.bytes_written()).sum();
+        assert_eq!(total, sizes.iter().sum::<usize>() as u64);
+        assert!(stats[0].segments_written() >= 3);
+        assert!(stats[0].last_write_latency().is_some());
+        assert!(stats[0].live());
+        assert_eq!(stats[2].id(), StreamId::new(2));
+        assert_eq!(stats[2].errors(), 1);
+        assert_eq!(stats[2].segments_written(), 0);
+        assert!(!stats[2].live());
+    }
+
+    #[tokio::test]
+    async fn max_segment_size() {
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for _ in 0..2 {
+            let (tx, rx) = tokio::io::duplex(1 << 21);
+            send_streams.push(tx);
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+        sender.set_max_segment_size(NonZeroUsize::new(1 << 16));
+
+        sender
+            .batch_send_all(Bytes::from(vec![0; 1 << 20]))
+            .await
+            .unwrap();
+        sender.shutdown().await.unwrap();
+
+        let mut frames = vec![];
+        for mut rx in recv_streams {
+            Hello::decode(&mut rx).await.unwrap();
+            let mut stream_frames = 0;
+            loop {
+                match Message::decode(&mut rx).await.unwrap() {
+                    Message::DataSegment(data_segment) => {
+                        frames.push(data_segment.size());
+                        stream_frames += 1;
+                    }
+                    Message::Ping
+                    | Message::Fin(_)
+                    | Message::Ack(_)
+                    | Message::Control(_)
+                    | Message::Probe(_)
+                    | Message::Pong(_)
+                    | Message::Oob { .. }
+                    | Message::Feedback(_)
+                    | Message::Abort { .. }
+                    | Message::Unknown { .. } => (),
+                    Message::Shutdown => break,
+                }
+            }
+            assert!(stream_frames > 0);
+        }
+        assert_eq!(frames, [1 << 16; 16]);
+    }
+
+    #[tokio::test]
+    async fn zero_stream_grace() {
+        let (dead_tx, dead_rx) = tokio::io::duplex(1 << 10);
+        drop(dead_rx);
+        let mut sender = SenderBuilder::new()
+            .zero_stream_grace(Duration::from_secs(10))
+            .build(vec![dead_tx]);
+        let pool = sender.pool_handle();
+        let (tx, mut rx) = tokio::io::duplex(1 << 10);
+        let add = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            pool.add_stream(tx).unwrap();
+        };
+        let start = Instant::now();
+        let (res, ()) = tokio::join!(sender.batch_send_all(Bytes::from_static(b"hello")), add);
+        res.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(sender.take_evicted_streams().len(), 1);
+        assert_eq!(
+            sender.stream_state(StreamId::new(1)),
+            Some(StreamState::Idle)
+        );
+        Hello::decode(&mut rx).await.unwrap();
+        let Message::DataSegment(data_segment) = Message::decode(&mut rx).await.unwrap() else {
+            panic!("expected a data segment");
+        };
+        assert_eq!(&data_segment.payload()[..], b"hello");
+
+        // Out of grace
+        drop(rx);
+        sender.set_zero_stream_grace(Some(Duration::from_millis(20)));
+        let res = sender.batch_send_all(Bytes::from_static(b"world")).await;
+        assert!(matches!(res, Err(SendError::Incomplete { sent: 0, .. })));
+        let start = Instant::now();
+        let res = sender.batch_send_all(Bytes::from_static(b"world")).await;
+        assert!(matches!(res, Err(SendError::NoStreamLeft { .. })));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        drop(sender);
+        let (tx, _rx) = tokio::io::duplex(1 << 10);
+        assert!(pool.add_stream(tx).is_err());
+    }
+
+    #[tokio::test]
+    async fn max_frame_size_per_stream() {
+        const LIMITS: [usize; 2] = [1 << 12, 1 << 16];
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for _ in LIMITS {
+            let (tx, rx) = tokio::io::duplex(1 << 21);
+            send_streams.push(tx);
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+        for (i, limit) in LIMITS.into_iter().enumerate() {
+            assert!(sender.set_max_frame_size(StreamId::new(i), NonZeroUsize::new(limit)));
+        }
+        assert!(sender.advertise_max_frame_size(StreamId::new(0), 1 << 10));
+        assert!(!sender.set_max_frame_size(StreamId::new(2), None));
+
+        sender
+            .batch_send_all(Bytes::from(vec![0; 1 << 20]))
+            .await
+            .unwrap();
+        sender.shutdown().await.unwrap();
+        // Too late to be advertised
+        assert!(!sender.advertise_max_frame_size(StreamId::new(1), 1 << 10));
+
+        let mut bytes = 0;
+        for (i, mut rx) in recv_streams.into_iter().enumerate() {
+            let hello = Hello::decode(&mut rx).await.unwrap();
+            let advertised = (i == 0).then_some(1 << 10);
+            assert_eq!(hello.max_frame_size(), advertised);
+            let mut largest = 0;
+            loop {
+                match Message::decode(&mut rx).await.unwrap() {
+                    Message::DataSegment(data_segment) => {
+                        largest = largest.max(DATA_SEGMENT_OVERHEAD + data_segment.size());
+                        bytes += data_segment.size();
+                    }
+                    Message::Shutdown => break,
+                    _ => (),
+                }
+            }
+            assert!(largest <= LIMITS[i]);
+            // Every stream took its share in frames as large as it accepts
+            assert!(largest > LIMITS[i] / 2);
+        }
+        assert_eq!(bytes, 1 << 20);
+    }
+
+    /// Every data segment written on `recv_streams` up to their shutdown, in sequence order
+    async fn capture_segments(recv_streams: Vec<DuplexStream>) -> Vec<DataSegment> {
+        // Drain the streams concurrently so that none of them fills up and blocks the sender
+        let captures = recv_streams.into_iter().map(|mut rx| async move {
+            let mut segments = vec![];
+            Hello::decode(&mut rx).await.unwrap();
+            loop {
+                match Message::decode(&mut rx).await.unwrap() {
+                    Message::DataSegment(data_segment) => segments.push(data_segment),
+                    Message::Ping
+                    | Message::Fin(_)
+                    | Message::Ack(_)
+                    | Message::Control(_)
+                    | Message::Probe(_)
+                    | Message::Pong(_)
+                    | Message::Oob { .. }
+                    | Message::Feedback(_)
+                    | Message::Abort { .. }
+                    | Message::Unknown { .. } => (),
+                    Message::Shutdown => break,
+                }
+            }
+            segments
+        });
+        let mut segments: Vec<DataSegment> = futures_util::future::join_all(captures)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        segments.sort_by_key(|segment| segment.start_sequence());
+        segments
+    }
+
+    fn duplex_streams(streams: usize) -> (Vec<DuplexStream>, Vec<DuplexStream>) {
+        (0..streams).map(|_| tokio::io::duplex(1 << 16)).unzip()
+    }
+
+    #[tokio::test]
+    async fn empty_write() {
+        let (send_streams, _recv_streams) = duplex_streams(2);
+        let mut async_write = Sender::new(send_streams).into_async_write();
+        assert_eq!(async_write.write(&[]).await.unwrap(), 0);
+        let bufs = [IoSlice::new(&[]), IoSlice::new(&[])];
+        assert_eq!(async_write.write_vectored(&bufs).await.unwrap(), 0);
+        let sender = async_write.inner();
+        assert_eq!(sender.next_sequence(), Sequence::new(0));
+        assert!(sender.stats().iter().all(|s| s.bytes_written() == 0));
+    }
+
+    #[tokio::test]
+    async fn tiny_write() {
+        let (send_streams, recv_streams) = duplex_streams(8);
+        let mut async_write = Sender::new(send_streams).into_async_write();
+        assert_eq!(async_write.write(&[1]).await.unwrap(), 1);
+        async_write.shutdown().await.unwrap();
+        let segments = capture_segments(recv_streams).await;
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].payload()[..], [1]);
+    }
+
+    #[tokio::test]
+    async fn small_writes_reassemble() {
+        for len in 0..=64 {
+            let (send_streams, recv_streams) = duplex_streams(8);
+            let mut async_write = Sender::new(send_streams).into_async_write();
+            let msg: Vec<u8> = (0..len).collect();
+            async_write.write_all(&msg).await.unwrap();
+            async_write.shutdown().await.unwrap();
+
+            let segments = capture_segments(recv_streams).await;
+            assert!(segments.iter().all(|segment| segment.size() > 0));
+            let payloads: Vec<u8> = segments
+                .iter()
+                .flat_map(|segment| segment.payload().iter().copied())
+                .collect();
+            assert_eq!(payloads, msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn segment_size_bounds() {
+        const MIN: usize = 1 << 14;
+        const MAX: usize = 1 << 16;
+        let (send_streams, recv_streams) = duplex_streams(4);
+        let capture = tokio::spawn(capture_segments(recv_streams));
+        let mut sender = SenderBuilder::new()
+            .min_segment_size(NonZeroUsize::new(MIN).unwrap())
+            .max_segment_size(NonZeroUsize::new(MAX).unwrap())
+            .build(send_streams);
+
+        let lens = [1 << 14, 1 << 14, 1 << 20, 100_000, 40_000, 5000];
+        let mut ends = vec![];
+        for len in lens {
+            sender
+                .batch_send_all(Bytes::from(vec![0; len]))
+                .await
+                .unwrap();
+            ends.push(sender.next_sequence());
+        }
+        let mut async_write = sender.into_async_write();
+        async_write.write_all(&[0; 50_000]).await.unwrap();
+        ends.push(async_write.inner().next_sequence());
+        async_write.shutdown().await.unwrap();
+
+        let segments = capture.await.unwrap();
+        for segment in &segments {
+            assert!(segment.size() <= MAX);
+            // Only the tail of each write is smaller
+            if !ends.contains(&segment.end_sequence()) {
+                assert!(segment.size() >= MIN, "{} bytes", segment.size());
+            }
+        }
+        let sizes: Vec<usize> = segments.iter().map(|s| s.size()).collect();
+        assert_eq!(sizes[..2], [1 << 14; 2]);
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let (flaky_tx, _flaky_rx) = tokio::io::duplex(1 << 20);
+        let (tx, _rx) = tokio::io::duplex(1 << 20);
+        let send_streams: Vec<BoxWriter> =
+            vec![Box::pin(FlakyWriter::new(flaky_tx, 0)), Box::pin(tx)];
+        let mut sender = Sender::new(send_streams);
+        let mut events = sender.subscribe_events();
+        let dials = Arc::new(AtomicUsize::new(0));
+        let far_end = Arc::new(Mutex::new(None));
+        let policy = ReconnectPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+        };
+        sender.set_reconnect(policy, {
+            let dials = dials.clone();
+            let far_end = far_end.clone();
+            move |id| {
+                assert_eq!(id, StreamId::new(0));
+                let dial = dials.fetch_add(1, Ordering::Relaxed);
+                let far_end = far_end.clone();
+                async move {
+                    if dial < 2 {
+                        return Err(io::ErrorKind::ConnectionRefused.into());
+                    }
+                    let (tx, rx) = tokio::io::duplex(1 << 20);
+                    *far_end.lock().unwrap() = Some(rx);
+                    Ok::<BoxWriter, _>(Box::pin(tx))
+                }
+            }
+        });
+
+        // The data flows over the remaining stream during the outage
+        sender
+            .batch_send_all(Bytes::from(vec![0; 1 << 16]))
+            .await
+            .unwrap();
+        assert_eq!(sender.take_evicted_streams().len(), 1);
+        assert_eq!(sender.live_streams(), 1);
+        assert_eq!(sender.reconnecting(), 1);
+
+        sender.wait_for_reconnects().await;
+        assert_eq!(dials.load(Ordering::Relaxed), 3);
+        assert_eq!(sender.live_streams(), 2);
+        sender
+            .batch_send_all(Bytes::from(vec![1; 1 << 16]))
+            .await
+            .unwrap();
+        let mut rx = far_end.lock().unwrap().take().unwrap();
+        Hello::decode(&mut rx).await.unwrap();
+        let Message::DataSegment(data_segment) = Message::decode(&mut rx).await.unwrap() else {
+            panic!("expected a data segment");
+        };
+        assert!(data_segment.start_sequence() >= Sequence::new(1 << 16));
+
+        let mut attempts = vec![];
+        let mut failures = 0;
+        let mut replacement = None;
+        while let Ok(event) = events.try_recv() {
+            match event {
+                SubflowEvent::ReconnectAttempt { attempt, .. } => attempts.push(attempt),
+                SubflowEvent::ReconnectFailed { error, .. } => {
+                    assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+                    failures += 1;
+                }
+                SubflowEvent::Reconnected {
+                    id,
+                    replacement: new,
+                } => {
+                    assert_eq!(id, StreamId::new(0));
+                    replacement = Some(new);
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(attempts, [1, 2, 3]);
+        assert_eq!(failures, 2);
+        assert_eq!(replacement, Some(StreamId::new(2)));
+    }
+
+    #[tokio::test]
+    async fn coalesced_heartbeats() {
+        const INTERVAL: Duration = Duration::from_millis(200);
+        let streams: Vec<_> = (0..3).map(|_| tokio::io::duplex(1 << 16)).collect();
+        let (txs, _rxs): (Vec<_>, Vec<_>) = streams.into_iter().unzip();
+        let mut txs = txs.into_iter();
+        let mut sender = Sender::new(vec![txs.next().unwrap()]);
+        sender.set_keepalive(Some(INTERVAL));
+        assert_eq!(sender.keepalive_stats().unwrap().wakeups(), 0);
+
+        // Nearly due when the first one is
+        tokio::time::sleep(INTERVAL / 20).await;
+        sender.add_stream(txs.next().unwrap());
+        // Carried something within the interval
+        tokio::time::sleep(INTERVAL / 2).await;
+        sender.add_stream(txs.next().unwrap());
+
+        let due = sender.next_heartbeat().unwrap();
+        tokio::time::sleep_until(due.into()).await;
+        sender.heartbeat().await.unwrap();
+        let heartbeats: Vec<u64> = sender.stats().iter().map(StreamStats::heartbeats).collect();
+        assert_eq!(heartbeats, [1, 1, 0]);
+        let stats = sender.keepalive_stats().unwrap();
+        assert_eq!(stats.wakeups(), 1);
+        assert!(stats.wakeup_rate() > 0.0);
+        assert!(sender.next_heartbeat().unwrap() > Instant::now());
+
+        sender.set_keepalive(None);
+        assert!(sender.keepalive_stats().is_none());
+    }
+
+    #[tokio::test]
+    async fn remove_the_last_stream() {
+        let (tx, mut rx) = tokio::io::duplex(1 << 10);
+        let mut sender = Sender::new(vec![tx]);
+        let id = StreamId::new(0);
+        assert!(matches!(
+            sender.remove_stream(id, false).await,
+            Err(RemoveError::LastStream)
+        ));
+        let _tx = sender.remove_stream(id, true).await.unwrap();
+        assert_eq!(sender.live_streams(), 0);
+        assert!(matches!(
+            sender.remove_stream(id, true).await,
+            Err(RemoveError::UnknownStream)
+        ));
+
+        // Greeted even though it never carried anything
+        Hello::decode(&mut rx).await.unwrap();
+        assert_eq!(Message::decode(&mut rx).await.unwrap(), Message::Shutdown);
+    }
+
+    #[tokio::test]
+    async fn heartbeat_idle_streams() {
+        const INTERVAL: Duration = Duration::from_millis(50);
+        let (idle_tx, mut idle_rx) = tokio::io::duplex(1 << 16);
+        let (busy_tx, mut busy_rx) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::new(vec![idle_tx]);
+        sender.set_keepalive(Some(INTERVAL));
+        assert!(sender.next_heartbeat().unwrap() > Instant::now());
+
+        tokio::time::sleep(INTERVAL / 2).await;
+        sender.add_stream(busy_tx);
+        tokio::time::sleep(INTERVAL / 2).await;
+        assert!(sender.next_heartbeat().unwrap() <= Instant::now());
+        sender.heartbeat().await.unwrap();
+        sender.shutdown().await.unwrap();
+
+        Hello::decode(&mut idle_rx).await.unwrap();
+        Hello::decode(&mut busy_rx).await.unwrap();
+
+        assert!(matches!(
+            Message::decode(&mut idle_rx).await.unwrap(),
+            Message::Ping
+        ));
+        assert!(matches!(
+            Message::decode(&mut idle_rx).await.unwrap(),
+            Message::Fin(_)
+        ));
+        assert!(matches!(
+            Message::decode(&mut idle_rx).await.unwrap(),
+            Message::Shutdown
+        ));
+        assert!(matches!(
+            Message::decode(&mut busy_rx).await.unwrap(),
+            Message::Fin(_)
+        ));
+        assert!(matches!(
+            Message::decode(&mut busy_rx).await.unwrap(),
+            Message::Shutdown
+        ));
+    }
+
+    #[tokio::test]
+    async fn checksum() {
+        let (tx, rx) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::new(vec![tx]);
+        sender.set_checksum(true);
+        let mut receiver = Receiver::new(vec![rx]).into_async_read();
+
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        let mut buf = [0; 5];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn compact_headers() {
+        let msg: Vec<u8> = (0..300 * 200).map(|i| i as u8).collect();
+        let send = |compact: bool| {
+            let msg = msg.clone();
+            async move {
+                let mut sender = SenderBuilder::new()
+                    .compact_headers(compact)
+                    .build(vec![Vec::new(), Vec::new()]);
+                for chunk in msg.chunks(300) {
+                    sender
+                        .batch_send_all(Bytes::copy_from_slice(chunk))
+                        .await
+                        .unwrap();
+                }
+                sender.shutdown().await.unwrap();
+                let wires: Vec<Vec<u8>> = sender
+                    .streams
+                    .iter()
+                    .map(|subflow| subflow.stream.get_ref().clone())
+                    .collect();
+
+                let hello = Hello::decode(&mut &wires[0][..]).await.unwrap();
+                let compact_capability = hello.capabilities() & CAPABILITY_COMPACT_HEADERS != 0;
+                assert_eq!(compact_capability, compact);
+                let recv_streams = wires.iter().cloned().map(io::Cursor::new).collect();
+                let mut buf = vec![];
+                Receiver::new(recv_streams)
+                    .into_async_read()
+                    .read_to_end(&mut buf)
+                    .await
+                    .unwrap();
+                assert_eq!(buf, msg);
+                wires.iter().map(|wire| wire.len()).sum::<usize>()
+            }
+        };
+
+        let full = send(false).await;
+        let compact = send(true).await;
+        // 13 bytes of framing per segment against at most 1 + 2 + 2
+        assert!(200 * 8 <= full - compact);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[tokio::test]
+    async fn compression() {
+        /// The wires of the streams after sending `chunks`, and the stats of the sender
+        async fn send(chunks: &[Vec<u8>], checksum: bool) -> (Vec<Vec<u8>>, Vec<StreamStats>) {
+            let mut sender = SenderBuilder::new()
+                .checksum(checksum)
+                .compact_headers(true)
+                .compression(Compression::Lz4)
+                .build(vec![Vec::new(), Vec::new()]);
+            for chunk in chunks {
+                sender
+                    .batch_send_all(Bytes::copy_from_slice(chunk))
+                    .await
+                    .unwrap();
+            }
+            sender.shutdown().await.unwrap();
+            let wires: Vec<Vec<u8>> = sender
+                .streams
+                .iter()
+                .map(|subflow| subflow.stream.get_ref().clone())
+                .collect();
+            let hello = Hello::decode(&mut &wires[0][..]).await.unwrap();
+            assert_ne!(hello.capabilities() & Compression::Lz4.capability(), 0);
+
+            let recv_streams = wires.iter().cloned().map(io::Cursor::new).collect();
+            let mut buf = vec![];
+            Receiver::new(recv_streams)
+                .into_async_read()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            assert_eq!(buf, chunks.concat());
+            (wires, sender.stats())
+        }
+
+        // Small segments go as they are between the compressed ones, with compact headers
+        let json: Vec<Vec<u8>> = (0..64)
+            .map(|i| match i % 4 {
+                0 => format!("{{\"seq\":{i}}}").into_bytes(),
+                _ => format!("{{\"seq\":{i},\"sensor\":\"temperature\",\"unit\":\"celsius\"}}")
+                    .repeat(32)
+                    .into_bytes(),
+            })
+            .collect();
+        for checksum in [false, true] {
+            let (wires, stats) = send(&json, checksum).await;
+            let total = json.iter().map(|chunk| chunk.len()).sum::<usize>();
+            assert!(wires.iter().map(|wire| wire.len()).sum::<usize>() < total / 4);
+            let uncompressed: u64 = stats.iter().map(|s| s.uncompressed_bytes()).sum();
+            assert_eq!(uncompressed, total as u64);
+            for stats in &stats {
+                assert_eq!(stats.uncompressed_bytes(), stats.bytes_written());
+                if let Some(ratio) = stats.compression_ratio() {
+                    assert!(ratio > 4.0);
+                }
+            }
+        }
+
+        // Data that does not shrink is sent as it is
+        let random: Vec<Vec<u8>> = (0..16)
+            .map(|_| (0..1 << 12).map(|_| rand::random()).collect())
+            .collect();
+        let (_, stats) = send(&random, false).await;
+        for stats in &stats {
+            assert_eq!(stats.compressed_bytes(), stats.uncompressed_bytes());
+        }
+        assert!(stats.iter().any(|s| s.compression_ratio() == Some(1.0)));
+    }
+
+    #[tokio::test]
+    async fn handshake_once_per_stream() {
+        let (tx, mut rx) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::new(vec![tx]);
+        sender.set_checksum(true);
+        sender.handshake().await.unwrap();
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        sender.handshake().await.unwrap();
+        sender.shutdown().await.unwrap();
+
+        let hello = Hello::decode(&mut rx).await.unwrap();
+        assert_eq!(hello.capabilities(), CAPABILITY_CHECKSUM);
+        assert!(matches!(
+            Message::decode(&mut rx).await.unwrap(),
+            Message::DataSegment(_)
+        ));
+        let Message::Fin(fin) = Message::decode(&mut rx).await.unwrap() else {
+            panic!("expected a FIN");
+        };
+        assert_eq!(fin, Sequence::new(5));
+        assert!(matches!(
+            Message::decode(&mut rx).await.unwrap(),
+            Message::Shutdown
+        ));
+    }
+
+    #[tokio::test]
+    async fn retransmit_lost_segments() {
+        let (tx, rx) = tokio::io::duplex(1 << 20);
+        let dead = Arc::new(AtomicBool::new(false));
+        let black_hole = BlackHoleWriter { dead: dead.clone() };
+        let send_streams: Vec<BoxWriter> = vec![Box::pin(tx), Box::pin(black_hole)];
+        let mut sender = Sender::new(send_streams);
+        let receiver = Receiver::new(vec![rx]);
+        sender.enable_retransmission(receiver.acks(), NonZeroUsize::new(1 << 20).unwrap());
+        sender.set_max_segment_size(NonZeroUsize::new(1 << 14));
+
+        let msg: Vec<u8> = (0..1 << 17).map(|_| rand::random()).collect();
+        sender
+            .batch_send_all(Bytes::from(msg.clone()))
+            .await
+            .unwrap();
+        // The segments written into the black hole are never acknowledged
+        assert!(sender.retained_bytes() > 0);
+
+        dead.store(true, Ordering::SeqCst);
+        let res = sender.flush().await;
+        assert!(matches!(res, Err(SendError::Io(_))));
+        assert_eq!(sender.live_streams(), 1);
+        sender.shutdown().await.unwrap();
+
+        let mut buf = vec![];
+        receiver
+            .into_async_read()
+            .read_to_end(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, msg);
+    }
+
+    #[tokio::test]
+    async fn migrate() {
+        let session = Session::new(7);
+        let (send_streams, recv_streams) = duplex_streams(2);
+        // Most of what the old streams carry is still in their pipes when they go
+        let mut receiver = ReceiverBuilder::new()
+            .buffer_limit(NonZeroUsize::new(1 << 12).unwrap())
+            .session(session)
+            .build(recv_streams);
+        let mut sender = SenderBuilder::new()
+            .max_segment_size(NonZeroUsize::new(1 << 12).unwrap())
+            .retransmission(receiver.acks(), NonZeroUsize::new(1 << 20).unwrap())
+            .session(session)
+            .build(send_streams);
+
+        let msg: Vec<u8> = (0..1 << 18).map(|_| rand::random()).collect();
+        let msg = Bytes::from(msg);
+        sender.batch_send_all(msg.slice(..1 << 16)).await.unwrap();
+        let mut buf = vec![0; 1 << 16];
+        let mut filled = 0;
+        while filled < 1 << 10 {
+            filled += receiver.recv(&mut buf[filled..1 << 10]).await.unwrap();
+        }
+
+        let (send_streams, recv_streams) = duplex_streams(2);
+        let ids = sender.migrate(send_streams).await.unwrap();
+        assert_eq!(ids, [StreamId::new(2), StreamId::new(3)]);
+        assert_eq!(sender.live_streams(), 2);
+        let retransmitted: u64 = (sender.stats().iter())
+            .map(|stats| stats.retransmitted_bytes)
+            .sum();
+        assert!(retransmitted > 0);
+        assert_eq!(receiver.migrate(recv_streams).await, 2..4);
+
+        let send = async {
+            sender.batch_send_all(msg.slice(1 << 16..)).await.unwrap();
+            sender.shutdown().await.unwrap();
+        };
+        let recv = async {
+            let mut buf = buf;
+            loop {
+                if filled == buf.len() {
+                    buf.resize(buf.len() * 2, 0);
+                }
+                match receiver.recv(&mut buf[filled..]).await.unwrap() {
+                    0 => break,
+                    read => filled += read,
+                }
+            }
+            buf.truncate(filled);
+            buf
+        };
+        let ((), received) = tokio::join!(send, recv);
+        assert_eq!(received, msg);
+        assert!(receiver.take_subflow_errors().is_empty());
+    }
+
+    #[tokio::test]
+    async fn close_delivers_everything() {
+        let (send_streams, recv_streams) = duplex_streams(3);
+        let receiver = Receiver::new(recv_streams);
+        let mut sender = SenderBuilder::new()
+            .cork(Cork::default())
+            .write_buffer(NonZeroUsize::new(1 << 12).unwrap())
+            .retransmission(receiver.acks(), NonZeroUsize::new(1 << 22).unwrap())
+            .build(send_streams);
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver
+                .into_async_read()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            buf
+        });
+
+        let msg: Vec<u8> = (0..1 << 20).map(|_| rand::random()).collect();
+        sender.write_all(&msg).await.unwrap();
+        sender.close().await.unwrap();
+        assert_eq!(sender.retained_bytes(), 0);
+        assert!(matches!(
+            sender.batch_send_all(Bytes::from_static(b"late")).await,
+            Err(SendError::Closed)
+        ));
+        assert_eq!(recv_task.await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn close_timeout() {
+        let (send_streams, _recv_streams) = duplex_streams(2);
+        let (_ack_tx, ack_rx) = watch::channel(Sequence::new(0));
+        let mut sender = SenderBuilder::new()
+            .retransmission(ack_rx, NonZeroUsize::new(1 << 16).unwrap())
+            .close_timeout(Duration::from_millis(100))
+            .build(send_streams);
+
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        let res = sender.close().await;
+        assert!(matches!(res, Err(CloseError::TimedOut { undelivered: 5 })));
+        assert!(matches!(
+            sender.write_all(b"late").await,
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe
+        ));
+    }
+
+    #[tokio::test]
+    async fn retransmission_buffer_limit() {
+        let (tx, _rx) = tokio::io::duplex(1 << 16);
+        let (ack_tx, ack_rx) = watch::channel(Sequence::new(0));
+        let mut sender = Sender::new(vec![tx]);
+        sender.enable_retransmission(ack_rx, NonZeroUsize::new(8).unwrap());
+
+        sender
+            .batch_send_all(Bytes::from_static(b"hello wo"))
+            .await
+            .unwrap();
+        assert_eq!(sender.retained_bytes(), 8);
+        let res = tokio::time::timeout(
+            Duration::from_millis(50),
+            sender.batch_send_all(Bytes::from_static(b"rld")),
+        )
+        .await;
+        assert!(res.is_err());
+
+        // Segments are released only once acknowledged as a whole
+        ack_tx.send(Sequence::new(5)).unwrap();
+        let res = tokio::time::timeout(
+            Duration::from_millis(50),
+            sender.batch_send_all(Bytes::from_static(b"rld")),
+        )
+        .await;
+        assert!(res.is_err());
+
+        ack_tx.send(Sequence::new(8)).unwrap();
+        sender
+            .batch_send_all(Bytes::from_static(b"rld"))
+            .await
+            .unwrap();
+        assert_eq!(sender.retained_bytes(), 3);
+
+        drop(ack_tx);
+        let res = sender.batch_send_all(Bytes::from_static(b"!!!!!!")).await;
+        assert!(matches!(res, Err(SendError::AcksClosed)));
+    }
+
+    #[tokio::test]
+    async fn send_window() {
+        const WINDOW: usize = 1 << 16;
+        let (tx, _rx) = tokio::io::duplex(1 << 21);
+        let (ack_tx, ack_rx) = watch::channel(Sequence::new(0));
+        let mut sender = Sender::new(vec![tx]);
+        sender.enable_retransmission(ack_rx, NonZeroUsize::new(1 << 20).unwrap());
+        sender.set_send_window(NonZeroUsize::new(WINDOW));
+        let mut async_write = sender.into_async_write();
+
+        let msg = vec![0; 1 << 20];
+        let mut written = 0;
+        let mut stalls = 0;
+        while written < msg.len() {
+            let write = async_write.write(&msg[written..]);
+            let Ok(res) = tokio::time::timeout(Duration::from_millis(20), write).await else {
+                // The window is full until the written data is acknowledged
+                stalls += 1;
+                ack_tx.send(Sequence::new(written as u64)).unwrap();
+                continue;
+            };
+            let n = res.unwrap();
+            assert!(0 < n && n <= WINDOW);
+            written += n;
+            assert!(async_write.inner().retained_bytes() <= WINDOW);
+        }
+        assert_eq!(stalls, msg.len() / WINDOW - 1);
+    }
+
+    #[tokio::test]
+    async fn window_caps_resident_bytes() {
+        const WINDOW: usize = 1 << 16;
+        let (tx, rx) = tokio::io::duplex(1 << 16);
+        let receiver = Receiver::new(vec![rx]);
+        let acks = receiver.acks();
+        let reader = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver
+                .into_async_read()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            buf
+        });
+
+        let send_streams: Vec<BoxWriter> = vec![Box::pin(StalledWriter), Box::pin(tx)];
+        let mut sender = Sender::new(send_streams);
+        sender.set_write_timeout(Some(Duration::from_millis(50)));
+        sender.enable_retransmission(acks, NonZeroUsize::new(1 << 20).unwrap());
+        sender.set_send_window(NonZeroUsize::new(WINDOW));
+        let msg: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        for piece in msg.chunks(WINDOW / 4 * 3) {
+            sender.send(Bytes::copy_from_slice(piece)).await.unwrap();
+            assert!(sender.resident_bytes() <= WINDOW);
+        }
+        assert_eq!(sender.take_evicted_streams().len(), 1);
+        sender.shutdown().await.unwrap();
+        assert_eq!(reader.await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn give_up_on_failing_streams() {
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for _ in 0..3 {
+            let (tx, rx) = tokio::io::duplex(1 << 16);
+            send_streams.push(FlakyWriter::new(tx, 0));
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+        let res = sender.batch_send_all(Bytes::from_static(b"hello")).await;
+        let Err(SendError::Incomplete {
+            sent,
+            remaining,
+            errors,
+        }) = res
+        else {
+            panic!("expected an incomplete send");
+        };
+        assert_eq!(sent, 0);
+        assert_eq!(remaining, "hello");
+        assert_eq!(errors.len(), 3);
+        assert!(sender.take_evicted_streams().is_empty());
+        let res = sender.batch_send_all(Bytes::from_static(b"hello")).await;
+        assert!(matches!(
+            res,
+            Err(SendError::NoStreamLeft { streams: 3, .. })
+        ));
+
+        // The first stream dies after carrying one segment
+        const SEGMENT: usize = 1 << 14;
+        let (tx_1, _rx_1) = tokio::io::duplex(1 << 16);
+        let (tx_2, _rx_2) = tokio::io::duplex(1 << 16);
+        let hello_size = 4 + 1 + 4;
+        let frame_size = 1 + 8 + 4 + SEGMENT;
+        let mut sender = Sender::new(vec![
+            FlakyWriter::new(tx_1, hello_size + frame_size),
+            FlakyWriter::new(tx_2, 0),
+        ]);
+        sender.set_max_segment_size(NonZeroUsize::new(SEGMENT));
+        let res = sender
+            .batch_send_all(Bytes::from(vec![0; SEGMENT * 4]))
+            .await;
+        let Err(SendError::Incomplete {
+            sent,
+            remaining,
+            errors,
+        }) = res
+        else {
+            panic!("expected an incomplete send");
+        };
+        assert_eq!(sent, SEGMENT);
+        assert_eq!(remaining.len(), SEGMENT * 3);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn resubmit_remaining() {
+        const SEGMENT: usize = 1 << 12;
+        let hello_size = 4 + 1 + 4;
+        let frame_size = 1 + 8 + 4 + SEGMENT;
+        let (send_streams, recv_streams) = duplex_streams(3);
+        let mut send_streams = send_streams.into_iter();
+        let receiver = Receiver::new(recv_streams);
+        let mut sender = Sender::new(vec![]);
+        // Both die in the middle of a frame halfway through
+        for _ in 0..2 {
+            let budget = hello_size + 8 * frame_size + 100;
+            sender.add_stream(FlakyWriter::new(send_streams.next().unwrap(), budget));
+        }
+        sender.set_max_segment_size(NonZeroUsize::new(SEGMENT));
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver
+                .into_async_read()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            buf
+        });
+
+        let msg: Vec<u8> = (0..1 << 17).map(|_| rand::random()).collect();
+        let res = sender.batch_send_all(Bytes::from(msg.clone())).await;
+        let Err(SendError::Incomplete {
+            sent, remaining, ..
+        }) = res
+        else {
+            panic!("expected an incomplete send");
+        };
+        assert!(0 < sent && sent < msg.len());
+        assert_eq!(&remaining[..], &msg[sent..]);
+        assert_eq!(sender.next_sequence(), Sequence::new(sent as u64));
+
+        let spare = send_streams.next().unwrap();
+        sender.add_stream(FlakyWriter::new(spare, usize::MAX));
+        sender.batch_send_all(remaining).await.unwrap();
+        sender.shutdown().await.unwrap();
+        drop(sender);
+        assert_eq!(recv_task.await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn label_errors() {
+        let (tx_1, _rx_1) = tokio::io::duplex(1 << 16);
+        let (tx_2, _rx_2) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::new_with_labels(vec![
+            ("wan0".to_string(), FlakyWriter::new(tx_1, usize::MAX)),
+            ("wan1".to_string(), FlakyWriter::new(tx_2, 0)),
+        ]);
+        let data = Bytes::from(vec![0; 1 << 16]);
+        let mut send_buf = SendStreamBuf::new(data, Sequence::new(0));
+        send_buf.split_first_unsent_segment(2);
+
+        let Err(SendError::Io(errors)) = sender.batch_send(&mut send_buf).await else {
+            panic!("expected I/O errors");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].id(), StreamId::new(1));
+        assert_eq!(errors[0].label(), Some("wan1"));
+        assert_eq!(errors[0].sequence(), Some(Sequence::new(1 << 15)));
+        assert_eq!(
+            SendError::Io(errors).to_string(),
+            "Stream I/O errors: [stream 1 (wan1) at sequence 32768: flaky]"
+        );
+    }
+
+    #[tokio::test]
+    async fn write_vectored() {
+        let (tx, mut rx) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::new(vec![tx]);
+        sender.set_send_window(NonZeroUsize::new(8));
+
+        let bufs = [b"hello".as_slice(), b" ", b"world"].map(IoSlice::new);
+        let n = sender.write_vectored(&bufs).await.unwrap();
+        assert_eq!(n, 8);
+
+        Hello::decode(&mut rx).await.unwrap();
+        let Message::DataSegment(data_segment) = Message::decode(&mut rx).await.unwrap() else {
+            panic!("expected a data segment");
+        };
+        assert_eq!(&data_segment.payload()[..], b"hello wo");
+    }
+
+    #[tokio::test]
+    async fn send_without_copy() {
+        let (tx, rx) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::new(vec![tx]);
+        sender.set_send_window(NonZeroUsize::new(4));
+        let mut receiver = Receiver::new(vec![rx]).into_async_read();
+
+        sender
+            .send(Bytes::from_static(b"hello world"))
+            .await
+            .unwrap();
+        assert_eq!(sender.stats()[0].segments_written(), 3);
+        let mut buf = [0; 11];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn send_chained_bytes() {
+        let (tx, rx) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::with_initial_sequence(vec![tx], Sequence::new(3));
+        let mut receiver = Receiver::with_expected_sequence(vec![rx], Sequence::new(3));
+
+        let rope = Bytes::from_static(b"hello")
+            .chain(Bytes::from_static(b" multipath"))
+            .chain(Bytes::from_static(b" world"));
+        sender.send_buf(rope).await.unwrap();
+        assert_eq!(sender.next_sequence(), Sequence::new(24));
+        assert_eq!(sender.stats()[0].segments_written(), 3);
+        sender.shutdown().await.unwrap();
+
+        let mut pieces = vec![];
+        while let Some(piece) = receiver.recv_bytes().await.unwrap() {
+            pieces.push(piece);
+        }
+        assert_eq!(pieces.concat(), b"hello multipath world");
+    }
+
+    #[tokio::test]
+    async fn drive_submissions_between_ticks() {
+        async fn received(streams: Vec<DuplexStream>) -> Vec<u8> {
+            let mut receiver = Receiver::new(streams);
+            let mut buf = vec![];
+            while let Some(piece) = receiver.recv_bytes().await.unwrap() {
+                buf.extend_from_slice(&piece);
+            }
+            buf
+        }
+        let msg: Vec<u8> = (0..1 << 18).map(|_| rand::random()).collect();
+        let chunks: Vec<Bytes> = msg.chunks(1 << 14).map(Bytes::copy_from_slice).collect();
+
+        let (send_streams, recv_streams): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| tokio::io::duplex(1 << 12)).unzip();
+        let recv_task = tokio::spawn(received(recv_streams));
+        let mut sender = Sender::new(send_streams);
+        sender.set_max_segment_size(NonZeroUsize::new(1 << 12));
+        for chunk in &chunks {
+            sender.batch_send_all(chunk.clone()).await.unwrap();
+        }
+        sender.shutdown().await.unwrap();
+        let plain = recv_task.await.unwrap();
+        assert_eq!(plain, msg);
+
+        let (send_streams, recv_streams): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| tokio::io::duplex(1 << 12)).unzip();
+        let recv_task = tokio::spawn(received(recv_streams));
+        let mut sender = Sender::new(send_streams);
+        sender.set_max_segment_size(NonZeroUsize::new(1 << 12));
+        let tickets: Vec<SendTicket> = chunks
+            .iter()
+            .map(|chunk| sender.submit(chunk.clone()).unwrap())
+            .collect();
+        assert_eq!(
+            tickets[1].sequences(),
+            Sequence::new(1 << 14)..Sequence::new(1 << 15)
+        );
+        assert_eq!(sender.next_sequence(), Sequence::new(msg.len() as u64));
+
+        let mut interval = tokio::time::interval(Duration::from_micros(50));
+        let (mut ticks, mut rounds) = (0, 0);
+        let mut sent_before_last = false;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => ticks += 1,
+                progress = sender.drive_once() => {
+                    rounds += 1;
+                    let progress = progress.unwrap();
+                    sent_before_last |= tickets[0].is_sent() && !tickets.last().unwrap().is_sent();
+                    if progress.pending == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        assert!(ticks > 0 && rounds > chunks.len(), "{ticks} {rounds}");
+        assert!(sent_before_last);
+        assert!(tickets.iter().all(SendTicket::is_sent));
+        let progress = sender.drive_once().await.unwrap();
+        assert_eq!(
+            progress,
+            DriveProgress {
+                sent_bytes: 0,
+                pending: 0
+            }
+        );
+        sender.shutdown().await.unwrap();
+        assert_eq!(recv_task.await.unwrap(), plain);
+    }
+
+    #[tokio::test]
+    async fn send_oob_on_least_loaded_stream() {
+        /// The out-of-band messages written on `rx` up to its shutdown
+        async fn oob_messages(mut rx: DuplexStream) -> Vec<(u32, Bytes)> {
+            Hello::decode(&mut rx).await.unwrap();
+            let mut messages = vec![];
+            loop {
+                match Message::decode(&mut rx).await.unwrap() {
+                    Message::Oob { sequence, payload } => messages.push((sequence, payload)),
+                    Message::Shutdown => return messages,
+                    _ => (),
+                }
+            }
+        }
+
+        let (tx0, rx0) = tokio::io::duplex(1 << 16);
+        let (tx1, rx1) = tokio::io::duplex(1 << 16);
+        let (_ack_tx, ack_rx) = watch::channel(Sequence::new(0));
+        let mut sender = Sender::new(vec![tx0]);
+        sender.enable_retransmission(ack_rx, NonZeroUsize::new(1 << 16).unwrap());
+        sender
+            .batch_send_all(Bytes::from_static(b"unacknowledged"))
+            .await
+            .unwrap();
+        sender.add_stream(tx1);
+
+        // Away from the stream holding unacknowledged data
+        sender
+            .send_oob(Bytes::from_static(b"urgent"))
+            .await
+            .unwrap();
+        sender.set_send_mode(SendMode::Duplicate);
+        sender.send_oob(Bytes::from_static(b"both")).await.unwrap();
+        let payload = Bytes::from(vec![0; MAX_OOB_PAYLOAD_SIZE + 1]);
+        let res = sender.send_oob(payload).await;
+        assert!(matches!(res, Err(SendError::OobTooLarge(_))));
+        sender.shutdown().await.unwrap();
+
+        let both = (1, Bytes::from_static(b"both"));
+        assert_eq!(oob_messages(rx0).await, std::slice::from_ref(&both));
+        let urgent = (0, Bytes::from_static(b"urgent"));
+        assert_eq!(oob_messages(rx1).await, [urgent, both]);
+    }
+
+    #[tokio::test]
+    async fn send_on_borrowed_streams() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut conns = vec![];
+        let mut accepted = vec![];
+        for _ in 0..2 {
+            let (conn, res) = tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+            conns.push(conn.unwrap());
+            accepted.push(res.unwrap().0);
+        }
+        let mut receiver = Receiver::new(accepted).into_async_read();
+
+        // Halves of connections the caller keeps, which are neither `'static` nor owned by a sender
+        let mut halves: Vec<tokio::net::tcp::WriteHalf<'_>> =
+            conns.iter_mut().map(|conn| conn.split().1).collect();
+        Sender::handshake_on(&mut halves).await.unwrap();
+        let msg: Vec<u8> = (0..1 << 20).map(|_| rand::random()).collect();
+        let mut next = Sequence::new(0);
+        for chunk in msg.chunks(1 << 16) {
+            let data = Bytes::copy_from_slice(chunk);
+            let errors = Sender::batch_send_all_on(&mut halves, data, &mut next)
+                .await
+                .unwrap();
+            assert!(errors.is_empty());
+        }
+        assert_eq!(next, Sequence::new(1 << 20));
+        drop(halves);
+
+        let mut buf = vec![0; msg.len()];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+        assert_eq!(conns.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn send_on_borrowed_streams_failing() {
+        let mut hello = vec![];
+        Hello::new(0).encode(&mut hello).await.unwrap();
+        let (tx1, rx1) = tokio::io::duplex(1 << 16);
+        let (tx2, rx2) = tokio::io::duplex(1 << 16);
+        let mut streams = vec![
+            TearingWriter::new(tx1, usize::MAX),
+            TearingWriter::new(tx2, hello.len() + 4),
+        ];
+        let mut receiver = Receiver::new(vec![rx1, rx2]).into_async_read();
+
+        Sender::handshake_on(&mut streams).await.unwrap();
+        let msg: Vec<u8> = (0..1 << 15).map(|_| rand::random()).collect();
+        let mut next = Sequence::new(0);
+        let data = Bytes::from(msg.clone());
+        let errors = Sender::batch_send_all_on(&mut streams, data, &mut next)
+            .await
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].id(), StreamId(1));
+        assert_eq!(next, Sequence::new(1 << 15));
+        let mut buf = vec![0; msg.len()];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+
+        // Every stream torn
+        let mut streams = vec![TearingWriter::new(tokio::io::duplex(64).0, 0)];
+        let res =
+            Sender::batch_send_all_on(&mut streams, Bytes::from_static(b"!"), &mut next).await;
+        let Err(SendError::Incomplete {
+            sent,
+            remaining,
+            errors,
+        }) = res
+        else {
+            panic!("expected an incomplete send");
+        };
+        assert_eq!((sent, &remaining[..], errors.len()), (0, &b"!"[..], 1));
+        assert_eq!(next, Sequence::new(1 << 15));
+    }
+
+    #[derive(Debug)]
+    struct Primary {
+        assign: bool,
+    }
+
+    impl Scheduler for Primary {
+        fn assign(&mut self, segments: &[SegmentMeta], streams: &[StreamMeta]) -> Vec<Assignment> {
+            if !self.assign || segments.is_empty() || streams.is_empty() {
+                return vec![];
+            }
+            vec![Assignment {
+                segment: 0,
+                stream: 0,
+            }]
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_scheduler() {
+        let send_streams = (0..3).map(|_| tokio::io::sink()).collect();
+        let mut sender = Sender::new(send_streams);
+        sender.set_scheduler(Primary { assign: true });
+        sender
+            .batch_send_all(Bytes::from(vec![0; 1 << 16]))
+            .await
+            .unwrap();
+        let written: Vec<u64> = sender.stats().iter().map(|s| s.bytes_written()).collect();
+        assert_eq!(written, [1 << 16, 0, 0]);
+        assert!(sender.stats()[0].segments_written() > 1);
+
+        sender.set_scheduler(Primary { assign: false });
+        let res = sender.batch_send_all(Bytes::from_static(b"hello")).await;
+        let Err(SendError::Incomplete {
+            sent,
+            remaining,
+            errors,
+        }) = res
+        else {
+            panic!("expected an incomplete send");
+        };
+        assert_eq!(sent, 0);
+        assert_eq!(remaining, "hello");
+        assert!(errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicate() {
+        const DELAY: Duration = Duration::from_millis(500);
+        let (fast_tx, fast_rx) = tokio::io::duplex(1 << 16);
+        let (slow_tx, mut slow_mid) = tokio::io::duplex(1 << 16);
+        let (mut slow_mid_tx, slow_rx) = tokio::io::duplex(1 << 16);
+        tokio::spawn(async move {
+            tokio::time::sleep(DELAY).await;
+            tokio::io::copy(&mut slow_mid, &mut slow_mid_tx).await
+        });
+        let mut sender = Sender::new(vec![fast_tx, slow_tx]);
+        sender.set_send_mode(SendMode::Duplicate);
+        let mut receiver = Receiver::new(vec![fast_rx, slow_rx]).into_async_read();
+
+        let start = Instant::now();
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        let mut buf = [0; 5];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(start.elapsed() < DELAY / 2);
+
+        sender
+            .batch_send_all_with_mode(Bytes::from_static(b" world"), SendMode::Stripe)
+            .await
+            .unwrap();
+        sender
+            .batch_send_all(Bytes::from_static(b"!"))
+            .await
+            .unwrap();
+        sender.shutdown().await.unwrap();
+        let mut buf = vec![];
+        receiver.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b" world!");
+        let written: Vec<u64> = sender.stats().iter().map(|s| s.bytes_written()).collect();
+        assert_eq!(written.iter().sum::<u64>(), 5 * 2 + 6 + 2);
+    }
+
+    #[tokio::test]
+    async fn sticky() {
+        let (a_tx, a_rx) = tokio::io::duplex(1 << 16);
+        let (b_tx, b_rx) = tokio::io::duplex(1 << 16);
+        let mut sender = Sender::new(vec![a_tx, b_tx]);
+        sender.set_send_mode(SendMode::Sticky { threshold: 1000 });
+        let (_acks_tx, acks) = watch::channel(Sequence::new(0));
+        sender.enable_retransmission(acks, NonZeroUsize::new(1 << 20).unwrap());
+        let mut receiver = Receiver::new(vec![a_rx, b_rx]).into_async_read();
+        let data: Vec<u8> = (0..60_000).map(|i| i as u8).collect();
+        let data = Bytes::from(data);
+
+        let segments = |sender: &Sender<_>| -> Vec<u64> {
+            sender
+                .stats()
+                .iter()
+                .map(|s| s.segments_written())
+                .collect()
+        };
+        // The stream without an estimate goes first, then the one acknowledging sooner
+        sender.batch_send_all(data.slice(..999)).await.unwrap();
+        let slow = segments(&sender).iter().position(|&n| n == 1).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        sender.handle_ack(Sequence::new(999), &[]);
+        sender.batch_send_all(data.slice(999..1998)).await.unwrap();
+        sender.handle_ack(Sequence::new(1998), &[]);
+        for i in 2..4 {
+            sender
+                .batch_send_all(data.slice(i * 999..(i + 1) * 999))
+                .await
+                .unwrap();
+        }
+        let mut expected = [3, 3];
+        expected[slow] = 1;
+        assert_eq!(segments(&sender), expected);
+        assert_eq!(sender.stats()[slow].bytes_written(), 999);
+
+        // Larger sends still stripe, and so do their submissions
+        sender.set_max_segment_size(NonZeroUsize::new(5000));
+        sender
+            .batch_send_all(data.slice(3996..23996))
+            .await
+            .unwrap();
+        sender.submit(data.slice(23996..43996)).unwrap();
+        sender.submit(data.slice(43996..44496)).unwrap();
+        while sender.drive_once().await.unwrap().pending > 0 {}
+        // At least 4 segments of each large send, depending on the split between the streams
+        let segments = segments(&sender);
+        assert!(segments.iter().sum::<u64>() > 4 + 4 + 4);
+
+        sender.batch_send_all(data.slice(44496..)).await.unwrap();
+        sender.shutdown().await.unwrap();
+        let mut buf = vec![];
+        receiver.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[tokio::test]
+    async fn backup_stays_idle() {
+        let mut sender = Sender::new_with_priorities(vec![
+            (tokio::io::sink(), Priority::PRIMARY),
+            (tokio::io::sink(), Priority::BACKUP),
+            (tokio::io::sink(), Priority::PRIMARY),
+        ]);
+        for _ in 0..4 {
+            sender
+                .batch_send_all(Bytes::from(vec![0; 1 << 18]))
+                .await
+                .unwrap();
+        }
+        let written: Vec<u64> = sender.stats().iter().map(|s| s.bytes_written()).collect();
+        assert!(written[0] > 0 && written[2] > 0);
+        assert_eq!(written[1], 0);
+        assert_eq!(sender.stats()[1].priority(), Priority::BACKUP);
+        assert_eq!(sender.active_tier(), Some(Priority::PRIMARY));
+        assert!(sender.take_tier_changes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failover_and_failback() {
+        let (primary_tx, primary_rx) = tokio::io::duplex(1 << 20);
+        let (backup_tx, backup_rx) = tokio::io::duplex(1 << 20);
+        let primary: BoxWriter = Box::pin(FlakyWriter::new(primary_tx, 1 << 10));
+        let backup: BoxWriter = Box::pin(backup_tx);
+        let mut sender = Sender::new_with_priorities(vec![
+            (primary, Priority::PRIMARY),
+            (backup, Priority::BACKUP),
+        ]);
+        let mut receiver = Receiver::new(vec![primary_rx, backup_rx]).into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        sender
+            .batch_send_all(Bytes::from(msg.clone()))
+            .await
+            .unwrap();
+        let mut buf = vec![0; msg.len()];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+        assert_eq!(sender.take_evicted_streams().len(), 1);
+        assert_eq!(sender.active_tier(), Some(Priority::BACKUP));
+        assert_eq!(
+            sender.take_tier_changes(),
+            [TierChange {
+                from: Some(Priority::PRIMARY),
+                to: Some(Priority::BACKUP),
+                reason: TierChangeReason::Failover,
+            }]
+        );
+        let backup_written = sender.stats()[1].bytes_written();
+        assert_eq!(backup_written, msg.len() as u64);
+
+        sender.add_stream_with_priority(Priority::PRIMARY, Box::pin(tokio::io::sink()));
+        assert_eq!(
+            sender.take_tier_changes(),
+            [TierChange {
+                from: Some(Priority::BACKUP),
+                to: Some(Priority::PRIMARY),
+                reason: TierChangeReason::Failback,
+            }]
+        );
+        sender
+            .batch_send_all(Bytes::from(vec![0; 1 << 16]))
+            .await
+            .unwrap();
+        let stats = sender.stats();
+        assert_eq!(stats[1].bytes_written(), backup_written);
+        assert_eq!(stats[2].bytes_written(), 1 << 16);
+    }
+
+    #[tokio::test]
+    async fn resume_from_sequence() {
+        let (tx_1, rx_1) = tokio::io::duplex(1 << 16);
+        let (tx_2, rx_2) = tokio::io::duplex(1 << 16);
+        let mut receiver = Receiver::new(vec![rx_1, rx_2]).into_async_read();
+
+        let mut first = Sender::new(vec![tx_1]);
+        first
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        let next = first.next_sequence();
+        assert_eq!(next, Sequence::new(5));
+        drop(first);
+
+        let mut second = Sender::with_initial_sequence(vec![tx_2], next);
+        second
+            .batch_send_all(Bytes::from_static(b" world"))
+            .await
+            .unwrap();
+        assert_eq!(second.next_sequence(), Sequence::new(11));
+        second.shutdown().await.unwrap();
+
+        let mut buf = vec![];
+        receiver.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn builder() {
+        let (tx, mut rx) = tokio::io::duplex(1 << 20);
+        let send_streams: Vec<BoxWriter> = vec![Box::pin(StalledWriter), Box::pin(tx)];
+        let mut sender = SenderBuilder::new()
+            .initial_sequence(Sequence::new(100))
+            .labels(vec!["stalled".into()])
+            .priorities(vec![Priority::PRIMARY, Priority::PRIMARY])
+            .write_timeout(Duration::from_millis(100))
+            .max_segment_size(NonZeroUsize::new(4).unwrap())
+            .checksum(true)
+            .keepalive(Duration::from_secs(1))
+            .build(send_streams);
+        assert_eq!(sender.next_sequence(), Sequence::new(100));
+        assert_eq!(sender.active_tier(), Some(Priority::PRIMARY));
+        assert!(sender.next_heartbeat().is_some());
+
+        sender
+            .batch_send_all(Bytes::from_static(b"hello world"))
+            .await
+            .unwrap();
+        let evicted = sender.take_evicted_streams();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].label(), Some("stalled"));
+        assert_eq!(evicted[0].error().kind(), io::ErrorKind::TimedOut);
+        sender.shutdown().await.unwrap();
+
+        // Checksummed segments of at most 4 bytes from sequence 100 on
+        Hello::decode(&mut rx).await.unwrap();
+        let mut segments = vec![];
+        loop {
+            let type_code = rx.read_u8().await.unwrap();
+            match type_code {
+                3 => {
+                    let data_segment = DataSegment::decode(&mut rx).await.unwrap();
+                    assert_eq!(rx.read_u32().await.unwrap(), data_segment.checksum());
+                    segments.push((data_segment.start_sequence().inner(), data_segment.size()));
+                }
+                1 => (),
+                4 => assert_eq!(rx.read_u64().await.unwrap(), 111),
+                2 => break,
+                _ => panic!("unexpected type code {type_code}"),
+            }
+        }
+        segments.sort();
+        assert_eq!(segments, [(100, 4), (104, 4), (108, 3)]);
+    }
+
+    #[tokio::test]
+    async fn builder_defaults() {
+        let sender = SenderBuilder::default().build(vec![tokio::io::sink()]);
+        assert_eq!(sender.next_sequence(), Sequence::new(0));
+        assert_eq!(sender.active_tier(), Some(Priority::default()));
+        assert!(sender.next_heartbeat().is_none());
+        assert_eq!(sender.write_timeout, None);
+        assert_eq!(sender.send_deadline, None);
+        assert_eq!(sender.write_buffer, None);
+        assert_eq!(sender.cork, None);
+        assert_eq!(sender.max_segment_size, None);
+        assert_eq!(sender.min_segment_size, None);
+        assert_eq!(sender.send_chunk_size.get(), DEFAULT_SEND_CHUNK_SIZE);
+        assert!(!sender.encode_options.checksum);
+        assert_eq!(sender.send_window, None);
+        assert!(sender.retransmission.is_none());
+        assert_eq!(sender.send_mode, SendMode::Stripe);
+        assert_eq!(sender.decision_log().capacity(), 0);
+    }
+
+    #[tokio::test]
+    async fn decision_log() {
+        let (send_streams, _recv_streams) = duplex_streams(2);
+        let mut sender = Sender::new(send_streams);
+        sender
+            .batch_send_all(Bytes::from(vec![0; 1 << 10]))
+            .await
+            .unwrap();
+        assert!(sender.decision_log().is_empty());
+
+        sender.set_decision_log(64);
+        for size in [1 << 16, 1 << 10, 3, 1 << 15] {
+            sender
+                .batch_send_all(Bytes::from(vec![0; size]))
+                .await
+                .unwrap();
+        }
+        let log = sender.decision_log();
+        assert!(!log.is_empty());
+        assert_eq!(log.dropped(), 0);
+        let logged: usize = log.iter().map(|d| d.size()).sum();
+        assert_eq!(logged, (1 << 16) + (1 << 10) + 3 + (1 << 15));
+
+        // Each stream wrote what the log handed it, on top of what it had written before the log
+        let before = log.iter().next().unwrap().streams();
+        for stats in sender.stats() {
+            let handed: usize = log
+                .iter()
+                .filter(|d| d.stream() == stats.id())
+                .map(|d| d.size())
+                .sum();
+            let prior = before.iter().find(|s| s.id() == stats.id()).unwrap();
+            assert_eq!(stats.bytes_written(), prior.bytes_written() + handed as u64);
+        }
+
+        let mut dump = vec![];
+        log.write_to(&mut dump).unwrap();
+        assert_eq!(dump.iter().filter(|&&b| b == b'\n').count(), log.len());
+    }
+
+    #[tokio::test]
+    async fn copy_into_sender() {
+        let (send_streams, recv_streams) = duplex_streams(4);
+        let receiver = Receiver::new(recv_streams);
+        let reader = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver
+                .into_async_read()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            buf
+        });
+
+        let mut sender = Sender::new(send_streams);
+        let msg: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        let n = tokio::io::copy(&mut &msg[..], &mut sender).await.unwrap();
+        assert_eq!(n, msg.len() as u64);
+        AsyncWriteExt::shutdown(&mut sender).await.unwrap();
+        assert_eq!(reader.await.unwrap(), msg);
+        assert_eq!(sender.next_sequence(), Sequence::new(1 << 20));
+        assert_eq!(sender.stats().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn poll_write_is_partial() {
+        const WINDOW: usize = 1 << 12;
+        let (tx, _rx) = tokio::io::duplex(1 << 16);
+        let (ack_tx, ack_rx) = watch::channel(Sequence::new(0));
+        let mut sender = Sender::new(vec![tx]);
+        sender.enable_retransmission(ack_rx, NonZeroUsize::new(1 << 20).unwrap());
+        sender.set_send_window(NonZeroUsize::new(WINDOW));
+
+        let msg = vec![0; 1 << 16];
+        let n = AsyncWriteExt::write(&mut sender, &msg).await.unwrap();
+        assert_eq!(n, WINDOW);
+        assert_eq!(sender.retained_bytes(), WINDOW);
+
+        // The window stays full without acknowledgements
+        let write = AsyncWriteExt::write(&mut sender, &msg);
+        assert!(tokio::time::timeout(Duration::from_millis(50), write)
+            .await
+            .is_err());
+        // The sender is lent to the pending write until it is polled again
+        assert_eq!(sender.stats().len(), 0);
+        let write = AsyncWriteExt::write(&mut sender, &msg);
+        ack_tx.send(Sequence::new(WINDOW as u64)).unwrap();
+        assert_eq!(write.await.unwrap(), WINDOW);
+        assert_eq!(sender.stats().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn not_send_streams() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Sender<DuplexStream>>();
+
+        let writers = vec![RcWriter::default(), RcWriter::default()];
+        let mut sender = SenderBuilder::new()
+            .max_segment_size(NonZeroUsize::new(1 << 10).unwrap())
+            .build_local(writers.clone());
+        sender
+            .batch_send_all(Bytes::from(vec![0; 1 << 16]))
+            .await
+            .unwrap();
+
+        let mut async_write = sender.into_local_async_write();
+        async_write.write_all(&[1; 1 << 16]).await.unwrap();
+        async_write.flush().await.unwrap();
+        async_write.shutdown().await.unwrap();
+        let sender = async_write.into_inner();
+        let sent: u64 = sender.stats().iter().map(|s| s.bytes_written()).sum();
+        assert_eq!(sent, 1 << 17);
+        assert!(writers.iter().all(|w| !w.written.borrow().is_empty()));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "broken writer")]
+    async fn write_panic_propagates() {
+        let mut sender = Sender::new(vec![PanickingWriter]);
+        let _ = sender.batch_send_all(Bytes::from_static(b"hello")).await;
+    }
+
+    #[tokio::test]
+    async fn cancel_batch_send() {
+        let send_streams = vec![ThrottledWriter::new(1 << 10), ThrottledWriter::new(1 << 10)];
+        let mut sender = Sender::new(send_streams);
+
+        tokio::select! {
+            res = sender.batch_send_all(Bytes::from(vec![0; 1 << 20])) => panic!("{res:?}"),
+            () = tokio::time::sleep(Duration::from_millis(50)) => (),
+        }
+        assert_eq!(sender.live_streams(), 2);
+        assert_eq!(sender.next_sequence(), Sequence::new(1 << 20));
+
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(sender.live_streams(), 2);
+        assert!(sender.take_evicted_streams().is_empty());
+        let stats = sender.stats();
+        assert_eq!(stats.len(), 2);
+        assert!(stats.iter().all(|s| s.live()));
+        let written: u64 = stats.iter().map(|s| s.bytes_written()).sum();
+        assert_eq!(written, (1 << 20) + 5);
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_cancelled_writes() {
+        const DELAY: Duration = Duration::from_millis(10);
+        const DEADLINE: Duration = Duration::from_secs(1);
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for _ in 0..2 {
+            let (tx, rx) = tokio::io::duplex(1 << 20);
+            send_streams.push(DelayedWriter::new(tx, DELAY));
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+        let mut receiver = Receiver::new(recv_streams).into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        tokio::select! {
+            res = sender.batch_send_all(Bytes::from(msg.clone())) => panic!("{res:?}"),
+            () = tokio::time::sleep(DELAY / 2) => (),
+        }
+        sender.flush().await.unwrap();
+        let mut buf = vec![0; msg.len()];
+        tokio::time::timeout(DEADLINE, receiver.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, msg);
+
+        // Requests are delivered once flushed
+        let mut async_write = sender.into_async_write();
+        for i in 0..8_u8 {
+            let request = [i; 100];
+            async_write.write_all(&request).await.unwrap();
+            async_write.flush().await.unwrap();
+            let mut buf = [0; 100];
+            tokio::time::timeout(DEADLINE, receiver.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(buf, request);
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_interrupted_writes() {
+        let mut send_streams = vec![];
+        let mut recv_streams = vec![];
+        for _ in 0..2 {
+            let (tx, rx) = tokio::io::duplex(1 << 16);
+            send_streams.push(FailingWriter::new(tx, io::ErrorKind::Interrupted, 1));
+            recv_streams.push(rx);
+        }
+        let mut sender = Sender::new(send_streams);
+        let mut receiver = Receiver::new(recv_streams).into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        sender
+            .batch_send_all(Bytes::from(msg.clone()))
+            .await
+            .unwrap();
+        assert_eq!(sender.live_streams(), 2);
+        assert!(sender.take_evicted_streams().is_empty());
+        sender.shutdown().await.unwrap();
+        let mut buf = vec![];
+        receiver.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+    }
+
+    #[tokio::test]
+    async fn evict_after_retries() {
+        let (tx_1, rx_1) = tokio::io::duplex(1 << 16);
+        let (tx_2, rx_2) = tokio::io::duplex(1 << 16);
+        let mut sender = SenderBuilder::new().max_write_retries(2).build(vec![
+            FailingWriter::new(tx_1, io::ErrorKind::Interrupted, 3),
+            FailingWriter::new(tx_2, io::ErrorKind::Interrupted, 2),
+        ]);
+        let mut receiver = Receiver::new(vec![rx_1, rx_2]).into_async_read();
+
+        // Only the stream failing more often than retried is evicted
+        let msg: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        sender
+            .batch_send_all(Bytes::from(msg.clone()))
+            .await
+            .unwrap();
+        assert_eq!(sender.live_streams(), 1);
+        let evicted = sender.take_evicted_streams();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].id(), StreamId::new(0));
+        sender.shutdown().await.unwrap();
+        let mut buf = vec![];
+        receiver.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+    }
+
+    #[tokio::test]
+    async fn custom_failure_policy() {
+        #[derive(Debug)]
+        struct RetryTimeouts;
+
+        impl FailurePolicy for RetryTimeouts {
+            fn classify(&self, error: &io::Error) -> FailureAction {
+                match error.kind() {
+                    io::ErrorKind::TimedOut => FailureAction::Retry,
+                    _ => FailureAction::Evict,
+                }
+            }
+        }
+
+        let send = |policy: Option<RetryTimeouts>| async move {
+            let (tx_1, _rx_1) = tokio::io::duplex(1 << 16);
+            let (tx_2, _rx_2) = tokio::io::duplex(1 << 16);
+            let mut sender = Sender::new(vec![]);
+            if let Some(policy) = policy {
+                sender.set_failure_policy(policy);
+            }
+            sender.add_stream(FailingWriter::new(tx_1, io::ErrorKind::TimedOut, 1));
+            sender.add_stream(FailingWriter::new(tx_2, io::ErrorKind::TimedOut, 0));
+            sender
+                .batch_send_all(Bytes::from_static(b"hello"))
+                .await
+                .unwrap();
+            sender.live_streams()
+        };
+        assert_eq!(send(None).await, 1);
+        assert_eq!(send(Some(RetryTimeouts)).await, 2);
+    }
+
+    #[tokio::test]
+    async fn resume_torn_frames() {
+        const HELLO_SIZE: usize = 4 + 1 + 4;
+        let payload = Bytes::from((0..100).collect::<Vec<u8>>());
+        let message = Message::DataSegment(DataSegment::new(Sequence::new(0), payload).unwrap());
+        for compact in [false, true] {
+            // Torn in the handshake, mid-header, between header and payload, mid-payload and in the checksum
+            for budget in 0.. {
+                let (tx, mut rx) = tokio::io::duplex(1 << 16);
+                let mut sender = SenderBuilder::new()
+                    .checksum(true)
+                    .compact_headers(compact)
+                    .build(vec![TearingWriter::new(tx, budget)]);
+                let options = sender.write_options();
+                let mut subflow = sender.streams.pop_front().unwrap();
+                if subflow.write(&message, options).await.is_ok() {
+                    assert!(budget > HELLO_SIZE + 100);
+                    break;
+                }
+                if budget >= HELLO_SIZE {
+                    // No other frame fits on the stream until the torn one is whole
+                    assert!(subflow.write(&Message::Ping, options).await.is_err());
+                }
+                subflow.write(&message, options).await.unwrap();
+                drop(subflow);
+
+                let hello = Hello::decode(&mut rx).await.unwrap();
+                assert_eq!(hello.capabilities(), options.capabilities);
+                let mut context = HeaderContext::new();
+                let decoded = Message::decode_next_in(&mut rx, &mut context).await;
+                assert_eq!(decoded.unwrap(), Some(message.clone()), "budget {budget}");
+                let end = Message::decode_next_in(&mut rx, &mut context).await;
+                assert_eq!(end.unwrap(), None);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn move_torn_segments() {
+        const HELLO_SIZE: usize = 4 + 1 + 4;
+        let msg: Vec<u8> = (0..100).collect();
+        for torn in [0, 5, 1 + 8 + 4, 1 + 8 + 4 + 50] {
+            let (tx_1, rx_1) = tokio::io::duplex(1 << 16);
+            let (tx_2, rx_2) = tokio::io::duplex(1 << 16);
+            let mut sender = Sender::new(vec![
+                TearingWriter::new(tx_1, HELLO_SIZE + torn),
+                TearingWriter::new(tx_2, usize::MAX),
+            ]);
+            let mut receiver = Receiver::new(vec![rx_1, rx_2]).into_async_read();
+
+            // The segment is written on the other stream from scratch
+            sender
+                .batch_send_all(Bytes::from(msg.clone()))
+                .await
+                .unwrap();
+            assert_eq!(sender.live_streams(), 1);
+            assert_eq!(sender.take_evicted_streams().len(), 1);
+            sender.shutdown().await.unwrap();
+            drop(sender);
+            let mut buf = vec![];
+            receiver.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_segments() {
+        let (send_streams, recv_streams): (Vec<_>, Vec<_>) =
+            (0..2).map(|_| tokio::io::duplex(1 << 16)).unzip();
+        let mut sender = Sender::new(send_streams);
+        let mut receiver = Receiver::new(recv_streams).into_async_read();
+        let msg: Vec<u8> = (0..3000).map(|_| rand::random()).collect();
+        let segment = |range: Range<usize>| {
+            (
+                Sequence::new(range.start as u64),
+                Bytes::copy_from_slice(&msg[range]),
+            )
+        };
+        sender
+            .batch_send_all(Bytes::copy_from_slice(&msg[..1000]))
+            .await
+            .unwrap();
+
+        // Old data only with retransmissions allowed, and never overlapping
+        let res = sender.send_segments(vec![segment(100..200)]).await;
+        assert!(
+            matches!(res, Err(SendError::InvalidSegment { sequence }) if sequence == Sequence::new(100))
+        );
+        sender.set_allow_retransmit(true);
+        let res = sender
+            .send_segments(vec![segment(1000..1500), segment(1400..2000)])
+            .await;
+        assert!(
+            matches!(res, Err(SendError::InvalidSegment { sequence }) if sequence == Sequence::new(1400))
+        );
+        let res = sender
+            .send_segments(vec![(Sequence::new(1000), Bytes::new())])
+            .await;
+        assert!(matches!(res, Err(SendError::InvalidSegment { .. })));
+
+        // Retransmissions interleaved with new data, out of order
+        sender
+            .send_segments(vec![
+                segment(2000..3000),
+                segment(100..200),
+                segment(1000..1500),
+                segment(500..900),
+                segment(1500..2000),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(sender.next, Sequence::new(1000));
+        let mut buf = vec![0; msg.len()];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+    }
+
+    #[tokio::test]
+    async fn handle_selective_acks() {
+        let (tx, _rx) = tokio::io::duplex(1 << 16);
+        let (_acks_tx, acks) = watch::channel(Sequence::new(0));
+        let mut sender = SenderBuilder::new()
+            .retransmission(acks, NonZeroUsize::new(1 << 16).unwrap())
+            .max_segment_size(NonZeroUsize::new(100).unwrap())
+            .build(vec![tx]);
+        sender
+            .batch_send_all(Bytes::from(vec![0; 300]))
+            .await
+            .unwrap();
+        assert_eq!(sender.retained_bytes(), 300);
+
+        // Only whole segments are released out of order
+        let sequence = |sequence| Sequence::new(sequence);
+        sender.handle_ack(
+            sequence(0),
+            &[sequence(50)..sequence(100), sequence(100)..sequence(200)],
+        );
+        assert_eq!(sender.retained_bytes(), 200);
+        sender.handle_ack(sequence(100), &[sequence(200)..sequence(300)]);
+        assert_eq!(sender.retained_bytes(), 0);
+        assert_eq!(sender.undelivered(), 200);
+        sender.handle_ack(sequence(300), &[]);
+        assert_eq!(sender.undelivered(), 0);
+    }
+}
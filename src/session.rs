@@ -0,0 +1,747 @@
+//! Demultiplexing the subflows of many sessions accepted on one listener
+//!
+//! Every connection starts with an `Init` naming its session, and a `SessionMap` groups the connections by it.
+//! A connection carries the frames of that session only.
+//!
+//! The handshakes of the subflows may announce their session and subflow ID too, see `crate::message::Hello::with_subflow`.
+//! A receiver then rejects a subflow repeating the ID of another or belonging to another session with a `SessionSetupError` before reading anything else from it.
+//!
+//! Once the handshakes of both ends are through, `SessionInfo` tells what they agreed on.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, WriteHalf},
+    net::{tcp, TcpStream},
+    task::JoinSet,
+};
+
+use crate::{
+    compression::Compression,
+    message::{
+        Hello, Init, SeqWidth, Session, CAPABILITY_CHECKSUM, CAPABILITY_SEQUENCE_U32, VERSION,
+    },
+    receiver::{Liveness, Receiver, ReceiverBuilder},
+    sender::{SendError, Sender, SenderBuilder, StreamId},
+};
+
+/// The capabilities every subflow of a session has to agree on, unlike those a sender may change for the subflows it adds later
+const NEGOTIATED_CAPABILITIES: u32 = CAPABILITY_SEQUENCE_U32;
+
+/// What a `SessionMap` takes in before it drops connections
+///
+/// The caps bound the memory and tasks any number of clients can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionLimits {
+    /// Sessions established or waiting for their subflows at once
+    pub max_sessions: usize,
+    /// Subflows a session may announce in its `Init`
+    pub max_session_streams: NonZeroUsize,
+    /// Connections whose `Init` has not arrived yet
+    pub max_handshakes: usize,
+    /// How long a connection may take to deliver its `Init`
+    pub init_timeout: Duration,
+    /// How long a session may wait for its remaining subflows since the last one arrived
+    pub assembly_timeout: Duration,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            max_sessions: 1024,
+            max_session_streams: NonZeroUsize::new(8).unwrap(),
+            max_handshakes: 64,
+            init_timeout: Duration::from_secs(1),
+            assembly_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Groups accepted connections into sessions by the `Init` each one starts with
+///
+/// A session is handed out by `Self::next_session` once all the subflows its `Init` announces have arrived.
+/// It counts against `SessionLimits::max_sessions` until every subflow of its `Receiver` has closed, so its ID cannot be reused before.
+/// Connections beyond the limits, with an invalid or late `Init`, or disagreeing with the other subflows of their session are dropped.
+#[derive(Debug)]
+pub struct SessionMap<S> {
+    limits: SessionLimits,
+    handshakes: JoinSet<io::Result<(Init, S)>>,
+    pending: HashMap<Session, PendingSession<S>>,
+    established: HashMap<Session, Liveness>,
+    rejected: u64,
+}
+
+impl<S> SessionMap<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(limits: SessionLimits) -> Self {
+        Self {
+            limits,
+            handshakes: JoinSet::new(),
+            pending: HashMap::new(),
+            established: HashMap::new(),
+            rejected: 0,
+        }
+    }
+
+    /// Take an accepted connection and read its `Init` in the background
+    pub fn insert(&mut self, mut connection: S) {
+        if self.limits.max_handshakes <= self.handshakes.len() {
+            self.rejected += 1;
+            return;
+        }
+        let timeout = self.limits.init_timeout;
+        self.handshakes.spawn(async move {
+            let init = tokio::time::timeout(timeout, Init::decode(&mut connection))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "init timed out"))??;
+            Ok((init, connection))
+        });
+    }
+
+    /// Wait for the next session to have all its subflows
+    ///
+    /// Returns `None` once no connection inserted before is waiting for its `Init`.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe: the connections whose `Init` has arrived are kept.
+    pub async fn next_session(&mut self) -> Option<MultipathSession<S>> {
+        loop {
+            let res = self.handshakes.join_next().await?;
+            let Ok(Ok((init, connection))) = res else {
+                self.rejected += 1;
+                continue;
+            };
+            if let Some(session) = self.route(init, connection) {
+                return Some(session);
+            }
+        }
+    }
+
+    fn route(&mut self, init: Init, connection: S) -> Option<MultipathSession<S>> {
+        let id = init.session();
+        if self.limits.max_session_streams < init.streams() {
+            self.rejected += 1;
+            return None;
+        }
+        self.clean();
+        if !self.pending.contains_key(&id) {
+            let sessions = self.pending.len() + self.established.len();
+            if self.established.contains_key(&id) || self.limits.max_sessions <= sessions {
+                self.rejected += 1;
+                return None;
+            }
+            self.pending.insert(id, PendingSession::new(init.streams()));
+        }
+        let pending = self.pending.get_mut(&id).unwrap();
+        if pending.streams != init.streams() {
+            self.rejected += 1;
+            return None;
+        }
+        pending.connections.push(connection);
+        pending.last_update = Instant::now();
+        if pending.connections.len() < pending.streams.get() {
+            return None;
+        }
+
+        let pending = self.pending.remove(&id).unwrap();
+        let (read_streams, write_streams) = pending
+            .connections
+            .into_iter()
+            .map(tokio::io::split)
+            .unzip();
+        let receiver = ReceiverBuilder::new().session(id).build(read_streams);
+        self.established.insert(id, receiver.liveness());
+        Some(MultipathSession {
+            id,
+            streams: pending.streams,
+            sender: SenderBuilder::new().session(id).build(write_streams),
+            receiver,
+        })
+    }
+
+    /// Forget the sessions whose subflows have all closed and those that have waited too long for their subflows
+    pub fn clean(&mut self) {
+        let timeout = self.limits.assembly_timeout;
+        self.pending
+            .retain(|_, pending| pending.last_update.elapsed() <= timeout);
+        self.established
+            .retain(|_, liveness| liveness.live_streams() > 0);
+    }
+
+    /// The number of sessions handed out whose subflows have not all closed
+    pub fn established_sessions(&self) -> usize {
+        self.established.len()
+    }
+
+    /// The number of sessions waiting for their subflows
+    pub fn pending_sessions(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The number of connections dropped so far
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+#[derive(Debug)]
+struct PendingSession<S> {
+    connections: Vec<S>,
+    streams: NonZeroUsize,
+    last_update: Instant,
+}
+
+impl<S> PendingSession<S> {
+    fn new(streams: NonZeroUsize) -> Self {
+        Self {
+            connections: Vec::new(),
+            streams,
+            last_update: Instant::now(),
+        }
+    }
+}
+
+/// The subflows of a session, as a `Sender` and a `Receiver` over the halves of its connections
+#[derive(Debug)]
+pub struct MultipathSession<S> {
+    id: Session,
+    /// Announced in the `Init` of the session
+    streams: NonZeroUsize,
+    sender: Sender<WriteHalf<S>>,
+    receiver: Receiver,
+}
+
+impl<S> MultipathSession<S> {
+    pub fn id(&self) -> Session {
+        self.id
+    }
+
+    /// Write the handshakes and wait for those of the peer, then tell what the session agreed on
+    ///
+    /// See `SessionInfo::exchange`.
+    pub async fn session_info(&mut self) -> Result<SessionInfo, SendError>
+    where
+        S: AsyncWrite + Send + 'static,
+    {
+        let info = SessionInfo::exchange(&mut self.sender, &self.receiver).await?;
+        Ok(info.with_peer_subflows(self.streams))
+    }
+
+    pub fn sender(&mut self) -> &mut Sender<WriteHalf<S>> {
+        &mut self.sender
+    }
+
+    pub fn receiver(&mut self) -> &mut Receiver {
+        &mut self.receiver
+    }
+
+    pub fn into_parts(self) -> (Sender<WriteHalf<S>>, Receiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+/// A subflow whose handshake disagrees with those of the other subflows of its session
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum SessionSetupError {
+    #[error("Subflow {index} repeats the subflow ID {id} of subflow {first}")]
+    DuplicateSubflow { index: usize, first: usize, id: u32 },
+    #[error("Subflow {index} belongs to session {peer:?} rather than {expected:?}")]
+    SessionMismatch {
+        index: usize,
+        expected: Session,
+        peer: Session,
+    },
+    #[error("Subflow {index} and subflow {first} disagree on announcing their session")]
+    IdentityMismatch { index: usize, first: usize },
+    #[error("Subflow {index} negotiated {peer:#x} rather than {expected:#x} like subflow {first}")]
+    ParameterMismatch {
+        index: usize,
+        first: usize,
+        expected: u32,
+        peer: u32,
+    },
+}
+
+impl SessionSetupError {
+    /// The index of the offending subflow
+    pub fn index(&self) -> usize {
+        match *self {
+            Self::DuplicateSubflow { index, .. }
+            | Self::SessionMismatch { index, .. }
+            | Self::IdentityMismatch { index, .. }
+            | Self::ParameterMismatch { index, .. } => index,
+        }
+    }
+}
+
+/// Checks the handshakes of the subflows of one session against each other in the order they are read
+#[derive(Debug, Default)]
+pub(crate) struct SetupCheck {
+    /// The session the subflows are to announce, if known in advance
+    expected: Option<Session>,
+    /// The first subflow admitted and its handshake
+    first: Option<(usize, Hello)>,
+    /// The subflow that announced each ID
+    ids: HashMap<u32, usize>,
+}
+
+impl SetupCheck {
+    pub fn new(expected: Option<Session>) -> Self {
+        Self {
+            expected,
+            ..Default::default()
+        }
+    }
+
+    /// Admit the subflow at `index` that started with `hello` unless it disagrees with those admitted before
+    pub fn admit(&mut self, index: usize, hello: &Hello) -> Result<(), SessionSetupError> {
+        if let Some((first, first_hello)) = &self.first {
+            let first = *first;
+            if first_hello.session().is_some() != hello.session().is_some() {
+                return Err(SessionSetupError::IdentityMismatch { index, first });
+            }
+            let expected = first_hello.capabilities() & NEGOTIATED_CAPABILITIES;
+            let peer = hello.capabilities() & NEGOTIATED_CAPABILITIES;
+            if expected != peer {
+                return Err(SessionSetupError::ParameterMismatch {
+                    index,
+                    first,
+                    expected,
+                    peer,
+                });
+            }
+        }
+        if let Some(peer) = hello.session() {
+            let expected = self.expected.get_or_insert(peer);
+            if *expected != peer {
+                return Err(SessionSetupError::SessionMismatch {
+                    index,
+                    expected: *expected,
+                    peer,
+                });
+            }
+        }
+        if let Some(id) = hello.subflow_id() {
+            if let Some(&first) = self.ids.get(&id) {
+                return Err(SessionSetupError::DuplicateSubflow { index, first, id });
+            }
+            self.ids.insert(id, index);
+        }
+        self.first.get_or_insert((index, *hello));
+        Ok(())
+    }
+}
+
+/// A subflow that can tell the address of its peer, for `SubflowInfo::peer_addr`
+///
+/// Streams without one, e.g., in-memory pipes or the halves of `tokio::io::split`, implement it as it is.
+pub trait PeerAddr {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+impl PeerAddr for tcp::OwnedWriteHalf {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        tcp::OwnedWriteHalf::peer_addr(self).ok()
+    }
+}
+
+impl PeerAddr for tcp::OwnedReadHalf {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        tcp::OwnedReadHalf::peer_addr(self).ok()
+    }
+}
+
+impl<S> PeerAddr for WriteHalf<S> {}
+
+impl PeerAddr for DuplexStream {}
+
+/// What the two ends of a session agreed on in their handshakes
+///
+/// Each end writes a `Hello` on every subflow advertising what its sender does, and a feature is on when both ends advertise it, so that it applies to the two directions alike.
+/// The subflows are those of the local sender, paired by index with the streams the local receiver reads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    session: Option<Session>,
+    local_capabilities: u32,
+    subflows: Vec<SubflowInfo>,
+    peer_subflows: Option<NonZeroUsize>,
+}
+
+impl SessionInfo {
+    /// Write the handshakes of `sender` and wait for those of the peer read by `receiver`, then tell what they agreed on
+    ///
+    /// The peer writes its handshakes before its first message on each subflow, or right away by calling this too.
+    /// Fails like `Sender::handshake`.
+    pub async fn exchange<W>(sender: &mut Sender<W>, receiver: &Receiver) -> Result<Self, SendError>
+    where
+        W: AsyncWrite + PeerAddr + Unpin + Send + 'static,
+    {
+        sender.handshake().await?;
+        let peer_hellos = receiver.peer_hellos().await;
+        let subflows = sender
+            .subflow_handshakes()
+            .into_iter()
+            .map(|(id, max_frame_size, peer_addr)| SubflowInfo {
+                id,
+                peer_addr,
+                max_frame_size,
+                peer_hello: peer_hellos.get(id.inner()).copied().flatten(),
+            })
+            .collect();
+        Ok(Self {
+            session: sender.session(),
+            local_capabilities: sender.capabilities(),
+            subflows,
+            peer_subflows: None,
+        })
+    }
+
+    pub(crate) fn with_peer_subflows(mut self, streams: NonZeroUsize) -> Self {
+        self.peer_subflows = Some(streams);
+        self
+    }
+
+    /// The session the local handshakes announce, if any
+    pub fn session(&self) -> Option<Session> {
+        self.session
+    }
+
+    /// The lowest protocol version of the two ends
+    pub fn version(&self) -> u8 {
+        self.peer_hellos()
+            .map(Hello::version)
+            .fold(VERSION, u8::min)
+    }
+
+    /// The `CAPABILITY_*` bits both ends advertise on every subflow whose peer handshake was read
+    pub fn capabilities(&self) -> u32 {
+        self.peer_hellos()
+            .fold(self.local_capabilities, |capabilities, hello| {
+                capabilities & hello.capabilities()
+            })
+    }
+
+    /// The `CAPABILITY_*` bits the local handshakes advertise
+    pub fn local_capabilities(&self) -> u32 {
+        self.local_capabilities
+    }
+
+    /// Whether the frames carry checksums both ways
+    pub fn checksums(&self) -> bool {
+        self.capabilities() & CAPABILITY_CHECKSUM != 0
+    }
+
+    /// How the data segments are compressed both ways
+    pub fn compression(&self) -> Compression {
+        #[cfg(feature = "lz4")]
+        if self.capabilities() & Compression::Lz4.capability() != 0 {
+            return Compression::Lz4;
+        }
+        Compression::None
+    }
+
+    /// The width of the sequences, on which every subflow agrees once its handshake is admitted
+    pub fn sequence_width(&self) -> SeqWidth {
+        if self.local_capabilities & CAPABILITY_SEQUENCE_U32 != 0 {
+            SeqWidth::U32
+        } else {
+            SeqWidth::U64
+        }
+    }
+
+    /// The number of subflows the `Init` of the session announced, if it started with one
+    pub fn peer_subflows(&self) -> Option<NonZeroUsize> {
+        self.peer_subflows
+    }
+
+    pub fn subflows(&self) -> &[SubflowInfo] {
+        &self.subflows
+    }
+
+    fn peer_hellos(&self) -> impl Iterator<Item = &Hello> {
+        self.subflows
+            .iter()
+            .filter_map(|subflow| subflow.peer_hello.as_ref())
+    }
+}
+
+/// The handshakes of a subflow of a `SessionInfo`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubflowInfo {
+    id: StreamId,
+    peer_addr: Option<SocketAddr>,
+    max_frame_size: Option<u32>,
+    peer_hello: Option<Hello>,
+}
+
+impl SubflowInfo {
+    /// The ID of the subflow in the local sender, whose index is also that of the stream the local receiver reads
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// See `PeerAddr`
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// The largest frame the local handshake advertises to accept, see `Sender::advertise_max_frame_size`
+    pub fn max_frame_size(&self) -> Option<u32> {
+        self.max_frame_size
+    }
+
+    /// The largest frame the peer advertised to accept
+    pub fn peer_max_frame_size(&self) -> Option<u32> {
+        self.peer_hello?.max_frame_size()
+    }
+
+    /// The handshake of the peer, `None` if its stream ended before it was read
+    pub fn peer_hello(&self) -> Option<&Hello> {
+        self.peer_hello.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn negotiated_session() {
+        let session = Session::new(5);
+        for (checksum, peer_checksum) in [(true, true), (true, false), (false, true)] {
+            let (ends, peer_ends): (Vec<_>, Vec<_>) =
+                (0..2).map(|_| tokio::io::duplex(1 << 16)).unzip();
+            let side = |ends: Vec<DuplexStream>, checksum: bool| {
+                let (reads, writes) = ends.into_iter().map(tokio::io::split).unzip();
+                let sender = SenderBuilder::new()
+                    .checksum(checksum)
+                    .sequence_width(SeqWidth::U32)
+                    .session(session)
+                    .build(writes);
+                let receiver = ReceiverBuilder::new()
+                    .sequence_width(SeqWidth::U32)
+                    .session(session)
+                    .build(reads);
+                (sender, receiver)
+            };
+            let (mut sender, receiver) = side(ends, checksum);
+            let (mut peer_sender, peer_receiver) = side(peer_ends, peer_checksum);
+            assert!(peer_sender.advertise_max_frame_size(StreamId::new(1), 4096));
+
+            let (info, peer_info) = tokio::join!(
+                SessionInfo::exchange(&mut sender, &receiver),
+                SessionInfo::exchange(&mut peer_sender, &peer_receiver),
+            );
+            let (info, peer_info) = (info.unwrap(), peer_info.unwrap());
+            for info in [&info, &peer_info] {
+                assert_eq!(info.session(), Some(session));
+                assert_eq!(info.version(), VERSION);
+                assert_eq!(info.checksums(), checksum && peer_checksum);
+                assert_eq!(info.compression(), Compression::None);
+                assert_eq!(info.sequence_width(), SeqWidth::U32);
+                assert_eq!(info.peer_subflows(), None);
+                assert_eq!(info.subflows().len(), 2);
+            }
+            assert_eq!(
+                info.local_capabilities() & CAPABILITY_CHECKSUM != 0,
+                checksum
+            );
+            let frame_sizes = |info: &SessionInfo| -> Vec<_> {
+                let subflows = info.subflows();
+                subflows
+                    .iter()
+                    .map(SubflowInfo::peer_max_frame_size)
+                    .collect()
+            };
+            assert_eq!(frame_sizes(&info), [None, Some(4096)]);
+            assert_eq!(frame_sizes(&peer_info), [None, None]);
+            assert_eq!(peer_info.subflows()[1].max_frame_size(), Some(4096));
+            assert_eq!(info.subflows()[1].peer_addr(), None);
+        }
+    }
+
+    /// The client and server ends of the subflows of a session
+    async fn connect(session: u64, streams: usize) -> (Vec<DuplexStream>, Vec<DuplexStream>) {
+        let init = Init::new(Session::new(session), NonZeroUsize::new(streams).unwrap());
+        let mut clients = vec![];
+        let mut servers = vec![];
+        for _ in 0..streams {
+            let (mut client, server) = tokio::io::duplex(1 << 16);
+            init.encode(&mut client).await.unwrap();
+            clients.push(client);
+            servers.push(server);
+        }
+        (clients, servers)
+    }
+
+    #[tokio::test]
+    async fn interleaved_sessions() {
+        let (a_clients, a_servers) = connect(1, 2).await;
+        let (b_clients, b_servers) = connect(2, 2).await;
+        let mut map = SessionMap::new(SessionLimits::default());
+        for (a, b) in a_servers.into_iter().zip(b_servers) {
+            map.insert(a);
+            map.insert(b);
+        }
+        let mut sessions = HashMap::new();
+        for _ in 0..2 {
+            let session = map.next_session().await.unwrap();
+            sessions.insert(session.id().inner(), session);
+        }
+        assert_eq!(map.established_sessions(), 2);
+        assert_eq!(map.pending_sessions(), 0);
+
+        let client = |clients: Vec<DuplexStream>, msg: Vec<u8>| {
+            tokio::spawn(async move {
+                let (read_streams, write_streams): (Vec<_>, Vec<_>) =
+                    clients.into_iter().map(tokio::io::split).unzip();
+                let mut sender = Sender::new(write_streams);
+                for chunk in msg.chunks(1 << 12) {
+                    sender
+                        .batch_send_all(Bytes::copy_from_slice(chunk))
+                        .await
+                        .unwrap();
+                }
+                sender.shutdown().await.unwrap();
+                let mut reply = vec![];
+                let mut receiver = Receiver::new(read_streams).into_async_read();
+                receiver.read_to_end(&mut reply).await.unwrap();
+                reply
+            })
+        };
+        let msgs: Vec<Vec<u8>> = (0..2)
+            .map(|_| (0..1 << 18).map(|_| rand::random()).collect())
+            .collect();
+        let a_client = client(a_clients, msgs[0].clone());
+        let b_client = client(b_clients, msgs[1].clone());
+
+        for (id, msg) in [(1, &msgs[0]), (2, &msgs[1])] {
+            let (mut sender, receiver) = sessions.remove(&id).unwrap().into_parts();
+            let mut buf = vec![];
+            receiver
+                .into_async_read()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            assert_eq!(buf, *msg);
+            sender
+                .batch_send_all(Bytes::from(format!("session {id}")))
+                .await
+                .unwrap();
+            sender.shutdown().await.unwrap();
+        }
+        assert_eq!(a_client.await.unwrap(), b"session 1");
+        assert_eq!(b_client.await.unwrap(), b"session 2");
+
+        // Both sessions are gone with their subflows
+        wait_cleaned(&mut map).await;
+    }
+
+    async fn wait_cleaned(map: &mut SessionMap<DuplexStream>) {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while map.established_sessions() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                map.clean();
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let limits = SessionLimits {
+            max_sessions: 1,
+            max_session_streams: NonZeroUsize::new(2).unwrap(),
+            ..Default::default()
+        };
+        let mut map = SessionMap::new(limits);
+        let (clients, servers) = connect(1, 2).await;
+        let (_too_many_clients, too_many_servers) = connect(2, 1).await;
+        let (_too_wide_clients, too_wide_servers) = connect(3, 3).await;
+        let (_reused_clients, reused_servers) = connect(1, 2).await;
+        let (truncated_client, truncated_server) = tokio::io::duplex(1 << 16);
+        drop(truncated_client);
+
+        let mut servers = servers.into_iter();
+        map.insert(servers.next().unwrap());
+        map.insert(truncated_server);
+        map.insert(too_wide_servers.into_iter().next().unwrap());
+        map.insert(servers.next().unwrap());
+        let session = map.next_session().await.unwrap();
+        assert_eq!(session.id(), Session::new(1));
+
+        // The session holds the only slot and its ID while its subflows are open
+        map.insert(too_many_servers.into_iter().next().unwrap());
+        map.insert(reused_servers.into_iter().next().unwrap());
+        assert!(map.next_session().await.is_none());
+        assert_eq!(map.rejected(), 4);
+        assert_eq!(map.pending_sessions(), 0);
+        assert_eq!(map.established_sessions(), 1);
+
+        // Closed once the client closes its ends too
+        drop(session);
+        drop(clients);
+        wait_cleaned(&mut map).await;
+    }
+
+    #[tokio::test]
+    async fn subflow_of_another_session() {
+        let (mut clients, servers) = connect(1, 2).await;
+        let mut map = SessionMap::new(SessionLimits::default());
+        for server in servers {
+            map.insert(server);
+        }
+        let mut session = map.next_session().await.unwrap();
+
+        // The second subflow announces another session than its `Init`
+        let mut stray = clients.pop().unwrap();
+        Hello::new(0)
+            .with_subflow(Session::new(2), 1)
+            .encode(&mut stray)
+            .await
+            .unwrap();
+        let mut sender = SenderBuilder::new().session(Session::new(1)).build(clients);
+        sender
+            .batch_send_all(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+
+        let receiver = session.receiver();
+        let mut buf = [0; 5];
+        assert_eq!(receiver.recv(&mut buf).await.unwrap(), 5);
+        let recv = tokio::time::timeout(Duration::from_millis(50), receiver.recv(&mut buf));
+        assert!(recv.await.is_err());
+        let errors = receiver.take_subflow_errors();
+        assert_eq!(errors.len(), 1);
+        let err = errors[0].error().get_ref().unwrap();
+        let err = err.downcast_ref::<SessionSetupError>().unwrap();
+        assert_eq!(err.index(), errors[0].index());
+        assert!(matches!(
+            err,
+            SessionSetupError::SessionMismatch { expected, peer, .. }
+                if *expected == Session::new(1) && *peer == Session::new(2)
+        ));
+    }
+}
@@ -0,0 +1,1246 @@
+//! Simulated subflows for testing against misbehaving paths
+//!
+//! `SimStream::pair` connects two in-memory ends like `tokio::io::duplex`, but each direction follows a `SimConfig` with its own latency, bandwidth, corruption and failures.
+//! `transfer` runs a whole session over such paths and measures how well it aggregates them, for benchmarking schedulers and send modes: see `benches/aggregation.rs`.
+//! `replay` runs the decisions of `Sender::decision_log` past another scheduler to see where it would have chosen differently.
+//!
+//! Enabled by the `sim` feature.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    num::NonZeroUsize,
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll, Waker},
+    time::Duration,
+};
+
+use bytes::{Buf, Bytes};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use crate::{
+    message::Sequence,
+    receiver::ReceiverBuilder,
+    scheduler::{Assignment, Decision, Scheduler, SegmentMeta, StreamMeta},
+    sender::{SenderBuilder, StreamId},
+    session::PeerAddr,
+};
+
+/// The most bytes a single write takes in
+const MAX_WRITE_SIZE: usize = 1 << 14;
+
+/// Payload bytes a `transfer` keeps for retransmission
+const TRANSFER_RETRANSMISSION_LIMIT: usize = 1 << 22;
+
+/// How one direction of a `SimStream` pair behaves
+///
+/// The default is an ideal path with a 1 MiB buffer.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    latency: Duration,
+    bandwidth: Option<u64>,
+    corruption: f64,
+    stall_after: Option<u64>,
+    fail_after: Option<u64>,
+    drop_after: Option<u64>,
+    buffer: usize,
+    seed: u64,
+}
+
+impl SimConfig {
+    pub fn new() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            bandwidth: None,
+            corruption: 0.0,
+            stall_after: None,
+            fail_after: None,
+            drop_after: None,
+            buffer: 1 << 20,
+            seed: 0,
+        }
+    }
+
+    /// Delay every byte by `latency` on top of the time it takes to transmit
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Transmit at most `bytes_per_sec` bytes per second
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_sec` is zero.
+    pub fn bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        assert_ne!(bytes_per_sec, 0, "bandwidth must not be zero");
+        self.bandwidth = Some(bytes_per_sec);
+        self
+    }
+
+    /// Flip a random bit of each byte with `probability`
+    pub fn corruption(mut self, probability: f64) -> Self {
+        self.corruption = probability;
+        self
+    }
+
+    /// Leave every write pending forever once `bytes` bytes have been written
+    pub fn stall_after(mut self, bytes: u64) -> Self {
+        self.stall_after = Some(bytes);
+        self
+    }
+
+    /// Fail every write with `io::ErrorKind::BrokenPipe` once `bytes` bytes have been written
+    ///
+    /// The reading end sees `io::ErrorKind::ConnectionReset` after the bytes written before.
+    pub fn fail_after(mut self, bytes: u64) -> Self {
+        self.fail_after = Some(bytes);
+        self
+    }
+
+    /// Take every write but deliver nothing once `bytes` bytes have been written, like a path that silently stopped forwarding
+    pub fn drop_after(mut self, bytes: u64) -> Self {
+        self.drop_after = Some(bytes);
+        self
+    }
+
+    /// Bytes in transit before writes wait for the reading end
+    pub fn buffer(mut self, bytes: usize) -> Self {
+        self.buffer = bytes.max(1);
+        self
+    }
+
+    /// Seed of the RNG deciding the corruption, so that a run can be reproduced
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One end of a simulated path
+#[derive(Debug)]
+pub struct SimStream {
+    /// What this end writes
+    outgoing: Arc<Mutex<Pipe>>,
+    /// What this end reads
+    incoming: Arc<Mutex<Pipe>>,
+    config: SimConfig,
+    rng: StdRng,
+    written: u64,
+    /// When the bytes written so far have been transmitted
+    transmitted_at: Instant,
+    /// Whether the pending write waited for `transmitted_at`
+    paced: bool,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+    read_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl SimStream {
+    /// Connect two ends where what the first one writes follows `forward` and what the second one writes follows `backward`
+    pub fn pair(forward: SimConfig, backward: SimConfig) -> (Self, Self) {
+        let forward_pipe = Arc::new(Mutex::new(Pipe::default()));
+        let backward_pipe = Arc::new(Mutex::new(Pipe::default()));
+        let first = Self::new(forward_pipe.clone(), backward_pipe.clone(), forward);
+        let second = Self::new(backward_pipe, forward_pipe, backward);
+        (first, second)
+    }
+
+    fn new(outgoing: Arc<Mutex<Pipe>>, incoming: Arc<Mutex<Pipe>>, config: SimConfig) -> Self {
+        Self {
+            outgoing,
+            incoming,
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            written: 0,
+            transmitted_at: Instant::now(),
+            paced: false,
+            write_sleep: None,
+            read_sleep: None,
+        }
+    }
+
+    /// Bytes this end has written
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    fn corrupt(&mut self, data: &mut [u8]) {
+        if self.config.corruption <= 0.0 {
+            return;
+        }
+        let probability = self.config.corruption.min(1.0);
+        for byte in data {
+            if self.rng.gen_bool(probability) {
+                *byte ^= 1 << self.rng.gen_range(0..8);
+            }
+        }
+    }
+}
+
+impl PeerAddr for SimStream {}
+
+/// The bytes in transit in one direction
+#[derive(Debug, Default)]
+struct Pipe {
+    /// Bytes and when they reach the reading end
+    queue: VecDeque<(Instant, Bytes)>,
+    buffered: usize,
+    write_closed: bool,
+    read_closed: bool,
+    reset: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl Pipe {
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Wait on `sleep` until `deadline`
+fn poll_sleep(
+    sleep: &mut Option<Pin<Box<Sleep>>>,
+    deadline: Instant,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    let sleep = sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+    if sleep.deadline() != deadline {
+        sleep.as_mut().reset(deadline);
+    }
+    sleep.as_mut().poll(cx)
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut n = buf.len().min(MAX_WRITE_SIZE);
+        if let Some(limit) = this.config.fail_after {
+            if limit <= this.written {
+                let mut pipe = this.outgoing.lock().unwrap();
+                pipe.reset = true;
+                pipe.wake_reader();
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "simulated failure",
+                )));
+            }
+            n = n.min((limit - this.written) as usize);
+        }
+        if let Some(limit) = this.config.stall_after {
+            if limit <= this.written {
+                return Poll::Pending;
+            }
+            n = n.min((limit - this.written) as usize);
+        }
+        let dropped = match this.config.drop_after {
+            Some(limit) if limit <= this.written => true,
+            Some(limit) => {
+                n = n.min((limit - this.written) as usize);
+                false
+            }
+            None => false,
+        };
+
+        // The previous bytes are still being transmitted
+        if Instant::now() < this.transmitted_at {
+            this.paced = true;
+            ready!(poll_sleep(&mut this.write_sleep, this.transmitted_at, cx));
+        }
+
+        let mut pipe = this.outgoing.lock().unwrap();
+        if pipe.read_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if this.config.buffer <= pipe.buffered {
+            // The path idles until the reading end makes room
+            this.paced = false;
+            pipe.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = n.min(this.config.buffer - pipe.buffered);
+        drop(pipe);
+
+        let mut data = buf[..n].to_vec();
+        this.corrupt(&mut data);
+        let now = Instant::now();
+        if let Some(bandwidth) = this.config.bandwidth {
+            let transmission = Duration::from_secs_f64(n as f64 / bandwidth as f64);
+            // A write that waited for the previous transmission follows it right away, however late the sleep woke up
+            let start = if std::mem::take(&mut this.paced) {
+                this.transmitted_at
+            } else {
+                this.transmitted_at.max(now)
+            };
+            this.transmitted_at = start + transmission;
+        } else {
+            this.transmitted_at = now;
+        }
+        let arrival = this.transmitted_at + this.config.latency;
+        this.written += n as u64;
+        if dropped {
+            return Poll::Ready(Ok(n));
+        }
+
+        let mut pipe = this.outgoing.lock().unwrap();
+        pipe.buffered += n;
+        pipe.queue.push_back((arrival, data.into()));
+        pipe.wake_reader();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut pipe = self.outgoing.lock().unwrap();
+        pipe.write_closed = true;
+        pipe.wake_reader();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let mut pipe = this.incoming.lock().unwrap();
+            if let Some((arrival, data)) = pipe.queue.front_mut() {
+                let arrival = *arrival;
+                if Instant::now() < arrival {
+                    drop(pipe);
+                    ready!(poll_sleep(&mut this.read_sleep, arrival, cx));
+                    continue;
+                }
+                let n = data.len().min(buf.remaining());
+                buf.put_slice(&data[..n]);
+                data.advance(n);
+                if data.is_empty() {
+                    pipe.queue.pop_front();
+                }
+                pipe.buffered -= n;
+                pipe.wake_writer();
+                return Poll::Ready(Ok(()));
+            }
+            if pipe.reset {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            if pipe.write_closed {
+                return Poll::Ready(Ok(()));
+            }
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        outgoing.write_closed = true;
+        outgoing.wake_reader();
+        drop(outgoing);
+        let mut incoming = self.incoming.lock().unwrap();
+        incoming.read_closed = true;
+        incoming.wake_writer();
+    }
+}
+
+/// What `transfer` sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// `bytes` in sends of `chunk` bytes, each as soon as the one before returns
+    Bulk { bytes: usize, chunk: usize },
+    /// `count` messages of `size` bytes, one every `interval`
+    Messages {
+        count: usize,
+        size: usize,
+        interval: Duration,
+    },
+}
+
+impl Workload {
+    /// The number of sends and the bytes of each
+    fn pieces(&self) -> (usize, usize) {
+        match *self {
+            Self::Bulk { bytes, chunk } => (bytes.div_ceil(chunk.max(1)), chunk.max(1)),
+            Self::Messages { count, size, .. } => (count, size.max(1)),
+        }
+    }
+}
+
+/// How a `transfer` went
+#[derive(Debug, Clone)]
+pub struct TransferReport {
+    bytes: u64,
+    elapsed: Duration,
+    /// From each send to the delivery of its last byte, sorted
+    latencies: Vec<Duration>,
+    capacity: Option<u64>,
+}
+
+impl TransferReport {
+    /// Bytes delivered
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// From the first send to the delivery of the last byte
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Bytes delivered per second
+    pub fn goodput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// The sum of the bandwidths of the paths, `None` if any has none
+    pub fn capacity(&self) -> Option<u64> {
+        self.capacity
+    }
+
+    /// The goodput over the capacity, which framing and head-of-line blocking keep below 1
+    pub fn efficiency(&self) -> Option<f64> {
+        Some(self.goodput() / self.capacity? as f64)
+    }
+
+    /// The delivery latency of the sends at `quantile` between 0 and 1, e.g., 0.99 for the p99
+    pub fn latency(&self, quantile: f64) -> Duration {
+        let Some(last) = self.latencies.len().checked_sub(1) else {
+            return Duration::ZERO;
+        };
+        let index = (quantile.clamp(0.0, 1.0) * last as f64).round() as usize;
+        self.latencies[index]
+    }
+}
+
+/// Send `workload` with a `Sender` of `sender` over a new pair of each of `paths` and measure its delivery
+///
+/// The other direction of each path is ideal. The sender retransmits on the acknowledgements of the receiver, so that a path failing mid-transfer costs time but loses no data.
+/// The receiver acknowledges every segment, and the sender takes the selective acknowledgements in before each send, for `SendMode::Sticky` to tell the loads apart.
+/// Fails if the sender gives up or the bytes delivered are not those sent.
+pub async fn transfer(
+    paths: &[SimConfig],
+    sender: SenderBuilder,
+    workload: Workload,
+) -> io::Result<TransferReport> {
+    let (send_streams, recv_streams): (Vec<_>, Vec<_>) = paths
+        .iter()
+        .map(|path| SimStream::pair(path.clone(), SimConfig::new()))
+        .unzip();
+    let capacity = paths.iter().map(|path| path.bandwidth).sum();
+    let mut receiver = ReceiverBuilder::new()
+        .ack_every(NonZeroUsize::MIN)
+        .build(recv_streams);
+    let mut ack_frames = receiver.ack_frames();
+    let limit = NonZeroUsize::new(TRANSFER_RETRANSMISSION_LIMIT).unwrap();
+    let mut sender = sender
+        .retransmission(receiver.acks(), limit)
+        .build(send_streams);
+    let (pieces, piece_size) = workload.pieces();
+    let data: Bytes = (0..pieces * piece_size).map(pattern).collect();
+
+    let mut reader = receiver.into_async_read();
+    let recv_task = tokio::spawn(async move {
+        let mut buf = vec![0; 1 << 16];
+        let mut received = 0;
+        let mut delivered = vec![];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok((received, delivered));
+            }
+            if !(received..).zip(&buf[..n]).all(|(i, &b)| pattern(i) == b) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupt delivery",
+                ));
+            }
+            received += n;
+            let now = Instant::now();
+            delivered.resize(received / piece_size, now);
+        }
+    });
+
+    let start = Instant::now();
+    let mut sent = Vec::with_capacity(pieces);
+    for (i, piece) in data.chunks(piece_size).enumerate() {
+        if let Workload::Messages { interval, .. } = workload {
+            tokio::time::sleep_until(start + interval * i as u32).await;
+        }
+        while let Ok(frame) = ack_frames.try_recv() {
+            sender.handle_ack(frame.cumulative(), frame.sacks());
+        }
+        sent.push(Instant::now());
+        sender.batch_send_all(data.slice_ref(piece)).await?;
+    }
+    sender.close().await?;
+    drop(sender);
+    let (received, delivered): (usize, Vec<Instant>) = recv_task.await??;
+    if received != data.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "incomplete delivery",
+        ));
+    }
+
+    let mut latencies: Vec<_> = sent
+        .iter()
+        .zip(&delivered)
+        .map(|(sent, delivered)| *delivered - *sent)
+        .collect();
+    latencies.sort_unstable();
+    Ok(TransferReport {
+        bytes: received as u64,
+        elapsed: delivered
+            .last()
+            .map_or(Duration::ZERO, |&last| last - start),
+        latencies,
+        capacity,
+    })
+}
+
+/// The `i`th byte a `transfer` sends
+fn pattern(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+/// The stream `scheduler` would have handed the segment of each of `decisions`, or `None` if it would have left the segment for a later round
+///
+/// The decisions of a round are replayed together: the scheduler is offered their segments and the streams of their snapshot, and each stream carries at most one segment as in `Sender::batch_send`.
+/// Segments no stream took in a round are not in the log, so they are not offered again.
+pub fn replay<'a>(
+    decisions: impl IntoIterator<Item = &'a Decision>,
+    scheduler: &mut dyn Scheduler,
+) -> Vec<Option<StreamId>> {
+    let decisions: Vec<&Decision> = decisions.into_iter().collect();
+    let mut chosen = Vec::with_capacity(decisions.len());
+    for round in decisions.chunk_by(|a, b| a.round() == b.round()) {
+        let sequence = |decision: &Decision| decision.start_sequence()..decision.end_sequence();
+        let mut sequences: Vec<Range<Sequence>> = vec![];
+        for &decision in round {
+            if !sequences.contains(&sequence(decision)) {
+                sequences.push(sequence(decision));
+            }
+        }
+        let segments: Vec<SegmentMeta> = sequences.iter().cloned().map(SegmentMeta::new).collect();
+        let streams: Vec<StreamMeta> = round[0]
+            .streams()
+            .iter()
+            .map(|&stats| StreamMeta::new(stats))
+            .collect();
+
+        // The streams each segment would have gone to, a segment written on several being logged once per stream
+        let mut taken = vec![false; streams.len()];
+        let mut targets = vec![VecDeque::new(); segments.len()];
+        for Assignment { segment, stream } in scheduler.assign(&segments, &streams) {
+            if segment >= segments.len() || taken.get(stream) != Some(&false) {
+                continue;
+            }
+            taken[stream] = true;
+            targets[segment].push_back(streams[stream].id());
+        }
+        for &decision in round {
+            let segment = sequences.iter().position(|s| *s == sequence(decision));
+            chosen.push(segment.and_then(|segment| targets[segment].pop_front()));
+        }
+    }
+    chosen
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        receiver::{Receiver, ReceiverBuilder},
+        scheduler::{FewestGaps, LowestRtt, RoundRobin},
+        sender::{SendMode, Sender, SenderBuilder, StreamStats, SubflowEvent},
+    };
+
+    use super::*;
+
+    fn ideal() -> SimConfig {
+        SimConfig::new()
+    }
+
+    #[tokio::test]
+    async fn latency_and_bandwidth() {
+        let forward = SimConfig::new()
+            .latency(Duration::from_millis(50))
+            .bandwidth(1 << 20);
+        let (mut a, mut b) = SimStream::pair(forward, ideal());
+
+        let start = Instant::now();
+        a.write_all(&[1; 1 << 17]).await.unwrap();
+        a.shutdown().await.unwrap();
+        let mut buf = vec![];
+        b.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, [1; 1 << 17]);
+        // 128 KiB at 1 MiB/s plus the latency
+        assert!(start.elapsed() >= Duration::from_millis(170));
+
+        // The other direction is ideal
+        let start = Instant::now();
+        b.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        a.read_exact(&mut buf).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn passthrough_as_fast_as_plain_copy() {
+        let path = || SimConfig::new().bandwidth(1 << 22);
+        let msg: Vec<u8> = (0..1 << 20).map(|i| b'a' + (i % 26) as u8).collect();
+
+        let (mut a, mut b) = SimStream::pair(path(), ideal());
+        let start = Instant::now();
+        let write = async {
+            a.write_all(&msg).await.unwrap();
+            a.shutdown().await.unwrap();
+        };
+        let mut copied = vec![];
+        tokio::join!(write, b.read_to_end(&mut copied)).1.unwrap();
+        let plain = start.elapsed();
+        assert!(copied == msg);
+
+        let (a, b) = SimStream::pair(path(), ideal());
+        let mut sender = SenderBuilder::new().passthrough(true).build(vec![a]);
+        let mut receiver = Receiver::new(vec![b]).into_async_read();
+        let start = Instant::now();
+        let send = async {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(&msg))
+                .await
+                .unwrap();
+            sender.shutdown().await.unwrap();
+        };
+        let mut received = vec![];
+        tokio::join!(send, receiver.read_to_end(&mut received))
+            .1
+            .unwrap();
+        let passthrough = start.elapsed();
+        assert!(received == msg);
+        // 256 ms each at 4 MiB/s
+        assert!(
+            passthrough < plain.mul_f64(1.1) + Duration::from_millis(20),
+            "{passthrough:?} {plain:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn fail_after() {
+        let (mut a, mut b) = SimStream::pair(SimConfig::new().fail_after(10), ideal());
+        let e = a.write_all(&[0; 16]).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(a.written(), 10);
+        let mut buf = vec![];
+        let e = b.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(buf, [0; 10]);
+    }
+
+    #[tokio::test]
+    async fn stall_after() {
+        let (mut a, mut b) = SimStream::pair(SimConfig::new().stall_after(10), ideal());
+        let write = tokio::time::timeout(Duration::from_millis(50), a.write_all(&[0; 16])).await;
+        assert!(write.is_err());
+        let mut buf = [0; 10];
+        b.read_exact(&mut buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn seeded_corruption() {
+        let corrupted = |seed| async move {
+            let forward = SimConfig::new().corruption(0.01).seed(seed);
+            let (mut a, mut b) = SimStream::pair(forward, ideal());
+            a.write_all(&[0; 1 << 12]).await.unwrap();
+            a.shutdown().await.unwrap();
+            let mut buf = vec![];
+            b.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let first = corrupted(7).await;
+        assert!(first.iter().any(|&byte| byte != 0));
+        assert_eq!(first, corrupted(7).await);
+        assert_ne!(first, corrupted(8).await);
+    }
+
+    #[tokio::test]
+    async fn asymmetric_bandwidth() {
+        let (fast_tx, fast_rx) = SimStream::pair(SimConfig::new().bandwidth(8 << 20), ideal());
+        let (slow_tx, slow_rx) = SimStream::pair(SimConfig::new().bandwidth(1 << 20), ideal());
+        let mut sender = Sender::new(vec![fast_tx, slow_tx]);
+        sender.set_send_mode(SendMode::Stripe);
+        let mut receiver = Receiver::new(vec![fast_rx, slow_rx]).into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 21).map(|_| rand::random()).collect();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        for chunk in msg.chunks(1 << 17) {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.shutdown().await.unwrap();
+        let stats = sender.stats();
+        drop(sender);
+
+        assert_eq!(recv_task.await.unwrap(), msg);
+        // The faster path carries more once the goodput is measured
+        assert!(stats[0].bytes_written() > 2 * stats[1].bytes_written());
+    }
+
+    #[tokio::test]
+    async fn mid_transfer_failure() {
+        let paths = [
+            SimConfig::new().latency(Duration::from_millis(5)),
+            SimConfig::new()
+                .latency(Duration::from_millis(5))
+                .fail_after(1 << 17),
+            SimConfig::new().latency(Duration::from_millis(20)),
+        ];
+        let (send_streams, recv_streams): (Vec<_>, Vec<_>) = paths
+            .into_iter()
+            .map(|forward| SimStream::pair(forward, ideal()))
+            .unzip();
+        let mut sender = Sender::new(send_streams);
+        let receiver = Receiver::new(recv_streams);
+        sender.enable_retransmission(receiver.acks(), NonZeroUsize::new(1 << 22).unwrap());
+        let mut receiver = receiver.into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 20).map(|_| rand::random()).collect();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        for chunk in msg.chunks(1 << 16) {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.shutdown().await.unwrap();
+
+        let evicted = sender.take_evicted_streams();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].error().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(sender.live_streams(), 2);
+        drop(sender);
+        assert_eq!(recv_task.await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn rtt_probes() {
+        const INTERVAL: Duration = Duration::from_millis(20);
+        let mut forward = (vec![], vec![]);
+        let mut backward = (vec![], vec![]);
+        for latency in [5, 50] {
+            let path = SimConfig::new().latency(Duration::from_millis(latency));
+            let (a, b) = SimStream::pair(path.clone(), path);
+            let (a_read, a_write) = tokio::io::split(a);
+            let (b_read, b_write) = tokio::io::split(b);
+            forward.0.push(a_write);
+            forward.1.push(b_read);
+            backward.0.push(b_write);
+            backward.1.push(a_read);
+        }
+
+        // The opposite direction reflects the probes and carries the pongs back
+        let mut receiver = Receiver::new(forward.1);
+        let mut probes = receiver.probes();
+        let mut reflector = Sender::new(backward.0);
+        let reflect_task = tokio::spawn(async move {
+            while let Some(probe) = probes.recv().await {
+                reflector.reflect_probe(probe).await.unwrap();
+            }
+        });
+        let mut pongs = Receiver::new(backward.1);
+        let mut sender = SenderBuilder::new()
+            .scheduler(LowestRtt)
+            .rtt_probes(pongs.pongs(), INTERVAL)
+            .build(forward.0);
+        let mut receiver = receiver.into_async_read();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+
+        let start = Instant::now();
+        while sender.stats().iter().any(|stats| stats.rtt().is_none()) {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            sender.probe_rtt().await.unwrap();
+            tokio::time::sleep(INTERVAL).await;
+        }
+        // Twice the latency of each direction
+        let rtts: Vec<Duration> = sender.stats().iter().map(|s| s.rtt().unwrap()).collect();
+        assert!(Duration::from_millis(10) <= rtts[0] && rtts[0] < Duration::from_millis(50));
+        assert!(Duration::from_millis(100) <= rtts[1] && rtts[1] < Duration::from_millis(200));
+
+        let msg: Vec<u8> = (0..1 << 19).map(|_| rand::random()).collect();
+        for chunk in msg.chunks(1 << 12) {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.shutdown().await.unwrap();
+        let stats = sender.stats();
+        drop(sender);
+        reflect_task.abort();
+
+        assert_eq!(recv_task.await.unwrap(), msg);
+        assert_eq!(stats[0].bytes_written(), msg.len() as u64);
+        assert_eq!(stats[1].bytes_written(), 0);
+    }
+
+    #[tokio::test]
+    async fn feedback_shifts_load_off_delayed_subflow() {
+        const INTERVAL: Duration = Duration::from_millis(50);
+        let mut forward = (vec![], vec![]);
+        let mut backward = (vec![], vec![]);
+        // The second path holds every frame up without failing
+        for latency in [5, 200] {
+            let path = SimConfig::new().latency(Duration::from_millis(latency));
+            let (a, b) = SimStream::pair(path, ideal());
+            let (a_read, a_write) = tokio::io::split(a);
+            let (b_read, b_write) = tokio::io::split(b);
+            forward.0.push(a_write);
+            forward.1.push(b_read);
+            backward.0.push(b_write);
+            backward.1.push(a_read);
+        }
+
+        // The opposite direction carries the scores back
+        let mut receiver = Receiver::new(forward.1);
+        let mut reports = receiver.feedback();
+        let mut reporter = Sender::new(backward.0);
+        let report_task = tokio::spawn(async move {
+            while let Some(scores) = reports.recv().await {
+                reporter.send_feedback(scores).await.unwrap();
+            }
+        });
+        let mut feedback = Receiver::new(backward.1);
+        let mut sender = SenderBuilder::new()
+            .scheduler(FewestGaps)
+            .send_mode(SendMode::Stripe)
+            .max_segment_size(NonZeroUsize::new(1 << 12).unwrap())
+            .feedback(feedback.peer_feedback(), INTERVAL)
+            .build(forward.0);
+        let mut receiver = receiver.into_async_read();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+
+        let msg: Vec<u8> = (0..1 << 20).map(|_| rand::random()).collect();
+        let chunks: Vec<&[u8]> = msg.chunks(1 << 13).collect();
+        let start = Instant::now();
+        let mut before = None;
+        for (i, chunk) in chunks.iter().enumerate() {
+            tokio::time::sleep_until(start + Duration::from_millis(10) * i as u32).await;
+            // A few feedback intervals after the first frames got through the delay
+            if before.is_none() && start.elapsed() >= Duration::from_millis(200) + INTERVAL * 4 {
+                before = Some(sender.stats());
+            }
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.shutdown().await.unwrap();
+        let stats = sender.stats();
+        drop(sender);
+        report_task.abort();
+        assert_eq!(recv_task.await.unwrap(), msg);
+
+        let before = before.unwrap();
+        let later = |i: usize| stats[i].bytes_written() - before[i].bytes_written();
+        assert!(stats[0].feedback().is_some());
+        assert!(stats[1].feedback_gaps().unwrap() > stats[0].feedback_gaps().unwrap());
+        // Striped evenly without feedback
+        assert!(later(1) * 3 < later(0), "{} {}", later(0), later(1));
+    }
+
+    #[tokio::test]
+    async fn retransmit_black_holed_segments() {
+        const RTO: Duration = Duration::from_millis(100);
+        let paths = [
+            SimConfig::new().latency(Duration::from_millis(5)),
+            SimConfig::new()
+                .latency(Duration::from_millis(5))
+                .drop_after(0),
+        ];
+        let (send_streams, recv_streams): (Vec<_>, Vec<_>) = paths
+            .into_iter()
+            .map(|forward| SimStream::pair(forward, ideal()))
+            .unzip();
+        let receiver = Receiver::new(recv_streams);
+        let mut sender = SenderBuilder::new()
+            .retransmission(receiver.acks(), NonZeroUsize::new(1 << 22).unwrap())
+            .retransmission_timeout(RTO)
+            .build(send_streams);
+        let mut receiver = receiver.into_async_read();
+        let msg: Vec<u8> = (0..1 << 19).map(|_| rand::random()).collect();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+
+        let start = Instant::now();
+        for chunk in msg.chunks(1 << 16) {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.close().await.unwrap();
+        assert!(start.elapsed() < RTO * 5, "{:?}", start.elapsed());
+
+        // Nothing is evicted, the healthy path just carries the segments again
+        let stats = sender.stats();
+        assert_eq!(sender.live_streams(), 2);
+        assert!(stats[0].retransmitted_segments() > 0);
+        assert_eq!(stats[0].retransmitted_bytes(), stats[1].bytes_written());
+        assert_eq!(stats[1].retransmitted_bytes(), 0);
+        drop(sender);
+        assert_eq!(recv_task.await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn pacing_budgets() {
+        const TARGET: Duration = Duration::from_millis(50);
+        let (fast_tx, fast_rx) = SimStream::pair(SimConfig::new().bandwidth(8 << 20), ideal());
+        let (slow_tx, slow_rx) = SimStream::pair(SimConfig::new().bandwidth(1 << 20), ideal());
+        let mut sender = SenderBuilder::new()
+            .send_mode(SendMode::Stripe)
+            .pacing(TARGET)
+            .build(vec![fast_tx, slow_tx]);
+        let mut receiver = Receiver::new(vec![fast_rx, slow_rx]).into_async_read();
+
+        let msg: Vec<u8> = (0..1 << 22).map(|_| rand::random()).collect();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            receiver.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        for chunk in msg.chunks(1 << 20) {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.shutdown().await.unwrap();
+        let stats = sender.stats();
+        drop(sender);
+
+        assert_eq!(recv_task.await.unwrap(), msg);
+        // Each budget settles around what its path drains within the target
+        let fast = stats[0].budget().unwrap();
+        let slow = stats[1].budget().unwrap();
+        assert!(fast > 2 * slow, "{fast} {slow}");
+        assert!(stats[0].bytes_written() > 2 * stats[1].bytes_written());
+    }
+
+    #[tokio::test]
+    async fn throttle_subflow_racing_ahead() {
+        const LIMIT: usize = 1 << 15;
+        let (fast_tx, fast_rx) = SimStream::pair(SimConfig::new().bandwidth(8 << 20), ideal());
+        let (slow_tx, slow_rx) = SimStream::pair(
+            SimConfig::new()
+                .bandwidth(1 << 20)
+                .latency(Duration::from_millis(100)),
+            ideal(),
+        );
+        let mut sender = SenderBuilder::new()
+            .send_mode(SendMode::Stripe)
+            .max_segment_size(NonZeroUsize::new(1 << 14).unwrap())
+            .build(vec![fast_tx, slow_tx]);
+        let mut receiver = ReceiverBuilder::new()
+            .subflow_buffer_limit(NonZeroUsize::new(LIMIT).unwrap())
+            .build(vec![fast_rx, slow_rx]);
+
+        let msg: Vec<u8> = (0..1 << 21).map(|_| rand::random()).collect();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            let mut piece = [0; 1 << 12];
+            let mut max_buffered = [0; 2];
+            loop {
+                let n = receiver.recv(&mut piece).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                buf.extend_from_slice(&piece[..n]);
+                let stats = receiver.stats();
+                for (max, &buffered) in max_buffered.iter_mut().zip(stats.subflow_buffered_bytes())
+                {
+                    *max = buffered.max(*max);
+                }
+            }
+            (buf, max_buffered)
+        });
+        for chunk in msg.chunks(1 << 18) {
+            sender
+                .batch_send_all(Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        sender.shutdown().await.unwrap();
+        drop(sender);
+
+        let (buf, max_buffered) = recv_task.await.unwrap();
+        assert_eq!(buf, msg);
+        // The fast stream is held back while the slow one fills the gaps before it
+        assert!(
+            0 < max_buffered[0] && max_buffered[0] <= LIMIT,
+            "{max_buffered:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn head_of_line_wait_behind_delayed_subflow() {
+        let (fast_tx, fast_rx) = SimStream::pair(ideal(), ideal());
+        let (slow_tx, slow_rx) =
+            SimStream::pair(SimConfig::new().latency(Duration::from_millis(50)), ideal());
+        let mut sender = SenderBuilder::new()
+            .send_mode(SendMode::Stripe)
+            .max_segment_size(NonZeroUsize::new(1 << 10).unwrap())
+            .build(vec![slow_tx, fast_tx]);
+        let mut receiver = Receiver::new(vec![slow_rx, fast_rx]);
+
+        let msg: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![];
+            let mut piece = [0; 1 << 12];
+            loop {
+                let n = receiver.recv(&mut piece).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                buf.extend_from_slice(&piece[..n]);
+            }
+            (buf, receiver.stats())
+        });
+        sender
+            .batch_send_all(Bytes::copy_from_slice(&msg))
+            .await
+            .unwrap();
+        sender.shutdown().await.unwrap();
+        drop(sender);
+
+        let (buf, stats) = recv_task.await.unwrap();
+        assert_eq!(buf, msg);
+        // The fast stream ran ahead while the byte stream waited for the slow one
+        assert!(
+            stats.head_of_line_wait() >= Duration::from_millis(25),
+            "{:?}",
+            stats.head_of_line_wait()
+        );
+        assert!(stats.max_gap() > 0);
+        let received: u64 = stats.subflows().iter().map(|s| s.bytes_received()).sum();
+        assert!(received >= msg.len() as u64);
+        assert!(stats.subflows().iter().all(|s| s.frames_received() > 0));
+    }
+
+    #[tokio::test]
+    async fn reassign_segments_stalled_on_black_holed_subflow() {
+        const INTERVAL: Duration = Duration::from_millis(20);
+        async fn transfer(
+            paths: Vec<SimConfig>,
+            msg: &[u8],
+        ) -> (Duration, Vec<StreamStats>, Vec<SubflowEvent>) {
+            let mut forward = (vec![], vec![]);
+            let mut backward = (vec![], vec![]);
+            for path in paths {
+                let (a, b) = SimStream::pair(path, ideal());
+                let (a_read, a_write) = tokio::io::split(a);
+                let (b_read, b_write) = tokio::io::split(b);
+                forward.0.push(a_write);
+                forward.1.push(b_read);
+                backward.0.push(b_write);
+                backward.1.push(a_read);
+            }
+            let mut receiver = Receiver::new(forward.1);
+            let mut probes = receiver.probes();
+            let mut reflector = Sender::new(backward.0);
+            let reflect_task = tokio::spawn(async move {
+                while let Some(probe) = probes.recv().await {
+                    reflector.reflect_probe(probe).await.unwrap();
+                }
+            });
+            let mut pongs = Receiver::new(backward.1);
+            let mut sender = SenderBuilder::new()
+                .retransmission(receiver.acks(), NonZeroUsize::new(1 << 17).unwrap())
+                .rtt_probes(pongs.pongs(), INTERVAL)
+                .stall_reassignment(true)
+                .max_segment_size(NonZeroUsize::new(1 << 14).unwrap())
+                .build(forward.0);
+            let mut events = sender.subscribe_events();
+            let mut receiver = receiver.into_async_read();
+            let recv_task = tokio::spawn(async move {
+                let mut buf = vec![];
+                receiver.read_to_end(&mut buf).await.unwrap();
+                buf
+            });
+            while sender.stats()[0].rtt().is_none() {
+                sender.probe_rtt().await.unwrap();
+                tokio::time::sleep(INTERVAL).await;
+            }
+
+            let start = Instant::now();
+            for chunk in msg.chunks(1 << 16) {
+                sender
+                    .batch_send_all(Bytes::copy_from_slice(chunk))
+                    .await
+                    .unwrap();
+            }
+            sender.close().await.unwrap();
+            let elapsed = start.elapsed();
+            let stats = sender.stats();
+            drop(sender);
+            reflect_task.abort();
+            assert_eq!(recv_task.await.unwrap(), msg);
+            let mut stalls = vec![];
+            while let Ok(event) = events.try_recv() {
+                if let SubflowEvent::Stalled { .. } = event {
+                    stalls.push(event);
+                }
+            }
+            (elapsed, stats, stalls)
+        }
+
+        let healthy = SimConfig::new()
+            .latency(Duration::from_millis(10))
+            .bandwidth(8 << 20);
+        // The 9 bytes of the handshake get through
+        let black_hole = SimConfig::new()
+            .latency(Duration::from_millis(10))
+            .drop_after(9);
+        let msg: Vec<u8> = (0..1 << 21).map(|_| rand::random()).collect();
+        let (alone, stats, stalls) = transfer(vec![healthy.clone()], &msg).await;
+        assert_eq!(stats[0].stalls(), 0);
+        assert!(stalls.is_empty());
+
+        let (elapsed, stats, stalls) = transfer(vec![healthy, black_hole], &msg).await;
+        assert!(elapsed < alone * 2, "{elapsed:?} {alone:?}");
+        assert_eq!(stats[0].stalls(), 0);
+        assert!(stats[1].stalls() > 0);
+        assert_eq!(stalls.len() as u64, stats[1].stalls());
+        // Everything the black hole took was written again on the healthy path
+        assert_eq!(stats[0].retransmitted_bytes(), stats[1].bytes_written());
+    }
+
+    #[tokio::test]
+    async fn transfer_report() {
+        let path = SimConfig::new()
+            .latency(Duration::from_millis(5))
+            .bandwidth(4 << 20);
+        let bulk = Workload::Bulk {
+            bytes: 1 << 21,
+            chunk: 1 << 16,
+        };
+        let report = super::transfer(&[path.clone(), path.clone()], SenderBuilder::new(), bulk)
+            .await
+            .unwrap();
+        assert_eq!(report.bytes(), 1 << 21);
+        assert_eq!(report.capacity(), Some(8 << 20));
+        let efficiency = report.efficiency().unwrap();
+        assert!(efficiency > 0.5 && efficiency < 1.0, "{efficiency}");
+
+        let messages = Workload::Messages {
+            count: 20,
+            size: 100,
+            interval: Duration::from_millis(2),
+        };
+        let report = super::transfer(&[path, ideal()], SenderBuilder::new(), messages)
+            .await
+            .unwrap();
+        assert_eq!(report.bytes(), 2000);
+        assert_eq!(report.capacity(), None);
+        assert!(report.latency(0.0) <= report.latency(0.5));
+        assert!(report.latency(0.5) <= report.latency(0.99));
+        assert!(report.elapsed() >= Duration::from_millis(38));
+    }
+
+    /// Hands the segments to the streams in the reverse of the order they are offered
+    #[derive(Debug)]
+    struct Reversed;
+
+    impl Scheduler for Reversed {
+        fn assign(&mut self, segments: &[SegmentMeta], streams: &[StreamMeta]) -> Vec<Assignment> {
+            (0..segments.len().min(streams.len()))
+                .map(|i| Assignment {
+                    segment: i,
+                    stream: streams.len() - 1 - i,
+                })
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_decisions() {
+        let (streams, _peers): (Vec<_>, Vec<_>) =
+            (0..2).map(|_| tokio::io::duplex(1 << 20)).unzip();
+        let mut sender = SenderBuilder::new().decision_log(64).build(streams);
+        for _ in 0..4 {
+            sender
+                .batch_send_all(Bytes::from(vec![0; 1 << 16]))
+                .await
+                .unwrap();
+        }
+        // A round has a segment per stream, or fewer once the goodput weights leave a stream too small a share
+        let log = sender.decision_log();
+        assert!((4..=8).contains(&log.len()));
+
+        let recorded: Vec<Option<StreamId>> = log.iter().map(|d| Some(d.stream())).collect();
+        assert_eq!(replay(log.iter(), &mut RoundRobin), recorded);
+        let reversed = replay(log.iter(), &mut Reversed);
+        assert_eq!(reversed.len(), recorded.len());
+        assert!(reversed.iter().all(Option::is_some));
+        assert_ne!(reversed, recorded);
+    }
+}
@@ -1,36 +1,247 @@
-use std::{io, net::SocketAddr, num::NonZeroUsize, pin::Pin};
+use std::{
+    io::{self, IoSlice},
+    net::SocketAddr,
+    num::NonZeroUsize,
+    pin::Pin,
+    task::Poll,
+    time::Duration,
+};
 
 use async_async_io::{read::PollRead, write::PollWrite, PollIo};
+use bytes::Bytes;
+use thiserror::Error;
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{self as tokio_io, AsyncRead, AsyncReadExt, AsyncWrite},
     net::{tcp, TcpStream, ToSocketAddrs},
     task::JoinSet,
 };
 
 use crate::{
-    message::{Init, Session},
-    receiver::Receiver,
-    sender::Sender,
+    message::{Hello, Init, Session, DATA_SEGMENT_OVERHEAD},
+    receiver::{Receiver, ReceiverBuilder},
+    sender::{SendError, Sender, StreamId},
+    session::{PeerAddr, SessionInfo, SetupCheck},
 };
 
+/// A duplex byte stream over a set of subflows
+///
+/// `W` is the write half of each subflow.
+///
+/// Like a `TcpStream`, shutting it down closes the write direction only: the peer reads to the end of the data while this end keeps reading what the peer sends.
+/// Dropping it tears both directions down as `Self::set_linger` says.
 #[derive(Debug)]
-pub struct MptcpStream {
-    poll: PollIo<Receiver, Sender<tcp::OwnedWriteHalf>>,
+pub struct MptcpStream<W = tcp::OwnedWriteHalf> {
+    /// `None` once split or torn down
+    poll: Option<PollIo<Receiver, Sender<W>>>,
     addr: SingleAddress,
+    /// The subflows announced in the `Init` of the session, if it started with one
+    init_streams: Option<NonZeroUsize>,
+    read_closed: bool,
+    write_closed: bool,
+    /// Whether the last read or write was left pending, which holds on to the `Receiver` or the `Sender`
+    read_pending: bool,
+    write_pending: bool,
+    linger: LingerPolicy,
+    /// Spawns the teardown of `LingerPolicy::Drain` with the bounds `Drop` cannot have
+    spawn_linger: SpawnLinger<W>,
 }
 
-impl MptcpStream {
-    pub(crate) fn new(
-        read_streams: Vec<tcp::OwnedReadHalf>,
-        write_streams: Vec<tcp::OwnedWriteHalf>,
+type SpawnLinger<W> = fn(PollIo<Receiver, Sender<W>>, Teardown, Duration);
+
+/// What dropping an `MptcpStream` does with its subflows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LingerPolicy {
+    /// Drop the subflows at once, which the peer sees as the byte stream breaking off unless the write direction was shut down
+    Abort,
+    /// Shut the write direction down, so that the peer reads to the FIN, and discard what the peer still sends until it finishes too, for up to the duration
+    ///
+    /// It happens on a task spawned on the runtime the stream is dropped in, and without a runtime it falls back to `Self::Abort`.
+    Drain(Duration),
+}
+
+impl Default for LingerPolicy {
+    /// `Self::Drain` for 10 seconds
+    fn default() -> Self {
+        Self::Drain(Duration::from_secs(10))
+    }
+}
+
+/// The directions a teardown still has to close
+#[derive(Debug, Clone, Copy)]
+struct Teardown {
+    read: bool,
+    write: bool,
+}
+
+impl<S> MptcpStream<tokio_io::WriteHalf<S>>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    /// Split each subflow into halves and send and receive over all of them
+    pub fn new(streams: Vec<S>) -> Self {
+        let (read_streams, write_streams) = streams.into_iter().map(tokio_io::split).unzip();
+        Self::from_split(read_streams, write_streams, SingleAddress::Unknown, None)
+    }
+}
+
+impl<W> MptcpStream<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    /// Announce the session of `init` in the handshakes and reject the subflows of the peer that announce another one
+    pub(crate) fn from_split<R>(
+        read_streams: Vec<R>,
+        write_streams: Vec<W>,
         addr: SingleAddress,
-    ) -> Self {
-        let sender = Sender::new(write_streams);
-        let receiver = Receiver::new(read_streams);
+        init: Option<&Init>,
+    ) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let session = init.map(Init::session);
+        let mut sender = Sender::new(write_streams);
+        sender.set_session(session);
+        let receiver = match session {
+            Some(session) => ReceiverBuilder::new().session(session),
+            None => ReceiverBuilder::new(),
+        }
+        .build(read_streams);
+        let mut stream = Self::from_parts(receiver, sender, addr);
+        stream.init_streams = init.map(Init::streams);
+        stream
+    }
+
+    fn from_parts(receiver: Receiver, sender: Sender<W>, addr: SingleAddress) -> Self {
         let poll = PollIo::new(PollRead::new(receiver), PollWrite::new(sender));
-        Self { poll, addr }
+        Self {
+            poll: Some(poll),
+            addr,
+            init_streams: None,
+            read_closed: false,
+            write_closed: false,
+            read_pending: false,
+            write_pending: false,
+            linger: LingerPolicy::default(),
+            spawn_linger: spawn_linger::<W>,
+        }
+    }
+
+    /// Shut the write direction down and discard what the peer still sends for the window of `Self::set_linger`, then drop the subflows
+    ///
+    /// The peer reads to the FIN even under `LingerPolicy::Abort`, which only skips the drain.
+    /// The direction of an operation left pending is dropped as it is.
+    /// Fails with the error of the shutdown.
+    pub async fn close(mut self) -> io::Result<()> {
+        let teardown = self.teardown();
+        let poll = self.poll.take().unwrap();
+        let window = match self.linger {
+            LingerPolicy::Abort => Duration::ZERO,
+            LingerPolicy::Drain(window) => window,
+        };
+        linger(poll, teardown, Some(window)).await
+    }
+
+    /// Set what dropping the stream does, `LingerPolicy::Drain` for 10 seconds by default
+    pub fn set_linger(&mut self, policy: LingerPolicy) {
+        self.linger = policy;
+    }
+
+    pub fn linger(&self) -> LingerPolicy {
+        self.linger
+    }
+
+    pub fn into_split(mut self) -> (OwnedReadHalf, OwnedWriteHalf<W>) {
+        let (read, write) = self.poll.take().unwrap().into_split();
+        let addr = self.addr;
+        let read = OwnedReadHalf {
+            poll: read,
+            addr,
+            init_streams: self.init_streams,
+            linger: self.linger,
+            closed: self.read_closed,
+            pending: self.read_pending,
+        };
+        let write = OwnedWriteHalf {
+            poll: write,
+            addr,
+            closed: self.write_closed,
+            pending: self.write_pending,
+            spawn_linger: self.spawn_linger,
+        };
+        (read, write)
+    }
+
+    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_, W>) {
+        let (read, write) = self.poll.as_mut().unwrap().split_mut();
+        let addr = self.addr;
+        let read = ReadHalf {
+            poll: read,
+            addr,
+            closed: &mut self.read_closed,
+            pending: &mut self.read_pending,
+        };
+        let write = WriteHalf {
+            poll: write,
+            addr,
+            closed: &mut self.write_closed,
+            pending: &mut self.write_pending,
+        };
+        (read, write)
+    }
+
+    /// Whether the peer finished sending and every byte it sent was read
+    pub fn is_read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    /// Whether the write direction was shut down
+    pub fn is_write_closed(&self) -> bool {
+        self.write_closed
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.addr.local()
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.addr.peer()
     }
 
+    /// Write the handshakes and wait for those of the peer, then tell what the session agreed on
+    ///
+    /// The peer writes its handshakes with its first data or by calling this too, see `SessionInfo::exchange`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an `AsyncWrite` operation was left pending.
+    pub async fn session_info(&mut self) -> io::Result<SessionInfo>
+    where
+        W: PeerAddr,
+    {
+        let (read, write) = self.poll.as_mut().unwrap().split_mut();
+        let info = SessionInfo::exchange(write.inner_mut(), read.inner()).await?;
+        Ok(match self.init_streams {
+            Some(streams) => info.with_peer_subflows(streams),
+            None => info,
+        })
+    }
+
+    /// Send `data` without copying it
+    ///
+    /// See `Sender::send`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an `AsyncWrite` operation was left pending.
+    pub async fn send(&mut self, data: Bytes) -> io::Result<()> {
+        check_write_open(self.write_closed)?;
+        let (_, write) = self.poll.as_mut().unwrap().split_mut();
+        write.inner_mut().send(data).await?;
+        Ok(())
+    }
+}
+
+impl MptcpStream {
     pub async fn connect(
         addr: impl IntoIterator<Item = impl ToSocketAddrs + Clone + Send + Sync + 'static>,
         streams: NonZeroUsize,
@@ -66,79 +277,285 @@ impl MptcpStream {
 
         let addr = SingleAddress::Peer(last_peer_addr.unwrap());
 
-        Ok(Self::new(read_streams, write_streams, addr))
+        Ok(Self::from_split(
+            read_streams,
+            write_streams,
+            addr,
+            Some(&init),
+        ))
     }
 
-    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
-        let (read, write) = self.poll.into_split();
-        let addr = self.addr;
-        let read = OwnedReadHalf { poll: read, addr };
-        let write = OwnedWriteHalf { poll: write, addr };
-        (read, write)
+    /// Send and receive over TCP streams already connected to the streams of a peer that does the same
+    ///
+    /// No session is set up: both ends write the handshake of the framing on every stream and check the one of the peer, so every stream is known to carry the protocol both ways before any data.
+    /// A peer that never writes its handshake leaves the call pending.
+    /// The frames written on every stream fit the frame size the peer advertised in its handshake, if any.
+    /// The handshakes announce a random session, and a stream of the peer disagreeing with the others fails with `crate::session::SessionSetupError` wrapped in `io::ErrorKind::InvalidData`.
+    /// Fails with the lowest index of the streams whose handshake failed.
+    pub async fn from_streams(streams: Vec<TcpStream>) -> Result<Self, SubflowHandshakeError> {
+        let addr = streams
+            .first()
+            .and_then(|stream| stream.peer_addr().ok())
+            .map_or(SingleAddress::Unknown, SingleAddress::Peer);
+        let (read_streams, write_streams): (Vec<_>, Vec<_>) =
+            streams.into_iter().map(TcpStream::into_split).unzip();
+        let mut sender = Sender::new(write_streams);
+        sender.set_session(Some(Session::new(rand::random())));
+        let greetings = read_streams.into_iter().map(|mut read| async move {
+            let hello = Hello::decode(&mut read).await?;
+            let max_frame_size = hello.max_frame_size().map(|size| size as usize);
+            if max_frame_size.is_some_and(|size| size <= DATA_SEGMENT_OVERHEAD) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "advertised frame size cannot fit a data segment",
+                ));
+            }
+            // The receiver reads the handshake again
+            let mut greeting = vec![];
+            hello.encode(&mut greeting).await?;
+            let read = io::Cursor::new(greeting).chain(read);
+            Ok((hello, max_frame_size.and_then(NonZeroUsize::new), read))
+        });
+        let (greeted, read_streams) = tokio::join!(
+            sender.handshake(),
+            futures_util::future::join_all(greetings)
+        );
+
+        let mut failed = vec![];
+        match greeted {
+            Ok(()) => (),
+            Err(SendError::Io(errors)) => failed.extend(
+                errors
+                    .into_iter()
+                    .map(|e| SubflowHandshakeError::new(e.id().inner(), e.into_error())),
+            ),
+            Err(e) => unreachable!("a handshake only fails on its streams: {e}"),
+        }
+        let mut setup = SetupCheck::new(None);
+        let read_streams: Vec<_> = read_streams
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, read)| {
+                let checked = read.and_then(|(hello, max_frame_size, read)| {
+                    setup
+                        .admit(index, &hello)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    Ok((max_frame_size, read))
+                });
+                checked
+                    .map_err(|e| failed.push(SubflowHandshakeError::new(index, e)))
+                    .ok()
+            })
+            .collect();
+        if let Some(error) = failed.into_iter().min_by_key(|e| e.index) {
+            return Err(error);
+        }
+        let read_streams = read_streams
+            .into_iter()
+            .enumerate()
+            .map(|(index, (max_frame_size, read))| {
+                sender.set_max_frame_size(StreamId::new(index), max_frame_size);
+                read
+            })
+            .collect();
+        Ok(Self::from_parts(Receiver::new(read_streams), sender, addr))
     }
+}
 
-    pub fn split(&mut self) -> (ReadHalf, WriteHalf) {
-        let (read, write) = self.poll.split_mut();
-        let addr = self.addr;
-        let read = ReadHalf { poll: read, addr };
-        let write = WriteHalf { poll: write, addr };
-        (read, write)
+/// A stream of `MptcpStream::from_streams` that failed the handshake
+#[derive(Debug, Error)]
+#[error("Subflow {index} failed the handshake")]
+pub struct SubflowHandshakeError {
+    index: usize,
+    #[source]
+    error: io::Error,
+}
+
+impl SubflowHandshakeError {
+    fn new(index: usize, error: io::Error) -> Self {
+        Self { index, error }
     }
 
-    pub fn local_addr(&self) -> Option<SocketAddr> {
-        self.addr.local()
+    /// The index of the stream in those passed to `MptcpStream::from_streams`
+    pub fn index(&self) -> usize {
+        self.index
     }
 
-    pub fn peer_addr(&self) -> Option<SocketAddr> {
-        self.addr.peer()
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    pub fn into_error(self) -> io::Error {
+        self.error
     }
 }
 
-impl AsyncRead for MptcpStream {
+impl From<SubflowHandshakeError> for io::Error {
+    fn from(e: SubflowHandshakeError) -> Self {
+        io::Error::new(e.error.kind(), e)
+    }
+}
+
+impl<W> AsyncRead for MptcpStream<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
     fn poll_read(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<io::Result<()>> {
-        Pin::new(&mut self.poll).poll_read(cx, buf)
+        let this = &mut *self;
+        let poll = Pin::new(this.poll.as_mut().unwrap());
+        let res = poll_read_tracked(poll, cx, buf, &mut this.read_closed);
+        track(res, &mut this.read_pending)
     }
 }
 
-impl AsyncWrite for MptcpStream {
+impl<W> AsyncWrite for MptcpStream<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, io::Error>> {
-        Pin::new(&mut self.poll).poll_write(cx, buf)
+        check_write_open(self.write_closed)?;
+        let this = &mut *self;
+        let res = Pin::new(this.poll.as_mut().unwrap()).poll_write(cx, buf);
+        track(res, &mut this.write_pending)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> std::task::Poll<Result<usize, io::Error>> {
+        check_write_open(self.write_closed)?;
+        let this = &mut *self;
+        let res = poll_write_coalesced(Pin::new(this.poll.as_mut().unwrap()), cx, bufs);
+        track(res, &mut this.write_pending)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.poll).poll_flush(cx)
+        if self.write_closed {
+            return std::task::Poll::Ready(Ok(()));
+        }
+        let this = &mut *self;
+        let res = Pin::new(this.poll.as_mut().unwrap()).poll_flush(cx);
+        track(res, &mut this.write_pending)
     }
 
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.poll).poll_shutdown(cx)
+        let this = &mut *self;
+        let poll = Pin::new(this.poll.as_mut().unwrap());
+        let res = poll_shutdown_tracked(poll, cx, &mut this.write_closed);
+        track(res, &mut this.write_pending)
+    }
+}
+
+impl<W> MptcpStream<W> {
+    fn teardown(&self) -> Teardown {
+        Teardown {
+            read: !self.read_pending && !self.read_closed,
+            write: !self.write_pending && !self.write_closed,
+        }
+    }
+}
+
+impl<W> Drop for MptcpStream<W> {
+    fn drop(&mut self) {
+        let (Some(poll), LingerPolicy::Drain(window)) = (self.poll.take(), self.linger) else {
+            return;
+        };
+        (self.spawn_linger)(poll, self.teardown(), window);
+    }
+}
+
+fn spawn_linger<W>(poll: PollIo<Receiver, Sender<W>>, teardown: Teardown, window: Duration)
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        runtime.spawn(async move {
+            let _ = tokio::time::timeout(window, linger(poll, teardown, None)).await;
+        });
+    }
+}
+
+/// Shut the write direction of `teardown` down, then drain the read direction for `window` or until the FIN
+async fn linger<W>(
+    poll: PollIo<Receiver, Sender<W>>,
+    teardown: Teardown,
+    window: Option<Duration>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (read, write) = poll.into_split();
+    let receiver = teardown.read.then(|| read.into_inner());
+    if teardown.write {
+        write.into_inner().shutdown().await?;
+    }
+    if let Some(mut receiver) = receiver {
+        let drain = async { while let Ok(Some(_)) = receiver.recv_bytes().await {} };
+        match window {
+            Some(window) => {
+                let _ = tokio::time::timeout(window, drain).await;
+            }
+            None => drain.await,
+        }
     }
+    Ok(())
+}
+
+/// Note in `pending` whether `poll` was left pending
+fn track<T>(poll: Poll<T>, pending: &mut bool) -> Poll<T> {
+    *pending = poll.is_pending();
+    poll
 }
 
 #[derive(Debug)]
 pub struct OwnedReadHalf {
     poll: PollRead<Receiver>,
     addr: SingleAddress,
+    /// Kept for `Self::reunite`
+    init_streams: Option<NonZeroUsize>,
+    linger: LingerPolicy,
+    closed: bool,
+    pending: bool,
 }
 
 impl OwnedReadHalf {
-    pub fn reunite(self, write: OwnedWriteHalf) -> MptcpStream {
+    pub fn reunite<W>(self, write: OwnedWriteHalf<W>) -> MptcpStream<W> {
         let poll = PollIo::new(self.poll, write.poll);
-        let addr = self.addr;
-        MptcpStream { poll, addr }
+        MptcpStream {
+            poll: Some(poll),
+            addr: self.addr,
+            init_streams: self.init_streams,
+            read_closed: self.closed,
+            write_closed: write.closed,
+            read_pending: self.pending,
+            write_pending: write.pending,
+            linger: self.linger,
+            spawn_linger: write.spawn_linger,
+        }
+    }
+
+    /// See `MptcpStream::is_read_closed`.
+    pub fn is_read_closed(&self) -> bool {
+        self.closed
     }
 
     pub fn local_addr(&self) -> Option<SocketAddr> {
@@ -156,21 +573,30 @@ impl AsyncRead for OwnedReadHalf {
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<io::Result<()>> {
-        Pin::new(&mut self.poll).poll_read(cx, buf)
+        let this = &mut *self;
+        let res = poll_read_tracked(Pin::new(&mut this.poll), cx, buf, &mut this.closed);
+        track(res, &mut this.pending)
     }
 }
 
 #[derive(Debug)]
-pub struct OwnedWriteHalf {
-    poll: PollWrite<Sender<tcp::OwnedWriteHalf>>,
+pub struct OwnedWriteHalf<W = tcp::OwnedWriteHalf> {
+    poll: PollWrite<Sender<W>>,
     addr: SingleAddress,
+    closed: bool,
+    pending: bool,
+    /// Kept for `Self::reunite`
+    spawn_linger: SpawnLinger<W>,
 }
 
-impl OwnedWriteHalf {
-    pub fn reunite(self, read: OwnedReadHalf) -> MptcpStream {
-        let poll = PollIo::new(read.poll, self.poll);
-        let addr = self.addr;
-        MptcpStream { poll, addr }
+impl<W> OwnedWriteHalf<W> {
+    pub fn reunite(self, read: OwnedReadHalf) -> MptcpStream<W> {
+        read.reunite(self)
+    }
+
+    /// See `MptcpStream::is_write_closed`.
+    pub fn is_write_closed(&self) -> bool {
+        self.closed
     }
 
     pub fn local_addr(&self) -> Option<SocketAddr> {
@@ -182,27 +608,74 @@ impl OwnedWriteHalf {
     }
 }
 
-impl AsyncWrite for OwnedWriteHalf {
+impl<W> OwnedWriteHalf<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    /// Send `data` without copying it
+    ///
+    /// See `Sender::send`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an `AsyncWrite` operation was left pending.
+    pub async fn send(&mut self, data: Bytes) -> io::Result<()> {
+        check_write_open(self.closed)?;
+        self.poll.inner_mut().send(data).await?;
+        Ok(())
+    }
+}
+
+impl<W> AsyncWrite for OwnedWriteHalf<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, io::Error>> {
-        Pin::new(&mut self.poll).poll_write(cx, buf)
+        check_write_open(self.closed)?;
+        let this = &mut *self;
+        track(
+            Pin::new(&mut this.poll).poll_write(cx, buf),
+            &mut this.pending,
+        )
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> std::task::Poll<Result<usize, io::Error>> {
+        check_write_open(self.closed)?;
+        let this = &mut *self;
+        let res = poll_write_coalesced(Pin::new(&mut this.poll), cx, bufs);
+        track(res, &mut this.pending)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.poll).poll_flush(cx)
+        if self.closed {
+            return std::task::Poll::Ready(Ok(()));
+        }
+        let this = &mut *self;
+        track(Pin::new(&mut this.poll).poll_flush(cx), &mut this.pending)
     }
 
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.poll).poll_shutdown(cx)
+        let this = &mut *self;
+        let res = poll_shutdown_tracked(Pin::new(&mut this.poll), cx, &mut this.closed);
+        track(res, &mut this.pending)
     }
 }
 
@@ -210,9 +683,16 @@ impl AsyncWrite for OwnedWriteHalf {
 pub struct ReadHalf<'poll> {
     poll: &'poll mut PollRead<Receiver>,
     addr: SingleAddress,
+    closed: &'poll mut bool,
+    pending: &'poll mut bool,
 }
 
 impl ReadHalf<'_> {
+    /// See `MptcpStream::is_read_closed`.
+    pub fn is_read_closed(&self) -> bool {
+        *self.closed
+    }
+
     pub fn local_addr(&self) -> Option<SocketAddr> {
         self.addr.local()
     }
@@ -228,17 +708,26 @@ impl AsyncRead for ReadHalf<'_> {
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<io::Result<()>> {
-        Pin::new(&mut self.poll).poll_read(cx, buf)
+        let this = &mut *self;
+        let res = poll_read_tracked(Pin::new(&mut this.poll), cx, buf, this.closed);
+        track(res, this.pending)
     }
 }
 
 #[derive(Debug)]
-pub struct WriteHalf<'poll> {
-    poll: &'poll mut PollWrite<Sender<tcp::OwnedWriteHalf>>,
+pub struct WriteHalf<'poll, W = tcp::OwnedWriteHalf> {
+    poll: &'poll mut PollWrite<Sender<W>>,
     addr: SingleAddress,
+    closed: &'poll mut bool,
+    pending: &'poll mut bool,
 }
 
-impl WriteHalf<'_> {
+impl<W> WriteHalf<'_, W> {
+    /// See `MptcpStream::is_write_closed`.
+    pub fn is_write_closed(&self) -> bool {
+        *self.closed
+    }
+
     pub fn local_addr(&self) -> Option<SocketAddr> {
         self.addr.local()
     }
@@ -248,48 +737,362 @@ impl WriteHalf<'_> {
     }
 }
 
-impl AsyncWrite for WriteHalf<'_> {
+impl<W> AsyncWrite for WriteHalf<'_, W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, io::Error>> {
-        Pin::new(&mut self.poll).poll_write(cx, buf)
+        check_write_open(*self.closed)?;
+        let this = &mut *self;
+        track(Pin::new(&mut this.poll).poll_write(cx, buf), this.pending)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> std::task::Poll<Result<usize, io::Error>> {
+        check_write_open(*self.closed)?;
+        let this = &mut *self;
+        let res = poll_write_coalesced(Pin::new(&mut this.poll), cx, bufs);
+        track(res, this.pending)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.poll).poll_flush(cx)
+        if *self.closed {
+            return std::task::Poll::Ready(Ok(()));
+        }
+        let this = &mut *self;
+        track(Pin::new(&mut this.poll).poll_flush(cx), this.pending)
     }
 
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.poll).poll_shutdown(cx)
+        let this = &mut *self;
+        let res = poll_shutdown_tracked(Pin::new(&mut this.poll), cx, this.closed);
+        track(res, this.pending)
+    }
+}
+
+/// Read into `buf`, noting the end of the byte stream in `closed`
+fn poll_read_tracked<R>(
+    read: Pin<&mut R>,
+    cx: &mut std::task::Context<'_>,
+    buf: &mut tokio::io::ReadBuf<'_>,
+    closed: &mut bool,
+) -> std::task::Poll<io::Result<()>>
+where
+    R: AsyncRead,
+{
+    let filled = buf.filled().len();
+    std::task::ready!(read.poll_read(cx, buf))?;
+    if buf.remaining() != 0 && buf.filled().len() == filled {
+        *closed = true;
+    }
+    std::task::Poll::Ready(Ok(()))
+}
+
+/// Shut down the write direction once, noting it in `closed`
+///
+/// Only the write halves of the subflows are shut down, so the peer can still send on them.
+fn poll_shutdown_tracked<W>(
+    write: Pin<&mut W>,
+    cx: &mut std::task::Context<'_>,
+    closed: &mut bool,
+) -> std::task::Poll<Result<(), io::Error>>
+where
+    W: AsyncWrite,
+{
+    if !*closed {
+        std::task::ready!(write.poll_shutdown(cx))?;
+        *closed = true;
+    }
+    std::task::Poll::Ready(Ok(()))
+}
+
+fn check_write_open(closed: bool) -> io::Result<()> {
+    if closed {
+        return Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "the write direction is shut down",
+        ));
+    }
+    Ok(())
+}
+
+/// Write `bufs` as one buffer since the sender copies the data anyway
+fn poll_write_coalesced<W>(
+    write: Pin<&mut W>,
+    cx: &mut std::task::Context<'_>,
+    bufs: &[IoSlice<'_>],
+) -> std::task::Poll<Result<usize, io::Error>>
+where
+    W: AsyncWrite,
+{
+    let mut buf = Vec::with_capacity(bufs.iter().map(|buf| buf.len()).sum());
+    for slice in bufs {
+        buf.extend_from_slice(slice);
     }
+    write.poll_write(cx, &buf)
 }
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum SingleAddress {
     Local(SocketAddr),
     Peer(SocketAddr),
+    /// The subflows are not TCP streams
+    Unknown,
 }
 
 impl SingleAddress {
     pub fn local(&self) -> Option<SocketAddr> {
         match self {
             SingleAddress::Local(local) => Some(*local),
-            SingleAddress::Peer(_) => None,
+            SingleAddress::Peer(_) | SingleAddress::Unknown => None,
         }
     }
 
     pub fn peer(&self) -> Option<SocketAddr> {
         match self {
-            SingleAddress::Local(_) => None,
+            SingleAddress::Local(_) | SingleAddress::Unknown => None,
             SingleAddress::Peer(peer) => Some(*peer),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use crate::message::HandshakeError;
+
+    use super::*;
+
+    /// `count` connected pairs of TCP streams on localhost, with the client ends first
+    async fn tcp_pairs(count: usize) -> (Vec<TcpStream>, Vec<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut clients = vec![];
+        let mut servers = vec![];
+        for _ in 0..count {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+            servers.push(listener.accept().await.unwrap().0);
+        }
+        (clients, servers)
+    }
+
+    #[tokio::test]
+    async fn from_tcp_streams() {
+        let (clients, servers) = tcp_pairs(3).await;
+        let (client, server) = tokio::join!(
+            MptcpStream::from_streams(clients),
+            MptcpStream::from_streams(servers)
+        );
+        let (client, mut server) = (client.unwrap(), server.unwrap());
+        assert!(client.peer_addr().is_some() && server.peer_addr().is_some());
+
+        let request: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        let response: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        let (mut read, mut write) = client.into_split();
+        let write_request = request.clone();
+        let client_task = tokio::spawn(async move {
+            write.write_all(&write_request).await.unwrap();
+            write.shutdown().await.unwrap();
+            let mut buf = vec![];
+            read.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        let mut buf = vec![];
+        server.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, request);
+        server.write_all(&response).await.unwrap();
+        server.shutdown().await.unwrap();
+        assert_eq!(client_task.await.unwrap(), response);
+    }
+
+    #[tokio::test]
+    async fn half_close_keeps_reading() {
+        let (clients, servers) = tcp_pairs(2).await;
+        let (client, server) = tokio::join!(
+            MptcpStream::from_streams(clients),
+            MptcpStream::from_streams(servers)
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        let echo = tokio::spawn(async move {
+            let (mut read, mut write) = server.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+            assert!(read.is_read_closed() && !write.is_write_closed());
+            write.shutdown().await.unwrap();
+            assert!(server.is_read_closed() && server.is_write_closed());
+        });
+
+        let request: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        client.write_all(&request).await.unwrap();
+        client.shutdown().await.unwrap();
+        assert!(client.is_write_closed() && !client.is_read_closed());
+        let err = client.write_all(b"late").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        client.shutdown().await.unwrap();
+
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, request);
+        assert!(client.is_read_closed());
+        echo.await.unwrap();
+
+        let (read, write) = client.into_split();
+        assert!(read.is_read_closed() && write.is_write_closed());
+        let client = read.reunite(write);
+        assert!(client.is_read_closed() && client.is_write_closed());
+    }
+
+    #[tokio::test]
+    async fn from_streams_rejects_foreign_subflow() {
+        let (mut clients, servers) = tcp_pairs(3).await;
+        for (index, client) in clients.iter_mut().enumerate() {
+            match index {
+                1 => client.write_all(b"SSH-2.0-OpenSSH\r\n").await.unwrap(),
+                _ => Hello::new(0).encode(client).await.unwrap(),
+            }
+        }
+        let err = MptcpStream::from_streams(servers).await.unwrap_err();
+        assert_eq!(err.index(), 1);
+        assert_eq!(err.error().kind(), io::ErrorKind::InvalidData);
+        let handshake = err.error().get_ref().unwrap().downcast_ref();
+        assert!(matches!(handshake, Some(HandshakeError::BadMagic)));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn copy_bidirectional() {
+        let mut client_streams = vec![];
+        let mut server_streams = vec![];
+        for _ in 0..4 {
+            let (client, server) = tokio::io::duplex(64);
+            client_streams.push(client);
+            server_streams.push(server);
+        }
+        let client = MptcpStream::new(client_streams);
+        let mut server = MptcpStream::new(server_streams);
+
+        // Proxy the server end to an echo service
+        let (mut backend, echo) = tokio::io::duplex(64);
+        let echo_task = tokio::spawn(async move {
+            let (mut read, mut write) = tokio::io::split(echo);
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+            write.shutdown().await.unwrap();
+        });
+        let proxy_task = tokio::spawn(async move {
+            tokio::io::copy_bidirectional(&mut server, &mut backend)
+                .await
+                .unwrap()
+        });
+
+        let msg: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        let (mut read, mut write) = client.into_split();
+        let write_msg = msg.clone();
+        let write_task = tokio::spawn(async move {
+            write.write_all(&write_msg).await.unwrap();
+            write.shutdown().await.unwrap();
+        });
+        let mut buf = vec![];
+        read.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+
+        write_task.await.unwrap();
+        echo_task.await.unwrap();
+        let (to_backend, to_server) = proxy_task.await.unwrap();
+        assert_eq!(to_backend, msg.len() as u64);
+        assert_eq!(to_server, msg.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn write_vectored() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let mut client = MptcpStream::new(vec![client]);
+        let mut server = MptcpStream::new(vec![server]);
+        assert!(client.is_write_vectored());
+
+        let bufs = [b"hello".as_slice(), b" ", b"world"].map(IoSlice::new);
+        let n = client.write_vectored(&bufs).await.unwrap();
+        assert_eq!(n, 11);
+        let mut buf = [0; 11];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn send_bytes() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (_, mut write) = MptcpStream::new(vec![client]).into_split();
+        let mut server = MptcpStream::new(vec![server]);
+
+        write.send(Bytes::from_static(b"hello")).await.unwrap();
+        write.write_all(b" world").await.unwrap();
+        let mut buf = [0; 11];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+    }
+
+    async fn tcp_stream_pair() -> (MptcpStream, MptcpStream) {
+        let (clients, servers) = tcp_pairs(2).await;
+        let (client, server) = tokio::join!(
+            MptcpStream::from_streams(clients),
+            MptcpStream::from_streams(servers)
+        );
+        (client.unwrap(), server.unwrap())
+    }
+
+    #[tokio::test]
+    async fn drop_drains() {
+        let (mut client, mut server) = tcp_stream_pair().await;
+        assert_eq!(client.linger(), LingerPolicy::default());
+        let request: Vec<u8> = (0..1 << 16).map(|_| rand::random()).collect();
+        client.write_all(&request).await.unwrap();
+        drop(client);
+
+        // The peer reads to the FIN, and its data is drained after it
+        let mut buf = vec![];
+        server.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, request);
+        server.write_all(b"late").await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_aborts() {
+        let (mut client, mut server) = tcp_stream_pair().await;
+        client.set_linger(LingerPolicy::Abort);
+        client.write_all(b"hello").await.unwrap();
+        drop(client);
+
+        let mut buf = vec![];
+        server.read_to_end(&mut buf).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let (mut client, mut server) = tcp_stream_pair().await;
+        let echo = tokio::spawn(async move {
+            let (mut read, mut write) = server.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+            write.shutdown().await.unwrap();
+        });
+        client.write_all(b"hello").await.unwrap();
+        client.close().await.unwrap();
+        echo.await.unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! `tracing` spans and events of the send and receive paths, behind the `tracing` feature
+//!
+//! Without the feature nothing below is compiled in. The names and fields are stable:
+//!
+//! | Target           | Level | Name                 | Fields |
+//! |------------------|-------|----------------------|--------|
+//! | `mptcp::sender`   | debug | span `batch_send_all` | `start_sequence`, `end_sequence`, `bytes` |
+//! | `mptcp::sender`   | debug | `segment_dispatched`  | `stream_id`, `sequence`, `len` |
+//! | `mptcp::sender`   | debug | `segment_completed`   | `stream_id`, `sequence`, `len`, `elapsed_us` |
+//! | `mptcp::sender`   | warn  | `subflow_failed`      | `stream_id`, `sequence` if a segment was being written, `kind`, `error` |
+//! | `mptcp::receiver` | debug | `gap_opened`          | `expected`, `missing_bytes` |
+//! | `mptcp::receiver` | debug | `gap_closed`          | `next`, `waited_us` |
+//!
+//! The name of an event is its message. Sequences are `u64`s, `stream_id` is the `StreamId` of the sender and `len` counts payload bytes.
+//! `segment_completed` counts the bytes the stream wrote of the segment, which stealing and pacing may leave short of the dispatched `len`.
+//! A gap is open while the receiver buffers data but misses the next byte to read, whose sequence is `expected` and then `next`.
+
+/// A `tracing::debug!` compiled in with the `tracing` feature only
+macro_rules! debug_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!($($arg)*)
+    };
+}
+
+/// A `tracing::warn!` compiled in with the `tracing` feature only
+macro_rules! warn_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::warn!($($arg)*)
+    };
+}
+
+pub(crate) use debug_event;
+pub(crate) use warn_event;
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fmt,
+        num::NonZeroUsize,
+        sync::{Arc, Mutex},
+    };
+
+    use bytes::Bytes;
+    use tokio::io::AsyncWriteExt;
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Subscriber,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+    use crate::{
+        message::{DataSegment, Hello, Message, Sequence},
+        receiver::Receiver,
+        sender::SenderBuilder,
+    };
+
+    /// An event, or a span, with its fields formatted
+    #[derive(Debug)]
+    struct Record {
+        target: String,
+        name: String,
+        /// Of the span the event is in
+        span: Option<String>,
+        fields: HashMap<String, String>,
+    }
+
+    impl Record {
+        fn field(&self, name: &str) -> Option<&str> {
+            self.fields.get(name).map(String::as_str)
+        }
+    }
+
+    impl Visit for Record {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            let value = format!("{value:?}");
+            match field.name() {
+                "message" => self.name = value,
+                name => {
+                    self.fields.insert(name.to_owned(), value);
+                }
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<Record>>>);
+
+    impl Collector {
+        fn named(&self, name: &str) -> Vec<Record> {
+            let mut records = self.0.lock().unwrap();
+            let (named, rest) = records.drain(..).partition(|record| record.name == name);
+            *records = rest;
+            named
+        }
+    }
+
+    impl<S> Layer<S> for Collector
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, attrs: &span::Attributes<'_>, _: &span::Id, _: Context<'_, S>) {
+            let mut record = Record {
+                target: attrs.metadata().target().to_owned(),
+                name: attrs.metadata().name().to_owned(),
+                span: None,
+                fields: HashMap::new(),
+            };
+            attrs.record(&mut record);
+            self.0.lock().unwrap().push(record);
+        }
+
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            let mut record = Record {
+                target: event.metadata().target().to_owned(),
+                name: String::new(),
+                span: ctx.event_span(event).map(|span| span.name().to_owned()),
+                fields: HashMap::new(),
+            };
+            event.record(&mut record);
+            self.0.lock().unwrap().push(record);
+        }
+    }
+
+    #[tokio::test]
+    async fn events_of_a_transfer() {
+        let collector = Collector::default();
+        let _default = tracing_subscriber::registry()
+            .with(collector.clone())
+            .set_default();
+
+        let (tx, mut rx) = tokio::io::duplex(1 << 16);
+        let (dead_tx, dead_rx) = tokio::io::duplex(1 << 16);
+        drop(dead_rx);
+        let mut sender = SenderBuilder::new()
+            .max_segment_size(NonZeroUsize::new(1000).unwrap())
+            .build(vec![tx, dead_tx]);
+        sender
+            .batch_send_all(Bytes::from(vec![1; 4000]))
+            .await
+            .unwrap();
+        assert_eq!(sender.take_evicted_streams().len(), 1);
+        Hello::decode(&mut rx).await.unwrap();
+
+        let spans = collector.named("batch_send_all");
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].target, "mptcp::sender");
+        assert_eq!(spans[0].field("start_sequence"), Some("0"));
+        assert_eq!(spans[0].field("end_sequence"), Some("4000"));
+        assert_eq!(spans[0].field("bytes"), Some("4000"));
+
+        let failed = collector.named("subflow_failed");
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].field("stream_id"), Some("1"));
+        assert_eq!(failed[0].field("kind"), Some("BrokenPipe"));
+        assert!(failed[0].fields.contains_key("error"));
+
+        let dispatched = collector.named("segment_dispatched");
+        let completed = collector.named("segment_completed");
+        for record in dispatched.iter().chain(&completed) {
+            assert_eq!(record.target, "mptcp::sender");
+            assert_eq!(record.span.as_deref(), Some("batch_send_all"));
+        }
+        // Every byte was written by the live stream in the end
+        let mut written: Vec<(u64, u64)> = completed
+            .iter()
+            .map(|record| {
+                assert_eq!(record.field("stream_id"), Some("0"));
+                assert!(record.fields.contains_key("elapsed_us"));
+                let sequence = record.field("sequence").unwrap().parse().unwrap();
+                (sequence, record.field("len").unwrap().parse().unwrap())
+            })
+            .collect();
+        written.sort_unstable();
+        assert_eq!(written.iter().map(|(_, len)| len).sum::<u64>(), 4000);
+        assert_eq!(written[0].0, 0);
+        assert!(dispatched
+            .iter()
+            .any(|record| record.field("stream_id") == Some("1")));
+
+        let (mut tx, rx) = tokio::io::duplex(1 << 10);
+        let mut receiver = Receiver::new(vec![rx]);
+        Hello::new(0).encode(&mut tx).await.unwrap();
+        for (start, payload) in [(5, &b"world"[..]), (0, b"hello")] {
+            let data_segment =
+                DataSegment::new(Sequence::new(start), Bytes::from_static(payload)).unwrap();
+            Message::DataSegment(data_segment)
+                .encode(&mut tx)
+                .await
+                .unwrap();
+        }
+        tx.flush().await.unwrap();
+        let mut buf = [0; 10];
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += receiver.recv(&mut buf[filled..]).await.unwrap();
+        }
+
+        let opened = collector.named("gap_opened");
+        assert_eq!(opened.len(), 1);
+        assert_eq!(opened[0].target, "mptcp::receiver");
+        assert_eq!(opened[0].field("expected"), Some("0"));
+        assert_eq!(opened[0].field("missing_bytes"), Some("5"));
+        let closed = collector.named("gap_closed");
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].field("next"), Some("0"));
+        assert!(closed[0].fields.contains_key("waited_us"));
+    }
+}
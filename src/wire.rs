@@ -0,0 +1,1906 @@
+//! The wire format of a subflow
+//!
+//! Every integer is big-endian and there is no padding.
+//!
+//! A client starts every subflow with an `Init` to the listener:
+//!
+//! | Field    | Type  | |
+//! |----------|-------|-|
+//! | session  | `u64` | Identifies the session among the subflows of every client |
+//! | subflows | `u64` | The number of subflows in the session, never zero |
+//!
+//! A listener with a `crate::auth::SessionSecret` answers with a nonce of `crate::auth::NONCE_SIZE` bytes, the client with an HMAC-SHA256 of the session `u64` and the nonce keyed by the secret, and the listener with a `u8` verdict: 0 if the proof is right.
+//! Depending on its `crate::auth::RejectPolicy`, a listener closes a subflow with a wrong proof right away or after a verdict of 1.
+//!
+//! Both directions of a subflow then start with a `Hello`:
+//!
+//! | Field        | Type      | |
+//! |--------------|-----------|-|
+//! | magic        | `[u8; 4]` | `MAGIC` |
+//! | version      | `u8`      | `VERSION` |
+//! | capabilities | `u32`     | Bit set of the `CAPABILITY_*` constants |
+//!
+//! Then come the fields of the capabilities that have one, in this order:
+//!
+//! | Capability                  | Fields |
+//! |-----------------------------|--------|
+//! | `CAPABILITY_MAX_FRAME_SIZE` | largest frame accepted on the opposite direction `u32` |
+//! | `CAPABILITY_SUBFLOW_ID`     | session `u64`, subflow ID `u32` |
+//! | `CAPABILITY_FEEDBACK`       | feedback interval in milliseconds `u32` |
+//! | `CAPABILITY_PASSTHROUGH`    | sequence of the first raw payload byte `u64` |
+//!
+//! After a hello with `CAPABILITY_PASSTHROUGH` come raw payload bytes from its sequence on instead of frames, with every `PASSTHROUGH_ESCAPE` byte doubled.
+//! An escape followed by `PASSTHROUGH_SWITCH` ends them, and frames follow as after any other hello; an escape followed by any other byte is invalid.
+//! A sender only does so on the lone subflow of a byte stream, so that a payload without the escape byte, such as UTF-8 text, crosses it byte for byte.
+//!
+//! What follows is a sequence of frames, each made of a `u8` type code and a body that depends on it:
+//!
+//! | Type code | Frame                    | Body |
+//! |-----------|--------------------------|------|
+//! | 0         | Data segment             | start sequence, payload length `u32`, payload |
+//! | 1         | Ping                     | |
+//! | 2         | Shutdown                 | |
+//! | 3         | Checksummed data segment | start sequence, payload length `u32`, payload, CRC32 of the payload `u32` |
+//! | 4         | Fin                      | final sequence |
+//! | 5         | Ack                      | sequence |
+//! | 6         | Control                  | payload length `u16`, payload |
+//! | 7         | Compact data segment     | start sequence delta varint, payload length varint, payload |
+//! | 8         | Compact checksummed data segment | start sequence delta varint, payload length varint, payload, CRC32 of the payload `u32` |
+//! | 9         | Probe                    | timestamp `u64` |
+//! | 10        | Pong                     | timestamp `u64` of the probe answered |
+//! | 11        | LZ4 data segment         | start sequence, payload length `u32`, compressed length `u32`, payload in the LZ4 block format |
+//! | 12        | LZ4 checksummed data segment | start sequence, payload length `u32`, compressed length `u32`, payload in the LZ4 block format, CRC32 of the payload `u32` |
+//! | 13        | Out-of-band message      | OOB sequence `u32`, payload length `u16`, payload |
+//! | 14        | Feedback                 | score count `u16`, scores of subflow index `u32`, frames `u64`, gaps `u64`, jitter in microseconds `u32` |
+//! | 15        | Abort                    | sequence, reason length `u16`, reason in UTF-8 |
+//! | 128–255   | Extension frame          | payload length `u16`, payload |
+//!
+//! The payload of a data segment is never empty and does not run past `u64::MAX` in the sequence space.
+//! Checksummed data segments are only sent with `CAPABILITY_CHECKSUM` in the hello, compact ones with `CAPABILITY_COMPACT_HEADERS`, LZ4 ones with `CAPABILITY_LZ4` and probes with `CAPABILITY_RTT_PROBES`.
+//! The payload length of an LZ4 data segment is that of the payload once decompressed, which its CRC32 is computed over.
+//! The timestamp of a probe means nothing to the receiver, which reflects it in a pong on the opposite direction.
+//! A feedback frame answers the interval in the hello of the opposite direction with the scores of the subflows over the last interval, indexed like the streams of the receiver.
+//! Out-of-band messages take no place in the byte stream: their sequences count the messages of the sender from 0, wrapping around, so that the receiver can drop the copies sent on several subflows.
+//! An abort takes the place of a fin where the sender gave up on the byte stream: the receiver reads up to its sequence and then fails instead of ending.
+//! No frame follows a shutdown on the same subflow.
+//! The type codes of `EXTENSION_TYPE_CODES` are reserved for the frames of later versions, which all take the body of an extension frame so that a receiver skips those it does not know instead of ending the subflow.
+//!
+//! A sequence is a `u64`, or its low 32 bits as a `u32` on a subflow whose hello has `CAPABILITY_SEQUENCE_U32`, which the receiver must expect: see `SeqWidth`.
+//! A truncated sequence stands for the nearest sequence with those low bits to a reference, or the one after it if the nearest is negative.
+//! The reference of data segments, fins and aborts is the furthest of the end of the previous data segment on the subflow and where the reassembly of the receiver stands, that of acks the previous ack on the subflow.
+//!
+//! A varint is the unsigned LEB128 encoding of a `u64`: seven bits per byte from the least significant, with the high bit set on every byte but the last.
+//! It takes at most `MAX_VARINT_SIZE` bytes and does not end with a zero byte, except for 0 itself; a payload length varint is at most `u32::MAX`.
+//! The start sequence of a compact data segment is its delta added, modulo 2<sup>64</sup>, to the end sequence of the previous data segment on the same subflow in either encoding, or to 0 for the first one.
+//!
+//! [`Frame::decode`] is a synchronous, incremental decoder of these frames for event loops and for testing other implementations against, which takes them after any passthrough bytes.
+//! [`inspect`] lists the frames of a capture of a subflow, such as the payload of a TCP stream out of `tcpdump`, for debugging.
+//! The exact bytes of every frame type are pinned by the vectors of `tests/golden`, which a change of the encoding breaks.
+
+use std::{
+    fmt,
+    io::{self, IoSlice},
+    ops::{Range, RangeInclusive},
+};
+
+use bytes::{Buf, BufMut, Bytes};
+use thiserror::Error;
+
+use crate::{
+    compression::Compression,
+    message::{
+        DataSegment, EncodeOptions, Hello, Message, Sequence, SubflowScore, MAGIC,
+        MAX_PAYLOAD_SIZE, SUBFLOW_SCORE_SIZE,
+    },
+};
+
+pub const DATA_SEGMENT_TYPE_CODE: u8 = 0;
+pub const PING_TYPE_CODE: u8 = 1;
+pub const SHUTDOWN_TYPE_CODE: u8 = 2;
+pub const CHECKSUMMED_DATA_SEGMENT_TYPE_CODE: u8 = 3;
+pub const FIN_TYPE_CODE: u8 = 4;
+pub const ACK_TYPE_CODE: u8 = 5;
+pub const CONTROL_TYPE_CODE: u8 = 6;
+pub const COMPACT_DATA_SEGMENT_TYPE_CODE: u8 = 7;
+pub const COMPACT_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE: u8 = 8;
+pub const PROBE_TYPE_CODE: u8 = 9;
+pub const PONG_TYPE_CODE: u8 = 10;
+pub const LZ4_DATA_SEGMENT_TYPE_CODE: u8 = 11;
+pub const LZ4_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE: u8 = 12;
+pub const OOB_TYPE_CODE: u8 = 13;
+pub const FEEDBACK_TYPE_CODE: u8 = 14;
+pub const ABORT_TYPE_CODE: u8 = 15;
+
+/// Doubled in passthrough bytes, or followed by `PASSTHROUGH_SWITCH` where they end
+pub const PASSTHROUGH_ESCAPE: u8 = 0xff;
+/// Follows `PASSTHROUGH_ESCAPE` where the passthrough bytes switch to frames
+pub const PASSTHROUGH_SWITCH: u8 = 0x00;
+
+/// The type codes of extension frames, see `Frame::Unknown`
+pub const EXTENSION_TYPE_CODES: RangeInclusive<u8> = 128..=255;
+
+/// The largest payload `Frame::decode` accepts
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 1 << 24;
+
+/// The longest varint
+pub const MAX_VARINT_SIZE: usize = 10;
+
+/// The longest header, that of LZ4 data segments
+const MAX_HEADER_SIZE: usize = 1 + 8 + 4 + 4;
+
+/// How many bits of a sequence the frames of a subflow carry
+///
+/// Both ends of a subflow must agree on it: a receiver rejects a hello announcing another width with `crate::message::HandshakeError::SequenceWidthMismatch`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SeqWidth {
+    /// The low 32 bits, saving 4 bytes per full header, fin and ack
+    ///
+    /// Sequences still go up to `u64::MAX` and wrap around on the wire every 4 GiB of the byte stream.
+    /// A sequence is only told apart from the one 4 GiB away as long as no subflow carries data more than 2 GiB off the reference of the receiver, which the send window and the buffer limits of the receiver are to bound.
+    U32,
+    #[default]
+    U64,
+}
+
+impl SeqWidth {
+    /// Bytes a sequence takes on the wire
+    pub fn size(self) -> usize {
+        match self {
+            Self::U32 => 4,
+            Self::U64 => 8,
+        }
+    }
+}
+
+/// What the headers of the frames on a subflow are relative to
+///
+/// Each end of a subflow keeps one across the frames of the subflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderContext {
+    previous_end: Sequence,
+    width: SeqWidth,
+    /// What truncated sequences of the byte stream are expanded around
+    reference: Sequence,
+    previous_ack: Sequence,
+}
+
+impl HeaderContext {
+    pub fn new() -> Self {
+        Self::with_sequence_width(SeqWidth::U64)
+    }
+
+    pub fn with_sequence_width(width: SeqWidth) -> Self {
+        Self {
+            previous_end: Sequence::new(0),
+            width,
+            reference: Sequence::new(0),
+            previous_ack: Sequence::new(0),
+        }
+    }
+
+    /// The end sequence of the previous data segment
+    pub fn previous_end(&self) -> Sequence {
+        self.previous_end
+    }
+
+    pub fn sequence_width(&self) -> SeqWidth {
+        self.width
+    }
+
+    /// Expand the truncated sequences of the byte stream around `sequence` from now on if it is further than the reference so far
+    ///
+    /// A receiver passes where its reassembly stands, so that a subflow that sat idle while the others carried gigabytes still expands its next frames right.
+    pub fn observe(&mut self, sequence: Sequence) {
+        self.reference = self.reference.max(sequence);
+    }
+
+    /// Like `Self::observe` for the acknowledgements of the opposite byte stream
+    pub fn observe_ack(&mut self, ack: Sequence) {
+        self.previous_ack = self.previous_ack.max(ack);
+    }
+
+    pub(crate) fn delta_to(&self, start_sequence: Sequence) -> u64 {
+        start_sequence
+            .inner()
+            .wrapping_sub(self.previous_end.inner())
+    }
+
+    pub(crate) fn start_sequence(&self, delta: u64) -> Sequence {
+        Sequence::new(self.previous_end.inner().wrapping_add(delta))
+    }
+
+    pub(crate) fn record(&mut self, data_segment_end: Sequence) {
+        self.previous_end = data_segment_end;
+        self.observe(data_segment_end);
+    }
+
+    /// The sequence of the byte stream a sequence field of the subflow stands for
+    pub(crate) fn expand(&self, field: u64) -> Result<Sequence, DecodeError> {
+        expand(self.width, self.reference, field)
+    }
+
+    /// The acknowledgement an ack field of the subflow stands for
+    pub(crate) fn expand_ack(&mut self, field: u64) -> Result<Sequence, DecodeError> {
+        let ack = expand(self.width, self.previous_ack, field)?;
+        self.observe_ack(ack);
+        Ok(ack)
+    }
+}
+
+fn expand(width: SeqWidth, reference: Sequence, field: u64) -> Result<Sequence, DecodeError> {
+    if width == SeqWidth::U64 {
+        return Ok(Sequence::new(field));
+    }
+    let reference = reference.inner();
+    let delta = i64::from((field as u32).wrapping_sub(reference as u32) as i32);
+    reference
+        .checked_add_signed(delta)
+        .or_else(|| {
+            // The nearest one would be negative
+            (delta < 0)
+                .then(|| reference.checked_add_signed(delta + (1 << 32)))
+                .flatten()
+        })
+        .map(Sequence::new)
+        .ok_or(DecodeError::SequenceOutOfRange(field))
+}
+
+/// Put the sequence field of `sequence` on a subflow of `width`
+pub(crate) fn put_sequence(dst: &mut impl BufMut, sequence: Sequence, width: SeqWidth) {
+    match width {
+        SeqWidth::U32 => dst.put_u32(sequence.inner() as u32),
+        SeqWidth::U64 => dst.put_u64(sequence.inner()),
+    }
+}
+
+impl Default for HeaderContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn put_varint(dst: &mut impl BufMut, mut value: u64) {
+    while value >= 0x80 {
+        dst.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    dst.put_u8(value as u8);
+}
+
+/// The value of the varint at the start of `bytes` and its size, or `None` if `bytes` ends before it does
+pub fn decode_varint(bytes: &[u8]) -> Result<Option<(u64, usize)>, DecodeError> {
+    let mut value = 0;
+    for (i, &byte) in bytes.iter().take(MAX_VARINT_SIZE).enumerate() {
+        if i == MAX_VARINT_SIZE - 1 && 1 < byte {
+            return Err(DecodeError::InvalidVarint);
+        }
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            if byte == 0 && i != 0 {
+                return Err(DecodeError::InvalidVarint);
+            }
+            return Ok(Some((value, i + 1)));
+        }
+    }
+    Ok(None)
+}
+
+/// A frame as it is on the wire
+///
+/// New frame types may be added in minor versions, so matches outside of this crate need a wildcard arm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Frame {
+    DataSegment {
+        start_sequence: Sequence,
+        payload: Bytes,
+        /// Followed by a CRC32 of the payload
+        checksummed: bool,
+        /// With varint headers relative to the previous data segment
+        compact: bool,
+        /// Carried compressed, in which case the headers are never compact and `payload` is the decompressed one
+        compression: Compression,
+    },
+    Ping,
+    Shutdown,
+    Fin(Sequence),
+    Ack(Sequence),
+    Control(Bytes),
+    Probe(u64),
+    Pong(u64),
+    Oob {
+        sequence: u32,
+        payload: Bytes,
+    },
+    Feedback(Vec<SubflowScore>),
+    Abort {
+        sequence: Sequence,
+        reason: Bytes,
+    },
+    /// An extension frame that this version does not know
+    Unknown {
+        /// One of `EXTENSION_TYPE_CODES`
+        type_code: u8,
+        payload: Bytes,
+    },
+}
+
+impl Frame {
+    /// The frame `message` is put on the wire as
+    pub fn new(message: Message, options: EncodeOptions) -> Self {
+        match message {
+            Message::DataSegment(data_segment) => {
+                let compression = match options.compression.compress(data_segment.payload()) {
+                    Some(_) => options.compression,
+                    None => Compression::None,
+                };
+                Self::DataSegment {
+                    start_sequence: data_segment.start_sequence(),
+                    payload: data_segment.payload().clone(),
+                    checksummed: options.checksum,
+                    compact: options.compact && compression == Compression::None,
+                    compression,
+                }
+            }
+            Message::Ping => Self::Ping,
+            Message::Shutdown => Self::Shutdown,
+            Message::Fin(sequence) => Self::Fin(sequence),
+            Message::Ack(sequence) => Self::Ack(sequence),
+            Message::Control(payload) => Self::Control(payload),
+            Message::Probe(timestamp) => Self::Probe(timestamp),
+            Message::Pong(timestamp) => Self::Pong(timestamp),
+            Message::Oob { sequence, payload } => Self::Oob { sequence, payload },
+            Message::Feedback(scores) => Self::Feedback(scores),
+            Message::Abort { sequence, reason } => Self::Abort { sequence, reason },
+            Message::Unknown { type_code, payload } => Self::Unknown { type_code, payload },
+        }
+    }
+
+    pub fn type_code(&self) -> u8 {
+        match self {
+            Self::DataSegment {
+                checksummed,
+                compact,
+                compression,
+                ..
+            } => data_segment_type_code(*checksummed, *compact, *compression),
+            Self::Ping => PING_TYPE_CODE,
+            Self::Shutdown => SHUTDOWN_TYPE_CODE,
+            Self::Fin(_) => FIN_TYPE_CODE,
+            Self::Ack(_) => ACK_TYPE_CODE,
+            Self::Control(_) => CONTROL_TYPE_CODE,
+            Self::Probe(_) => PROBE_TYPE_CODE,
+            Self::Pong(_) => PONG_TYPE_CODE,
+            Self::Oob { .. } => OOB_TYPE_CODE,
+            Self::Feedback(_) => FEEDBACK_TYPE_CODE,
+            Self::Abort { .. } => ABORT_TYPE_CODE,
+            Self::Unknown { type_code, .. } => *type_code,
+        }
+    }
+
+    /// `Self::encode_in` with a new `HeaderContext`
+    pub fn encode(&self, dst: &mut impl BufMut) -> io::Result<()> {
+        self.encode_in(dst, &mut HeaderContext::new())
+    }
+
+    /// Fails with `io::ErrorKind::InvalidInput` and writes nothing if a length does not fit in its field or an unknown frame has none of `EXTENSION_TYPE_CODES`
+    pub fn encode_in(&self, dst: &mut impl BufMut, context: &mut HeaderContext) -> io::Result<()> {
+        let too_large = |what| io::Error::new(io::ErrorKind::InvalidInput, what);
+        match self {
+            Self::DataSegment {
+                start_sequence,
+                payload,
+                checksummed,
+                compact,
+                compression,
+            } => {
+                let length =
+                    u32::try_from(payload.len()).map_err(|_| too_large("payload too large"))?;
+                let compressed = match compression.encode(payload) {
+                    Some(compressed) => {
+                        let compressed_length = u32::try_from(compressed.len())
+                            .map_err(|_| too_large("compressed payload too large"))?;
+                        Some((compressed, compressed_length))
+                    }
+                    None => None,
+                };
+                dst.put_u8(self.type_code());
+                if let Some((compressed, compressed_length)) = &compressed {
+                    put_sequence(dst, *start_sequence, context.width);
+                    dst.put_u32(length);
+                    dst.put_u32(*compressed_length);
+                    dst.put_slice(compressed);
+                } else if *compact {
+                    put_varint(dst, context.delta_to(*start_sequence));
+                    put_varint(dst, length.into());
+                    dst.put_slice(payload);
+                } else {
+                    put_sequence(dst, *start_sequence, context.width);
+                    dst.put_u32(length);
+                    dst.put_slice(payload);
+                }
+                if *checksummed {
+                    dst.put_u32(crc32fast::hash(payload));
+                }
+                let end = start_sequence.inner().wrapping_add(payload.len() as u64);
+                context.record(Sequence::new(end));
+            }
+            Self::Ping | Self::Shutdown => dst.put_u8(self.type_code()),
+            Self::Fin(sequence) | Self::Ack(sequence) => {
+                dst.put_u8(self.type_code());
+                put_sequence(dst, *sequence, context.width);
+            }
+            Self::Probe(timestamp) | Self::Pong(timestamp) => {
+                dst.put_u8(self.type_code());
+                dst.put_u64(*timestamp);
+            }
+            Self::Control(payload) => {
+                let length = u16::try_from(payload.len())
+                    .map_err(|_| too_large("control payload too large"))?;
+                dst.put_u8(self.type_code());
+                dst.put_u16(length);
+                dst.put_slice(payload);
+            }
+            Self::Oob { sequence, payload } => {
+                let length = u16::try_from(payload.len())
+                    .map_err(|_| too_large("out-of-band payload too large"))?;
+                dst.put_u8(self.type_code());
+                dst.put_u32(*sequence);
+                dst.put_u16(length);
+                dst.put_slice(payload);
+            }
+            Self::Feedback(scores) => {
+                let count = u16::try_from(scores.len())
+                    .map_err(|_| too_large("too many feedback scores"))?;
+                dst.put_u8(self.type_code());
+                dst.put_u16(count);
+                for score in scores {
+                    score.put(dst);
+                }
+            }
+            Self::Abort { sequence, reason } => {
+                let length =
+                    u16::try_from(reason.len()).map_err(|_| too_large("abort reason too large"))?;
+                dst.put_u8(self.type_code());
+                put_sequence(dst, *sequence, context.width);
+                dst.put_u16(length);
+                dst.put_slice(reason);
+            }
+            Self::Unknown { type_code, payload } => {
+                if !EXTENSION_TYPE_CODES.contains(type_code) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "not an extension type code",
+                    ));
+                }
+                let length = u16::try_from(payload.len())
+                    .map_err(|_| too_large("extension payload too large"))?;
+                dst.put_u8(*type_code);
+                dst.put_u16(length);
+                dst.put_slice(payload);
+            }
+        }
+        Ok(())
+    }
+
+    /// `Self::decode_in` with a new `HeaderContext` and `DEFAULT_MAX_PAYLOAD_SIZE`
+    pub fn decode(src: &mut impl Buf) -> Result<Option<Self>, DecodeError> {
+        Self::decode_in(src, &mut HeaderContext::new(), DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+
+    /// Take the next frame off `src` or return `None` without consuming anything if `src` does not hold all of it yet
+    ///
+    /// Payloads larger than `max_payload_size` are rejected as soon as their length is read.
+    /// The header of a frame is looked at through `Buf::chunks_vectored`, which sees every byte of the `Buf`s of `bytes`.
+    /// After an error, `src` is left at no particular position and the rest of the subflow cannot be decoded.
+    pub fn decode_in(
+        src: &mut impl Buf,
+        context: &mut HeaderContext,
+        max_payload_size: usize,
+    ) -> Result<Option<Self>, DecodeError> {
+        let mut header = [0; MAX_HEADER_SIZE];
+        let header = peek(src, &mut header);
+        let Some(&type_code) = header.first() else {
+            return Ok(None);
+        };
+        let Some(Header {
+            size: header_size,
+            payload_size,
+            decompressed_size,
+            start_sequence,
+        }) = Header::parse(type_code, header, context)?
+        else {
+            return Ok(None);
+        };
+        for length in [payload_size, decompressed_size] {
+            if max_payload_size < length {
+                return Err(DecodeError::FrameTooLarge {
+                    length,
+                    limit: max_payload_size,
+                });
+            }
+        }
+        let checksummed = is_checksummed(type_code);
+        let trailer_size = if checksummed { 4 } else { 0 };
+        if src.remaining() < header_size + payload_size + trailer_size {
+            return Ok(None);
+        }
+
+        let frame = match type_code {
+            type_code if is_data_segment(type_code) => {
+                let compression = compression_of(type_code);
+                src.advance(header_size);
+                let mut payload = src.copy_to_bytes(payload_size);
+                if compression != Compression::None {
+                    payload = decompress(compression, &payload, decompressed_size).ok_or(
+                        DecodeError::InvalidCompressedPayload {
+                            sequence: start_sequence,
+                        },
+                    )?;
+                }
+                if checksummed && src.get_u32() != crc32fast::hash(&payload) {
+                    return Err(DecodeError::ChecksumMismatch {
+                        sequence: start_sequence,
+                    });
+                }
+                let Some(data_segment) = DataSegment::new(start_sequence, payload.clone()) else {
+                    return Err(DecodeError::InvalidDataSegment {
+                        sequence: start_sequence,
+                    });
+                };
+                context.record(data_segment.end_sequence());
+                Self::DataSegment {
+                    start_sequence,
+                    payload,
+                    checksummed,
+                    compact: matches!(
+                        type_code,
+                        COMPACT_DATA_SEGMENT_TYPE_CODE | COMPACT_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+                    ),
+                    compression,
+                }
+            }
+            PING_TYPE_CODE | SHUTDOWN_TYPE_CODE | FIN_TYPE_CODE | ACK_TYPE_CODE
+            | PROBE_TYPE_CODE | PONG_TYPE_CODE => {
+                src.advance(1);
+                match type_code {
+                    PING_TYPE_CODE => Self::Ping,
+                    SHUTDOWN_TYPE_CODE => Self::Shutdown,
+                    FIN_TYPE_CODE => Self::Fin(context.expand(src.get_uint(context.width.size()))?),
+                    ACK_TYPE_CODE => {
+                        Self::Ack(context.expand_ack(src.get_uint(context.width.size()))?)
+                    }
+                    PROBE_TYPE_CODE => Self::Probe(src.get_u64()),
+                    _ => Self::Pong(src.get_u64()),
+                }
+            }
+            CONTROL_TYPE_CODE => {
+                src.advance(header_size);
+                Self::Control(src.copy_to_bytes(payload_size))
+            }
+            OOB_TYPE_CODE => {
+                src.advance(1);
+                let sequence = src.get_u32();
+                src.advance(header_size - 1 - 4);
+                Self::Oob {
+                    sequence,
+                    payload: src.copy_to_bytes(payload_size),
+                }
+            }
+            FEEDBACK_TYPE_CODE => {
+                src.advance(header_size);
+                let count = payload_size / SUBFLOW_SCORE_SIZE;
+                Self::Feedback((0..count).map(|_| SubflowScore::get(src)).collect())
+            }
+            ABORT_TYPE_CODE => {
+                src.advance(1);
+                let sequence = context.expand(src.get_uint(context.width.size()))?;
+                src.advance(2);
+                Self::Abort {
+                    sequence,
+                    reason: src.copy_to_bytes(payload_size),
+                }
+            }
+            type_code if EXTENSION_TYPE_CODES.contains(&type_code) => {
+                src.advance(header_size);
+                Self::Unknown {
+                    type_code,
+                    payload: src.copy_to_bytes(payload_size),
+                }
+            }
+            _ => unreachable!(),
+        };
+        Ok(Some(frame))
+    }
+}
+
+pub(crate) fn data_segment_type_code(
+    checksummed: bool,
+    compact: bool,
+    compression: Compression,
+) -> u8 {
+    match (checksummed, compact, compression) {
+        (false, false, Compression::None) => DATA_SEGMENT_TYPE_CODE,
+        (true, false, Compression::None) => CHECKSUMMED_DATA_SEGMENT_TYPE_CODE,
+        (false, true, Compression::None) => COMPACT_DATA_SEGMENT_TYPE_CODE,
+        (true, true, Compression::None) => COMPACT_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE,
+        #[cfg(feature = "lz4")]
+        (false, _, Compression::Lz4) => LZ4_DATA_SEGMENT_TYPE_CODE,
+        #[cfg(feature = "lz4")]
+        (true, _, Compression::Lz4) => LZ4_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE,
+    }
+}
+
+/// Whether `type_code` is that of a data segment this build can decode
+pub(crate) fn is_data_segment(type_code: u8) -> bool {
+    let compressed = matches!(
+        type_code,
+        LZ4_DATA_SEGMENT_TYPE_CODE | LZ4_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+    );
+    matches!(
+        type_code,
+        DATA_SEGMENT_TYPE_CODE
+            | CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+            | COMPACT_DATA_SEGMENT_TYPE_CODE
+            | COMPACT_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+    ) || compressed && cfg!(feature = "lz4")
+}
+
+/// Whether a CRC32 follows the payload of a frame of `type_code`
+fn is_checksummed(type_code: u8) -> bool {
+    matches!(
+        type_code,
+        CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+            | COMPACT_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+            | LZ4_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+    )
+}
+
+/// How the payload of a data segment of `type_code` is compressed
+pub(crate) fn compression_of(type_code: u8) -> Compression {
+    match type_code {
+        #[cfg(feature = "lz4")]
+        LZ4_DATA_SEGMENT_TYPE_CODE | LZ4_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE => Compression::Lz4,
+        _ => Compression::None,
+    }
+}
+
+/// The `size` bytes of payload `compressed` came from
+#[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+pub(crate) fn decompress(
+    compression: Compression,
+    compressed: &[u8],
+    size: usize,
+) -> Option<Bytes> {
+    match compression {
+        Compression::None => None,
+        #[cfg(feature = "lz4")]
+        compression => compression.decode(compressed, size),
+    }
+}
+
+/// The fields of a frame before its payload
+struct Header {
+    /// Type code included
+    size: usize,
+    /// Bytes on the wire
+    payload_size: usize,
+    /// Of compressed data segments
+    decompressed_size: usize,
+    /// Of data segments
+    start_sequence: Sequence,
+}
+
+impl Header {
+    /// `None` if `bytes` ends before the header does
+    fn parse(
+        type_code: u8,
+        bytes: &[u8],
+        context: &HeaderContext,
+    ) -> Result<Option<Self>, DecodeError> {
+        let fixed = |size| Self {
+            size,
+            payload_size: 0,
+            decompressed_size: 0,
+            start_sequence: Sequence::new(0),
+        };
+        let sequence_size = context.width.size();
+        let header = match type_code {
+            DATA_SEGMENT_TYPE_CODE | CHECKSUMMED_DATA_SEGMENT_TYPE_CODE => {
+                let Some(mut fields) = bytes.get(1..1 + sequence_size + 4) else {
+                    return Ok(None);
+                };
+                let start_sequence = context.expand(fields.get_uint(sequence_size))?;
+                let length = fields.get_u32();
+                Self {
+                    size: 1 + sequence_size + 4,
+                    payload_size: usize::try_from(length).unwrap_or(usize::MAX),
+                    decompressed_size: 0,
+                    start_sequence,
+                }
+            }
+            #[cfg(feature = "lz4")]
+            LZ4_DATA_SEGMENT_TYPE_CODE | LZ4_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE => {
+                let Some(mut fields) = bytes.get(1..1 + sequence_size + 4 + 4) else {
+                    return Ok(None);
+                };
+                let start_sequence = context.expand(fields.get_uint(sequence_size))?;
+                let length = fields.get_u32();
+                let compressed_length = fields.get_u32();
+                Self {
+                    size: 1 + sequence_size + 4 + 4,
+                    payload_size: usize::try_from(compressed_length).unwrap_or(usize::MAX),
+                    decompressed_size: usize::try_from(length).unwrap_or(usize::MAX),
+                    start_sequence,
+                }
+            }
+            COMPACT_DATA_SEGMENT_TYPE_CODE | COMPACT_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE => {
+                let Some((delta, delta_size)) = decode_varint(&bytes[1..])? else {
+                    return Ok(None);
+                };
+                let Some((length, length_size)) = decode_varint(&bytes[1 + delta_size..])? else {
+                    return Ok(None);
+                };
+                let payload_size = usize::try_from(length)
+                    .ok()
+                    .filter(|&size| size <= MAX_PAYLOAD_SIZE)
+                    .ok_or(DecodeError::InvalidVarint)?;
+                Self {
+                    size: 1 + delta_size + length_size,
+                    payload_size,
+                    decompressed_size: 0,
+                    start_sequence: context.start_sequence(delta),
+                }
+            }
+            PING_TYPE_CODE | SHUTDOWN_TYPE_CODE => fixed(1),
+            FIN_TYPE_CODE | ACK_TYPE_CODE => fixed(1 + sequence_size),
+            PROBE_TYPE_CODE | PONG_TYPE_CODE => fixed(1 + 8),
+            type_code
+                if type_code == CONTROL_TYPE_CODE || EXTENSION_TYPE_CODES.contains(&type_code) =>
+            {
+                let Some(length) = bytes.get(1..3) else {
+                    return Ok(None);
+                };
+                Self {
+                    payload_size: usize::from(u16::from_be_bytes([length[0], length[1]])),
+                    ..fixed(1 + 2)
+                }
+            }
+            OOB_TYPE_CODE => {
+                let Some(length) = bytes.get(1 + 4..1 + 4 + 2) else {
+                    return Ok(None);
+                };
+                Self {
+                    payload_size: usize::from(u16::from_be_bytes([length[0], length[1]])),
+                    ..fixed(1 + 4 + 2)
+                }
+            }
+            ABORT_TYPE_CODE => {
+                let Some(length) = bytes.get(1 + sequence_size..1 + sequence_size + 2) else {
+                    return Ok(None);
+                };
+                Self {
+                    payload_size: usize::from(u16::from_be_bytes([length[0], length[1]])),
+                    ..fixed(1 + sequence_size + 2)
+                }
+            }
+            FEEDBACK_TYPE_CODE => {
+                let Some(count) = bytes.get(1..3) else {
+                    return Ok(None);
+                };
+                let count = usize::from(u16::from_be_bytes([count[0], count[1]]));
+                Self {
+                    payload_size: count * SUBFLOW_SCORE_SIZE,
+                    ..fixed(1 + 2)
+                }
+            }
+            _ => return Err(DecodeError::UnknownType(type_code)),
+        };
+        if bytes.len() < header.size {
+            return Ok(None);
+        }
+        Ok(Some(header))
+    }
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::DataSegment {
+                start_sequence,
+                payload,
+                ..
+            } => {
+                // `Frame::decode` has validated decoded data segments already
+                let data_segment = DataSegment::new(start_sequence, payload)
+                    .expect("data segment out of the sequence space");
+                Self::DataSegment(data_segment)
+            }
+            Frame::Ping => Self::Ping,
+            Frame::Shutdown => Self::Shutdown,
+            Frame::Fin(sequence) => Self::Fin(sequence),
+            Frame::Ack(sequence) => Self::Ack(sequence),
+            Frame::Control(payload) => Self::Control(payload),
+            Frame::Probe(timestamp) => Self::Probe(timestamp),
+            Frame::Pong(timestamp) => Self::Pong(timestamp),
+            Frame::Oob { sequence, payload } => Self::Oob { sequence, payload },
+            Frame::Feedback(scores) => Self::Feedback(scores),
+            Frame::Abort { sequence, reason } => Self::Abort { sequence, reason },
+            Frame::Unknown { type_code, payload } => Self::Unknown { type_code, payload },
+        }
+    }
+}
+
+/// The passthrough bytes of `payload`, which is itself unless it holds `PASSTHROUGH_ESCAPE`
+#[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+pub(crate) fn escape_passthrough(payload: &Bytes) -> Bytes {
+    let escapes = payload
+        .iter()
+        .filter(|&&byte| byte == PASSTHROUGH_ESCAPE)
+        .count();
+    if escapes == 0 {
+        return payload.clone();
+    }
+    let mut escaped = Vec::with_capacity(payload.len() + escapes);
+    for run in payload.split_inclusive(|&byte| byte == PASSTHROUGH_ESCAPE) {
+        escaped.extend_from_slice(run);
+        if run.last() == Some(&PASSTHROUGH_ESCAPE) {
+            escaped.push(PASSTHROUGH_ESCAPE);
+        }
+    }
+    escaped.into()
+}
+
+/// Undoes `escape_passthrough` on the passthrough bytes of a subflow as they come
+#[derive(Debug, Default)]
+pub(crate) struct Unescape {
+    /// Whether the bytes so far end with an escape
+    escaped: bool,
+}
+
+impl Unescape {
+    /// Unescape the passthrough bytes of `buf` in place
+    ///
+    /// Returns how many payload bytes they make at the start of `buf`, and where the frames begin in `buf` if the bytes switch to them.
+    pub(crate) fn unescape(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<(usize, Option<usize>), DecodeError> {
+        let mut len = 0;
+        let mut i = 0;
+        while i < buf.len() {
+            if std::mem::take(&mut self.escaped) {
+                match buf[i] {
+                    PASSTHROUGH_ESCAPE => {
+                        buf[len] = PASSTHROUGH_ESCAPE;
+                        len += 1;
+                    }
+                    PASSTHROUGH_SWITCH => return Ok((len, Some(i + 1))),
+                    byte => return Err(DecodeError::InvalidEscape(byte)),
+                }
+                i += 1;
+                continue;
+            }
+            let run = buf[i..]
+                .iter()
+                .position(|&byte| byte == PASSTHROUGH_ESCAPE)
+                .unwrap_or(buf.len() - i);
+            buf.copy_within(i..i + run, len);
+            len += run;
+            i += run;
+            if i < buf.len() {
+                self.escaped = true;
+                i += 1;
+            }
+        }
+        Ok((len, None))
+    }
+}
+
+/// What `inspect` found in the bytes of a subflow
+#[derive(Debug)]
+pub struct Inspection {
+    /// If the bytes start with one
+    pub hello: Option<Hello>,
+    /// Where the passthrough bytes after a hello with `crate::message::CAPABILITY_PASSTHROUGH` lie, with their switch to frames if any
+    pub passthrough: Option<Range<usize>>,
+    pub frames: Vec<FrameInfo>,
+    /// Why the inspection stopped before the end of the bytes, if it did
+    pub stop: Option<InspectStop>,
+}
+
+/// A frame found by `inspect`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// Of the type code in the inspected bytes
+    pub offset: usize,
+    pub type_code: u8,
+    /// The start sequence of a data segment, the final sequence of a fin or the sequence of an ack or an abort
+    pub sequence: Option<Sequence>,
+    /// Bytes of the whole frame
+    pub len: usize,
+    /// Bytes of the payload on the wire
+    pub payload_len: usize,
+    /// Bytes of the payload of a compressed data segment once decompressed
+    pub decompressed_len: Option<usize>,
+    /// Whether the CRC32 of a checksummed data segment matches its payload
+    pub checksum_valid: Option<bool>,
+}
+
+impl FrameInfo {
+    /// The name of the frame type in the table of this module
+    pub fn name(&self) -> &'static str {
+        match self.type_code {
+            DATA_SEGMENT_TYPE_CODE => "data segment",
+            PING_TYPE_CODE => "ping",
+            SHUTDOWN_TYPE_CODE => "shutdown",
+            CHECKSUMMED_DATA_SEGMENT_TYPE_CODE => "checksummed data segment",
+            FIN_TYPE_CODE => "fin",
+            ACK_TYPE_CODE => "ack",
+            CONTROL_TYPE_CODE => "control",
+            COMPACT_DATA_SEGMENT_TYPE_CODE => "compact data segment",
+            COMPACT_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE => "compact checksummed data segment",
+            PROBE_TYPE_CODE => "probe",
+            PONG_TYPE_CODE => "pong",
+            LZ4_DATA_SEGMENT_TYPE_CODE => "LZ4 data segment",
+            LZ4_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE => "LZ4 checksummed data segment",
+            OOB_TYPE_CODE => "out-of-band message",
+            FEEDBACK_TYPE_CODE => "feedback",
+            ABORT_TYPE_CODE => "abort",
+            _ => "extension frame",
+        }
+    }
+}
+
+/// Why `inspect` stopped before the end of the bytes
+#[derive(Debug)]
+pub enum InspectStop {
+    /// The bytes end within the hello or the frame at `offset`
+    Truncated { offset: usize },
+    /// The hello or the frame at `offset` does not decode, and nothing after it can
+    Invalid { offset: usize, error: io::Error },
+}
+
+/// List the frames in `bytes`, one direction of a subflow starting with its hello or at a frame boundary
+///
+/// A hello is recognized by its magic and sets the sequence width of the frames that follow, which is `SeqWidth::U64` without one.
+/// Unlike `Frame::decode`, no payload is too large and a checksum mismatch does not stop the inspection.
+/// The sequences of compact data segments are relative to the data segments before them in `bytes`, so start it at the hello to get them right.
+pub fn inspect(bytes: &[u8]) -> Inspection {
+    let mut inspection = Inspection {
+        hello: None,
+        passthrough: None,
+        frames: vec![],
+        stop: None,
+    };
+    let mut src = bytes;
+    let mut context = HeaderContext::new();
+    if !src.is_empty() && (src.starts_with(&MAGIC) || MAGIC.starts_with(src)) {
+        match Hello::decode_buf(&mut src) {
+            Ok(Some(hello)) => {
+                context = HeaderContext::with_sequence_width(hello.sequence_width());
+                inspection.hello = Some(hello);
+            }
+            Ok(None) => inspection.stop = Some(InspectStop::Truncated { offset: 0 }),
+            Err(error) => inspection.stop = Some(InspectStop::Invalid { offset: 0, error }),
+        }
+    }
+    if inspection
+        .hello
+        .is_some_and(|hello| hello.passthrough().is_some())
+    {
+        let offset = bytes.len() - src.len();
+        match Unescape::default().unescape(&mut src.to_vec()) {
+            Ok((_, switch)) => {
+                let len = switch.unwrap_or(src.len());
+                inspection.passthrough = Some(offset..offset + len);
+                src.advance(len);
+            }
+            Err(error) => {
+                let error = error.into();
+                inspection.stop = Some(InspectStop::Invalid { offset, error });
+            }
+        }
+    }
+    while inspection.stop.is_none() && !src.is_empty() {
+        let offset = bytes.len() - src.len();
+        match inspect_frame(&mut src, &mut context) {
+            Ok(Some(frame)) => inspection.frames.push(FrameInfo { offset, ..frame }),
+            Ok(None) => inspection.stop = Some(InspectStop::Truncated { offset }),
+            Err(error) => {
+                let error = error.into();
+                inspection.stop = Some(InspectStop::Invalid { offset, error });
+            }
+        }
+    }
+    inspection
+}
+
+/// Take the next frame off `src` like `Frame::decode_in` does, but without checking its checksum
+fn inspect_frame(
+    src: &mut &[u8],
+    context: &mut HeaderContext,
+) -> Result<Option<FrameInfo>, DecodeError> {
+    let type_code = src[0];
+    let Some(header) = Header::parse(type_code, src, context)? else {
+        return Ok(None);
+    };
+    let mut frame = FrameInfo {
+        offset: 0,
+        type_code,
+        sequence: None,
+        len: header.size + header.payload_size,
+        payload_len: header.payload_size,
+        decompressed_len: None,
+        checksum_valid: None,
+    };
+    if !is_data_segment(type_code) {
+        let mut rest = *src;
+        let Some(decoded) = Frame::decode_in(&mut rest, context, usize::MAX)? else {
+            return Ok(None);
+        };
+        if let Frame::Fin(sequence) | Frame::Ack(sequence) | Frame::Abort { sequence, .. } = decoded
+        {
+            frame.sequence = Some(sequence);
+        }
+        *src = rest;
+        return Ok(Some(frame));
+    }
+
+    let checksummed = is_checksummed(type_code);
+    if checksummed {
+        frame.len += 4;
+    }
+    let Some(bytes) = src.get(..frame.len) else {
+        return Ok(None);
+    };
+    let sequence = header.start_sequence;
+    let on_the_wire = &bytes[header.size..header.size + header.payload_size];
+    let compression = compression_of(type_code);
+    let decompressed = decompress(compression, on_the_wire, header.decompressed_size);
+    if compression != Compression::None {
+        if decompressed.is_none() {
+            return Err(DecodeError::InvalidCompressedPayload { sequence });
+        }
+        frame.decompressed_len = Some(header.decompressed_size);
+    }
+    let payload = decompressed.as_deref().unwrap_or(on_the_wire);
+    if checksummed {
+        let checksum = u32::from_be_bytes(bytes[frame.len - 4..].try_into().unwrap());
+        frame.checksum_valid = Some(checksum == crc32fast::hash(payload));
+    }
+    let end = sequence.checked_add(payload.len() as u64);
+    let Some(end) = end.filter(|_| !payload.is_empty()) else {
+        return Err(DecodeError::InvalidDataSegment { sequence });
+    };
+    context.record(end);
+    frame.sequence = Some(sequence);
+    src.advance(frame.len);
+    Ok(Some(frame))
+}
+
+impl fmt::Display for Inspection {
+    /// One line per frame, with its offset and type code, and one for the hello and the stop, if any
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(hello) = &self.hello {
+            write!(
+                f,
+                "hello version {} capabilities {:#x}",
+                hello.version(),
+                hello.capabilities()
+            )?;
+            if let Some(size) = hello.max_frame_size() {
+                write!(f, " max_frame_size {size}")?;
+            }
+            if let (Some(session), Some(id)) = (hello.session(), hello.subflow_id()) {
+                write!(f, " session {} subflow {id}", session.inner())?;
+            }
+            if let Some(interval) = hello.feedback_interval() {
+                write!(f, " feedback_interval {interval:?}")?;
+            }
+            writeln!(f)?;
+        }
+        if let Some(passthrough) = &self.passthrough {
+            let len = passthrough.len();
+            writeln!(f, "{:>8} passthrough len {len}", passthrough.start)?;
+        }
+        for frame in &self.frames {
+            write!(
+                f,
+                "{:>8} {} ({})",
+                frame.offset,
+                frame.name(),
+                frame.type_code
+            )?;
+            if let Some(sequence) = frame.sequence {
+                write!(f, " sequence {sequence}")?;
+            }
+            write!(f, " len {}", frame.len)?;
+            if frame.payload_len != 0 {
+                write!(f, " payload {}", frame.payload_len)?;
+            }
+            if let Some(len) = frame.decompressed_len {
+                write!(f, " decompressed {len}")?;
+            }
+            match frame.checksum_valid {
+                Some(true) => write!(f, " checksum ok")?,
+                Some(false) => write!(f, " checksum MISMATCH")?,
+                None => (),
+            }
+            writeln!(f)?;
+        }
+        match &self.stop {
+            Some(InspectStop::Truncated { offset }) => writeln!(f, "{offset:>8} truncated"),
+            Some(InspectStop::Invalid { offset, error }) => {
+                writeln!(f, "{offset:>8} invalid: {error}")
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Copy the first bytes of `src` into `buf` without consuming them
+pub(crate) fn peek<'a>(src: &impl Buf, buf: &'a mut [u8]) -> &'a [u8] {
+    let mut chunks = [IoSlice::new(&[]); MAX_HEADER_SIZE];
+    let n = src.chunks_vectored(&mut chunks);
+    let mut filled = 0;
+    for chunk in &chunks[..n] {
+        let n = chunk.len().min(buf.len() - filled);
+        buf[filled..filled + n].copy_from_slice(&chunk[..n]);
+        filled += n;
+    }
+    &buf[..filled]
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("Checksum mismatch in the data segment at {sequence:?}")]
+    ChecksumMismatch { sequence: Sequence },
+    #[error("Unknown type code: {0}")]
+    UnknownType(u8),
+    #[error("Payload of {length} bytes exceeds the limit of {limit}")]
+    FrameTooLarge { length: usize, limit: usize },
+    #[error("Data segment at {sequence:?} is empty or runs past the sequence space")]
+    InvalidDataSegment { sequence: Sequence },
+    #[error("Malformed or out of range varint")]
+    InvalidVarint,
+    #[error("Truncated sequence {0} stands for none in the sequence space")]
+    SequenceOutOfRange(u64),
+    #[error(
+        "Compressed payload of the data segment at {sequence:?} does not decompress to its length"
+    )]
+    InvalidCompressedPayload { sequence: Sequence },
+    #[error("Passthrough escape followed by {0:#04x}")]
+    InvalidEscape(u8),
+}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_frame(rng: &mut StdRng) -> Frame {
+        let payload = |rng: &mut StdRng, max: usize| -> Bytes {
+            let len = rng.gen_range(1..=max);
+            (0..len).map(|_| rng.gen()).collect::<Vec<u8>>().into()
+        };
+        match rng.gen_range(0..12) {
+            #[cfg(feature = "lz4")]
+            0 if rng.gen() => {
+                // Repetitive enough to compress most of the time
+                let payload = payload(rng, 4).repeat(rng.gen_range(256..512));
+                let data_segment =
+                    DataSegment::new(Sequence::new(rng.gen_range(0..1 << 40)), payload.into());
+                let options = EncodeOptions {
+                    checksum: rng.gen(),
+                    compression: Compression::Lz4,
+                    ..Default::default()
+                };
+                Frame::new(Message::DataSegment(data_segment.unwrap()), options)
+            }
+            0 => Frame::DataSegment {
+                start_sequence: Sequence::new(rng.gen_range(0..1 << 40)),
+                payload: payload(rng, 512),
+                checksummed: rng.gen(),
+                compact: rng.gen(),
+                compression: Compression::None,
+            },
+            1 => Frame::Ping,
+            2 => Frame::Shutdown,
+            3 => Frame::Fin(Sequence::new(rng.gen())),
+            4 => Frame::Ack(Sequence::new(rng.gen())),
+            5 => Frame::Probe(rng.gen()),
+            6 => Frame::Pong(rng.gen()),
+            7 => Frame::Oob {
+                sequence: rng.gen(),
+                payload: payload(rng, 64),
+            },
+            8 => Frame::Unknown {
+                type_code: rng.gen_range(EXTENSION_TYPE_CODES),
+                payload: payload(rng, 64),
+            },
+            9 => {
+                let score = |rng: &mut StdRng| {
+                    let jitter = Duration::from_micros(rng.gen::<u32>().into());
+                    SubflowScore::new(rng.gen(), rng.gen(), rng.gen(), jitter)
+                };
+                Frame::Feedback((0..rng.gen_range(0..4)).map(|_| score(rng)).collect())
+            }
+            10 => Frame::Abort {
+                sequence: Sequence::new(rng.gen()),
+                reason: payload(rng, 64),
+            },
+            _ => Frame::Control(payload(rng, 64)),
+        }
+    }
+
+    fn encode_all(frames: &[Frame]) -> Vec<u8> {
+        let mut context = HeaderContext::new();
+        let mut wire = vec![];
+        for frame in frames {
+            frame.encode_in(&mut wire, &mut context).unwrap();
+        }
+        wire
+    }
+
+    /// Decode `wire` as the frames of one subflow fed `step` bytes at a time
+    fn decode_in_steps(wire: &[u8], step: usize) -> Result<Vec<Frame>, DecodeError> {
+        let mut context = HeaderContext::new();
+        let mut src = BytesMut::new();
+        let mut frames = vec![];
+        for piece in wire.chunks(step) {
+            src.extend_from_slice(piece);
+            while let Some(frame) =
+                Frame::decode_in(&mut src, &mut context, DEFAULT_MAX_PAYLOAD_SIZE)?
+            {
+                frames.push(frame);
+            }
+        }
+        Ok(frames)
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn same_bytes_as_message_encode() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let frames: Vec<Frame> = (0..256).map(|_| random_frame(&mut rng)).collect();
+        let wire = encode_all(&frames);
+
+        let mut context = HeaderContext::new();
+        let mut expected = vec![];
+        for frame in &frames {
+            let options = match *frame {
+                Frame::DataSegment {
+                    checksummed,
+                    compact,
+                    compression,
+                    ..
+                } => EncodeOptions {
+                    checksum: checksummed,
+                    compact,
+                    compression,
+                },
+                _ => EncodeOptions::default(),
+            };
+            Message::from(frame.clone())
+                .encode_in(&mut expected, options, &mut context)
+                .await
+                .unwrap();
+        }
+        assert_eq!(wire, expected);
+
+        let mut context = HeaderContext::new();
+        let mut reader = io::Cursor::new(&wire);
+        for frame in &frames {
+            let message = Message::decode_next_in(&mut reader, &mut context)
+                .await
+                .unwrap()
+                .unwrap();
+            let Message::DataSegment(data_segment) = message else {
+                assert_eq!(Frame::new(message, EncodeOptions::default()), *frame);
+                continue;
+            };
+            let Frame::DataSegment {
+                start_sequence,
+                payload,
+                ..
+            } = frame
+            else {
+                panic!("expected {frame:?}");
+            };
+            assert_eq!(data_segment.start_sequence(), *start_sequence);
+            assert_eq!(data_segment.payload(), payload);
+        }
+    }
+
+    /// One frame of every type and encoding with its exact bytes, each encoded with a new `HeaderContext`
+    fn golden_vectors() -> Vec<(Frame, Vec<u8>)> {
+        let data_segment = |start_sequence, checksummed, compact| Frame::DataSegment {
+            start_sequence: Sequence::new(start_sequence),
+            payload: Bytes::from_static(b"hi"),
+            checksummed,
+            compact,
+            compression: Compression::None,
+        };
+        let crc = [0xd8, 0x93, 0x2a, 0xac];
+        vec![
+            (
+                data_segment(0x0102_0304_0506_0708, false, false),
+                [&[0, 1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 2][..], b"hi"].concat(),
+            ),
+            (
+                data_segment(5, true, false),
+                [&[3, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 2][..], b"hi", &crc].concat(),
+            ),
+            (
+                data_segment(300, false, true),
+                [&[7, 0xac, 0x02, 2][..], b"hi"].concat(),
+            ),
+            (
+                data_segment(0, true, true),
+                [&[8, 0, 2][..], b"hi", &crc].concat(),
+            ),
+            (Frame::Ping, vec![1]),
+            (Frame::Shutdown, vec![2]),
+            (
+                Frame::Fin(Sequence::new(0x0102_0304_0506_0708)),
+                vec![4, 1, 2, 3, 4, 5, 6, 7, 8],
+            ),
+            (
+                Frame::Ack(Sequence::new(9)),
+                vec![5, 0, 0, 0, 0, 0, 0, 0, 9],
+            ),
+            (
+                Frame::Control(Bytes::from_static(b"ok")),
+                [&[6, 0, 2][..], b"ok"].concat(),
+            ),
+            (Frame::Probe(1), vec![9, 0, 0, 0, 0, 0, 0, 0, 1]),
+            (Frame::Pong(u64::MAX), [&[10][..], &[0xff; 8]].concat()),
+            (
+                Frame::Oob {
+                    sequence: 0x0102_0304,
+                    payload: Bytes::from_static(b"ok"),
+                },
+                [&[13, 1, 2, 3, 4, 0, 2][..], b"ok"].concat(),
+            ),
+            (
+                Frame::Feedback(vec![SubflowScore::new(1, 2, 3, Duration::from_micros(4))]),
+                [
+                    &[14, 0, 1][..],
+                    &[0, 0, 0, 1],
+                    &[0, 0, 0, 0, 0, 0, 0, 2],
+                    &[0, 0, 0, 0, 0, 0, 0, 3],
+                    &[0, 0, 0, 4],
+                ]
+                .concat(),
+            ),
+            (
+                Frame::Abort {
+                    sequence: Sequence::new(0x0102_0304_0506_0708),
+                    reason: Bytes::from_static(b"ok"),
+                },
+                [&[15, 1, 2, 3, 4, 5, 6, 7, 8, 0, 2][..], b"ok"].concat(),
+            ),
+            #[cfg(feature = "lz4")]
+            (
+                Frame::DataSegment {
+                    start_sequence: Sequence::new(1),
+                    payload: Bytes::from_static(b"hi"),
+                    checksummed: false,
+                    compact: false,
+                    compression: Compression::Lz4,
+                },
+                // A single LZ4 sequence of two literals
+                [
+                    &[11, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0x20][..],
+                    b"hi",
+                ]
+                .concat(),
+            ),
+            #[cfg(feature = "lz4")]
+            (
+                Frame::DataSegment {
+                    start_sequence: Sequence::new(1),
+                    payload: Bytes::from_static(b"hi"),
+                    checksummed: true,
+                    compact: false,
+                    compression: Compression::Lz4,
+                },
+                [
+                    &[12, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0x20][..],
+                    b"hi",
+                    &crc,
+                ]
+                .concat(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn golden_round_trip() {
+        let vectors = golden_vectors();
+        for (frame, bytes) in &vectors {
+            let mut wire = vec![];
+            frame.encode(&mut wire).unwrap();
+            assert_eq!(wire, *bytes, "{frame:?}");
+            let mut src = &bytes[..];
+            assert_eq!(Frame::decode(&mut src).unwrap().as_ref(), Some(frame));
+            assert!(src.is_empty());
+        }
+
+        // Every type code is pinned, which a new frame type has to extend
+        let mut type_codes: Vec<u8> = vectors.iter().map(|(frame, _)| frame.type_code()).collect();
+        type_codes.sort_unstable();
+        // The LZ4 ones only with the feature
+        let expected: Vec<u8> = (0..=ABORT_TYPE_CODE)
+            .filter(|&type_code| {
+                let lz4 = matches!(
+                    type_code,
+                    LZ4_DATA_SEGMENT_TYPE_CODE | LZ4_CHECKSUMMED_DATA_SEGMENT_TYPE_CODE
+                );
+                !lz4 || is_data_segment(type_code)
+            })
+            .collect();
+        assert_eq!(type_codes, expected);
+        for (frame, _) in &vectors {
+            match frame {
+                Frame::DataSegment { .. }
+                | Frame::Ping
+                | Frame::Shutdown
+                | Frame::Fin(_)
+                | Frame::Ack(_)
+                | Frame::Control(_)
+                | Frame::Probe(_)
+                | Frame::Pong(_)
+                | Frame::Oob { .. }
+                | Frame::Feedback(_)
+                | Frame::Abort { .. }
+                | Frame::Unknown { .. } => (),
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_round_trip() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let frames: Vec<Frame> = (0..256).map(|_| random_frame(&mut rng)).collect();
+        let wire = encode_all(&frames);
+        for step in [1, 2, 7, 64, wire.len()] {
+            assert_eq!(decode_in_steps(&wire, step).unwrap(), frames);
+        }
+
+        // Across the chunks of a chained buffer
+        let (head, tail) = wire.split_at(wire.len() / 2 + 5);
+        let mut src = head.chain(tail);
+        let mut context = HeaderContext::new();
+        let mut decoded = vec![];
+        while let Some(frame) = Frame::decode_in(&mut src, &mut context, usize::MAX).unwrap() {
+            decoded.push(frame);
+        }
+        assert_eq!(decoded, frames);
+        assert!(!src.has_remaining());
+    }
+
+    #[test]
+    fn truncated_frames_consume_nothing() {
+        for compact in [false, true] {
+            let frame = Frame::DataSegment {
+                start_sequence: Sequence::new(1 << 40),
+                payload: Bytes::from_static(b"hello"),
+                checksummed: true,
+                compact,
+                compression: Compression::None,
+            };
+            let mut wire = vec![];
+            frame.encode(&mut wire).unwrap();
+            for len in 0..wire.len() {
+                let mut src = &wire[..len];
+                assert!(Frame::decode(&mut src).unwrap().is_none());
+                assert_eq!(src.len(), len);
+            }
+        }
+    }
+
+    #[test]
+    fn reject_invalid_frames() {
+        let mut src = &[127_u8][..];
+        let err = Frame::decode(&mut src).unwrap_err();
+        assert!(matches!(err, DecodeError::UnknownType(127)));
+
+        // Compressed data segments are unknown to a build without their algorithm
+        let mut src = &[LZ4_DATA_SEGMENT_TYPE_CODE][..];
+        let res = Frame::decode(&mut src);
+        if cfg!(feature = "lz4") {
+            assert!(res.unwrap().is_none());
+        } else {
+            assert!(matches!(res, Err(DecodeError::UnknownType(11))));
+        }
+
+        // Rejected before the payload arrives
+        let mut header = vec![DATA_SEGMENT_TYPE_CODE];
+        header.put_u64(0);
+        header.put_u32(u32::MAX);
+        let err = Frame::decode(&mut &header[..]).unwrap_err();
+        assert!(matches!(
+            err,
+            DecodeError::FrameTooLarge {
+                length,
+                limit: DEFAULT_MAX_PAYLOAD_SIZE,
+            } if length == u32::MAX as usize
+        ));
+        let mut header = vec![CONTROL_TYPE_CODE];
+        header.put_u16(9);
+        let err = Frame::decode_in(&mut &header[..], &mut HeaderContext::new(), 8).unwrap_err();
+        assert!(matches!(err, DecodeError::FrameTooLarge { length: 9, .. }));
+        let mut header = vec![COMPACT_DATA_SEGMENT_TYPE_CODE, 0];
+        put_varint(&mut header, u32::MAX.into());
+        let err = Frame::decode(&mut &header[..]).unwrap_err();
+        assert!(matches!(err, DecodeError::FrameTooLarge { .. }));
+
+        let mut empty = vec![DATA_SEGMENT_TYPE_CODE];
+        empty.put_u64(0);
+        empty.put_u32(0);
+        let err = Frame::decode(&mut &empty[..]).unwrap_err();
+        assert!(matches!(err, DecodeError::InvalidDataSegment { .. }));
+
+        let mut past_the_end = vec![DATA_SEGMENT_TYPE_CODE];
+        past_the_end.put_u64(u64::MAX);
+        past_the_end.put_u32(1);
+        past_the_end.put_u8(0);
+        let err = Frame::decode(&mut &past_the_end[..]).unwrap_err();
+        assert!(matches!(err, DecodeError::InvalidDataSegment { .. }));
+
+        let frame = Frame::DataSegment {
+            start_sequence: Sequence::new(3),
+            payload: Bytes::from_static(b"hello"),
+            checksummed: true,
+            compact: false,
+            compression: Compression::None,
+        };
+        let mut wire = vec![];
+        frame.encode(&mut wire).unwrap();
+        wire[1 + 8 + 4] ^= 1;
+        let err = Frame::decode(&mut &wire[..]).unwrap_err();
+        assert!(matches!(
+            err,
+            DecodeError::ChecksumMismatch { sequence } if sequence == Sequence::new(3)
+        ));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn reject_invalid_compressed_payloads() {
+        let payload = Bytes::from(b"abcd".repeat(128));
+        let frame = Frame::DataSegment {
+            start_sequence: Sequence::new(3),
+            payload: payload.clone(),
+            checksummed: false,
+            compact: false,
+            compression: Compression::Lz4,
+        };
+        let mut wire = vec![];
+        frame.encode(&mut wire).unwrap();
+        assert!(wire.len() < payload.len() / 4);
+        assert_eq!(Frame::decode(&mut &wire[..]).unwrap(), Some(frame));
+
+        // Claiming one byte more than it decompresses to
+        let mut longer = wire.clone();
+        longer[1 + 8 + 3] += 1;
+        let err = Frame::decode(&mut &longer[..]).unwrap_err();
+        assert!(matches!(
+            err,
+            DecodeError::InvalidCompressedPayload { sequence } if sequence == Sequence::new(3)
+        ));
+
+        // The decompressed size counts against the limit
+        let err = Frame::decode_in(&mut &wire[..], &mut HeaderContext::new(), 256).unwrap_err();
+        assert!(matches!(
+            err,
+            DecodeError::FrameTooLarge {
+                length: 512,
+                limit: 256
+            }
+        ));
+    }
+
+    #[test]
+    fn varint_boundaries() {
+        let previous_end = Sequence::new(1000);
+        for (delta, size) in [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (16383, 2),
+            (16384, 3),
+            // Up to the end of the sequence space and back to right before the previous data segment
+            (u64::MAX - 1001, 10),
+            (u64::MAX, 10),
+        ] {
+            let mut varint = vec![];
+            put_varint(&mut varint, delta);
+            assert_eq!(varint.len(), size);
+            assert_eq!(decode_varint(&varint).unwrap(), Some((delta, size)));
+            assert_eq!(decode_varint(&varint[..size - 1]).unwrap(), None);
+
+            let mut context = HeaderContext::new();
+            context.record(previous_end);
+            let frame = Frame::DataSegment {
+                start_sequence: context.start_sequence(delta),
+                payload: Bytes::from_static(b"x"),
+                checksummed: false,
+                compact: true,
+                compression: Compression::None,
+            };
+            let mut wire = vec![];
+            frame.encode_in(&mut wire, &mut context.clone()).unwrap();
+            assert_eq!(wire.len(), 1 + size + 1 + 1);
+            let decoded = Frame::decode_in(&mut &wire[..], &mut context, usize::MAX).unwrap();
+            assert_eq!(decoded, Some(frame));
+            let start = previous_end.inner().wrapping_add(delta);
+            assert_eq!(context.previous_end(), Sequence::new(start + 1));
+        }
+
+        for invalid in [
+            &[0x80, 0x00][..],
+            &[0xff; 10][..],
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02],
+        ] {
+            assert!(matches!(
+                decode_varint(invalid),
+                Err(DecodeError::InvalidVarint)
+            ));
+        }
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..4096 {
+            let len = rng.gen_range(0..64);
+            let mut wire: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            // Mostly known type codes and small lengths to get past the headers
+            if let Some(type_code) = wire.first_mut() {
+                *type_code %= 10;
+            }
+            match wire.first() {
+                Some(0 | 3) if wire.len() > 12 => wire[9..12].fill(0),
+                Some(6) if wire.len() > 2 => wire[1] = 0,
+                Some(7 | 8) if wire.len() > 2 => wire[1..3].fill(0x01),
+                _ => (),
+            }
+
+            let whole = {
+                let mut src = &wire[..];
+                let mut context = HeaderContext::new();
+                let mut frames = vec![];
+                let res = loop {
+                    match Frame::decode_in(&mut src, &mut context, DEFAULT_MAX_PAYLOAD_SIZE) {
+                        Ok(Some(frame)) => frames.push(frame),
+                        Ok(None) => break Ok(src.len()),
+                        Err(e) => break Err(e),
+                    }
+                };
+                (frames, res)
+            };
+            // Decoding byte by byte finds the same frames and the same error
+            let stepped = decode_in_steps(&wire, 1);
+            match (&whole.1, stepped) {
+                (Ok(_), Ok(frames)) => assert_eq!(frames, whole.0),
+                (Err(_), Err(_)) => (),
+                (whole, stepped) => panic!("{whole:?} != {stepped:?}"),
+            }
+            // Whatever is decoded encodes back to the bytes it came from
+            if let (frames, Ok(rest)) = whole {
+                assert_eq!(encode_all(&frames)[..], wire[..wire.len() - rest]);
+            }
+            inspect(&wire).to_string();
+        }
+    }
+
+    #[test]
+    fn inspect_damaged_subflows() {
+        let frame = Frame::DataSegment {
+            start_sequence: Sequence::new(3),
+            payload: Bytes::from_static(b"hello"),
+            checksummed: true,
+            compact: false,
+            compression: Compression::None,
+        };
+        let mut wire = vec![];
+        Hello::new(0).put(&mut wire);
+        let hello_len = wire.len();
+        let mut context = HeaderContext::new();
+        for frame in [&frame, &frame, &Frame::Ping] {
+            frame.encode_in(&mut wire, &mut context).unwrap();
+        }
+        let frame_len = 1 + 8 + 4 + 5 + 4;
+        // A mismatch in the first one, which the inspection goes past
+        wire[hello_len + 1 + 8 + 4] ^= 1;
+        let inspection = inspect(&wire);
+        assert!(inspection.hello.is_some());
+        assert!(inspection.stop.is_none());
+        let checksums: Vec<_> = inspection
+            .frames
+            .iter()
+            .map(|frame| frame.checksum_valid)
+            .collect();
+        assert_eq!(checksums, [Some(false), Some(true), None]);
+        assert_eq!(inspection.frames[1].offset, hello_len + frame_len);
+        assert_eq!(inspection.frames[1].sequence, Some(Sequence::new(3)));
+        assert!(inspection.to_string().contains("checksum MISMATCH"));
+
+        // Ending within the second one
+        let truncated = inspect(&wire[..hello_len + frame_len + 3]);
+        assert_eq!(truncated.frames.len(), 1);
+        assert!(matches!(
+            truncated.stop,
+            Some(InspectStop::Truncated { offset }) if offset == hello_len + frame_len
+        ));
+        for len in 1..hello_len {
+            let truncated = inspect(&wire[..len]);
+            assert!(truncated.hello.is_none());
+            assert!(matches!(
+                truncated.stop,
+                Some(InspectStop::Truncated { offset: 0 })
+            ));
+        }
+
+        // Without the hello, then with an unknown type code after the first frame
+        let mut frames = wire[hello_len..].to_vec();
+        frames[frame_len] = 127;
+        let invalid = inspect(&frames);
+        assert!(invalid.hello.is_none());
+        assert_eq!(invalid.frames.len(), 1);
+        assert!(matches!(
+            invalid.stop,
+            Some(InspectStop::Invalid { offset, .. }) if offset == frame_len
+        ));
+        assert!(invalid
+            .to_string()
+            .ends_with("invalid: Unknown type code: 127\n"));
+        assert!(inspect(&[]).frames.is_empty());
+    }
+
+    #[test]
+    fn passthrough_escapes() {
+        let text = Bytes::from_static(b"hello");
+        assert_eq!(escape_passthrough(&text).as_ptr(), text.as_ptr());
+        let payload = Bytes::from_static(&[0xff, 1, 0xff, 0xff, 0, 2, 0xff]);
+        let escaped = escape_passthrough(&payload);
+        assert_eq!(
+            escaped[..],
+            [0xff, 0xff, 1, 0xff, 0xff, 0xff, 0xff, 0, 2, 0xff, 0xff]
+        );
+
+        // Split anywhere, with frames after the switch
+        let mut wire = escaped.to_vec();
+        wire.extend_from_slice(&[PASSTHROUGH_ESCAPE, PASSTHROUGH_SWITCH, PING_TYPE_CODE]);
+        for split in 0..=wire.len() {
+            let mut unescape = Unescape::default();
+            let mut decoded = vec![];
+            let mut switch = None;
+            let mut offset = 0;
+            for piece in [&wire[..split], &wire[split..]] {
+                let mut piece = piece.to_vec();
+                let (len, at) = unescape.unescape(&mut piece).unwrap();
+                decoded.extend_from_slice(&piece[..len]);
+                if let Some(at) = at {
+                    switch = Some(offset + at);
+                    break;
+                }
+                offset += piece.len();
+            }
+            assert_eq!(decoded, payload, "split at {split}");
+            assert_eq!(switch, Some(wire.len() - 1), "split at {split}");
+        }
+        let res = Unescape::default().unescape(&mut [1, 0xff, 7]);
+        assert!(matches!(res, Err(DecodeError::InvalidEscape(7))));
+
+        let mut wire = vec![];
+        Hello::new(0)
+            .with_passthrough(Sequence::new(0))
+            .put(&mut wire);
+        let hello_len = wire.len();
+        wire.extend_from_slice(&escaped);
+        wire.extend_from_slice(&[PASSTHROUGH_ESCAPE, PASSTHROUGH_SWITCH]);
+        Frame::Ping
+            .encode_in(&mut wire, &mut HeaderContext::new())
+            .unwrap();
+        let inspection = inspect(&wire);
+        let passthrough = hello_len..hello_len + escaped.len() + 2;
+        assert_eq!(inspection.passthrough, Some(passthrough.clone()));
+        assert_eq!(inspection.frames.len(), 1);
+        assert_eq!(inspection.frames[0].offset, passthrough.end);
+        assert!(inspection
+            .to_string()
+            .contains(&format!("{hello_len:>8} passthrough len 13")));
+        // Not switched yet
+        let inspection = inspect(&wire[..hello_len + 4]);
+        assert_eq!(inspection.passthrough, Some(hello_len..hello_len + 4));
+        assert!(inspection.frames.is_empty() && inspection.stop.is_none());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn u32_sequences_across_wrap() {
+        let wrap = 1_u64 << 32;
+        let data_segment = |start: u64, compact| Frame::DataSegment {
+            start_sequence: Sequence::new(start),
+            payload: Bytes::from_static(b"0123456789"),
+            checksummed: true,
+            compact,
+            compression: Compression::None,
+        };
+        let frames = [
+            data_segment(wrap - 3, false),
+            data_segment(wrap + 7, true),
+            // A retransmission from before the wrap
+            data_segment(wrap - 3, false),
+            data_segment(wrap + 17, false),
+            Frame::Fin(Sequence::new(wrap + 27)),
+            Frame::Ack(Sequence::new(wrap - 1)),
+            Frame::Ack(Sequence::new(wrap + 5)),
+        ];
+        let encode = |width| {
+            let mut context = HeaderContext::with_sequence_width(width);
+            let mut wire = vec![];
+            for frame in &frames {
+                frame.encode_in(&mut wire, &mut context).unwrap();
+            }
+            wire
+        };
+        let wire = encode(SeqWidth::U32);
+        // Four bytes less for each full header, fin and ack
+        assert_eq!(encode(SeqWidth::U64).len() - wire.len(), 4 * 6);
+
+        let mut context = HeaderContext::with_sequence_width(SeqWidth::U32);
+        context.observe(Sequence::new(wrap - 100));
+        let mut src = &wire[..];
+        let mut decoded = vec![];
+        while let Some(frame) = Frame::decode_in(&mut src, &mut context, 1 << 10).unwrap() {
+            decoded.push(frame);
+        }
+        assert_eq!(decoded, frames);
+
+        let mut context = HeaderContext::with_sequence_width(SeqWidth::U32);
+        context.observe(Sequence::new(wrap - 100));
+        let mut reader = &wire[..];
+        for frame in frames {
+            let message = Message::decode_next_in(&mut reader, &mut context).await;
+            assert_eq!(message.unwrap().unwrap(), Message::from(frame));
+        }
+
+        // The nearest sequence, or the one after it if that would be negative
+        let mut context = HeaderContext::with_sequence_width(SeqWidth::U32);
+        context.observe(Sequence::new(5));
+        assert_eq!(context.expand(3).unwrap(), Sequence::new(3));
+        assert_eq!(
+            context.expand(u64::from(u32::MAX)).unwrap(),
+            Sequence::new(wrap - 1)
+        );
+        context.observe(Sequence::new(wrap + 5));
+        assert_eq!(
+            context.expand(u64::from(u32::MAX)).unwrap(),
+            Sequence::new(wrap - 1)
+        );
+        assert_eq!(
+            context.expand(wrap / 4).unwrap(),
+            Sequence::new(wrap + wrap / 4)
+        );
+        context.observe(Sequence::new(u64::MAX - 5));
+        assert!(matches!(
+            context.expand(10),
+            Err(DecodeError::SequenceOutOfRange(10))
+        ));
+    }
+}
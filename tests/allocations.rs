@@ -0,0 +1,50 @@
+//! Allocations per segment on the send path, counted by a global allocator of this test binary
+
+#![cfg(feature = "tokio")]
+
+use std::num::NonZeroUsize;
+
+use bytes::Bytes;
+use mptcp::sender::Sender;
+
+mod common;
+
+use common::alloc::{self, Counting};
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const STREAMS: usize = 4;
+const PAYLOAD_SIZE: usize = 64;
+
+/// Allocations per segment of sending `segments` segments of `PAYLOAD_SIZE` bytes
+fn allocations_per_segment(segments: usize, compact: bool) -> f64 {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let sinks = (0..STREAMS).map(|_| tokio::io::sink()).collect();
+        let mut sender = Sender::new(sinks);
+        sender.set_compact_headers(compact);
+        sender.set_max_segment_size(NonZeroUsize::new(PAYLOAD_SIZE));
+        let data = Bytes::from(vec![0; PAYLOAD_SIZE * segments]);
+        // Warm up the buffers kept across sends
+        sender.batch_send_all(data.clone()).await.unwrap();
+
+        let before = alloc::allocations();
+        sender.batch_send_all(data).await.unwrap();
+        (alloc::allocations() - before) as f64 / segments as f64
+    })
+}
+
+#[test]
+fn per_segment_allocations() {
+    for compact in [false, true] {
+        let few = allocations_per_segment(1 << 8, compact);
+        let many = allocations_per_segment(1 << 12, compact);
+        // The write of a segment, its task in the set of writes and its claim, plus a share of the round
+        assert!(many < 4., "compact: {compact}, {many}");
+        assert!((few - many).abs() < 0.5, "compact: {compact}, {few} {many}");
+    }
+}
@@ -1,7 +1,13 @@
-use std::{net::SocketAddr, num::NonZeroUsize, process::exit, time::Instant};
+#![cfg(feature = "tokio")]
 
-use bytes::BytesMut;
-use mptcp::{MptcpListener, MptcpStream};
+use std::{io::IoSlice, net::SocketAddr, num::NonZeroUsize, process::exit, time::Instant};
+
+use bytes::{Bytes, BytesMut};
+use mptcp::{
+    message::{DataSegment, EncodeOptions, HeaderContext, Message, Sequence},
+    sender::Sender,
+    MptcpListener, MptcpStream,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     task::JoinSet,
@@ -28,7 +34,7 @@ async fn bench_server() -> (SocketAddr, JoinSet<()>) {
 
 async fn bench_client(listen_addr: SocketAddr) {
     const ROUNDS: usize = 100;
-    let mut client = MptcpStream::connect(listen_addr, NonZeroUsize::new(STREAMS).unwrap())
+    let mut client = MptcpStream::connect([listen_addr], NonZeroUsize::new(STREAMS).unwrap())
         .await
         .unwrap();
     let buf: Vec<u8> = (0..PAYLOAD_SIZE).map(|_| rand::random()).collect();
@@ -50,3 +56,139 @@ async fn bench() {
     bench_client(addr).await;
     exit(0);
 }
+
+async fn bench_encode(options: EncodeOptions) {
+    const ROUNDS: usize = 100;
+    let payload: Vec<u8> = (0..PAYLOAD_SIZE).map(|_| rand::random()).collect();
+    let message =
+        Message::DataSegment(DataSegment::new(Sequence::new(0), Bytes::from(payload)).unwrap());
+    let mut sink = tokio::io::sink();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        message.encode_with(&mut sink, options).await.unwrap();
+    }
+    let duration = start.elapsed();
+    let throughput = (PAYLOAD_SIZE * ROUNDS) as f64 / duration.as_secs_f64();
+    let throughput_mib_s = throughput / 1024. / 1024.;
+    println!(
+        "checksum: {}, throughput: {throughput_mib_s:.2} MiB/s",
+        options.checksum
+    );
+}
+
+#[ignore]
+#[tokio::test]
+async fn bench_checksum() {
+    for checksum in [false, true] {
+        bench_encode(EncodeOptions {
+            checksum,
+            ..Default::default()
+        })
+        .await;
+    }
+}
+
+/// Bytes on the wire for many small messages spread over the streams
+async fn bench_header_overhead(compact: bool) {
+    const MESSAGES: usize = 100_000;
+    const MESSAGE_SIZE: usize = 300;
+    let options = EncodeOptions {
+        compact,
+        ..Default::default()
+    };
+    let mut contexts = [HeaderContext::new(); STREAMS];
+    let mut wires = vec![vec![]; STREAMS];
+    let payload = Bytes::from(vec![0; MESSAGE_SIZE]);
+    let start = Instant::now();
+    for i in 0..MESSAGES {
+        let start_sequence = Sequence::new((i * MESSAGE_SIZE) as u64);
+        let segment = DataSegment::new(start_sequence, payload.clone()).unwrap();
+        let stream = i % STREAMS;
+        Message::DataSegment(segment)
+            .encode_in(&mut wires[stream], options, &mut contexts[stream])
+            .await
+            .unwrap();
+    }
+    let duration = start.elapsed();
+    let wire_bytes: usize = wires.iter().map(|wire| wire.len()).sum();
+    let overhead = (wire_bytes - MESSAGES * MESSAGE_SIZE) as f64 / MESSAGES as f64;
+    let overhead_percent = overhead * 100. / MESSAGE_SIZE as f64;
+    let throughput = wire_bytes as f64 / duration.as_secs_f64();
+    let throughput_mib_s = throughput / 1024. / 1024.;
+    println!(
+        "compact: {compact}, overhead: {overhead:.2} bytes per message ({overhead_percent:.2}%), throughput: {throughput_mib_s:.2} MiB/s"
+    );
+}
+
+#[ignore]
+#[tokio::test]
+async fn bench_compact_headers() {
+    bench_header_overhead(false).await;
+    bench_header_overhead(true).await;
+}
+
+async fn bench_small_writes(vectored: bool) {
+    const WRITES: usize = 1000;
+    const WRITE_SIZE: usize = 1 << 10;
+    let payload: Vec<Vec<u8>> = (0..WRITES)
+        .map(|_| (0..WRITE_SIZE).map(|_| rand::random()).collect())
+        .collect();
+    let mut sender = Sender::new((0..STREAMS).map(|_| tokio::io::sink()).collect());
+    let start = Instant::now();
+    if vectored {
+        let mut bufs: Vec<IoSlice<'_>> = payload.iter().map(|buf| IoSlice::new(buf)).collect();
+        let mut bufs = &mut bufs[..];
+        while !bufs.is_empty() {
+            let n = sender.write_vectored(bufs).await.unwrap();
+            IoSlice::advance_slices(&mut bufs, n);
+        }
+    } else {
+        let mut async_write = sender.into_async_write();
+        for buf in &payload {
+            async_write.write_all(buf).await.unwrap();
+        }
+        sender = async_write.into_inner();
+    }
+    let duration = start.elapsed();
+    let segments: u64 = sender.stats().iter().map(|s| s.segments_written()).sum();
+    let throughput = (WRITES * WRITE_SIZE) as f64 / duration.as_secs_f64();
+    let throughput_mib_s = throughput / 1024. / 1024.;
+    println!("vectored: {vectored}, segments: {segments}, throughput: {throughput_mib_s:.2} MiB/s");
+}
+
+#[ignore]
+#[tokio::test]
+async fn bench_write_vectored() {
+    bench_small_writes(false).await;
+    bench_small_writes(true).await;
+}
+
+async fn bench_chunks(owned: bool) {
+    const CHUNK_SIZE: usize = 1 << 22;
+    const ROUNDS: usize = 100;
+    let chunk: Vec<u8> = (0..CHUNK_SIZE).map(|_| rand::random()).collect();
+    let chunk = Bytes::from(chunk);
+    let mut sender = Sender::new((0..STREAMS).map(|_| tokio::io::sink()).collect());
+    let start = Instant::now();
+    if owned {
+        for _ in 0..ROUNDS {
+            sender.send(chunk.clone()).await.unwrap();
+        }
+    } else {
+        let mut async_write = sender.into_async_write();
+        for _ in 0..ROUNDS {
+            async_write.write_all(&chunk).await.unwrap();
+        }
+    }
+    let duration = start.elapsed();
+    let throughput = (CHUNK_SIZE * ROUNDS) as f64 / duration.as_secs_f64();
+    let throughput_mib_s = throughput / 1024. / 1024.;
+    println!("owned: {owned}, throughput: {throughput_mib_s:.2} MiB/s");
+}
+
+#[ignore]
+#[tokio::test]
+async fn bench_send_bytes() {
+    bench_chunks(false).await;
+    bench_chunks(true).await;
+}
@@ -0,0 +1,56 @@
+//! Memory held by a single 512 MiB write, tracked by a global allocator of this test binary
+
+#![cfg(feature = "tokio")]
+
+use std::num::NonZeroUsize;
+
+use mptcp::{receiver::Receiver, sender::SenderBuilder};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+mod common;
+
+use common::alloc::{self, Counting};
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const STREAMS: usize = 4;
+const CHUNK_SIZE: usize = 16 << 20;
+const TRANSFER_SIZE: usize = 512 << 20;
+
+fn pattern(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+#[tokio::test]
+async fn write_all_holds_a_chunk_at_a_time() {
+    let data: Vec<u8> = (0..TRANSFER_SIZE).map(pattern).collect();
+    let (send_streams, recv_streams): (Vec<_>, Vec<_>) =
+        (0..STREAMS).map(|_| tokio::io::duplex(1 << 20)).unzip();
+    let mut writer = SenderBuilder::new()
+        .send_chunk_size(NonZeroUsize::new(CHUNK_SIZE).unwrap())
+        .build(send_streams);
+    let mut reader = Receiver::new(recv_streams).into_async_read();
+
+    let before = alloc::live();
+    alloc::reset_peak();
+    let recv = tokio::spawn(async move {
+        let mut buf = vec![0; 1 << 16];
+        let mut received = 0;
+        loop {
+            let n = reader.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return received;
+            }
+            assert!((received..).zip(&buf[..n]).all(|(i, &b)| pattern(i) == b));
+            received += n;
+        }
+    });
+    writer.write_all(&data).await.unwrap();
+    writer.shutdown().await.unwrap();
+    assert_eq!(recv.await.unwrap(), TRANSFER_SIZE);
+
+    // A chunk copied out of `data`, about as much reassembled by the receiver and the buffers of the streams, far from the whole of it
+    let peak = alloc::peak() - before;
+    assert!(peak < 5 * CHUNK_SIZE, "{peak}");
+}
@@ -0,0 +1,5 @@
+//! Helpers shared by the test binaries, each of which uses only some of them
+
+#![allow(dead_code)]
+
+pub mod alloc;
@@ -0,0 +1,85 @@
+//! The largest allocation of the decoders fed corrupt subflows, tracked by a global allocator of this test binary
+
+#![cfg(feature = "tokio")]
+
+use std::io::Cursor;
+
+use bytes::Bytes;
+use mptcp::{
+    compression::Compression,
+    message::{Message, Sequence},
+    wire::{Frame, HeaderContext},
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+mod common;
+
+use common::alloc::{self, Counting};
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const MAX_PAYLOAD_SIZE: usize = 1 << 12;
+
+/// A valid subflow of `frames` frames with payloads up to `MAX_PAYLOAD_SIZE`
+fn subflow(rng: &mut StdRng, frames: usize) -> Vec<u8> {
+    let mut context = HeaderContext::new();
+    let mut wire = vec![];
+    let mut next = 0;
+    for _ in 0..frames {
+        let payload: Bytes = (0..rng.gen_range(1..=MAX_PAYLOAD_SIZE))
+            .map(|_| rng.gen::<u8>())
+            .collect::<Vec<_>>()
+            .into();
+        let frame = match rng.gen_range(0..4) {
+            0 => Frame::Ping,
+            1 => Frame::Ack(Sequence::new(next)),
+            2 => Frame::Control(payload.slice(..payload.len().min(64))),
+            _ => {
+                let start_sequence = Sequence::new(next);
+                next += payload.len() as u64;
+                Frame::DataSegment {
+                    start_sequence,
+                    payload,
+                    checksummed: rng.gen(),
+                    compact: rng.gen(),
+                    compression: Compression::None,
+                }
+            }
+        };
+        frame.encode_in(&mut wire, &mut context).unwrap();
+    }
+    wire
+}
+
+#[test]
+fn corrupt_lengths_allocate_no_more_than_the_limit() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..256 {
+        let mut wire = subflow(&mut rng, 16);
+        // A random prefix with a few flipped bits, most likely in the headers
+        wire.truncate(rng.gen_range(1..=wire.len()));
+        for _ in 0..rng.gen_range(1..4) {
+            let byte = rng.gen_range(0..wire.len().min(32));
+            wire[byte] ^= 1 << rng.gen_range(0..8);
+        }
+
+        alloc::reset_largest();
+        runtime.block_on(async {
+            let mut reader = Cursor::new(&wire[..]);
+            let mut context = HeaderContext::new();
+            while let Ok(Some(_)) =
+                Message::decode_next_limited(&mut reader, &mut context, MAX_PAYLOAD_SIZE).await
+            {
+            }
+        });
+        let mut src = &wire[..];
+        let mut context = HeaderContext::new();
+        while let Ok(Some(_)) = Frame::decode_in(&mut src, &mut context, MAX_PAYLOAD_SIZE) {}
+        let largest = alloc::largest();
+        assert!(largest <= MAX_PAYLOAD_SIZE, "{largest}");
+    }
+}
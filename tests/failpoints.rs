@@ -0,0 +1,146 @@
+//! The failpoints of the `failpoints` feature, armed one test at a time
+
+#![cfg(feature = "failpoints")]
+
+use std::{
+    io::ErrorKind,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use mptcp::{
+    failpoints::{self, FailAction},
+    receiver::{Receiver, RecvError},
+    sender::{ReconnectPolicy, SendError, Sender, SubflowEvent},
+};
+use tokio::sync::Mutex;
+
+/// Held by every test, as the points are armed for the whole process
+static SERIAL: Mutex<()> = Mutex::const_new(());
+
+fn missing_segment(e: &std::io::Error) -> bool {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<RecvError>())
+        .is_some_and(|e| matches!(e, RecvError::MissingSegment { .. }))
+}
+
+#[tokio::test]
+async fn segment_write() {
+    let _serial = SERIAL.lock().await;
+    failpoints::disarm_all();
+    let (streams, _peers): (Vec<_>, Vec<_>) = (0..2).map(|_| tokio::io::duplex(1 << 10)).unzip();
+    let mut sender = Sender::new(streams);
+
+    failpoints::arm(
+        failpoints::SEND_SEGMENT_WRITE,
+        FailAction::Error(ErrorKind::ConnectionReset),
+    );
+    let res = sender.batch_send_all(Bytes::from_static(b"hello")).await;
+    let Err(SendError::Incomplete { sent, errors, .. }) = res else {
+        panic!("{res:?}");
+    };
+    assert_eq!(sent, 0);
+    assert_eq!(errors.len(), 2);
+    assert!(errors
+        .iter()
+        .all(|e| e.error().kind() == ErrorKind::ConnectionReset));
+    let res = sender.batch_send_all(Bytes::from_static(b"hello")).await;
+    failpoints::disarm(failpoints::SEND_SEGMENT_WRITE);
+    assert!(matches!(
+        res,
+        Err(SendError::NoStreamLeft { streams: 2, .. })
+    ));
+}
+
+#[tokio::test]
+async fn reconnect_dial() {
+    let _serial = SERIAL.lock().await;
+    failpoints::disarm_all();
+    let (stream, peer) = tokio::io::duplex(1 << 10);
+    drop(peer);
+    let mut sender = Sender::new(vec![stream]);
+    let policy = ReconnectPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+    };
+    // Every dial would succeed but for the failpoint
+    sender.set_reconnect(policy, |_| async { Ok(tokio::io::duplex(1 << 10).0) });
+    let mut events = sender.subscribe_events();
+
+    failpoints::arm(
+        failpoints::RECONNECT_DIAL,
+        FailAction::Error(ErrorKind::ConnectionRefused),
+    );
+    let res = sender.batch_send_all(Bytes::from_static(b"hello")).await;
+    sender.wait_for_reconnects().await;
+    failpoints::disarm(failpoints::RECONNECT_DIAL);
+    assert!(res.is_err(), "{res:?}");
+    assert_eq!(sender.live_streams(), 0);
+
+    let mut failed = 0;
+    while let Ok(event) = events.try_recv() {
+        if let SubflowEvent::ReconnectFailed { error, .. } = event {
+            assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+            failed += 1;
+        }
+    }
+    assert_eq!(failed, 3);
+}
+
+#[tokio::test]
+async fn handshake() {
+    let _serial = SERIAL.lock().await;
+    failpoints::disarm_all();
+    let (tx, rx) = tokio::io::duplex(1 << 10);
+    failpoints::arm(
+        failpoints::RECV_HANDSHAKE,
+        FailAction::Error(ErrorKind::InvalidData),
+    );
+    let mut receiver = Receiver::new(vec![rx]);
+    let mut sender = Sender::new(vec![tx]);
+    sender
+        .batch_send_all(Bytes::from_static(b"hello"))
+        .await
+        .unwrap();
+    let res = receiver.recv(&mut [0; 5]).await;
+    failpoints::disarm(failpoints::RECV_HANDSHAKE);
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn gap_timer() {
+    let _serial = SERIAL.lock().await;
+    failpoints::disarm_all();
+    let (tx, rx) = tokio::io::duplex(1 << 10);
+    let mut receiver = Receiver::new(vec![rx]);
+    let mut sender = Sender::new(vec![tx]);
+    sender.handshake().await.unwrap();
+
+    failpoints::arm(
+        failpoints::RECV_GAP_TIMER,
+        FailAction::Error(ErrorKind::TimedOut),
+    );
+    let e = receiver.recv(&mut [0; 5]).await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+    assert!(missing_segment(&e));
+
+    failpoints::arm(
+        failpoints::RECV_GAP_TIMER,
+        FailAction::Delay(Duration::from_millis(50)),
+    );
+    let start = Instant::now();
+    let e = receiver.recv(&mut [0; 5]).await.unwrap_err();
+    failpoints::disarm(failpoints::RECV_GAP_TIMER);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert!(missing_segment(&e));
+
+    // Disarmed, the data goes through again
+    sender
+        .batch_send_all(Bytes::from_static(b"hello"))
+        .await
+        .unwrap();
+    let mut buf = [0; 5];
+    assert_eq!(receiver.recv(&mut buf).await.unwrap(), 5);
+    assert_eq!(&buf, b"hello");
+}
@@ -0,0 +1,65 @@
+//! The `futures-io` sender and receiver against the tokio ones, over in-memory pipes
+
+#![cfg(all(feature = "futures-io", feature = "tokio"))]
+
+use std::num::NonZeroUsize;
+
+use bytes::Bytes;
+use futures_util::{AsyncReadExt as _, AsyncWriteExt as _};
+use mptcp::{futures_io, receiver::Receiver, sender::Sender};
+use tokio::io::{AsyncReadExt, DuplexStream};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+const STREAMS: usize = 4;
+
+fn pipes() -> (Vec<DuplexStream>, Vec<DuplexStream>) {
+    (0..STREAMS).map(|_| tokio::io::duplex(1 << 12)).unzip()
+}
+
+fn compat(streams: Vec<DuplexStream>) -> Vec<Compat<DuplexStream>> {
+    streams
+        .into_iter()
+        .map(TokioAsyncReadCompatExt::compat)
+        .collect()
+}
+
+fn data() -> Vec<u8> {
+    (0..1 << 20).map(|i: u32| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn futures_io_sender_to_tokio_receiver() {
+    let (tx, rx) = pipes();
+    let mut sender = futures_io::Sender::new(compat(tx));
+    sender.set_max_segment_size(NonZeroUsize::new(1000).unwrap());
+    let receiver = Receiver::new(rx);
+
+    let send = tokio::spawn(async move {
+        sender.write_all(&data()).await.unwrap();
+        sender.close().await.unwrap();
+    });
+    let mut received = vec![];
+    receiver
+        .into_async_read()
+        .read_to_end(&mut received)
+        .await
+        .unwrap();
+    send.await.unwrap();
+    assert!(received == data());
+}
+
+#[tokio::test]
+async fn tokio_sender_to_futures_io_receiver() {
+    let (tx, rx) = pipes();
+    let mut sender = Sender::new(tx);
+    let mut receiver = futures_io::Receiver::new(compat(rx));
+
+    let send = tokio::spawn(async move {
+        sender.batch_send_all(Bytes::from(data())).await.unwrap();
+        sender.shutdown().await.unwrap();
+    });
+    let mut received = vec![];
+    receiver.read_to_end(&mut received).await.unwrap();
+    send.await.unwrap();
+    assert!(received == data());
+}
@@ -0,0 +1,145 @@
+//! The subflows of `tests/golden`, whose bytes every version has to decode to the same frames and encode the same frames to
+//!
+//! A test failing here means a change of the wire format, which peers of other versions do not understand.
+
+use bytes::Bytes;
+use mptcp::{
+    compression::Compression,
+    message::{Hello, Sequence},
+    wire::{inspect, Frame, HeaderContext},
+};
+
+/// The bytes of a `.hex` file, whose `#` starts a comment to the end of the line
+fn parse_hex(text: &str) -> Vec<u8> {
+    let digits: Vec<u8> = text
+        .lines()
+        .flat_map(|line| line.split('#').next().unwrap().bytes())
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+/// Decode `hex` as a subflow made of `frames`, encode them back to the same bytes and inspect it
+fn check(hex: &str, frames: &[Frame], inspection: &str) {
+    let bytes = parse_hex(hex);
+    let mut src = &bytes[..];
+    let hello = Hello::decode_buf(&mut src).unwrap().unwrap();
+    let mut context = HeaderContext::with_sequence_width(hello.sequence_width());
+    let mut decoded = vec![];
+    while let Some(frame) = Frame::decode_in(&mut src, &mut context, usize::MAX).unwrap() {
+        decoded.push(frame);
+    }
+    assert!(src.is_empty());
+    assert_eq!(decoded, frames);
+
+    let mut encoded = vec![];
+    hello.put(&mut encoded);
+    let mut context = HeaderContext::with_sequence_width(hello.sequence_width());
+    for frame in frames {
+        frame.encode_in(&mut encoded, &mut context).unwrap();
+    }
+    assert_eq!(encoded, bytes);
+
+    assert_eq!(inspect(&bytes).to_string(), inspection);
+}
+
+fn data_segment(
+    start_sequence: u64,
+    payload: &'static [u8],
+    checksummed: bool,
+    compact: bool,
+    compression: Compression,
+) -> Frame {
+    Frame::DataSegment {
+        start_sequence: Sequence::new(start_sequence),
+        payload: Bytes::from_static(payload),
+        checksummed,
+        compact,
+        compression,
+    }
+}
+
+#[test]
+fn u64_sequences() {
+    let data_segment = |start_sequence, checksummed, compact| {
+        data_segment(
+            start_sequence,
+            b"hi",
+            checksummed,
+            compact,
+            Compression::None,
+        )
+    };
+    let frames = [
+        data_segment(0, false, false),
+        data_segment(2, true, false),
+        data_segment(4, false, true),
+        data_segment(306, true, true),
+        Frame::Ping,
+        Frame::Ack(Sequence::new(9)),
+        Frame::Control(Bytes::from_static(b"ok")),
+        Frame::Probe(1),
+        Frame::Pong(u64::MAX),
+        Frame::Oob {
+            sequence: 0x0102_0304,
+            payload: Bytes::from_static(b"ok"),
+        },
+        Frame::Unknown {
+            type_code: 128,
+            payload: Bytes::from_static(b"ok"),
+        },
+        Frame::Abort {
+            sequence: Sequence::new(308),
+            reason: Bytes::from_static(b"ok"),
+        },
+        Frame::Fin(Sequence::new(308)),
+        Frame::Shutdown,
+    ];
+    check(
+        include_str!("golden/u64.hex"),
+        &frames,
+        include_str!("golden/u64.txt"),
+    );
+}
+
+#[test]
+fn u32_sequences() {
+    let wrap = 1 << 32;
+    let data_segment = |start_sequence, checksummed| {
+        data_segment(
+            start_sequence,
+            b"0123456789",
+            checksummed,
+            false,
+            Compression::None,
+        )
+    };
+    let frames = [
+        data_segment(wrap - 3, false),
+        data_segment(wrap + 7, true),
+        Frame::Fin(Sequence::new(wrap + 17)),
+        Frame::Ack(Sequence::new(wrap - 1)),
+        Frame::Ack(Sequence::new(wrap + 5)),
+    ];
+    check(
+        include_str!("golden/u32.hex"),
+        &frames,
+        include_str!("golden/u32.txt"),
+    );
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn lz4_data_segments() {
+    let lz4 = |start_sequence, checksummed| {
+        data_segment(start_sequence, b"hi", checksummed, false, Compression::Lz4)
+    };
+    check(
+        include_str!("golden/lz4.hex"),
+        &[lz4(1, false), lz4(3, true)],
+        include_str!("golden/lz4.txt"),
+    );
+}